@@ -0,0 +1,49 @@
+use nymchat_protocol::Envelope;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A message received from the mixnet, together with its transport metadata.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct IncomingMessage {
+    #[pyo3(get)]
+    pub message: String,
+    /// Base58 anonymous sender tag, present when the sender attached SURBs.
+    #[pyo3(get)]
+    pub sender_tag: Option<String>,
+    /// Unix timestamp (seconds) at which the message was received.
+    #[pyo3(get)]
+    pub timestamp: f64,
+}
+
+impl IncomingMessage {
+    pub fn new(message: String, sender_tag: Option<String>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        IncomingMessage { message, sender_tag, timestamp }
+    }
+}
+
+#[pymethods]
+impl IncomingMessage {
+    fn envelope(&self) -> PyResult<Envelope> {
+        Envelope::parse(&self.message)
+            .map_err(|e| PyValueError::new_err(format!("Invalid envelope JSON: {}", e)))
+    }
+
+    fn __str__(&self) -> String {
+        self.message.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "IncomingMessage(sender_tag={:?}, timestamp={}, len={})",
+            self.sender_tag,
+            self.timestamp,
+            self.message.len()
+        )
+    }
+}
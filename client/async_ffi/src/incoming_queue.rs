@@ -0,0 +1,214 @@
+use crate::incoming::IncomingMessage;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Messages held between the mixnet listener and the Python callback by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What the listener does when the Python side falls `capacity` messages behind.
+pub enum OverflowPolicy {
+    /// Stop reading from the mixnet until there is room. Nothing is lost, but
+    /// the gateway buffers in the meantime.
+    Block,
+    /// Discard the oldest queued message and count it in `dropped`.
+    DropOldest,
+    /// Write overflow to a SQLite file and deliver it, in order, once the
+    /// queue has drained. Counted in `spilled`.
+    Spill(Connection),
+}
+
+impl OverflowPolicy {
+    /// Parses `"block"`, `"drop-oldest"` or `"spill"`; spilling needs `spill_path`.
+    pub fn parse(name: &str, spill_path: Option<&str>) -> anyhow::Result<Self> {
+        match (name, spill_path) {
+            ("block", _) => Ok(OverflowPolicy::Block),
+            ("drop-oldest", _) => Ok(OverflowPolicy::DropOldest),
+            ("spill", Some(path)) => Ok(OverflowPolicy::Spill(open_spill(Path::new(path))?)),
+            ("spill", None) => anyhow::bail!("The spill policy needs a spill_path"),
+            (other, _) => anyhow::bail!("Unknown overflow policy {:?}", other),
+        }
+    }
+}
+
+fn open_spill(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS spill (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message TEXT NOT NULL,
+            sender_tag TEXT,
+            timestamp REAL NOT NULL
+        )",
+        [],
+    )?;
+    Ok(conn)
+}
+
+/// Counters exposed to Python by `PyMixnetClient.incoming_stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    /// Messages waiting in memory.
+    pub queued: usize,
+    /// Messages waiting in the spill file.
+    pub spilled_pending: usize,
+    /// Messages discarded under `DropOldest`, since startup.
+    pub dropped: u64,
+    /// Messages written to the spill file, since startup.
+    pub spilled: u64,
+}
+
+struct State {
+    queue: VecDeque<IncomingMessage>,
+    policy: OverflowPolicy,
+    stats: QueueStats,
+}
+
+/// Bounded hand-off from the mixnet listener to the task calling into Python.
+pub struct IncomingQueue {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Signalled when a message is pushed.
+    filled: Notify,
+    /// Signalled when a message is popped.
+    drained: Notify,
+}
+
+impl IncomingQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        let mut stats = QueueStats::default();
+        if let OverflowPolicy::Spill(ref conn) = policy {
+            // Messages spilled before a restart are still delivered.
+            stats.spilled_pending = spill_len(conn).unwrap_or_default();
+        }
+        IncomingQueue {
+            capacity: capacity.max(1),
+            state: Mutex::new(State { queue: VecDeque::new(), policy, stats }),
+            filled: Notify::new(),
+            drained: Notify::new(),
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats { queued: state.queue.len(), ..state.stats }
+    }
+
+    /// Queues `message`, applying the overflow policy when the queue is full.
+    /// Only waits under `OverflowPolicy::Block`.
+    pub async fn push(&self, message: IncomingMessage) {
+        let mut message = Some(message);
+        loop {
+            // Register for a wake-up before checking, so a pop in between is not missed.
+            let drained = self.drained.notified();
+            let blocked = {
+                let mut guard = self.state.lock().unwrap();
+                let state = &mut *guard;
+                let full = state.queue.len() >= self.capacity;
+                match &state.policy {
+                    OverflowPolicy::Block if full => true,
+                    // Once anything is spilled, later messages queue behind it.
+                    OverflowPolicy::Spill(conn) if full || state.stats.spilled_pending > 0 => {
+                        match spill(conn, message.as_ref().expect("message is pushed once")) {
+                            Ok(()) => {
+                                state.stats.spilled += 1;
+                                state.stats.spilled_pending += 1;
+                            }
+                            Err(e) => {
+                                println!("❌ Failed to spill incoming message: {:?}", e);
+                                state.stats.dropped += 1;
+                            }
+                        }
+                        false
+                    }
+                    policy => {
+                        if full && matches!(policy, OverflowPolicy::DropOldest) {
+                            state.queue.pop_front();
+                            state.stats.dropped += 1;
+                        }
+                        state.queue.extend(message.take());
+                        false
+                    }
+                }
+            };
+            if blocked {
+                drained.await;
+                continue;
+            }
+            self.filled.notify_one();
+            return;
+        }
+    }
+
+    /// Waits for the next message, oldest first.
+    pub async fn pop(&self) -> IncomingMessage {
+        loop {
+            let filled = self.filled.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                let state = &mut *state;
+                let next = match state.queue.pop_front() {
+                    Some(message) => Some(message),
+                    None => match &state.policy {
+                        OverflowPolicy::Spill(conn) if state.stats.spilled_pending > 0 => {
+                            match unspill(conn) {
+                                Ok(Some(message)) => {
+                                    state.stats.spilled_pending -= 1;
+                                    Some(message)
+                                }
+                                Ok(None) => {
+                                    state.stats.spilled_pending = 0;
+                                    None
+                                }
+                                Err(e) => {
+                                    println!("❌ Failed to read spilled message: {:?}", e);
+                                    state.stats.spilled_pending = 0;
+                                    None
+                                }
+                            }
+                        }
+                        _ => None,
+                    },
+                };
+                if let Some(message) = next {
+                    self.drained.notify_one();
+                    return message;
+                }
+            }
+            filled.await;
+        }
+    }
+}
+
+fn spill_len(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.query_row("SELECT COUNT(*) FROM spill", [], |row| row.get::<_, i64>(0))
+        .map(|n| n as usize)
+}
+
+fn spill(conn: &Connection, message: &IncomingMessage) -> rusqlite::Result<()> {
+    conn.prepare_cached("INSERT INTO spill (message, sender_tag, timestamp) VALUES (?1, ?2, ?3)")?
+        .execute(params![message.message, message.sender_tag, message.timestamp])?;
+    Ok(())
+}
+
+/// Removes and returns the oldest spilled message.
+fn unspill(conn: &Connection) -> rusqlite::Result<Option<IncomingMessage>> {
+    let row = conn
+        .prepare_cached("SELECT id, message, sender_tag, timestamp FROM spill ORDER BY id LIMIT 1")?
+        .query_row([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                IncomingMessage { message: row.get(1)?, sender_tag: row.get(2)?, timestamp: row.get(3)? },
+            ))
+        })
+        .optional()?;
+    match row {
+        Some((id, message)) => {
+            conn.prepare_cached("DELETE FROM spill WHERE id = ?1")?.execute(params![id])?;
+            Ok(Some(message))
+        }
+        None => Ok(None),
+    }
+}
@@ -1,8 +1,28 @@
+mod incoming;
+mod incoming_queue;
 mod mixnet_client;
+mod network_watch;
+mod traffic;
+use incoming::IncomingMessage;
+use incoming_queue::{OverflowPolicy, DEFAULT_CAPACITY};
 use mixnet_client::MixnetHandler;
+use nymchat_core::db::{
+    AuditRow, ChatFolderRow, ContactPrivacyRow, ContactRow, ConversationRow, Db, DeliveryRow, DraftRow, ExportRow,
+    FolderRow, GroupLogRow, GroupMessageRow, GroupRow, HandshakeRow, KeyChangeRow, KeyHistoryRow, MessageRow,
+    NewMessage, NotificationRow, ObservationRow, OutboxRow, PagedMessageRow, PollRow, PollVoteRow, PresenceRow,
+    QuarantineRow, SearchRow, SenderTagRow, StoredGroup,
+};
+use nymchat_core::transfer::{self, Chunk, Download, Manifest, Upload};
+use nymchat_core::{envelope, keystore, Error, StorageError};
+use nymchat_protocol::{
+    pow, ratchet, ChatPayload, ContactInvite, Crypto, Delegation, Encrypted, EncryptedBody, Envelope, GroupSession,
+    Hello, MembershipLog, MessageBody, Negotiated, RatchetSession,
+};
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 use pyo3_asyncio::tokio::future_into_py;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 #[pyclass]
 struct PyMixnetClient {
@@ -11,10 +31,26 @@ struct PyMixnetClient {
 
 #[pymethods]
 impl PyMixnetClient {
+    /// Received messages queue up to `incoming_capacity` deep while the message
+    /// callback catches up. Beyond that, `overflow` decides: `"block"` stops
+    /// reading from the mixnet, `"drop-oldest"` discards the oldest queued
+    /// message, and `"spill"` writes overflow to the SQLite file at `spill_path`.
+    /// With a `data_dir` the mixnet identity is kept there and reused by later
+    /// clients created with the same directory; without one it is ephemeral.
     #[staticmethod]
-    fn create(py: Python) -> PyResult<&PyAny> {
-        future_into_py(py, async {
-            let client = MixnetHandler::new().await.map_err(|e| {
+    #[pyo3(signature = (incoming_capacity = DEFAULT_CAPACITY, overflow = "block", spill_path = None, data_dir = None))]
+    fn create<'a>(
+        py: Python<'a>,
+        incoming_capacity: usize,
+        overflow: &str,
+        spill_path: Option<&str>,
+        data_dir: Option<&str>,
+    ) -> PyResult<&'a PyAny> {
+        let overflow = OverflowPolicy::parse(overflow, spill_path)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{:#}", e)))?;
+        let data_dir = data_dir.map(std::path::PathBuf::from);
+        future_into_py(py, async move {
+            let client = MixnetHandler::new(incoming_capacity, overflow, data_dir).await.map_err(|e| {
                 pyo3::exceptions::PyRuntimeError::new_err(format!("Client init failed: {:?}", e))
             })?;
             Ok(PyMixnetClient { inner: Arc::new(client) })
@@ -45,6 +81,39 @@ impl PyMixnetClient {
         })
     }
 
+    #[pyo3(name = "send_with_surbs")]
+    fn send_with_surbs<'a>(
+        &self,
+        py: Python<'a>,
+        recipient: String,
+        message: String,
+        surb_count: u32,
+    ) -> PyResult<&'a PyAny> {
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            client.send_with_surbs(&recipient, &message, surb_count).await.map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to send message: {:?}", e))
+            })?;
+            Ok(())
+        })
+    }
+
+    #[pyo3(name = "reply_with_surb")]
+    fn reply_with_surb<'a>(
+        &self,
+        py: Python<'a>,
+        sender_tag: String,
+        message: String,
+    ) -> PyResult<&'a PyAny> {
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            client.reply_with_surb(&sender_tag, &message).await.map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to send reply: {:?}", e))
+            })?;
+            Ok(())
+        })
+    }
+
     #[pyo3(name = "receive_messages")]
     fn receive_messages<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
         let client = self.inner.clone();
@@ -54,11 +123,73 @@ impl PyMixnetClient {
         })
     }
 
-    #[pyo3(name = "set_message_callback")]
-    fn set_message_callback<'a>(&self, py: Python<'a>, py_callback: PyObject) -> PyResult<&'a PyAny> {
+    /// With `typed=True` the callback receives `IncomingMessage` objects instead of strings.
+    #[pyo3(name = "set_message_callback", signature = (py_callback, typed = false))]
+    fn set_message_callback<'a>(
+        &self,
+        py: Python<'a>,
+        py_callback: PyObject,
+        typed: bool,
+    ) -> PyResult<&'a PyAny> {
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            client.set_callback(py_callback, typed).await;
+            Ok(())
+        })
+    }
+
+    /// Incoming queue metrics: `queued` and `spilled_pending` are current depths,
+    /// `dropped` and `spilled` count messages since the client was created.
+    fn incoming_stats(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let stats = self.inner.incoming_stats();
+        let dict = pyo3::types::PyDict::new(py);
+        dict.set_item("queued", stats.queued)?;
+        dict.set_item("spilled_pending", stats.spilled_pending)?;
+        dict.set_item("dropped", stats.dropped)?;
+        dict.set_item("spilled", stats.spilled)?;
+        Ok(dict.into())
+    }
+
+    /// Traffic through this client since it was created: message, estimated
+    /// packet and reply SURB counts, retried and failed sends and reconnects, plus the
+    /// `uptime` of the current connection in seconds (`None` while disconnected).
+    fn network_stats(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let stats = self.inner.traffic_stats();
+        let dict = pyo3::types::PyDict::new(py);
+        dict.set_item("uptime", self.inner.uptime().map(|uptime| uptime.as_secs_f64()))?;
+        dict.set_item("messages_sent", stats.messages_sent)?;
+        dict.set_item("messages_received", stats.messages_received)?;
+        dict.set_item("packets_sent", stats.packets_sent)?;
+        dict.set_item("packets_received", stats.packets_received)?;
+        dict.set_item("surbs_sent", stats.surbs_sent)?;
+        dict.set_item("surbs_remaining", stats.surbs_remaining())?;
+        dict.set_item("send_retries", stats.send_retries)?;
+        dict.set_item("send_failures", stats.send_failures)?;
+        dict.set_item("reconnects", stats.reconnects)?;
+        Ok(dict.into())
+    }
+
+    /// Resolves once the receive task has exited.
+    #[pyo3(name = "stop_listening")]
+    fn stop_listening<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
         let client = self.inner.clone();
         future_into_py(py, async move {
-            client.set_callback(py_callback).await;
+            client.stop_listening().await;
+            Ok(())
+        })
+    }
+
+    /// The callback is called as `callback(status, nym_address, reason)` where
+    /// `status` is `"connected"`, `"disconnected"` or `"reconnecting"`, and
+    /// `reason` says why the connection was dropped or is being remade:
+    /// `"connection_closed"`, `"resumed"` (from sleep), `"network_changed"`,
+    /// `"network_lost"`, `"network_restored"`, `"reconnect_failed"` or `"retry"`.
+    /// It is `None` when the client connects or is shut down.
+    #[pyo3(name = "set_status_callback")]
+    fn set_status_callback<'a>(&self, py: Python<'a>, py_callback: PyObject) -> PyResult<&'a PyAny> {
+        let client = self.inner.clone();
+        future_into_py(py, async move {
+            client.set_status_callback(py_callback).await;
             Ok(())
         })
     }
@@ -73,9 +204,801 @@ impl PyMixnetClient {
     }
 }
 
+/// Contact and message storage shared with the Rust side.
+#[pyclass]
+struct PyDb {
+    inner: Mutex<Option<Db>>,
+}
+
+fn db_error(e: impl std::fmt::Debug) -> PyErr {
+    pyo3::exceptions::PyRuntimeError::new_err(format!("Database error: {:?}", e))
+}
+
+impl PyDb {
+    fn with_db<T>(&self, f: impl FnOnce(&Db) -> Result<T, StorageError>) -> PyResult<T> {
+        let guard = self.inner.lock().map_err(db_error)?;
+        let db = guard
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Database is closed"))?;
+        f(db).map_err(db_error)
+    }
+}
+
+#[pymethods]
+impl PyDb {
+    /// Opens `<storage_dir>/<username>/<username>_client.db`.
+    #[staticmethod]
+    #[pyo3(signature = (username, storage_dir = "storage"))]
+    fn open(username: &str, storage_dir: &str) -> PyResult<Self> {
+        let db = Db::open(username, storage_dir).map_err(db_error)?;
+        Ok(Self { inner: Mutex::new(Some(db)) })
+    }
+
+    fn create_user_tables(&self, username: &str) -> PyResult<()> {
+        self.with_db(|db| db.create_user_tables(username))
+    }
+
+    fn register_user(&self, username: &str, public_key: &str) -> PyResult<()> {
+        self.with_db(|db| db.register_user(username, public_key))
+    }
+
+    fn get_all_users(&self) -> PyResult<Vec<ContactRow>> {
+        self.with_db(|db| db.get_all_users())
+    }
+
+    fn add_contact(&self, active_user: &str, username: &str, public_key: &str) -> PyResult<()> {
+        self.with_db(|db| db.add_contact(active_user, username, public_key))
+    }
+
+    fn record_key_change(
+        &self,
+        active_user: &str,
+        username: &str,
+        public_key: &str,
+        old_fingerprint: &str,
+        new_fingerprint: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.record_key_change(active_user, username, public_key, old_fingerprint, new_fingerprint))
+    }
+
+    fn reject_key_change(&self, active_user: &str, username: &str) -> PyResult<bool> {
+        self.with_db(|db| db.reject_key_change(active_user, username))
+    }
+
+    fn is_trusted(&self, active_user: &str, username: &str) -> PyResult<bool> {
+        self.with_db(|db| db.is_trusted(active_user, username))
+    }
+
+    fn set_trusted(&self, active_user: &str, username: &str, trusted: bool) -> PyResult<()> {
+        self.with_db(|db| db.set_trusted(active_user, username, trusted))
+    }
+
+    fn set_anonymous(&self, active_user: &str, username: &str, anonymous: bool) -> PyResult<()> {
+        self.with_db(|db| db.set_anonymous(active_user, username, anonymous))
+    }
+
+    fn get_anonymous_contacts(&self, active_user: &str) -> PyResult<Vec<String>> {
+        self.with_db(|db| db.get_anonymous_contacts(active_user))
+    }
+
+    fn is_verified(&self, active_user: &str, username: &str) -> PyResult<bool> {
+        self.with_db(|db| db.is_verified(active_user, username))
+    }
+
+    fn set_verified(&self, active_user: &str, username: &str, verified: bool) -> PyResult<()> {
+        self.with_db(|db| db.set_verified(active_user, username, verified))
+    }
+
+    /// Returns `(old_fingerprint, new_fingerprint, changed_at)` tuples, oldest first.
+    fn get_key_changes(&self, active_user: &str, username: &str) -> PyResult<Vec<KeyChangeRow>> {
+        self.with_db(|db| db.get_key_changes(active_user, username))
+    }
+
+    /// Returns `(old_fingerprint, new_fingerprint, old_public_key, changed_at)` tuples, oldest first.
+    fn get_key_history(&self, active_user: &str, username: &str) -> PyResult<Vec<KeyHistoryRow>> {
+        self.with_db(|db| db.get_key_history(active_user, username))
+    }
+
+    /// Saves tuples read by `get_key_history`, keeping their times.
+    fn import_key_history(&self, active_user: &str, username: &str, changes: Vec<KeyHistoryRow>) -> PyResult<usize> {
+        self.with_db(|db| db.import_key_history(active_user, username, &changes))
+    }
+
+    fn set_key_observation(&self, active_user: &str, username: &str, content: &str, signature: &str) -> PyResult<()> {
+        self.with_db(|db| db.set_key_observation(active_user, username, content, signature))
+    }
+
+    /// Returns `(content, signature)` of the signed `queryResponse` for `username`, if kept.
+    fn get_key_observation(&self, active_user: &str, username: &str) -> PyResult<Option<ObservationRow>> {
+        self.with_db(|db| db.get_key_observation(active_user, username))
+    }
+
+    fn append_audit_event(&self, active_user: &str, event: &str, detail: &str) -> PyResult<()> {
+        self.with_db(|db| db.append_audit_event(active_user, event, detail))
+    }
+
+    /// Returns `(id, event, detail, timestamp)` tuples, oldest first.
+    fn get_audit_log(&self, active_user: &str) -> PyResult<Vec<AuditRow>> {
+        self.with_db(|db| db.get_audit_log(active_user))
+    }
+
+    /// Returns the id of the first tampered audit entry, or `None` if the log is intact.
+    fn verify_audit_log(&self, active_user: &str) -> PyResult<Option<i64>> {
+        self.with_db(|db| db.verify_audit_log(active_user))
+    }
+
+    /// Returns what SQLite's integrity check found wrong; empty if the database is sound.
+    fn integrity_check(&self) -> PyResult<Vec<String>> {
+        self.with_db(|db| db.integrity_check())
+    }
+
+    fn get_contact(&self, active_user: &str, username: &str) -> PyResult<Option<ContactRow>> {
+        self.with_db(|db| db.get_contact(active_user, username))
+    }
+
+    fn get_all_contacts(&self, active_user: &str) -> PyResult<Vec<ContactRow>> {
+        self.with_db(|db| db.get_all_contacts(active_user))
+    }
+
+    fn set_share_presence(&self, active_user: &str, username: &str, share: bool) -> PyResult<()> {
+        self.with_db(|db| db.set_share_presence(active_user, username, share))
+    }
+
+    fn set_last_seen(&self, active_user: &str, username: &str, last_seen: i64) -> PyResult<()> {
+        self.with_db(|db| db.set_last_seen(active_user, username, last_seen))
+    }
+
+    /// Returns `(username, share_presence, last_seen)` for every contact.
+    fn get_presence(&self, active_user: &str) -> PyResult<Vec<PresenceRow>> {
+        self.with_db(|db| db.get_presence(active_user))
+    }
+
+    fn set_contact_privacy(
+        &self,
+        active_user: &str,
+        username: &str,
+        send_receipts: Option<bool>,
+        send_typing: Option<bool>,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.set_contact_privacy(active_user, username, send_receipts, send_typing))
+    }
+
+    /// Returns `(username, send_receipts, send_typing)` for contacts with their own settings.
+    fn get_contact_privacy(&self, active_user: &str) -> PyResult<Vec<ContactPrivacyRow>> {
+        self.with_db(|db| db.get_contact_privacy(active_user))
+    }
+
+    fn set_contact_handshake(
+        &self,
+        active_user: &str,
+        username: &str,
+        version: u32,
+        features: Vec<String>,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.set_contact_handshake(active_user, username, version, &features))
+    }
+
+    /// Returns `(version, features)` of the last handshake with `username`, or None.
+    fn get_contact_handshake(&self, active_user: &str, username: &str) -> PyResult<Option<HandshakeRow>> {
+        self.with_db(|db| db.get_contact_handshake(active_user, username))
+    }
+
+    /// Records the features a handshake with `username` dropped, until the
+    /// user accepts them; an empty list clears them.
+    fn set_handshake_downgrade(&self, active_user: &str, username: &str, lost: Vec<String>) -> PyResult<()> {
+        self.with_db(|db| db.set_handshake_downgrade(active_user, username, &lost))
+    }
+
+    /// Returns the features dropped with `username` the user has not accepted yet.
+    fn get_handshake_downgrade(&self, active_user: &str, username: &str) -> PyResult<Vec<String>> {
+        self.with_db(|db| db.get_handshake_downgrade(active_user, username))
+    }
+
+    /// Stores the `RatchetSession` JSON of `username`; None drops it.
+    #[pyo3(signature = (active_user, username, session = None))]
+    fn set_ratchet_session(&self, active_user: &str, username: &str, session: Option<&str>) -> PyResult<()> {
+        self.with_db(|db| db.set_ratchet_session(active_user, username, session))
+    }
+
+    /// Returns the `RatchetSession` JSON of `username`, or None.
+    fn get_ratchet_session(&self, active_user: &str, username: &str) -> PyResult<Option<String>> {
+        self.with_db(|db| db.get_ratchet_session(active_user, username))
+    }
+
+    /// Upgrades messages stored in an older format; `create_user_tables` does
+    /// this already. Returns how many rows were rewritten.
+    fn migrate_messages(&self, active_user: &str) -> PyResult<usize> {
+        self.with_db(|db| db.migrate_messages(active_user))
+    }
+
+    fn delete_contact(&self, active_user: &str, username: &str) -> PyResult<()> {
+        self.with_db(|db| db.delete_contact(active_user, username))
+    }
+
+    /// `kind` is `"text"`, `"location"`, `"forwarded"` or `"notice"`; the message is stored as text either way.
+    #[pyo3(signature = (active_user, username, msg_type, message, kind = "text"))]
+    fn save_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        msg_type: &str,
+        message: &str,
+        kind: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_message(active_user, username, msg_type, message, kind))
+    }
+
+    /// Saves `(username, type, message, kind)` tuples in a single transaction.
+    fn save_messages(&self, active_user: &str, messages: Vec<NewMessage>) -> PyResult<usize> {
+        self.with_db(|db| db.save_messages(active_user, &messages))
+    }
+
+    /// Saves a message sent to `username` under `send_id`; `status` is
+    /// "pending", "sent", "delivered" or "read".
+    #[pyo3(signature = (active_user, username, message, send_id, status, kind = "text"))]
+    fn save_sent_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        message: &str,
+        send_id: &str,
+        status: &str,
+        kind: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_sent_message(active_user, username, message, kind, send_id, status))
+    }
+
+    /// Moves the messages sent to `username` under `send_ids` on to `status`,
+    /// never back. Returns how many changed.
+    fn set_message_status(
+        &self,
+        active_user: &str,
+        username: &str,
+        send_ids: Vec<String>,
+        status: &str,
+    ) -> PyResult<usize> {
+        self.with_db(|db| db.set_message_status(active_user, username, &send_ids, status))
+    }
+
+    /// Returns `(id, send_id, status)` tuples of the messages sent to `username`, oldest first.
+    fn get_message_statuses(&self, active_user: &str, username: &str) -> PyResult<Vec<DeliveryRow>> {
+        self.with_db(|db| db.get_message_statuses(active_user, username))
+    }
+
+    /// Saves `(username, type, message, timestamp)` tuples, keeping their timestamps.
+    fn import_messages(&self, active_user: &str, messages: Vec<ConversationRow>) -> PyResult<usize> {
+        self.with_db(|db| db.import_messages(active_user, &messages))
+    }
+
+    /// Returns `(type, message, timestamp)` tuples, oldest first.
+    fn load_messages(&self, active_user: &str, username: &str) -> PyResult<Vec<MessageRow>> {
+        self.with_db(|db| db.load_messages(active_user, username))
+    }
+
+    /// Returns `(id, type, message, timestamp)` tuples older than `before_id`
+    /// (the newest when `None`), at most `limit`, oldest first.
+    #[pyo3(signature = (active_user, username, before_id = None, limit = 100))]
+    fn load_messages_before(
+        &self,
+        active_user: &str,
+        username: &str,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> PyResult<Vec<PagedMessageRow>> {
+        self.with_db(|db| db.load_messages_before(active_user, username, before_id, limit))
+    }
+
+    /// Returns `(sender, kind, message, attachments, timestamp)` tuples stamped
+    /// between `since` and `until` (stored timestamps, both included), oldest first.
+    #[pyo3(signature = (active_user, username, since = None, until = None))]
+    fn export_messages(
+        &self,
+        active_user: &str,
+        username: &str,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> PyResult<Vec<ExportRow>> {
+        self.with_db(|db| db.export_messages(active_user, username, since, until))
+    }
+
+    /// Returns `(chat, id, sender, snippet, timestamp)` tuples of messages in
+    /// any conversation matching every word of `query`, best first; matched
+    /// words in `snippet` are between "\x02" and "\x03".
+    #[pyo3(signature = (active_user, query, limit = 200))]
+    fn search_messages(&self, active_user: &str, query: &str, limit: usize) -> PyResult<Vec<SearchRow>> {
+        self.with_db(|db| db.search_messages(active_user, query, limit))
+    }
+
+    /// Deletes the stored copy of a message with `username` of `message_type`
+    /// ("to" or "from") and text `body`, shown as sent at `timestamp`.
+    /// Returns whether one was found.
+    fn delete_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        message_type: &str,
+        body: &str,
+        timestamp: &str,
+    ) -> PyResult<bool> {
+        self.with_db(|db| db.delete_message(active_user, username, message_type, body, timestamp))
+    }
+
+    fn get_all_messages(&self, active_user: &str) -> PyResult<Vec<ConversationRow>> {
+        self.with_db(|db| db.get_all_messages(active_user))
+    }
+
+    fn get_chat_usernames(&self, active_user: &str) -> PyResult<Vec<String>> {
+        self.with_db(|db| db.get_chat_usernames(active_user))
+    }
+
+    fn delete_all_messages(&self, active_user: &str) -> PyResult<()> {
+        self.with_db(|db| db.delete_all_messages(active_user))
+    }
+
+    fn save_group(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        name: &str,
+        session: &str,
+        membership: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_group(active_user, group_id, name, session, membership))
+    }
+
+    fn get_group(&self, active_user: &str, group_id: &str) -> PyResult<Option<StoredGroup>> {
+        self.with_db(|db| db.get_group(active_user, group_id))
+    }
+
+    fn get_groups(&self, active_user: &str) -> PyResult<Vec<GroupRow>> {
+        self.with_db(|db| db.get_groups(active_user))
+    }
+
+    fn save_group_message(&self, active_user: &str, group_id: &str, sender: &str, message: &str) -> PyResult<()> {
+        self.with_db(|db| db.save_group_message(active_user, group_id, sender, message))
+    }
+
+    fn import_group_messages(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        messages: Vec<GroupMessageRow>,
+    ) -> PyResult<usize> {
+        self.with_db(|db| db.import_group_messages(active_user, group_id, &messages))
+    }
+
+    fn load_group_messages(&self, active_user: &str, group_id: &str) -> PyResult<Vec<GroupMessageRow>> {
+        self.with_db(|db| db.load_group_messages(active_user, group_id))
+    }
+
+    fn delete_group_message(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        sender: &str,
+        body: &str,
+        timestamp: &str,
+    ) -> PyResult<bool> {
+        self.with_db(|db| db.delete_group_message(active_user, group_id, sender, body, timestamp))
+    }
+
+    #[pyo3(signature = (active_user, group_id, since = None, until = None))]
+    fn export_group_messages(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> PyResult<Vec<ExportRow>> {
+        self.with_db(|db| db.export_group_messages(active_user, group_id, since, until))
+    }
+
+    fn append_group_log(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        seq: u64,
+        entry: &str,
+        signature: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.append_group_log(active_user, group_id, seq, entry, signature))
+    }
+
+    fn get_group_log(&self, active_user: &str, group_id: &str) -> PyResult<Vec<GroupLogRow>> {
+        self.with_db(|db| db.get_group_log(active_user, group_id))
+    }
+
+    #[pyo3(signature = (active_user, secret_hash, group_id, expires_at, single_use))]
+    fn save_group_invite(
+        &self,
+        active_user: &str,
+        secret_hash: &str,
+        group_id: &str,
+        expires_at: Option<i64>,
+        single_use: bool,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_group_invite(active_user, secret_hash, group_id, expires_at, single_use))
+    }
+
+    fn redeem_group_invite(&self, active_user: &str, secret_hash: &str, group_id: &str, now: i64) -> PyResult<bool> {
+        self.with_db(|db| db.redeem_group_invite(active_user, secret_hash, group_id, now))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn save_poll(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        poll_id: &str,
+        creator: &str,
+        question: &str,
+        options: &str,
+        multiple: bool,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_poll(active_user, group_id, poll_id, creator, question, options, multiple))
+    }
+
+    fn get_polls(&self, active_user: &str, group_id: &str) -> PyResult<Vec<PollRow>> {
+        self.with_db(|db| db.get_polls(active_user, group_id))
+    }
+
+    fn save_poll_vote(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        poll_id: &str,
+        voter: &str,
+        choices: &str,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.save_poll_vote(active_user, group_id, poll_id, voter, choices))
+    }
+
+    fn get_poll_votes(&self, active_user: &str, group_id: &str, poll_id: &str) -> PyResult<Vec<PollVoteRow>> {
+        self.with_db(|db| db.get_poll_votes(active_user, group_id, poll_id))
+    }
+
+    fn set_sender_tag(&self, active_user: &str, username: &str, sender_tag: &str, nym_address: &str) -> PyResult<()> {
+        self.with_db(|db| db.set_sender_tag(active_user, username, sender_tag, nym_address))
+    }
+
+    /// Returns `(username, sender_tag)` tuples of the tags received at `nym_address`.
+    fn get_sender_tags(&self, active_user: &str, nym_address: &str) -> PyResult<Vec<SenderTagRow>> {
+        self.with_db(|db| db.get_sender_tags(active_user, nym_address))
+    }
+
+    fn delete_sender_tag(&self, active_user: &str, username: &str) -> PyResult<()> {
+        self.with_db(|db| db.delete_sender_tag(active_user, username))
+    }
+
+    /// Queues a message for when the mixnet is back and returns its outbox id.
+    fn queue_outbox(&self, active_user: &str, chat: &str, content: &str) -> PyResult<i64> {
+        self.with_db(|db| db.queue_outbox(active_user, chat, content))
+    }
+
+    /// Returns `(id, chat, content)` tuples of the queued messages, oldest first.
+    fn get_outbox(&self, active_user: &str) -> PyResult<Vec<OutboxRow>> {
+        self.with_db(|db| db.get_outbox(active_user))
+    }
+
+    fn delete_outbox(&self, active_user: &str, id: i64) -> PyResult<()> {
+        self.with_db(|db| db.delete_outbox(active_user, id))
+    }
+
+    /// Keeps a message that failed to parse, decrypt or verify; returns its id.
+    #[pyo3(signature = (active_user, sender, reason, content))]
+    fn quarantine(&self, active_user: &str, sender: Option<&str>, reason: &str, content: &str) -> PyResult<i64> {
+        self.with_db(|db| db.quarantine(active_user, sender, reason, content))
+    }
+
+    /// Returns `(id, sender, reason, content, received_at)` tuples of the quarantined messages, newest first.
+    fn get_quarantine(&self, active_user: &str) -> PyResult<Vec<QuarantineRow>> {
+        self.with_db(|db| db.get_quarantine(active_user))
+    }
+
+    fn count_quarantine(&self, active_user: &str) -> PyResult<u64> {
+        self.with_db(|db| db.count_quarantine(active_user))
+    }
+
+    fn delete_quarantined(&self, active_user: &str, id: i64) -> PyResult<()> {
+        self.with_db(|db| db.delete_quarantined(active_user, id))
+    }
+
+    fn clear_quarantine(&self, active_user: &str) -> PyResult<()> {
+        self.with_db(|db| db.clear_quarantine(active_user))
+    }
+
+    fn get_setting(&self, active_user: &str, key: &str) -> PyResult<Option<String>> {
+        self.with_db(|db| db.get_setting(active_user, key))
+    }
+
+    fn set_setting(&self, active_user: &str, key: &str, value: &str) -> PyResult<()> {
+        self.with_db(|db| db.set_setting(active_user, key, value))
+    }
+
+    /// Returns False if there is a folder of that name already.
+    fn create_folder(&self, active_user: &str, name: &str) -> PyResult<bool> {
+        self.with_db(|db| db.create_folder(active_user, name))
+    }
+
+    /// Returns False if `old` does not exist or `new` does.
+    fn rename_folder(&self, active_user: &str, old: &str, new: &str) -> PyResult<bool> {
+        self.with_db(|db| db.rename_folder(active_user, old, new))
+    }
+
+    fn delete_folder(&self, active_user: &str, name: &str) -> PyResult<()> {
+        self.with_db(|db| db.delete_folder(active_user, name))
+    }
+
+    /// Returns `(name, collapsed)` tuples in sidebar order.
+    fn get_folders(&self, active_user: &str) -> PyResult<Vec<FolderRow>> {
+        self.with_db(|db| db.get_folders(active_user))
+    }
+
+    fn set_folder_collapsed(&self, active_user: &str, name: &str, collapsed: bool) -> PyResult<()> {
+        self.with_db(|db| db.set_folder_collapsed(active_user, name, collapsed))
+    }
+
+    /// Files `chat` (a username or `group:<id>`) in `folder`, or in none when `None`.
+    #[pyo3(signature = (active_user, chat, folder = None))]
+    fn set_chat_folder(&self, active_user: &str, chat: &str, folder: Option<&str>) -> PyResult<()> {
+        self.with_db(|db| db.set_chat_folder(active_user, chat, folder))
+    }
+
+    /// Returns `(chat, folder)` tuples.
+    fn get_chat_folders(&self, active_user: &str) -> PyResult<Vec<ChatFolderRow>> {
+        self.with_db(|db| db.get_chat_folders(active_user))
+    }
+
+    /// `level` is "all", "mentions" or "none"; `muted_until` is in Unix
+    /// seconds, -1 for no end, or None when the chat is not muted.
+    #[pyo3(signature = (active_user, chat, level, muted_until = None))]
+    fn set_chat_notifications(
+        &self,
+        active_user: &str,
+        chat: &str,
+        level: &str,
+        muted_until: Option<i64>,
+    ) -> PyResult<()> {
+        self.with_db(|db| db.set_chat_notifications(active_user, chat, level, muted_until))
+    }
+
+    /// Returns `(chat, level, muted_until)` tuples of chats with a setting of their own.
+    fn get_chat_notifications(&self, active_user: &str) -> PyResult<Vec<NotificationRow>> {
+        self.with_db(|db| db.get_chat_notifications(active_user))
+    }
+
+    /// Keeps `text` as the unsent draft of `chat`; an empty one drops it.
+    fn save_draft(&self, active_user: &str, chat: &str, text: &str) -> PyResult<()> {
+        self.with_db(|db| db.save_draft(active_user, chat, text))
+    }
+
+    /// Returns `(chat, text)` tuples of unsent drafts, most recently changed first.
+    fn get_drafts(&self, active_user: &str) -> PyResult<Vec<DraftRow>> {
+        self.with_db(|db| db.get_drafts(active_user))
+    }
+
+    fn close(&self) -> PyResult<()> {
+        self.inner.lock().map_err(db_error)?.take();
+        Ok(())
+    }
+}
+
+/// Encodes 16 to 32 bytes of entropy as a BIP39 English mnemonic.
+#[pyfunction]
+fn mnemonic_from_entropy(entropy: &[u8]) -> PyResult<String> {
+    bip39::Mnemonic::from_entropy(entropy)
+        .map(|mnemonic| mnemonic.to_string())
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("Invalid entropy: {}", e)))
+}
+
+/// Checks a BIP39 mnemonic (words and checksum) and returns its 64-byte seed.
+#[pyfunction]
+fn mnemonic_to_seed(py: Python<'_>, mnemonic: &str) -> PyResult<Py<PyBytes>> {
+    let mnemonic = bip39::Mnemonic::parse(mnemonic.trim().to_lowercase())
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("Invalid mnemonic: {}", e)))?;
+    Ok(PyBytes::new(py, &mnemonic.to_seed("")).into())
+}
+
+/// Solves a registration proof of work, releasing the GIL while it hashes.
+#[pyfunction]
+fn solve_pow(py: Python<'_>, nonce: &str, difficulty: u32) -> u64 {
+    py.allow_threads(|| pow::solve(nonce, difficulty))
+}
+
+/// The compact form of the JSON envelope `json`, or None if it has none.
+#[pyfunction]
+fn encode_envelope(json: &str) -> Option<String> {
+    envelope::encode(json)
+}
+
+/// The JSON envelope `raw` stands for: `raw` itself unless it is compact.
+#[pyfunction]
+fn decode_envelope(raw: &str) -> PyResult<String> {
+    envelope::decode(raw)
+        .map(|json| json.into_owned())
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+}
+
+fn keystore_error(e: StorageError) -> PyErr {
+    match e {
+        StorageError::Passphrase(_) => pyo3::exceptions::PyValueError::new_err("Wrong passphrase"),
+        e => pyo3::exceptions::PyRuntimeError::new_err(e.to_string()),
+    }
+}
+
+/// Whether `username` keeps their private key in a passphrase-encrypted keystore.
+#[pyfunction]
+fn keystore_exists(storage_dir: &str, username: &str) -> bool {
+    keystore::exists(storage_dir, username)
+}
+
+/// Stores `private_key_pem` (PKCS#8) in the keystore of `username`, encrypted with `passphrase`.
+#[pyfunction]
+fn keystore_save(
+    py: Python<'_>,
+    storage_dir: &str,
+    username: &str,
+    private_key_pem: &str,
+    passphrase: &str,
+) -> PyResult<()> {
+    let key = Crypto::private_key_from_pem(private_key_pem)
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+    py.allow_threads(|| keystore::save(storage_dir, username, &key, passphrase)).map_err(keystore_error)
+}
+
+/// The PKCS#8 PEM private key of `username`; raises ValueError if `passphrase` is wrong.
+#[pyfunction]
+fn keystore_load(py: Python<'_>, storage_dir: &str, username: &str, passphrase: &str) -> PyResult<String> {
+    let key = py.allow_threads(|| keystore::load(storage_dir, username, passphrase)).map_err(keystore_error)?;
+    Crypto::private_key_pem(&key).map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+}
+
+/// Moves the plain private key file of `username` into a keystore encrypted with `passphrase`.
+#[pyfunction]
+fn keystore_protect(py: Python<'_>, storage_dir: &str, username: &str, passphrase: &str) -> PyResult<()> {
+    py.allow_threads(|| keystore::protect(storage_dir, username, passphrase)).map_err(keystore_error)
+}
+
+fn transfer_error(e: Error) -> PyErr {
+    match e {
+        Error::Storage(e) => pyo3::exceptions::PyOSError::new_err(e.to_string()),
+        e => pyo3::exceptions::PyValueError::new_err(e.to_string()),
+    }
+}
+
+/// A file being sent in chunks; see `nymchat_core::transfer`.
+#[pyclass]
+struct FileUpload {
+    inner: Upload,
+}
+
+#[pymethods]
+impl FileUpload {
+    /// Opens the file at `path` to send it as `mime`. Raises OSError if it
+    /// cannot be read and ValueError if it is too large.
+    #[staticmethod]
+    fn open(py: Python<'_>, path: &str, mime: &str) -> PyResult<Self> {
+        let inner = py.allow_threads(|| Upload::open(Path::new(path), mime)).map_err(transfer_error)?;
+        Ok(Self { inner })
+    }
+
+    /// The manifest to send first, as JSON.
+    fn manifest(&self) -> String {
+        self.inner.manifest().to_json()
+    }
+
+    #[getter]
+    fn chunks(&self) -> u32 {
+        self.inner.manifest().chunks
+    }
+
+    /// Chunk `seq` as JSON.
+    fn chunk(&mut self, py: Python<'_>, seq: u32) -> PyResult<String> {
+        let chunk = py.allow_threads(|| self.inner.chunk(seq)).map_err(transfer_error)?;
+        Ok(chunk.to_json())
+    }
+}
+
+/// A file being received in chunks; see `nymchat_core::transfer`.
+#[pyclass]
+struct FileDownload {
+    inner: Option<Download>,
+}
+
+impl FileDownload {
+    fn download(&mut self) -> PyResult<&mut Download> {
+        self.inner.as_mut().ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Download is finished"))
+    }
+}
+
+#[pymethods]
+impl FileDownload {
+    /// Starts receiving the file the JSON `manifest` describes into
+    /// `downloads_dir`. Raises ValueError if the manifest is not acceptable.
+    #[staticmethod]
+    fn start(downloads_dir: &str, manifest: &str) -> PyResult<Self> {
+        let manifest = Manifest::from_json(manifest).map_err(transfer_error)?;
+        let inner = Download::start(Path::new(downloads_dir), manifest).map_err(transfer_error)?;
+        Ok(Self { inner: Some(inner) })
+    }
+
+    /// The file's name, as it will be saved if it is free.
+    #[getter]
+    fn name(&mut self) -> PyResult<String> {
+        Ok(self.download()?.manifest().name.clone())
+    }
+
+    #[getter]
+    fn chunks(&mut self) -> PyResult<u32> {
+        Ok(self.download()?.manifest().chunks)
+    }
+
+    #[getter]
+    fn received(&mut self) -> PyResult<u32> {
+        Ok(self.download()?.received())
+    }
+
+    fn is_complete(&mut self) -> PyResult<bool> {
+        Ok(self.download()?.is_complete())
+    }
+
+    /// Adds the JSON `chunk`; returns whether it was new. Raises ValueError
+    /// if it is not part of this file or does not decrypt.
+    fn add(&mut self, py: Python<'_>, chunk: &str) -> PyResult<bool> {
+        let chunk = Chunk::from_json(chunk).map_err(transfer_error)?;
+        let download = self.download()?;
+        py.allow_threads(|| download.add(&chunk)).map_err(transfer_error)
+    }
+
+    /// Checks the complete file and moves it into the downloads directory;
+    /// returns its path. Raises ValueError if it does not match the manifest.
+    fn finish(&mut self, py: Python<'_>) -> PyResult<String> {
+        let download =
+            self.inner.take().ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Download is finished"))?;
+        let path = py.allow_threads(|| download.finish()).map_err(transfer_error)?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Gives the download up and removes what was received of it.
+    fn abort(&mut self) {
+        self.inner.take();
+    }
+}
+
 #[pymodule]
 fn async_ffi(_py: Python, m: &PyModule) -> PyResult<()> {
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
     m.add_class::<PyMixnetClient>()?;
+    m.add_class::<PyDb>()?;
+    m.add_class::<Envelope>()?;
+    m.add_class::<ChatPayload>()?;
+    m.add_class::<MessageBody>()?;
+    m.add_class::<Encrypted>()?;
+    m.add_class::<EncryptedBody>()?;
+    m.add_class::<GroupSession>()?;
+    m.add_class::<RatchetSession>()?;
+    m.add_class::<MembershipLog>()?;
+    m.add_class::<ContactInvite>()?;
+    m.add_class::<Delegation>()?;
+    m.add_class::<Hello>()?;
+    m.add_class::<Negotiated>()?;
+    m.add_class::<IncomingMessage>()?;
+    m.add_class::<FileUpload>()?;
+    m.add_class::<FileDownload>()?;
+    m.add_function(wrap_pyfunction!(solve_pow, m)?)?;
+    m.add_function(wrap_pyfunction!(mnemonic_from_entropy, m)?)?;
+    m.add_function(wrap_pyfunction!(mnemonic_to_seed, m)?)?;
+    m.add_function(wrap_pyfunction!(encode_envelope, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_envelope, m)?)?;
+    m.add_function(wrap_pyfunction!(keystore_exists, m)?)?;
+    m.add_function(wrap_pyfunction!(keystore_save, m)?)?;
+    m.add_function(wrap_pyfunction!(keystore_load, m)?)?;
+    m.add_function(wrap_pyfunction!(keystore_protect, m)?)?;
+    m.add("COMPACT_FEATURE", envelope::COMPACT_FEATURE)?;
+    m.add("RATCHET_FEATURE", ratchet::RATCHET_FEATURE)?;
+    m.add("MAX_FILE_SIZE", transfer::MAX_FILE_SIZE)?;
     Ok(())
 }
 
@@ -1,126 +1,542 @@
 use futures::StreamExt;
-use nym_sdk::mixnet::{MixnetClient, MixnetClientSender, MixnetMessageSender, Recipient};
+use nym_sdk::mixnet::{
+    AnonymousSenderTag, IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender,
+    Recipient, ReconstructedMessage, StoragePaths,
+};
+use nym_sdk::NymNetworkDetails;
+use nymchat_protocol::NetworkConfig;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug_span, info_span, Instrument};
 use pyo3::prelude::*;
 use anyhow::Context;
+use crate::incoming::IncomingMessage;
+use crate::incoming_queue::{IncomingQueue, OverflowPolicy, QueueStats};
+use crate::network_watch::{self, NetworkChange, NetworkWatch};
+use crate::traffic::{Traffic, TrafficStats};
+
+/// Python callable invoked for every received message.
+struct MessageCallback {
+    callback: PyObject,
+    /// Deliver `IncomingMessage` objects instead of raw strings.
+    typed: bool,
+}
+
+/// Connection state reported to the status callback.
+#[derive(Clone, Copy, Debug)]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+impl ConnectionStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionStatus::Connected => "connected",
+            ConnectionStatus::Disconnected => "disconnected",
+            ConnectionStatus::Reconnecting => "reconnecting",
+        }
+    }
+}
+
+/// Reply SURBs attached to a plain `send_message` call.
+const DEFAULT_SURB_COUNT: u32 = 10;
+/// Reconnection attempts after the gateway connection drops.
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// Delay before the first reconnection attempt; doubled after each failure.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(2);
+/// After giving up on reconnecting, when to try again if the network has not
+/// changed meanwhile.
+const RECONNECT_IDLE_RETRY: Duration = Duration::from_secs(5 * 60);
+/// How long closing a connection the network dropped under it may take.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Attempts at handing a message to the mixnet client before giving up.
+const SEND_ATTEMPTS: u32 = 3;
+/// Delay before retrying a failed send; doubled after each failure.
+const SEND_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Where a message goes: a nym address, with reply SURBs attached, or the
+/// sender behind a tag, through its SURBs.
+#[derive(Clone, Copy)]
+enum Destination {
+    Address(Recipient, u32),
+    Reply(AnonymousSenderTag),
+}
+
+/// Background tasks started by `receive_messages`: the listener reading the
+/// mixnet into the incoming queue, the dispatcher handing queued messages to
+/// the Python callback, and the watcher telling the listener when the network
+/// changed.
+struct Listener {
+    cancel: CancellationToken,
+    handle: JoinHandle<()>,
+    dispatcher: JoinHandle<()>,
+    watcher: JoinHandle<()>,
+}
 
 pub struct MixnetHandler {
     client: Arc<Mutex<Option<MixnetClient>>>,
-    sender: MixnetClientSender,
-    message_callback: Arc<Mutex<Option<PyObject>>>,
-    listening: Arc<Mutex<bool>>,
-    shutdown_signal: Arc<Notify>,
+    sender: Arc<RwLock<MixnetClientSender>>,
+    nym_address: Arc<RwLock<Option<String>>>,
+    message_callback: Arc<Mutex<Option<MessageCallback>>>,
+    status_callback: Arc<Mutex<Option<PyObject>>>,
+    incoming: Arc<IncomingQueue>,
+    listener: Mutex<Option<Listener>>,
+    traffic: Arc<Traffic>,
+    /// Where the client's keys are kept; `None` for an ephemeral identity.
+    data_dir: Option<PathBuf>,
 }
 
-impl MixnetHandler {
-    /// Creates a new Mixnet client.
-    pub async fn new() -> anyhow::Result<Self> {
-        let client = nym_sdk::mixnet::MixnetClientBuilder::new_ephemeral()
+/// Details of the network `NYM_NETWORK` selects, mainnet by default.
+fn network_details() -> anyhow::Result<NymNetworkDetails> {
+    let network = NetworkConfig::from_env()?;
+    if network.is_mainnet() {
+        return Ok(NymNetworkDetails::new_mainnet());
+    }
+    network.export_to_env()?;
+    Ok(NymNetworkDetails::new_from_env())
+}
+
+/// Builds a client and connects it to the mixnet. With a `data_dir` the keys
+/// are loaded from (or created in) it, so the nym address stays the same
+/// across connections; otherwise the identity is ephemeral.
+async fn connect(data_dir: Option<&Path>) -> anyhow::Result<MixnetClient> {
+    let network = network_details()?;
+    let client = match data_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).context("Failed to create the nym data directory")?;
+            let storage = StoragePaths::new_from_dir(dir).context("Failed to open the nym data directory")?;
+            nym_sdk::mixnet::MixnetClientBuilder::new_with_default_storage(storage)
+                .await
+                .context("Failed to load the nym identity")?
+                .network_details(network)
+                .build()
+                .context("Failed to build client")?
+        }
+        None => nym_sdk::mixnet::MixnetClientBuilder::new_ephemeral()
+            .network_details(network)
             .build()
-            .context("Failed to build ephemeral client")?
-            .connect_to_mixnet()
-            .await
-            .context("Failed to connect to mixnet")?;
+            .context("Failed to build ephemeral client")?,
+    };
+    client.connect_to_mixnet().await.context("Failed to connect to mixnet")
+}
+
+/// Invokes the Python status callback, if one is set, with
+/// `(status, nym_address, reason)`.
+async fn emit_status(
+    callback: &Mutex<Option<PyObject>>,
+    status: ConnectionStatus,
+    nym_address: Option<String>,
+    reason: Option<&str>,
+) {
+    let callback = callback.lock().await;
+    if let Some(ref callback) = *callback {
+        pyo3::Python::with_gil(|py| {
+            if let Err(e) = callback.call1(py, (status.as_str(), nym_address, reason)) {
+                e.print(py);
+            }
+        });
+    }
+}
+
+/// What woke the listener loop up.
+enum ListenerEvent {
+    Shutdown,
+    Message(ReconstructedMessage),
+    StreamClosed,
+    NetworkChanged(NetworkChange),
+}
+
+impl MixnetHandler {
+    /// Creates a new Mixnet client. Up to `capacity` received messages wait for
+    /// the Python callback before `overflow` applies. The identity is kept in
+    /// `data_dir` if one is given, and is ephemeral otherwise.
+    pub async fn new(capacity: usize, overflow: OverflowPolicy, data_dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let client = connect(data_dir.as_deref()).await?;
 
         let sender = client.split_sender();
+        let nym_address = client.nym_address().to_string();
         Ok(Self {
             client: Arc::new(Mutex::new(Some(client))),
-            sender,
+            sender: Arc::new(RwLock::new(sender)),
+            nym_address: Arc::new(RwLock::new(Some(nym_address))),
             message_callback: Arc::new(Mutex::new(None)),
-            listening: Arc::new(Mutex::new(false)),
-            shutdown_signal: Arc::new(Notify::new()),
+            status_callback: Arc::new(Mutex::new(None)),
+            incoming: Arc::new(IncomingQueue::new(capacity, overflow)),
+            listener: Mutex::new(None),
+            traffic: Arc::new(Traffic::new()),
+            data_dir,
         })
     }
 
-    pub async fn set_callback(&self, callback: PyObject) {
+    pub async fn set_callback(&self, callback: PyObject, typed: bool) {
         let mut cb = self.message_callback.lock().await;
-        *cb = Some(callback);
+        *cb = Some(MessageCallback { callback, typed });
+    }
+
+    /// Sets the connection status callback and immediately reports the current state.
+    pub async fn set_status_callback(&self, callback: PyObject) {
+        {
+            let mut cb = self.status_callback.lock().await;
+            *cb = Some(callback);
+        }
+        let nym_address = self.get_nym_address().await;
+        let status = if nym_address.is_some() {
+            ConnectionStatus::Connected
+        } else {
+            ConnectionStatus::Disconnected
+        };
+        emit_status(&self.status_callback, status, nym_address, None).await;
+    }
+
+    pub fn incoming_stats(&self) -> QueueStats {
+        self.incoming.stats()
+    }
+
+    pub fn traffic_stats(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// How long the current gateway connection has been up.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.traffic.uptime()
     }
 
     pub async fn get_nym_address(&self) -> Option<String> {
-        let lock = self.client.lock().await;
-        lock.as_ref().map(|c| c.nym_address().to_string())
+        self.nym_address.read().await.clone()
     }
 
     pub async fn send_message(&self, recipient: &str, message: &str) -> anyhow::Result<()> {
-    let parsed_recipient = recipient
-        .parse::<Recipient>()
-        .context("Failed to parse recipient address")?;
-
-    println!("🚀 Sending message to: {}", recipient);
-
-    self.sender
-        .send_message(
-            parsed_recipient,
-            message.as_bytes().to_vec(),
-            nym_sdk::mixnet::IncludedSurbs::Amount(10), // 👈 Corrected here
-        )
-        .await
-        .context("Failed to send message with SURBs")?;
-
-    println!("✅ Message sent successfully with 10 SURBs included!");
-    Ok(())
-}
+        self.send_with_surbs(recipient, message, DEFAULT_SURB_COUNT).await
+    }
+
+    /// Sends a message to a nym address, attaching `surb_count` reply SURBs.
+    pub async fn send_with_surbs(
+        &self,
+        recipient: &str,
+        message: &str,
+        surb_count: u32,
+    ) -> anyhow::Result<()> {
+        let parsed_recipient = recipient
+            .parse::<Recipient>()
+            .context("Failed to parse recipient address")?;
 
+        println!("🚀 Sending message to: {}", recipient);
 
+        self.send_to(Destination::Address(parsed_recipient, surb_count), message)
+            .await
+            .context("Failed to send message with SURBs")?;
+
+        println!("✅ Message sent successfully with {} SURBs included!", surb_count);
+        Ok(())
+    }
+
+    /// Replies anonymously to the sender identified by a base58 sender tag,
+    /// using one of the SURBs they attached.
+    pub async fn reply_with_surb(&self, sender_tag: &str, message: &str) -> anyhow::Result<()> {
+        let parsed_tag = AnonymousSenderTag::try_from_base58_string(sender_tag)
+            .context("Failed to parse sender tag")?;
+
+        println!("↩️ Replying to sender tag: {}", sender_tag);
+
+        self.send_to(Destination::Reply(parsed_tag), message)
+            .await
+            .context("Failed to send reply via SURB")?;
+
+        println!("✅ Reply sent successfully!");
+        Ok(())
+    }
+
+    /// Hands `message` to the mixnet client, retrying with backoff. Sends fail
+    /// while the client cannot build routes or SURBs, typically when the
+    /// topology epoch has just changed and it has yet to fetch the new one;
+    /// waiting gives it the time to, and the sender is looked up again on
+    /// every attempt in case the connection was replaced meanwhile.
+    #[tracing::instrument(skip_all, fields(bytes = message.len()))]
+    async fn send_to(&self, destination: Destination, message: &str) -> nym_sdk::Result<()> {
+        let mut delay = SEND_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let sender = self.sender.read().await;
+            let sent = match destination {
+                Destination::Address(recipient, surbs) => {
+                    sender.send_message(recipient, message.as_bytes(), IncludedSurbs::Amount(surbs)).await
+                }
+                Destination::Reply(tag) => sender.send_reply(tag, message.as_bytes()).await,
+            };
+            drop(sender);
+            match sent {
+                Ok(()) => {
+                    let surbs = match destination {
+                        Destination::Address(_, surbs) => surbs,
+                        Destination::Reply(_) => 0,
+                    };
+                    self.traffic.sent(message.len(), surbs);
+                    return Ok(());
+                }
+                Err(e) if attempt < SEND_ATTEMPTS => {
+                    println!("⚠️ Send failed ({}), retrying in {:?}...", e, delay);
+                    self.traffic.retried();
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.traffic.send_failed();
+                    return Err(e);
+                }
+            }
+        }
+    }
 
     pub async fn receive_messages(&self) {
-        let mut listening = self.listening.lock().await;
-        if *listening {
+        let mut listener = self.listener.lock().await;
+        if listener.as_ref().is_some_and(|l| !l.handle.is_finished()) {
             println!("⚠️ Listener already running, skipping...");
             return;
         }
-        *listening = true;
-        drop(listening);
 
         let client_ref = Arc::clone(&self.client);
-        let callback_ref = Arc::clone(&self.message_callback);
-        let shutdown_signal = Arc::clone(&self.shutdown_signal);
+        let sender_ref = Arc::clone(&self.sender);
+        let address_ref = Arc::clone(&self.nym_address);
+        let status_ref = Arc::clone(&self.status_callback);
+        let incoming = Arc::clone(&self.incoming);
+        let traffic = Arc::clone(&self.traffic);
+        let data_dir = self.data_dir.clone();
+        let cancel = CancellationToken::new();
+        let shutdown_signal = cancel.clone();
+        let dispatcher = tokio::spawn(
+            dispatch(Arc::clone(&self.incoming), Arc::clone(&self.message_callback), cancel.clone())
+                .instrument(info_span!("dispatcher")),
+        );
+        let (change_sender, mut changes) = mpsc::unbounded_channel();
+        let probe_host = NetworkConfig::from_env().map(|network| network_watch::api_host(&network)).unwrap_or_default();
+        let watcher = tokio::spawn(
+            network_watch::run(NetworkWatch::new(probe_host), change_sender, cancel.clone())
+                .instrument(info_span!("network_watch")),
+        );
 
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             let mut lock = client_ref.lock().await;
-            if let Some(client) = lock.as_mut() {
-                println!("📡 Listening for incoming messages...");
-                loop {
-                    tokio::select! {
-                        _ = shutdown_signal.notified() => {
-                            println!("🛑 Listener stopping...");
-                            break;
+            println!("📡 Listening for incoming messages...");
+            while let Some(client) = lock.as_mut() {
+                let event = tokio::select! {
+                    _ = shutdown_signal.cancelled() => ListenerEvent::Shutdown,
+                    received = client.next() => match received {
+                        Some(received) => ListenerEvent::Message(received),
+                        None => ListenerEvent::StreamClosed,
+                    },
+                    Some(change) = changes.recv() => ListenerEvent::NetworkChanged(change),
+                };
+
+                let (reason, network_lost) = match event {
+                    ListenerEvent::Shutdown => {
+                        println!("🛑 Listener stopping...");
+                        break;
+                    }
+                    ListenerEvent::Message(received) => {
+                        if received.message.is_empty() {
+                            continue;
+                        }
+                        let msg_str = String::from_utf8_lossy(&received.message).to_string();
+                        let sender_tag = received.sender_tag.map(|t| t.to_base58_string());
+                        traffic.received(received.message.len(), sender_tag.is_none());
+                        // Under the block policy this waits for the dispatcher,
+                        // which holds back reading from the mixnet.
+                        let push = incoming.push(IncomingMessage::new(msg_str, sender_tag));
+                        tokio::select! {
+                            _ = shutdown_signal.cancelled() => break,
+                            _ = push.instrument(debug_span!("enqueue")) => {}
                         }
-                        received = client.next() => {
-                            if let Some(received) = received {
-                                if !received.message.is_empty() {
-                                    let msg_str = String::from_utf8_lossy(&received.message).to_string();
-                                    let callback = callback_ref.lock().await;
-                                    pyo3::Python::with_gil(|py| {
-                                        if let Some(ref callback) = *callback {
-                                            if let Err(e) = callback.call1(py, (&msg_str,)) {
-                                                e.print(py);
-                                            }
-                                        } else {
-                                            println!("📩 Received: {}", msg_str);
-                                        }
-                                    });
-                                }
+                        continue;
+                    }
+                    ListenerEvent::StreamClosed => {
+                        println!("⚠️ Mixnet connection lost.");
+                        lock.take();
+                        ("connection_closed", false)
+                    }
+                    // Only follows a loss, which dropped the connection already.
+                    ListenerEvent::NetworkChanged(NetworkChange::Restored) => continue,
+                    ListenerEvent::NetworkChanged(change) => {
+                        // The socket may take minutes to notice; do not wait for it.
+                        println!("🌐 {}, dropping the gateway connection.", change);
+                        if let Some(client) = lock.take() {
+                            if tokio::time::timeout(DISCONNECT_TIMEOUT, client.disconnect()).await.is_err() {
+                                println!("⚠️ Old connection did not close in time, abandoning it.");
                             }
                         }
+                        (change.reason(), change == NetworkChange::Lost)
                     }
+                };
+
+                traffic.disconnected();
+                *address_ref.write().await = None;
+                emit_status(&status_ref, ConnectionStatus::Disconnected, None, Some(reason)).await;
+
+                let reconnected =
+                    reestablish(data_dir.as_deref(), &status_ref, &mut changes, reason, network_lost, &shutdown_signal);
+                match reconnected.await {
+                    Some(client) => {
+                        let nym_address = client.nym_address().to_string();
+                        *sender_ref.write().await = client.split_sender();
+                        *address_ref.write().await = Some(nym_address.clone());
+                        *lock = Some(client);
+                        traffic.reconnected();
+                        // Whatever changed while reconnecting, the new connection already saw.
+                        while changes.try_recv().is_ok() {}
+                        println!("✅ Reconnected as {}", nym_address);
+                        emit_status(&status_ref, ConnectionStatus::Connected, Some(nym_address), Some(reason)).await;
+                    }
+                    None => break,
                 }
             }
-        });
+        }.instrument(info_span!("listener")));
+        *listener = Some(Listener { cancel, handle, dispatcher, watcher });
+    }
+
+    /// Cancels the receive task and returns once it has exited and released the client.
+    /// `receive_messages` can be called again afterwards.
+    pub async fn stop_listening(&self) {
+        let listener = self.listener.lock().await.take();
+        if let Some(Listener { cancel, handle, dispatcher, watcher }) = listener {
+            cancel.cancel();
+            if let Err(e) = handle.await {
+                println!("❌ Listener task failed: {:?}", e);
+            }
+            if let Err(e) = dispatcher.await {
+                println!("❌ Dispatcher task failed: {:?}", e);
+            }
+            if let Err(e) = watcher.await {
+                println!("❌ Network watcher task failed: {:?}", e);
+            }
+        }
     }
 
+    /// Stops the listener, waits for it to exit and disconnects the client.
     pub async fn disconnect(&self) {
         println!("🚪 Stopping background tasks...");
-        self.shutdown_signal.notify_waiters();
+        self.stop_listening().await;
 
         let mut lock = self.client.lock().await;
         if let Some(client) = lock.take() {
             println!("🔌 Disconnecting Mixnet client...");
             client.disconnect().await;
+            self.traffic.disconnected();
             println!("✅ Client disconnected.");
         }
+        *self.nym_address.write().await = None;
+        emit_status(&self.status_callback, ConnectionStatus::Disconnected, None, None).await;
+    }
+}
+
+/// Hands queued messages to the Python callback, one at a time. Messages still
+/// queued when it is cancelled are delivered after the next `receive_messages`.
+async fn dispatch(
+    incoming: Arc<IncomingQueue>,
+    callback: Arc<Mutex<Option<MessageCallback>>>,
+    shutdown_signal: CancellationToken,
+) {
+    loop {
+        let message = tokio::select! {
+            _ = shutdown_signal.cancelled() => break,
+            message = incoming.pop() => message,
+        };
+        let callback = callback.lock().await;
+        // A slow or stuck callback holds up every message behind it.
+        let _span = debug_span!("python_callback").entered();
+        pyo3::Python::with_gil(|py| {
+            if let Some(ref cb) = *callback {
+                let result = if cb.typed {
+                    Py::new(py, message).and_then(|msg| cb.callback.call1(py, (msg,)))
+                } else {
+                    cb.callback.call1(py, (&message.message,))
+                };
+                if let Err(e) = result {
+                    e.print(py);
+                }
+            } else {
+                println!("📩 Received: {}", message.message);
+            }
+        });
     }
 }
 
+/// Replaces a dropped connection. If the network was lost it first waits for
+/// it to come back; if reconnecting fails, it tries again after the next
+/// network change, or after `RECONNECT_IDLE_RETRY`. Returns `None` on shutdown.
+#[tracing::instrument(skip_all, fields(reason = %reason))]
+async fn reestablish(
+    data_dir: Option<&Path>,
+    status_callback: &Mutex<Option<PyObject>>,
+    changes: &mut UnboundedReceiver<NetworkChange>,
+    mut reason: &'static str,
+    mut network_lost: bool,
+    shutdown_signal: &CancellationToken,
+) -> Option<MixnetClient> {
+    loop {
+        let mut wait = None;
+        if !network_lost {
+            if let Some(client) = reconnect(data_dir, status_callback, reason, shutdown_signal).await {
+                return Some(client);
+            }
+            emit_status(status_callback, ConnectionStatus::Disconnected, None, Some("reconnect_failed")).await;
+            wait = Some(RECONNECT_IDLE_RETRY);
+        }
+        let idle = async {
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = shutdown_signal.cancelled() => return None,
+            change = changes.recv() => {
+                let change = change?;
+                println!("🌐 {}.", change);
+                reason = change.reason();
+                network_lost = change == NetworkChange::Lost;
+            }
+            _ = idle => reason = "retry",
+        }
+    }
+}
+
+/// Tries to connect a fresh client, with the same identity as before, with
+/// exponential backoff. Gives up after `RECONNECT_ATTEMPTS` failures or when
+/// shutdown is requested.
+#[tracing::instrument(skip_all, fields(reason = %reason))]
+async fn reconnect(
+    data_dir: Option<&Path>,
+    status_callback: &Mutex<Option<PyObject>>,
+    reason: &str,
+    shutdown_signal: &CancellationToken,
+) -> Option<MixnetClient> {
+    let mut delay = RECONNECT_BASE_DELAY;
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        emit_status(status_callback, ConnectionStatus::Reconnecting, None, Some(reason)).await;
+        println!("🔄 Reconnecting to the mixnet (attempt {}/{})...", attempt, RECONNECT_ATTEMPTS);
+
+        let result = tokio::select! {
+            _ = shutdown_signal.cancelled() => return None,
+            result = connect(data_dir) => result,
+        };
+        match result {
+            Ok(client) => return Some(client),
+            Err(e) => println!("❌ Reconnect failed: {:?}", e),
+        }
+
+        tokio::select! {
+            _ = shutdown_signal.cancelled() => return None,
+            _ = tokio::time::sleep(delay) => {}
+        }
+        delay *= 2;
+    }
+    println!("❌ Giving up on reconnecting.");
+    None
+}
@@ -0,0 +1,183 @@
+//! Notices when the machine slept or its network changed under the mixnet
+//! client.
+//!
+//! The gateway connection is a websocket over TCP, which after a suspend or a
+//! move to another network can look open for minutes before the kernel gives
+//! up on it. The watch looks every few seconds instead: the wall clock running
+//! ahead of the monotonic one means the machine was asleep, another local
+//! address for the default route means another interface carries traffic (or
+//! none does), and failing lookups of the nym-api host mean DNS is gone. The
+//! listener then drops the connection and makes a new one once the network is
+//! usable again.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+use nymchat_protocol::NetworkConfig;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+/// How often the network is looked at.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Time missing from the monotonic clock between two looks that counts as a suspend.
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(10);
+/// How long a name lookup may take before DNS counts as failed.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+/// The mainnet nym-api, whose name is looked up when the network names none.
+const MAINNET_API_HOST: &str = "validator.nymtech.net";
+/// Public addresses to "connect" a UDP socket to. Nothing is sent; the kernel
+/// only picks the local address of the route there.
+const ROUTE_PROBES: [&str; 2] = ["1.1.1.1:53", "[2606:4700:4700::1111]:53"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkChange {
+    /// The machine was suspended for about this long.
+    Resumed(Duration),
+    /// Traffic leaves through another local address.
+    RouteChanged,
+    /// There is no route out, or names no longer resolve.
+    Lost,
+    /// Routes and DNS work again after `Lost`.
+    Restored,
+}
+
+impl NetworkChange {
+    /// The reason passed to the status callback for the reconnect it causes.
+    pub fn reason(self) -> &'static str {
+        match self {
+            NetworkChange::Resumed(_) => "resumed",
+            NetworkChange::RouteChanged => "network_changed",
+            NetworkChange::Lost => "network_lost",
+            NetworkChange::Restored => "network_restored",
+        }
+    }
+}
+
+impl fmt::Display for NetworkChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkChange::Resumed(slept) => write!(f, "Resumed after {}s asleep", slept.as_secs()),
+            NetworkChange::RouteChanged => f.write_str("Network interface changed"),
+            NetworkChange::Lost => f.write_str("Network lost"),
+            NetworkChange::Restored => f.write_str("Network restored"),
+        }
+    }
+}
+
+/// What one look at the network found.
+#[derive(Clone, Debug, PartialEq)]
+struct Observation {
+    /// Local address of the default route; `None` without one.
+    local_addr: Option<IpAddr>,
+    dns_ok: bool,
+}
+
+impl Observation {
+    fn usable(&self) -> bool {
+        self.local_addr.is_some() && self.dns_ok
+    }
+}
+
+pub struct NetworkWatch {
+    /// `host:port` looked up to check DNS.
+    probe_host: String,
+    last: Option<Observation>,
+    /// Monotonic and wall clock time of the last look.
+    looked_at: (Instant, SystemTime),
+}
+
+/// `host:port` of the network's nym-api, from its `NYM_API` URL.
+pub fn api_host(network: &NetworkConfig) -> String {
+    let url = network
+        .variables()
+        .ok()
+        .and_then(|variables| variables.into_iter().find(|(key, _)| key == "NYM_API").map(|(_, value)| value));
+    let host = url.as_deref().and_then(|url| url.split("://").nth(1)).and_then(|rest| rest.split('/').next());
+    match host.filter(|host| !host.is_empty()) {
+        Some(host) if host.contains(':') => host.to_string(),
+        Some(host) => format!("{}:443", host),
+        None => format!("{}:443", MAINNET_API_HOST),
+    }
+}
+
+async fn default_route() -> Option<IpAddr> {
+    for probe in ROUTE_PROBES {
+        let bind = if probe.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(socket) => socket,
+            Err(_) => continue,
+        };
+        if socket.connect(probe).await.is_ok() {
+            if let Ok(local) = socket.local_addr() {
+                return Some(local.ip());
+            }
+        }
+    }
+    None
+}
+
+async fn resolves(host: &str) -> bool {
+    match tokio::time::timeout(DNS_TIMEOUT, lookup_host(host)).await {
+        Ok(Ok(mut addrs)) => addrs.next().is_some(),
+        _ => false,
+    }
+}
+
+impl NetworkWatch {
+    pub fn new(probe_host: String) -> Self {
+        NetworkWatch { probe_host, last: None, looked_at: (Instant::now(), SystemTime::now()) }
+    }
+
+    /// Looks at the network every [`WATCH_INTERVAL`] until something changed.
+    pub async fn next_change(&mut self) -> NetworkChange {
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            if let Some(change) = self.look().await {
+                return change;
+            }
+        }
+    }
+
+    async fn look(&mut self) -> Option<NetworkChange> {
+        // The monotonic clock stops while the machine is suspended; the wall clock does not.
+        let (then, then_wall) = self.looked_at;
+        let (now, now_wall) = (Instant::now(), SystemTime::now());
+        self.looked_at = (now, now_wall);
+        let slept = now_wall.duration_since(then_wall).unwrap_or_default().saturating_sub(now - then);
+
+        let local_addr = default_route().await;
+        let dns_ok = local_addr.is_some() && resolves(&self.probe_host).await;
+        let current = Observation { local_addr, dns_ok };
+        let previous = self.last.replace(current.clone());
+        let was_usable = previous.as_ref().is_none_or(Observation::usable);
+
+        if !current.usable() {
+            return was_usable.then_some(NetworkChange::Lost);
+        }
+        if !was_usable {
+            return Some(NetworkChange::Restored);
+        }
+        if slept >= SLEEP_THRESHOLD {
+            return Some(NetworkChange::Resumed(slept));
+        }
+        match previous {
+            Some(previous) if previous.local_addr != current.local_addr => Some(NetworkChange::RouteChanged),
+            _ => None,
+        }
+    }
+}
+
+/// Sends every change `watch` notices to `changes` until shutdown, or until
+/// nobody listens any more.
+pub async fn run(mut watch: NetworkWatch, changes: UnboundedSender<NetworkChange>, shutdown_signal: CancellationToken) {
+    loop {
+        let change = tokio::select! {
+            _ = shutdown_signal.cancelled() => break,
+            change = watch.next_change() => change,
+        };
+        if changes.send(change).is_err() {
+            break;
+        }
+    }
+}
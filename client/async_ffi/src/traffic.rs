@@ -0,0 +1,107 @@
+//! Traffic counters behind the client's network statistics.
+//!
+//! The nym client splits messages into Sphinx packets, acknowledges and
+//! retransmits them and sends cover traffic without reporting any of it, so
+//! these count what passes through `MixnetHandler` and estimate packets from
+//! message sizes. Every reply a server sends us uses up reply SURBs we
+//! attached earlier; the difference is an estimate of how many it has left.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// About this many bytes of a message fit in one Sphinx packet.
+pub const PACKET_PAYLOAD_BYTES: usize = 2000;
+
+/// Packets a message of `bytes` bytes takes.
+pub fn packets_for(bytes: usize) -> u64 {
+    bytes.div_ceil(PACKET_PAYLOAD_BYTES).max(1) as u64
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrafficStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Estimated from message sizes.
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Reply SURBs attached to sent messages.
+    pub surbs_sent: u64,
+    /// Packets received as replies through those SURBs.
+    pub surbs_used: u64,
+    /// Sends that failed and were tried again.
+    pub send_retries: u64,
+    /// Sends that still failed after their last retry.
+    pub send_failures: u64,
+    pub reconnects: u64,
+}
+
+impl TrafficStats {
+    /// Reply SURBs estimated to be left with whoever we sent them to.
+    pub fn surbs_remaining(&self) -> u64 {
+        self.surbs_sent.saturating_sub(self.surbs_used)
+    }
+}
+
+pub struct Traffic {
+    stats: Mutex<TrafficStats>,
+    /// When the current gateway connection was made; `None` while disconnected.
+    connected_at: Mutex<Option<Instant>>,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Traffic {
+    /// Counters for a client that has just connected.
+    pub fn new() -> Self {
+        Traffic { stats: Mutex::new(TrafficStats::default()), connected_at: Mutex::new(Some(Instant::now())) }
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// How long the current connection has been up.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.connected_at.lock().unwrap().map(|since| since.elapsed())
+    }
+
+    pub fn sent(&self, bytes: usize, surbs: u32) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.packets_sent += packets_for(bytes);
+        stats.surbs_sent += u64::from(surbs);
+    }
+
+    pub fn retried(&self) {
+        self.stats.lock().unwrap().send_retries += 1;
+    }
+
+    pub fn send_failed(&self) {
+        self.stats.lock().unwrap().send_failures += 1;
+    }
+
+    /// Records a received message; `reply` if it came through our SURBs,
+    /// i.e. without a sender tag to answer.
+    pub fn received(&self, bytes: usize, reply: bool) {
+        let packets = packets_for(bytes);
+        let mut stats = self.stats.lock().unwrap();
+        stats.messages_received += 1;
+        stats.packets_received += packets;
+        if reply {
+            stats.surbs_used += packets;
+        }
+    }
+
+    pub fn disconnected(&self) {
+        *self.connected_at.lock().unwrap() = None;
+    }
+
+    pub fn reconnected(&self) {
+        self.stats.lock().unwrap().reconnects += 1;
+        *self.connected_at.lock().unwrap() = Some(Instant::now());
+    }
+}
@@ -0,0 +1,50 @@
+"""
+Compares storing a burst of incoming messages one transaction at a time
+(`save_message`) with a single batched transaction (`save_messages`).
+
+Run from the client directory after building async_ffi:
+    python benchmarks/db_writes.py [messages]
+"""
+import sys
+import tempfile
+import time
+from async_ffi import PyDb
+
+USER = "bench"
+
+
+def open_db(storage_dir):
+    db = PyDb.open(USER, storage_dir)
+    db.register_user(USER, "key")
+    return db
+
+
+def burst(count):
+    return [(f"contact{i % 10}", "from", f"message {i}") for i in range(count)]
+
+
+def one_by_one(db, messages):
+    for username, msg_type, message in messages:
+        db.save_message(USER, username, msg_type, message)
+
+
+def batched(db, messages):
+    db.save_messages(USER, messages)
+
+
+def main():
+    count = int(sys.argv[1]) if len(sys.argv) > 1 else 500
+    messages = burst(count)
+    for name, store in (("save_message", one_by_one), ("save_messages", batched)):
+        with tempfile.TemporaryDirectory() as storage_dir:
+            db = open_db(storage_dir)
+            start = time.perf_counter()
+            store(db, messages)
+            elapsed = time.perf_counter() - start
+            assert len(db.get_all_messages(USER)) == count
+            db.close()
+        print(f"{name:>14}: {count} messages in {elapsed * 1000:8.1f} ms ({count / elapsed:,.0f}/s)")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,77 @@
+"""
+Measures how long the event loop the interface is drawn from stalls while
+large messages are encrypted and signed: once with the crypto run inline, as
+the client used to, and once on the crypto worker pool
+(`CryptoUtils.encrypt_message_async` and friends). A frame task ticks at
+60 Hz meanwhile; a late tick is a dropped frame.
+
+Run from the client directory:
+    python benchmarks/render_latency.py [messages] [payload KiB]
+"""
+import asyncio
+import json
+import os
+import sys
+import tempfile
+import time
+
+sys.path.insert(0, os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "src"))
+from cryptographyUtils import CryptoUtils  # noqa: E402
+
+FRAME = 1 / 60
+
+
+async def frames(gaps, stop):
+    """ Records the time between ticks until `stop` is set. """
+    last = time.perf_counter()
+    while not stop.is_set():
+        await asyncio.sleep(FRAME)
+        now = time.perf_counter()
+        gaps.append(now - last)
+        last = now
+
+
+def seal_inline(crypto, private_key, public_key_pem, message):
+    encrypted = crypto.encrypt_message(public_key_pem, message)
+    return crypto.sign_message(private_key, json.dumps(encrypted))
+
+
+async def seal_offloaded(crypto, private_key, public_key_pem, message):
+    encrypted = await crypto.encrypt_message_async(public_key_pem, message)
+    return await crypto.sign_message_async(private_key, json.dumps(encrypted))
+
+
+async def measure(crypto, private_key, public_key_pem, messages, offload):
+    gaps, stop = [], asyncio.Event()
+    ticker = asyncio.create_task(frames(gaps, stop))
+    await asyncio.sleep(FRAME)
+    start = time.perf_counter()
+    for message in messages:
+        if offload:
+            await seal_offloaded(crypto, private_key, public_key_pem, message)
+        else:
+            seal_inline(crypto, private_key, public_key_pem, message)
+            # What the send path yields to anyway, between messages
+            await asyncio.sleep(0)
+    elapsed = time.perf_counter() - start
+    stop.set()
+    await ticker
+    return elapsed, gaps
+
+
+def main():
+    count = int(sys.argv[1]) if len(sys.argv) > 1 else 50
+    size = int(sys.argv[2]) if len(sys.argv) > 2 else 1024
+    messages = [os.urandom(size * 512).hex() for _ in range(count)]
+    with tempfile.TemporaryDirectory() as storage_dir:
+        crypto = CryptoUtils(storage_dir)
+        private_key, public_key_pem = crypto.generate_key_pair("bench")
+        for name, offload in (("inline", False), ("worker pool", True)):
+            elapsed, gaps = asyncio.run(measure(crypto, private_key, public_key_pem, messages, offload))
+            dropped = sum(int(gap / FRAME) - 1 for gap in gaps if gap >= 2 * FRAME)
+            print(f"{name:>11}: {count} x {size} KiB in {elapsed * 1000:8.1f} ms, "
+                  f"longest frame {max(gaps, default=0) * 1000:6.1f} ms, {dropped} frames dropped")
+
+
+if __name__ == "__main__":
+    main()
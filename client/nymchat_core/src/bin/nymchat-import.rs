@@ -0,0 +1,40 @@
+//! Imports an account of the old Python client into a storage directory
+//! the other clients read; see `nymchat_core::legacy`.
+
+use nymchat_core::legacy;
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage: nymchat-import <username> <old storage dir> [storage dir]
+
+Copies the key pair, contacts and message history of <username> from the
+old client's storage directory (the one holding <username>/<username>_client.db)
+into [storage dir], by default ./storage. Running it again adds only what is new.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (username, legacy_dir) = match (args.first(), args.get(1)) {
+        (Some(username), Some(legacy_dir)) => (username, legacy_dir),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let storage_dir = args.get(2).map_or("storage", String::as_str);
+
+    match legacy::import_account(username, legacy_dir, storage_dir) {
+        Ok(imported) => {
+            println!(
+                "Imported {} into {}: {} key files, {} contacts, {} messages.",
+                username, storage_dir, imported.keys, imported.contacts, imported.messages
+            );
+            for contact in imported.conflicts {
+                println!("{} has another key here than in the old client; kept the one here.", contact);
+            }
+        }
+        Err(e) => {
+            eprintln!("Import failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
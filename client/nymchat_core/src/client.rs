@@ -0,0 +1,333 @@
+//! A mixnet connection to nymCHAT servers: registration, login, health
+//! probes and sending, with everything else that arrives passed on as
+//! [`Event`]s.
+
+use crate::error::{Error, ProtocolError, Result, TransportError};
+use crate::event::{self, Event, Inbox, TransportStatus};
+use futures::StreamExt;
+use nym_sdk::mixnet::{IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender, Recipient};
+use nym_sdk::NymNetworkDetails;
+use nymchat_protocol::{ct_eq, pow, CryptoError, NetworkConfig};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tracing::{info_span, Instrument};
+
+/// Reply SURBs attached to every message sent to the server.
+const SURB_COUNT: u32 = 10;
+/// How long register/login wait for each server reply.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Server reply envelope, as sent by `sendEncapsulatedReply`.
+#[derive(Debug, Deserialize)]
+pub struct ServerReply {
+    pub action: String,
+    #[serde(default)]
+    pub context: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+/// What a server reported in reply to a health probe.
+#[derive(Debug, Deserialize)]
+pub struct Health {
+    pub version: String,
+    /// Seconds since the server started.
+    pub uptime: u64,
+    /// Federation name, if the server is federated.
+    #[serde(default)]
+    pub server: Option<String>,
+    /// Round trip through the mixnet, including the server's response delay.
+    #[serde(skip, default)]
+    pub rtt: Duration,
+}
+
+#[derive(Deserialize)]
+struct HealthContent {
+    nonce: String,
+    #[serde(flatten)]
+    health: Health,
+}
+
+/// Where the events for the embedding application go.
+pub type EventSink = Box<dyn Fn(Event) + Send + Sync>;
+
+/// Waiter for the next `challenge`/`challengeResponse`/`healthResponse` in a given context.
+struct PendingReply {
+    context: String,
+    reply: oneshot::Sender<ServerReply>,
+}
+
+/// An ephemeral mixnet client, with a background task listening for what
+/// arrives.
+pub struct ChatClient {
+    sender: MixnetClientSender,
+    nym_address: String,
+    pending: Arc<Mutex<Option<PendingReply>>>,
+    inbox: Arc<Mutex<Option<Inbox>>>,
+    shutdown_signal: Arc<Notify>,
+    listener: Option<tokio::task::JoinHandle<MixnetClient>>,
+}
+
+impl ChatClient {
+    /// Connects an ephemeral client to the network `NYM_NETWORK` selects and
+    /// starts the background listener. The sink is told the client is
+    /// connected before this returns.
+    pub async fn connect(sink: EventSink) -> Result<Self> {
+        let network = NetworkConfig::from_env().map_err(TransportError::from)?;
+        let details = if network.is_mainnet() {
+            NymNetworkDetails::new_mainnet()
+        } else {
+            network.export_to_env().map_err(TransportError::from)?;
+            NymNetworkDetails::new_from_env()
+        };
+        let client = nym_sdk::mixnet::MixnetClientBuilder::new_ephemeral()
+            .network_details(details)
+            .build()
+            .map_err(|e| TransportError::Connect(e.to_string()))?
+            .connect_to_mixnet()
+            .await
+            .map_err(|e| TransportError::Connect(e.to_string()))?;
+
+        let sender = client.split_sender();
+        let nym_address = client.nym_address().to_string();
+        let pending = Arc::new(Mutex::new(None));
+        let inbox = Arc::new(Mutex::new(None));
+        let shutdown_signal = Arc::new(Notify::new());
+
+        sink(Event::TransportStatus(TransportStatus::Connected));
+        let listener = tokio::spawn(
+            listen(client, sink, Arc::clone(&pending), Arc::clone(&inbox), Arc::clone(&shutdown_signal))
+                .instrument(info_span!("listener")),
+        );
+
+        Ok(Self { sender, nym_address, pending, inbox, shutdown_signal, listener: Some(listener) })
+    }
+
+    /// Decrypts what arrives from now on with `inbox`, usually once its user
+    /// logged in, so the sink gets chat messages instead of raw envelopes.
+    pub fn set_inbox(&self, inbox: Inbox) {
+        *self.inbox.lock().unwrap() = Some(inbox);
+    }
+
+    /// Where this client receives.
+    pub fn nym_address(&self) -> &str {
+        &self.nym_address
+    }
+
+    /// Sends `message` to the nym address `recipient`, with reply SURBs.
+    pub async fn send(&self, recipient: &str, message: &str) -> Result<()> {
+        let address = recipient
+            .parse::<Recipient>()
+            .map_err(|_| TransportError::Recipient(recipient.to_string()))?;
+        self.sender
+            .send_message(address, message.as_bytes().to_vec(), IncludedSurbs::Amount(SURB_COUNT))
+            .await
+            .map_err(|e| TransportError::Send(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Registers `username` with the server, answering its nonce challenge.
+    pub async fn register(
+        &self,
+        server: &str,
+        username: &str,
+        private_key_pem: &str,
+    ) -> Result<()> {
+        let key = load_signing_key(private_key_pem)?;
+        let public_key_pem = key
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+
+        let request = json!({ "action": "register", "usernym": username, "publicKey": public_key_pem });
+        self.challenge_flow(server, "registration", "registrationResponse", username, &key, request)
+            .await
+    }
+
+    /// Logs `username` in, answering the server's nonce challenge.
+    pub async fn login(
+        &self,
+        server: &str,
+        username: &str,
+        private_key_pem: &str,
+    ) -> Result<()> {
+        let key = load_signing_key(private_key_pem)?;
+        let request = json!({ "action": "login", "usernym": username });
+        self.challenge_flow(server, "login", "loginResponse", username, &key, request).await
+    }
+
+    async fn challenge_flow(
+        &self,
+        server: &str,
+        context: &str,
+        response_action: &str,
+        username: &str,
+        key: &SigningKey,
+        request: serde_json::Value,
+    ) -> Result<()> {
+        let challenge = self.request(server, context, &request.to_string()).await?;
+        if challenge.action != "challenge" {
+            return Err(rejected(context, challenge.content.unwrap_or_default()));
+        }
+
+        let content = challenge.content.unwrap_or_default();
+        let content = serde_json::from_str::<serde_json::Value>(&content).unwrap_or_default();
+        let nonce = content
+            .get("nonce")
+            .and_then(|n| n.as_str())
+            .ok_or_else(|| ProtocolError::MissingNonce(context.to_string()))?;
+
+        let signature: Signature = key.sign(nonce.as_bytes());
+        let mut response = json!({
+            "action": response_action,
+            "username": username,
+            "signature": hex::encode(signature.to_der().as_bytes()),
+        });
+        // Servers that want a proof of work say how hard it is in the challenge.
+        if let Some(difficulty) = content.get("difficulty").and_then(|d| d.as_u64()) {
+            let nonce = nonce.to_string();
+            let counter = tokio::task::spawn_blocking(move || pow::solve(&nonce, difficulty as u32))
+                .await
+                .map_err(|e| ProtocolError::ProofOfWork(e.to_string()))?;
+            response["pow"] = json!(counter);
+        }
+
+        let result = self.request(server, context, &response.to_string()).await?;
+        match result.content.as_deref() {
+            Some("success") => Ok(()),
+            other => Err(rejected(context, other.unwrap_or("no content").to_string())),
+        }
+    }
+
+    /// Probes `server` and measures the round trip. The reply must echo a
+    /// fresh nonce, so a delayed answer to an earlier probe is not mistaken
+    /// for this one.
+    pub async fn health(&self, server: &str) -> Result<Health> {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let nonce = hex::encode(bytes);
+
+        let started = Instant::now();
+        let request = json!({ "action": "health", "nonce": nonce });
+        let reply = self.request(server, "health", &request.to_string()).await?;
+        let rtt = started.elapsed();
+
+        let content = reply.content.unwrap_or_default();
+        let reply: HealthContent = match serde_json::from_str(&content) {
+            Ok(reply) => reply,
+            Err(_) => return Err(ProtocolError::Malformed { context: "health".to_string(), content }.into()),
+        };
+        if !ct_eq(reply.nonce.as_bytes(), nonce.as_bytes()) {
+            return Err(ProtocolError::NonceMismatch("health".to_string()).into());
+        }
+        Ok(Health { rtt, ..reply.health })
+    }
+
+    /// Probes each of `servers` in turn and returns the one that answered
+    /// fastest, with its health. Servers that fail to answer are skipped.
+    pub async fn fastest_server<'a>(&self, servers: &[&'a str]) -> Result<(&'a str, Health)> {
+        let mut best: Option<(&str, Health)> = None;
+        for &server in servers {
+            if let Ok(health) = self.health(server).await {
+                if best.as_ref().is_none_or(|(_, b)| health.rtt < b.rtt) {
+                    best = Some((server, health));
+                }
+            }
+        }
+        best.ok_or_else(|| TransportError::NoServer.into())
+    }
+
+    /// Sends `message` to the server and waits for its reply in `context`.
+    #[tracing::instrument(skip(self, message))]
+    async fn request(&self, server: &str, context: &str, message: &str) -> Result<ServerReply> {
+        let (tx, rx) = oneshot::channel();
+        *self.pending.lock().unwrap() = Some(PendingReply { context: context.to_string(), reply: tx });
+
+        self.send(server, message).await?;
+
+        tokio::time::timeout(RESPONSE_TIMEOUT, rx)
+            .await
+            .map_err(|_| TransportError::Timeout(context.to_string()))?
+            .map_err(|_| TransportError::Disconnected.into())
+    }
+
+    /// Stops the listener and closes the connection.
+    pub async fn disconnect(&mut self) {
+        self.shutdown_signal.notify_one();
+        if let Some(listener) = self.listener.take() {
+            if let Ok(client) = listener.await {
+                client.disconnect().await;
+            }
+        }
+    }
+}
+
+fn load_signing_key(private_key_pem: &str) -> Result<SigningKey> {
+    SigningKey::from_pkcs8_pem(private_key_pem).map_err(|e| CryptoError::InvalidKey(e.to_string()).into())
+}
+
+fn rejected(context: &str, reason: String) -> Error {
+    ProtocolError::Rejected { context: context.to_string(), reason }.into()
+}
+
+async fn listen(
+    mut client: MixnetClient,
+    sink: EventSink,
+    pending: Arc<Mutex<Option<PendingReply>>>,
+    inbox: Arc<Mutex<Option<Inbox>>>,
+    shutdown_signal: Arc<Notify>,
+) -> MixnetClient {
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.notified() => break,
+            received = client.next() => {
+                let received = match received {
+                    Some(received) => received,
+                    None => {
+                        sink(Event::TransportStatus(TransportStatus::Disconnected));
+                        break;
+                    }
+                };
+                if received.message.is_empty() {
+                    continue;
+                }
+                let message = String::from_utf8_lossy(&received.message).to_string();
+                if let Some(reply) = match_pending_reply(&pending, &message) {
+                    let waiter = pending.lock().unwrap().take();
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.reply.send(reply);
+                    }
+                    continue;
+                }
+                let sender_tag = received.sender_tag.map(|t| t.to_base58_string());
+                let ts = event::now();
+                let events = match inbox.lock().unwrap().as_ref() {
+                    Some(inbox) => inbox.events(&message, sender_tag, ts),
+                    None => vec![Event::Raw { message, sender_tag, ts }],
+                };
+                events.into_iter().for_each(&sink);
+            }
+        }
+    }
+    client
+}
+
+/// Parses `message` as the reply a pending register/login/health probe is waiting for.
+fn match_pending_reply(pending: &Mutex<Option<PendingReply>>, message: &str) -> Option<ServerReply> {
+    let guard = pending.lock().unwrap();
+    let waiting_for = guard.as_ref()?.context.as_str();
+    let reply: ServerReply = serde_json::from_str(message).ok()?;
+    let is_awaited = matches!(reply.action.as_str(), "challenge" | "challengeResponse" | "healthResponse");
+    if is_awaited && reply.context.as_deref() == Some(waiting_for) {
+        Some(reply)
+    } else {
+        None
+    }
+}
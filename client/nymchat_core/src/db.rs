@@ -0,0 +1,1826 @@
+use crate::error::StorageError;
+use crate::legacy::LegacyImport;
+use crate::stored::{StoredMessage, KIND_TEXT, STORED_MESSAGE_VERSION};
+use hmac::{Hmac, Mac};
+use nymchat_protocol::ct_eq;
+use rand_core::{OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, StorageError>;
+
+/// `(username, public_key)`
+pub type ContactRow = (String, String);
+/// `(type, message, timestamp)` where `type` is `"to"` or `"from"`.
+pub type MessageRow = (String, String, String);
+/// `(id, type, message, timestamp)`; `id` pages further back, see [`Db::load_messages_before`].
+pub type PagedMessageRow = (i64, String, String, String);
+/// `(username, type, message, timestamp)`
+pub type ConversationRow = (String, String, String, String);
+/// `(username, type, message, kind)`, a message waiting to be saved; see [`StoredMessage`].
+pub type NewMessage = (String, String, String, String);
+/// `(username, share_presence, last_seen)`; `last_seen` is in Unix seconds.
+pub type PresenceRow = (String, bool, Option<i64>);
+/// `(username, send_receipts, send_typing)`; `None` follows the user's own setting.
+pub type ContactPrivacyRow = (String, Option<bool>, Option<bool>);
+/// `(version, features)` agreed in the last handshake with a contact.
+pub type HandshakeRow = (u32, Vec<String>);
+/// `(old_fingerprint, new_fingerprint, changed_at)`
+pub type KeyChangeRow = (String, String, String);
+/// `(old_fingerprint, new_fingerprint, old_public_key, changed_at)`; `old_public_key`
+/// is missing for changes recorded before it was kept.
+pub type KeyHistoryRow = (String, String, Option<String>, String);
+/// `(id, event, detail, timestamp)`
+pub type AuditRow = (i64, String, String, String);
+/// `(content, signature)` of a server-signed `queryResponse`.
+pub type ObservationRow = (String, String);
+/// `(group_id, name)`
+pub type GroupRow = (String, String);
+/// `(sender, message, timestamp)`
+pub type GroupMessageRow = (String, String, String);
+/// `(name, session, membership)`; `membership` is missing for groups made before signed membership.
+pub type StoredGroup = (String, String, Option<String>);
+/// `(entry, signature)` of a signed membership change, see `nymchat_protocol::MembershipLog`.
+pub type GroupLogRow = (String, String);
+/// `(poll_id, creator, question, options, multiple, timestamp)`; `options` is a JSON list.
+pub type PollRow = (String, String, String, String, bool, String);
+/// `(voter, choices)`; `choices` is a JSON list of option indices.
+pub type PollVoteRow = (String, String);
+/// `(username, sender_tag)`
+pub type SenderTagRow = (String, String);
+/// `(name, collapsed)` of a sidebar folder.
+pub type FolderRow = (String, bool);
+/// `(chat, folder)`; `chat` is a username or `group:<id>`.
+pub type ChatFolderRow = (String, String);
+/// `(chat, level, muted_until)`; `muted_until` is in Unix seconds, -1 for no end.
+pub type NotificationRow = (String, String, Option<i64>);
+/// `(chat, text)` of an unsent draft.
+pub type DraftRow = (String, String);
+/// `(chat, id, sender, snippet, timestamp)` of a search hit; `chat` is a username or `group:<id>`, `id`
+/// the message's row id in its table, and `snippet` the text around the match with matched words between
+/// [`MATCH_START`] and [`MATCH_END`].
+pub type SearchRow = (String, i64, String, String, String);
+/// `(sender, kind, message, attachments, timestamp)`; `attachments` are the files' names.
+pub type ExportRow = (String, String, String, Vec<String>, String);
+/// `(id, chat, content)` of a message waiting in the outbox; `chat` is a username or `group:<id>`.
+pub type OutboxRow = (i64, String, String);
+/// `(id, send_id, status)` of a sent message; `id` is its row id, see [`Db::get_message_statuses`].
+pub type DeliveryRow = (i64, String, String);
+/// `(id, sender, reason, content, received_at)` of a quarantined message; `sender` is missing when it
+/// could not be read, and `content` is the message as it arrived.
+pub type QuarantineRow = (i64, Option<String>, String, String, String);
+
+/// Marks the start of a matched word in search snippets.
+pub const MATCH_START: &str = "\u{2}";
+/// Marks the end of a matched word in search snippets.
+pub const MATCH_END: &str = "\u{3}";
+/// Words of context a search snippet keeps around the match.
+const SNIPPET_WORDS: i32 = 12;
+
+/// Delivery states of a sent message, in the order it goes through them: stored but not handed on yet,
+/// accepted by the mixnet or server, acknowledged by the contact's client, and seen by the contact.
+pub const MESSAGE_STATUSES: [&str; 4] = ["pending", "sent", "delivered", "read"];
+
+/// Quarantined messages kept; older ones are dropped as new ones arrive.
+pub const QUARANTINE_LIMIT: i64 = 500;
+
+/// Notification level of chats with no setting of their own.
+const DEFAULT_NOTIFY_LEVEL: &str = "all";
+
+/// Settings key of the format message rows are stored in.
+const MESSAGE_FORMAT_SETTING: &str = "message_format";
+
+/// The text shown for a stored message row.
+fn body(row: String) -> String {
+    StoredMessage::decode(&row).body
+}
+
+/// `text` as an FTS5 query matching every word in it as a prefix, with
+/// FTS5 syntax taken literally; `None` if it has no words.
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> =
+        text.split_whitespace().map(|word| format!("\"{}\"*", word.replace('"', "\"\""))).collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+fn export_row(sender: String, row: String, timestamp: String) -> ExportRow {
+    let message = StoredMessage::decode(&row);
+    let attachments = message.attachments.into_iter().map(|attachment| attachment.name).collect();
+    (sender, message.kind, message.body, attachments, timestamp)
+}
+
+/// Per-user client storage: a global `users` table plus `contacts_<user>`,
+/// `messages_<user>`, `key_changes_<user>`, `audit_<user>`, `groups_<user>`,
+/// `group_messages_<user>`, `group_log_<user>`, `group_invites_<user>`,
+/// `polls_<user>`, `poll_votes_<user>`, `sender_tags_<user>`, `outbox_<user>`,
+/// `quarantine_<user>`, `settings_<user>`, `folders_<user>`, `folder_chats_<user>`,
+/// `notifications_<user>` and `drafts_<user>` tables, and the full-text indexes of message text
+/// `message_index_<user>` and `group_message_index_<user>`. The layout matches the databases written by
+/// the old Python `SQLiteManager`, so existing `storage/` directories keep
+/// working.
+/// The table names are fixed for a session, so per-user statements still hit
+/// the connection's statement cache after their first use.
+///
+/// `audit_<user>` is the security audit log: append-only (triggers refuse
+/// updates and deletes) and chained, each entry carrying an HMAC over its
+/// contents and the previous entry's HMAC. The HMAC key lives in
+/// `<username>_audit.key` beside the database, so editing the database alone,
+/// e.g. a copy of it, breaks the chain; see [`Db::verify_audit_log`]. Entries
+/// cut off the end of the log leave no trace.
+pub struct Db {
+    conn: Connection,
+    audit_key: [u8; 32],
+}
+
+/// Quotes a per-user table name; usernames are user supplied.
+fn table(prefix: &str, username: &str) -> String {
+    format!("\"{}_{}\"", prefix, username.replace('"', "\"\""))
+}
+
+/// Reads the audit log's HMAC key from `path`, creating it on first use.
+fn load_audit_key(path: &Path) -> Result<[u8; 32]> {
+    if let Ok(existing) = fs::read(path) {
+        return existing.try_into().map_err(|_| StorageError::AuditKey(path.to_path_buf()));
+    }
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path).and_then(|mut file| file.write_all(&key)).map_err(StorageError::io(path))?;
+    Ok(key)
+}
+
+/// HMAC of one audit entry, chained to the entry before it.
+fn audit_mac(key: &[u8], previous: &str, event: &str, detail: &str, timestamp: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for field in [previous, event, detail, timestamp] {
+        mac.update(&(field.len() as u64).to_be_bytes());
+        mac.update(field.as_bytes());
+    }
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl Db {
+    /// Opens (or creates) `<storage_dir>/<username>/<username>_client.db`.
+    pub fn open(username: &str, storage_dir: &str) -> Result<Self> {
+        let user_dir = Path::new(storage_dir).join(username);
+        fs::create_dir_all(&user_dir).map_err(StorageError::io(&user_dir))?;
+
+        let conn = Connection::open(user_dir.join(format!("{}_client.db", username)))?;
+        let audit_key = load_audit_key(&user_dir.join(format!("{}_audit.key", username)))?;
+        let db = Self { conn, audit_key };
+        db.create_global_tables()?;
+        Ok(db)
+    }
+
+    fn create_global_tables(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_user_tables(&self, username: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                username TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                type TEXT CHECK(type IN ('to', 'from')) NOT NULL,
+                message TEXT NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                old_fingerprint TEXT NOT NULL,
+                new_fingerprint TEXT NOT NULL,
+                changed_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS {audit} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event TEXT NOT NULL,
+                detail TEXT NOT NULL,
+                timestamp DATETIME NOT NULL,
+                mac TEXT NOT NULL
+            );
+            CREATE TRIGGER IF NOT EXISTS {} BEFORE UPDATE ON {audit}
+                BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+            CREATE TRIGGER IF NOT EXISTS {} BEFORE DELETE ON {audit}
+                BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+            CREATE TABLE IF NOT EXISTS {} (
+                group_id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                session TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                message TEXT NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                group_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                entry TEXT NOT NULL,
+                signature TEXT NOT NULL,
+                PRIMARY KEY (group_id, seq)
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                secret_hash TEXT PRIMARY KEY,
+                group_id TEXT NOT NULL,
+                expires_at INTEGER,
+                single_use INTEGER NOT NULL,
+                uses INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                group_id TEXT NOT NULL,
+                poll_id TEXT NOT NULL,
+                creator TEXT NOT NULL,
+                question TEXT NOT NULL,
+                options TEXT NOT NULL,
+                multiple INTEGER NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (group_id, poll_id)
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                group_id TEXT NOT NULL,
+                poll_id TEXT NOT NULL,
+                voter TEXT NOT NULL,
+                choices TEXT NOT NULL,
+                PRIMARY KEY (group_id, poll_id, voter)
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                username TEXT PRIMARY KEY,
+                sender_tag TEXT NOT NULL,
+                nym_address TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat TEXT NOT NULL,
+                content TEXT NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender TEXT,
+                reason TEXT NOT NULL,
+                content TEXT NOT NULL,
+                received_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                name TEXT PRIMARY KEY,
+                position INTEGER NOT NULL,
+                collapsed INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                chat TEXT PRIMARY KEY,
+                folder TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                chat TEXT PRIMARY KEY,
+                level TEXT NOT NULL,
+                muted_until INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+                chat TEXT PRIMARY KEY,
+                text TEXT NOT NULL,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );",
+            table("contacts", username),
+            table("messages", username),
+            table("key_changes", username),
+            table("audit_no_update", username),
+            table("audit_no_delete", username),
+            table("groups", username),
+            table("group_messages", username),
+            table("group_log", username),
+            table("group_invites", username),
+            table("polls", username),
+            table("poll_votes", username),
+            table("sender_tags", username),
+            table("outbox", username),
+            table("quarantine", username),
+            table("settings", username),
+            table("folders", username),
+            table("folder_chats", username),
+            table("notifications", username),
+            table("drafts", username),
+            audit = table("audit", username),
+        ))?;
+        // Presence columns, added to tables created before presence existed.
+        self.add_column_if_missing("contacts", username, "share_presence", "INTEGER NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("contacts", username, "last_seen", "INTEGER")?;
+        self.add_column_if_missing("contacts", username, "trusted", "INTEGER NOT NULL DEFAULT 1")?;
+        // Set once the user compared safety numbers with the contact out of band.
+        self.add_column_if_missing("contacts", username, "verified", "INTEGER NOT NULL DEFAULT 0")?;
+        // The directory's signed answer for the contact, gossiped to them as a key transparency check.
+        self.add_column_if_missing("contacts", username, "key_observation", "TEXT")?;
+        self.add_column_if_missing("contacts", username, "key_observation_signature", "TEXT")?;
+        // Per-contact overrides of whether read receipts and typing indicators are sent.
+        self.add_column_if_missing("contacts", username, "send_receipts", "INTEGER")?;
+        self.add_column_if_missing("contacts", username, "send_typing", "INTEGER")?;
+        // Whether the contact is kept from learning our nym address; they reply through our SURBs.
+        self.add_column_if_missing("contacts", username, "anonymous", "INTEGER NOT NULL DEFAULT 0")?;
+        // What the last completed handshake with the contact agreed on; features comma-separated.
+        self.add_column_if_missing("contacts", username, "handshake_version", "INTEGER")?;
+        self.add_column_if_missing("contacts", username, "handshake_features", "TEXT")?;
+        // Features a handshake with the contact dropped, until the user accepts sending without them.
+        self.add_column_if_missing("contacts", username, "handshake_downgrade", "TEXT")?;
+        // The contact's Double Ratchet session (`nymchat_protocol::RatchetSession`), secret keys included.
+        self.add_column_if_missing("contacts", username, "ratchet_session", "TEXT")?;
+        // The id a sent message went out with, and how far it got; see `MESSAGE_STATUSES`.
+        self.add_column_if_missing("messages", username, "send_id", "TEXT")?;
+        self.add_column_if_missing("messages", username, "status", "TEXT")?;
+        self.add_column_if_missing("groups", username, "membership", "TEXT")?;
+        // The key a change replaced, so the user can go back to it.
+        self.add_column_if_missing("key_changes", username, "old_public_key", "TEXT")?;
+        self.migrate_messages(username)?;
+        self.create_message_index(username, "messages", "message_index")?;
+        Ok(self.create_message_index(username, "group_messages", "group_message_index")?)
+    }
+
+    /// Creates the FTS5 index `index` of the message text in table `messages`,
+    /// kept up to date by triggers, and fills it if it is new. The index
+    /// holds the text alone, so searches do not match the stored format.
+    fn create_message_index(&self, username: &str, messages: &str, index: &str) -> rusqlite::Result<()> {
+        let body = |row: &str| {
+            format!(
+                "CASE WHEN json_valid({row}.message) THEN COALESCE(json_extract({row}.message, '$.body'), {row}.message)
+                 ELSE {row}.message END",
+                row = row
+            )
+        };
+        self.conn.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS {index} USING fts5(body, tokenize = 'unicode61 remove_diacritics 2');
+            CREATE TRIGGER IF NOT EXISTS {on_insert} AFTER INSERT ON {messages} BEGIN
+                INSERT INTO {index} (rowid, body) VALUES (new.id, {new_body});
+            END;
+            CREATE TRIGGER IF NOT EXISTS {on_update} AFTER UPDATE OF message ON {messages} BEGIN
+                DELETE FROM {index} WHERE rowid = old.id;
+                INSERT INTO {index} (rowid, body) VALUES (new.id, {new_body});
+            END;
+            CREATE TRIGGER IF NOT EXISTS {on_delete} AFTER DELETE ON {messages} BEGIN
+                DELETE FROM {index} WHERE rowid = old.id;
+            END;
+            INSERT INTO {index} (rowid, body)
+                SELECT id, {row_body} FROM {messages} AS m WHERE NOT EXISTS (SELECT 1 FROM {index});",
+            index = table(index, username),
+            messages = table(messages, username),
+            on_insert = table(&format!("{}_insert", index), username),
+            on_update = table(&format!("{}_update", index), username),
+            on_delete = table(&format!("{}_delete", index), username),
+            new_body = body("new"),
+            row_body = body("m"),
+        ))
+    }
+
+    /// Rewrites the direct and group messages of `active_user` stored in an
+    /// older format (see [`crate::stored`]) in the current one, in one
+    /// transaction. The format rows are in is kept in `settings_<user>`.
+    /// Returns how many rows were rewritten.
+    pub fn migrate_messages(&self, active_user: &str) -> Result<usize> {
+        let version = self
+            .get_setting(active_user, MESSAGE_FORMAT_SETTING)?
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0);
+        if version >= STORED_MESSAGE_VERSION {
+            return Ok(0);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let mut migrated = 0;
+        for prefix in ["messages", "group_messages"] {
+            let rows = tx
+                .prepare(&format!("SELECT id, message FROM {}", table(prefix, active_user)))?
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut update =
+                tx.prepare(&format!("UPDATE {} SET message = ?2 WHERE id = ?1", table(prefix, active_user)))?;
+            for (id, message) in rows {
+                migrated += update.execute(params![id, StoredMessage::upgrade(version, &message).encode()])?;
+            }
+        }
+        self.set_setting(active_user, MESSAGE_FORMAT_SETTING, &STORED_MESSAGE_VERSION.to_string())?;
+        tx.commit()?;
+        Ok(migrated)
+    }
+
+    fn add_column_if_missing(
+        &self,
+        prefix: &str,
+        username: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<()> {
+        let exists = self
+            .conn
+            .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+            .exists(params![format!("{}_{}", prefix, username), column])?;
+        if !exists {
+            self.conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table(prefix, username), column, definition),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Records the user's own public key and creates their tables.
+    pub fn register_user(&self, username: &str, public_key: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO users (username, public_key) VALUES (?1, ?2)",
+            params![username, public_key],
+        )?;
+        self.create_user_tables(username)
+    }
+
+    pub fn get_all_users(&self) -> Result<Vec<ContactRow>> {
+        let mut stmt = self.conn.prepare("SELECT username, public_key FROM users")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Adds a contact or replaces its stored public key, keeping its presence settings.
+    /// A different key is no longer verified.
+    pub fn add_contact(&self, active_user: &str, username: &str, public_key: &str) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (username, public_key) VALUES (?1, ?2)
+                 ON CONFLICT(username) DO UPDATE SET public_key = excluded.public_key,
+                     verified = CASE WHEN public_key = excluded.public_key THEN verified ELSE 0 END",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, public_key])?;
+        Ok(())
+    }
+
+    /// Replaces the public key of `username` after it changed, records the change
+    /// with the old key and marks the contact untrusted until [`Db::set_trusted`]
+    /// confirms the new key or [`Db::reject_key_change`] goes back to the old one.
+    /// The ratchet session with the old key is dropped, and so is its verification.
+    pub fn record_key_change(
+        &self,
+        active_user: &str,
+        username: &str,
+        public_key: &str,
+        old_fingerprint: &str,
+        new_fingerprint: &str,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO {} (username, old_fingerprint, new_fingerprint, old_public_key)
+                 SELECT ?1, ?2, ?3, public_key FROM {} WHERE username = ?1",
+                table("key_changes", active_user),
+                table("contacts", active_user)
+            ),
+            params![username, old_fingerprint, new_fingerprint],
+        )?;
+        tx.execute(
+            &format!(
+                "UPDATE {} SET public_key = ?2, trusted = 0, verified = 0, ratchet_session = NULL WHERE username = ?1",
+                table("contacts", active_user)
+            ),
+            params![username, public_key],
+        )?;
+        Ok(tx.commit()?)
+    }
+
+    /// Puts back the key the last change of `username` replaced and trusts it
+    /// again, if that change is still unconfirmed. Returns whether it did.
+    pub fn reject_key_change(&self, active_user: &str, username: &str) -> Result<bool> {
+        let changed = self.conn.execute(
+            &format!(
+                "UPDATE {contacts} SET trusted = 1, ratchet_session = NULL,
+                     public_key = (SELECT old_public_key FROM {changes} WHERE username = ?1 ORDER BY id DESC LIMIT 1)
+                 WHERE username = ?1 AND trusted = 0
+                     AND (SELECT old_public_key FROM {changes} WHERE username = ?1 ORDER BY id DESC LIMIT 1)
+                         IS NOT NULL",
+                contacts = table("contacts", active_user),
+                changes = table("key_changes", active_user)
+            ),
+            params![username],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Whether the stored key of `username` is trusted; unknown contacts have nothing to distrust.
+    pub fn is_trusted(&self, active_user: &str, username: &str) -> Result<bool> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT trusted FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| row.get(0))
+            .optional()
+            .map(|trusted| trusted.unwrap_or(true))?)
+    }
+
+    pub fn set_trusted(&self, active_user: &str, username: &str, trusted: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET trusted = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, trusted])?;
+        Ok(())
+    }
+
+    /// Whether the user marked the current key of `username` as verified.
+    pub fn is_verified(&self, active_user: &str, username: &str) -> Result<bool> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT verified FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| row.get(0))
+            .optional()
+            .map(|verified| verified.unwrap_or(false))?)
+    }
+
+    pub fn set_verified(&self, active_user: &str, username: &str, verified: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET verified = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, verified])?;
+        Ok(())
+    }
+
+    /// Every recorded key change of `username`, oldest first.
+    pub fn get_key_changes(&self, active_user: &str, username: &str) -> Result<Vec<KeyChangeRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT old_fingerprint, new_fingerprint, changed_at FROM {} WHERE username = ?1 ORDER BY id ASC",
+            table("key_changes", active_user)
+        ))?;
+        let rows = stmt.query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Every recorded key change of `username` with the key it replaced,
+    /// oldest first, as an account export carries them.
+    pub fn get_key_history(&self, active_user: &str, username: &str) -> Result<Vec<KeyHistoryRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT old_fingerprint, new_fingerprint, old_public_key, changed_at FROM {} WHERE username = ?1 ORDER BY id ASC",
+            table("key_changes", active_user)
+        ))?;
+        let rows =
+            stmt.query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Stores key changes of `username` read by [`Db::get_key_history`], e.g.
+    /// from an account export, keeping their times.
+    pub fn import_key_history(&self, active_user: &str, username: &str, changes: &[KeyHistoryRow]) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO {} (username, old_fingerprint, new_fingerprint, old_public_key, changed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                table("key_changes", active_user)
+            ))?;
+            for (old_fingerprint, new_fingerprint, old_public_key, changed_at) in changes {
+                stmt.execute(params![username, old_fingerprint, new_fingerprint, old_public_key, changed_at])?;
+            }
+        }
+        tx.commit()?;
+        Ok(changes.len())
+    }
+
+    /// Keeps the server-signed `queryResponse` that gave us the key of `username`.
+    pub fn set_key_observation(
+        &self,
+        active_user: &str,
+        username: &str,
+        content: &str,
+        signature: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET key_observation = ?2, key_observation_signature = ?3 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, content, signature])?;
+        Ok(())
+    }
+
+    pub fn get_key_observation(&self, active_user: &str, username: &str) -> Result<Option<ObservationRow>> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT key_observation, key_observation_signature FROM {}
+                 WHERE username = ?1 AND key_observation IS NOT NULL AND key_observation_signature IS NOT NULL",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?)
+    }
+
+    /// Appends `event` to the audit log, chained to the entry before it.
+    pub fn append_audit_event(&self, active_user: &str, event: &str, detail: &str) -> Result<()> {
+        let audit = table("audit", active_user);
+        let tx = self.conn.unchecked_transaction()?;
+        let previous: String = tx
+            .query_row(&format!("SELECT mac FROM {} ORDER BY id DESC LIMIT 1", audit), [], |row| row.get(0))
+            .optional()?
+            .unwrap_or_default();
+        let timestamp: String = tx.query_row("SELECT CURRENT_TIMESTAMP", [], |row| row.get(0))?;
+        let mac = audit_mac(&self.audit_key, &previous, event, detail, &timestamp);
+        tx.execute(
+            &format!("INSERT INTO {} (event, detail, timestamp, mac) VALUES (?1, ?2, ?3, ?4)", audit),
+            params![event, detail, timestamp, mac],
+        )?;
+        Ok(tx.commit()?)
+    }
+
+    /// The audit log, oldest first.
+    pub fn get_audit_log(&self, active_user: &str) -> Result<Vec<AuditRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, event, detail, timestamp FROM {} ORDER BY id ASC",
+            table("audit", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Checks the audit log's chain. Returns the id of the first entry that was
+    /// altered, inserted or follows a removed one, or `None` if the log is intact.
+    pub fn verify_audit_log(&self, active_user: &str) -> Result<Option<i64>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, event, detail, timestamp, mac FROM {} ORDER BY id ASC",
+            table("audit", active_user)
+        ))?;
+        let mut rows = stmt.query([])?;
+        let mut previous = String::new();
+        while let Some(row) = rows.next()? {
+            let (id, event, detail, timestamp, mac): (i64, String, String, String, String) =
+                (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+            let expected = audit_mac(&self.audit_key, &previous, &event, &detail, &timestamp);
+            if !ct_eq(expected.as_bytes(), mac.as_bytes()) {
+                return Ok(Some(id));
+            }
+            previous = mac;
+        }
+        Ok(None)
+    }
+
+    /// Runs SQLite's `integrity_check` over the whole database. Returns the
+    /// problems it found, none if the database is sound.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let problems = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(problems.into_iter().filter(|problem| problem != "ok").collect())
+    }
+
+    pub fn get_contact(&self, active_user: &str, username: &str) -> Result<Option<ContactRow>> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT username, public_key FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?)
+    }
+
+    pub fn get_all_contacts(&self, active_user: &str) -> Result<Vec<ContactRow>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT username, public_key FROM {}",
+            table("contacts", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Whether the active user shares their presence with `username`.
+    pub fn set_share_presence(&self, active_user: &str, username: &str, share: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET share_presence = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, share])?;
+        Ok(())
+    }
+
+    /// Records when `username` was last seen online.
+    pub fn set_last_seen(&self, active_user: &str, username: &str, last_seen: i64) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET last_seen = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, last_seen])?;
+        Ok(())
+    }
+
+    pub fn get_presence(&self, active_user: &str) -> Result<Vec<PresenceRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT username, share_presence, last_seen FROM {}",
+            table("contacts", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn set_anonymous(&self, active_user: &str, username: &str, anonymous: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET anonymous = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, anonymous])?;
+        Ok(())
+    }
+
+    /// The contacts we send to without revealing our nym address.
+    pub fn get_anonymous_contacts(&self, active_user: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT username FROM {} WHERE anonymous = 1 ORDER BY username",
+            table("contacts", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Whether read receipts and typing indicators are sent to `username`;
+    /// `None` follows the active user's settings.
+    pub fn set_contact_privacy(
+        &self,
+        active_user: &str,
+        username: &str,
+        send_receipts: Option<bool>,
+        send_typing: Option<bool>,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET send_receipts = ?2, send_typing = ?3 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, send_receipts, send_typing])?;
+        Ok(())
+    }
+
+    /// The contacts with a receipt or typing setting of their own.
+    pub fn get_contact_privacy(&self, active_user: &str) -> Result<Vec<ContactPrivacyRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT username, send_receipts, send_typing FROM {}
+             WHERE send_receipts IS NOT NULL OR send_typing IS NOT NULL",
+            table("contacts", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn set_contact_handshake(
+        &self,
+        active_user: &str,
+        username: &str,
+        version: u32,
+        features: &[String],
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET handshake_version = ?2, handshake_features = ?3 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, version, features.join(",")])?;
+        Ok(())
+    }
+
+    /// What the last handshake with `username` agreed on; `None` before any.
+    pub fn get_contact_handshake(&self, active_user: &str, username: &str) -> Result<Option<HandshakeRow>> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT handshake_version, handshake_features FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| {
+                let version: Option<u32> = row.get(0)?;
+                let features: Option<String> = row.get(1)?;
+                let features = features.unwrap_or_default();
+                let features = features.split(',').filter(|f| !f.is_empty()).map(str::to_string).collect();
+                Ok(version.map(|version| (version, features)))
+            })
+            .optional()
+            .map(Option::flatten)?)
+    }
+
+    /// Records the features a handshake with `username` dropped that the
+    /// user has not accepted yet; none clears them.
+    pub fn set_handshake_downgrade(&self, active_user: &str, username: &str, lost: &[String]) -> Result<()> {
+        let lost = if lost.is_empty() { None } else { Some(lost.join(",")) };
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET handshake_downgrade = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, lost])?;
+        Ok(())
+    }
+
+    /// The features dropped with `username` awaiting the user's acceptance;
+    /// empty if there are none.
+    pub fn get_handshake_downgrade(&self, active_user: &str, username: &str) -> Result<Vec<String>> {
+        let lost: Option<String> = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT handshake_downgrade FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| row.get(0))
+            .optional()?
+            .flatten();
+        Ok(lost.unwrap_or_default().split(',').filter(|f| !f.is_empty()).map(str::to_string).collect())
+    }
+
+    /// Stores the Double Ratchet session with `username`, replacing the one
+    /// before; `None` drops it.
+    pub fn set_ratchet_session(&self, active_user: &str, username: &str, session: Option<&str>) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET ratchet_session = ?2 WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .execute(params![username, session])?;
+        Ok(())
+    }
+
+    /// The Double Ratchet session with `username`, if one was set up.
+    pub fn get_ratchet_session(&self, active_user: &str, username: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT ratchet_session FROM {} WHERE username = ?1",
+                table("contacts", active_user)
+            ))?
+            .query_row(params![username], |row| row.get(0))
+            .optional()?
+            .flatten())
+    }
+
+    pub fn delete_contact(&self, active_user: &str, username: &str) -> Result<()> {
+        self.conn.execute(
+            &format!("DELETE FROM {} WHERE username = ?1", table("contacts", active_user)),
+            params![username],
+        )?;
+        Ok(())
+    }
+
+    /// Stores a message exchanged with `username`; `msg_type` is `"to"` or
+    /// `"from"`, `kind` the [`StoredMessage`] kind.
+    pub fn save_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        msg_type: &str,
+        message: &str,
+        kind: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (username, type, message) VALUES (?1, ?2, ?3)",
+                table("messages", active_user)
+            ))?
+            .execute(params![username, msg_type, StoredMessage::new(kind, message).encode()])?;
+        Ok(())
+    }
+
+    /// Stores a batch of messages in one transaction. A burst such as an
+    /// offline queue flush then costs one commit instead of one per message.
+    pub fn save_messages(&self, active_user: &str, messages: &[NewMessage]) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO {} (username, type, message) VALUES (?1, ?2, ?3)",
+                table("messages", active_user)
+            ))?;
+            for (username, msg_type, message, kind) in messages {
+                stmt.execute(params![username, msg_type, StoredMessage::new(kind, message).encode()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(messages.len())
+    }
+
+    /// Stores a message sent to `username` under the id it went out with, so
+    /// receipts for it can be matched; `status` is one of [`MESSAGE_STATUSES`].
+    pub fn save_sent_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        message: &str,
+        kind: &str,
+        send_id: &str,
+        status: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (username, type, message, send_id, status) VALUES (?1, 'to', ?2, ?3, ?4)",
+                table("messages", active_user)
+            ))?
+            .execute(params![username, StoredMessage::new(kind, message).encode(), send_id, status])?;
+        Ok(())
+    }
+
+    /// Moves the messages sent to `username` under `send_ids` on to `status`.
+    /// States only move forward, so a late `"sent"` does not undo a receipt,
+    /// and a status not in [`MESSAGE_STATUSES`] changes nothing. Returns how
+    /// many messages changed.
+    pub fn set_message_status(
+        &self,
+        active_user: &str,
+        username: &str,
+        send_ids: &[String],
+        status: &str,
+    ) -> Result<usize> {
+        let rank = match MESSAGE_STATUSES.iter().position(|known| *known == status) {
+            Some(rank) => rank as i64,
+            None => return Ok(0),
+        };
+        let tx = self.conn.unchecked_transaction()?;
+        let mut changed = 0;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                "UPDATE {} SET status = ?3 WHERE username = ?1 AND send_id = ?2 AND type = 'to'
+                 AND CASE status WHEN 'pending' THEN 0 WHEN 'sent' THEN 1 WHEN 'delivered' THEN 2
+                 WHEN 'read' THEN 3 ELSE -1 END < ?4",
+                table("messages", active_user)
+            ))?;
+            for send_id in send_ids {
+                changed += stmt.execute(params![username, send_id, status, rank])?;
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// The send ids and delivery states of the messages sent to `username`
+    /// that have them, oldest first.
+    pub fn get_message_statuses(&self, active_user: &str, username: &str) -> Result<Vec<DeliveryRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, send_id, status FROM {} WHERE username = ?1 AND type = 'to' AND send_id IS NOT NULL
+             AND status IS NOT NULL ORDER BY id",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Stores messages with their original timestamps, e.g. history restored
+    /// from an account export, as text.
+    pub fn import_messages(&self, active_user: &str, messages: &[ConversationRow]) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO {} (username, type, message, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                table("messages", active_user)
+            ))?;
+            for (username, msg_type, message, timestamp) in messages {
+                let message = StoredMessage::new(KIND_TEXT, message).encode();
+                stmt.execute(params![username, msg_type, message, timestamp])?;
+            }
+        }
+        tx.commit()?;
+        Ok(messages.len())
+    }
+
+    /// Adds the contacts and messages of `username` in the database at `path`,
+    /// written by the old Python client or an earlier version of this one, to
+    /// theirs here. Contacts already here keep the key stored here; those
+    /// whose key differs are listed in [`LegacyImport::conflicts`]. Messages
+    /// keep their timestamps, and ones already here are skipped, so importing
+    /// twice adds nothing. The user is registered with the key stored there
+    /// if they are not yet. See [`crate::legacy::import_account`] for the key files.
+    pub fn import_legacy(&self, username: &str, path: &Path) -> Result<LegacyImport> {
+        let invalid = |reason: &str| StorageError::Import { path: path.to_path_buf(), reason: reason.to_string() };
+        let location = path.to_str().ok_or_else(|| invalid("the path is not valid UTF-8"))?;
+        self.conn.execute("ATTACH DATABASE ?1 AS legacy", params![location])?;
+        let imported = self.import_attached(username).and_then(|imported| imported.ok_or_else(|| {
+            invalid(&format!("no account {} in it", username))
+        }));
+        self.conn.execute("DETACH DATABASE legacy", [])?;
+        imported
+    }
+
+    /// [`Db::import_legacy`] from the database attached as `legacy`, or `None`
+    /// if it holds no tables of `username`.
+    fn import_attached(&self, username: &str) -> Result<Option<LegacyImport>> {
+        let legacy_table = |prefix: &str| format!("legacy.{}", table(prefix, username));
+        let has_table = |name: String| -> Result<bool> {
+            Ok(self
+                .conn
+                .prepare("SELECT 1 FROM legacy.sqlite_master WHERE type = 'table' AND name = ?1")?
+                .exists(params![name])?)
+        };
+        let has_user_table = |prefix: &str| has_table(format!("{}_{}", prefix, username));
+        if !has_user_table("contacts")? || !has_user_table("messages")? {
+            return Ok(None);
+        }
+
+        let registered = self
+            .conn
+            .prepare("SELECT 1 FROM users WHERE username = ?1")?
+            .exists(params![username])?;
+        let legacy_key: Option<String> = if has_table("users".to_string())? {
+            let query = "SELECT public_key FROM legacy.users WHERE username = ?1";
+            self.conn.query_row(query, params![username], |row| row.get(0)).optional()?
+        } else {
+            None
+        };
+        match legacy_key {
+            Some(key) if !registered => self.register_user(username, &key)?,
+            _ => self.create_user_tables(username)?,
+        }
+
+        let mut imported = LegacyImport::default();
+        let contacts = self
+            .conn
+            .prepare(&format!("SELECT username, public_key FROM {} ORDER BY username", legacy_table("contacts")))?
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (contact, key) in contacts {
+            match self.get_contact(username, &contact)? {
+                None => {
+                    self.add_contact(username, &contact, &key)?;
+                    imported.contacts += 1;
+                }
+                Some((_, stored)) if stored.trim() != key.trim() => imported.conflicts.push(contact),
+                Some(_) => {}
+            }
+        }
+
+        // Rows of an old client are text; a newer one recorded the format it wrote.
+        let version = if has_user_table("settings")? {
+            self.conn
+                .query_row(
+                    &format!("SELECT value FROM {} WHERE key = ?1", legacy_table("settings")),
+                    params![MESSAGE_FORMAT_SETTING],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?
+                .and_then(|version| version.parse().ok())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let messages = table("messages", username);
+        let mut existing = std::collections::HashSet::new();
+        let mut stmt = self.conn.prepare(&format!("SELECT username, type, message, timestamp FROM {}", messages))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            existing.insert((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get(3)?));
+        }
+        let legacy_messages = self
+            .conn
+            .prepare(&format!(
+                "SELECT username, type, message, timestamp FROM {} ORDER BY id",
+                legacy_table("messages")
+            ))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, String, String)>>>()?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut insert = tx.prepare(&format!(
+                "INSERT INTO {} (username, type, message, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                messages
+            ))?;
+            for (contact, msg_type, message, timestamp) in legacy_messages {
+                let row = (contact, msg_type, StoredMessage::upgrade(version, &message).encode(), timestamp);
+                if existing.insert(row.clone()) {
+                    insert.execute(params![row.0, row.1, row.2, row.3])?;
+                    imported.messages += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(Some(imported))
+    }
+
+    /// Returns the conversation with `username`, oldest first.
+    pub fn load_messages(&self, active_user: &str, username: &str) -> Result<Vec<MessageRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT type, message, timestamp FROM {} WHERE username = ?1 ORDER BY timestamp ASC",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![username], |row| Ok((row.get(0)?, body(row.get(1)?), row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns up to `limit` messages with `username` older than `before_id`
+    /// (the newest ones when `None`), oldest first. Lets the UI hold only the
+    /// recent part of long conversations and page back on demand.
+    pub fn load_messages_before(
+        &self,
+        active_user: &str,
+        username: &str,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<PagedMessageRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, type, message, timestamp FROM {} WHERE username = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![username, before_id.unwrap_or(i64::MAX), limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, body(row.get(2)?), row.get(3)?))
+        })?;
+        let mut page = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        page.reverse();
+        Ok(page)
+    }
+
+    pub fn get_all_messages(&self, active_user: &str) -> Result<Vec<ConversationRow>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT username, type, message, timestamp FROM {} ORDER BY username, timestamp ASC",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, body(row.get(2)?), row.get(3)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Messages with `username` stamped between `since` and `until`, both
+    /// included and unbounded when `None`, oldest first, for exporting.
+    pub fn export_messages(
+        &self,
+        active_user: &str,
+        username: &str,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<Vec<ExportRow>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT type, message, timestamp FROM {} WHERE username = ?1 AND timestamp >= ?2 AND timestamp <= ?3 \
+             ORDER BY timestamp ASC, id ASC",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![username, since.unwrap_or(""), until.unwrap_or("\u{10ffff}")], |row| {
+            let sender = if row.get::<_, String>(0)? == "to" { active_user } else { username };
+            Ok(export_row(sender.to_string(), row.get(1)?, row.get(2)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Messages in any conversation, direct or group, matching every word of
+    /// `query` (as the start of a word), best matches first, at most `limit`.
+    pub fn search_messages(&self, active_user: &str, query: &str, limit: usize) -> Result<Vec<SearchRow>> {
+        let query = match fts_query(query) {
+            Some(query) => query,
+            None => return Ok(Vec::new()),
+        };
+        let snippet = |index: &str| {
+            format!("snippet({}, 0, '{}', '{}', '…', {})", index, MATCH_START, MATCH_END, SNIPPET_WORDS)
+        };
+        let (index, group_index) = (table("message_index", active_user), table("group_message_index", active_user));
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT chat, id, sender, snippet, timestamp FROM (
+                 SELECT m.username AS chat, m.id AS id, CASE m.type WHEN 'to' THEN ?3 ELSE m.username END AS sender,
+                        {snippet} AS snippet, m.timestamp AS timestamp, bm25({index}) AS rank
+                 FROM {index} JOIN {messages} AS m ON m.id = {index}.rowid WHERE {index} MATCH ?1
+                 UNION ALL
+                 SELECT 'group:' || g.group_id, g.id, g.sender, {group_snippet}, g.timestamp, bm25({group_index})
+                 FROM {group_index} JOIN {group_messages} AS g ON g.id = {group_index}.rowid
+                 WHERE {group_index} MATCH ?1
+             ) ORDER BY rank LIMIT ?2",
+            snippet = snippet(&index),
+            group_snippet = snippet(&group_index),
+            index = index,
+            group_index = group_index,
+            messages = table("messages", active_user),
+            group_messages = table("group_messages", active_user),
+        ))?;
+        let rows = stmt.query_map(params![query, limit.min(i64::MAX as usize) as i64, active_user], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Deletes the stored copy of a message shown in a chat with `username`:
+    /// the one of `type` with text `body` stamped closest to `timestamp`.
+    /// Returns whether there was one.
+    pub fn delete_message(
+        &self,
+        active_user: &str,
+        username: &str,
+        message_type: &str,
+        body: &str,
+        timestamp: &str,
+    ) -> Result<bool> {
+        self.delete_closest(
+            &table("messages", active_user),
+            "username = ?1 AND type = ?2",
+            params![username, message_type, timestamp],
+            body,
+        )
+    }
+
+    /// Deletes the row of `messages` matching `filter` whose text is `body`,
+    /// stamped closest to the timestamp bound to `?3`.
+    fn delete_closest(
+        &self,
+        messages: &str,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+        body: &str,
+    ) -> Result<bool> {
+        // Shown and stored times can be a moment apart, as the UI stamps a message when it shows it.
+        let candidates = self
+            .conn
+            .prepare(&format!(
+                "SELECT id, message FROM {} WHERE {} ORDER BY ABS(julianday(timestamp) - julianday(?3)), id DESC",
+                messages, filter
+            ))?
+            .query_map(params, |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        match candidates.into_iter().find(|(_, message)| StoredMessage::decode(message).body == body) {
+            Some((id, _)) => {
+                self.conn.execute(&format!("DELETE FROM {} WHERE id = ?1", messages), params![id])?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Usernames the active user has exchanged at least one message with.
+    pub fn get_chat_usernames(&self, active_user: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT DISTINCT username FROM {}",
+            table("messages", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn delete_all_messages(&self, active_user: &str) -> Result<()> {
+        self.conn
+            .execute(&format!("DELETE FROM {}", table("messages", active_user)), [])?;
+        Ok(())
+    }
+
+    /// Stores a group, its sender-key session (see `nymchat_protocol::GroupSession`)
+    /// and the membership its log has established (`nymchat_protocol::MembershipLog`),
+    /// replacing what was stored for it before.
+    pub fn save_group(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        name: &str,
+        session: &str,
+        membership: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (group_id, name, session, membership) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(group_id) DO UPDATE SET
+                     name = excluded.name, session = excluded.session, membership = excluded.membership",
+                table("groups", active_user)
+            ))?
+            .execute(params![group_id, name, session, membership])?;
+        Ok(())
+    }
+
+    /// A group the active user is in.
+    pub fn get_group(&self, active_user: &str, group_id: &str) -> Result<Option<StoredGroup>> {
+        Ok(self.conn
+            .prepare_cached(&format!(
+                "SELECT name, session, membership FROM {} WHERE group_id = ?1",
+                table("groups", active_user)
+            ))?
+            .query_row(params![group_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .optional()?)
+    }
+
+    pub fn get_groups(&self, active_user: &str) -> Result<Vec<GroupRow>> {
+        let mut stmt =
+            self.conn.prepare(&format!("SELECT group_id, name FROM {} ORDER BY name", table("groups", active_user)))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn save_group_message(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        sender: &str,
+        message: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (group_id, sender, message) VALUES (?1, ?2, ?3)",
+                table("group_messages", active_user)
+            ))?
+            .execute(params![group_id, sender, StoredMessage::new(KIND_TEXT, message).encode()])?;
+        Ok(())
+    }
+
+    /// Stores group messages with their original timestamps, e.g. history
+    /// shared by an admin on joining. Messages already stored are skipped.
+    /// Returns how many were new.
+    pub fn import_group_messages(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        messages: &[GroupMessageRow],
+    ) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut imported = 0;
+        {
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO {t} (group_id, sender, message, timestamp) SELECT ?1, ?2, ?3, ?4
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM {t} WHERE group_id = ?1 AND sender = ?2 AND message = ?3 AND timestamp = ?4
+                 )",
+                t = table("group_messages", active_user)
+            ))?;
+            for (sender, message, timestamp) in messages {
+                let message = StoredMessage::new(KIND_TEXT, message).encode();
+                imported += stmt.execute(params![group_id, sender, message, timestamp])?;
+            }
+        }
+        tx.commit()?;
+        Ok(imported)
+    }
+
+    /// The messages of a group, oldest first.
+    pub fn load_group_messages(&self, active_user: &str, group_id: &str) -> Result<Vec<GroupMessageRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT sender, message, timestamp FROM {} WHERE group_id = ?1 ORDER BY timestamp ASC, id ASC",
+            table("group_messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![group_id], |row| Ok((row.get(0)?, body(row.get(1)?), row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// As [`Db::export_messages`], for a group.
+    pub fn export_group_messages(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<Vec<ExportRow>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT sender, message, timestamp FROM {} WHERE group_id = ?1 AND timestamp >= ?2 AND timestamp <= ?3 \
+             ORDER BY timestamp ASC, id ASC",
+            table("group_messages", active_user)
+        ))?;
+        let rows = stmt.query_map(params![group_id, since.unwrap_or(""), until.unwrap_or("\u{10ffff}")], |row| {
+            Ok(export_row(row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// As [`Db::delete_message`], for a message `sender` sent to a group.
+    pub fn delete_group_message(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        sender: &str,
+        body: &str,
+        timestamp: &str,
+    ) -> Result<bool> {
+        self.delete_closest(
+            &table("group_messages", active_user),
+            "group_id = ?1 AND sender = ?2",
+            params![group_id, sender, timestamp],
+            body,
+        )
+    }
+
+    /// Records a membership change applied to a group's log. Entries are kept
+    /// as signed so they can be passed on to members who join later.
+    pub fn append_group_log(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        seq: u64,
+        entry: &str,
+        signature: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT OR IGNORE INTO {} (group_id, seq, entry, signature) VALUES (?1, ?2, ?3, ?4)",
+                table("group_log", active_user)
+            ))?
+            .execute(params![group_id, seq as i64, entry, signature])?;
+        Ok(())
+    }
+
+    /// A group's membership log, first entry first.
+    pub fn get_group_log(&self, active_user: &str, group_id: &str) -> Result<Vec<GroupLogRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT entry, signature FROM {} WHERE group_id = ?1 ORDER BY seq ASC",
+            table("group_log", active_user)
+        ))?;
+        let rows = stmt.query_map(params![group_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Records an invite to a group. Only a hash of the invite's secret is
+    /// kept; `expires_at` is in Unix seconds, `None` for invites that never expire.
+    pub fn save_group_invite(
+        &self,
+        active_user: &str,
+        secret_hash: &str,
+        group_id: &str,
+        expires_at: Option<i64>,
+        single_use: bool,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (secret_hash, group_id, expires_at, single_use) VALUES (?1, ?2, ?3, ?4)",
+                table("group_invites", active_user)
+            ))?
+            .execute(params![secret_hash, group_id, expires_at, single_use])?;
+        Ok(())
+    }
+
+    /// Uses up one redemption of an invite to `group_id`, if it exists, has
+    /// not expired by `now` and, if single use, was not redeemed before.
+    /// Returns whether it could be redeemed.
+    pub fn redeem_group_invite(
+        &self,
+        active_user: &str,
+        secret_hash: &str,
+        group_id: &str,
+        now: i64,
+    ) -> Result<bool> {
+        let redeemed = self
+            .conn
+            .prepare_cached(&format!(
+                "UPDATE {} SET uses = uses + 1
+                 WHERE secret_hash = ?1 AND group_id = ?2
+                   AND (expires_at IS NULL OR expires_at > ?3)
+                   AND (single_use = 0 OR uses = 0)",
+                table("group_invites", active_user)
+            ))?
+            .execute(params![secret_hash, group_id, now])?;
+        Ok(redeemed > 0)
+    }
+
+    /// Stores a poll posted to a group. A poll id already taken in the group
+    /// keeps its first poll.
+    #[allow(clippy::too_many_arguments)]
+    pub fn save_poll(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        poll_id: &str,
+        creator: &str,
+        question: &str,
+        options: &str,
+        multiple: bool,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT OR IGNORE INTO {} (group_id, poll_id, creator, question, options, multiple)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                table("polls", active_user)
+            ))?
+            .execute(params![group_id, poll_id, creator, question, options, multiple])?;
+        Ok(())
+    }
+
+    /// The polls of a group, oldest first.
+    pub fn get_polls(&self, active_user: &str, group_id: &str) -> Result<Vec<PollRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT poll_id, creator, question, options, multiple, timestamp FROM {}
+             WHERE group_id = ?1 ORDER BY timestamp ASC",
+            table("polls", active_user)
+        ))?;
+        let rows = stmt.query_map(params![group_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Records the choices of `voter` in a poll, replacing their earlier vote.
+    pub fn save_poll_vote(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        poll_id: &str,
+        voter: &str,
+        choices: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (group_id, poll_id, voter, choices) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(group_id, poll_id, voter) DO UPDATE SET choices = excluded.choices",
+                table("poll_votes", active_user)
+            ))?
+            .execute(params![group_id, poll_id, voter, choices])?;
+        Ok(())
+    }
+
+    pub fn get_poll_votes(
+        &self,
+        active_user: &str,
+        group_id: &str,
+        poll_id: &str,
+    ) -> Result<Vec<PollVoteRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT voter, choices FROM {} WHERE group_id = ?1 AND poll_id = ?2 ORDER BY voter",
+            table("poll_votes", active_user)
+        ))?;
+        let rows = stmt.query_map(params![group_id, poll_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Records the sender tag of direct messages from `username`, received at
+    /// our `nym_address`; replies through it use the SURBs kept with that
+    /// mixnet identity.
+    pub fn set_sender_tag(
+        &self,
+        active_user: &str,
+        username: &str,
+        sender_tag: &str,
+        nym_address: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (username, sender_tag, nym_address) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(username) DO UPDATE SET sender_tag = excluded.sender_tag,
+                 nym_address = excluded.nym_address",
+                table("sender_tags", active_user)
+            ))?
+            .execute(params![username, sender_tag, nym_address])?;
+        Ok(())
+    }
+
+    /// The sender tags received at `nym_address`, by correspondent.
+    pub fn get_sender_tags(&self, active_user: &str, nym_address: &str) -> Result<Vec<SenderTagRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT username, sender_tag FROM {} WHERE nym_address = ?1 ORDER BY username",
+            table("sender_tags", active_user)
+        ))?;
+        let rows = stmt.query_map(params![nym_address], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn delete_sender_tag(&self, active_user: &str, username: &str) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!("DELETE FROM {} WHERE username = ?1", table("sender_tags", active_user)))?
+            .execute(params![username])?;
+        Ok(())
+    }
+
+    /// Queues a message composed while the mixnet is down and returns its id;
+    /// ids grow in the order messages were queued.
+    pub fn queue_outbox(&self, active_user: &str, chat: &str, content: &str) -> Result<i64> {
+        self.conn
+            .prepare_cached(&format!("INSERT INTO {} (chat, content) VALUES (?1, ?2)", table("outbox", active_user)))?
+            .execute(params![chat, content])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// The queued messages, oldest first.
+    pub fn get_outbox(&self, active_user: &str) -> Result<Vec<OutboxRow>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(&format!("SELECT id, chat, content FROM {} ORDER BY id", table("outbox", active_user)))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn delete_outbox(&self, active_user: &str, id: i64) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table("outbox", active_user)))?
+            .execute(params![id])?;
+        Ok(())
+    }
+
+    /// Keeps a message that could not be parsed, decrypted or verified, with
+    /// why, and returns its id. Only the newest [`QUARANTINE_LIMIT`] are kept.
+    pub fn quarantine(&self, active_user: &str, sender: Option<&str>, reason: &str, content: &str) -> Result<i64> {
+        let quarantine = table("quarantine", active_user);
+        self.conn
+            .prepare_cached(&format!("INSERT INTO {} (sender, reason, content) VALUES (?1, ?2, ?3)", quarantine))?
+            .execute(params![sender, reason, content])?;
+        let id = self.conn.last_insert_rowid();
+        self.conn
+            .prepare_cached(&format!("DELETE FROM {} WHERE id <= ?1", quarantine))?
+            .execute(params![id - QUARANTINE_LIMIT])?;
+        Ok(id)
+    }
+
+    /// The quarantined messages, newest first.
+    pub fn get_quarantine(&self, active_user: &str) -> Result<Vec<QuarantineRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, sender, reason, content, received_at FROM {} ORDER BY id DESC",
+            table("quarantine", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn count_quarantine(&self, active_user: &str) -> Result<u64> {
+        Ok(self.conn
+            .prepare_cached(&format!("SELECT COUNT(*) FROM {}", table("quarantine", active_user)))?
+            .query_row([], |row| row.get(0))?)
+    }
+
+    pub fn delete_quarantined(&self, active_user: &str, id: i64) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table("quarantine", active_user)))?
+            .execute(params![id])?;
+        Ok(())
+    }
+
+    pub fn clear_quarantine(&self, active_user: &str) -> Result<()> {
+        self.conn.prepare_cached(&format!("DELETE FROM {}", table("quarantine", active_user)))?.execute([])?;
+        Ok(())
+    }
+
+    pub fn get_setting(&self, active_user: &str, key: &str) -> Result<Option<String>> {
+        Ok(self.conn
+            .prepare_cached(&format!("SELECT value FROM {} WHERE key = ?1", table("settings", active_user)))?
+            .query_row(params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn set_setting(&self, active_user: &str, key: &str, value: &str) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                table("settings", active_user)
+            ))?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+
+    /// Adds a folder after the existing ones; returns false if there is one
+    /// of that name already.
+    pub fn create_folder(&self, active_user: &str, name: &str) -> Result<bool> {
+        let folders = table("folders", active_user);
+        let added = self
+            .conn
+            .prepare_cached(&format!(
+                "INSERT OR IGNORE INTO {folders} (name, position)
+                 SELECT ?1, COALESCE(MAX(position) + 1, 0) FROM {folders}",
+                folders = folders
+            ))?
+            .execute(params![name])?;
+        Ok(added > 0)
+    }
+
+    /// Renames a folder, keeping its chats in it; returns false if `old` does
+    /// not exist or `new` does.
+    pub fn rename_folder(&self, active_user: &str, old: &str, new: &str) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let renamed = tx.execute(
+            &format!(
+                "UPDATE {folders} SET name = ?2
+                 WHERE name = ?1 AND NOT EXISTS (SELECT 1 FROM {folders} WHERE name = ?2)",
+                folders = table("folders", active_user)
+            ),
+            params![old, new],
+        )?;
+        if renamed > 0 {
+            tx.execute(
+                &format!("UPDATE {} SET folder = ?2 WHERE folder = ?1", table("folder_chats", active_user)),
+                params![old, new],
+            )?;
+        }
+        tx.commit()?;
+        Ok(renamed > 0)
+    }
+
+    /// Removes a folder; its chats are left outside any folder.
+    pub fn delete_folder(&self, active_user: &str, name: &str) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(&format!("DELETE FROM {} WHERE folder = ?1", table("folder_chats", active_user)), params![name])?;
+        tx.execute(&format!("DELETE FROM {} WHERE name = ?1", table("folders", active_user)), params![name])?;
+        Ok(tx.commit()?)
+    }
+
+    /// The folders in the order they were made.
+    pub fn get_folders(&self, active_user: &str) -> Result<Vec<FolderRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT name, collapsed FROM {} ORDER BY position",
+            table("folders", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn set_folder_collapsed(&self, active_user: &str, name: &str, collapsed: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!("UPDATE {} SET collapsed = ?2 WHERE name = ?1", table("folders", active_user)))?
+            .execute(params![name, collapsed])?;
+        Ok(())
+    }
+
+    /// Files `chat` in `folder`, or in none when `None`. A chat is in at most
+    /// one folder; filing it in a folder that does not exist does nothing.
+    pub fn set_chat_folder(&self, active_user: &str, chat: &str, folder: Option<&str>) -> Result<()> {
+        let chats = table("folder_chats", active_user);
+        match folder {
+            Some(folder) => self
+                .conn
+                .prepare_cached(&format!(
+                    "INSERT INTO {} (chat, folder) SELECT ?1, name FROM {} WHERE name = ?2
+                     ON CONFLICT(chat) DO UPDATE SET folder = excluded.folder",
+                    chats,
+                    table("folders", active_user)
+                ))?
+                .execute(params![chat, folder])?,
+            None => {
+                self.conn.prepare_cached(&format!("DELETE FROM {} WHERE chat = ?1", chats))?.execute(params![chat])?
+            }
+        };
+        Ok(())
+    }
+
+    pub fn get_chat_folders(&self, active_user: &str) -> Result<Vec<ChatFolderRow>> {
+        let mut stmt =
+            self.conn.prepare_cached(&format!("SELECT chat, folder FROM {}", table("folder_chats", active_user)))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Sets how a chat notifies: `level` is `all`, `mentions` or `none`, and
+    /// while muted (until `muted_until`, or for good when it is -1) it does not
+    /// notify at all. Chats left at the defaults have no row.
+    pub fn set_chat_notifications(
+        &self,
+        active_user: &str,
+        chat: &str,
+        level: &str,
+        muted_until: Option<i64>,
+    ) -> Result<()> {
+        let notifications = table("notifications", active_user);
+        if level == DEFAULT_NOTIFY_LEVEL && muted_until.is_none() {
+            self.conn
+                .prepare_cached(&format!("DELETE FROM {} WHERE chat = ?1", notifications))?
+                .execute(params![chat])?;
+        } else {
+            self.conn
+                .prepare_cached(&format!(
+                    "INSERT INTO {} (chat, level, muted_until) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(chat) DO UPDATE SET level = excluded.level, muted_until = excluded.muted_until",
+                    notifications
+                ))?
+                .execute(params![chat, level, muted_until])?;
+        }
+        Ok(())
+    }
+
+    /// The chats with a notification setting of their own.
+    pub fn get_chat_notifications(&self, active_user: &str) -> Result<Vec<NotificationRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT chat, level, muted_until FROM {}",
+            table("notifications", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Keeps `text` as the unsent draft of `chat`; an empty one drops it.
+    pub fn save_draft(&self, active_user: &str, chat: &str, text: &str) -> Result<()> {
+        let drafts = table("drafts", active_user);
+        if text.is_empty() {
+            self.conn.prepare_cached(&format!("DELETE FROM {} WHERE chat = ?1", drafts))?.execute(params![chat])?;
+        } else {
+            self.conn
+                .prepare_cached(&format!(
+                    "INSERT INTO {} (chat, text, updated_at) VALUES (?1, ?2, CURRENT_TIMESTAMP)
+                     ON CONFLICT(chat) DO UPDATE SET text = excluded.text, updated_at = excluded.updated_at",
+                    drafts
+                ))?
+                .execute(params![chat, text])?;
+        }
+        Ok(())
+    }
+
+    /// The unsent drafts, most recently changed first.
+    pub fn get_drafts(&self, active_user: &str) -> Result<Vec<DraftRow>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT chat, text FROM {} ORDER BY updated_at DESC, rowid DESC",
+            table("drafts", active_user)
+        ))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
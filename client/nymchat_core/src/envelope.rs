@@ -0,0 +1,238 @@
+//! The compact encoding of chat envelopes.
+//!
+//! A direct chat message as JSON spends about 520 bytes on its framing: field
+//! names, the ephemeral key as PEM, every key, nonce and signature as hex, and
+//! the payload escaped once more inside the envelope. The compact form carries
+//! the same message as a CBOR map with numeric keys and raw bytes, behind a
+//! two-byte header:
+//!
+//! ```text
+//! "~" base64url(version: u8 | action code: u8 | CBOR map)
+//! ```
+//!
+//! The mixnet API carries text, hence the base64. JSON always starts with `{`,
+//! so the `~` tells the two apart. [`decode`] turns a compact envelope back
+//! into the JSON one it was made from, field for field, so the payload
+//! signature still verifies and everything after it only ever sees JSON.
+//!
+//! Only chat payloads ([`ChatPayload`]) have a compact form, and only ones it
+//! carries without loss: [`encode`] leaves everything else as JSON. Clients
+//! send compact envelopes to contacts whose handshake agreed on
+//! [`COMPACT_FEATURE`].
+
+use crate::error::ProtocolError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::value::{Integer, Value as Cbor};
+use nymchat_protocol::{to_python_json, ChatPayload, Encrypted, EncryptedBody, Envelope, MessageBody};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+use p256::PublicKey;
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// Handshake feature of clients that read compact envelopes.
+pub const COMPACT_FEATURE: &str = "compactEnvelope";
+
+/// First character of a compact envelope.
+pub const COMPACT_PREFIX: char = '~';
+
+/// Version of the compact encoding, the first byte of the frame.
+pub const COMPACT_VERSION: u8 = 1;
+
+/// Actions with a compact form, by their code in the frame.
+const ACTIONS: &[(u8, &str)] = &[(1, "incomingMessage"), (2, "send")];
+
+// Keys of the CBOR map. Binary fields are stored as bytes, keys as SEC1
+// compressed points.
+const CONTEXT: u8 = 1;
+const SIGNATURE: u8 = 2;
+const SENDER: u8 = 3;
+const RECIPIENT: u8 = 4;
+const RECIPIENT_TOKEN: u8 = 5;
+const EPHEMERAL_KEY: u8 = 6;
+const SALT: u8 = 7;
+const IV: u8 = 8;
+const CIPHERTEXT: u8 = 9;
+const TAG: u8 = 10;
+const PAYLOAD_SIGNATURE: u8 = 11;
+const ENCRYPTED: u8 = 12;
+const SENDER_PUBLIC_KEY: u8 = 13;
+
+type Result<T> = std::result::Result<T, ProtocolError>;
+
+/// Whether `raw` is a compact envelope rather than JSON.
+pub fn is_compact(raw: &str) -> bool {
+    raw.starts_with(COMPACT_PREFIX)
+}
+
+/// The compact form of the JSON envelope `json`, or `None` if it has none:
+/// it is not a chat payload, or holds something the compact form would drop.
+pub fn encode(json: &str) -> Option<String> {
+    let envelope = Envelope::parse(json).ok()?;
+    let (code, _) = ACTIONS.iter().find(|(_, action)| *action == envelope.action)?;
+    if !envelope.fields.is_empty() {
+        return None;
+    }
+    let payload: ChatPayload = serde_json::from_str(envelope.content.as_deref()?).ok()?;
+    let body = &payload.body;
+    let encrypted = &body.encrypted_payload;
+
+    let mut map = Vec::new();
+    let mut put = |key: u8, value: Cbor| map.push((Cbor::Integer(key.into()), value));
+    if let Some(context) = &envelope.context {
+        put(CONTEXT, Cbor::Text(context.clone()));
+    }
+    if let Some(signature) = &envelope.signature {
+        put(SIGNATURE, Cbor::Bytes(hex::decode(signature).ok()?));
+    }
+    put(SENDER, Cbor::Text(payload.sender.clone()));
+    if let Some(recipient) = &payload.recipient {
+        put(RECIPIENT, Cbor::Text(recipient.clone()));
+    }
+    if let Some(token) = &payload.recipient_token {
+        put(RECIPIENT_TOKEN, Cbor::Text(token.clone()));
+    }
+    put(EPHEMERAL_KEY, Cbor::Bytes(point(&encrypted.ephemeral_public_key)?));
+    put(SALT, Cbor::Bytes(hex::decode(&encrypted.salt).ok()?));
+    put(IV, Cbor::Bytes(hex::decode(&encrypted.encrypted_body.iv).ok()?));
+    put(CIPHERTEXT, Cbor::Bytes(hex::decode(&encrypted.encrypted_body.ciphertext).ok()?));
+    put(TAG, Cbor::Bytes(hex::decode(&encrypted.encrypted_body.tag).ok()?));
+    put(PAYLOAD_SIGNATURE, Cbor::Bytes(hex::decode(&body.payload_signature).ok()?));
+    if let Some(flag) = payload.encrypted {
+        put(ENCRYPTED, Cbor::Bool(flag));
+    }
+    if let Some(key) = &payload.sender_public_key {
+        put(SENDER_PUBLIC_KEY, Cbor::Bytes(point(key)?));
+    }
+
+    let mut frame = vec![COMPACT_VERSION, *code];
+    ciborium::ser::into_writer(&Cbor::Map(map), &mut frame).ok()?;
+    let compact = format!("{}{}", COMPACT_PREFIX, URL_SAFE_NO_PAD.encode(frame));
+
+    // Unknown fields, upper-case hex or a key PEM not as the clients write
+    // it would not come back the same; those stay JSON.
+    let decoded = decode(&compact).ok()?;
+    (same_envelope(json, &decoded) == Some(true)).then_some(compact)
+}
+
+/// The JSON envelope `raw` stands for: `raw` itself unless it is compact.
+pub fn decode(raw: &str) -> Result<Cow<'_, str>> {
+    let encoded = match raw.strip_prefix(COMPACT_PREFIX) {
+        Some(encoded) => encoded,
+        None => return Ok(Cow::Borrowed(raw)),
+    };
+    let frame = URL_SAFE_NO_PAD.decode(encoded).map_err(|_| malformed("not base64url"))?;
+    let (version, code, map) = match frame.as_slice() {
+        [version, code, map @ ..] => (*version, *code, map),
+        _ => return Err(malformed("too short")),
+    };
+    if version != COMPACT_VERSION {
+        return Err(malformed(&format!("unknown version {}", version)));
+    }
+    let action = ACTIONS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, action)| *action)
+        .ok_or_else(|| malformed(&format!("unknown action code {}", code)))?;
+    let map = match ciborium::de::from_reader(map).map_err(|_| malformed("not CBOR"))? {
+        Cbor::Map(map) => Fields(map),
+        _ => return Err(malformed("not a map")),
+    };
+
+    let payload = ChatPayload {
+        sender: map.text(SENDER)?.ok_or_else(|| missing(SENDER))?,
+        recipient: map.text(RECIPIENT)?,
+        recipient_token: map.text(RECIPIENT_TOKEN)?,
+        body: MessageBody {
+            encrypted_payload: Encrypted {
+                ephemeral_public_key: pem(&map.required(EPHEMERAL_KEY)?)?,
+                salt: hex::encode(map.required(SALT)?),
+                encrypted_body: EncryptedBody {
+                    iv: hex::encode(map.required(IV)?),
+                    ciphertext: hex::encode(map.required(CIPHERTEXT)?),
+                    tag: hex::encode(map.required(TAG)?),
+                },
+            },
+            payload_signature: hex::encode(map.required(PAYLOAD_SIGNATURE)?),
+        },
+        encrypted: map.bool(ENCRYPTED)?,
+        sender_public_key: map.bytes(SENDER_PUBLIC_KEY)?.map(|key| pem(&key)).transpose()?,
+    };
+    let mut envelope = Envelope::new(action)
+        .with_content(to_python_json(&payload).map_err(|e| malformed(&e.to_string()))?);
+    envelope.context = map.text(CONTEXT)?;
+    envelope.signature = map.bytes(SIGNATURE)?.map(hex::encode);
+    Ok(Cow::Owned(to_python_json(&envelope).map_err(|e| malformed(&e.to_string()))?))
+}
+
+/// Whether two JSON envelopes say the same, their content compared as JSON.
+fn same_envelope(a: &str, b: &str) -> Option<bool> {
+    let parse = |raw: &str| -> Option<Value> {
+        let mut envelope: Value = serde_json::from_str(raw).ok()?;
+        let content: Value = serde_json::from_str(envelope.get("content")?.as_str()?).ok()?;
+        envelope["content"] = content;
+        Some(envelope)
+    };
+    Some(parse(a)? == parse(b)?)
+}
+
+/// The compressed point of a PEM public key.
+fn point(pem: &str) -> Option<Vec<u8>> {
+    let key = PublicKey::from_public_key_pem(pem).ok()?;
+    Some(key.to_encoded_point(true).as_bytes().to_vec())
+}
+
+/// A public key as the PEM the clients write: SPKI, uncompressed, LF line ends.
+fn pem(point: &[u8]) -> Result<String> {
+    PublicKey::from_sec1_bytes(point)
+        .map_err(|_| malformed("invalid public key"))?
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| malformed(&e.to_string()))
+}
+
+fn malformed(reason: &str) -> ProtocolError {
+    ProtocolError::CompactEnvelope(reason.to_string())
+}
+
+fn missing(key: u8) -> ProtocolError {
+    malformed(&format!("no field {}", key))
+}
+
+/// The CBOR map of a frame, looked up by key.
+struct Fields(Vec<(Cbor, Cbor)>);
+
+impl Fields {
+    fn get(&self, key: u8) -> Option<&Cbor> {
+        let key = Integer::from(key);
+        self.0.iter().find(|(k, _)| k.as_integer() == Some(key)).map(|(_, value)| value)
+    }
+
+    fn text(&self, key: u8) -> Result<Option<String>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Cbor::Text(text)) => Ok(Some(text.clone())),
+            Some(_) => Err(malformed(&format!("field {} is not text", key))),
+        }
+    }
+
+    fn bytes(&self, key: u8) -> Result<Option<Vec<u8>>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Cbor::Bytes(bytes)) => Ok(Some(bytes.clone())),
+            Some(_) => Err(malformed(&format!("field {} is not bytes", key))),
+        }
+    }
+
+    fn required(&self, key: u8) -> Result<Vec<u8>> {
+        self.bytes(key)?.ok_or_else(|| missing(key))
+    }
+
+    fn bool(&self, key: u8) -> Result<Option<bool>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Cbor::Bool(flag)) => Ok(Some(*flag)),
+            Some(_) => Err(malformed(&format!("field {} is not a boolean", key))),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Failures of the core, by kind, so an interface can tell a full disk from
+//! a lost connection or a server that said no, and say what to do about it.
+//!
+//! [`Db`](crate::Db) only fails with a [`StorageError`]; the mixnet client
+//! fails with an [`Error`], which is one of the four kinds.
+
+use nymchat_protocol::{CryptoError, NetworkError};
+use std::io;
+use std::path::PathBuf;
+
+/// The local store could not be read or written.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("cannot access {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{0} is not a 32-byte key")]
+    AuditKey(PathBuf),
+    /// An old client's account could not be imported; see [`crate::legacy`].
+    #[error("cannot import {path}: {reason}")]
+    Import { path: PathBuf, reason: String },
+    /// The keystore (see [`crate::keystore`]) does not open with the passphrase given.
+    #[error("wrong passphrase for {0}")]
+    Passphrase(PathBuf),
+    #[error("invalid keystore {path}: {reason}")]
+    Keystore { path: PathBuf, reason: String },
+}
+
+impl StorageError {
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| Self::Io { path, source }
+    }
+}
+
+/// The mixnet could not be reached, or a message did not get through.
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("invalid network configuration: {0}")]
+    Network(#[from] NetworkError),
+    #[error("failed to connect to the mixnet: {0}")]
+    Connect(String),
+    #[error("invalid recipient address {0}")]
+    Recipient(String),
+    #[error("failed to send: {0}")]
+    Send(String),
+    #[error("timed out waiting for {0} reply")]
+    Timeout(String),
+    #[error("no server answered the health probe")]
+    NoServer,
+    #[error("client disconnected")]
+    Disconnected,
+}
+
+/// A server answered, but not as the protocol expects, or refused.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    /// The server turned the request down; `reason` is what it said.
+    #[error("{context} failed: {reason}")]
+    Rejected { context: String, reason: String },
+    #[error("no nonce in {0} challenge")]
+    MissingNonce(String),
+    #[error("malformed {context} reply: {content}")]
+    Malformed { context: String, content: String },
+    #[error("{0} reply does not echo the nonce")]
+    NonceMismatch(String),
+    #[error("proof of work failed: {0}")]
+    ProofOfWork(String),
+    /// A compact envelope (see [`crate::envelope`]) that does not decode.
+    #[error("malformed compact envelope: {0}")]
+    CompactEnvelope(String),
+    /// A file transfer (see [`crate::transfer`]) that is malformed, too
+    /// large, or whose file does not match its manifest.
+    #[error("invalid file transfer: {0}")]
+    Transfer(String),
+}
+
+/// Any failure of the core.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
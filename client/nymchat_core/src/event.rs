@@ -0,0 +1,248 @@
+//! What arrives for the user, as typed events an interface can show without
+//! knowing the wire format. [`ChatClient`](crate::ChatClient) hands every
+//! embedder the same [`Event`]s, through its sink.
+//!
+//! Until an [`Inbox`] is set, which takes the user's key, everything that
+//! arrives is passed on as [`Event::Raw`]. With one, direct messages are
+//! decrypted and checked against the contacts in the user's [`Db`]; what
+//! the core does not model itself, such as server replies, handshakes and
+//! group messages, is still passed on raw for the embedder to handle.
+//! Direct messages that cannot be parsed, decrypted or verified are not
+//! dropped but quarantined in the [`Db`] with why, for the user to inspect,
+//! delete or retry; each one is announced with [`Event::Quarantined`].
+//! Compact envelopes (see [`envelope`](crate::envelope)) are read like JSON,
+//! and messages under a contact's Double Ratchet session (see
+//! [`protocol::ratchet`](crate::protocol::ratchet)) are opened with the
+//! session stored for them, which then moves on. A message under a key the
+//! user has not confirmed changes nothing stored.
+
+use crate::db::Db;
+use crate::envelope;
+use crate::error::Result;
+use crate::stored::KIND_TEXT;
+use nymchat_protocol::{ChatPayload, Crypto, Envelope, RatchetMessage, RatchetSession, SealedPayload};
+use p256::SecretKey;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Direct message types the inbox turns into events of their own.
+const CHAT_MESSAGE: u64 = 0;
+const LOCATION_MESSAGE: u64 = 9;
+const RECEIPT_MESSAGE: u64 = 10;
+const TYPING_MESSAGE: u64 = 11;
+/// What a receipt may say about our messages.
+const RECEIPT_STATUSES: [&str; 2] = ["delivered", "read"];
+/// Direct message type carrying another direct message under the Double Ratchet.
+const RATCHET_MESSAGE: u64 = 13;
+
+/// Whether the mixnet connection is up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportStatus {
+    Connected,
+    Disconnected,
+}
+
+/// Something that arrived, or happened to the connection. Timestamps are
+/// Unix seconds of when the message was received.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A decrypted chat message. `kind` is how it is stored (see
+    /// [`StoredMessage`](crate::StoredMessage)): `body` is the text, or for
+    /// a location its JSON. `verified` is whether it is signed with the key
+    /// stored for `from`; a message from someone new, or whose key changed,
+    /// is only signed with the key it brought along, and is not. Messages
+    /// whose signature fails are quarantined.
+    ChatMessage { from: String, body: String, kind: String, ts: u64, verified: bool },
+    /// `from`'s client received our messages sent under `ids`, or `from`
+    /// read them; `status` is `"delivered"` or `"read"`. The inbox records
+    /// it for the messages stored with those ids (see
+    /// [`Db::set_message_status`](crate::Db::set_message_status)). Receipts
+    /// and typing indicators under a new or changed key are dropped.
+    Receipt { from: String, ids: Vec<String>, status: String, ts: u64 },
+    /// `from` is typing to us. It is not stored; an interface shows it for a
+    /// few seconds, or until their next chat message.
+    Typing { from: String, ts: u64 },
+    /// Someone not in our contacts wrote, with the key they claim.
+    ContactRequest { from: String, public_key: String, ts: u64 },
+    /// A contact wrote with a key other than the one stored for them.
+    KeyChange { username: String, old_key: String, new_key: String },
+    /// A direct message was quarantined for `reason`; `count` are now kept.
+    Quarantined { reason: String, count: u64 },
+    TransportStatus(TransportStatus),
+    /// Anything else, as it arrived.
+    Raw { message: String, sender_tag: Option<String>, ts: u64 },
+}
+
+/// The inner message of a direct message.
+#[derive(Deserialize)]
+struct DirectMessage {
+    #[serde(rename = "type")]
+    message_type: u64,
+    #[serde(default)]
+    message: Value,
+}
+
+/// What a receipt (type 10) says; older clients sent a bare string meaning
+/// the contact read our messages.
+#[derive(Deserialize)]
+struct ReceiptMessage {
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default = "read_status")]
+    status: String,
+}
+
+fn read_status() -> String {
+    "read".to_string()
+}
+
+/// Why a direct message did not become events.
+enum Unopened {
+    /// It is not something the inbox handles; passed on raw.
+    Raw,
+    /// It is damaged, forged or not for us.
+    Quarantine { sender: Option<String>, reason: &'static str },
+}
+
+fn quarantine(sender: Option<&str>, reason: &'static str) -> Unopened {
+    Unopened::Quarantine { sender: sender.map(str::to_string), reason }
+}
+
+fn is_group_message(content: &str) -> bool {
+    serde_json::from_str::<Value>(content).is_ok_and(|value| value.get("group").is_some())
+}
+
+/// Unix seconds now, as events are stamped.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Decrypts and checks what arrives for one user.
+pub struct Inbox {
+    username: String,
+    key: SecretKey,
+    db: Db,
+}
+
+impl Inbox {
+    /// An inbox for `username`, with their PKCS#8 PEM key and database.
+    pub fn new(username: &str, private_key_pem: &str, db: Db) -> Result<Self> {
+        let key = Crypto::private_key_from_pem(private_key_pem)?;
+        Ok(Self { username: username.to_string(), key, db })
+    }
+
+    /// The events for `message`, received at `ts` with `sender_tag`.
+    /// Messages the inbox does not handle are passed on raw.
+    pub fn events(&self, message: &str, sender_tag: Option<String>, ts: u64) -> Vec<Event> {
+        // A compact envelope is handled, and passed on, as the JSON it stands for.
+        let decoded = envelope::decode(message);
+        let message = decoded.as_deref().unwrap_or(message);
+        match self.direct_message(message, ts) {
+            Ok(events) => events,
+            Err(Unopened::Quarantine { sender, reason }) => {
+                let stored = self.db.quarantine(&self.username, sender.as_deref(), reason, message);
+                match stored.and_then(|_| self.db.count_quarantine(&self.username)) {
+                    Ok(count) => vec![Event::Quarantined { reason: reason.to_string(), count }],
+                    Err(_) => vec![Event::Raw { message: message.to_string(), sender_tag, ts }],
+                }
+            }
+            Err(Unopened::Raw) => vec![Event::Raw { message: message.to_string(), sender_tag, ts }],
+        }
+    }
+
+    fn direct_message(&self, message: &str, ts: u64) -> std::result::Result<Vec<Event>, Unopened> {
+        let envelope = Envelope::parse(message).map_err(|_| quarantine(None, "unparseable envelope"))?;
+        if envelope.action != "incomingMessage" {
+            return Err(Unopened::Raw);
+        }
+        let content = envelope.content.ok_or_else(|| quarantine(None, "no content"))?;
+        let payload: ChatPayload = match serde_json::from_str::<SealedPayload>(&content) {
+            Ok(sealed) => {
+                // The seal is only the transport; the payload inside is signed as usual.
+                let opened = Crypto::decrypt(&self.key, &sealed.sealed)
+                    .map_err(|_| quarantine(None, "sealed message could not be opened"))?
+                    .into_unauthenticated();
+                serde_json::from_str(&opened).map_err(|_| quarantine(None, "malformed sealed message"))?
+            }
+            // Group messages are for the embedder.
+            Err(_) if is_group_message(&content) => return Err(Unopened::Raw),
+            Err(_) => serde_json::from_str(&content).map_err(|_| quarantine(None, "malformed message"))?,
+        };
+
+        let from = payload.sender;
+        let stored = self.db.get_contact(&self.username, &from).map_err(|_| Unopened::Raw)?.map(|(_, key)| key);
+        let claimed = payload.sender_public_key.filter(|key| !key.trim().is_empty());
+        let (key, verified, notice) = match (stored, claimed) {
+            (Some(old_key), Some(new_key)) if old_key.trim() != new_key.trim() => {
+                let notice = Event::KeyChange { username: from.clone(), old_key, new_key: new_key.clone() };
+                (new_key, false, Some(notice))
+            }
+            (Some(key), _) => (key, true, None),
+            (None, Some(key)) => {
+                let notice = Event::ContactRequest { from: from.clone(), public_key: key.clone(), ts };
+                (key, false, Some(notice))
+            }
+            (None, None) => return Err(Unopened::Raw),
+        };
+
+        let key = Crypto::public_key_from_pem(&key).map_err(|_| quarantine(Some(&from), "invalid sender key"))?;
+        let unverified = Crypto::decrypt(&self.key, &payload.body.encrypted_payload)
+            .map_err(|_| quarantine(Some(&from), "decryption failed"))?;
+        // Forged or damaged on the way.
+        let plaintext = unverified
+            .verify(&key, &payload.body.payload_signature)
+            .map_err(|_| quarantine(Some(&from), "invalid signature"))?;
+
+        let mut direct: DirectMessage =
+            serde_json::from_str(&plaintext).map_err(|_| quarantine(Some(&from), "malformed inner message"))?;
+        if direct.message_type == RATCHET_MESSAGE {
+            let plaintext = self.open_ratchet(&from, direct.message, verified)?;
+            direct = serde_json::from_str(&plaintext).map_err(|_| quarantine(Some(&from), "malformed inner message"))?;
+        }
+        // Under a key the user has not confirmed, only the chat message is
+        // shown; nothing it says is applied.
+        let event = match direct.message_type {
+            CHAT_MESSAGE => {
+                let body = direct.message.as_str().ok_or_else(|| quarantine(Some(&from), "malformed inner message"))?;
+                Some(Event::ChatMessage { from, body: body.to_string(), kind: KIND_TEXT.to_string(), ts, verified })
+            }
+            LOCATION_MESSAGE => {
+                let body = direct.message.to_string();
+                Some(Event::ChatMessage { from, body, kind: "location".to_string(), ts, verified })
+            }
+            RECEIPT_MESSAGE => {
+                let receipt = serde_json::from_value(direct.message)
+                    .unwrap_or_else(|_| ReceiptMessage { ids: Vec::new(), status: read_status() });
+                if !RECEIPT_STATUSES.contains(&receipt.status.as_str()) {
+                    return Err(quarantine(Some(&from), "malformed receipt"));
+                }
+                if verified {
+                    self.db
+                        .set_message_status(&self.username, &from, &receipt.ids, &receipt.status)
+                        .map_err(|_| Unopened::Raw)?;
+                }
+                verified.then_some(Event::Receipt { from, ids: receipt.ids, status: receipt.status, ts })
+            }
+            TYPING_MESSAGE if direct.message == Value::Bool(true) => verified.then_some(Event::Typing { from, ts }),
+            _ => return Err(Unopened::Raw),
+        };
+        Ok(notice.into_iter().chain(event).collect())
+    }
+
+    /// Decrypts a ratchet message from `from` with the session stored for
+    /// them, and stores the session as it moved on if `store`.
+    fn open_ratchet(&self, from: &str, message: Value, store: bool) -> std::result::Result<String, Unopened> {
+        let stored = self.db.get_ratchet_session(&self.username, from).map_err(|_| Unopened::Raw)?;
+        let mut session = stored
+            .and_then(|raw| RatchetSession::from_json(&raw).ok())
+            .ok_or_else(|| quarantine(Some(from), "no ratchet session"))?;
+        let message: RatchetMessage =
+            serde_json::from_value(message).map_err(|_| quarantine(Some(from), "malformed ratchet message"))?;
+        let plaintext = session.decrypt(&message).map_err(|_| quarantine(Some(from), "ratchet decryption failed"))?;
+        if store {
+            self.db.set_ratchet_session(&self.username, from, Some(&session.to_json())).map_err(|_| Unopened::Raw)?;
+        }
+        Ok(plaintext)
+    }
+}
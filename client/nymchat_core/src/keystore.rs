@@ -0,0 +1,148 @@
+//! The account's private key at rest, encrypted with a passphrase.
+//!
+//! Without a keystore the key pair lies in `<storage>/<username>/` as the
+//! plain PEM files the Python client always wrote (see [`crate::legacy`]).
+//! A keystore replaces the private key file with
+//! `<username>_keystore.json`: the PKCS#8 PEM of the key, encrypted with
+//! AES-256-GCM under a key derived from the passphrase with Argon2id, and
+//! bound to the username. The public key file stays as it is, so the
+//! account is listed and its fingerprint shown before it is unlocked.
+
+use crate::error::StorageError;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use nymchat_protocol::Crypto;
+use p256::SecretKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, StorageError>;
+
+/// Version of the keystore file format.
+pub const KEYSTORE_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The keystore file as stored.
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    /// Argon2id parameters: memory in KiB, iterations and lanes.
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+    salt: String,
+    nonce: String,
+    /// The encrypted PEM, GCM tag included.
+    ciphertext: String,
+}
+
+/// Where the keystore of `username` is kept.
+pub fn keystore_path(storage_dir: &str, username: &str) -> PathBuf {
+    Path::new(storage_dir).join(username).join(format!("{}_keystore.json", username))
+}
+
+fn private_key_path(storage_dir: &str, username: &str) -> PathBuf {
+    Path::new(storage_dir).join(username).join(format!("{}_private_key.pem", username))
+}
+
+/// Whether `username` keeps their private key in a keystore.
+pub fn exists(storage_dir: &str, username: &str) -> bool {
+    keystore_path(storage_dir, username).is_file()
+}
+
+/// Stores `key` encrypted with `passphrase`, replacing the keystore there
+/// was. The file is written next to it first and then moved, so a crash
+/// leaves the old keystore or the new one.
+pub fn save(storage_dir: &str, username: &str, key: &SecretKey, passphrase: &str) -> Result<()> {
+    let path = keystore_path(storage_dir, username);
+    let invalid = |reason: String| StorageError::Keystore { path: path.clone(), reason };
+    let pem = Crypto::private_key_pem(key).map_err(|e| invalid(e.to_string()))?;
+
+    let params = Params::default();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+    let cipher = cipher(passphrase, &salt, &params).map_err(invalid)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: pem.as_bytes(), aad: username.as_bytes() })
+        .map_err(|_| invalid("encryption failed".to_string()))?;
+    let file = KeystoreFile {
+        version: KEYSTORE_VERSION,
+        memory: params.m_cost(),
+        iterations: params.t_cost(),
+        parallelism: params.p_cost(),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+
+    let dir = path.parent().expect("a keystore lies in the account directory");
+    fs::create_dir_all(dir).map_err(StorageError::io(dir))?;
+    let partial = path.with_extension("json.partial");
+    let json = serde_json::to_vec(&file).map_err(|e| invalid(e.to_string()))?;
+    fs::write(&partial, json).map_err(StorageError::io(&partial))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o600)).map_err(StorageError::io(&partial))?;
+    }
+    fs::rename(&partial, &path).map_err(StorageError::io(&path))
+}
+
+/// The private key of `username`, decrypted with `passphrase`. A wrong
+/// passphrase, or a keystore of another account, fails with
+/// [`StorageError::Passphrase`].
+pub fn load(storage_dir: &str, username: &str, passphrase: &str) -> Result<SecretKey> {
+    let path = keystore_path(storage_dir, username);
+    let invalid = |reason: &str| StorageError::Keystore { path: path.clone(), reason: reason.to_string() };
+    let raw = fs::read(&path).map_err(StorageError::io(&path))?;
+    let file: KeystoreFile = serde_json::from_slice(&raw).map_err(|_| invalid("not a keystore"))?;
+    if file.version != KEYSTORE_VERSION {
+        return Err(invalid(&format!("unknown version {}", file.version)));
+    }
+    let salt = hex::decode(&file.salt).map_err(|_| invalid("malformed salt"))?;
+    let nonce = hex::decode(&file.nonce).map_err(|_| invalid("malformed nonce"))?;
+    let ciphertext = hex::decode(&file.ciphertext).map_err(|_| invalid("malformed ciphertext"))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(invalid("malformed nonce"));
+    }
+    let params = Params::new(file.memory, file.iterations, file.parallelism, None)
+        .map_err(|_| invalid("invalid key derivation parameters"))?;
+
+    let cipher = cipher(passphrase, &salt, &params).map_err(|reason| invalid(&reason))?;
+    let pem = cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: username.as_bytes() })
+        .map_err(|_| StorageError::Passphrase(path.clone()))?;
+    let pem = String::from_utf8(pem).map_err(|_| invalid("not a PEM key"))?;
+    Crypto::private_key_from_pem(&pem).map_err(|_| invalid("not a PEM key"))
+}
+
+/// Moves the plain private key file of `username` into a keystore
+/// encrypted with `passphrase`. The plain file is overwritten before it is
+/// removed; as with any file, the disk may still hold old copies.
+pub fn protect(storage_dir: &str, username: &str, passphrase: &str) -> Result<()> {
+    let plain = private_key_path(storage_dir, username);
+    let pem = fs::read_to_string(&plain).map_err(StorageError::io(&plain))?;
+    let key = Crypto::private_key_from_pem(&pem)
+        .map_err(|e| StorageError::Keystore { path: plain.clone(), reason: e.to_string() })?;
+    save(storage_dir, username, &key, passphrase)?;
+    // Only once the keystore opens is the plain key gone.
+    load(storage_dir, username, passphrase)?;
+    let mut noise = vec![0u8; pem.len()];
+    OsRng.fill_bytes(&mut noise);
+    fs::write(&plain, noise).map_err(StorageError::io(&plain))?;
+    fs::remove_file(&plain).map_err(StorageError::io(&plain))
+}
+
+fn cipher(passphrase: &str, salt: &[u8], params: &Params) -> std::result::Result<Aes256Gcm, String> {
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("key derivation failed: {}", e))?;
+    Ok(Aes256Gcm::new_from_slice(&key).expect("a 32-byte key"))
+}
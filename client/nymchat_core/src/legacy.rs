@@ -0,0 +1,86 @@
+//! Importing accounts of the old Python client.
+//!
+//! The Python client (`dbUtils.SQLiteManager`) kept an account in
+//! `<storage>/<username>/`: its key pair as `<username>_private_key.pem` and
+//! `<username>_public_key.pem`, and `<username>_client.db` with the tables
+//! `users`, `contacts_<username>` and `messages_<username>`, messages as
+//! plain text. [`Db`] reads that layout in place, so a client pointed at the
+//! same storage directory needs nothing imported. [`import_account`] is for
+//! an account kept somewhere else, e.g. copied from another machine: it
+//! brings the key pair, contacts and message history over into a storage
+//! directory, next to what is there already. A private key kept in a
+//! [`keystore`](crate::keystore) comes along encrypted as it is.
+
+use crate::db::Db;
+use crate::error::StorageError;
+use std::fs;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, StorageError>;
+
+/// What an import added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LegacyImport {
+    /// Key files copied; 0 if the account's keys were there already.
+    pub keys: usize,
+    pub contacts: usize,
+    /// Contacts stored here with another key than there. The key here is
+    /// kept; check with them which one is right.
+    pub conflicts: Vec<String>,
+    pub messages: usize,
+}
+
+/// Imports `username` from the storage directory `legacy_dir` of the old
+/// client into `storage_dir`. Importing the same account again adds only
+/// what is new. Fails without changing anything if `storage_dir` already
+/// holds another key pair for `username`.
+pub fn import_account(username: &str, legacy_dir: &str, storage_dir: &str) -> Result<LegacyImport> {
+    let source = Path::new(legacy_dir).join(username);
+    let target = Path::new(storage_dir).join(username);
+    let database = source.join(format!("{}_client.db", username));
+    let invalid = |reason: &str| StorageError::Import { path: source.clone(), reason: reason.to_string() };
+    if !database.is_file() {
+        return Err(invalid(&format!("no {}_client.db in it", username)));
+    }
+    if let (Ok(from), Ok(to)) = (source.canonicalize(), target.canonicalize()) {
+        if from == to {
+            return Err(invalid("it is the storage directory being imported into"));
+        }
+    }
+
+    // The keys first: an account of the same name with other keys is not this one.
+    let mut copies = Vec::new();
+    let key_files = [
+        format!("{}_private_key.pem", username),
+        format!("{}_keystore.json", username),
+        format!("{}_public_key.pem", username),
+    ];
+    for name in key_files {
+        let from = source.join(&name);
+        if !from.is_file() {
+            continue;
+        }
+        let to = target.join(&name);
+        if to.exists() {
+            let existing = fs::read(&to).map_err(StorageError::io(&to))?;
+            if fs::read(&from).map_err(StorageError::io(&from))? != existing {
+                return Err(invalid(&format!("{} holds another key for {}", storage_dir, username)));
+            }
+        } else {
+            copies.push((from, to));
+        }
+    }
+
+    let db = Db::open(username, storage_dir)?;
+    let mut imported = db.import_legacy(username, &database)?;
+    for (from, to) in copies {
+        fs::copy(&from, &to).map_err(StorageError::io(&to))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&to, fs::Permissions::from_mode(0o600)).map_err(StorageError::io(&to))?;
+        }
+        imported.keys += 1;
+    }
+    Ok(imported)
+}
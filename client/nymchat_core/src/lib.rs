@@ -0,0 +1,54 @@
+//! The nymCHAT messaging engine, for any interface to build on.
+//!
+//! The Python client (through `async_ffi`), the C ABI (`nymchat-ffi`) and
+//! third-party UIs, bots or mobile apps all use the same pieces:
+//!
+//! - [`Db`], the per-device store of contacts, messages, groups and
+//!   settings, one SQLite database per account;
+//! - [`ChatClient`] (feature `mixnet`, on by default), a mixnet connection
+//!   that registers and logs in with a nymCHAT server and sends and receives
+//!   messages;
+//! - [`Event`], what every interface is told arrived, decrypted and checked
+//!   by an [`Inbox`];
+//! - [`envelope`], the compact encoding of chat envelopes, for contacts
+//!   that negotiated it;
+//! - [`keystore`], the account's private key encrypted with a passphrase;
+//! - [`transfer`], files sent in encrypted chunks and checked on arrival;
+//! - [`legacy`], importing accounts of the old Python client, also as the
+//!   `nymchat-import` tool;
+//! - the wire format and end-to-end encryption, re-exported from
+//!   [`nymchat_protocol`] as [`protocol`], with [`Crypto`] and the message
+//!   types at the top level.
+//!
+//! Failures are typed by kind (see [`error`]): [`StorageError`],
+//! [`TransportError`], [`ProtocolError`] and [`CryptoError`].
+//!
+//! # Stability
+//!
+//! Everything public here is the crate's API and follows semver: until 1.0,
+//! a minor version (0.x) may break it and a patch version may not; from 1.0,
+//! only a major version may. Breaking changes are listed in `CHANGELOG.md`.
+//! Databases written by one version stay readable by later ones:
+//! [`Db::create_user_tables`] upgrades their tables and stored messages in
+//! place.
+
+#[cfg(feature = "mixnet")]
+pub mod client;
+pub mod db;
+pub mod envelope;
+pub mod error;
+pub mod event;
+pub mod keystore;
+pub mod legacy;
+pub mod stored;
+pub mod transfer;
+
+pub use nymchat_protocol as protocol;
+pub use nymchat_protocol::{ContactInvite, Crypto, CryptoError, Envelope, GroupSession, MembershipLog};
+
+#[cfg(feature = "mixnet")]
+pub use client::{ChatClient, EventSink, Health, ServerReply};
+pub use db::Db;
+pub use error::{Error, ProtocolError, Result, StorageError, TransportError};
+pub use event::{Event, Inbox, TransportStatus};
+pub use stored::{Attachment, StoredMessage, KIND_TEXT, STORED_MESSAGE_VERSION};
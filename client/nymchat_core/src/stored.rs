@@ -0,0 +1,81 @@
+//! The format chat messages are stored in.
+//!
+//! Message rows used to hold whatever text the handler had at hand. They now
+//! hold a [`StoredMessage`] as JSON, which names its format version, so rows
+//! written before a change to what is stored can be told apart and upgraded
+//! instead of being shown wrongly. [`Db::migrate_messages`] brings the rows of
+//! a user up to [`STORED_MESSAGE_VERSION`] when their tables are opened; each
+//! format change adds a step to [`StoredMessage::upgrade`].
+//!
+//! [`Db::migrate_messages`]: crate::db::Db::migrate_messages
+
+use serde::{Deserialize, Serialize};
+
+/// Format of rows written now. Version 0 is the text alone.
+pub const STORED_MESSAGE_VERSION: u32 = 1;
+
+/// A message typed or received as text.
+pub const KIND_TEXT: &str = "text";
+
+/// A file sent with a message; the file itself is kept elsewhere.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    #[serde(rename = "v")]
+    pub version: u32,
+    /// [`KIND_TEXT`], `location`, `forwarded` (sent on from another chat),
+    /// `file` (a file sent or received, see [`crate::transfer`]) or `notice`
+    /// (written by the client, such as a contact's account being deleted).
+    /// Readers show unknown kinds as text.
+    pub kind: String,
+    /// The text shown for the message.
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// None are defined yet; bits a reader does not know are kept.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub flags: u32,
+}
+
+fn is_zero(flags: &u32) -> bool {
+    *flags == 0
+}
+
+impl StoredMessage {
+    pub fn new(kind: &str, body: &str) -> Self {
+        StoredMessage {
+            version: STORED_MESSAGE_VERSION,
+            kind: kind.to_string(),
+            body: body.to_string(),
+            attachments: Vec::new(),
+            flags: 0,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("stored messages serialize")
+    }
+
+    /// Reads a row. Rows that are not in this format were written before it,
+    /// or by an older client since, and are text.
+    pub fn decode(row: &str) -> Self {
+        serde_json::from_str(row).unwrap_or_else(|_| Self::new(KIND_TEXT, row))
+    }
+
+    /// The row written in format `version`, in the current one.
+    pub fn upgrade(version: u32, row: &str) -> Self {
+        let mut message = match version {
+            // Any text, however it looks, is the message itself.
+            0 => Self::new(KIND_TEXT, row),
+            _ => Self::decode(row),
+        };
+        message.version = STORED_MESSAGE_VERSION;
+        message
+    }
+}
@@ -0,0 +1,316 @@
+//! Files sent to a contact in chunks small enough for mixnet messages.
+//!
+//! The sender opens the file as an [`Upload`], sends its [`Manifest`] as a
+//! direct message and then every [`Chunk`] in its own direct message, in
+//! any order. Each chunk is encrypted with AES-256-GCM under a key made for
+//! the file alone, which only the manifest carries, with its sequence
+//! number as the nonce: a chunk cannot be read without the manifest, nor be
+//! taken for another one. The receiver starts a [`Download`] from the
+//! manifest, adds chunks as they arrive and, once all have, checks the
+//! whole file against the manifest's SHA-256 before it is moved into the
+//! downloads directory.
+
+use crate::error::{ProtocolError, Result, StorageError};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use nymchat_protocol::CryptoError;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes of the file in each chunk sent.
+pub const CHUNK_SIZE: u32 = 16 * 1024;
+/// Largest chunk accepted from other clients.
+pub const MAX_CHUNK_SIZE: u32 = 64 * 1024;
+/// Largest file sent or accepted.
+pub const MAX_FILE_SIZE: u64 = 25 * 1024 * 1024;
+/// Longest file name kept, in characters.
+const MAX_NAME_LEN: usize = 200;
+const ID_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// What a file is, and how to put it back together. It travels end-to-end
+/// encrypted like any direct message, and holds the key of the chunks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Random hex id naming the transfer in its chunks.
+    pub id: String,
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    pub chunk_size: u32,
+    pub chunks: u32,
+    /// Hex SHA-256 of the whole file.
+    pub sha256: String,
+    /// Hex AES-256-GCM key of the chunks.
+    pub key: String,
+}
+
+/// Part `seq` of the file of transfer `id`; `data` is its ciphertext, GCM
+/// tag included, in base64.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub id: String,
+    pub seq: u32,
+    pub data: String,
+}
+
+impl Chunk {
+    pub fn from_json(raw: &str) -> Result<Self> {
+        Ok(serde_json::from_str(raw).map_err(|_| invalid("malformed chunk"))?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("chunks serialize")
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> ProtocolError {
+    ProtocolError::Transfer(reason.into())
+}
+
+impl Manifest {
+    pub fn from_json(raw: &str) -> Result<Self> {
+        Ok(serde_json::from_str(raw).map_err(|_| invalid("malformed manifest"))?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("manifests serialize")
+    }
+
+    /// Bytes of the file in chunk `seq`.
+    fn chunk_len(&self, seq: u32) -> usize {
+        let start = seq as u64 * self.chunk_size as u64;
+        self.size.saturating_sub(start).min(self.chunk_size as u64) as usize
+    }
+
+    fn cipher(&self) -> Result<Aes256Gcm> {
+        let key = hex::decode(&self.key).map_err(|_| invalid("malformed key"))?;
+        Ok(Aes256Gcm::new_from_slice(&key).map_err(|_| invalid("malformed key"))?)
+    }
+
+    /// Refuses what another client could not have sent: files or chunks
+    /// too large, a chunk count that does not fit the size, or an id that
+    /// could name a path.
+    fn check(&self) -> Result<()> {
+        let hex_of = |value: &str, len: usize| value.len() == 2 * len && hex::decode(value).is_ok();
+        if !hex_of(&self.id, ID_LEN) || !hex_of(&self.key, KEY_LEN) || !hex_of(&self.sha256, 32) {
+            return Err(invalid("malformed manifest").into());
+        }
+        if self.size > MAX_FILE_SIZE {
+            return Err(invalid(format!("file of {} bytes is too large", self.size)).into());
+        }
+        if self.chunk_size == 0 || self.chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid(format!("chunk size {} is out of range", self.chunk_size)).into());
+        }
+        if self.chunks as u64 != self.size.div_ceil(self.chunk_size as u64) {
+            return Err(invalid("chunk count does not match the size").into());
+        }
+        Ok(())
+    }
+}
+
+fn nonce(seq: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 4..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+/// A file being sent. Chunks are read from it as they are asked for, so a
+/// file changed meanwhile arrives damaged and is discarded by the receiver.
+pub struct Upload {
+    manifest: Manifest,
+    file: File,
+    cipher: Aes256Gcm,
+}
+
+impl Upload {
+    /// Opens `path` to send it, with `mime` as its type.
+    pub fn open(path: &Path, mime: &str) -> Result<Self> {
+        let mut file = File::open(path).map_err(StorageError::io(path))?;
+        let size = file.metadata().map_err(StorageError::io(path))?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(invalid(format!("file of {} bytes is too large", size)).into());
+        }
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher).map_err(StorageError::io(path))?;
+
+        let mut id = [0u8; ID_LEN];
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut id);
+        OsRng.fill_bytes(&mut key);
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let manifest = Manifest {
+            id: hex::encode(id),
+            name: safe_name(&name),
+            mime: mime.to_string(),
+            size,
+            chunk_size: CHUNK_SIZE,
+            chunks: size.div_ceil(CHUNK_SIZE as u64) as u32,
+            sha256: hex::encode(hasher.finalize()),
+            key: hex::encode(key),
+        };
+        let cipher = manifest.cipher()?;
+        Ok(Upload { manifest, file, cipher })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Chunk `seq`, read and encrypted.
+    pub fn chunk(&mut self, seq: u32) -> Result<Chunk> {
+        if seq >= self.manifest.chunks {
+            return Err(invalid(format!("no chunk {}", seq)).into());
+        }
+        let path = PathBuf::from(&self.manifest.name);
+        let mut plain = vec![0u8; self.manifest.chunk_len(seq)];
+        let offset = seq as u64 * self.manifest.chunk_size as u64;
+        self.file.seek(SeekFrom::Start(offset)).map_err(StorageError::io(&path))?;
+        self.file.read_exact(&mut plain).map_err(StorageError::io(&path))?;
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce(seq)), Payload { msg: &plain, aad: self.manifest.id.as_bytes() })
+            .expect("a chunk is small enough to encrypt");
+        Ok(Chunk { id: self.manifest.id.clone(), seq, data: STANDARD.encode(ciphertext) })
+    }
+}
+
+/// A file being received, kept in a hidden partial file in the downloads
+/// directory until it is complete. Dropping it before [`Download::finish`]
+/// removes the partial file.
+pub struct Download {
+    manifest: Manifest,
+    dir: PathBuf,
+    partial: PathBuf,
+    file: File,
+    cipher: Aes256Gcm,
+    received: Vec<bool>,
+    count: u32,
+}
+
+impl Download {
+    /// Starts receiving the file `manifest` describes into `downloads_dir`.
+    pub fn start(downloads_dir: &Path, mut manifest: Manifest) -> Result<Self> {
+        manifest.check()?;
+        manifest.name = safe_name(&manifest.name);
+        let cipher = manifest.cipher()?;
+        fs::create_dir_all(downloads_dir).map_err(StorageError::io(downloads_dir))?;
+        let partial = downloads_dir.join(format!(".{}.partial", manifest.id));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial)
+            .map_err(StorageError::io(&partial))?;
+        file.set_len(manifest.size).map_err(StorageError::io(&partial))?;
+        Ok(Download {
+            received: vec![false; manifest.chunks as usize],
+            count: 0,
+            dir: downloads_dir.to_path_buf(),
+            partial,
+            file,
+            cipher,
+            manifest,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Chunks received so far.
+    pub fn received(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count == self.manifest.chunks
+    }
+
+    /// Writes `chunk` into place. Returns whether it was new; chunks that
+    /// arrive twice are ignored, and ones that do not decrypt are refused.
+    pub fn add(&mut self, chunk: &Chunk) -> Result<bool> {
+        if chunk.id != self.manifest.id || chunk.seq >= self.manifest.chunks {
+            return Err(invalid(format!("chunk {} is not part of this file", chunk.seq)).into());
+        }
+        if self.received[chunk.seq as usize] {
+            return Ok(false);
+        }
+        let ciphertext = STANDARD.decode(&chunk.data).map_err(|_| invalid("malformed chunk"))?;
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce(chunk.seq)), Payload { msg: &ciphertext, aad: chunk.id.as_bytes() })
+            .map_err(|_| CryptoError::Decryption)?;
+        if plain.len() != self.manifest.chunk_len(chunk.seq) {
+            return Err(invalid(format!("chunk {} has the wrong length", chunk.seq)).into());
+        }
+        let offset = chunk.seq as u64 * self.manifest.chunk_size as u64;
+        self.file.seek(SeekFrom::Start(offset)).map_err(StorageError::io(&self.partial))?;
+        self.file.write_all(&plain).map_err(StorageError::io(&self.partial))?;
+        self.received[chunk.seq as usize] = true;
+        self.count += 1;
+        Ok(true)
+    }
+
+    /// Checks the complete file against the manifest's SHA-256 and moves it
+    /// into the downloads directory under its name, numbered if a file of
+    /// that name is there. Returns where it is now.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        if !self.is_complete() {
+            return Err(invalid(format!("{} of {} chunks received", self.count, self.manifest.chunks)).into());
+        }
+        self.file.flush().map_err(StorageError::io(&self.partial))?;
+        let mut hasher = Sha256::new();
+        self.file.seek(SeekFrom::Start(0)).map_err(StorageError::io(&self.partial))?;
+        std::io::copy(&mut self.file, &mut hasher).map_err(StorageError::io(&self.partial))?;
+        if hex::encode(hasher.finalize()) != self.manifest.sha256.to_lowercase() {
+            return Err(invalid("the file does not match its SHA-256").into());
+        }
+        let path = free_path(&self.dir, &self.manifest.name);
+        fs::rename(&self.partial, &path).map_err(StorageError::io(&path))?;
+        Ok(path)
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        // Gone already once finished.
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+/// The last component of `name`, without control characters or leading
+/// dots, so a received name cannot reach outside the downloads directory
+/// or hide there.
+fn safe_name(name: &str) -> String {
+    let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).take(MAX_NAME_LEN).collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// `dir/name`, or `dir/name (n).ext` with the first `n` not taken.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    (1..).map(|n| dir.join(format!("{} ({}){}", stem, n, extension))).find(|path| !path.exists()).unwrap()
+}
@@ -0,0 +1,74 @@
+use nymchat_core::{Db, StorageError, StoredMessage, KIND_TEXT};
+
+#[test]
+fn messages_round_trip_through_the_public_api() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-{}", std::process::id()));
+    let storage = dir.to_str().unwrap();
+    {
+        let db = Db::open("alice", storage).unwrap();
+        db.register_user("alice", "alice-key").unwrap();
+        db.add_contact("alice", "bob", "bob-key").unwrap();
+        db.save_message("alice", "bob", "to", "Hello Bob", KIND_TEXT).unwrap();
+        db.save_message("alice", "bob", "from", "📍 (1.500000, 2.500000)", "location").unwrap();
+        db.save_draft("alice", "bob", "Half a reply").unwrap();
+    }
+
+    // A later session, or another UI, reads back what the first one wrote.
+    let db = Db::open("alice", storage).unwrap();
+    db.create_user_tables("alice").unwrap();
+    let messages = db.load_messages("alice", "bob").unwrap();
+    let problems = db.integrity_check().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let bodies: Vec<_> = messages.iter().map(|(kind, body, _)| (kind.as_str(), body.as_str())).collect();
+    assert!(problems.is_empty(), "{:?}", problems);
+    assert_eq!(bodies, [("to", "Hello Bob"), ("from", "📍 (1.500000, 2.500000)")]);
+    assert_eq!(db.get_contact("alice", "bob").unwrap().unwrap().1, "bob-key");
+    assert_eq!(db.get_drafts("alice").unwrap(), [("bob".to_string(), "Half a reply".to_string())]);
+    assert_eq!(db.search_messages("alice", "hello", 10).unwrap().len(), 1);
+
+    let stored = StoredMessage::new("location", "📍 (1.500000, 2.500000)");
+    assert_eq!(StoredMessage::decode(&stored.encode()), stored);
+    assert_eq!(StoredMessage::decode("plain text from an old client").kind, KIND_TEXT);
+}
+
+#[test]
+fn storage_failures_are_typed() {
+    // A file where the account directory should be.
+    let path = std::env::temp_dir().join(format!("nymchat-core-blocked-{}", std::process::id()));
+    std::fs::write(&path, b"").unwrap();
+    let result = Db::open("alice", path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    match result {
+        Err(StorageError::Io { path: failed, .. }) => assert_eq!(failed, path.join("alice")),
+        other => panic!("expected an I/O error, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn key_history_moves_to_another_database() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-key-history-{}", std::process::id()));
+    let storage = dir.to_str().unwrap();
+    let db = Db::open("alice", storage).unwrap();
+    db.create_user_tables("alice").unwrap();
+    db.add_contact("alice", "bob", "bob-key").unwrap();
+    db.record_key_change("alice", "bob", "bob-new-key", "aa", "bb").unwrap();
+    let history = db.get_key_history("alice", "bob").unwrap();
+
+    // As an account export carries it into a fresh database.
+    let restored = Db::open("alice2", storage).unwrap();
+    restored.create_user_tables("alice2").unwrap();
+    restored.add_contact("alice2", "bob", "bob-new-key").unwrap();
+    restored.set_trusted("alice2", "bob", false).unwrap();
+    assert_eq!(restored.import_key_history("alice2", "bob", &history).unwrap(), 1);
+    let moved = restored.get_key_history("alice2", "bob").unwrap();
+    let rejected = restored.reject_key_change("alice2", "bob").unwrap();
+    let key = restored.get_contact("alice2", "bob").unwrap().unwrap().1;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(moved, history);
+    assert_eq!(history[0].2.as_deref(), Some("bob-key"));
+    assert!(rejected);
+    assert_eq!(key, "bob-key");
+}
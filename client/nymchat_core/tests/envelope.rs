@@ -0,0 +1,84 @@
+use nymchat_core::envelope::{self, COMPACT_PREFIX};
+use nymchat_core::protocol::{to_python_json, ChatPayload, MessageBody};
+use nymchat_core::{Crypto, Db, Envelope, Event, Inbox};
+
+fn direct_message(to: &str, from_key: &p256::SecretKey, public_key: Option<&str>) -> String {
+    let to = Crypto::public_key_from_pem(to).unwrap();
+    let encrypted = Crypto::encrypt(&to, r#"{"type": 0, "message": "hi"}"#).unwrap();
+    let signature = Crypto::sign(from_key, &to_python_json(&encrypted).unwrap());
+    let payload = ChatPayload {
+        sender: "bob".to_string(),
+        recipient: Some("alice".to_string()),
+        recipient_token: None,
+        body: MessageBody { encrypted_payload: encrypted, payload_signature: signature },
+        encrypted: Some(true),
+        sender_public_key: public_key.map(str::to_string),
+    };
+    let content = to_python_json(&payload).unwrap();
+    let signature = Crypto::sign(from_key, &content);
+    Envelope::new("incomingMessage")
+        .with_context("chat")
+        .with_content(content)
+        .with_signature(signature)
+        .to_json_string()
+        .unwrap()
+}
+
+#[test]
+fn compact_envelopes_decode_to_the_json_they_were_made_from() {
+    let (_, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (bob, bob_pem) = Crypto::generate_key_pair().unwrap();
+    for public_key in [None, Some(bob_pem.as_str())] {
+        let json = direct_message(&alice_pem, &bob, public_key);
+        let compact = envelope::encode(&json).unwrap();
+        assert!(compact.starts_with(COMPACT_PREFIX) && envelope::is_compact(&compact));
+        // Most of the framing is gone.
+        assert!(json.len() - compact.len() > 350, "{} -> {}", json.len(), compact.len());
+
+        let decoded = envelope::decode(&compact).unwrap();
+        let (before, after) = (Envelope::parse(&json).unwrap(), Envelope::parse(&decoded).unwrap());
+        assert_eq!(
+            (&after.action, &after.context, &after.signature),
+            (&before.action, &before.context, &before.signature)
+        );
+        let content = |envelope: &Envelope| -> ChatPayload {
+            serde_json::from_str(envelope.content.as_deref().unwrap()).unwrap()
+        };
+        assert_eq!(content(&after), content(&before));
+    }
+}
+
+#[test]
+fn only_chat_payloads_have_a_compact_form() {
+    let reply = r#"{"action": "queryResponse", "context": "query", "content": "{}"}"#;
+    assert_eq!(envelope::encode(reply), None);
+    assert_eq!(envelope::decode(reply).unwrap(), reply);
+    // A field the compact form does not carry keeps the message JSON.
+    let (_, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (bob, _) = Crypto::generate_key_pair().unwrap();
+    let mut json: serde_json::Value = serde_json::from_str(&direct_message(&alice_pem, &bob, None)).unwrap();
+    json["messageId"] = "7".into();
+    assert_eq!(envelope::encode(&json.to_string()), None);
+
+    for broken in ["~", "~AQ", "~CQE", "~!!", "~AQFh"] {
+        assert!(envelope::decode(broken).is_err(), "{}", broken);
+    }
+}
+
+#[test]
+fn the_inbox_reads_compact_envelopes() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-envelope-{}", std::process::id()));
+    let (alice, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (bob, bob_pem) = Crypto::generate_key_pair().unwrap();
+    let db = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    db.create_user_tables("alice").unwrap();
+    db.add_contact("alice", "bob", &bob_pem).unwrap();
+    let inbox = Inbox::new("alice", &Crypto::private_key_pem(&alice).unwrap(), db).unwrap();
+
+    let compact = envelope::encode(&direct_message(&alice_pem, &bob, None)).unwrap();
+    let events = inbox.events(&compact, None, 7);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let event =
+        Event::ChatMessage { from: "bob".into(), body: "hi".into(), kind: "text".into(), ts: 7, verified: true };
+    assert_eq!(events, [event]);
+}
@@ -0,0 +1,162 @@
+use nymchat_core::protocol::ratchet::ratchet_key_pair;
+use nymchat_core::protocol::{to_python_json, ChatPayload, MessageBody, RatchetSession, SealedPayload};
+use nymchat_core::{Crypto, Db, Envelope, Event, Inbox};
+use p256::SecretKey;
+
+fn incoming(to: &str, from: &str, key: &SecretKey, message: &str, public_key: Option<&str>) -> String {
+    let to = Crypto::public_key_from_pem(to).unwrap();
+    let encrypted = Crypto::encrypt(&to, message).unwrap();
+    let signature = Crypto::sign(key, &to_python_json(&encrypted).unwrap());
+    let payload = ChatPayload {
+        sender: from.to_string(),
+        recipient: None,
+        recipient_token: None,
+        body: MessageBody { encrypted_payload: encrypted, payload_signature: signature },
+        encrypted: Some(true),
+        sender_public_key: public_key.map(str::to_string),
+    };
+    envelope(&to_python_json(&payload).unwrap())
+}
+
+fn envelope(content: &str) -> String {
+    Envelope::new("incomingMessage").with_context("chat").with_content(content).to_json_string().unwrap()
+}
+
+#[test]
+fn the_inbox_turns_what_arrives_into_typed_events() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-events-{}", std::process::id()));
+    let (alice, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (bob, bob_pem) = Crypto::generate_key_pair().unwrap();
+    let (carol, carol_pem) = Crypto::generate_key_pair().unwrap();
+    let db = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    db.create_user_tables("alice").unwrap();
+    db.add_contact("alice", "bob", &bob_pem).unwrap();
+    let inbox = Inbox::new("alice", &Crypto::private_key_pem(&alice).unwrap(), db).unwrap();
+
+    let chat = incoming(&alice_pem, "bob", &bob, r#"{"type": 0, "message": "hi"}"#, None);
+    let event = Event::ChatMessage {
+        from: "bob".into(),
+        body: "hi".into(),
+        kind: "text".into(),
+        ts: 7,
+        verified: true,
+    };
+    assert_eq!(inbox.events(&chat, None, 7), [event]);
+
+    // Sealed, the same message reads the same.
+    let opened: serde_json::Value = serde_json::from_str(&Envelope::parse(&chat).unwrap().content.unwrap()).unwrap();
+    let sealed = SealedPayload {
+        recipient: None,
+        recipient_token: None,
+        sealed: Crypto::encrypt(&alice.public_key(), &opened.to_string()).unwrap(),
+    };
+    let events = inbox.events(&envelope(&to_python_json(&sealed).unwrap()), None, 7);
+    assert!(matches!(&events[..], [Event::ChatMessage { verified: true, .. }]));
+
+    let receipt = incoming(&alice_pem, "bob", &bob, r#"{"type": 10, "message": ""}"#, None);
+    let read = |ids: &[&str]| Event::Receipt {
+        from: "bob".into(),
+        ids: ids.iter().map(|id| id.to_string()).collect(),
+        status: "read".into(),
+        ts: 8,
+    };
+    assert_eq!(inbox.events(&receipt, None, 8), [read(&[])]);
+
+    // Receipts move the messages they name on, and never back.
+    let store = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    store.save_sent_message("alice", "bob", "Hi", "text", "m1", "sent").unwrap();
+    let receipt = r#"{"type": 10, "message": {"ids": ["m1"], "status": "read"}}"#;
+    assert_eq!(inbox.events(&incoming(&alice_pem, "bob", &bob, receipt, None), None, 8), [read(&["m1"])]);
+    let receipt = r#"{"type": 10, "message": {"ids": ["m1"], "status": "delivered"}}"#;
+    inbox.events(&incoming(&alice_pem, "bob", &bob, receipt, None), None, 8);
+    assert_eq!(store.get_message_statuses("alice", "bob").unwrap()[0].2, "read");
+    let typing = incoming(&alice_pem, "bob", &bob, r#"{"type": 11, "message": true}"#, None);
+    assert_eq!(inbox.events(&typing, None, 8), [Event::Typing { from: "bob".into(), ts: 8 }]);
+
+    // Someone new, and a contact with a new key: told, and not verified.
+    let hello = incoming(&alice_pem, "carol", &carol, r#"{"type": 0, "message": "hello"}"#, Some(&carol_pem));
+    let events = inbox.events(&hello, None, 9);
+    assert_eq!(events[0], Event::ContactRequest { from: "carol".into(), public_key: carol_pem.clone(), ts: 9 });
+    assert!(matches!(&events[1], Event::ChatMessage { verified: false, .. }));
+    let rekeyed = incoming(&alice_pem, "bob", &carol, r#"{"type": 0, "message": "new phone"}"#, Some(&carol_pem));
+    let events = inbox.events(&rekeyed, None, 9);
+    assert!(matches!(&events[0], Event::KeyChange { username, .. } if username == "bob"));
+    assert!(matches!(&events[1], Event::ChatMessage { verified: false, .. }));
+
+    // Forged and damaged messages are quarantined; what the inbox does not model arrives raw.
+    let forged = incoming(&alice_pem, "bob", &carol, r#"{"type": 0, "message": "trust me"}"#, None);
+    let quarantined = Event::Quarantined { reason: "invalid signature".into(), count: 1 };
+    assert_eq!(inbox.events(&forged, None, 10), [quarantined]);
+    let damaged = envelope("{\"sender\": \"bob\"}");
+    let quarantined = Event::Quarantined { reason: "malformed message".into(), count: 2 };
+    assert_eq!(inbox.events(&damaged, None, 10), [quarantined]);
+    let kept = Db::open("alice", dir.to_str().unwrap()).unwrap().get_quarantine("alice").unwrap();
+    let kept: Vec<_> = kept.iter().map(|(_, sender, _, content, _)| (sender.as_deref(), content.as_str())).collect();
+    assert_eq!(kept, [(None, damaged.as_str()), (Some("bob"), forged.as_str())]);
+    let handshake = incoming(&alice_pem, "bob", &bob, r#"{"type": 1, "message": {}}"#, None);
+    let reply = r#"{"action": "queryResponse", "context": "query", "content": "{}"}"#;
+    for raw in [handshake.as_str(), reply] {
+        let events = inbox.events(raw, Some("tag".into()), 11);
+        assert_eq!(events, [Event::Raw { message: raw.to_string(), sender_tag: Some("tag".into()), ts: 11 }]);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_changed_key_changes_nothing_stored_until_confirmed() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-rekeyed-{}", std::process::id()));
+    let (alice, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (_, bob_pem) = Crypto::generate_key_pair().unwrap();
+    let (mallory, mallory_pem) = Crypto::generate_key_pair().unwrap();
+    let db = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    db.create_user_tables("alice").unwrap();
+    db.add_contact("alice", "bob", &bob_pem).unwrap();
+    db.save_sent_message("alice", "bob", "Hi", "text", "m1", "sent").unwrap();
+    let inbox = Inbox::new("alice", &Crypto::private_key_pem(&alice).unwrap(), db).unwrap();
+
+    let receipt = r#"{"type": 10, "message": {"ids": ["m1"], "status": "read"}}"#;
+    let events = inbox.events(&incoming(&alice_pem, "bob", &mallory, receipt, Some(&mallory_pem)), None, 7);
+    assert!(matches!(&events[..], [Event::KeyChange { username, .. }] if username == "bob"));
+    let typing = incoming(&alice_pem, "bob", &mallory, r#"{"type": 11, "message": true}"#, Some(&mallory_pem));
+    assert!(matches!(&inbox.events(&typing, None, 7)[..], [Event::KeyChange { .. }]));
+
+    let store = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    assert_eq!(store.get_message_statuses("alice", "bob").unwrap()[0].2, "sent");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ratchet_messages_open_with_the_stored_session() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-ratchet-{}", std::process::id()));
+    let (alice, alice_pem) = Crypto::generate_key_pair().unwrap();
+    let (bob, bob_pem) = Crypto::generate_key_pair().unwrap();
+    let (alice_ratchet, _) = ratchet_key_pair();
+    let (bob_ratchet, _) = ratchet_key_pair();
+    let mut sender = RatchetSession::initiate(&bob, &bob_ratchet, &alice.public_key(), &alice_ratchet.public_key());
+    let receiver = RatchetSession::respond(&alice, &alice_ratchet, &bob.public_key(), &bob_ratchet.public_key());
+    let db = Db::open("alice", dir.to_str().unwrap()).unwrap();
+    db.create_user_tables("alice").unwrap();
+    db.add_contact("alice", "bob", &bob_pem).unwrap();
+    db.set_ratchet_session("alice", "bob", Some(&receiver.to_json())).unwrap();
+    let inbox = Inbox::new("alice", &Crypto::private_key_pem(&alice).unwrap(), db).unwrap();
+
+    let ratcheted = |session: &mut RatchetSession, text: &str| {
+        let inner = to_python_json(&serde_json::json!({"type": 0, "message": text})).unwrap();
+        let message = to_python_json(&session.encrypt(&inner).unwrap()).unwrap();
+        incoming(&alice_pem, "bob", &bob, &format!(r#"{{"type": 13, "message": {}}}"#, message), None)
+    };
+    let first = ratcheted(&mut sender, "forward secret");
+    let events = inbox.events(&first, None, 7);
+    assert!(matches!(&events[..], [Event::ChatMessage { body, verified: true, .. }] if body == "forward secret"));
+    let second = ratcheted(&mut sender, "again");
+    assert!(matches!(&inbox.events(&second, None, 8)[..], [Event::ChatMessage { body, .. }] if body == "again"));
+
+    // Each message key is used once: a replay is quarantined.
+    let replayed = inbox.events(&first, None, 9);
+    assert!(matches!(&replayed[..], [Event::Quarantined { reason, .. }] if reason == "ratchet decryption failed"));
+
+    let stored = Db::open("alice", dir.to_str().unwrap()).unwrap().get_ratchet_session("alice", "bob").unwrap();
+    assert!(RatchetSession::from_json(&stored.unwrap()).unwrap().can_send());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,31 @@
+use nymchat_core::{keystore, Crypto, StorageError};
+
+#[test]
+fn the_private_key_opens_only_with_its_passphrase() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-keystore-{}", std::process::id()));
+    let storage = dir.to_str().unwrap();
+    let (key, _) = Crypto::generate_key_pair().unwrap();
+    std::fs::create_dir_all(dir.join("alice")).unwrap();
+    let plain = dir.join("alice").join("alice_private_key.pem");
+    std::fs::write(&plain, Crypto::private_key_pem(&key).unwrap()).unwrap();
+
+    // The plain key file moves into the keystore.
+    assert!(!keystore::exists(storage, "alice"));
+    keystore::protect(storage, "alice", "correct horse").unwrap();
+    let stored = std::fs::read_to_string(keystore::keystore_path(storage, "alice")).unwrap();
+    let opened = keystore::load(storage, "alice", "correct horse");
+    let wrong = keystore::load(storage, "alice", "battery staple");
+
+    // Bound to the account: another user's copy of it does not open.
+    std::fs::create_dir_all(dir.join("mallory")).unwrap();
+    std::fs::write(keystore::keystore_path(storage, "mallory"), &stored).unwrap();
+    let copied = keystore::load(storage, "mallory", "correct horse");
+    let plain_left = plain.exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!plain_left);
+    assert!(!stored.contains("PRIVATE KEY"));
+    assert_eq!(opened.unwrap(), key);
+    assert!(matches!(wrong, Err(StorageError::Passphrase(_))));
+    assert!(matches!(copied, Err(StorageError::Passphrase(_))));
+}
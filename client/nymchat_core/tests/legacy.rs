@@ -0,0 +1,75 @@
+use nymchat_core::legacy::{import_account, LegacyImport};
+use nymchat_core::{Db, StorageError};
+use rusqlite::{params, Connection};
+use std::fs;
+
+/// An account as the old Python client (`dbUtils.SQLiteManager`) left it.
+fn legacy_account(dir: &std::path::Path) {
+    let user_dir = dir.join("alice");
+    fs::create_dir_all(&user_dir).unwrap();
+    fs::write(user_dir.join("alice_private_key.pem"), "alice private key").unwrap();
+    fs::write(user_dir.join("alice_public_key.pem"), "alice public key").unwrap();
+    let conn = Connection::open(user_dir.join("alice_client.db")).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (username TEXT PRIMARY KEY, public_key TEXT NOT NULL);
+         CREATE TABLE contacts_alice (username TEXT PRIMARY KEY, public_key TEXT NOT NULL);
+         CREATE TABLE messages_alice (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             username TEXT NOT NULL,
+             type TEXT CHECK(type IN ('to', 'from')) NOT NULL,
+             message TEXT NOT NULL,
+             timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+         );
+         INSERT INTO users VALUES ('alice', 'alice public key');
+         INSERT INTO contacts_alice VALUES ('bob', 'bob key'), ('carol', 'carol old key');",
+    )
+    .unwrap();
+    for (contact, kind, message, timestamp) in [
+        ("bob", "to", "Hello Bob", "2024-01-02 03:04:05"),
+        ("bob", "from", "{\"looks\": \"like JSON\"}", "2024-01-02 03:05:00"),
+        ("carol", "from", "Hi", "2024-01-03 10:00:00"),
+    ] {
+        conn.execute(
+            "INSERT INTO messages_alice (username, type, message, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            params![contact, kind, message, timestamp],
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn accounts_of_the_old_client_are_imported_once() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-legacy-{}", std::process::id()));
+    let (legacy, storage) = (dir.join("old"), dir.join("new"));
+    legacy_account(&legacy);
+    let (legacy, storage) = (legacy.to_str().unwrap(), storage.to_str().unwrap());
+    {
+        // Carol is known here already, with the key she has now.
+        let db = Db::open("alice", storage).unwrap();
+        db.create_user_tables("alice").unwrap();
+        db.add_contact("alice", "carol", "carol new key").unwrap();
+    }
+
+    let imported = import_account("alice", legacy, storage).unwrap();
+    assert_eq!(imported, LegacyImport { keys: 2, contacts: 1, conflicts: vec!["carol".into()], messages: 3 });
+    assert_eq!(import_account("alice", legacy, storage).unwrap(), LegacyImport {
+        conflicts: vec!["carol".into()],
+        ..Default::default()
+    });
+
+    let db = Db::open("alice", storage).unwrap();
+    assert_eq!(db.get_all_users().unwrap(), [("alice".to_string(), "alice public key".to_string())]);
+    assert_eq!(db.get_contact("alice", "carol").unwrap().unwrap().1, "carol new key");
+    let bob: Vec<_> = db.load_messages("alice", "bob").unwrap();
+    assert_eq!(bob[0], ("to".into(), "Hello Bob".into(), "2024-01-02 03:04:05".into()));
+    // Old rows are text, whatever they look like.
+    assert_eq!(bob[1].1, "{\"looks\": \"like JSON\"}");
+    let key = fs::read_to_string(dir.join("new/alice/alice_private_key.pem")).unwrap();
+    assert_eq!(key, "alice private key");
+
+    // Another account of the same name is not merged into this one.
+    fs::write(dir.join("old/alice/alice_public_key.pem"), "someone else").unwrap();
+    assert!(matches!(import_account("alice", legacy, storage), Err(StorageError::Import { .. })));
+    assert!(matches!(import_account("alice", storage, storage), Err(StorageError::Import { .. })));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,49 @@
+use nymchat_core::transfer::{Download, Upload, CHUNK_SIZE};
+use nymchat_core::{Error, ProtocolError};
+
+#[test]
+fn files_arrive_whole_in_any_order_or_not_at_all() {
+    let dir = std::env::temp_dir().join(format!("nymchat-core-transfer-{}", std::process::id()));
+    let downloads = dir.join("downloads");
+    std::fs::create_dir_all(&downloads).unwrap();
+    let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+    let source = dir.join("notes.txt");
+    std::fs::write(&source, &content).unwrap();
+    std::fs::write(downloads.join("notes.txt"), "taken").unwrap();
+
+    let mut upload = Upload::open(&source, "text/plain").unwrap();
+    let manifest = upload.manifest().clone();
+    assert_eq!((manifest.name.as_str(), manifest.size, manifest.chunks), ("notes.txt", content.len() as u64, 3));
+    let chunks: Vec<_> = (0..manifest.chunks).rev().map(|seq| upload.chunk(seq).unwrap()).collect();
+
+    let mut download = Download::start(&downloads, manifest.clone()).unwrap();
+    assert!(download.add(&chunks[0]).unwrap());
+    assert!(!download.add(&chunks[0]).unwrap());
+    // A chunk put in another place does not decrypt.
+    let mut moved = chunks[1].clone();
+    moved.seq = chunks[2].seq;
+    assert!(matches!(download.add(&moved), Err(Error::Crypto(_))));
+    download.add(&chunks[1]).unwrap();
+    download.add(&chunks[2]).unwrap();
+    assert!(download.is_complete());
+    let received = download.finish().unwrap();
+    assert_eq!(received, downloads.join("notes (1).txt"));
+    assert_eq!(std::fs::read(&received).unwrap(), content);
+
+    // A file that does not match its manifest is discarded; names cannot leave the downloads directory.
+    let mut forged = manifest.clone();
+    forged.sha256 = "00".repeat(32);
+    forged.name = "../../escape.txt".to_string();
+    let mut download = Download::start(&downloads, forged).unwrap();
+    assert_eq!(download.manifest().name, "escape.txt");
+    chunks.iter().for_each(|chunk| assert!(download.add(chunk).unwrap()));
+    assert!(matches!(download.finish(), Err(Error::Protocol(ProtocolError::Transfer(_)))));
+    let mut oversized = manifest;
+    oversized.chunks += 1;
+    let refused = Download::start(&downloads, oversized).is_err();
+    let left: Vec<_> = std::fs::read_dir(&downloads).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(refused);
+    assert_eq!(left.len(), 2);
+}
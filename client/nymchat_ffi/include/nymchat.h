@@ -0,0 +1,245 @@
+#ifndef NYMCHAT_H
+#define NYMCHAT_H
+
+/* Generated by cbindgen from src/lib.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Kind of the last error, so callers can react without parsing its message.
+ */
+typedef enum NymchatErrorKind {
+  /**
+   * No call on this thread has failed yet.
+   */
+  NYMCHAT_ERROR_KIND_NONE,
+  /**
+   * A null handle, or a string argument that is null or not UTF-8.
+   */
+  NYMCHAT_ERROR_KIND_INVALID_ARGUMENT,
+  /**
+   * An invalid key, or a signature or decryption that failed.
+   */
+  NYMCHAT_ERROR_KIND_CRYPTO,
+  /**
+   * The local store could not be read or written.
+   */
+  NYMCHAT_ERROR_KIND_STORAGE,
+  /**
+   * The mixnet could not be reached, a send failed or a reply timed out.
+   */
+  NYMCHAT_ERROR_KIND_TRANSPORT,
+  /**
+   * The server refused the request, or replied in a way it should not.
+   */
+  NYMCHAT_ERROR_KIND_PROTOCOL,
+  /**
+   * Anything else, such as the runtime failing to start.
+   */
+  NYMCHAT_ERROR_KIND_OTHER,
+} NymchatErrorKind;
+
+/**
+ * Kind of event delivered to the event callback. Which fields of
+ * `NymchatEvent` are set depends on it; the others are null.
+ */
+typedef enum NymchatEventKind {
+  /**
+   * Anything not decoded into one of the kinds below, such as server
+   * replies, and everything before `nymchat_open_inbox`: `message` is
+   * the message as it arrived and `sender_tag` may be set.
+   */
+  NYMCHAT_EVENT_KIND_RAW,
+  /**
+   * A chat message: `from`, `message` (its text, or a location as JSON),
+   * `message_kind` ("text" or "location") and `verified`.
+   */
+  NYMCHAT_EVENT_KIND_CHAT_MESSAGE,
+  /**
+   * `from`'s client received our messages, or `from` read them:
+   * `message` is "delivered" or "read". Which messages is recorded in the store.
+   */
+  NYMCHAT_EVENT_KIND_RECEIPT,
+  /**
+   * Someone not in our contacts wrote: `from` and the `public_key` they claim.
+   */
+  NYMCHAT_EVENT_KIND_CONTACT_REQUEST,
+  /**
+   * A contact wrote with another key: `from`, `old_public_key` and `public_key`.
+   */
+  NYMCHAT_EVENT_KIND_KEY_CHANGE,
+  /**
+   * The mixnet connection is up.
+   */
+  NYMCHAT_EVENT_KIND_CONNECTED,
+  /**
+   * The mixnet connection was lost.
+   */
+  NYMCHAT_EVENT_KIND_DISCONNECTED,
+  /**
+   * A direct message could not be parsed, decrypted or verified and was
+   * quarantined: `message` is why, and `count` how many are now kept.
+   */
+  NYMCHAT_EVENT_KIND_QUARANTINED,
+  /**
+   * `from` is typing to us. Nothing is stored.
+   */
+  NYMCHAT_EVENT_KIND_TYPING,
+} NymchatEventKind;
+
+/**
+ * Opaque client handle.
+ */
+typedef struct NymchatClient NymchatClient;
+
+/**
+ * Event passed to the callback. Strings are only valid during the callback.
+ */
+typedef struct NymchatEvent {
+  enum NymchatEventKind kind;
+  const char *message;
+  const char *sender_tag;
+  const char *from;
+  const char *message_kind;
+  const char *public_key;
+  const char *old_public_key;
+  /**
+   * Unix seconds the message was received at; 0 for connection events.
+   */
+  uint64_t timestamp;
+  /**
+   * Whether a chat message is signed with the key stored for its sender.
+   */
+  bool verified;
+  /**
+   * Messages in quarantine, for `Quarantined` events.
+   */
+  uint64_t count;
+} NymchatEvent;
+
+/**
+ * Called from a background thread for every event.
+ */
+typedef void (*NymchatEventCallback)(const struct NymchatEvent *event, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Connects a new ephemeral client to the mixnet. Returns null on failure.
+ * The network is read from `NYM_NETWORK` and related variables, as for the
+ * Python client; mainnet when unset.
+ *
+ * # Safety
+ * `user_data` is passed back untouched to `callback` from a background thread.
+ */
+struct NymchatClient *nymchat_connect(NymchatEventCallback callback, void *user_data);
+
+/**
+ * Returns the client's own nym address. Free with `nymchat_string_free`.
+ *
+ * # Safety
+ * `client` must be a live handle from `nymchat_connect`.
+ */
+char *nymchat_nym_address(const struct NymchatClient *client);
+
+/**
+ * Registers `username` with the discovery server at `server_address`.
+ * `private_key_pem` is the user's PKCS#8 P-256 key, as saved by the Python client.
+ *
+ * # Safety
+ * `client` must be a live handle; string arguments must be NUL-terminated.
+ */
+int nymchat_register(struct NymchatClient *client,
+                     const char *server_address,
+                     const char *username,
+                     const char *private_key_pem);
+
+/**
+ * Logs `username` in with the discovery server at `server_address`.
+ *
+ * # Safety
+ * `client` must be a live handle; string arguments must be NUL-terminated.
+ */
+int nymchat_login(struct NymchatClient *client,
+                  const char *server_address,
+                  const char *username,
+                  const char *private_key_pem);
+
+/**
+ * Decrypts what arrives for `username` from now on, with their key and the
+ * contacts in their database under `storage_dir`, so the callback gets chat
+ * messages, receipts, contact requests and key changes instead of raw
+ * envelopes. Call it once the user has logged in.
+ *
+ * # Safety
+ * `client` must be a live handle; string arguments must be NUL-terminated.
+ */
+int nymchat_open_inbox(struct NymchatClient *client,
+                       const char *username,
+                       const char *private_key_pem,
+                       const char *storage_dir);
+
+/**
+ * Probes the server at `server_address` and returns its health as JSON:
+ * `{"rttMs": .., "version": .., "uptime": .., "server": ..}`. Returns null
+ * on failure. Free with `nymchat_string_free`.
+ *
+ * # Safety
+ * `client` must be a live handle; `server_address` must be NUL-terminated.
+ */
+char *nymchat_health(struct NymchatClient *client, const char *server_address);
+
+/**
+ * Probes each server in the comma-separated `server_addresses` and returns
+ * the address that answered fastest, or null if none did. Free with
+ * `nymchat_string_free`.
+ *
+ * # Safety
+ * `client` must be a live handle; `server_addresses` must be NUL-terminated.
+ */
+char *nymchat_fastest_server(struct NymchatClient *client, const char *server_addresses);
+
+/**
+ * Sends a raw message (usually a JSON envelope) to `recipient`, with reply SURBs attached.
+ *
+ * # Safety
+ * `client` must be a live handle; string arguments must be NUL-terminated.
+ */
+int nymchat_send(struct NymchatClient *client, const char *recipient, const char *message);
+
+/**
+ * Stops the listener, disconnects from the mixnet and frees the handle.
+ *
+ * # Safety
+ * `client` must be a handle from `nymchat_connect`; it is invalid afterwards.
+ */
+void nymchat_disconnect(struct NymchatClient *client);
+
+/**
+ * Returns the last error on this thread, or null. Valid until the next call.
+ */
+const char *nymchat_last_error(void);
+
+/**
+ * Returns the kind of the last error on this thread.
+ */
+enum NymchatErrorKind nymchat_last_error_kind(void);
+
+/**
+ * Frees a string returned by this library.
+ *
+ * # Safety
+ * `s` must come from this library and not have been freed already.
+ */
+void nymchat_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* NYMCHAT_H */
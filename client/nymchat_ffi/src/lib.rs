@@ -0,0 +1,510 @@
+//! C ABI for nymCHAT clients. The header in `include/nymchat.h` is generated
+//! from this file by cbindgen at build time.
+//!
+//! Every call except `nymchat_connect` takes a handle returned by
+//! `nymchat_connect`. Functions returning `int` yield `0` on success and `-1`
+//! on failure, in which case `nymchat_last_error` describes what went wrong
+//! and `nymchat_last_error_kind` says what kind of failure it was.
+
+use nymchat_core::client::ChatClient;
+use nymchat_core::{Db, Error, Event, Inbox, TransportStatus};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use tokio::runtime::Runtime;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(NymchatErrorKind, CString)>> = const { RefCell::new(None) };
+}
+
+/// Kind of the last error, so callers can react without parsing its message.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NymchatErrorKind {
+    /// No call on this thread has failed yet.
+    None,
+    /// A null handle, or a string argument that is null or not UTF-8.
+    InvalidArgument,
+    /// An invalid key, or a signature or decryption that failed.
+    Crypto,
+    /// The local store could not be read or written.
+    Storage,
+    /// The mixnet could not be reached, a send failed or a reply timed out.
+    Transport,
+    /// The server refused the request, or replied in a way it should not.
+    Protocol,
+    /// Anything else, such as the runtime failing to start.
+    Other,
+}
+
+impl From<&Error> for NymchatErrorKind {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Crypto(_) => Self::Crypto,
+            Error::Storage(_) => Self::Storage,
+            Error::Transport(_) => Self::Transport,
+            Error::Protocol(_) => Self::Protocol,
+        }
+    }
+}
+
+fn set_last_error(kind: NymchatErrorKind, err: impl std::fmt::Display) {
+    let msg = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some((kind, msg)));
+}
+
+fn set_core_error(e: &Error) {
+    set_last_error(e.into(), e);
+}
+
+/// Opaque client handle.
+pub struct NymchatClient {
+    runtime: Runtime,
+    client: ChatClient,
+}
+
+/// Kind of event delivered to the event callback. Which fields of
+/// `NymchatEvent` are set depends on it; the others are null.
+#[repr(C)]
+pub enum NymchatEventKind {
+    /// Anything not decoded into one of the kinds below, such as server
+    /// replies, and everything before `nymchat_open_inbox`: `message` is
+    /// the message as it arrived and `sender_tag` may be set.
+    Raw,
+    /// A chat message: `from`, `message` (its text, or a location as JSON),
+    /// `message_kind` ("text" or "location") and `verified`.
+    ChatMessage,
+    /// `from`'s client received our messages, or `from` read them:
+    /// `message` is "delivered" or "read". Which messages is recorded in the store.
+    Receipt,
+    /// Someone not in our contacts wrote: `from` and the `public_key` they claim.
+    ContactRequest,
+    /// A contact wrote with another key: `from`, `old_public_key` and `public_key`.
+    KeyChange,
+    /// The mixnet connection is up.
+    Connected,
+    /// The mixnet connection was lost.
+    Disconnected,
+    /// A direct message could not be parsed, decrypted or verified and was
+    /// quarantined: `message` is why, and `count` how many are now kept.
+    Quarantined,
+    /// `from` is typing to us. Nothing is stored.
+    Typing,
+}
+
+/// Event passed to the callback. Strings are only valid during the callback.
+#[repr(C)]
+pub struct NymchatEvent {
+    pub kind: NymchatEventKind,
+    pub message: *const c_char,
+    pub sender_tag: *const c_char,
+    pub from: *const c_char,
+    pub message_kind: *const c_char,
+    pub public_key: *const c_char,
+    pub old_public_key: *const c_char,
+    /// Unix seconds the message was received at; 0 for connection events.
+    pub timestamp: u64,
+    /// Whether a chat message is signed with the key stored for its sender.
+    pub verified: bool,
+    /// Messages in quarantine, for `Quarantined` events.
+    pub count: u64,
+}
+
+/// Called from a background thread for every event.
+pub type NymchatEventCallback = Option<extern "C" fn(event: *const NymchatEvent, user_data: *mut c_void)>;
+
+struct CallbackTarget {
+    callback: extern "C" fn(*const NymchatEvent, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// The embedder promises `user_data` may be used from the listener thread.
+unsafe impl Send for CallbackTarget {}
+unsafe impl Sync for CallbackTarget {}
+
+impl CallbackTarget {
+    fn dispatch(&self, event: Event) {
+        let (kind, fields) = match event {
+            Event::Raw { message, sender_tag, ts } => (
+                NymchatEventKind::Raw,
+                EventFields { message: Some(message), sender_tag, ts, ..Default::default() },
+            ),
+            Event::ChatMessage { from, body, kind, ts, verified } => (
+                NymchatEventKind::ChatMessage,
+                EventFields {
+                    from: Some(from),
+                    message: Some(body),
+                    message_kind: Some(kind),
+                    ts,
+                    verified,
+                    ..Default::default()
+                },
+            ),
+            Event::Receipt { from, status, ts, .. } => (
+                NymchatEventKind::Receipt,
+                EventFields { from: Some(from), message: Some(status), ts, ..Default::default() },
+            ),
+            Event::Typing { from, ts } => {
+                (NymchatEventKind::Typing, EventFields { from: Some(from), ts, ..Default::default() })
+            }
+            Event::ContactRequest { from, public_key, ts } => (
+                NymchatEventKind::ContactRequest,
+                EventFields { from: Some(from), public_key: Some(public_key), ts, ..Default::default() },
+            ),
+            Event::KeyChange { username, old_key, new_key } => (
+                NymchatEventKind::KeyChange,
+                EventFields {
+                    from: Some(username),
+                    public_key: Some(new_key),
+                    old_public_key: Some(old_key),
+                    ..Default::default()
+                },
+            ),
+            Event::Quarantined { reason, count } => (
+                NymchatEventKind::Quarantined,
+                EventFields { message: Some(reason), count, ..Default::default() },
+            ),
+            Event::TransportStatus(TransportStatus::Connected) => (NymchatEventKind::Connected, Default::default()),
+            Event::TransportStatus(TransportStatus::Disconnected) => {
+                (NymchatEventKind::Disconnected, Default::default())
+            }
+        };
+
+        let c_string = |s: Option<String>| s.and_then(|s| CString::new(s).ok());
+        let as_ptr = |s: &Option<CString>| s.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+        let message = c_string(fields.message);
+        let sender_tag = c_string(fields.sender_tag);
+        let from = c_string(fields.from);
+        let message_kind = c_string(fields.message_kind);
+        let public_key = c_string(fields.public_key);
+        let old_public_key = c_string(fields.old_public_key);
+        let event = NymchatEvent {
+            kind,
+            message: as_ptr(&message),
+            sender_tag: as_ptr(&sender_tag),
+            from: as_ptr(&from),
+            message_kind: as_ptr(&message_kind),
+            public_key: as_ptr(&public_key),
+            old_public_key: as_ptr(&old_public_key),
+            timestamp: fields.ts,
+            verified: fields.verified,
+            count: fields.count,
+        };
+        (self.callback)(&event, self.user_data);
+    }
+}
+
+/// The parts of an event set for its kind, before they become C strings.
+#[derive(Default)]
+struct EventFields {
+    message: Option<String>,
+    sender_tag: Option<String>,
+    from: Option<String>,
+    message_kind: Option<String>,
+    public_key: Option<String>,
+    old_public_key: Option<String>,
+    ts: u64,
+    verified: bool,
+    count: u64,
+}
+
+unsafe fn to_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+fn status(result: nymchat_core::Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_core_error(&e);
+            -1
+        }
+    }
+}
+
+fn invalid_argument(err: impl std::fmt::Display) -> c_int {
+    set_last_error(NymchatErrorKind::InvalidArgument, err);
+    -1
+}
+
+/// Connects a new ephemeral client to the mixnet. Returns null on failure.
+/// The network is read from `NYM_NETWORK` and related variables, as for the
+/// Python client; mainnet when unset.
+///
+/// # Safety
+/// `user_data` is passed back untouched to `callback` from a background thread.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_connect(
+    callback: NymchatEventCallback,
+    user_data: *mut c_void,
+) -> *mut NymchatClient {
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            set_last_error(NymchatErrorKind::Other, format!("Failed to start runtime: {}", e));
+            return ptr::null_mut();
+        }
+    };
+
+    let target = callback.map(|callback| CallbackTarget { callback, user_data });
+    let sink = Box::new(move |event: Event| {
+        if let Some(ref target) = target {
+            target.dispatch(event);
+        }
+    });
+
+    match runtime.block_on(ChatClient::connect(sink)) {
+        Ok(client) => Box::into_raw(Box::new(NymchatClient { runtime, client })),
+        Err(e) => {
+            set_last_error((&e).into(), format!("Client init failed: {}", e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Returns the client's own nym address. Free with `nymchat_string_free`.
+///
+/// # Safety
+/// `client` must be a live handle from `nymchat_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_nym_address(client: *const NymchatClient) -> *mut c_char {
+    match client.as_ref() {
+        Some(handle) => CString::new(handle.client.nym_address()).map_or(ptr::null_mut(), CString::into_raw),
+        None => {
+            set_last_error(NymchatErrorKind::InvalidArgument, "client is null");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Registers `username` with the discovery server at `server_address`.
+/// `private_key_pem` is the user's PKCS#8 P-256 key, as saved by the Python client.
+///
+/// # Safety
+/// `client` must be a live handle; string arguments must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_register(
+    client: *mut NymchatClient,
+    server_address: *const c_char,
+    username: *const c_char,
+    private_key_pem: *const c_char,
+) -> c_int {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("client is null"),
+    };
+    let args = (|| {
+        Ok::<_, String>((
+            to_str(server_address, "server_address")?,
+            to_str(username, "username")?,
+            to_str(private_key_pem, "private_key_pem")?,
+        ))
+    })();
+    match args {
+        Ok((server, username, key)) => {
+            status(handle.runtime.block_on(handle.client.register(server, username, key)))
+        }
+        Err(e) => invalid_argument(e),
+    }
+}
+
+/// Logs `username` in with the discovery server at `server_address`.
+///
+/// # Safety
+/// `client` must be a live handle; string arguments must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_login(
+    client: *mut NymchatClient,
+    server_address: *const c_char,
+    username: *const c_char,
+    private_key_pem: *const c_char,
+) -> c_int {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("client is null"),
+    };
+    let args = (|| {
+        Ok::<_, String>((
+            to_str(server_address, "server_address")?,
+            to_str(username, "username")?,
+            to_str(private_key_pem, "private_key_pem")?,
+        ))
+    })();
+    match args {
+        Ok((server, username, key)) => {
+            status(handle.runtime.block_on(handle.client.login(server, username, key)))
+        }
+        Err(e) => invalid_argument(e),
+    }
+}
+
+/// Decrypts what arrives for `username` from now on, with their key and the
+/// contacts in their database under `storage_dir`, so the callback gets chat
+/// messages, receipts, contact requests and key changes instead of raw
+/// envelopes. Call it once the user has logged in.
+///
+/// # Safety
+/// `client` must be a live handle; string arguments must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_open_inbox(
+    client: *mut NymchatClient,
+    username: *const c_char,
+    private_key_pem: *const c_char,
+    storage_dir: *const c_char,
+) -> c_int {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("client is null"),
+    };
+    let args = (|| {
+        Ok::<_, String>((
+            to_str(username, "username")?,
+            to_str(private_key_pem, "private_key_pem")?,
+            to_str(storage_dir, "storage_dir")?,
+        ))
+    })();
+    let (username, key, storage_dir) = match args {
+        Ok(args) => args,
+        Err(e) => return invalid_argument(e),
+    };
+    let inbox = Db::open(username, storage_dir)
+        .map_err(Error::from)
+        .and_then(|db| Inbox::new(username, key, db));
+    status(inbox.map(|inbox| handle.client.set_inbox(inbox)))
+}
+
+/// Probes the server at `server_address` and returns its health as JSON:
+/// `{"rttMs": .., "version": .., "uptime": .., "server": ..}`. Returns null
+/// on failure. Free with `nymchat_string_free`.
+///
+/// # Safety
+/// `client` must be a live handle; `server_address` must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_health(client: *mut NymchatClient, server_address: *const c_char) -> *mut c_char {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => {
+            set_last_error(NymchatErrorKind::InvalidArgument, "client is null");
+            return ptr::null_mut();
+        }
+    };
+    let server = match to_str(server_address, "server_address") {
+        Ok(server) => server,
+        Err(e) => {
+            set_last_error(NymchatErrorKind::InvalidArgument, e);
+            return ptr::null_mut();
+        }
+    };
+    match handle.runtime.block_on(handle.client.health(server)) {
+        Ok(health) => {
+            let report = serde_json::json!({
+                "rttMs": health.rtt.as_millis() as u64,
+                "version": health.version,
+                "uptime": health.uptime,
+                "server": health.server,
+            });
+            CString::new(report.to_string()).map_or(ptr::null_mut(), CString::into_raw)
+        }
+        Err(e) => {
+            set_core_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Probes each server in the comma-separated `server_addresses` and returns
+/// the address that answered fastest, or null if none did. Free with
+/// `nymchat_string_free`.
+///
+/// # Safety
+/// `client` must be a live handle; `server_addresses` must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_fastest_server(
+    client: *mut NymchatClient,
+    server_addresses: *const c_char,
+) -> *mut c_char {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => {
+            set_last_error(NymchatErrorKind::InvalidArgument, "client is null");
+            return ptr::null_mut();
+        }
+    };
+    let servers = match to_str(server_addresses, "server_addresses") {
+        Ok(servers) => servers.split(',').map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>(),
+        Err(e) => {
+            set_last_error(NymchatErrorKind::InvalidArgument, e);
+            return ptr::null_mut();
+        }
+    };
+    match handle.runtime.block_on(handle.client.fastest_server(&servers)) {
+        Ok((server, _)) => CString::new(server).map_or(ptr::null_mut(), CString::into_raw),
+        Err(e) => {
+            set_core_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Sends a raw message (usually a JSON envelope) to `recipient`, with reply SURBs attached.
+///
+/// # Safety
+/// `client` must be a live handle; string arguments must be NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_send(
+    client: *mut NymchatClient,
+    recipient: *const c_char,
+    message: *const c_char,
+) -> c_int {
+    let handle = match client.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("client is null"),
+    };
+    match (to_str(recipient, "recipient"), to_str(message, "message")) {
+        (Ok(recipient), Ok(message)) => {
+            status(handle.runtime.block_on(handle.client.send(recipient, message)))
+        }
+        (Err(e), _) | (_, Err(e)) => invalid_argument(e),
+    }
+}
+
+/// Stops the listener, disconnects from the mixnet and frees the handle.
+///
+/// # Safety
+/// `client` must be a handle from `nymchat_connect`; it is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_disconnect(client: *mut NymchatClient) {
+    if client.is_null() {
+        return;
+    }
+    let mut handle = Box::from_raw(client);
+    let NymchatClient { runtime, client } = &mut *handle;
+    runtime.block_on(client.disconnect());
+}
+
+/// Returns the last error on this thread, or null. Valid until the next call.
+#[no_mangle]
+pub extern "C" fn nymchat_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |(_, msg)| msg.as_ptr()))
+}
+
+/// Returns the kind of the last error on this thread.
+#[no_mangle]
+pub extern "C" fn nymchat_last_error_kind() -> NymchatErrorKind {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(NymchatErrorKind::None, |(kind, _)| *kind))
+}
+
+/// Frees a string returned by this library.
+///
+/// # Safety
+/// `s` must come from this library and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn nymchat_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
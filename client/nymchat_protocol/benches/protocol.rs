@@ -0,0 +1,60 @@
+//! Throughput of the per-message crypto and wire format work.
+//!
+//!     cargo bench -p nymchat-protocol --bench protocol
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use nymchat_protocol::{to_python_json, ChatPayload, Crypto, Envelope, MessageBody};
+
+/// A chat message of typical length, wrapped the way clients send it.
+const MESSAGE: &str = r#"{"type": 0, "message": "Hey, are we still on for tomorrow? Let me know when you are free."}"#;
+
+fn crypto(c: &mut Criterion) {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+    let signature = Crypto::sign(&secret, MESSAGE);
+    let encrypted = Crypto::encrypt(&public, MESSAGE).unwrap();
+
+    let mut group = c.benchmark_group("crypto");
+    group.bench_function("sign", |b| b.iter(|| Crypto::sign(&secret, black_box(MESSAGE))));
+    group.bench_function("verify", |b| {
+        b.iter(|| assert!(Crypto::verify(&public, black_box(MESSAGE), &signature)))
+    });
+    group.bench_function("encrypt", |b| b.iter(|| Crypto::encrypt(&public, black_box(MESSAGE)).unwrap()));
+    group.bench_function("decrypt", |b| b.iter(|| Crypto::decrypt(&secret, black_box(&encrypted)).unwrap()));
+    group.finish();
+}
+
+fn envelope(c: &mut Criterion) {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+    let encrypted = Crypto::encrypt(&public, MESSAGE).unwrap();
+    let payload = ChatPayload {
+        sender: "alice".into(),
+        recipient: Some("bob".into()),
+        recipient_token: None,
+        body: MessageBody {
+            payload_signature: Crypto::sign(&secret, &to_python_json(&encrypted).unwrap()),
+            encrypted_payload: encrypted,
+        },
+        encrypted: Some(true),
+        sender_public_key: None,
+    };
+    let content = to_python_json(&payload).unwrap();
+    let envelope = Envelope::new("incomingMessage")
+        .with_context("chat")
+        .with_signature(Crypto::sign(&secret, &content))
+        .with_content(content);
+    let raw = envelope.to_json_string().unwrap();
+
+    let mut group = c.benchmark_group("envelope");
+    group.bench_function("encode", |b| b.iter(|| black_box(&envelope).to_json_string().unwrap()));
+    group.bench_function("encode_padded", |b| {
+        b.iter(|| black_box(&envelope).to_padded_json_string(1024).unwrap())
+    });
+    group.bench_function("decode", |b| b.iter(|| Envelope::parse(black_box(&raw)).unwrap()));
+    group.bench_function("payload_to_python_json", |b| b.iter(|| to_python_json(black_box(&payload)).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, crypto, envelope);
+criterion_main!(benches);
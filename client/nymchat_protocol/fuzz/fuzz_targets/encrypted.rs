@@ -0,0 +1,16 @@
+//! `Encrypted` payload decoding: the PEM ephemeral key and the hex salt, IV,
+//! tag and ciphertext fields are all sender-controlled.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nymchat_protocol::{Crypto, Encrypted};
+use p256::SecretKey;
+
+fuzz_target!(|data: &[u8]| {
+    let payload: Encrypted = match serde_json::from_slice(data) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    let key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+    let _ = Crypto::decrypt(&key, &payload);
+});
@@ -0,0 +1,24 @@
+//! Envelope parsing, as done for every message read off the mixnet.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nymchat_protocol::Envelope;
+
+fuzz_target!(|data: &[u8]| {
+    let raw = match std::str::from_utf8(data) {
+        Ok(raw) => raw,
+        Err(_) => return,
+    };
+    if let Ok(envelope) = Envelope::parse(raw) {
+        let _ = envelope.field("username");
+        // Whatever parses must survive a round trip. Extra fields are passed
+        // through opaquely, and serde_json does not round-trip floats exactly.
+        let decoded = Envelope::parse(&envelope.to_json_string().unwrap()).unwrap();
+        assert_eq!(
+            (decoded.action, decoded.context, decoded.content, decoded.signature),
+            (envelope.action.clone(), envelope.context.clone(), envelope.content.clone(), envelope.signature.clone())
+        );
+        let padded = envelope.to_padded_json_string(512).unwrap();
+        assert_eq!(padded.len() % 512, 0);
+    }
+});
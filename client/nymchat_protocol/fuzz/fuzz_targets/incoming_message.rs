@@ -0,0 +1,40 @@
+//! The nested JSON of an `incomingMessage`: the envelope's `content` string
+//! holds a `ChatPayload`, whose decrypted payload is JSON again. Clients walk
+//! every layer before knowing whether the sender is genuine.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nymchat_protocol::{to_python_json, ChatPayload, Crypto, Envelope};
+use p256::SecretKey;
+use serde_json::Value;
+
+fuzz_target!(|data: &[u8]| {
+    let raw = match std::str::from_utf8(data) {
+        Ok(raw) => raw,
+        Err(_) => return,
+    };
+    let envelope = match Envelope::parse(raw) {
+        Ok(envelope) => envelope,
+        Err(_) => return,
+    };
+    let _ = envelope.field("messageId");
+    let payload: ChatPayload = match envelope.content.as_deref().map(serde_json::from_str) {
+        Some(Ok(payload)) => payload,
+        _ => return,
+    };
+
+    // Signatures are checked over the Python-style serialization.
+    let signed = to_python_json(&payload.body.encrypted_payload).unwrap();
+    if let Some(pem) = payload.sender_public_key.as_deref() {
+        if let Ok(sender) = Crypto::public_key_from_pem(pem) {
+            let _ = Crypto::verify(&sender, &signed, &payload.body.payload_signature);
+        }
+    }
+
+    let key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+    if let Ok(plaintext) = Crypto::decrypt(&key, &payload.body.encrypted_payload).map(|m| m.into_unauthenticated()) {
+        if let Ok(message) = serde_json::from_str::<Value>(&plaintext) {
+            let _ = message.get("message").and_then(Value::as_str);
+        }
+    }
+});
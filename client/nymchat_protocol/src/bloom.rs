@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bloom filter over registered usernames, served by the directory so clients
+/// can check whether a name exists without telling the server which one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    /// Bit array, least significant bit first within each byte. Hex encoded on the wire.
+    #[serde(with = "hex_bytes")]
+    bits: Vec<u8>,
+    /// Number of bits in the filter.
+    size: u64,
+    /// Number of hash functions.
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `items` entries at the given false positive rate.
+    pub fn with_capacity(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let size = (-(items * false_positive_rate.ln()) / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let hashes = ((size as f64 / items) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; size.div_ceil(8) as usize],
+            size,
+            hashes,
+        }
+    }
+
+    fn positions<'a>(&self, item: &'a str) -> impl Iterator<Item = u64> + 'a {
+        let size = self.size.max(1);
+        (0..self.hashes).map(move |i| {
+            let digest = Sha256::new()
+                .chain_update(i.to_le_bytes())
+                .chain_update(item.as_bytes())
+                .finalize();
+            let mut prefix = [0u8; 8];
+            prefix.copy_from_slice(&digest[..8]);
+            u64::from_le_bytes(prefix) % size
+        })
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for position in self.positions(item) {
+            if let Some(byte) = self.bits.get_mut((position / 8) as usize) {
+                *byte |= 1 << (position % 8);
+            }
+        }
+    }
+
+    /// `false` means the item is definitely absent; `true` means it is probably present.
+    pub fn contains(&self, item: &str) -> bool {
+        self.positions(item).all(|position| {
+            self.bits
+                .get((position / 8) as usize)
+                .is_some_and(|byte| byte & (1 << (position % 8)) != 0)
+        })
+    }
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,254 @@
+use crate::envelope::{to_python_json, Encrypted, EncryptedBody};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use hkdf::Hkdf;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use p256::{PublicKey, SecretKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+/// HKDF info string used by the Python client when deriving message keys.
+const HKDF_INFO: &[u8] = b"ECDH session key";
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+/// Groups of five digits in a safety number.
+pub const SAFETY_NUMBER_GROUPS: usize = 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("invalid hex in field `{0}`")]
+    InvalidHex(&'static str),
+    #[error("invalid length for field `{0}`")]
+    InvalidLength(&'static str),
+    #[error("decryption failed")]
+    Decryption,
+    #[error("decrypted message is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("signature verification failed")]
+    Signature,
+    #[error("invalid JSON: {0}")]
+    InvalidJson(String),
+    #[error("`{0}` is not a member of the group")]
+    NotAMember(String),
+    #[error("no sender key from `{0}`")]
+    UnknownSenderKey(String),
+    #[error("message key already used or too far ahead")]
+    MessageKey,
+    #[error("no ratchet session to encrypt or decrypt with")]
+    NoSession,
+}
+
+pub type Result<T> = std::result::Result<T, CryptoError>;
+
+/// Compares secrets (MACs, token hashes, nonces) in time that depends only on
+/// their lengths, so a mismatch does not tell an attacker how much matched.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+/// A decrypted message whose sender has not been checked. The plaintext is
+/// only handed out by [`Unverified::verify`], once the payload signature
+/// checks out, or by [`Unverified::into_unauthenticated`] for callers that
+/// authenticate it some other way.
+#[must_use]
+pub struct Unverified {
+    plaintext: String,
+    /// The payload as its sender signed it.
+    signed: String,
+}
+
+impl Unverified {
+    /// The plaintext, if `signature_hex` is the sender's signature over the payload.
+    pub fn verify(self, sender: &PublicKey, signature_hex: &str) -> Result<String> {
+        if Crypto::verify(sender, &self.signed, signature_hex) {
+            Ok(self.plaintext)
+        } else {
+            Err(CryptoError::Signature)
+        }
+    }
+
+    /// The plaintext without a signature check: for payloads that carry their
+    /// own authentication, such as sealed-sender messages, and for tests.
+    pub fn into_unauthenticated(self) -> String {
+        self.plaintext
+    }
+}
+
+impl fmt::Debug for Unverified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Unverified { .. }")
+    }
+}
+
+/// ECDSA signing and ECDH + AES-GCM encryption over SECP256R1, byte-compatible
+/// with `CryptoUtils` in the Python client.
+pub struct Crypto;
+
+impl Crypto {
+    /// Generates a new long-term keypair, returning the key and its public PEM.
+    pub fn generate_key_pair() -> Result<(SecretKey, String)> {
+        let secret = SecretKey::random(&mut OsRng);
+        let public_pem = Self::public_key_pem(&secret.public_key())?;
+        Ok((secret, public_pem))
+    }
+
+    /// Loads a PKCS#8 PEM private key, as written by `CryptoUtils.save_keys`.
+    pub fn private_key_from_pem(pem: &str) -> Result<SecretKey> {
+        SecretKey::from_pkcs8_pem(pem).map_err(|e| CryptoError::InvalidKey(e.to_string()))
+    }
+
+    pub fn private_key_pem(key: &SecretKey) -> Result<String> {
+        key.to_pkcs8_pem(LineEnding::LF)
+            .map(|pem| pem.to_string())
+            .map_err(|e| CryptoError::InvalidKey(e.to_string()))
+    }
+
+    /// Loads a SubjectPublicKeyInfo PEM public key.
+    pub fn public_key_from_pem(pem: &str) -> Result<PublicKey> {
+        PublicKey::from_public_key_pem(pem).map_err(|e| CryptoError::InvalidKey(e.to_string()))
+    }
+
+    pub fn public_key_pem(key: &PublicKey) -> Result<String> {
+        key.to_public_key_pem(LineEnding::LF)
+            .map_err(|e| CryptoError::InvalidKey(e.to_string()))
+    }
+
+    /// The safety number of `key`: the SHA-256 of its DER encoding, read as
+    /// six 5-byte numbers, each shown as five decimal digits. Two people
+    /// comparing safety numbers out of band confirm they hold the same key;
+    /// digits are easier to read aloud than the hex [`crate::invite::fingerprint`].
+    pub fn safety_number(key: &PublicKey) -> String {
+        let der = key.to_public_key_der().expect("P-256 keys encode");
+        let digest = Sha256::digest(der.as_bytes());
+        digest[..SAFETY_NUMBER_GROUPS * 5]
+            .chunks(5)
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+                format!("{:05}", value % 100_000)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Signs `message` with ECDSA/SHA-256, returning the hex encoded DER signature.
+    pub fn sign(key: &SecretKey, message: &str) -> String {
+        let signature: Signature = SigningKey::from(key).sign(message.as_bytes());
+        hex::encode(signature.to_der().as_bytes())
+    }
+
+    /// Verifies a hex encoded DER signature over `message`.
+    pub fn verify(key: &PublicKey, message: &str, signature_hex: &str) -> bool {
+        let der = match hex::decode(signature_hex) {
+            Ok(der) => der,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_der(&der) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        VerifyingKey::from(key).verify(message.as_bytes(), &signature).is_ok()
+    }
+
+    /// Encrypts `plaintext` for `recipient` using a fresh ephemeral key. With
+    /// a new ephemeral key and salt every time, each AES key encrypts exactly
+    /// one message, so its random IV cannot repeat under it.
+    pub fn encrypt(recipient: &PublicKey, plaintext: &str) -> Result<Encrypted> {
+        let ephemeral = SecretKey::random(&mut OsRng);
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut iv = [0u8; IV_LEN];
+        OsRng.fill_bytes(&mut iv);
+
+        let cipher = Self::derive_cipher(&ephemeral, recipient, &salt)?;
+        let mut buffer = plaintext.as_bytes().to_vec();
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&iv), b"", &mut buffer)
+            .map_err(|_| CryptoError::Decryption)?;
+
+        Ok(Encrypted {
+            ephemeral_public_key: Self::public_key_pem(&ephemeral.public_key())?,
+            salt: hex::encode(salt),
+            encrypted_body: EncryptedBody {
+                iv: hex::encode(iv),
+                ciphertext: hex::encode(buffer),
+                tag: hex::encode(tag),
+            },
+        })
+    }
+
+    /// Decrypts a payload addressed to `key`. The plaintext stays behind
+    /// [`Unverified`] until the sender's signature is checked.
+    pub fn decrypt(key: &SecretKey, payload: &Encrypted) -> Result<Unverified> {
+        let ephemeral = Self::public_key_from_pem(&payload.ephemeral_public_key)?;
+        let salt = decode_hex(&payload.salt, "salt")?;
+        let iv = decode_hex(&payload.encrypted_body.iv, "iv")?;
+        let tag = decode_hex(&payload.encrypted_body.tag, "tag")?;
+        let mut buffer = decode_hex(&payload.encrypted_body.ciphertext, "ciphertext")?;
+
+        if iv.len() != IV_LEN {
+            return Err(CryptoError::InvalidLength("iv"));
+        }
+        if tag.len() != 16 {
+            return Err(CryptoError::InvalidLength("tag"));
+        }
+
+        let cipher = Self::derive_cipher(key, &ephemeral, &salt)?;
+        cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&iv), b"", &mut buffer, Tag::from_slice(&tag))
+            .map_err(|_| CryptoError::Decryption)?;
+
+        let plaintext = String::from_utf8(buffer).map_err(|_| CryptoError::InvalidUtf8)?;
+        let signed = to_python_json(payload).map_err(|_| CryptoError::InvalidLength("payload"))?;
+        Ok(Unverified { plaintext, signed })
+    }
+
+    /// Encrypts `plaintext` under a symmetric `key`, authenticating `aad` with
+    /// it. Each key must encrypt only one message, as sender-key message keys do.
+    pub fn encrypt_with_key(key: &[u8; 32], aad: &[u8], plaintext: &str) -> Result<EncryptedBody> {
+        let mut iv = [0u8; IV_LEN];
+        OsRng.fill_bytes(&mut iv);
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| CryptoError::InvalidLength("key"))?;
+        let mut buffer = plaintext.as_bytes().to_vec();
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&iv), aad, &mut buffer)
+            .map_err(|_| CryptoError::Decryption)?;
+        Ok(EncryptedBody { iv: hex::encode(iv), ciphertext: hex::encode(buffer), tag: hex::encode(tag) })
+    }
+
+    /// Decrypts a body made by [`Crypto::encrypt_with_key`] with the same `key` and `aad`.
+    pub fn decrypt_with_key(key: &[u8; 32], aad: &[u8], body: &EncryptedBody) -> Result<String> {
+        let iv = decode_hex(&body.iv, "iv")?;
+        let tag = decode_hex(&body.tag, "tag")?;
+        let mut buffer = decode_hex(&body.ciphertext, "ciphertext")?;
+        if iv.len() != IV_LEN {
+            return Err(CryptoError::InvalidLength("iv"));
+        }
+        if tag.len() != 16 {
+            return Err(CryptoError::InvalidLength("tag"));
+        }
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| CryptoError::InvalidLength("key"))?;
+        cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&iv), aad, &mut buffer, Tag::from_slice(&tag))
+            .map_err(|_| CryptoError::Decryption)?;
+        String::from_utf8(buffer).map_err(|_| CryptoError::InvalidUtf8)
+    }
+
+    fn derive_cipher(secret: &SecretKey, public: &PublicKey, salt: &[u8]) -> Result<Aes256Gcm> {
+        let shared = p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), shared.raw_secret_bytes().as_slice())
+            .expand(HKDF_INFO, &mut key)
+            .map_err(|_| CryptoError::InvalidLength("key"))?;
+        Aes256Gcm::new_from_slice(&key).map_err(|_| CryptoError::InvalidLength("key"))
+    }
+}
+
+pub(crate) fn decode_hex(value: &str, field: &'static str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|_| CryptoError::InvalidHex(field))
+}
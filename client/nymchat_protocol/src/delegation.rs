@@ -0,0 +1,120 @@
+//! Delegated keys: a sub-key for a user's bot or daemon.
+//!
+//! A user who runs a bot does not hand it their identity key. They issue a
+//! [`Delegation`] instead: a certificate, signed with the identity key, that
+//! names a sub-key, what it may do and until when. The bot signs its
+//! messages with the sub-key and sends the certificate along; contacts check
+//! the certificate against the identity key they already have for the user,
+//! then the message against the sub-key.
+//!
+//! A sub-key can only do what its scopes allow, which for now is sending
+//! chat messages ([`SCOPE_SEND`]). Rotating keys, deleting the account and
+//! issuing further delegations all need the identity key itself. Revoking a
+//! delegation before it expires is up to the issuer telling their contacts
+//! its [`Delegation::id`]; the certificate itself cannot be taken back.
+
+use crate::crypto::{Crypto, CryptoError};
+use crate::envelope::to_python_json;
+use p256::PublicKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Sending chat messages on the issuer's behalf.
+pub const SCOPE_SEND: &str = "send";
+/// The scopes a delegation may grant.
+pub const DELEGATION_SCOPES: &[&str] = &[SCOPE_SEND];
+
+#[derive(Debug, thiserror::Error)]
+pub enum DelegationError {
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error("invalid delegation: {0}")]
+    Invalid(String),
+    #[error("unknown scope {0:?}")]
+    Scope(String),
+    #[error("delegation expired")]
+    Expired,
+}
+
+pub type Result<T> = std::result::Result<T, DelegationError>;
+
+/// What a user's identity key signs to let a sub-key act for them.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delegation {
+    /// Random hex, naming the delegation when it is revoked.
+    pub id: String,
+    /// The user the sub-key acts for.
+    pub issuer: String,
+    /// The sub-key, as SubjectPublicKeyInfo PEM.
+    pub public_key: String,
+    pub scopes: Vec<String>,
+    /// Unix seconds after which the sub-key is no longer accepted.
+    pub expires_at: u64,
+}
+
+impl Delegation {
+    /// A delegation from `issuer` to the key in `public_key_pem`, for the
+    /// given scopes; none may be unknown, and there must be at least one.
+    pub fn new(issuer: &str, public_key_pem: &str, scopes: Vec<String>, expires_at: u64) -> Result<Self> {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let delegation = Delegation {
+            id: hex::encode(id),
+            issuer: issuer.to_string(),
+            public_key: public_key_pem.to_string(),
+            scopes,
+            expires_at,
+        };
+        delegation.check()?;
+        Ok(delegation)
+    }
+
+    /// The certificate for the issuer to sign, as JSON; contacts are sent
+    /// this exact string with the signature.
+    pub fn to_json(&self) -> String {
+        to_python_json(self).expect("delegation serializes")
+    }
+
+    /// The delegation in `signed`, if `signature_hex` is `issuer_key`'s
+    /// signature over it and it has not expired at `now` (Unix seconds).
+    /// Whether its issuer is the user the caller expects, and whether it was
+    /// revoked, is for the caller to check.
+    pub fn verify(signed: &str, signature_hex: &str, issuer_key: &PublicKey, now: u64) -> Result<Self> {
+        if !Crypto::verify(issuer_key, signed, signature_hex) {
+            return Err(CryptoError::Signature.into());
+        }
+        let delegation: Delegation =
+            serde_json::from_str(signed).map_err(|e| DelegationError::Invalid(e.to_string()))?;
+        delegation.check()?;
+        if now >= delegation.expires_at {
+            return Err(DelegationError::Expired);
+        }
+        Ok(delegation)
+    }
+
+    /// Whether the sub-key may act in `scope`.
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
+    /// The sub-key, to check the messages it signed.
+    pub fn key(&self) -> Result<PublicKey> {
+        Ok(Crypto::public_key_from_pem(&self.public_key)?)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.issuer.is_empty() {
+            return Err(DelegationError::Invalid("no issuer".to_string()));
+        }
+        if self.scopes.is_empty() {
+            return Err(DelegationError::Invalid("no scopes".to_string()));
+        }
+        if let Some(scope) = self.scopes.iter().find(|scope| !DELEGATION_SCOPES.contains(&scope.as_str())) {
+            return Err(DelegationError::Scope(scope.clone()));
+        }
+        self.key()?;
+        Ok(())
+    }
+}
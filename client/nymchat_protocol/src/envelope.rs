@@ -0,0 +1,217 @@
+use crate::group::GroupMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::ser::Formatter;
+use serde_json::{Map, Value};
+use std::io;
+
+/// Outer JSON wrapper carried in every mixnet message (`action`, `context`, `content`, `signature`).
+#[cfg_attr(feature = "python", pyo3::pyclass)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Action specific top-level fields such as `username` or `publicKey`.
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl Envelope {
+    pub fn new(action: &str) -> Self {
+        Envelope { action: action.to_string(), ..Default::default() }
+    }
+
+    pub fn with_context(mut self, context: &str) -> Self {
+        self.context = Some(context.to_string());
+        self
+    }
+
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+
+    pub fn with_signature(mut self, signature: impl Into<String>) -> Self {
+        self.signature = Some(signature.into());
+        self
+    }
+
+    pub fn with_field(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.fields.insert(name.to_string(), Value::String(value.into()));
+        self
+    }
+
+    pub fn parse(raw: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(raw)
+    }
+
+    pub fn to_json_string(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Returns a string-valued top-level field.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).and_then(Value::as_str)
+    }
+
+    /// Serializes the envelope with a `padding` field sized so the output length is a
+    /// multiple of `bucket` bytes. Receivers ignore the field; it stops the size of a
+    /// reply from revealing which answer it carries.
+    pub fn to_padded_json_string(&self, bucket: usize) -> serde_json::Result<String> {
+        // `,"padding":""` adds 13 bytes on top of the padding itself.
+        const OVERHEAD: usize = 13;
+        // Padding already present (e.g. on a relayed envelope) is replaced, not counted.
+        let mut envelope = self.clone();
+        envelope.fields.remove("padding");
+        let base = envelope.to_json_string()?.len() + OVERHEAD;
+        let target = base.div_ceil(bucket) * bucket;
+        envelope
+            .with_field("padding", " ".repeat(target - base))
+            .to_json_string()
+    }
+}
+
+/// Usernames may contain letters, digits, `-` and `_`. Servers register
+/// only these, and clients take no other from an invite.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty() && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Content of a successful `queryResponse`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRecord {
+    pub username: String,
+    pub public_key: String,
+    /// Addresses `username` in a `send` without naming them, see `ChatPayload::recipient_token`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_token: Option<String>,
+}
+
+/// AES-GCM output nested inside `Encrypted`, all fields hex encoded.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all, set_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedBody {
+    pub iv: String,
+    pub ciphertext: String,
+    pub tag: String,
+}
+
+/// ECDH + AES-GCM payload as produced by `CryptoUtils.encrypt_message`.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all, set_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Encrypted {
+    pub ephemeral_public_key: String,
+    pub salt: String,
+    pub encrypted_body: EncryptedBody,
+}
+
+/// Sender-signed body of a chat message.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all, set_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageBody {
+    pub encrypted_payload: Encrypted,
+    pub payload_signature: String,
+}
+
+/// Content of a `send` / `incomingMessage` envelope.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all, set_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatPayload {
+    pub sender: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Opaque stand-in for `recipient`, issued by the server in `queryResponse`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_token: Option<String>,
+    pub body: MessageBody,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_public_key: Option<String>,
+}
+
+/// Content of a `sealedSend`, and of the `incomingMessage` it becomes. `sealed`
+/// is a `ChatPayload` encrypted to the recipient, so the sender is known only
+/// to them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_token: Option<String>,
+    pub sealed: Encrypted,
+}
+
+/// Content of a `sendGroup`, and of the `incomingMessage` each recipient gets
+/// (without the recipient lists). The group message is encrypted once under
+/// the sender's sender key and signed separately in `group_signature`, so
+/// every member can check it came from `sender`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupPayload {
+    pub sender: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    /// Opaque stand-ins for `recipients`, issued by the server in `queryResponse`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipient_tokens: Vec<String>,
+    pub group: GroupMessage,
+    pub group_signature: String,
+}
+
+/// Serializes `value` exactly like Python's `json.dumps` with default arguments
+/// (`", "` / `": "` separators, non-ASCII escaped). Signatures made by the Python
+/// client cover strings produced this way, so both sides must agree byte for byte.
+pub fn to_python_json<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    let mut out = Vec::new();
+    let mut ser = serde_json::Serializer::with_formatter(&mut out, PythonFormatter);
+    value.serialize(&mut ser)?;
+    Ok(String::from_utf8(out).expect("serde_json emits UTF-8"))
+}
+
+struct PythonFormatter;
+
+impl Formatter for PythonFormatter {
+    fn begin_array_value<W: ?Sized + io::Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(&mut self, writer: &mut W, first: bool) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
+
+    fn write_string_fragment<W: ?Sized + io::Write>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()> {
+        for c in fragment.chars() {
+            if c.is_ascii() {
+                writer.write_all(&[c as u8])?;
+            } else {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
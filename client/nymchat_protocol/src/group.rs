@@ -0,0 +1,263 @@
+//! Sender keys: group messages encrypted once for every member.
+//!
+//! Each member of a group has a sender key, a chain key that moves forward
+//! with every message it sends (HMAC-SHA256, as in Signal's sender keys). Each
+//! step yields a message key that encrypts exactly one message. A member
+//! hands its sender key to every other member once, pairwise over the normal
+//! end-to-end channel, as a [`SenderKeyDistribution`]; after that one
+//! [`GroupMessage`] reaches the whole group. Senders also sign their group
+//! messages, so members holding a sender key still cannot forge messages from
+//! its owner.
+//!
+//! [`GroupSession`] is one member's state for one group. It moves to a new
+//! sender key whenever the membership changes, so removed members cannot read
+//! what follows and new members cannot read what came before.
+
+use crate::crypto::{decode_hex, Crypto, CryptoError, Result};
+use crate::envelope::EncryptedBody;
+use hmac::{Hmac, Mac};
+use p256::PublicKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fmt;
+
+/// How far ahead of the last message from a sender a message may be; keys
+/// for the messages in between are kept until they arrive.
+pub const MAX_SKIP: u32 = 1000;
+
+pub(crate) const MESSAGE_KEY_LABEL: u8 = 1;
+pub(crate) const CHAIN_KEY_LABEL: u8 = 2;
+
+/// A member's sender key, sent pairwise to the other members.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderKeyDistribution {
+    pub group_id: String,
+    pub key_id: u32,
+    /// The iteration `chain_key` is at; earlier messages stay unreadable.
+    pub iteration: u32,
+    pub chain_key: String,
+}
+
+/// A message encrypted once for the whole group, under the message key for
+/// `iteration` of the sender key `key_id`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessage {
+    pub group_id: String,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub body: EncryptedBody,
+}
+
+pub(crate) fn derive(chain_key: &[u8; 32], label: u8) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(chain_key).expect("HMAC takes keys of any length");
+    mac.update(&[label]);
+    mac.finalize().into_bytes().into()
+}
+
+pub(crate) fn decode_key(value: &str, field: &'static str) -> Result<[u8; 32]> {
+    let bytes = decode_hex(value, field)?;
+    bytes.as_slice().try_into().map_err(|_| CryptoError::InvalidLength(field))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Chain {
+    key_id: u32,
+    iteration: u32,
+    chain_key: String,
+    /// Message keys of skipped iterations, by iteration.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    skipped: BTreeMap<u32, String>,
+}
+
+impl Chain {
+    fn random() -> Self {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        Chain { key_id: OsRng.next_u32(), iteration: 0, chain_key: hex::encode(chain_key), skipped: BTreeMap::new() }
+    }
+
+    /// The message key for the current iteration, moving the chain past it.
+    fn step(&mut self) -> Result<[u8; 32]> {
+        let chain_key = decode_key(&self.chain_key, "chainKey")?;
+        self.iteration = self.iteration.checked_add(1).ok_or(CryptoError::MessageKey)?;
+        self.chain_key = hex::encode(derive(&chain_key, CHAIN_KEY_LABEL));
+        Ok(derive(&chain_key, MESSAGE_KEY_LABEL))
+    }
+
+    /// The message key for `iteration`. Each key is handed out only once.
+    fn message_key(&mut self, iteration: u32) -> Result<[u8; 32]> {
+        if iteration < self.iteration {
+            let key = self.skipped.remove(&iteration).ok_or(CryptoError::MessageKey)?;
+            return decode_key(&key, "messageKey");
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err(CryptoError::MessageKey);
+        }
+        while self.iteration < iteration {
+            let skipped = self.iteration;
+            let key = self.step()?;
+            self.skipped.insert(skipped, hex::encode(key));
+        }
+        while self.skipped.len() > MAX_SKIP as usize {
+            let oldest = *self.skipped.keys().next().expect("not empty");
+            self.skipped.remove(&oldest);
+        }
+        self.step()
+    }
+}
+
+/// One member's sender keys for one group: its own, and those it has
+/// received from the other members.
+#[cfg_attr(feature = "python", pyo3::pyclass)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSession {
+    group_id: String,
+    members: BTreeSet<String>,
+    own: Chain,
+    #[serde(default)]
+    received: BTreeMap<String, Chain>,
+}
+
+impl GroupSession {
+    /// A session for `group_id` with a fresh sender key.
+    pub fn new<I, S>(group_id: &str, members: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        GroupSession {
+            group_id: group_id.to_string(),
+            members: members.into_iter().map(Into::into).collect(),
+            own: Chain::random(),
+            received: BTreeMap::new(),
+        }
+    }
+
+    /// A session saved with [`GroupSession::to_json`].
+    pub fn from_json(raw: &str) -> Result<Self> {
+        serde_json::from_str(raw).map_err(|e| CryptoError::InvalidJson(e.to_string()))
+    }
+
+    /// The whole session, secret keys included, for local storage only.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("sessions serialize")
+    }
+
+    pub fn group_id(&self) -> &str {
+        &self.group_id
+    }
+
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    /// Replaces the member list. On any change this member moves to a new
+    /// sender key, which must then be distributed again, and forgets the keys
+    /// of removed members. Returns whether the list changed.
+    pub fn set_members<I, S>(&mut self, members: I) -> bool
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let members: BTreeSet<String> = members.into_iter().map(Into::into).collect();
+        if members == self.members {
+            return false;
+        }
+        self.received.retain(|sender, _| members.contains(sender));
+        self.members = members;
+        self.own = Chain::random();
+        true
+    }
+
+    /// This member's current sender key, to send to every other member.
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            group_id: self.group_id.clone(),
+            key_id: self.own.key_id,
+            iteration: self.own.iteration,
+            chain_key: self.own.chain_key.clone(),
+        }
+    }
+
+    /// Stores the sender key of `sender`. A key already held is kept as it
+    /// is, so a repeated distribution cannot rewind it. Returns whether the
+    /// key was new.
+    pub fn process_distribution(&mut self, sender: &str, distribution: &SenderKeyDistribution) -> Result<bool> {
+        if distribution.group_id != self.group_id {
+            return Err(CryptoError::InvalidKey("sender key for another group".to_string()));
+        }
+        if !self.members.contains(sender) {
+            return Err(CryptoError::NotAMember(sender.to_string()));
+        }
+        decode_key(&distribution.chain_key, "chainKey")?;
+        if self.received.get(sender).is_some_and(|chain| chain.key_id == distribution.key_id) {
+            return Ok(false);
+        }
+        let chain = Chain {
+            key_id: distribution.key_id,
+            iteration: distribution.iteration,
+            chain_key: distribution.chain_key.clone(),
+            skipped: BTreeMap::new(),
+        };
+        self.received.insert(sender.to_string(), chain);
+        Ok(true)
+    }
+
+    /// Encrypts `plaintext` for the group under the next message key. The
+    /// caller signs the message (as `to_python_json`) before sending it.
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<GroupMessage> {
+        let mut own = self.own.clone();
+        let iteration = own.iteration;
+        let key = own.step()?;
+        let body = Crypto::encrypt_with_key(&key, self.group_id.as_bytes(), plaintext)?;
+        self.own = own;
+        Ok(GroupMessage { group_id: self.group_id.clone(), key_id: self.own.key_id, iteration, body })
+    }
+
+    /// Decrypts a group message from `sender`, given as the JSON it was signed
+    /// as. The signature is checked before any key is used, so another member
+    /// cannot spend `sender`'s message keys with a forgery.
+    pub fn decrypt(
+        &mut self,
+        sender: &str,
+        sender_key: &PublicKey,
+        signed_message: &str,
+        signature_hex: &str,
+    ) -> Result<String> {
+        if !Crypto::verify(sender_key, signed_message, signature_hex) {
+            return Err(CryptoError::Signature);
+        }
+        let message: GroupMessage =
+            serde_json::from_str(signed_message).map_err(|e| CryptoError::InvalidJson(e.to_string()))?;
+        if message.group_id != self.group_id {
+            return Err(CryptoError::InvalidKey("message for another group".to_string()));
+        }
+        if !self.members.contains(sender) {
+            return Err(CryptoError::NotAMember(sender.to_string()));
+        }
+        let mut chain = match self.received.get(sender) {
+            Some(chain) if chain.key_id == message.key_id => chain.clone(),
+            _ => return Err(CryptoError::UnknownSenderKey(sender.to_string())),
+        };
+        let key = chain.message_key(message.iteration)?;
+        let plaintext = Crypto::decrypt_with_key(&key, self.group_id.as_bytes(), &message.body)?;
+        self.received.insert(sender.to_string(), chain);
+        Ok(plaintext)
+    }
+}
+
+impl fmt::Debug for GroupSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupSession")
+            .field("group_id", &self.group_id)
+            .field("members", &self.members)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,145 @@
+//! Handshakes: what two clients agree to use with each other, bound to what
+//! each of them offered.
+//!
+//! A handshake is three direct messages, each encrypted and signed end to end
+//! like any other: the initiator's [`Hello`], the responder's `Hello` with the
+//! [`transcript`] hash of both, and the initiator's confirmation repeating that
+//! hash. Each side hashes the hellos exactly as it sent and received them, so
+//! a hello changed on the way (a version or a feature struck out) makes the
+//! hashes differ and the handshake is dropped. What is agreed follows from the
+//! two hellos alone ([`negotiate`]): the highest version and every feature both
+//! list.
+//!
+//! A hello may also carry a fresh ratchet key, from which the two sides set
+//! up a Double Ratchet session once the handshake completes (see
+//! [`ratchet`](crate::ratchet)).
+//!
+//! Version 1 is the handshake of older clients, a bare nym address with
+//! nothing negotiated.
+
+use crate::invite::is_valid_nym_address;
+use crate::ratchet::{decode_ratchet_key, encode_ratchet_key};
+use p256::PublicKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Handshake versions this crate speaks.
+pub const HANDSHAKE_VERSIONS: [u32; 1] = [2];
+const NONCE_BYTES: usize = 16;
+/// Most features a hello may list.
+const MAX_FEATURES: usize = 32;
+const MAX_FEATURE_LEN: usize = 32;
+const TRANSCRIPT_DOMAIN: &[u8] = b"nymchat handshake transcript";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("invalid handshake hello: {0}")]
+    Malformed(String),
+    #[error("no handshake version in common")]
+    NoCommonVersion,
+}
+
+pub type Result<T> = std::result::Result<T, HandshakeError>;
+
+/// What one side offers.
+#[cfg_attr(feature = "python", pyo3::pyclass(name = "HandshakeHello", get_all))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub versions: Vec<u32>,
+    pub features: Vec<String>,
+    /// Where to send to this side directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nym_address: Option<String>,
+    /// The public half of a ratchet key made for this handshake alone, see
+    /// [`RatchetSession`](crate::RatchetSession).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratchet_key: Option<String>,
+    /// Random for every handshake, so no two transcripts are the same.
+    pub nonce: String,
+}
+
+/// What both sides agreed on.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiated {
+    pub version: u32,
+    /// Sorted.
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    /// A hello offering [`HANDSHAKE_VERSIONS`] and `features`.
+    pub fn new(features: Vec<String>, nym_address: Option<String>) -> Result<Self> {
+        let mut nonce = [0u8; NONCE_BYTES];
+        OsRng.fill_bytes(&mut nonce);
+        let hello = Hello {
+            versions: HANDSHAKE_VERSIONS.to_vec(),
+            features,
+            nym_address,
+            ratchet_key: None,
+            nonce: hex::encode(nonce),
+        };
+        hello.check()?;
+        Ok(hello)
+    }
+
+    /// The hello offering `key` to start a Double Ratchet session from.
+    pub fn with_ratchet_key(mut self, key: &PublicKey) -> Self {
+        self.ratchet_key = Some(encode_ratchet_key(key));
+        self
+    }
+
+    fn check(&self) -> Result<()> {
+        let malformed = |what: &str| Err(HandshakeError::Malformed(what.to_string()));
+        if self.versions.is_empty() {
+            return malformed("no versions");
+        }
+        if self.features.len() > MAX_FEATURES
+            || self.features.iter().any(|f| f.is_empty() || f.len() > MAX_FEATURE_LEN || f.contains(','))
+        {
+            return malformed("bad feature list");
+        }
+        if self.nym_address.as_deref().is_some_and(|address| !is_valid_nym_address(address)) {
+            return malformed("bad nym address");
+        }
+        if self.ratchet_key.as_deref().is_some_and(|key| decode_ratchet_key(key).is_err()) {
+            return malformed("bad ratchet key");
+        }
+        if self.nonce.len() != NONCE_BYTES * 2 || hex::decode(&self.nonce).is_err() {
+            return malformed("bad nonce");
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("hello serializes")
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let hello: Hello = serde_json::from_str(json).map_err(|e| HandshakeError::Malformed(e.to_string()))?;
+        hello.check()?;
+        Ok(hello)
+    }
+}
+
+/// The highest version both hellos list, and the features both list.
+pub fn negotiate(a: &Hello, b: &Hello) -> Result<Negotiated> {
+    let version = a.versions.iter().filter(|v| b.versions.contains(v)).max().ok_or(HandshakeError::NoCommonVersion)?;
+    let mut features: Vec<String> = a.features.iter().filter(|f| b.features.contains(f)).cloned().collect();
+    features.sort();
+    features.dedup();
+    Ok(Negotiated { version: *version, features })
+}
+
+/// SHA-256 over both hellos as sent, initiator first, in hex.
+pub fn transcript(initiator_hello: &str, responder_hello: &str) -> String {
+    let mut hash = Sha256::new();
+    hash.update(TRANSCRIPT_DOMAIN);
+    for hello in [initiator_hello, responder_hello] {
+        hash.update((hello.len() as u64).to_be_bytes());
+        hash.update(hello.as_bytes());
+    }
+    hex::encode(hash.finalize())
+}
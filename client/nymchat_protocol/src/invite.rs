@@ -0,0 +1,159 @@
+//! Contact invites: what adding someone takes, without the server directory.
+//!
+//! An invite names a user and carries their public key, compressed, with the
+//! start of its fingerprint to catch links that were mistyped or cut short,
+//! and optionally the nym address they receive at, so messages can go to
+//! them directly from the start. It is shared as a URI:
+//!
+//! ```text
+//! nymchat://invite/<username>/<key>/<check>[/<nym address>]
+//! ```
+//!
+//! `key` is the hex SEC1 compressed point and `check` the first
+//! [`FINGERPRINT_CHECK_BYTES`] bytes of the key's fingerprint, in hex. The
+//! invite is only as trustworthy as the way it was passed on; anyone who can
+//! change it in transit can put their own key and a matching check in it.
+
+use crate::crypto::Crypto;
+use crate::envelope::is_valid_username;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::EncodePublicKey;
+use p256::PublicKey;
+use sha2::{Digest, Sha256};
+
+pub const INVITE_URI_PREFIX: &str = "nymchat://invite/";
+/// Bytes of the fingerprint an invite repeats.
+pub const FINGERPRINT_CHECK_BYTES: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InviteError {
+    #[error("not a nymchat invite")]
+    NotAnInvite,
+    #[error("invalid username {0:?}")]
+    Username(String),
+    #[error("invalid public key")]
+    Key,
+    #[error("the public key does not match the invite's fingerprint")]
+    Fingerprint,
+    #[error("invalid nym address {0:?}")]
+    NymAddress(String),
+}
+
+pub type Result<T> = std::result::Result<T, InviteError>;
+
+/// Only made by [`ContactInvite::new`] and [`ContactInvite::parse`], so the
+/// key is always one [`ContactInvite::to_uri`] can write.
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactInvite {
+    username: String,
+    /// SubjectPublicKeyInfo PEM, as contacts' keys are stored.
+    public_key: String,
+    /// See [`fingerprint`].
+    fingerprint: String,
+    nym_address: Option<String>,
+}
+
+/// SHA-256 of the key's SubjectPublicKeyInfo DER, as 16 groups of 4 hex
+/// digits; the same as `CryptoUtils.fingerprint` in the Python client.
+pub fn fingerprint(key: &PublicKey) -> String {
+    let der = key.to_public_key_der().expect("P-256 keys encode");
+    let hexed = hex::encode(Sha256::digest(der.as_bytes()));
+    hexed.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap()).collect::<Vec<_>>().join(" ")
+}
+
+fn check(fingerprint: &str) -> String {
+    fingerprint.replace(' ', "")[..FINGERPRINT_CHECK_BYTES * 2].to_string()
+}
+
+/// `<identity>.<encryption>@<gateway>`, each part base58.
+pub fn is_valid_nym_address(address: &str) -> bool {
+    let base58 = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() && !"0OIl".contains(c))
+    };
+    match address.split_once('@') {
+        Some((client, gateway)) => match client.split_once('.') {
+            Some((identity, encryption)) => base58(identity) && base58(encryption) && base58(gateway),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+impl ContactInvite {
+    /// An invite to add `username` with the key in `public_key_pem`.
+    pub fn new(username: &str, public_key_pem: &str, nym_address: Option<&str>) -> Result<Self> {
+        let key = Crypto::public_key_from_pem(public_key_pem).map_err(|_| InviteError::Key)?;
+        Self::from_key(username, &key, nym_address)
+    }
+
+    fn from_key(username: &str, key: &PublicKey, nym_address: Option<&str>) -> Result<Self> {
+        if !is_valid_username(username) {
+            return Err(InviteError::Username(username.to_string()));
+        }
+        if let Some(address) = nym_address.filter(|address| !is_valid_nym_address(address)) {
+            return Err(InviteError::NymAddress(address.to_string()));
+        }
+        Ok(ContactInvite {
+            username: username.to_string(),
+            public_key: Crypto::public_key_pem(key).map_err(|_| InviteError::Key)?,
+            fingerprint: fingerprint(key),
+            nym_address: nym_address.map(str::to_string),
+        })
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// SubjectPublicKeyInfo PEM, as contacts' keys are stored.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// See [`fingerprint`].
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn nym_address(&self) -> Option<&str> {
+        self.nym_address.as_deref()
+    }
+
+    pub fn to_uri(&self) -> String {
+        let key = Crypto::public_key_from_pem(&self.public_key).expect("checked when the invite was made");
+        let mut uri = format!(
+            "{}{}/{}/{}",
+            INVITE_URI_PREFIX,
+            self.username,
+            hex::encode(key.to_encoded_point(true).as_bytes()),
+            check(&self.fingerprint)
+        );
+        if let Some(address) = &self.nym_address {
+            uri.push('/');
+            uri.push_str(address);
+        }
+        uri
+    }
+
+    /// Reads an invite URI, checking every part. Surrounding whitespace, as
+    /// left by copying, is ignored.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let rest = uri.trim().strip_prefix(INVITE_URI_PREFIX).ok_or(InviteError::NotAnInvite)?;
+        let parts: Vec<&str> = rest.trim_end_matches('/').split('/').collect();
+        let (username, key, expected, address) = match parts[..] {
+            [username, key, check] => (username, key, check, None),
+            [username, key, check, address] => (username, key, check, Some(address)),
+            _ => return Err(InviteError::NotAnInvite),
+        };
+        let key = hex::decode(key)
+            .ok()
+            .and_then(|bytes| PublicKey::from_sec1_bytes(&bytes).ok())
+            .ok_or(InviteError::Key)?;
+        let invite = Self::from_key(username, &key, address)?;
+        if check(&invite.fingerprint) != expected.to_ascii_lowercase() {
+            return Err(InviteError::Fingerprint);
+        }
+        Ok(invite)
+    }
+}
@@ -0,0 +1,33 @@
+//! Wire format and end-to-end encryption shared by nymCHAT clients and servers.
+//!
+//! Everything here is pure Rust (RustCrypto primitives, no OpenSSL or native
+//! transport), so the crate also builds for `wasm32-unknown-unknown` and a
+//! browser client can reuse the exact message format and crypto.
+
+pub mod bloom;
+pub mod crypto;
+pub mod delegation;
+pub mod envelope;
+pub mod group;
+pub mod handshake;
+pub mod invite;
+pub mod membership;
+pub mod network;
+pub mod pow;
+pub mod ratchet;
+#[cfg(feature = "python")]
+mod python;
+
+pub use bloom::BloomFilter;
+pub use crypto::{ct_eq, Crypto, CryptoError, Unverified};
+pub use delegation::{Delegation, DelegationError};
+pub use envelope::{
+    is_valid_username, to_python_json, ChatPayload, Encrypted, EncryptedBody, Envelope, GroupPayload, MessageBody,
+    SealedPayload, UserRecord,
+};
+pub use group::{GroupMessage, GroupSession, SenderKeyDistribution};
+pub use handshake::{HandshakeError, Hello, Negotiated};
+pub use invite::{ContactInvite, InviteError};
+pub use membership::{MembershipEntry, MembershipError, MembershipLog, MembershipOp};
+pub use network::{NetworkConfig, NetworkError, NymNetwork};
+pub use ratchet::{RatchetHeader, RatchetMessage, RatchetSession};
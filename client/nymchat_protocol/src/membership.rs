@@ -0,0 +1,228 @@
+//! Signed group membership.
+//!
+//! Who is in a group, and who may change that, follows from a log of control
+//! entries that every member checks for itself. Each entry is signed by the
+//! member who made it and names the hash of the entry before it. The group's
+//! creator is its first admin; admins invite, kick and promote members, and
+//! set how much history new members may be sent. A
+//! server relaying the entries, or a member who is not an admin, therefore
+//! cannot change the membership: a forged entry fails its signature or the
+//! permission check, and a second, different entry for a place in the log
+//! that is already taken is rejected as a fork.
+//!
+//! [`MembershipLog`] is the state the entries lead to. Entries are applied
+//! as they were signed, so both sides must keep the exact JSON string.
+
+use crate::crypto::{Crypto, CryptoError};
+use crate::envelope::to_python_json;
+use p256::PublicKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MembershipError {
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error("invalid entry: {0}")]
+    Invalid(String),
+    #[error("entry {0} is out of order")]
+    OutOfOrder(u64),
+    #[error("fork at entry {0}: a different entry was applied there")]
+    Fork(u64),
+    #[error("`{0}` may not {1}")]
+    NotAllowed(String, &'static str),
+}
+
+pub type Result<T> = std::result::Result<T, MembershipError>;
+
+/// What an entry does; `Create` only ever comes first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum MembershipOp {
+    Create { name: String, members: Vec<String> },
+    Invite { member: String },
+    Kick { member: String },
+    Promote { member: String },
+    /// How many recent messages a new member may be sent; 0 sends none.
+    History { limit: u32 },
+}
+
+/// One control entry, signed by `actor`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MembershipEntry {
+    pub group_id: String,
+    pub seq: u64,
+    /// Hex SHA-256 of the previous entry as signed; empty for the first.
+    pub prev: String,
+    pub actor: String,
+    #[serde(flatten)]
+    pub op: MembershipOp,
+}
+
+/// The membership of one group, as its log has established it so far.
+#[cfg_attr(feature = "python", pyo3::pyclass)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MembershipLog {
+    group_id: String,
+    name: String,
+    members: BTreeSet<String>,
+    admins: BTreeSet<String>,
+    #[serde(default)]
+    history_limit: u32,
+    /// Hash of each applied entry, by sequence number.
+    hashes: Vec<String>,
+}
+
+fn entry_hash(signed_entry: &str) -> String {
+    hex::encode(Sha256::digest(signed_entry.as_bytes()))
+}
+
+impl MembershipLog {
+    /// The state before any entry of `group_id`.
+    pub fn new(group_id: &str) -> Self {
+        MembershipLog {
+            group_id: group_id.to_string(),
+            name: String::new(),
+            members: BTreeSet::new(),
+            admins: BTreeSet::new(),
+            history_limit: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// A log saved with [`MembershipLog::to_json`].
+    pub fn from_json(raw: &str) -> Result<Self> {
+        serde_json::from_str(raw).map_err(|e| MembershipError::Invalid(e.to_string()))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("membership serializes")
+    }
+
+    pub fn group_id(&self) -> &str {
+        &self.group_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    pub fn admins(&self) -> &BTreeSet<String> {
+        &self.admins
+    }
+
+    /// How many recent messages a new member may be sent.
+    pub fn history_limit(&self) -> u32 {
+        self.history_limit
+    }
+
+    /// Entries applied so far, which is also the sequence number of the next.
+    pub fn len(&self) -> u64 {
+        self.hashes.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// The next entry for `actor` to sign, as JSON, if they may make it.
+    pub fn propose(&self, actor: &str, op: MembershipOp) -> Result<String> {
+        let entry = MembershipEntry {
+            group_id: self.group_id.clone(),
+            seq: self.len(),
+            prev: self.hashes.last().cloned().unwrap_or_default(),
+            actor: actor.to_string(),
+            op,
+        };
+        self.check(&entry)?;
+        to_python_json(&entry).map_err(|e| MembershipError::Invalid(e.to_string()))
+    }
+
+    /// Applies `signed_entry` if `signature` is its actor's, made with
+    /// `actor_key`, and the actor was allowed to make it. An entry already
+    /// applied is skipped. Returns whether the membership changed.
+    pub fn apply(&mut self, signed_entry: &str, signature_hex: &str, actor_key: &PublicKey) -> Result<bool> {
+        if !Crypto::verify(actor_key, signed_entry, signature_hex) {
+            return Err(CryptoError::Signature.into());
+        }
+        let entry: MembershipEntry =
+            serde_json::from_str(signed_entry).map_err(|e| MembershipError::Invalid(e.to_string()))?;
+        if entry.group_id != self.group_id {
+            return Err(MembershipError::Invalid("entry for another group".to_string()));
+        }
+        let hash = entry_hash(signed_entry);
+        if let Some(applied) = self.hashes.get(entry.seq as usize) {
+            if *applied == hash {
+                return Ok(false);
+            }
+            return Err(MembershipError::Fork(entry.seq));
+        }
+        self.check(&entry)?;
+
+        match entry.op {
+            MembershipOp::Create { name, members } => {
+                self.name = name;
+                self.members = members.into_iter().collect();
+                self.admins = std::iter::once(entry.actor).collect();
+            }
+            MembershipOp::Invite { member } => {
+                self.members.insert(member);
+            }
+            MembershipOp::Kick { member } => {
+                self.members.remove(&member);
+                self.admins.remove(&member);
+            }
+            MembershipOp::Promote { member } => {
+                self.admins.insert(member);
+            }
+            MembershipOp::History { limit } => {
+                self.history_limit = limit;
+            }
+        }
+        self.hashes.push(hash);
+        Ok(true)
+    }
+
+    /// Whether `entry` may come next.
+    fn check(&self, entry: &MembershipEntry) -> Result<()> {
+        if entry.group_id != self.group_id {
+            return Err(MembershipError::Invalid("entry for another group".to_string()));
+        }
+        if entry.seq != self.len() {
+            return Err(MembershipError::OutOfOrder(entry.seq));
+        }
+        if entry.prev != self.hashes.last().map(String::as_str).unwrap_or_default() {
+            return Err(MembershipError::Invalid(format!("entry {} does not follow the log", entry.seq)));
+        }
+        let not_allowed = |what| Err(MembershipError::NotAllowed(entry.actor.clone(), what));
+        if let MembershipOp::Create { name, members } = &entry.op {
+            if !self.is_empty() {
+                return not_allowed("create the group again");
+            }
+            if name.is_empty() || !members.contains(&entry.actor) {
+                return Err(MembershipError::Invalid("a group needs a name and its creator".to_string()));
+            }
+            return Ok(());
+        }
+        if !self.admins.contains(&entry.actor) {
+            return not_allowed("change the members");
+        }
+        match &entry.op {
+            MembershipOp::Invite { member } if self.members.contains(member) => not_allowed("invite a member"),
+            MembershipOp::Kick { member } if member == &entry.actor || !self.members.contains(member) => {
+                not_allowed("kick themselves or a non-member")
+            }
+            MembershipOp::Promote { member } if !self.members.contains(member) || self.admins.contains(member) => {
+                not_allowed("promote a non-member or an admin")
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Which Nym network clients and servers connect to.
+//!
+//! `NYM_NETWORK` picks `mainnet` (the default), `sandbox` or `custom`. Networks
+//! other than mainnet are described by a network env file in the format the
+//! Nym repository ships (`NETWORK_NAME`, `NYM_API`, `NYXD`, contract addresses,
+//! ...), read from `NYM_NETWORK_ENV_FILE` or, for the sandbox, `envs/sandbox.env`.
+//! `NYM_API_URL` and `NYXD_URL` replace the endpoints the file names, e.g. to
+//! use a local nym-api. Nothing here talks to the network: the crates that
+//! connect export the settings with [`NetworkConfig::export_to_env`] and build
+//! the nym-sdk network details from the environment.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Network env file used for the sandbox when `NYM_NETWORK_ENV_FILE` is unset.
+pub const SANDBOX_ENV_FILE: &str = "envs/sandbox.env";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NymNetwork {
+    #[default]
+    Mainnet,
+    Sandbox,
+    Custom,
+}
+
+impl FromStr for NymNetwork {
+    type Err = NetworkError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "mainnet" => Ok(NymNetwork::Mainnet),
+            "sandbox" => Ok(NymNetwork::Sandbox),
+            "custom" => Ok(NymNetwork::Custom),
+            _ => Err(NetworkError::Unknown(value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for NymNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NymNetwork::Mainnet => "mainnet",
+            NymNetwork::Sandbox => "sandbox",
+            NymNetwork::Custom => "custom",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NetworkError {
+    #[error("NYM_NETWORK must be mainnet, sandbox or custom, not {0:?}")]
+    Unknown(String),
+    #[error("NYM_NETWORK=custom needs NYM_NETWORK_ENV_FILE")]
+    MissingEnvFile,
+    #[error("{0} only applies to NYM_NETWORK=sandbox or custom")]
+    MainnetOverride(&'static str),
+    #[error("Cannot read network env file {0}: {1}")]
+    EnvFile(String, std::io::Error),
+}
+
+/// The network to connect to and where its details come from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkConfig {
+    pub network: NymNetwork,
+    /// Network env file; unused on mainnet.
+    pub env_file: Option<PathBuf>,
+    /// Replaces the file's `NYM_API`.
+    pub nym_api: Option<String>,
+    /// Replaces the file's `NYXD`.
+    pub nyxd: Option<String>,
+}
+
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+impl NetworkConfig {
+    /// Reads `NYM_NETWORK`, `NYM_NETWORK_ENV_FILE`, `NYM_API_URL` and `NYXD_URL`.
+    pub fn from_env() -> Result<Self, NetworkError> {
+        let network: NymNetwork = var("NYM_NETWORK").unwrap_or_default().parse()?;
+        let config = NetworkConfig {
+            network,
+            env_file: var("NYM_NETWORK_ENV_FILE").map(PathBuf::from),
+            nym_api: var("NYM_API_URL"),
+            nyxd: var("NYXD_URL"),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), NetworkError> {
+        match self.network {
+            NymNetwork::Mainnet if self.nym_api.is_some() => Err(NetworkError::MainnetOverride("NYM_API_URL")),
+            NymNetwork::Mainnet if self.nyxd.is_some() => Err(NetworkError::MainnetOverride("NYXD_URL")),
+            NymNetwork::Custom if self.env_file.is_none() => Err(NetworkError::MissingEnvFile),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_mainnet(&self) -> bool {
+        self.network == NymNetwork::Mainnet
+    }
+
+    /// The network env file this configuration reads, if any.
+    pub fn env_file(&self) -> Option<PathBuf> {
+        match self.network {
+            NymNetwork::Mainnet => None,
+            NymNetwork::Sandbox => Some(self.env_file.clone().unwrap_or_else(|| SANDBOX_ENV_FILE.into())),
+            NymNetwork::Custom => self.env_file.clone(),
+        }
+    }
+
+    /// The variables describing the network: those of the env file, with the
+    /// endpoint overrides applied. Empty for mainnet, whose details are built in.
+    pub fn variables(&self) -> Result<Vec<(String, String)>, NetworkError> {
+        self.validate()?;
+        let path = match self.env_file() {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+        let contents =
+            fs::read_to_string(&path).map_err(|e| NetworkError::EnvFile(path.display().to_string(), e))?;
+        let mut variables = parse_env_file(&contents);
+        let overrides = [("NYM_API", &self.nym_api), ("NYXD", &self.nyxd)];
+        for (name, value) in overrides {
+            if let Some(value) = value {
+                variables.retain(|(key, _)| key != name);
+                variables.push((name.to_string(), value.clone()));
+            }
+        }
+        Ok(variables)
+    }
+
+    /// Sets [`Self::variables`] in the process environment, where nym-sdk's
+    /// `NymNetworkDetails::new_from_env` picks them up.
+    pub fn export_to_env(&self) -> Result<(), NetworkError> {
+        for (key, value) in self.variables()? {
+            env::set_var(key, value);
+        }
+        Ok(())
+    }
+}
+
+/// `KEY=value` lines of a network env file. Blank lines, `#` comments and an
+/// `export ` prefix are skipped, and quotes around values are dropped.
+pub fn parse_env_file(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            (key.trim().to_string(), unquoted.to_string())
+        })
+        .collect()
+}
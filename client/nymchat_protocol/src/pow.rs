@@ -0,0 +1,40 @@
+//! Hashcash-style proof of work attached to registration challenges.
+//!
+//! A solution for `nonce` at `difficulty` is a counter such that
+//! `SHA-256("{nonce}:{counter}")` starts with at least `difficulty` zero bits.
+//! Solving takes about `2^difficulty` hashes; checking takes one.
+
+use sha2::{Digest, Sha256};
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            return bits + byte.leading_zeros();
+        }
+    }
+    bits
+}
+
+fn digest(nonce: &str, counter: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(nonce.as_bytes())
+        .chain_update(b":")
+        .chain_update(counter.to_string().as_bytes())
+        .finalize()
+        .into()
+}
+
+/// Returns `true` if `counter` solves the puzzle for `nonce`.
+pub fn verify(nonce: &str, difficulty: u32, counter: u64) -> bool {
+    difficulty == 0 || leading_zero_bits(&digest(nonce, counter)) >= difficulty
+}
+
+/// Finds the smallest counter that solves the puzzle for `nonce`.
+pub fn solve(nonce: &str, difficulty: u32) -> u64 {
+    (0..u64::MAX)
+        .find(|counter| verify(nonce, difficulty, *counter))
+        .expect("a solution exists below u64::MAX")
+}
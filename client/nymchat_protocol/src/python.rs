@@ -0,0 +1,489 @@
+use crate::crypto::{Crypto, CryptoError};
+use crate::delegation::{Delegation, DelegationError};
+use crate::envelope::{to_python_json, ChatPayload, Encrypted, Envelope};
+use crate::group::{GroupSession, SenderKeyDistribution};
+use crate::handshake::{negotiate, transcript, HandshakeError, Hello, Negotiated};
+use crate::invite::{ContactInvite, InviteError};
+use crate::membership::{MembershipError, MembershipLog, MembershipOp};
+use crate::ratchet::{decode_ratchet_key, ratchet_key_pair, RatchetMessage, RatchetSession};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use serde_json::Value;
+
+fn invalid_json(what: &str, e: serde_json::Error) -> PyErr {
+    PyValueError::new_err(format!("Invalid {} JSON: {}", what, e))
+}
+
+fn crypto_error(e: CryptoError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn membership_error(e: MembershipError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn invite_error(e: InviteError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn handshake_error(e: HandshakeError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn delegation_error(e: DelegationError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+#[pymethods]
+impl Envelope {
+    #[getter]
+    fn action(&self) -> String {
+        self.action.clone()
+    }
+
+    #[setter]
+    fn set_action(&mut self, action: String) {
+        self.action = action;
+    }
+
+    #[getter]
+    fn context(&self) -> Option<String> {
+        self.context.clone()
+    }
+
+    #[setter]
+    fn set_context(&mut self, context: Option<String>) {
+        self.context = context;
+    }
+
+    #[getter]
+    fn content(&self) -> Option<String> {
+        self.content.clone()
+    }
+
+    #[setter]
+    fn set_content(&mut self, content: Option<String>) {
+        self.content = content;
+    }
+
+    #[getter]
+    fn signature(&self) -> Option<String> {
+        self.signature.clone()
+    }
+
+    #[setter]
+    fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    #[staticmethod]
+    fn from_json(raw: &str) -> PyResult<Self> {
+        Self::parse(raw).map_err(|e| invalid_json("envelope", e))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        self.to_json_string().map_err(|e| invalid_json("envelope", e))
+    }
+
+    /// Returns an extra top-level field; strings are returned as-is, anything else as JSON.
+    fn get_field(&self, name: &str) -> Option<String> {
+        self.fields.get(name).map(|v| match v {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+
+    fn set_field(&mut self, name: String, value: String) {
+        self.fields.insert(name, Value::String(value));
+    }
+
+    /// Parses `content` as a chat payload (`incomingMessage` / `send`).
+    fn chat_payload(&self) -> PyResult<ChatPayload> {
+        let content = self
+            .content
+            .as_deref()
+            .ok_or_else(|| PyValueError::new_err("Envelope has no content"))?;
+        ChatPayload::from_json(content)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Envelope(action={:?}, context={:?})", self.action, self.context)
+    }
+}
+
+#[pymethods]
+impl Encrypted {
+    #[staticmethod]
+    fn from_json(raw: &str) -> PyResult<Self> {
+        serde_json::from_str(raw).map_err(|e| invalid_json("encrypted payload", e))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(self).map_err(|e| invalid_json("encrypted payload", e))
+    }
+
+    /// Builds the dict `CryptoUtils.decrypt_message` expects. Key order matches the
+    /// original payload, so `json.dumps` of the result reproduces the signed string.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let body = PyDict::new(py);
+        body.set_item("iv", &self.encrypted_body.iv)?;
+        body.set_item("ciphertext", &self.encrypted_body.ciphertext)?;
+        body.set_item("tag", &self.encrypted_body.tag)?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("ephemeralPublicKey", &self.ephemeral_public_key)?;
+        dict.set_item("salt", &self.salt)?;
+        dict.set_item("encryptedBody", body)?;
+        Ok(dict)
+    }
+}
+
+#[pymethods]
+impl ChatPayload {
+    #[staticmethod]
+    fn from_json(raw: &str) -> PyResult<Self> {
+        serde_json::from_str(raw).map_err(|e| invalid_json("chat payload", e))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(self).map_err(|e| invalid_json("chat payload", e))
+    }
+}
+
+#[pymethods]
+impl GroupSession {
+    /// A new session, with a fresh sender key, for `members` (this user included).
+    #[staticmethod]
+    fn create(group_id: &str, members: Vec<String>) -> Self {
+        Self::new(group_id, members)
+    }
+
+    #[staticmethod]
+    #[pyo3(name = "from_json")]
+    fn py_from_json(raw: &str) -> PyResult<Self> {
+        Self::from_json(raw).map_err(crypto_error)
+    }
+
+    #[pyo3(name = "to_json")]
+    fn py_to_json(&self) -> String {
+        self.to_json()
+    }
+
+    #[getter(group_id)]
+    fn py_group_id(&self) -> String {
+        self.group_id().to_string()
+    }
+
+    #[pyo3(name = "members")]
+    fn py_members(&self) -> Vec<String> {
+        self.members().iter().cloned().collect()
+    }
+
+    #[pyo3(name = "set_members")]
+    fn py_set_members(&mut self, members: Vec<String>) -> bool {
+        self.set_members(members)
+    }
+
+    /// This member's sender key as JSON, to send pairwise to the others.
+    fn distribution_json(&self) -> PyResult<String> {
+        to_python_json(&self.distribution()).map_err(|e| invalid_json("sender key", e))
+    }
+
+    #[pyo3(name = "process_distribution")]
+    fn py_process_distribution(&mut self, sender: &str, raw: &str) -> PyResult<bool> {
+        let distribution: SenderKeyDistribution =
+            serde_json::from_str(raw).map_err(|e| invalid_json("sender key", e))?;
+        self.process_distribution(sender, &distribution).map_err(crypto_error)
+    }
+
+    /// The group message for `plaintext`, as the JSON string to sign and send.
+    #[pyo3(name = "encrypt")]
+    fn py_encrypt(&mut self, plaintext: &str) -> PyResult<String> {
+        let message = self.encrypt(plaintext).map_err(crypto_error)?;
+        to_python_json(&message).map_err(|e| invalid_json("group message", e))
+    }
+
+    #[pyo3(name = "decrypt")]
+    fn py_decrypt(
+        &mut self,
+        sender: &str,
+        sender_public_key_pem: &str,
+        signed_message: &str,
+        signature: &str,
+    ) -> PyResult<String> {
+        let key = Crypto::public_key_from_pem(sender_public_key_pem).map_err(crypto_error)?;
+        self.decrypt(sender, &key, signed_message, signature).map_err(crypto_error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+fn ratchet_secret_from_hex(secret_hex: &str) -> PyResult<p256::SecretKey> {
+    let bytes = hex::decode(secret_hex).map_err(|_| PyValueError::new_err("Invalid ratchet key hex"))?;
+    p256::SecretKey::from_slice(&bytes).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+#[pymethods]
+impl RatchetSession {
+    /// A fresh ratchet key for a hello, as `(secret, public)` hex; keep the
+    /// secret until the handshake completes.
+    #[staticmethod]
+    fn key_pair() -> (String, String) {
+        let (secret, public) = ratchet_key_pair();
+        (hex::encode(secret.to_bytes()), public)
+    }
+
+    /// The initiator's session, from our identity key, the secret ratchet key
+    /// of our hello, and the contact's identity key and hello ratchet key.
+    #[staticmethod]
+    #[pyo3(name = "initiate")]
+    fn py_initiate(
+        private_key_pem: &str,
+        ratchet_secret: &str,
+        their_public_key_pem: &str,
+        their_ratchet_key: &str,
+    ) -> PyResult<Self> {
+        let identity = Crypto::private_key_from_pem(private_key_pem).map_err(crypto_error)?;
+        let their_identity = Crypto::public_key_from_pem(their_public_key_pem).map_err(crypto_error)?;
+        let their_ratchet = decode_ratchet_key(their_ratchet_key).map_err(crypto_error)?;
+        Ok(Self::initiate(&identity, &ratchet_secret_from_hex(ratchet_secret)?, &their_identity, &their_ratchet))
+    }
+
+    /// The responder's session; arguments as for `initiate`.
+    #[staticmethod]
+    #[pyo3(name = "respond")]
+    fn py_respond(
+        private_key_pem: &str,
+        ratchet_secret: &str,
+        their_public_key_pem: &str,
+        their_ratchet_key: &str,
+    ) -> PyResult<Self> {
+        let identity = Crypto::private_key_from_pem(private_key_pem).map_err(crypto_error)?;
+        let their_identity = Crypto::public_key_from_pem(their_public_key_pem).map_err(crypto_error)?;
+        let their_ratchet = decode_ratchet_key(their_ratchet_key).map_err(crypto_error)?;
+        Ok(Self::respond(&identity, &ratchet_secret_from_hex(ratchet_secret)?, &their_identity, &their_ratchet))
+    }
+
+    #[staticmethod]
+    #[pyo3(name = "from_json")]
+    fn py_from_json(raw: &str) -> PyResult<Self> {
+        Self::from_json(raw).map_err(crypto_error)
+    }
+
+    #[pyo3(name = "to_json")]
+    fn py_to_json(&self) -> String {
+        self.to_json()
+    }
+
+    #[pyo3(name = "can_send")]
+    fn py_can_send(&self) -> bool {
+        self.can_send()
+    }
+
+    /// The ratchet message for `plaintext`, as JSON. Raises `ValueError`
+    /// while the session cannot send yet.
+    #[pyo3(name = "encrypt")]
+    fn py_encrypt(&mut self, plaintext: &str) -> PyResult<String> {
+        let message = self.encrypt(plaintext).map_err(crypto_error)?;
+        to_python_json(&message).map_err(|e| invalid_json("ratchet message", e))
+    }
+
+    #[pyo3(name = "decrypt")]
+    fn py_decrypt(&mut self, raw: &str) -> PyResult<String> {
+        let message: RatchetMessage = serde_json::from_str(raw).map_err(|e| invalid_json("ratchet message", e))?;
+        self.decrypt(&message).map_err(crypto_error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[pymethods]
+impl MembershipLog {
+    /// The state before the first entry of `group_id`.
+    #[staticmethod]
+    fn create(group_id: &str) -> Self {
+        Self::new(group_id)
+    }
+
+    #[staticmethod]
+    #[pyo3(name = "from_json")]
+    fn py_from_json(raw: &str) -> PyResult<Self> {
+        Self::from_json(raw).map_err(membership_error)
+    }
+
+    #[pyo3(name = "to_json")]
+    fn py_to_json(&self) -> String {
+        self.to_json()
+    }
+
+    #[getter(group_id)]
+    fn py_group_id(&self) -> String {
+        self.group_id().to_string()
+    }
+
+    #[getter(name)]
+    fn py_name(&self) -> String {
+        self.name().to_string()
+    }
+
+    #[pyo3(name = "members")]
+    fn py_members(&self) -> Vec<String> {
+        self.members().iter().cloned().collect()
+    }
+
+    #[pyo3(name = "admins")]
+    fn py_admins(&self) -> Vec<String> {
+        self.admins().iter().cloned().collect()
+    }
+
+    /// The signed-to-be entries that start a group, invite, kick or promote a
+    /// member, or set the history new members may be sent.
+    fn propose_create(&self, actor: &str, name: String, members: Vec<String>) -> PyResult<String> {
+        self.propose(actor, MembershipOp::Create { name, members }).map_err(membership_error)
+    }
+
+    fn propose_invite(&self, actor: &str, member: String) -> PyResult<String> {
+        self.propose(actor, MembershipOp::Invite { member }).map_err(membership_error)
+    }
+
+    fn propose_kick(&self, actor: &str, member: String) -> PyResult<String> {
+        self.propose(actor, MembershipOp::Kick { member }).map_err(membership_error)
+    }
+
+    fn propose_promote(&self, actor: &str, member: String) -> PyResult<String> {
+        self.propose(actor, MembershipOp::Promote { member }).map_err(membership_error)
+    }
+
+    fn propose_history(&self, actor: &str, limit: u32) -> PyResult<String> {
+        self.propose(actor, MembershipOp::History { limit }).map_err(membership_error)
+    }
+
+    #[getter(history_limit)]
+    fn py_history_limit(&self) -> u32 {
+        self.history_limit()
+    }
+
+    #[pyo3(name = "apply")]
+    fn py_apply(&mut self, signed_entry: &str, signature: &str, actor_public_key_pem: &str) -> PyResult<bool> {
+        let key = Crypto::public_key_from_pem(actor_public_key_pem).map_err(crypto_error)?;
+        self.apply(signed_entry, signature, &key).map_err(membership_error)
+    }
+
+    fn __len__(&self) -> usize {
+        self.len() as usize
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[pymethods]
+impl ContactInvite {
+    #[staticmethod]
+    #[pyo3(signature = (username, public_key_pem, nym_address = None))]
+    fn create(username: &str, public_key_pem: &str, nym_address: Option<&str>) -> PyResult<Self> {
+        Self::new(username, public_key_pem, nym_address).map_err(invite_error)
+    }
+
+    /// Raises `ValueError` saying what is wrong with an invalid invite.
+    #[staticmethod]
+    #[pyo3(name = "parse")]
+    fn py_parse(uri: &str) -> PyResult<Self> {
+        Self::parse(uri).map_err(invite_error)
+    }
+
+    #[pyo3(name = "to_uri")]
+    fn py_to_uri(&self) -> String {
+        self.to_uri()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[pymethods]
+impl Delegation {
+    #[staticmethod]
+    fn create(issuer: &str, public_key_pem: &str, scopes: Vec<String>, expires_at: u64) -> PyResult<Self> {
+        Self::new(issuer, public_key_pem, scopes, expires_at).map_err(delegation_error)
+    }
+
+    /// Raises `ValueError` if the signature does not check out, or the
+    /// delegation is invalid or expired at `now`.
+    #[staticmethod]
+    #[pyo3(name = "verify")]
+    fn py_verify(signed: &str, signature: &str, issuer_public_key_pem: &str, now: u64) -> PyResult<Self> {
+        let key = Crypto::public_key_from_pem(issuer_public_key_pem).map_err(crypto_error)?;
+        Self::verify(signed, signature, &key, now).map_err(delegation_error)
+    }
+
+    #[pyo3(name = "to_json")]
+    fn py_to_json(&self) -> String {
+        self.to_json()
+    }
+
+    #[pyo3(name = "allows")]
+    fn py_allows(&self, scope: &str) -> bool {
+        self.allows(scope)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[pymethods]
+impl Hello {
+    /// `ratchet_key` is the public half from `RatchetSession.key_pair`.
+    #[staticmethod]
+    #[pyo3(signature = (features, nym_address = None, ratchet_key = None))]
+    fn create(features: Vec<String>, nym_address: Option<String>, ratchet_key: Option<&str>) -> PyResult<Self> {
+        let hello = Self::new(features, nym_address).map_err(handshake_error)?;
+        match ratchet_key {
+            Some(key) => Ok(hello.with_ratchet_key(&decode_ratchet_key(key).map_err(crypto_error)?)),
+            None => Ok(hello),
+        }
+    }
+
+    /// Raises `ValueError` if `json` is not a valid hello.
+    #[staticmethod]
+    #[pyo3(name = "parse")]
+    fn py_parse(json: &str) -> PyResult<Self> {
+        Self::parse(json).map_err(handshake_error)
+    }
+
+    #[pyo3(name = "to_json")]
+    fn py_to_json(&self) -> String {
+        self.to_json()
+    }
+
+    /// Raises `ValueError` if the hellos have no version in common.
+    #[pyo3(name = "negotiate")]
+    fn py_negotiate(&self, other: &Hello) -> PyResult<Negotiated> {
+        negotiate(self, other).map_err(handshake_error)
+    }
+
+    #[staticmethod]
+    #[pyo3(name = "transcript")]
+    fn py_transcript(initiator_hello: &str, responder_hello: &str) -> String {
+        transcript(initiator_hello, responder_hello)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[pymethods]
+impl Negotiated {
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
@@ -0,0 +1,326 @@
+//! Double Ratchet sessions: forward secrecy for direct messages.
+//!
+//! Two contacts set a session up with an X3DH-style key agreement over their
+//! handshake. Each hello carries a fresh ratchet key (see
+//! [`Hello::with_ratchet_key`](crate::Hello::with_ratchet_key)), and the
+//! session starts from three Diffie-Hellman results: the initiator's identity
+//! key with the responder's ratchet key, the initiator's ratchet key with the
+//! responder's identity key, and the two ratchet keys. Both identities take
+//! part, so only the two contacts can derive it; the ratchet keys are thrown
+//! away, so a stolen identity key cannot derive it later either.
+//!
+//! From there the session follows Signal's Double Ratchet: every message is
+//! encrypted under its own message key from a sending chain (HMAC-SHA256, as
+//! group sender keys), and each time the conversation changes direction both
+//! sides move to a new ratchet key, so past messages stay unreadable after a
+//! compromise and later ones become unreadable to the attacker again. The
+//! initiator sends first; the responder can only send once a message from
+//! the initiator has arrived ([`RatchetSession::can_send`]).
+//!
+//! Ratchet messages are carried as the inner message of an ordinary direct
+//! message, so the server and the sender's signature see nothing new.
+
+use crate::crypto::{decode_hex, Crypto, CryptoError, Result};
+use crate::envelope::EncryptedBody;
+use crate::group::{decode_key, derive, CHAIN_KEY_LABEL, MAX_SKIP, MESSAGE_KEY_LABEL};
+use hkdf::Hkdf;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::fmt;
+
+/// Handshake feature of clients that keep Double Ratchet sessions.
+pub const RATCHET_FEATURE: &str = "doubleRatchet";
+
+const X3DH_INFO: &[u8] = b"nymchat X3DH";
+const ROOT_INFO: &[u8] = b"nymchat ratchet root";
+
+/// A ratchet public key as it travels: its SEC1 compressed point in hex.
+pub fn encode_ratchet_key(key: &PublicKey) -> String {
+    hex::encode(key.to_encoded_point(true).as_bytes())
+}
+
+/// A ratchet public key written by [`encode_ratchet_key`].
+pub fn decode_ratchet_key(value: &str) -> Result<PublicKey> {
+    let bytes = decode_hex(value, "ratchetKey")?;
+    PublicKey::from_sec1_bytes(&bytes).map_err(|e| CryptoError::InvalidKey(e.to_string()))
+}
+
+/// A fresh ratchet key, with its public half encoded as it travels.
+pub fn ratchet_key_pair() -> (SecretKey, String) {
+    let secret = SecretKey::random(&mut OsRng);
+    let public = encode_ratchet_key(&secret.public_key());
+    (secret, public)
+}
+
+fn dh(secret: &SecretKey, public: &PublicKey) -> Vec<u8> {
+    p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine()).raw_secret_bytes().to_vec()
+}
+
+/// The new root key and chain key after mixing `dh_output` into `root_key`.
+fn kdf_root(root_key: &[u8; 32], dh_output: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_output).expand(ROOT_INFO, &mut out).expect("64 bytes is a valid length");
+    let (root, chain) = out.split_at(32);
+    (root.try_into().expect("32 bytes"), chain.try_into().expect("32 bytes"))
+}
+
+/// What travels in the clear beside each ratchet message: the sender's
+/// current ratchet key, the length of their previous sending chain and the
+/// message's index in the current one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RatchetHeader {
+    pub ratchet_key: String,
+    pub previous_chain: u32,
+    pub index: u32,
+}
+
+/// A message encrypted under a Double Ratchet message key. The header is
+/// authenticated with it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    pub body: EncryptedBody,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChainState {
+    chain_key: String,
+    index: u32,
+}
+
+impl ChainState {
+    fn new(chain_key: [u8; 32]) -> Self {
+        ChainState { chain_key: hex::encode(chain_key), index: 0 }
+    }
+
+    /// The message key for the current index, moving the chain past it.
+    fn step(&mut self) -> Result<[u8; 32]> {
+        let chain_key = decode_key(&self.chain_key, "chainKey")?;
+        self.index = self.index.checked_add(1).ok_or(CryptoError::MessageKey)?;
+        self.chain_key = hex::encode(derive(&chain_key, CHAIN_KEY_LABEL));
+        Ok(derive(&chain_key, MESSAGE_KEY_LABEL))
+    }
+}
+
+/// A message key kept for a message that has not arrived yet.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SkippedKey {
+    ratchet_key: String,
+    index: u32,
+    message_key: String,
+}
+
+/// One side's Double Ratchet state with one contact.
+#[cfg_attr(feature = "python", pyo3::pyclass)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RatchetSession {
+    /// Both identity keys, initiator first, authenticated with every message.
+    associated_data: String,
+    root_key: String,
+    own_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    their_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sending: Option<ChainState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receiving: Option<ChainState>,
+    #[serde(default)]
+    previous_chain: u32,
+    /// Oldest first; at most [`MAX_SKIP`] are kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<SkippedKey>,
+}
+
+/// The X3DH shared secret and associated data, from the Diffie-Hellman
+/// results in the order the initiator computes them.
+fn agree(initiator: &PublicKey, responder: &PublicKey, results: [Vec<u8>; 3]) -> ([u8; 32], String) {
+    let mut input = vec![0xff; 32];
+    for result in results.iter() {
+        input.extend_from_slice(result);
+    }
+    let mut shared = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &input).expand(X3DH_INFO, &mut shared).expect("32 bytes is a valid length");
+    let associated_data = format!("{}{}", encode_ratchet_key(initiator), encode_ratchet_key(responder));
+    (shared, associated_data)
+}
+
+impl RatchetSession {
+    /// The session of the handshake's initiator, from its identity key and
+    /// the ratchet key of its hello, and the responder's. It can send at once.
+    pub fn initiate(
+        identity: &SecretKey,
+        ratchet: &SecretKey,
+        their_identity: &PublicKey,
+        their_ratchet: &PublicKey,
+    ) -> Self {
+        let results = [dh(identity, their_ratchet), dh(ratchet, their_identity), dh(ratchet, their_ratchet)];
+        let (shared, associated_data) = agree(&identity.public_key(), their_identity, results);
+        let own = SecretKey::random(&mut OsRng);
+        let (root_key, chain_key) = kdf_root(&shared, &dh(&own, their_ratchet));
+        RatchetSession {
+            associated_data,
+            root_key: hex::encode(root_key),
+            own_key: hex::encode(own.to_bytes()),
+            their_key: Some(encode_ratchet_key(their_ratchet)),
+            sending: Some(ChainState::new(chain_key)),
+            receiving: None,
+            previous_chain: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// The session of the handshake's responder, from its identity key and
+    /// the ratchet key of its hello, and the initiator's. It can send once
+    /// the initiator's first message has arrived.
+    pub fn respond(
+        identity: &SecretKey,
+        ratchet: &SecretKey,
+        their_identity: &PublicKey,
+        their_ratchet: &PublicKey,
+    ) -> Self {
+        let results = [dh(ratchet, their_identity), dh(identity, their_ratchet), dh(ratchet, their_ratchet)];
+        let (shared, associated_data) = agree(their_identity, &identity.public_key(), results);
+        RatchetSession {
+            associated_data,
+            root_key: hex::encode(shared),
+            own_key: hex::encode(ratchet.to_bytes()),
+            their_key: None,
+            sending: None,
+            receiving: None,
+            previous_chain: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// A session saved with [`RatchetSession::to_json`].
+    pub fn from_json(raw: &str) -> Result<Self> {
+        serde_json::from_str(raw).map_err(|e| CryptoError::InvalidJson(e.to_string()))
+    }
+
+    /// The whole session, secret keys included, for local storage only.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("sessions serialize")
+    }
+
+    /// Whether [`RatchetSession::encrypt`] has a chain to encrypt with.
+    pub fn can_send(&self) -> bool {
+        self.sending.is_some()
+    }
+
+    fn own_secret(&self) -> Result<SecretKey> {
+        let bytes = decode_key(&self.own_key, "ownKey")?;
+        SecretKey::from_slice(&bytes).map_err(|e| CryptoError::InvalidKey(e.to_string()))
+    }
+
+    fn aad(&self, header: &RatchetHeader) -> Vec<u8> {
+        let mut aad = self.associated_data.as_bytes().to_vec();
+        aad.extend_from_slice(&serde_json::to_vec(header).expect("headers serialize"));
+        aad
+    }
+
+    /// Encrypts `plaintext` under the next message key of the sending chain.
+    /// Fails with [`CryptoError::NoSession`] before the responder has heard
+    /// from the initiator.
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<RatchetMessage> {
+        let mut sending = self.sending.clone().ok_or(CryptoError::NoSession)?;
+        let header = RatchetHeader {
+            ratchet_key: encode_ratchet_key(&self.own_secret()?.public_key()),
+            previous_chain: self.previous_chain,
+            index: sending.index,
+        };
+        let key = sending.step()?;
+        let body = Crypto::encrypt_with_key(&key, &self.aad(&header), plaintext)?;
+        self.sending = Some(sending);
+        Ok(RatchetMessage { header, body })
+    }
+
+    /// Decrypts a ratchet message. The session only moves on if it decrypts,
+    /// so a forged or damaged message leaves it as it was; each message
+    /// decrypts once.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<String> {
+        let mut next = self.clone();
+        let plaintext = next.open(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn open(&mut self, message: &RatchetMessage) -> Result<String> {
+        let header = &message.header;
+        let ratchet_key = encode_ratchet_key(&decode_ratchet_key(&header.ratchet_key)?);
+        let aad = self.aad(header);
+
+        let kept =
+            self.skipped.iter().position(|skipped| skipped.ratchet_key == ratchet_key && skipped.index == header.index);
+        if let Some(position) = kept {
+            let key = decode_key(&self.skipped.remove(position).message_key, "messageKey")?;
+            return Crypto::decrypt_with_key(&key, &aad, &message.body);
+        }
+
+        if self.their_key.as_deref() != Some(ratchet_key.as_str()) {
+            self.skip_to(header.previous_chain)?;
+            self.turn(&ratchet_key)?;
+        }
+        self.skip_to(header.index)?;
+        let receiving = self.receiving.as_mut().ok_or(CryptoError::NoSession)?;
+        if header.index < receiving.index {
+            return Err(CryptoError::MessageKey);
+        }
+        let key = receiving.step()?;
+        Crypto::decrypt_with_key(&key, &aad, &message.body)
+    }
+
+    /// Keeps the message keys of the receiving chain up to `index`, for
+    /// messages that arrive out of order.
+    fn skip_to(&mut self, index: u32) -> Result<()> {
+        let (Some(receiving), Some(their_key)) = (self.receiving.as_mut(), self.their_key.as_ref()) else {
+            return Ok(());
+        };
+        if index > receiving.index && index - receiving.index > MAX_SKIP {
+            return Err(CryptoError::MessageKey);
+        }
+        while receiving.index < index {
+            let skipped = receiving.index;
+            let key = receiving.step()?;
+            self.skipped.push(SkippedKey {
+                ratchet_key: their_key.clone(),
+                index: skipped,
+                message_key: hex::encode(key),
+            });
+        }
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    /// Moves to the contact's new ratchet key `their_key`, and to a new one of our own.
+    fn turn(&mut self, their_key: &str) -> Result<()> {
+        let their_public = decode_ratchet_key(their_key)?;
+        let root_key = decode_key(&self.root_key, "rootKey")?;
+        let (root_key, receiving) = kdf_root(&root_key, &dh(&self.own_secret()?, &their_public));
+        let own = SecretKey::random(&mut OsRng);
+        let (root_key, sending) = kdf_root(&root_key, &dh(&own, &their_public));
+
+        self.previous_chain = self.sending.as_ref().map_or(0, |chain| chain.index);
+        self.their_key = Some(their_key.to_string());
+        self.own_key = hex::encode(own.to_bytes());
+        self.root_key = hex::encode(root_key);
+        self.receiving = Some(ChainState::new(receiving));
+        self.sending = Some(ChainState::new(sending));
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RatchetSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RatchetSession").field("can_send", &self.can_send()).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,170 @@
+use nymchat_protocol::{ct_eq, to_python_json, Crypto, CryptoError, Encrypted};
+use serde_json::Value;
+
+/// Written by the Python client (`client/src/tests/generate_crypto_vectors.py`)
+/// and checked by its tests too, so the two implementations stay in step.
+const VECTORS: &str = include_str!("vectors/crypto.json");
+
+#[test]
+fn encrypt_decrypt_round_trip() {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+
+    let payload = Crypto::encrypt(&public, r#"{"type": 0, "message": "héllo"}"#).unwrap();
+    let plaintext = Crypto::decrypt(&secret, &payload).unwrap().into_unauthenticated();
+
+    assert_eq!(plaintext, r#"{"type": 0, "message": "héllo"}"#);
+}
+
+#[test]
+fn decrypted_plaintext_needs_the_senders_signature() {
+    let (recipient, recipient_pem) = Crypto::generate_key_pair().unwrap();
+    let (sender, _) = Crypto::generate_key_pair().unwrap();
+    let (mallory, _) = Crypto::generate_key_pair().unwrap();
+    let payload = Crypto::encrypt(&Crypto::public_key_from_pem(&recipient_pem).unwrap(), "secret").unwrap();
+    let signature = Crypto::sign(&sender, &to_python_json(&payload).unwrap());
+
+    let forged = Crypto::decrypt(&recipient, &payload).unwrap().verify(&mallory.public_key(), &signature);
+    assert!(matches!(forged, Err(CryptoError::Signature)));
+    let plaintext = Crypto::decrypt(&recipient, &payload).unwrap().verify(&sender.public_key(), &signature);
+    assert_eq!(plaintext.unwrap(), "secret");
+}
+
+#[test]
+fn ct_eq_compares_contents_and_lengths() {
+    assert!(ct_eq(b"tag", b"tag"));
+    assert!(!ct_eq(b"tag", b"tab"));
+    assert!(!ct_eq(b"tag", b"tags"));
+    assert!(ct_eq(b"", b""));
+}
+
+#[test]
+fn decrypt_rejects_tampered_ciphertext() {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+
+    let mut payload = Crypto::encrypt(&public, "secret").unwrap();
+    payload.encrypted_body.ciphertext = "00".repeat(6);
+
+    assert!(matches!(Crypto::decrypt(&secret, &payload), Err(CryptoError::Decryption)));
+}
+
+#[test]
+fn decrypt_with_wrong_key_fails() {
+    let (_, public_pem) = Crypto::generate_key_pair().unwrap();
+    let (other_secret, _) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+
+    let payload = Crypto::encrypt(&public, "secret").unwrap();
+
+    assert!(Crypto::decrypt(&other_secret, &payload).is_err());
+}
+
+#[test]
+fn sign_and_verify() {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let public = Crypto::public_key_from_pem(&public_pem).unwrap();
+
+    let signature = Crypto::sign(&secret, "nonce");
+
+    assert!(Crypto::verify(&public, "nonce", &signature));
+    assert!(!Crypto::verify(&public, "other nonce", &signature));
+    assert!(!Crypto::verify(&public, "nonce", "not hex"));
+}
+
+#[test]
+fn private_key_pem_round_trip() {
+    let (secret, _) = Crypto::generate_key_pair().unwrap();
+    let pem = Crypto::private_key_pem(&secret).unwrap();
+
+    let loaded = Crypto::private_key_from_pem(&pem).unwrap();
+
+    assert_eq!(loaded.to_bytes(), secret.to_bytes());
+}
+
+#[test]
+fn python_json_matches_json_dumps() {
+    let payload: Encrypted = serde_json::from_str(
+        r#"{"ephemeralPublicKey":"pk","salt":"00","encryptedBody":{"iv":"01","ciphertext":"02","tag":"03"}}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        to_python_json(&payload).unwrap(),
+        r#"{"ephemeralPublicKey": "pk", "salt": "00", "encryptedBody": {"iv": "01", "ciphertext": "02", "tag": "03"}}"#
+    );
+    assert_eq!(to_python_json(&"é😀").unwrap(), r#""\u00e9\ud83d\ude00""#);
+}
+
+#[test]
+fn vector_keys_load_and_reencode_identically() {
+    let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+    for party in ["recipient", "sender"].iter() {
+        let keys = &vectors[*party];
+        let secret = Crypto::private_key_from_pem(keys["privateKey"].as_str().unwrap()).unwrap();
+
+        assert_eq!(Crypto::private_key_pem(&secret).unwrap(), keys["privateKey"]);
+        assert_eq!(Crypto::public_key_pem(&secret.public_key()).unwrap(), keys["publicKey"]);
+    }
+}
+
+#[test]
+fn vector_signatures_are_reproduced_exactly() {
+    let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+    let sender = Crypto::private_key_from_pem(vectors["sender"]["privateKey"].as_str().unwrap()).unwrap();
+
+    for vector in vectors["signatures"].as_array().unwrap() {
+        let message = vector["message"].as_str().unwrap();
+        // Both sides sign deterministically (RFC 6979).
+        assert_eq!(Crypto::sign(&sender, message), vector["signature"]);
+        assert!(Crypto::verify(&sender.public_key(), message, vector["signature"].as_str().unwrap()));
+    }
+}
+
+#[test]
+fn vector_payloads_decrypt_and_verify() {
+    let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+    let recipient = Crypto::private_key_from_pem(vectors["recipient"]["privateKey"].as_str().unwrap()).unwrap();
+    let sender = Crypto::public_key_from_pem(vectors["sender"]["publicKey"].as_str().unwrap()).unwrap();
+
+    for vector in vectors["encryptions"].as_array().unwrap() {
+        let payload: Encrypted = serde_json::from_value(vector["payload"].clone()).unwrap();
+        let signed = to_python_json(&payload).unwrap();
+
+        assert_eq!(signed, vector["signedPayload"]);
+        let signature = vector["payloadSignature"].as_str().unwrap();
+        assert!(Crypto::verify(&sender, &signed, signature));
+        let plaintext = Crypto::decrypt(&recipient, &payload).unwrap().verify(&sender, signature).unwrap();
+        assert_eq!(plaintext, vector["plaintext"]);
+    }
+}
+
+#[test]
+fn encrypted_payloads_match_the_vector_layout() {
+    let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+    let recipient = Crypto::private_key_from_pem(vectors["recipient"]["privateKey"].as_str().unwrap()).unwrap();
+
+    for vector in vectors["encryptions"].as_array().unwrap() {
+        let plaintext = vector["plaintext"].as_str().unwrap();
+        let expected = &vector["payload"];
+        let payload = Crypto::encrypt(&recipient.public_key(), plaintext).unwrap();
+        let hex_len = |value: &Value| value.as_str().unwrap().len();
+
+        assert_eq!(payload.salt.len(), hex_len(&expected["salt"]));
+        assert_eq!(payload.encrypted_body.iv.len(), hex_len(&expected["encryptedBody"]["iv"]));
+        assert_eq!(payload.encrypted_body.tag.len(), hex_len(&expected["encryptedBody"]["tag"]));
+        assert_eq!(payload.encrypted_body.ciphertext.len(), hex_len(&expected["encryptedBody"]["ciphertext"]));
+        assert_eq!(payload.ephemeral_public_key.len(), hex_len(&expected["ephemeralPublicKey"]));
+        assert_eq!(Crypto::decrypt(&recipient, &payload).unwrap().into_unauthenticated(), plaintext);
+    }
+}
+
+#[test]
+fn safety_numbers_match_the_python_client() {
+    let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+    let sender = Crypto::public_key_from_pem(vectors["sender"]["publicKey"].as_str().unwrap()).unwrap();
+    let recipient = Crypto::public_key_from_pem(vectors["recipient"]["publicKey"].as_str().unwrap()).unwrap();
+
+    assert_eq!(Crypto::safety_number(&sender), "18363 33796 21594 85783 68308 49598");
+    assert_ne!(Crypto::safety_number(&recipient), Crypto::safety_number(&sender));
+}
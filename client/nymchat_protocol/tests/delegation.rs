@@ -0,0 +1,55 @@
+use nymchat_protocol::delegation::SCOPE_SEND;
+use nymchat_protocol::{Crypto, CryptoError, Delegation, DelegationError};
+
+const NOW: u64 = 1_700_000_000;
+
+#[test]
+fn a_signed_delegation_vouches_for_its_sub_key() {
+    let (identity, _) = Crypto::generate_key_pair().unwrap();
+    let (bot, bot_pem) = Crypto::generate_key_pair().unwrap();
+    let delegation = Delegation::new("alice", &bot_pem, vec![SCOPE_SEND.to_string()], NOW + 3600).unwrap();
+    assert_eq!(delegation.id.len(), 32);
+
+    let certificate = delegation.to_json();
+    let signature = Crypto::sign(&identity, &certificate);
+    let verified = Delegation::verify(&certificate, &signature, &identity.public_key(), NOW).unwrap();
+    assert_eq!(verified, delegation);
+    assert!(verified.allows(SCOPE_SEND));
+    assert!(!verified.allows("deleteAccount"));
+
+    // Messages are then checked against the sub-key.
+    let message = Crypto::sign(&bot, "hello");
+    assert!(Crypto::verify(&verified.key().unwrap(), "hello", &message));
+}
+
+#[test]
+fn forged_expired_or_overreaching_delegations_are_refused() {
+    let (identity, _) = Crypto::generate_key_pair().unwrap();
+    let (_, bot_pem) = Crypto::generate_key_pair().unwrap();
+    let certificate = Delegation::new("alice", &bot_pem, vec![SCOPE_SEND.to_string()], NOW + 60).unwrap().to_json();
+    let signature = Crypto::sign(&identity, &certificate);
+
+    // Signed by the bot's key or another user's, not alice's.
+    let (other, _) = Crypto::generate_key_pair().unwrap();
+    let forged = Crypto::sign(&other, &certificate);
+    assert!(matches!(
+        Delegation::verify(&certificate, &forged, &identity.public_key(), NOW),
+        Err(DelegationError::Crypto(CryptoError::Signature))
+    ));
+    // Widened after signing.
+    let widened = certificate.replace(&format!("{}", NOW + 60), &format!("{}", NOW + 600));
+    assert!(Delegation::verify(&widened, &signature, &identity.public_key(), NOW).is_err());
+    // Past its expiry.
+    assert!(matches!(
+        Delegation::verify(&certificate, &signature, &identity.public_key(), NOW + 60),
+        Err(DelegationError::Expired)
+    ));
+
+    // Scopes beyond sending cannot be granted at all.
+    assert!(matches!(
+        Delegation::new("alice", &bot_pem, vec!["deleteAccount".to_string()], NOW + 60),
+        Err(DelegationError::Scope(scope)) if scope == "deleteAccount"
+    ));
+    assert!(Delegation::new("alice", &bot_pem, Vec::new(), NOW + 60).is_err());
+    assert!(Delegation::new("alice", "not a key", vec![SCOPE_SEND.to_string()], NOW + 60).is_err());
+}
@@ -0,0 +1,48 @@
+use nymchat_protocol::{pow, BloomFilter, Envelope};
+
+#[test]
+fn padded_replies_share_a_length() {
+    let short = Envelope::new("queryResponse")
+        .with_context("query")
+        .with_content("No user found")
+        .with_signature("ab".repeat(35));
+    let long = Envelope::new("queryResponse")
+        .with_context("query")
+        .with_content(format!(r#"{{"username": "alice", "publicKey": "{}"}}"#, "k".repeat(170)))
+        .with_signature("ab".repeat(36));
+
+    let short_json = short.to_padded_json_string(512).unwrap();
+    let long_json = long.to_padded_json_string(512).unwrap();
+
+    assert_eq!(short_json.len(), 512);
+    assert_eq!(long_json.len(), 512);
+    assert_eq!(Envelope::parse(&short_json).unwrap().content, short.content);
+}
+
+#[test]
+fn repadding_replaces_existing_padding() {
+    let padded = Envelope::new("incomingMessage").with_content("hi").to_padded_json_string(256).unwrap();
+    let repadded = Envelope::parse(&padded).unwrap().to_padded_json_string(512).unwrap();
+    assert_eq!(repadded.len(), 512);
+}
+
+#[test]
+fn bloom_filter_round_trips_membership() {
+    let mut filter = BloomFilter::with_capacity(100, 0.01);
+    for i in 0..100 {
+        filter.insert(&format!("user{}", i));
+    }
+
+    let decoded: BloomFilter = serde_json::from_str(&serde_json::to_string(&filter).unwrap()).unwrap();
+    assert!((0..100).all(|i| decoded.contains(&format!("user{}", i))));
+    let false_positives = (0..1000).filter(|i| decoded.contains(&format!("other{}", i))).count();
+    assert!(false_positives < 50, "{} false positives", false_positives);
+}
+
+#[test]
+fn proof_of_work_solutions_verify() {
+    let counter = pow::solve("abc123", 12);
+    assert!(pow::verify("abc123", 12, counter));
+    assert!((0..counter).all(|c| !pow::verify("abc123", 12, c)));
+    assert!(pow::verify("abc123", 0, 0));
+}
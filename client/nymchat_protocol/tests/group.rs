@@ -0,0 +1,113 @@
+use nymchat_protocol::group::MAX_SKIP;
+use nymchat_protocol::{to_python_json, Crypto, CryptoError, GroupSession};
+use p256::SecretKey;
+
+fn send(session: &mut GroupSession, key: &SecretKey, text: &str) -> (String, String) {
+    let message = to_python_json(&session.encrypt(text).unwrap()).unwrap();
+    let signature = Crypto::sign(key, &message);
+    (message, signature)
+}
+
+#[test]
+fn members_read_each_others_messages() {
+    let (alice_key, _) = Crypto::generate_key_pair().unwrap();
+    let (bob_key, _) = Crypto::generate_key_pair().unwrap();
+    let mut alice = GroupSession::new("g1", vec!["alice", "bob"]);
+    let mut bob = GroupSession::new("g1", vec!["alice", "bob"]);
+    assert!(bob.process_distribution("alice", &alice.distribution()).unwrap());
+    assert!(alice.process_distribution("bob", &bob.distribution()).unwrap());
+    // A repeated distribution changes nothing.
+    assert!(!bob.process_distribution("alice", &alice.distribution()).unwrap());
+
+    let (message, signature) = send(&mut alice, &alice_key, "hi bob");
+    assert_eq!(bob.decrypt("alice", &alice_key.public_key(), &message, &signature).unwrap(), "hi bob");
+    let (message, signature) = send(&mut bob, &bob_key, "hi alice");
+    assert_eq!(alice.decrypt("bob", &bob_key.public_key(), &message, &signature).unwrap(), "hi alice");
+
+    // The session survives being saved and loaded.
+    let mut bob = GroupSession::from_json(&bob.to_json()).unwrap();
+    let (message, signature) = send(&mut alice, &alice_key, "still there?");
+    assert_eq!(bob.decrypt("alice", &alice_key.public_key(), &message, &signature).unwrap(), "still there?");
+    assert!(!format!("{:?}", bob).contains(&alice.distribution().chain_key));
+}
+
+#[test]
+fn each_message_key_is_used_once_and_order_does_not_matter() {
+    let (alice_key, _) = Crypto::generate_key_pair().unwrap();
+    let mut alice = GroupSession::new("g1", vec!["alice", "bob"]);
+    let mut bob = GroupSession::new("g1", vec!["alice", "bob"]);
+    bob.process_distribution("alice", &alice.distribution()).unwrap();
+
+    let sent: Vec<_> = (0..3).map(|i| send(&mut alice, &alice_key, &format!("m{}", i))).collect();
+    for i in [2, 0, 1].iter() {
+        let (message, signature) = &sent[*i];
+        assert_eq!(bob.decrypt("alice", &alice_key.public_key(), message, signature).unwrap(), format!("m{}", i));
+    }
+    let (message, signature) = &sent[1];
+    assert!(matches!(
+        bob.decrypt("alice", &alice_key.public_key(), message, signature),
+        Err(CryptoError::MessageKey)
+    ));
+
+    for _ in 0..=MAX_SKIP {
+        alice.encrypt("unsent").unwrap();
+    }
+    let (message, signature) = send(&mut alice, &alice_key, "too far ahead");
+    assert!(matches!(
+        bob.decrypt("alice", &alice_key.public_key(), &message, &signature),
+        Err(CryptoError::MessageKey)
+    ));
+}
+
+#[test]
+fn members_cannot_forge_messages_from_each_other() {
+    let (alice_key, _) = Crypto::generate_key_pair().unwrap();
+    let (mallory_key, _) = Crypto::generate_key_pair().unwrap();
+    let mut alice = GroupSession::new("g1", vec!["alice", "bob", "mallory"]);
+    let mut bob = GroupSession::new("g1", vec!["alice", "bob", "mallory"]);
+    let mut mallory = GroupSession::new("g1", vec!["alice", "bob", "mallory"]);
+    bob.process_distribution("alice", &alice.distribution()).unwrap();
+    mallory.process_distribution("alice", &alice.distribution()).unwrap();
+
+    // Mallory holds Alice's sender key but cannot sign as her, and the
+    // failed forgery does not use up the key of Alice's real message.
+    let (message, signature) = send(&mut alice, &alice_key, "real");
+    let forged_signature = Crypto::sign(&mallory_key, &message);
+    assert!(matches!(
+        bob.decrypt("alice", &alice_key.public_key(), &message, &forged_signature),
+        Err(CryptoError::Signature)
+    ));
+    assert_eq!(bob.decrypt("alice", &alice_key.public_key(), &message, &signature).unwrap(), "real");
+
+    // Nor is a message from Alice accepted as Mallory's.
+    assert!(matches!(
+        bob.decrypt("mallory", &alice_key.public_key(), &message, &signature),
+        Err(CryptoError::UnknownSenderKey(_))
+    ));
+}
+
+#[test]
+fn membership_changes_rotate_sender_keys() {
+    let (alice_key, _) = Crypto::generate_key_pair().unwrap();
+    let mut alice = GroupSession::new("g1", vec!["alice", "bob", "carol"]);
+    let mut carol = GroupSession::new("g1", vec!["alice", "bob", "carol"]);
+    carol.process_distribution("alice", &alice.distribution()).unwrap();
+    let old_key = alice.distribution();
+
+    assert!(!alice.set_members(vec!["carol", "bob", "alice"]));
+    assert_eq!(alice.distribution(), old_key);
+    assert!(alice.set_members(vec!["alice", "bob"]));
+    assert_ne!(alice.distribution().chain_key, old_key.chain_key);
+
+    // Carol, removed, still has only the old key.
+    let (message, signature) = send(&mut alice, &alice_key, "after carol left");
+    assert!(matches!(
+        carol.decrypt("alice", &alice_key.public_key(), &message, &signature),
+        Err(CryptoError::UnknownSenderKey(_))
+    ));
+
+    // Keys of removed members are dropped, and non-members cannot join in.
+    assert!(alice.process_distribution("carol", &carol.distribution()).is_err());
+    let mut other_group = GroupSession::new("g2", vec!["alice", "bob"]);
+    assert!(other_group.process_distribution("alice", &alice.distribution()).is_err());
+}
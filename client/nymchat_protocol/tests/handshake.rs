@@ -0,0 +1,37 @@
+use nymchat_protocol::handshake::{negotiate, transcript, HANDSHAKE_VERSIONS};
+use nymchat_protocol::{HandshakeError, Hello};
+
+fn features(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn both_sides_agree_on_the_most_they_share() {
+    let ours = Hello::new(features(&["sealedSender", "keyObservation"]), None).unwrap();
+    let theirs = Hello { versions: vec![1, 2, 3], features: features(&["keyObservation", "groups"]), ..ours.clone() };
+    let agreed = negotiate(&ours, &theirs).unwrap();
+    assert_eq!(agreed, negotiate(&theirs, &ours).unwrap());
+    assert_eq!((agreed.version, agreed.features), (HANDSHAKE_VERSIONS[0], features(&["keyObservation"])));
+
+    let old = Hello { versions: vec![1], ..theirs };
+    assert!(matches!(negotiate(&ours, &old), Err(HandshakeError::NoCommonVersion)));
+}
+
+#[test]
+fn transcripts_bind_both_hellos() {
+    let initiator = Hello::new(features(&["sealedSender"]), None).unwrap().to_json();
+    let responder = Hello::new(features(&["sealedSender"]), None).unwrap().to_json();
+    assert_ne!(Hello::parse(&initiator).unwrap().nonce, Hello::parse(&responder).unwrap().nonce);
+
+    // A feature struck out on the way changes the hash, and so does swapping the roles.
+    let stripped = initiator.replace(r#""sealedSender""#, "");
+    assert!(Hello::parse(&stripped).is_ok());
+    let expected = transcript(&initiator, &responder);
+    assert_ne!(transcript(&stripped, &responder), expected);
+    assert_ne!(transcript(&responder, &initiator), expected);
+    assert_eq!(transcript(&initiator, &responder), expected);
+
+    assert!(Hello::parse(&initiator.replace("versions", "version")).is_err());
+    assert!(Hello::new(features(&["a,b"]), None).is_err());
+    assert!(Hello::new(Vec::new(), Some("not-an-address".to_string())).is_err());
+}
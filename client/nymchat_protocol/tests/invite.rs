@@ -0,0 +1,52 @@
+use nymchat_protocol::invite::{fingerprint, is_valid_nym_address, INVITE_URI_PREFIX};
+use nymchat_protocol::{ContactInvite, Crypto, InviteError};
+
+const ADDRESS: &str = concat!(
+    "8HVwZXW3oYbjzvyoNpnkGEKasYkbuvQ3R8Ze6N8rPJnc.4B3K7xGsDNBHjFP2R1VUJhJHaWmHLfFSjPmbVfy5qA4H",
+    "@Fo4f4SQLdoyoGkFae5TpVhRVoXCF8UiypLVGtGjujVPf"
+);
+
+#[test]
+fn invites_round_trip() {
+    let (key, pem) = Crypto::generate_key_pair().unwrap();
+    let invite = ContactInvite::new("alice", &pem, Some(ADDRESS)).unwrap();
+    assert_eq!(invite.fingerprint(), fingerprint(&key.public_key()));
+    assert_eq!(invite.fingerprint().split(' ').count(), 16);
+
+    let uri = invite.to_uri();
+    assert!(uri.starts_with(INVITE_URI_PREFIX));
+    assert!(uri.ends_with(ADDRESS));
+    assert_eq!(ContactInvite::parse(&format!("  {}\n", uri)).unwrap(), invite);
+
+    // The address is optional.
+    let bare = ContactInvite::new("alice", &pem, None).unwrap();
+    let parsed = ContactInvite::parse(&bare.to_uri()).unwrap();
+    assert_eq!((parsed.nym_address(), parsed.public_key()), (None, pem.as_str()));
+}
+
+#[test]
+fn damaged_invites_are_refused() {
+    let (_, pem) = Crypto::generate_key_pair().unwrap();
+    let (_, other) = Crypto::generate_key_pair().unwrap();
+    let uri = ContactInvite::new("alice", &pem, None).unwrap().to_uri();
+    let parts: Vec<&str> = uri.strip_prefix(INVITE_URI_PREFIX).unwrap().split('/').collect();
+
+    // Another key with the first key's check, or the check cut short.
+    let swapped = ContactInvite::new("alice", &other, None).unwrap().to_uri();
+    let swapped_key = swapped.strip_prefix(INVITE_URI_PREFIX).unwrap().split('/').nth(1).unwrap();
+    let forged = format!("{}alice/{}/{}", INVITE_URI_PREFIX, swapped_key, parts[2]);
+    assert!(matches!(ContactInvite::parse(&forged), Err(InviteError::Fingerprint)));
+    assert!(matches!(ContactInvite::parse(&uri[..uri.len() - 1]), Err(InviteError::Fingerprint)));
+
+    let truncated_key = format!("{}alice/{}/{}", INVITE_URI_PREFIX, &parts[1][..20], parts[2]);
+    assert!(matches!(ContactInvite::parse(&truncated_key), Err(InviteError::Key)));
+    let bad_name = uri.replace("/alice/", "/al ice/");
+    assert!(matches!(ContactInvite::parse(&bad_name), Err(InviteError::Username(_))));
+    assert!(matches!(ContactInvite::parse(&format!("{}/not-an-address", uri)), Err(InviteError::NymAddress(_))));
+    assert!(matches!(ContactInvite::parse("nymchat-invite:abc"), Err(InviteError::NotAnInvite)));
+    assert!(matches!(ContactInvite::parse(&format!("{}alice", INVITE_URI_PREFIX)), Err(InviteError::NotAnInvite)));
+
+    assert!(is_valid_nym_address(ADDRESS));
+    assert!(!is_valid_nym_address("abc@def"));
+    assert!(!is_valid_nym_address("ab0.cd@ef"));
+}
@@ -0,0 +1,96 @@
+use nymchat_protocol::{Crypto, MembershipError, MembershipLog, MembershipOp};
+use p256::SecretKey;
+
+fn commit(log: &mut MembershipLog, actor: &str, key: &SecretKey, op: MembershipOp) -> (String, String) {
+    let entry = log.propose(actor, op).unwrap();
+    let signature = Crypto::sign(key, &entry);
+    assert!(log.apply(&entry, &signature, &key.public_key()).unwrap());
+    (entry, signature)
+}
+
+fn member(name: &str) -> String {
+    name.to_string()
+}
+
+#[test]
+fn every_member_reaches_the_same_membership() {
+    let (alice, _) = Crypto::generate_key_pair().unwrap();
+    let mut log = MembershipLog::new("g1");
+    let create = MembershipOp::Create { name: "team".into(), members: vec![member("alice"), member("bob")] };
+    let entries = [
+        commit(&mut log, "alice", &alice, create),
+        commit(&mut log, "alice", &alice, MembershipOp::Invite { member: member("carol") }),
+        commit(&mut log, "alice", &alice, MembershipOp::Promote { member: member("carol") }),
+        commit(&mut log, "alice", &alice, MembershipOp::Kick { member: member("bob") }),
+    ];
+    assert_eq!(log.members().iter().collect::<Vec<_>>(), ["alice", "carol"]);
+    assert_eq!(log.admins().iter().collect::<Vec<_>>(), ["alice", "carol"]);
+
+    // Another member replays the same entries, each once.
+    let mut replica = MembershipLog::new("g1");
+    for (entry, signature) in entries.iter().chain(entries.iter()) {
+        replica.apply(entry, signature, &alice.public_key()).unwrap();
+    }
+    assert_eq!((replica.name(), replica.members(), replica.len()), (log.name(), log.members(), 4));
+    let saved = MembershipLog::from_json(&replica.to_json()).unwrap();
+    assert_eq!(saved.admins(), log.admins());
+}
+
+#[test]
+fn only_admins_change_the_membership() {
+    let (alice, _) = Crypto::generate_key_pair().unwrap();
+    let (bob, _) = Crypto::generate_key_pair().unwrap();
+    let mut log = MembershipLog::new("g1");
+    let create = MembershipOp::Create { name: "team".into(), members: vec![member("alice"), member("bob")] };
+    commit(&mut log, "alice", &alice, create);
+
+    assert!(matches!(
+        log.propose("bob", MembershipOp::Invite { member: member("mallory") }),
+        Err(MembershipError::NotAllowed(..))
+    ));
+    // Nor can bob apply such an entry by signing it himself.
+    let entry = log
+        .propose("alice", MembershipOp::Invite { member: member("mallory") })
+        .unwrap()
+        .replace(r#""actor": "alice""#, r#""actor": "bob""#);
+    assert!(matches!(
+        log.clone().apply(&entry, &Crypto::sign(&bob, &entry), &bob.public_key()),
+        Err(MembershipError::NotAllowed(..))
+    ));
+    // A server cannot sign as alice.
+    let entry = log.propose("alice", MembershipOp::Kick { member: member("bob") }).unwrap();
+    assert!(matches!(
+        log.apply(&entry, &Crypto::sign(&bob, &entry), &alice.public_key()),
+        Err(MembershipError::Crypto(_))
+    ));
+    assert!(log.propose("bob", MembershipOp::History { limit: 50 }).is_err());
+    commit(&mut log, "alice", &alice, MembershipOp::History { limit: 50 });
+    assert_eq!(log.history_limit(), 50);
+    assert!(log.propose("alice", MembershipOp::Kick { member: member("alice") }).is_err());
+    assert!(log.propose("alice", MembershipOp::Create { name: "again".into(), members: vec![] }).is_err());
+}
+
+#[test]
+fn conflicting_entries_are_forks() {
+    let (alice, _) = Crypto::generate_key_pair().unwrap();
+    let mut log = MembershipLog::new("g1");
+    let create = MembershipOp::Create { name: "team".into(), members: vec![member("alice"), member("bob")] };
+    commit(&mut log, "alice", &alice, create);
+    let before = log.clone();
+    commit(&mut log, "alice", &alice, MembershipOp::Invite { member: member("carol") });
+
+    // The same place in the log, filled differently, e.g. shown to other members.
+    let other = before.propose("alice", MembershipOp::Kick { member: member("bob") }).unwrap();
+    assert!(matches!(
+        log.apply(&other, &Crypto::sign(&alice, &other), &alice.public_key()),
+        Err(MembershipError::Fork(1))
+    ));
+
+    // Entries from further ahead wait for the ones before them.
+    let mut fresh = MembershipLog::new("g1");
+    let next = log.propose("alice", MembershipOp::Promote { member: member("carol") }).unwrap();
+    assert!(matches!(
+        fresh.apply(&next, &Crypto::sign(&alice, &next), &alice.public_key()),
+        Err(MembershipError::OutOfOrder(2))
+    ));
+}
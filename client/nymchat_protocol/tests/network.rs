@@ -0,0 +1,55 @@
+use nymchat_protocol::network::parse_env_file;
+use nymchat_protocol::{NetworkConfig, NetworkError, NymNetwork};
+use std::fs;
+
+fn pairs(variables: &[(String, String)]) -> Vec<(&str, &str)> {
+    variables.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect()
+}
+
+#[test]
+fn network_names_parse() {
+    assert_eq!("".parse::<NymNetwork>().unwrap(), NymNetwork::Mainnet);
+    assert_eq!("Sandbox".parse::<NymNetwork>().unwrap(), NymNetwork::Sandbox);
+    assert_eq!("custom".parse::<NymNetwork>().unwrap().to_string(), "custom");
+    assert!(matches!("qa".parse::<NymNetwork>(), Err(NetworkError::Unknown(_))));
+}
+
+#[test]
+fn env_files_describe_other_networks() {
+    let contents = "# sandbox\nNETWORK_NAME=sandbox\n\nexport NYM_API=\"https://sandbox-nym-api1.nymtech.net/api/\"\n\
+                    NYXD='https://rpc.sandbox.nymtech.net'\n";
+    assert_eq!(
+        pairs(&parse_env_file(contents)),
+        [
+            ("NETWORK_NAME", "sandbox"),
+            ("NYM_API", "https://sandbox-nym-api1.nymtech.net/api/"),
+            ("NYXD", "https://rpc.sandbox.nymtech.net"),
+        ]
+    );
+
+    let path = std::env::temp_dir().join(format!("nymchat-network-{}.env", std::process::id()));
+    fs::write(&path, contents).unwrap();
+    let config = NetworkConfig {
+        network: NymNetwork::Custom,
+        env_file: Some(path.clone()),
+        nym_api: Some("http://localhost:8080/api/".into()),
+        nyxd: None,
+    };
+    let variables = config.variables().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        pairs(&variables),
+        [
+            ("NETWORK_NAME", "sandbox"),
+            ("NYXD", "https://rpc.sandbox.nymtech.net"),
+            ("NYM_API", "http://localhost:8080/api/"),
+        ]
+    );
+
+    // Mainnet's details are built in, so there is nothing to read or override.
+    assert!(NetworkConfig::default().variables().unwrap().is_empty());
+    let overridden = NetworkConfig { nyxd: Some("http://localhost:26657".into()), ..NetworkConfig::default() };
+    assert!(matches!(overridden.variables(), Err(NetworkError::MainnetOverride("NYXD_URL"))));
+    let custom = NetworkConfig { network: NymNetwork::Custom, ..NetworkConfig::default() };
+    assert!(matches!(custom.variables(), Err(NetworkError::MissingEnvFile)));
+}
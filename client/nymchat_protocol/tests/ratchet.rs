@@ -0,0 +1,104 @@
+use nymchat_protocol::group::MAX_SKIP;
+use nymchat_protocol::ratchet::ratchet_key_pair;
+use nymchat_protocol::{Crypto, CryptoError, RatchetSession};
+
+/// Sessions of an initiator and a responder, as a handshake between them sets up.
+fn pair() -> (RatchetSession, RatchetSession) {
+    let (alice, _) = Crypto::generate_key_pair().unwrap();
+    let (bob, _) = Crypto::generate_key_pair().unwrap();
+    let (alice_ratchet, _) = ratchet_key_pair();
+    let (bob_ratchet, _) = ratchet_key_pair();
+    let initiator = RatchetSession::initiate(&alice, &alice_ratchet, &bob.public_key(), &bob_ratchet.public_key());
+    let responder = RatchetSession::respond(&bob, &bob_ratchet, &alice.public_key(), &alice_ratchet.public_key());
+    (initiator, responder)
+}
+
+#[test]
+fn both_sides_talk_once_the_initiator_has_spoken() {
+    let (mut alice, mut bob) = pair();
+    assert!(alice.can_send());
+    assert!(matches!(bob.encrypt("too early"), Err(CryptoError::NoSession)));
+
+    let message = alice.encrypt("hi bob").unwrap();
+    assert_eq!(bob.decrypt(&message).unwrap(), "hi bob");
+    assert!(bob.can_send());
+
+    for round in 0..3 {
+        let reply = bob.encrypt(&format!("reply {}", round)).unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), format!("reply {}", round));
+        let answer = alice.encrypt(&format!("answer {}", round)).unwrap();
+        assert_eq!(bob.decrypt(&answer).unwrap(), format!("answer {}", round));
+    }
+
+    // Sessions survive being saved and loaded.
+    let mut bob = RatchetSession::from_json(&bob.to_json()).unwrap();
+    let message = alice.encrypt("still there?").unwrap();
+    assert_eq!(bob.decrypt(&message).unwrap(), "still there?");
+}
+
+#[test]
+fn every_turn_moves_to_a_new_ratchet_key() {
+    let (mut alice, mut bob) = pair();
+    let first = alice.encrypt("one").unwrap();
+    let second = alice.encrypt("two").unwrap();
+    assert_eq!(first.header.ratchet_key, second.header.ratchet_key);
+    assert_eq!((first.header.index, second.header.index), (0, 1));
+    bob.decrypt(&first).unwrap();
+    bob.decrypt(&second).unwrap();
+
+    let reply = bob.encrypt("three").unwrap();
+    alice.decrypt(&reply).unwrap();
+    let next = alice.encrypt("four").unwrap();
+    assert_ne!(next.header.ratchet_key, first.header.ratchet_key);
+    assert_eq!((next.header.previous_chain, next.header.index), (2, 0));
+    assert_eq!(bob.decrypt(&next).unwrap(), "four");
+}
+
+#[test]
+fn messages_may_arrive_out_of_order_but_only_once() {
+    let (mut alice, mut bob) = pair();
+    let messages: Vec<_> = (0..4).map(|i| alice.encrypt(&i.to_string()).unwrap()).collect();
+
+    assert_eq!(bob.decrypt(&messages[2]).unwrap(), "2");
+    assert_eq!(bob.decrypt(&messages[0]).unwrap(), "0");
+    // A message from an earlier chain, after the conversation turned.
+    let reply = bob.encrypt("turn").unwrap();
+    alice.decrypt(&reply).unwrap();
+    let late = alice.encrypt("after the turn").unwrap();
+    assert_eq!(bob.decrypt(&late).unwrap(), "after the turn");
+    assert_eq!(bob.decrypt(&messages[1]).unwrap(), "1");
+    assert_eq!(bob.decrypt(&messages[3]).unwrap(), "3");
+
+    assert!(bob.decrypt(&messages[3]).is_err());
+    assert!(bob.decrypt(&late).is_err());
+}
+
+#[test]
+fn forgeries_leave_the_session_as_it_was() {
+    let (mut alice, mut bob) = pair();
+    let message = alice.encrypt("genuine").unwrap();
+
+    let mut tampered = message.clone();
+    tampered.header.index += 1;
+    assert!(matches!(bob.decrypt(&tampered), Err(CryptoError::Decryption)));
+    let mut tampered = message.clone();
+    tampered.body.ciphertext = "00".repeat(7);
+    assert!(bob.decrypt(&tampered).is_err());
+
+    // A session between other people cannot read it.
+    let (_, mut eve) = pair();
+    assert!(eve.decrypt(&message).is_err());
+
+    assert_eq!(bob.decrypt(&message).unwrap(), "genuine");
+}
+
+#[test]
+fn messages_too_far_ahead_are_refused() {
+    let (mut alice, mut bob) = pair();
+    bob.decrypt(&alice.encrypt("first").unwrap()).unwrap();
+    for _ in 0..MAX_SKIP + 1 {
+        alice.encrypt("lost").unwrap();
+    }
+    let far = alice.encrypt("far ahead").unwrap();
+    assert!(matches!(bob.decrypt(&far), Err(CryptoError::MessageKey)));
+}
@@ -1,4 +1,5 @@
 import json
+import os
 from async_ffi import PyMixnetClient
 from logUtils import logger
 
@@ -6,11 +7,19 @@ class MixnetConnectionClient:
     def __init__(self):
         self.client = None  # Will be initialized asynchronously
 
-    async def init(self):
+    async def init(self, data_dir=None):
         """
         Asynchronously initialize the mixnet client.
+        Up to INCOMING_QUEUE_CAPACITY received messages wait for the message
+        callback; beyond that INCOMING_OVERFLOW applies: "block", "drop-oldest"
+        or "spill" (to INCOMING_SPILL_PATH).
+        With `data_dir` the mixnet identity is kept in that directory, so the
+        nym address is the same every time; otherwise it is ephemeral.
         """
-        self.client = await PyMixnetClient.create()
+        capacity = int(os.getenv("INCOMING_QUEUE_CAPACITY", "1024"))
+        overflow = os.getenv("INCOMING_OVERFLOW", "block")
+        spill_path = os.getenv("INCOMING_SPILL_PATH", os.path.join("storage", "incoming_spill.db"))
+        self.client = await PyMixnetClient.create(capacity, overflow, spill_path if overflow == "spill" else None, data_dir)
 
     async def get_nym_address(self):
         """
@@ -29,11 +38,53 @@ class MixnetConnectionClient:
             raise ValueError("Both 'recipient' and 'message' must be provided.")
         await self.client.send_message(recipient, msg)
 
-    async def set_message_callback(self, callback):
+    async def send_with_surbs(self, recipient, message, surb_count):
+        """
+        Send a raw message to a nym address, attaching `surb_count` reply SURBs.
+        """
+        await self.client.send_with_surbs(recipient, message, surb_count)
+
+    async def reply_with_surb(self, sender_tag, message):
+        """
+        Reply anonymously to a sender tag using one of the SURBs it attached.
+        """
+        await self.client.reply_with_surb(sender_tag, message)
+
+    async def set_message_callback(self, callback, typed=False):
         """
         Set a callback function for incoming messages.
+        With `typed=True` the callback receives `async_ffi.IncomingMessage` objects
+        (raw message, sender tag, timestamp) instead of plain strings.
+        """
+        await self.client.set_message_callback(callback, typed)
+
+    async def set_status_callback(self, callback):
+        """
+        Set a callback for connection state changes.
+        Called as `callback(status, nym_address, reason)` with status "connected",
+        "disconnected" or "reconnecting"; the current state is reported immediately.
+        `reason` says what dropped the connection, e.g. "resumed" (from sleep),
+        "network_changed" or "network_lost" (see `PyMixnetClient.set_status_callback`).
         """
-        await self.client.set_message_callback(callback)
+        await self.client.set_status_callback(callback)
+
+    def incoming_stats(self):
+        """
+        Incoming queue depth ("queued", "spilled_pending") and overflow counters
+        ("dropped", "spilled") since the client was created.
+        """
+        return self.client.incoming_stats()
+
+    async def network_stats(self):
+        """
+        Traffic counters since the client was created (see `PyMixnetClient.network_stats`),
+        the connection's "uptime" in seconds and the "gateway" it goes through;
+        both are None while disconnected.
+        """
+        stats = dict(self.client.network_stats())
+        address = await self.get_nym_address()
+        stats["gateway"] = address.rpartition("@")[2] if address and "@" in address else None
+        return stats
 
     async def receive_messages(self):
         """
@@ -42,6 +93,13 @@ class MixnetConnectionClient:
         logger.info("STARTED MESSAGE RECEIVING LOOP")
         await self.client.receive_messages()  # Ensure this is awaited properly
 
+    async def stop_listening(self):
+        """
+        Stop the receive loop and wait for it to exit.
+        `receive_messages` can be called again afterwards.
+        """
+        await self.client.stop_listening()
+
     async def shutdown(self):
         """
         Asynchronously shut down the mixnet client.
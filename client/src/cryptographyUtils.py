@@ -1,15 +1,40 @@
 from cryptography.hazmat.primitives.asymmetric import ec
 from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+from cryptography.hazmat.primitives.kdf.scrypt import Scrypt
 from cryptography.hazmat.primitives import hashes, serialization
 from cryptography.hazmat.primitives.asymmetric.utils import encode_dss_signature, decode_dss_signature
 from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.exceptions import InvalidTag
+from concurrent.futures import ThreadPoolExecutor
+from async_ffi import keystore_exists, keystore_load, keystore_protect, keystore_save
+import asyncio
 import os
+import re
 import json
 
+# Threads that ECDH, signing and AES-GCM run on from async code, so a large
+# payload does not stall the event loop the interface is drawn from. OpenSSL
+# releases the GIL while it works.
+CRYPTO_WORKERS = min(4, os.cpu_count() or 1)
+# Groups of five digits in a safety number, as in nymchat_protocol.
+SAFETY_NUMBER_GROUPS = 6
+_worker_pool = None
+
+
+def worker_pool():
+    """The crypto worker pool, started on first use."""
+    global _worker_pool
+    if _worker_pool is None:
+        _worker_pool = ThreadPoolExecutor(max_workers=CRYPTO_WORKERS, thread_name_prefix="crypto")
+    return _worker_pool
+
+
 class CryptoUtils:
     def __init__(self, storage_dir="storage"):
         """Initialize the CryptoUtils with a base storage directory."""
         self.storage_dir = storage_dir
+        # Private keys opened from a passphrase keystore, by username, until locked
+        self.unlocked = {}
         if not os.path.exists(storage_dir):
             os.makedirs(storage_dir)
 
@@ -26,30 +51,66 @@ class CryptoUtils:
         ).decode()
         return private_key, public_key_pem
 
-    def save_keys(self, username, private_key, public_key_pem):
-        """Save the private and public keys to files."""
+    def save_keys(self, username, private_key, public_key_pem, passphrase=None):
+        """
+        Save the private and public keys to files. With a `passphrase` the
+        private key goes into a keystore encrypted with it (Argon2id and
+        AES-GCM) instead of a plain file, and stays unlocked.
+        """
         user_dir = os.path.join(self.storage_dir, username)
         if not os.path.exists(user_dir):
             os.makedirs(user_dir)
 
-        # Save private key
-        private_key_path = os.path.join(user_dir, f"{username}_private_key.pem")
-        with open(private_key_path, "wb") as f:
-            f.write(
-                private_key.private_bytes(
-                    encoding=serialization.Encoding.PEM,
-                    format=serialization.PrivateFormat.PKCS8,
-                    encryption_algorithm=serialization.NoEncryption(),
-                )
-            )
+        private_pem = private_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        )
+        if passphrase:
+            keystore_save(self.storage_dir, username, private_pem.decode(), passphrase)
+            self.unlocked[username] = private_key
+        else:
+            private_key_path = os.path.join(user_dir, f"{username}_private_key.pem")
+            with open(private_key_path, "wb") as f:
+                f.write(private_pem)
 
         # Save public key
         public_key_path = os.path.join(user_dir, f"{username}_public_key.pem")
         with open(public_key_path, "wb") as f:
             f.write(public_key_pem.encode())
 
+    def has_keystore(self, username):
+        """Whether the private key of `username` is kept encrypted with a passphrase."""
+        return keystore_exists(self.storage_dir, username)
+
+    def unlock(self, username, passphrase):
+        """Opens the keystore of `username` until `lock`. Returns False if the passphrase is wrong."""
+        try:
+            pem = keystore_load(self.storage_dir, username, passphrase)
+        except ValueError:
+            return False
+        self.unlocked[username] = serialization.load_pem_private_key(pem.encode(), password=None)
+        return True
+
+    def lock(self, username=None):
+        """Forgets the opened private key of `username`, or of everyone."""
+        if username is None:
+            self.unlocked.clear()
+        else:
+            self.unlocked.pop(username, None)
+
+    def protect_keys(self, username, passphrase):
+        """Moves the plain private key file of `username` into a keystore encrypted with `passphrase`."""
+        private_key = self.load_private_key(username)
+        keystore_protect(self.storage_dir, username, passphrase)
+        self.unlocked[username] = private_key
+
     def load_private_key(self, username):
-        """Load the private key from the user's folder."""
+        """Load the private key from the user's folder, or None if it is in a keystore not unlocked."""
+        if username in self.unlocked:
+            return self.unlocked[username]
+        if self.has_keystore(username):
+            return None
         private_key_path = os.path.join(self.storage_dir, username, f"{username}_private_key.pem")
         with open(private_key_path, "rb") as f:
             private_key = serialization.load_pem_private_key(
@@ -65,6 +126,76 @@ class CryptoUtils:
             public_key = serialization.load_pem_public_key(f.read())
         return public_key
 
+    def load_public_keys(self, pem_text):
+        """Every public key in `pem_text`, which may hold several PEM blocks."""
+        blocks = re.findall(r"-----BEGIN PUBLIC KEY-----.+?-----END PUBLIC KEY-----", pem_text, re.S)
+        return [serialization.load_pem_public_key(block.encode()) for block in blocks]
+
+    def public_key_pem(self, public_key):
+        """Serialize a public key as SPKI PEM."""
+        return public_key.public_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo,
+        ).decode()
+
+    def fingerprint(self, public_key_pem):
+        """SHA-256 of a PEM public key's DER encoding, as 16 groups of 4 hex digits."""
+        key = serialization.load_pem_public_key(public_key_pem.encode())
+        der = key.public_bytes(
+            encoding=serialization.Encoding.DER,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo,
+        )
+        digest = hashes.Hash(hashes.SHA256())
+        digest.update(der)
+        hexed = digest.finalize().hex()
+        return " ".join(hexed[i:i + 4] for i in range(0, len(hexed), 4))
+
+    def safety_number(self, public_key_pem):
+        """
+        The same SHA-256 read as six 5-byte numbers, each shown as five decimal
+        digits, like `Crypto::safety_number` in nymchat_protocol.
+        """
+        key = serialization.load_pem_public_key(public_key_pem.encode())
+        der = key.public_bytes(
+            encoding=serialization.Encoding.DER,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo,
+        )
+        digest = hashes.Hash(hashes.SHA256())
+        digest.update(der)
+        hashed = digest.finalize()
+        return " ".join(
+            f"{int.from_bytes(hashed[i:i + 5], 'big') % 100000:05d}" for i in range(0, SAFETY_NUMBER_GROUPS * 5, 5)
+        )
+
+    def wipe_user_data(self, username):
+        """
+        Deletes everything stored for `username` (keys, database, and any other
+        files in their folder), overwriting each file with random bytes before
+        unlinking it. Journaling filesystems and SSDs may still keep old copies,
+        so this is best effort beyond what the OS exposes.
+        """
+        user_dir = os.path.join(self.storage_dir, username)
+        if not os.path.isdir(user_dir):
+            return
+        for root, dirs, files in os.walk(user_dir, topdown=False):
+            for name in files:
+                path = os.path.join(root, name)
+                if os.path.islink(path):
+                    os.remove(path)
+                    continue
+                with open(path, "r+b") as f:
+                    remaining = os.path.getsize(path)
+                    while remaining > 0:
+                        chunk = min(remaining, 1 << 20)
+                        f.write(os.urandom(chunk))
+                        remaining -= chunk
+                    f.flush()
+                    os.fsync(f.fileno())
+                os.remove(path)
+            for name in dirs:
+                os.rmdir(os.path.join(root, name))
+        os.rmdir(user_dir)
+
     # ----------------------------------------------
     # 🔒 SIGNING & VERIFICATION
     # ----------------------------------------------
@@ -169,6 +300,116 @@ class CryptoUtils:
             return None
 
 
+    # ----------------------------------------------
+    # 🔐 PASSPHRASE ENCRYPTION
+    # ----------------------------------------------
+    # scrypt cost parameters for new files; stored alongside each file so they
+    # can be raised later without breaking old exports.
+    SCRYPT_N = 2 ** 15
+    SCRYPT_R = 8
+    SCRYPT_P = 1
+
+    def _passphrase_key(self, passphrase, salt, n, r, p):
+        return Scrypt(salt=salt, length=32, n=n, r=r, p=p).derive(passphrase.encode())
+
+    def encrypt_with_passphrase(self, passphrase, plaintext):
+        """Encrypt a string with a key derived from `passphrase` (scrypt + AES-GCM)."""
+        salt = os.urandom(16)
+        key = self._passphrase_key(passphrase, salt, self.SCRYPT_N, self.SCRYPT_R, self.SCRYPT_P)
+        return {
+            "kdf": {"name": "scrypt", "salt": salt.hex(), "n": self.SCRYPT_N, "r": self.SCRYPT_R, "p": self.SCRYPT_P},
+            "encryptedBody": self._aes_encrypt(plaintext, key),
+        }
+
+    def decrypt_with_passphrase(self, passphrase, sealed):
+        """Decrypt the output of `encrypt_with_passphrase`; raises ValueError on a wrong passphrase."""
+        kdf = sealed["kdf"]
+        if kdf.get("name") != "scrypt":
+            raise ValueError(f"Unsupported key derivation: {kdf.get('name')}")
+        key = self._passphrase_key(passphrase, bytes.fromhex(kdf["salt"]), kdf["n"], kdf["r"], kdf["p"])
+        try:
+            return self._aes_decrypt(sealed["encryptedBody"], key)
+        except InvalidTag:
+            raise ValueError("Wrong passphrase or corrupted file")
+
+    # ----------------------------------------------
+    # ☁️ REMOTE BACKUP
+    # ----------------------------------------------
+    def backup_keys(self, seed):
+        """
+        Derives what a remote backup needs from a mnemonic seed: the public
+        blob id, the write token the server checks, and the encryption key.
+        """
+        def derive(info, length=32):
+            return HKDF(algorithm=hashes.SHA256(), length=length, salt=None, info=info).derive(seed)
+
+        return {
+            "id": derive(b"nymchat backup id").hex(),
+            "token": derive(b"nymchat backup token").hex(),
+            "key": derive(b"nymchat backup key"),
+        }
+
+    def seal_chunk(self, key, backup_id, index, data):
+        """Encrypt one backup chunk; the id and index are authenticated so chunks cannot be swapped."""
+        iv = os.urandom(12)
+        encryptor = Cipher(algorithms.AES(key), modes.GCM(iv)).encryptor()
+        encryptor.authenticate_additional_data(f"{backup_id}:{index}".encode())
+        ciphertext = encryptor.update(data) + encryptor.finalize()
+        return (iv + ciphertext + encryptor.tag).hex()
+
+    def open_chunk(self, key, backup_id, index, sealed):
+        """Decrypt a chunk from `seal_chunk`; raises ValueError if it was tampered with."""
+        raw = bytes.fromhex(sealed)
+        iv, ciphertext, tag = raw[:12], raw[12:-16], raw[-16:]
+        decryptor = Cipher(algorithms.AES(key), modes.GCM(iv, tag)).decryptor()
+        decryptor.authenticate_additional_data(f"{backup_id}:{index}".encode())
+        try:
+            return decryptor.update(ciphertext) + decryptor.finalize()
+        except InvalidTag:
+            raise ValueError(f"Backup chunk {index} failed authentication")
+
+    def _backup_state_path(self, username):
+        return os.path.join(self.storage_dir, username, f"{username}_backup.json")
+
+    def load_backup_state(self, username):
+        """The backup seed and chunk digests saved for `username`, or None if backups are off."""
+        try:
+            with open(self._backup_state_path(username)) as f:
+                return json.load(f)
+        except FileNotFoundError:
+            return None
+
+    def save_backup_state(self, username, state):
+        path = self._backup_state_path(username)
+        with open(os.open(f"{path}.tmp", os.O_WRONLY | os.O_CREAT | os.O_TRUNC, 0o600), "w") as f:
+            json.dump(state, f)
+        os.replace(f"{path}.tmp", path)
+
+    def delete_backup_state(self, username):
+        try:
+            os.remove(self._backup_state_path(username))
+        except FileNotFoundError:
+            pass
+
+    # ----------------------------------------------
+    # ⚙️ ASYNC FACADE
+    # ----------------------------------------------
+    async def run(self, function, *args):
+        """Runs `function(*args)` on the crypto worker pool and awaits the result."""
+        return await asyncio.get_running_loop().run_in_executor(worker_pool(), function, *args)
+
+    async def sign_message_async(self, private_key, message):
+        return await self.run(self.sign_message, private_key, message)
+
+    async def verify_signature_async(self, public_key, message, signature):
+        return await self.run(self.verify_signature, public_key, message, signature)
+
+    async def encrypt_message_async(self, recipient_public_key_pem, message):
+        return await self.run(self.encrypt_message, recipient_public_key_pem, message)
+
+    async def decrypt_message_async(self, recipient_private_key, encrypted_message):
+        return await self.run(self.decrypt_message, recipient_private_key, encrypted_message)
+
     # ----------------------------------------------
     # 🛡 AES-GCM ENCRYPTION HELPERS
     # ----------------------------------------------
@@ -0,0 +1,178 @@
+"""
+Diagnostic bundles to attach to bug reports: recent log records, connection
+statistics, the result of the database's integrity checks and version
+information, in one JSON file.
+
+Nothing secret leaves in one. Keys, signatures, ciphertexts and nym addresses
+are replaced by placeholders, the account's username by "[me]" and each
+contact by "[contact N]"; messages themselves are never included.
+
+Made from the log viewer in the app, or from the command line for accounts
+that are not signed in:
+
+    python diagnostics.py <username> [--storage DIR] [--log-file FILE] [--output FILE]
+"""
+import argparse
+import json
+import os
+import platform
+import re
+import sys
+from datetime import datetime, timezone
+import async_ffi
+from async_ffi import PyDb
+from logUtils import LOG_FILE, DEFAULT_LOG_BUFFER_SIZE
+import timeUtils
+
+BUNDLE_FORMAT = "nymchat-diagnostics"
+BUNDLE_VERSION = 1
+
+BASE58 = "[1-9A-HJ-NP-Za-km-z]"
+# What is redacted, in this order: a key block would otherwise be taken apart
+# by the patterns after it.
+REDACTIONS = [
+    (re.compile(r"-----BEGIN [A-Z ]+-----.*?-----END [A-Z ]+-----", re.S), "[key]"),
+    (re.compile(rf"{BASE58}{{32,}}\.{BASE58}{{32,}}@{BASE58}{{32,}}"), "[nym address]"),
+    (re.compile(r"\b[0-9a-fA-F]{32,}\b"), "[hex]"),
+    # Base64, told from long paths and names by mixing digits and both cases
+    (re.compile(r"(?=[A-Za-z0-9+/_-]*\d)(?=[A-Za-z0-9+/_-]*[a-z])(?=[A-Za-z0-9+/_-]*[A-Z])"
+                r"(?<![A-Za-z0-9+/_-])[A-Za-z0-9+/_-]{40,}={0,2}"), "[data]"),
+]
+
+# A line of the log file, as logUtils formats it; lines that do not match
+# continue the record before them.
+LOG_LINE = re.compile(r"^(\d{4}-\d\d-\d\d \d\d:\d\d:\d\d,\d{3}) - (\w+) - (\S+) - (.*)$")
+
+
+def redact(text, names=None):
+    """ `text` with secrets replaced; `names` maps usernames to what stands in for them. """
+    for pattern, placeholder in REDACTIONS:
+        text = pattern.sub(placeholder, text)
+    # Longer names first, so a name containing another is replaced whole.
+    for name in sorted(names or {}, key=len, reverse=True):
+        text = re.sub(rf"(?<![\w-]){re.escape(name)}(?![\w-])", names[name], text)
+    return text
+
+
+def _scrub(value, names):
+    if isinstance(value, str):
+        return redact(value, names)
+    if isinstance(value, dict):
+        return {key: _scrub(item, names) for key, item in value.items()}
+    if isinstance(value, (list, tuple)):
+        return [_scrub(item, names) for item in value]
+    return value
+
+
+def buffered_records(entries):
+    """ `logUtils.LogEntry`s from the log viewer's buffer, as bundle records. """
+    return [{"time": datetime.fromtimestamp(entry.timestamp, timezone.utc).isoformat(timespec="milliseconds"),
+             "level": entry.level_name, "source": entry.target, "message": entry.message}
+            for entry in entries]
+
+
+def file_records(path=LOG_FILE, limit=DEFAULT_LOG_BUFFER_SIZE):
+    """ The last `limit` records of the log file at `path`, as bundle records; none without one. """
+    records = []
+    try:
+        with open(path, encoding="utf-8", errors="replace") as f:
+            for line in f:
+                line = line.rstrip("\n")
+                match = LOG_LINE.match(line)
+                if match:
+                    time, level, source, message = match.groups()
+                    records.append({"time": time, "level": level, "source": source, "message": message})
+                    if len(records) > limit:
+                        del records[0]
+                elif records:
+                    records[-1]["message"] += "\n" + line
+    except OSError:
+        return []
+    return records
+
+
+def check_database(db, username):
+    """ What the database's integrity checks found for `username`, and what is in it. """
+    return {
+        "integrity": db.integrity_check(),
+        "auditLogTamperedAt": db.verify_audit_log(username),
+        "contacts": len(db.get_all_contacts(username)),
+        "groups": len(db.get_groups(username)),
+        "quarantined": db.count_quarantine(username),
+    }
+
+
+def collect(records, db=None, username=None, stats=None, health=None):
+    """
+    A redacted bundle of the log `records` (see `buffered_records` and
+    `file_records`), the connection `stats` (see
+    `MessageHandler.network_stats`), the last server `health` probe and, with
+    a `db`, the checks of `username`'s database.
+    """
+    names = {}
+    database = None
+    if db is not None and username:
+        names[username] = "[me]"
+        for index, (contact, _key) in enumerate(db.get_all_contacts(username), start=1):
+            names.setdefault(contact, f"[contact {index}]")
+        try:
+            database = check_database(db, username)
+        except Exception as e:
+            database = {"error": str(e)}
+    bundle = {
+        "format": BUNDLE_FORMAT,
+        "version": BUNDLE_VERSION,
+        "created": timeUtils.utc_stamp(),
+        "versions": {
+            "client": async_ffi.__version__,
+            "python": platform.python_version(),
+            "platform": platform.platform(),
+            "server": (health or {}).get("version"),
+        },
+        "connection": stats,
+        "server": {key: value for key, value in (health or {}).items() if key != "version"} or None,
+        "database": database,
+        "logs": records,
+    }
+    return _scrub(bundle, names)
+
+
+def write_bundle(bundle, path):
+    with open(path, "w", encoding="utf-8") as f:
+        json.dump(bundle, f, indent=2, ensure_ascii=False)
+        f.write("\n")
+
+
+def bundle_filename():
+    """ A file name for a bundle made now. """
+    return f"nymchat-diagnostics-{datetime.now().strftime('%Y%m%d-%H%M%S')}.json"
+
+
+def main(argv=None):
+    parser = argparse.ArgumentParser(description="Write a redacted nymchat diagnostic bundle for a bug report.")
+    parser.add_argument("username", nargs="?", help="the account whose database is checked; none leaves it out")
+    parser.add_argument("--storage", default="storage", help="the client's storage directory")
+    parser.add_argument("--log-file", default=LOG_FILE, help="the client's log file")
+    parser.add_argument("--output", help="file to write; standard output by default")
+    args = parser.parse_args(argv)
+
+    db = None
+    if args.username:
+        if not os.path.isdir(os.path.join(args.storage, args.username)):
+            parser.error(f"no account {args.username!r} in {args.storage}")
+        db = PyDb.open(args.username, args.storage)
+        db.create_user_tables(args.username)
+    try:
+        bundle = collect(file_records(args.log_file), db, args.username)
+    finally:
+        if db is not None:
+            db.close()
+    if args.output:
+        write_bundle(bundle, args.output)
+    else:
+        json.dump(bundle, sys.stdout, indent=2, ensure_ascii=False)
+        sys.stdout.write("\n")
+
+
+if __name__ == "__main__":
+    main()
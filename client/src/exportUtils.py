@@ -0,0 +1,136 @@
+"""
+Export of a conversation to Markdown or plain text.
+
+Each message is written with its sender, its time in the display zone (see
+timeUtils) and the names of any files sent with it; the files themselves are
+not copied. A date range keeps the messages sent from the first day to the
+last, both included, as days in the display zone.
+
+Used by the `/export` chat command, and from the command line for accounts
+that are not signed in:
+
+    python exportUtils.py <username> <contact or group:<id>> [--format md|txt]
+        [--since YYYY-MM-DD] [--until YYYY-MM-DD] [--output FILE]
+"""
+import argparse
+import os
+import sys
+from async_ffi import PyDb
+from datetime import date, datetime, time, timedelta
+from i18n import _
+from messageHandler import GROUP_CHAT_PREFIX
+import timeUtils
+
+FORMATS = ("md", "txt")
+
+
+def parse_day(text):
+    """ A "YYYY-MM-DD" day, or None for an empty one; ValueError if it is not a day. """
+    try:
+        return date.fromisoformat(text) if text else None
+    except ValueError:
+        raise ValueError(_("{text} is not a date; use YYYY-MM-DD.", text=text)) from None
+
+
+def _day_start(day):
+    """ Midnight starting `day` in the display zone (naive, and so local, without one). """
+    return datetime.combine(day, time(), tzinfo=timeUtils.display_zone)
+
+
+def stamp_range(since=None, until=None):
+    """ Stored timestamps bounding the days `since` to `until`; None leaves a side open. """
+    if since and until and since > until:
+        raise ValueError(_("The range ends before it starts."))
+    first = timeUtils.utc_stamp(_day_start(since)) if since else None
+    last = timeUtils.utc_stamp(_day_start(until + timedelta(days=1)) - timedelta(seconds=1)) if until else None
+    return first, last
+
+
+def _time(stamp):
+    moment = timeUtils.parse_stamp(stamp)
+    return stamp if moment is None else timeUtils.local(moment).strftime("%Y-%m-%d %H:%M")
+
+
+def render(rows, title, fmt="md", since=None, until=None):
+    """
+    `rows` of `(sender, kind, message, attachments, timestamp)`, as returned by
+    `PyDb.export_messages`, as a document in `fmt`.
+    """
+    if fmt not in FORMATS:
+        raise ValueError(_("Unknown export format {fmt}.", fmt=fmt))
+    markdown = fmt == "md"
+    lines = [f"# {title}" if markdown else title]
+    if since or until:
+        lines.append(_("From {since} to {until}", since=since or "…", until=until or "…"))
+    lines.append("")
+    for sender, _kind, message, attachments, stamp in rows:
+        if markdown:
+            lines.append(f"**{sender}** · {_time(stamp)}  ")
+            lines.extend(f"{line}  " for line in message.splitlines())
+            lines.extend(f"📎 {_('Attachment')}: `{name}`  " for name in attachments)
+            lines.append("")
+        else:
+            body = message.replace("\n", "\n    ")
+            lines.append(f"[{_time(stamp)}] {sender}: {body}")
+            lines.extend(f"    [{_('Attachment')}: {name}]" for name in attachments)
+    if not rows:
+        lines.append(_("No messages."))
+    return "\n".join(lines).rstrip() + "\n"
+
+
+def export_conversation(db, active_user, chat, fmt="md", since=None, until=None, title=None):
+    """
+    The conversation `chat` (a username, or `group:<id>`) of `active_user`
+    between the days `since` and `until`, rendered in `fmt`.
+    """
+    first, last = stamp_range(since, until)
+    if chat.startswith(GROUP_CHAT_PREFIX):
+        group_id = chat.removeprefix(GROUP_CHAT_PREFIX)
+        rows = db.export_group_messages(active_user, group_id, first, last)
+        name = dict(db.get_groups(active_user)).get(group_id, group_id)
+        title = title or _("Group {name}", name=name)
+    else:
+        rows = db.export_messages(active_user, chat, first, last)
+    return render(rows, title or _("Chat with {name}", name=chat), fmt, since, until)
+
+
+def export_filename(chat, fmt):
+    """ A file name for an export of `chat` made now. """
+    safe = "".join(c if c.isalnum() or c in "-_" else "_" for c in chat)
+    return f"{safe}-{datetime.now().strftime('%Y%m%d-%H%M%S')}.{fmt}"
+
+
+def main(argv=None):
+    parser = argparse.ArgumentParser(description="Export a nymchat conversation to Markdown or plain text.")
+    parser.add_argument("username", help="the account whose messages are exported")
+    parser.add_argument("chat", help="a contact's username, or group:<group id>")
+    parser.add_argument("--format", choices=FORMATS, default="md")
+    parser.add_argument("--since", help="first day to include, YYYY-MM-DD")
+    parser.add_argument("--until", help="last day to include, YYYY-MM-DD")
+    parser.add_argument("--output", help="file to write; standard output by default")
+    parser.add_argument("--storage", default="storage", help="the client's storage directory")
+    args = parser.parse_args(argv)
+
+    timeUtils.configure()
+    if not os.path.isdir(os.path.join(args.storage, args.username)):
+        parser.error(f"no account {args.username!r} in {args.storage}")
+    try:
+        since, until = parse_day(args.since), parse_day(args.until)
+        stamp_range(since, until)
+    except ValueError as e:
+        parser.error(str(e))
+    db = PyDb.open(args.username, args.storage)
+    try:
+        db.create_user_tables(args.username)
+        document = export_conversation(db, args.username, args.chat, args.format, since, until)
+    finally:
+        db.close()
+    if args.output:
+        with open(args.output, "w", encoding="utf-8") as f:
+            f.write(document)
+    else:
+        sys.stdout.write(document)
+
+
+if __name__ == "__main__":
+    main()
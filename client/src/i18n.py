@@ -0,0 +1,78 @@
+"""
+Translations of user-facing text, gettext-style.
+
+The English text is the message id: wrap it in `_()` where it is shown, with
+named `{placeholders}` filled in from keyword arguments. A locale's catalog,
+`locales/<locale>.json`, maps ids to translations; anything missing from it
+is shown in English.
+The locale is NYMCHAT_LOCALE (e.g. in `.env`), falling back to the system's
+LC_ALL, LC_MESSAGES or LANG.
+"""
+import json
+import os
+from logUtils import logger
+
+LOCALES_DIR = os.path.join(os.path.dirname(os.path.abspath(__file__)), "locales")
+
+_catalog = {}
+current_locale = "en"
+
+
+def available_locales():
+    """ Locales with a catalog, plus English, which needs none. """
+    names = {"en"}
+    if os.path.isdir(LOCALES_DIR):
+        names.update(f[:-len(".json")] for f in os.listdir(LOCALES_DIR) if f.endswith(".json"))
+    return sorted(names)
+
+
+def _candidates(locale):
+    """ "pt_BR.UTF-8" -> ["pt_BR", "pt"] """
+    locale = locale.split(".")[0].split("@")[0].replace("-", "_")
+    language = locale.split("_")[0]
+    return [locale, language] if language != locale else [locale]
+
+
+def load_catalog(locale):
+    """
+    Returns the catalog for `locale`, trying the language alone if there is
+    none for the region. Returns None if neither exists.
+    """
+    for name in _candidates(locale):
+        path = os.path.join(LOCALES_DIR, f"{name}.json")
+        if os.path.isfile(path):
+            with open(path, encoding="utf-8") as f:
+                return json.load(f)
+    return None
+
+
+def system_locale():
+    for var in ("NYMCHAT_LOCALE", "LC_ALL", "LC_MESSAGES", "LANG"):
+        value = os.getenv(var)
+        if value and value not in ("C", "POSIX"):
+            return value
+    return "en"
+
+
+def set_locale(locale=None):
+    """ Switches to `locale` (default: `system_locale()`); unknown locales fall back to English. """
+    global _catalog, current_locale
+    locale = locale or system_locale()
+    try:
+        catalog = load_catalog(locale)
+    except (OSError, ValueError) as e:
+        logger.error(f"Could not load the {locale} catalog: {e}")
+        catalog = None
+    if catalog is None:
+        if _candidates(locale)[-1] != "en":
+            logger.warning(f"No translation for {locale}; using English.")
+        _catalog, current_locale = {}, "en"
+        return
+    _catalog, current_locale = catalog, locale
+    logger.info(f"Using the {locale} translation.")
+
+
+def _(message, **kwargs):
+    """ `message` in the current locale, with `kwargs` filled into its placeholders. """
+    text = _catalog.get(message) or message
+    return text.format(**kwargs) if kwargs else text
@@ -1,15 +1,88 @@
 import logging
 import os
+from collections import deque
+from typing import NamedTuple
 
 # Configure logging
 LOG_FILE = os.path.join(os.getcwd(), "storage", "app.log")
 
+# How many of the most recent records the log viewer keeps; older ones are dropped
+DEFAULT_LOG_BUFFER_SIZE = 1000
+
+
+class LogEntry(NamedTuple):
+    """ One log record as the viewer shows it. """
+    level: int
+    target: str
+    timestamp: float
+    message: str
+
+    @property
+    def level_name(self):
+        return logging.getLevelName(self.level)
+
+
+class LogBuffer(logging.Handler):
+    """
+    Keeps the last `capacity` records in memory for the log viewer, so a long
+    session does not grow without bound. The log file keeps everything.
+    """
+
+    def __init__(self, capacity=DEFAULT_LOG_BUFFER_SIZE):
+        super().__init__()
+        self.records = deque(maxlen=max(1, capacity))
+
+    @property
+    def capacity(self):
+        return self.records.maxlen
+
+    def set_capacity(self, capacity):
+        """ Keeps the newest `capacity` records from now on. """
+        with self.lock:
+            self.records = deque(self.records, maxlen=max(1, capacity))
+
+    def emit(self, record):
+        try:
+            message = record.getMessage()
+            if record.exc_info:
+                message += "\n" + logging.Formatter().formatException(record.exc_info)
+            self.records.append(LogEntry(record.levelno, record.name, record.created, message))
+        except Exception:
+            self.handleError(record)
+
+    def entries(self, level=logging.NOTSET):
+        """ The kept records at `level` or above, oldest first. """
+        with self.lock:
+            records = list(self.records)
+        return [entry for entry in records if entry.level >= level]
+
+    def clear(self):
+        with self.lock:
+            self.records.clear()
+
+
+log_buffer = LogBuffer()
+
+
+def configure_log_buffer(capacity=None):
+    """
+    Sets how many records the log viewer keeps, by default from
+    NYMCHAT_LOG_BUFFER; invalid values fall back to the default.
+    """
+    try:
+        capacity = int(capacity or os.getenv("NYMCHAT_LOG_BUFFER") or DEFAULT_LOG_BUFFER_SIZE)
+    except ValueError:
+        capacity = DEFAULT_LOG_BUFFER_SIZE
+    log_buffer.set_capacity(capacity)
+
+
 logging.basicConfig(
     level=logging.INFO,
     format="%(asctime)s - %(levelname)s - %(name)s - %(message)s",
     handlers=[
         logging.FileHandler(LOG_FILE),  # Log to file
-        logging.StreamHandler()  # Log to console
+        logging.StreamHandler(),  # Log to console
+        log_buffer,  # Kept for the log viewer
     ]
 )
 
@@ -1,13 +1,353 @@
+import os
+import re
+import math
 import json
+import base64
+import time
+import hashlib
+import hmac
+import random
 import asyncio
+import secrets
+import mimetypes
+import mixnetMessages
+from collections import deque
+from datetime import datetime, timezone
 from nicegui import ui
-from datetime import datetime
 from cryptography.hazmat.primitives import serialization
 from mixnetMessages import MixnetMessage
 from cryptographyUtils import CryptoUtils
 from connectionUtils import MixnetConnectionClient
-from dbUtils import SQLiteManager
+from async_ffi import (COMPACT_FEATURE, MAX_FILE_SIZE, RATCHET_FEATURE, ContactInvite, Delegation, FileDownload,
+                       FileUpload, GroupSession, HandshakeHello, MembershipLog, PyDb, RatchetSession, decode_envelope,
+                       encode_envelope, solve_pow, mnemonic_from_entropy, mnemonic_to_seed)
 from logUtils import logger
+from i18n import _
+from pipeline import Inbound, Pipeline, dedupe
+import timeUtils
+
+# Actions the server relays without vouching for them: chat messages are signed
+# end to end by their sender and may also arrive straight from a peer.
+NOT_SERVER_SIGNED = {"incomingMessage"}
+
+# Seconds to wait for the server to answer a register, login or query
+RESPONSE_TIMEOUT = 60
+
+# Seconds before a failed write of incoming messages is tried again
+WRITE_RETRY_DELAY = 5
+
+# Logins are renewed once this share of the server's session lifetime has
+# passed, but never sooner than SESSION_MIN_RENEWAL seconds after the last one
+SESSION_RENEW_AT = 0.8
+SESSION_MIN_RENEWAL = 30
+
+# Heartbeats tell the server we are reachable, so it delivers to us at once
+# rather than only queueing; see its heartbeat module. One goes out every
+# HEARTBEAT_INTERVAL seconds, moved by up to HEARTBEAT_JITTER of that either
+# way so they do not tick like a clock. Whether they are sent is a privacy
+# setting, kept under HEARTBEAT_SETTING.
+HEARTBEAT_INTERVAL = 60
+HEARTBEAT_JITTER = 0.5
+HEARTBEAT_SETTING = "send_heartbeats"
+
+# Server errors that mean we have to log in again
+SESSION_ERRORS = ("error: session expired", "error: not logged in")
+
+# Messages of the server's offline queue asked for at a time; the next page is
+# only fetched once this one is handled and acked
+PENDING_PAGE_SIZE = 25
+
+# Marks files written by `export_account`; bump the version if the bundle changes shape.
+# Version 2 added whether each contact's key is verified and trusted, and its key changes.
+ACCOUNT_EXPORT_FORMAT = "nymchat-account"
+ACCOUNT_EXPORT_VERSION = 2
+
+# Plaintext bytes per remote backup chunk; only chunks that changed are re-sent
+BACKUP_CHUNK_SIZE = 8 * 1024
+# Most chunks a restored backup may claim to have, well above the server's default limit
+MAX_BACKUP_CHUNKS = 4096
+
+# Group chats are keyed "group:<group id>" in the UI; usernames cannot contain ":"
+GROUP_CHAT_PREFIX = "group:"
+
+# Direct message type of handshakes. Older clients send a bare nym address;
+# current ones send a hello, and the contact a reply and a confirmation that
+# both carry the transcript hash of the two hellos, so neither can be altered
+# on the way (see nymchat_protocol's handshake module).
+HANDSHAKE_MESSAGE = 1
+# What we accept from a contact once a handshake agrees on it: sends sealed
+# inside the end-to-end encryption, gossiped key observations, compact
+# envelopes and Double Ratchet sessions
+HANDSHAKE_FEATURES = ["keyObservation", "sealedSender", COMPACT_FEATURE, RATCHET_FEATURE]
+
+# How direct and group chats are encrypted, and how their keys move on, as the
+# chat header names them. A direct message is encrypted to the contact's key
+# with a fresh ephemeral key; a group moves along each member's sender key chain.
+DIRECT_ENCRYPTION = {"algorithm": "ECDH P-256 + AES-256-GCM", "ratchet": "ephemeral key per message"}
+GROUP_ENCRYPTION = {"algorithm": "AES-256-GCM", "ratchet": "sender key chain (HMAC-SHA256)"}
+# Direct chats with a Double Ratchet session move on to new keys as well
+DOUBLE_RATCHET = "Double Ratchet (X3DH over the handshake)"
+
+# Direct message type carrying another direct message, encrypted under the
+# Double Ratchet session the last handshake set up (see nymchat_protocol's
+# ratchet module): {"header": {"ratchetKey", "previousChain", "index"},
+# "body": {"iv", "ciphertext", "tag"}}. Handshakes are never sent this way,
+# so a contact who lost their session can always set up a new one.
+RATCHET_MESSAGE = 13
+
+# Direct message type carrying a sender key for a group
+SENDER_KEY_MESSAGE = 3
+# Direct message type carrying a group's signed membership log
+GROUP_CONTROL_MESSAGE = 4
+
+# Direct message type asking an admin to redeem a group invite for the sender
+GROUP_JOIN_MESSAGE = 5
+
+# Direct message type carrying part of a group's recent history for a new member
+GROUP_HISTORY_MESSAGE = 6
+
+# History sent to a new member is at most this many messages, none older than
+# MAX_SHARED_HISTORY_AGE seconds, and never more than the group's policy allows
+MAX_SHARED_HISTORY = 200
+MAX_SHARED_HISTORY_AGE = 30 * 86400
+# It goes out in parts of about this many bytes of JSON
+HISTORY_CHUNK_SIZE = 8 * 1024
+# Shared histories being received at once, before new ones are refused
+MAX_PENDING_HISTORIES = 16
+
+# Group message types besides text (0): a poll, and a vote in one
+POLL_MESSAGE = 7
+VOTE_MESSAGE = 8
+MAX_POLL_OPTIONS = 10
+
+# Direct message carrying a location: {"lat", "lon", "accuracy" (metres, or
+# null), "label" (may be empty)}
+LOCATION_MESSAGE = 9
+MAX_LOCATION_LABEL = 100
+# How a location reads in the chat history; kept free of any language so it
+# can be recognised again whoever wrote it
+LOCATION_PATTERN = re.compile(r"📍 (?:(.+) )?\((-?\d{1,2}\.\d+), (-?\d{1,3}\.\d+)\)(?: ±(\d+) m)?")
+
+# A chat message sent on from another chat carries "forwardedFrom": {"sender":
+# the user who wrote it}. It reads in the chat history as FORWARD_MARK, the
+# sender and a line break before the text, free of any language like a location.
+FORWARD_MARK = "↪ "
+FORWARD_PATTERN = re.compile(FORWARD_MARK + r"([A-Za-z0-9_-]+)\n(.*)", re.DOTALL)
+
+# Direct message types telling a contact we received or read their messages,
+# and that we are typing. Whether read receipts and typing indicators are sent
+# is the user's choice, per contact or for everyone, whatever they choose to
+# see from others; delivery receipts are always sent.
+RECEIPT_MESSAGE = 10
+TYPING_MESSAGE = 11
+# The privacy setting deciding whether each is sent, and its default
+PRIVACY_SETTINGS = {RECEIPT_MESSAGE: "receipts", TYPING_MESSAGE: "typing"}
+DEFAULT_PRIVACY = {"receipts": True, "typing": True}
+# A receipt ({"ids": [...], "status": ...}) names chat messages by the "id"
+# they were sent with, at most MAX_RECEIPT_IDS at a time.
+RECEIPT_STATUSES = ("delivered", "read")
+MAX_RECEIPT_IDS = 100
+MAX_MESSAGE_ID = 64
+# How far a sent message got, in order; errors rank below all of them.
+DELIVERY_ORDER = {"sending": 0, "waiting": 0, "sent": 1, "delivered": 2, "read": 3}
+# A typing indicator (message True) is sent at most every TYPING_INTERVAL
+# seconds to a contact while the user types, and shown for TYPING_TIMEOUT
+# seconds after the last one arrived, or until their message does.
+TYPING_INTERVAL = 5
+TYPING_TIMEOUT = 8
+
+# Direct message types of a file transfer (see nymchat_core's transfer
+# module): the manifest, sent like a chat message and stored as FILE_MARK and
+# the file's name, then every encrypted chunk ({"id", "seq", "data"}), which
+# is neither stored nor tracked. Files are only sent while online, never
+# through the outbox, and kept under the downloads folder (DOWNLOADS_SETTING,
+# or "downloads" in the account's folder) once they arrived whole.
+FILE_MESSAGE = 14
+FILE_CHUNK_MESSAGE = 15
+FILE_MARK = "📎 "
+FILE_PATTERN = re.compile(FILE_MARK + r"([^./\\\n][^/\\\n]*)")
+DOWNLOADS_SETTING = "downloads_dir"
+# Most files received at once, and how long one waits for its next chunk (seconds)
+MAX_INCOMING_TRANSFERS = 4
+TRANSFER_TIMEOUT = 600
+# Chunks that arrive before their manifest are kept, up to this many
+MAX_EARLY_CHUNKS = 64
+
+# A bot or daemon signs with a sub-key its user delegated to it (see
+# nymchat_protocol's delegation module). What it sends carries "delegation":
+# {"certificate": the signed JSON, "signature": the identity key's}, is always
+# sealed, and can only be of these types.
+DELEGATED_MESSAGE_TYPES = (0, LOCATION_MESSAGE)
+# All that is taken from a contact whose changed key the user has not confirmed
+# yet: shown under the chat's key-change warning, and nothing else applied.
+UNTRUSTED_MESSAGE_TYPES = (0, LOCATION_MESSAGE)
+# What a delegation may grant, and how long one lasts unless the user says otherwise
+SCOPE_SEND = "send"
+DEFAULT_DELEGATION_LIFETIME = 30 * 86400
+# Direct message type revoking a delegation of the sender: {"id": its id}.
+# Only their identity key can send it.
+REVOCATION_MESSAGE = 12
+
+# How a chat notifies: every message, only mentions of us (groups), or never.
+# A muted chat is at "none" until its mute ends; MUTE_FOREVER mutes with no end.
+NOTIFY_LEVELS = ("all", "mentions", "none")
+MUTE_FOREVER = -1
+
+# Invite codes are this prefix followed by base64url JSON:
+# {"g": group id, "s": invite secret, "a": admin to ask, "n": group name, "e": expiry or null}
+INVITE_CODE_PREFIX = "nymchat-invite:"
+
+# "@username" in a group message; usernames are letters, digits, "-" and "_"
+MENTION_PATTERN = re.compile(r"(?<![\w@-])@([A-Za-z0-9_-]+)")
+
+# Most reply SURBs sent at once when the server asks for more
+MAX_SURB_TOP_UP = 100
+
+# Contacts in anonymous mode never learn our nym address: our hello leaves it
+# out, and what we send to theirs carries reply SURBs for them to answer with.
+# How many SURBs a contact's sender tag still holds is estimated as the server
+# does (see nymchat_server's surbs module): SURBS_PER_MESSAGE for each message
+# that came with it, one per SURB_PAYLOAD_BYTES of each reply. Below
+# SURB_LOW_WATER we ask them for SURB_TOP_UP more with a direct message of
+# type SURB_MESSAGE, {"surbs": count}; they answer with {"topUp": count}, sent
+# with that many attached.
+SURB_MESSAGE = 16
+SURBS_PER_MESSAGE = 10
+SURB_PAYLOAD_BYTES = 2000
+SURB_LOW_WATER = 4
+SURB_TOP_UP = 20
+
+# Most messages a search returns, across all conversations
+SEARCH_LIMIT = 200
+
+# Health probe round trips averaged into the latency the stats screen shows
+LATENCY_SAMPLES = 20
+
+# Sender keys that arrived before the membership entries admitting their
+# sender are kept for later, up to this many
+MAX_PENDING_SENDER_KEYS = 256
+
+# Settings key of the snapshot of what is in flight, see save_snapshot
+SNAPSHOT_SETTING = "snapshot"
+
+# While the user is away, chat messages are answered with their away message,
+# at most once per contact per interval (seconds). The answer carries
+# "autoReply": true, and is never answered itself. Settings keys of the away
+# mode ({"message", "interval"}, null while not away) and of the contacts
+# never answered (a list)
+AWAY_SETTING = "away"
+AWAY_OPT_OUT_SETTING = "away_opt_out"
+DEFAULT_AWAY_INTERVAL = 3600
+
+
+def group_chat_id(group_id):
+    return GROUP_CHAT_PREFIX + group_id
+
+
+def invite_code(group_id, secret, admin, name, expires_at=None):
+    """ The shareable code for an invite; `expires_at` is in Unix seconds """
+    invite = {"g": group_id, "s": secret, "a": admin, "n": name, "e": expires_at}
+    return INVITE_CODE_PREFIX + base64.urlsafe_b64encode(json.dumps(invite).encode()).decode().rstrip("=")
+
+
+def parse_invite(code):
+    """ The fields of an invite code, as `invite_code` took them, or None if it is not one. """
+    code = code.strip()
+    if not code.startswith(INVITE_CODE_PREFIX):
+        return None
+    data = code[len(INVITE_CODE_PREFIX):]
+    try:
+        invite = json.loads(base64.urlsafe_b64decode(data + "=" * (-len(data) % 4)))
+    except ValueError:
+        return None
+    if (not isinstance(invite, dict)
+            or not all(isinstance(invite.get(k), str) and invite[k] for k in ("g", "s", "a"))
+            or not isinstance(invite.get("n", ""), str)
+            or not isinstance(invite.get("e"), (int, type(None)))):
+        return None
+    return {"group_id": invite["g"], "secret": invite["s"], "admin": invite["a"],
+            "name": invite.get("n") or invite["g"], "expires_at": invite.get("e")}
+
+
+def parse_location(location):
+    """
+    A location message as {"lat", "lon", "accuracy", "label"}, rounded to what
+    is worth keeping, or None if it is not a valid one.
+    """
+    def number(value):
+        return isinstance(value, (int, float)) and not isinstance(value, bool) and math.isfinite(value)
+
+    if not isinstance(location, dict):
+        return None
+    lat, lon, accuracy, label = (location.get(k) for k in ("lat", "lon", "accuracy", "label"))
+    if not number(lat) or not number(lon) or not -90 <= lat <= 90 or not -180 <= lon <= 180:
+        return None
+    if accuracy is not None and (not number(accuracy) or accuracy < 0):
+        return None
+    if not isinstance(label or "", str) or len(label or "") > MAX_LOCATION_LABEL:
+        return None
+    return {"lat": round(lat, 6), "lon": round(lon, 6), "accuracy": None if accuracy is None else round(accuracy),
+            "label": " ".join((label or "").split())}
+
+
+def location_text(location):
+    """ A location, as `parse_location` returns it, as one line of chat text """
+    text = f"({location['lat']:.6f}, {location['lon']:.6f})"
+    if location["label"]:
+        text = f"{location['label']} {text}"
+    if location["accuracy"] is not None:
+        text += f" ±{location['accuracy']} m"
+    return "📍 " + text
+
+
+def location_from_text(text):
+    """ The location a chat line written by `location_text` shows, or None """
+    match = LOCATION_PATTERN.fullmatch(text)
+    if not match:
+        return None
+    label, lat, lon, accuracy = match.groups()
+    return parse_location({"lat": float(lat), "lon": float(lon), "label": label or "",
+                           "accuracy": None if accuracy is None else int(accuracy)})
+
+
+def osm_link(location):
+    """ The location on the OpenStreetMap website """
+    lat, lon = f"{location['lat']:.6f}", f"{location['lon']:.6f}"
+    return f"https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map=16/{lat}/{lon}"
+
+
+def forwarded_text(sender, text):
+    """ A message forwarded from `sender` as chat text """
+    return f"{FORWARD_MARK}{sender}\n{text}"
+
+
+def forwarded_from(text):
+    """ `(sender, text)` of a chat line written by `forwarded_text`, or None """
+    match = FORWARD_PATTERN.fullmatch(text)
+    return match.groups() if match else None
+
+
+def file_text(name):
+    """ A file sent or received as chat text """
+    return FILE_MARK + name
+
+
+def file_from_text(text):
+    """ The name of the file a chat line written by `file_text` shows, or None """
+    match = FILE_PATTERN.fullmatch(text)
+    return match.group(1) if match else None
+
+
+def _forwarded_sender(message_obj):
+    """ The sender a chat message says it was forwarded from, or None """
+    forwarded = message_obj.get("forwardedFrom")
+    sender = forwarded.get("sender") if isinstance(forwarded, dict) else None
+    return sender if isinstance(sender, str) and re.fullmatch(r"[A-Za-z0-9_-]+", sender) else None
+
+
+def mentioned_usernames(text):
+    """ The usernames `text` mentions with "@" """
+    return set(MENTION_PATTERN.findall(text))
 
 class MessageHandler:
     def __init__(self, crypto_utils: CryptoUtils, connection_client: MixnetConnectionClient):
@@ -17,17 +357,152 @@ class MessageHandler:
         self.temporary_keys = {"private_key": None, "public_key": None}
         self.db_manager = None  # Will be set after login/registration
 
-        # Wait-for-completion events
-        self.registration_complete = asyncio.Event()
-        self.login_complete = asyncio.Event()
-        
-        # Registration / login status (success or failure)
-        self.registration_successful = None
-        self.login_successful = None
+        # Register, login and account deletion share the temporary keys, so only
+        # one runs at a time; the challenge response resolves `pending_auth`
+        # with the outcome. `auth_renewal` is set while the login running renews
+        # the open session rather than starting one.
+        self.auth_lock = asyncio.Lock()
+        self.pending_auth = None
+        self.auth_renewal = False
+
+        # The server ends logins after the lifetime it sends once they succeed;
+        # `session_renewal` logs in again before that. If that fails (e.g. the
+        # key file is gone) the session is expired and the UI is told.
+        self.session_renewal = None
+        self.session_expired = False
+        self.session_expired_callback = None
+        # Whether heartbeats are sent, and the next one while logged in
+        self.send_heartbeats = True
+        self.next_heartbeat = None
+        # Id of the last offline queue message handled this login; pages are
+        # fetched after it and redelivered messages up to it are only acked.
+        self.pending_cursor = 0
+
+        # Queries in flight, in send order: [(username, future)]. Any number
+        # can be outstanding, so a search never waits on another one.
+        self.pending_queries = []
+
+        # Routing tokens from query responses, by username: sends name the
+        # recipient by token instead, so usernames stay out of send envelopes.
+        self.use_routing_tokens = mixnetMessages.ROUTING_TOKENS
+        self.routing_tokens = {}
+        # Seal the sender inside the end-to-end encryption of server-routed sends
+        self.use_sealed_sender = mixnetMessages.SEALED_SENDER
+        # Handshakes in flight, by contact: {"initiator": hello, "responder":
+        # hello or None until we answered theirs}, as the JSON sent, and
+        # "ratchetSecret": the secret of the ratchet key in our hello
+        self.handshakes = {}
+
+        # Messages sent through the server and awaiting its sendResponse, in
+        # send order: {send id: recipient}, and what to queue in the outbox to
+        # send them again if we crash first: {send id: outbox content}
+        self.pending_sends = {}
+        self.pending_contents = {}
+        # Delivery state of messages sent this session, or loaded with their
+        # chat, by send id: "sending", "sent", "delivered", "read", or the
+        # server's error (e.g. "error: recipient not found"). Direct chat
+        # messages go out with their send id as "id", which receipts name.
+        self.send_status = {}
+        # Ids of received chat messages not yet answered with a read receipt,
+        # sent once their chat is opened: {username: [id, ...]}
+        self.unreceipted = {}
+        # Typing indicators, never stored: until when each contact shows as
+        # typing, and when we last told each one we are ({username: Unix seconds})
+        self.typing = {}
+        self.typing_sent = {}
+        # Files being sent or received: {transfer id: {"chat", "name",
+        # "incoming", "done" and "total" chunks, and for incoming ones the
+        # FileDownload and when its last chunk came}}, and chunks that came
+        # before their manifest ({transfer id: (Unix seconds, [(sender, chunk)])})
+        self.transfers = {}
+        self.early_chunks = {}
+        self.send_failed_callback = None  # To tell the UI a message was not delivered
 
-        # Query flow
-        self.query_result_event = asyncio.Event()
-        self.query_result = None
+        # Whether the mixnet client is connected. Messages sent while it is not,
+        # or while older ones still wait, go to the outbox (stored in the db)
+        # with the send status "waiting", and are sent in order once it is back.
+        self.online = True
+        self.connection_status = "connected"
+        self.connection_reason = None  # Why it was last dropped or remade, see `set_connection_status`
+        self.outbox_pending = False
+        self.outbox_sends = {}  # {outbox id: send id shown for it}
+        self.flushing_outbox = False
+        # To warn the UI that a contact's key changed: (username, old fingerprint, new fingerprint)
+        self.key_change_callback = None
+        # To warn the UI that a handshake dropped features a contact had
+        # agreed on; chat messages wait until the user accepts: (username, features)
+        self.downgrade_callback = None
+
+        # Key transparency: each contact is sent, once per session, the
+        # server-signed directory answer we got for them; they check it against
+        # their own key. A mismatch means the server gave different keys for
+        # the same username. (contact, our fingerprint, fingerprint they were given)
+        self.gossiped = set()
+        self.equivocations = set()
+        self.equivocation_callback = None
+
+        # Direct messages that fail to parse, decrypt or verify are kept in the
+        # database's quarantine with why, instead of dropped; see quarantined().
+        # To tell the UI one was: (reason, messages now in quarantine)
+        self.quarantine_callback = None
+
+        # Presence of contacts that share it with us:
+        # {username: {"online": bool, "lastSeen": unix seconds or None}}
+        self.presence = {}
+        # Contacts we share our own presence with (opt-in, per contact)
+        self.presence_sharing = set()
+
+        # Whether read receipts and typing indicators are sent: to everyone
+        # ({"receipts": bool, "typing": bool}), and contacts' own settings,
+        # None following the first ({username: {"receipts": ..., "typing": ...}})
+        self.privacy = dict(DEFAULT_PRIVACY)
+        self.contact_privacy = {}
+
+        # Away mode: {"message": text, "interval": seconds}, None while not
+        # away; contacts opted out of it, and when each contact was last
+        # answered ({username: Unix seconds}). See set_away.
+        self.away = None
+        self.away_opt_out = set()
+        self.away_replied = {}
+
+        # Sidebar folders in display order ({name: collapsed}), the folder of
+        # each filed chat ({chat id: name}), and messages received in each chat
+        # while it was not open ({chat id: count}); see load_folders.
+        self.folders = {}
+        self.chat_folders = {}
+        self.unread = {}
+        # Chats' own notification settings:
+        # {chat id: {"level": one of NOTIFY_LEVELS, "mutedUntil": Unix seconds, MUTE_FOREVER or None}}
+        self.chat_notifications = {}
+        # Unsent text typed in each chat ({chat id: text}), and the chats whose
+        # draft changed since it was last written to the database
+        self.drafts = {}
+        self.unsaved_drafts = set()
+
+        # Backup being restored: {"keys": ..., "chunks": {index: data}, "future": ...}
+        self.pending_restore = None
+        # The snapshot last written, so unchanged state is not written again
+        self.last_snapshot = None
+
+        # Server keys pinned with `pin_server_keys`; while any are pinned, server
+        # replies must be signed by one of them.
+        self.server_keys = []
+        self.server_keys_path = None
+        self.transition_requested = False
+
+        # Sender keys waiting for their group's membership log to catch up:
+        # {group id: {username: sender key}}
+        self.pending_sender_keys = {}
+        # Shared group histories arriving in parts:
+        # {(admin, history id): {"groupId": ..., "total": ..., "parts": {index: messages}}}
+        self.pending_histories = {}
+
+        # Health probes in flight, by nonce: (future, send time)
+        self.pending_health = {}
+        # Last successful probe: {"rtt": seconds, "version": ..., "uptime": ..., "server": ...}
+        self.health = None
+        # Round trips of the latest probes to the server in use, in seconds
+        self.latencies = deque(maxlen=LATENCY_SAMPLES)
 
         # [OPTIONAL] references to UI or chat state
         self.chat_messages = None
@@ -37,13 +512,62 @@ class MessageHandler:
         self.chat_list_sidebar_fn = None  # For refreshing the chat list sidebar
         self.chat_container = None
         self.new_message_callback = None  # To notify UI of new messages
+        self.mention_callback = None  # To alert the UI to a mention of us: (chat id, sender, message)
+
+        # The receive path of direct messages; see pipeline.py. Features add
+        # stages of their own with add_inbound_stage. Messages go through it
+        # one at a time, in the order they arrived: the crypto stages wait on
+        # the worker pool, and a message overtaking another could reach the
+        # ratchet before the handshake setting up its session, or a group
+        # before its sender key.
+        self.inbound_lock = asyncio.Lock()
+        self.inbound = Pipeline([
+            ("open", self._open_stage),
+            ("verify", self._verify_stage),
+            ("decrypt", self._decrypt_stage),
+            ("dedupe", dedupe()),
+            ("ratchet", self._ratchet_stage),
+            ("control", self._control_stage),
+            ("content", self._content_stage),
+            ("persist", self._persist_stage),
+            ("emit", self._emit_stage),
+            ("receipt", self._receipt_stage),
+            ("away", self._away_stage),
+        ])
+
+        # Incoming messages waiting to be written, flushed in one transaction
+        # once the event loop has drained the messages that arrived together.
+        self.pending_writes = []
+        self.flush_scheduled = False
+        # Retries a failed write; the messages may already be acked to the server.
+        self.write_retry = None
+
+        # Parts of the UI that changed since the last render ("chat", "sidebar").
+        # Rendering waits until a burst of messages has been handled, so the
+        # chat is rebuilt once per burst instead of once per message.
+        self.dirty = set()
+        self.render_scheduled = False
 
         # Ephemeral mapping of usernames to nym addresses for p2p routing
         self.nym_addresses = {}  # {username: nym_address}
+        # Sender tags of direct messages from correspondents who did not give
+        # us their address; replies go through the SURBs they attached.
+        # Persisted, see load_sender_tags.
+        self.sender_tags = {}  # {username: sender tag}
+        # Estimated reply SURBs left behind each sender tag, and the contacts
+        # asked for more who have not sent any since.
+        self.reply_surbs = {}  # {username: count}
+        self.surbs_requested = set()
+        # Contacts kept from learning our nym address. Persisted, see load_anonymous.
+        self.anonymous = set()
 
         # Store our own nym address (to be set externally after mixnet initialization)
         self.nym_address = None
 
+        # On a bot's device, the delegation its sub-key signs under:
+        # {"certificate": ..., "signature": ...}; see start_delegated.
+        self.delegation = None
+
     def update_nym_address(self, nym_address):
         """Update the client's own nym address in MessageHandler."""
         self.nym_address = nym_address
@@ -66,47 +590,82 @@ class MessageHandler:
     # Registration & Login
     # --------------------------------------------------------------------------
 
-    async def register_user(self, username, first_name="", last_name=""):
+    async def _authenticate(self, msg, renewal=False):
+        """
+        Sends a register/login/deleteAccount request and waits for the challenge
+        flow to finish. A `renewal` login keeps the session it renews open.
+        Returns True on success.
+        """
+        self.pending_auth = asyncio.get_running_loop().create_future()
+        self.auth_renewal = renewal
         try:
-            self.current_user["username"] = username
-            self.registration_complete.clear()
+            await self.connection_client.send_message(msg)
+            return await asyncio.wait_for(self.pending_auth, RESPONSE_TIMEOUT)
+        except asyncio.TimeoutError:
+            logger.error("No response from server.")
+            return False
+        finally:
+            self.pending_auth = None
+            self.auth_renewal = False
 
-            private_key, public_key = self.crypto_utils.generate_key_pair(username)
-            self.temporary_keys["private_key"] = private_key
-            self.temporary_keys["public_key"] = public_key
-            logger.info(f"Keypair generated for user: {username}")
+    def _finish_auth(self, successful):
+        if self.pending_auth and not self.pending_auth.done():
+            self.pending_auth.set_result(successful)
 
-            # Send a 'register' message (only username and public key are used)
-            register_msg = MixnetMessage.register(usernym=username, publicKey=public_key)
-            await self.connection_client.send_message(register_msg)
-            logger.info("Registration message sent; waiting for challenge...")
+    async def register_user(self, username, first_name="", last_name="", passphrase=None):
+        """
+        Registers `username` with a new key pair. With a `passphrase` the
+        private key is stored encrypted with it, and needed to log in.
+        Returns True on success.
+        """
+        async with self.auth_lock:
+            try:
+                self.current_user["username"] = username
 
-            await self.registration_complete.wait()
+                private_key, public_key = self.crypto_utils.generate_key_pair(username)
+                self.temporary_keys["private_key"] = private_key
+                self.temporary_keys["public_key"] = public_key
+                self.temporary_keys["passphrase"] = passphrase
+                logger.info(f"Keypair generated for user: {username}")
 
-        except Exception as e:
-            logger.error(f"Registration error: {e}")
+                # Send a 'register' message (only username and public key are used)
+                register_msg = MixnetMessage.register(usernym=username, publicKey=public_key)
+                logger.info("Sending registration message; waiting for challenge...")
+                return await self._authenticate(register_msg)
 
-    async def login_user(self, username):
-        try:
-            self.current_user["username"] = username
-            self.login_complete.clear()
+            except Exception as e:
+                logger.error(f"Registration error: {e}")
+                return False
 
-            private_key = self.crypto_utils.load_private_key(username)
-            if not private_key:
-                logger.error(f"No private key for {username}")
-                return
+    async def login_user(self, username, passphrase=None):
+        """
+        Logs `username` in with their stored key, opening it with `passphrase`
+        if it is kept in a keystore. Returns True on success.
+        """
+        async with self.auth_lock:
+            try:
+                self.current_user["username"] = username
 
-            self.temporary_keys["private_key"] = private_key
-            logger.info(f"Loaded private key for {username}")
+                if passphrase and not self.crypto_utils.unlock(username, passphrase):
+                    logger.error(f"Wrong passphrase for the keystore of {username}")
+                    self.audit("unlock_failed", "wrong passphrase", username)
+                    return False
+                private_key = self.crypto_utils.load_private_key(username)
+                if not private_key:
+                    logger.error(f"No private key for {username}")
+                    return False
 
-            msg = MixnetMessage.login(username)
-            await self.connection_client.send_message(msg)
-            logger.info("Login message sent; waiting for challenge...")
+                self.temporary_keys["private_key"] = private_key
+                logger.info(f"Loaded private key for {username}")
 
-            await self.login_complete.wait()
+                msg = MixnetMessage.login(username)
+                logger.info("Sending login message; waiting for challenge...")
+                return await self._authenticate(msg)
 
-        except Exception as e:
-            logger.error(f"Login error: {e}")
+            except Exception as e:
+                logger.error(f"Login error: {e}")
+                self.audit("unlock_failed", str(e), username)
+                return False
 
     async def handle_registration_challenge(self, content):
         nonce = content.get("nonce")
@@ -120,7 +679,13 @@ class MessageHandler:
 
         try:
             signature = self.crypto_utils.sign_message(private_key, nonce)
-            resp = MixnetMessage.registrationResponse(self.current_user["username"], signature)
+            # Servers with anti-spam enabled ask for a proof of work alongside the nonce.
+            difficulty = content.get("difficulty")
+            pow_solution = None
+            if difficulty:
+                logger.info(f"Solving registration proof of work ({difficulty} bits)...")
+                pow_solution = await asyncio.to_thread(solve_pow, nonce, int(difficulty))
+            resp = MixnetMessage.registrationResponse(self.current_user["username"], signature, pow_solution)
             await self.connection_client.send_message(resp)
             logger.info("Registration challenge response sent.")
         except Exception as e:
@@ -136,9 +701,11 @@ class MessageHandler:
             logger.error("No private key for login.")
             return
 
+        if not self.auth_renewal:
+            self.pending_cursor = 0
         try:
             signature = self.crypto_utils.sign_message(private_key, nonce)
-            resp = MixnetMessage.loginResponse(self.current_user["username"], signature)
+            resp = MixnetMessage.loginResponse(self.current_user["username"], signature, PENDING_PAGE_SIZE)
             await self.connection_client.send_message(resp)
             logger.info("Login challenge response sent.")
         except Exception as e:
@@ -155,30 +722,28 @@ class MessageHandler:
             pub_k = self.temporary_keys["public_key"]
 
             try:
-                self.crypto_utils.save_keys(username, priv_k, pub_k)
+                self.crypto_utils.save_keys(username, priv_k, pub_k, self.temporary_keys.pop("passphrase", None))
                 logger.info("Keys saved.")
             except Exception as e:
                 logger.error(f"Saving keys: {e}")
-                self.registration_successful = False
-                self.registration_complete.set()  # Ensure the event is set
+                self._finish_auth(False)
                 return
 
             try:
-                self.db_manager = SQLiteManager(username)
+                self.db_manager = PyDb.open(username)
+                self.db_manager.create_user_tables(username)
                 logger.info("DB initialized for user: %s", username)
             except Exception as e:
                 logger.error(f"DB init: {e}")
-                self.registration_successful = False
-                self.registration_complete.set()  # Ensure the event is set
+                self._finish_auth(False)
                 return
 
-            self.registration_successful = True  # Registration is successful
-            self.registration_complete.set()  # Signal that registration is complete
+            self.audit("key_generated", self.crypto_utils.fingerprint(pub_k))
+            self._finish_auth(True)
 
         else:
             logger.error(f"Registration failed: {content}")
-            self.registration_successful = False
-            self.registration_complete.set()
+            self._finish_auth(False)
 
     async def handle_login_response(self, content):
         """
@@ -187,31 +752,602 @@ class MessageHandler:
         if content == "success":
             logger.info("Login successful!")
             username = self.current_user["username"]
+            self.session_expired = False
+            if self.auth_renewal:
+                # The session's database stays open.
+                self._finish_auth(True)
+                return
+            if self.db_manager is not None:
+                # Left open by a registration just before.
+                self.db_manager.close()
             try:
-                self.db_manager = SQLiteManager(username)
+                self.db_manager = PyDb.open(username)
                 logger.info("DB manager created.")
                 self.db_manager.create_user_tables(username)
             except Exception as e:
                 logger.error(f"DB init: {e}")
-                self.login_successful = False
-                self.login_complete.set()
+                self._finish_auth(False)
                 return
 
-            self.login_successful = True
-            self.login_complete.set()
+            self._finish_auth(True)
 
         else:
             logger.error(f"Login failed: {content}")
-            self.login_successful = False
-            self.login_complete.set()
+            self.audit("unlock_failed", str(content), self.current_user["username"])
+            self._finish_auth(False)
+
+    async def handle_session(self, content):
+        """ Schedules logging in again before the session the server just started runs out. """
+        ttl = content.get("ttl") if isinstance(content, dict) else None
+        if not isinstance(ttl, int):
+            logger.warning(f"session: {content}")
+            return
+        if self.session_renewal:
+            self.session_renewal.cancel()
+        delay = max(ttl * SESSION_RENEW_AT, SESSION_MIN_RENEWAL)
+        self.session_renewal = asyncio.get_running_loop().call_later(
+            delay, lambda: asyncio.ensure_future(self.renew_session())
+        )
+        logger.info(f"Session lasts {ttl}s; renewing in {int(delay)}s.")
+        # A new login resets the server's view of us, so heartbeats start over.
+        self._schedule_heartbeat()
+
+    async def renew_session(self):
+        """
+        Runs the login challenge again with the stored key, keeping the open
+        session. If it fails, the session is marked expired and the UI told,
+        so the user can log in again instead of requests failing silently.
+        Returns True on success.
+        """
+        username = self.current_user["username"]
+        if not username or self.auth_lock.locked():
+            return False
+        async with self.auth_lock:
+            try:
+                private_key = self.crypto_utils.load_private_key(username)
+            except Exception as e:
+                logger.error(f"Cannot renew the session, the key is unavailable: {e}")
+                self.audit("unlock_failed", str(e))
+                private_key = None
+            renewed = False
+            if private_key:
+                self.temporary_keys["private_key"] = private_key
+                renewed = await self._authenticate(MixnetMessage.login(username), renewal=True)
+        if renewed:
+            logger.info("Session renewed.")
+        elif self.current_user["username"] == username and not self.session_expired:
+            logger.error("Session expired and could not be renewed.")
+            self.session_expired = True
+            if self.session_expired_callback:
+                self.session_expired_callback()
+        return renewed
+
+    def _session_error(self, content):
+        """ Starts a renewal if `content` is the server refusing us for want of a session. """
+        if content in SESSION_ERRORS and not self.session_expired:
+            logger.warning(f"Server says: {content}; logging in again.")
+            asyncio.ensure_future(self.renew_session())
+            return True
+        return False
+
+    async def handle_ack_response(self, content):
+        if not self._session_error(content):
+            logger.warning(f"ackResponse: {content}")
+
+    async def handle_pending_page(self, content):
+        """
+        Handles a page of the server's offline queue: each message like one
+        sent to us live, then the page is acked and the next one asked for. A
+        long absence so arrives a page at a time instead of all at once.
+        """
+        if not isinstance(content, dict):
+            if not self._session_error(content):
+                logger.warning(f"pendingResponse: {content}")
+            return
+        username = self.current_user.get("username")
+        acked = []
+        for message in content.get("messages", []):
+            try:
+                message_id = int(message["messageId"])
+                if message_id > self.pending_cursor:
+                    await self.handle_incoming_message_content(self._parse_content(message.get("content")))
+                acked.append(str(message_id))
+            except Exception as e:
+                # Left unacked, so the server offers it again after the next login.
+                logger.error(f"Handling queued message: {e}")
+        cursor = content.get("cursor")
+        if isinstance(cursor, int):
+            self.pending_cursor = max(self.pending_cursor, cursor)
+        if not username:
+            return
+        if acked:
+            await self.connection_client.send_message(MixnetMessage.ack(username, acked))
+        if content.get("more"):
+            logger.info(f"Fetched {len(acked)} queued messages; asking for more.")
+            await self.connection_client.send_message(
+                MixnetMessage.fetchPending(username, self.pending_cursor, PENDING_PAGE_SIZE))
+
+    async def delete_account(self):
+        """
+        Deletes the current account: has the server drop us from its
+        directory and our remote backup, telling every contact on the way (see
+        handle_deletion_challenge), then wipes our keys and database from disk.
+        Returns True on success; nothing local is wiped if the server refuses.
+        """
+        username = self.current_user["username"]
+        if not username or not self.db_manager:
+            return False
+        if self.delegation:
+            logger.error("A delegated key cannot delete the account.")
+            return False
+        async with self.auth_lock:
+            try:
+                self.temporary_keys["private_key"] = self.crypto_utils.load_private_key(username)
+                logger.info("Sending account deletion request; waiting for challenge...")
+                if not await self._authenticate(MixnetMessage.deleteAccount(username)):
+                    return False
+                await self.disable_backup()
+
+                self.db_manager.close()
+                self.db_manager = None
+                self.crypto_utils.wipe_user_data(username)
+                self._clear_session()
+                logger.info(f"Deleted account {username} and wiped local data.")
+                return True
+
+            except Exception as e:
+                logger.error(f"Account deletion error: {e}")
+                return False
+
+    async def handle_deletion_challenge(self, content):
+        """
+        Tells every contact the account is gone, then answers the challenge.
+        The server took the request, and still relays for us until it has
+        the answer; a request it refused or never answered told nobody.
+        """
+        nonce = content.get("nonce") if isinstance(content, dict) else None
+        private_key = self.temporary_keys.get("private_key")
+        if not nonce or not private_key:
+            logger.error("Cannot answer account deletion challenge.")
+            self._finish_auth(False)
+            return
+        contacts = [contact for contact, _ in self.db_manager.get_all_contacts(self.current_user["username"])]
+        results = await asyncio.gather(*(self._send_typed(contact, 2, "") for contact in contacts),
+                                       return_exceptions=True)
+        for contact, result in zip(contacts, results):
+            if isinstance(result, Exception):
+                logger.warning(f"Could not tell {contact} the account is deleted: {result}")
+        signature = self.crypto_utils.sign_message(private_key, nonce)
+        await self.connection_client.send_message(
+            MixnetMessage.deleteAccountResponse(self.current_user["username"], signature)
+        )
+        logger.info("Account deletion challenge response sent.")
+
+    async def handle_deletion_response(self, content):
+        if content != "success":
+            logger.error(f"Account deletion failed: {content}")
+        self._finish_auth(content == "success")
+
+    # --------------------------------------------------------------------------
+    # Account export for moving to another device
+    # --------------------------------------------------------------------------
+    def export_account(self, path, passphrase, include_history=False):
+        """
+        Writes the current account to `path` as a single file encrypted with
+        `passphrase`: the private key, contacts with their presence settings,
+        the server the account is registered on and, if asked, the message
+        history. `import_account` restores it on another device.
+        """
+        bundle = self._account_bundle(include_history)
+        self._write_export(path, passphrase, bundle)
+        logger.info(f"Exported account {bundle['username']} (history: {include_history})")
+        self.audit("account_exported", f"{path} (history: {include_history})")
+
+    def protect_key(self, passphrase):
+        """
+        Moves the current user's private key from its plain file into a
+        keystore encrypted with `passphrase`, which logging in asks for from
+        then on. Returns False if it is in a keystore already.
+        """
+        username = self.current_user["username"]
+        if self.crypto_utils.has_keystore(username):
+            return False
+        self.crypto_utils.protect_keys(username, passphrase)
+        logger.info(f"Moved the private key of {username} into a keystore.")
+        self.audit("key_protected")
+        return True
+
+    def _write_export(self, path, passphrase, bundle):
+        sealed = self.crypto_utils.encrypt_with_passphrase(passphrase, json.dumps(bundle))
+        sealed["format"] = ACCOUNT_EXPORT_FORMAT
+        tmp_path = f"{path}.tmp"
+        with open(os.open(tmp_path, os.O_WRONLY | os.O_CREAT | os.O_TRUNC, 0o600), "w") as f:
+            json.dump(sealed, f)
+        os.replace(tmp_path, path)
+
+    def _account_bundle(self, include_history):
+        """ The current account as written by `export_account` and remote backups """
+        username = self.current_user["username"]
+        if not username or not self.db_manager:
+            raise RuntimeError(_("Log in before exporting an account."))
+        if self.delegation:
+            raise RuntimeError(_("A delegated key cannot export the account."))
+
+        private_key = self.crypto_utils.load_private_key(username)
+        sharing = {contact: share for contact, share, _ in self.db_manager.get_presence(username)}
+        bundle = {
+            "format": ACCOUNT_EXPORT_FORMAT,
+            "version": ACCOUNT_EXPORT_VERSION,
+            "username": username,
+            "privateKey": private_key.private_bytes(
+                encoding=serialization.Encoding.PEM,
+                format=serialization.PrivateFormat.PKCS8,
+                encryption_algorithm=serialization.NoEncryption(),
+            ).decode(),
+            "contacts": [
+                {
+                    "username": contact,
+                    "publicKey": public_key,
+                    "sharePresence": sharing.get(contact, False),
+                    "verified": self.db_manager.is_verified(username, contact),
+                    "trusted": self.db_manager.is_trusted(username, contact),
+                    "keyChanges": [list(change) for change in self.db_manager.get_key_history(username, contact)],
+                }
+                for contact, public_key in self.db_manager.get_all_contacts(username)
+            ],
+            "settings": {"server": mixnetMessages.SERVER_ADDRESS},
+        }
+        if include_history:
+            self.flush_writes()
+            # Oldest first and last in the bundle, so new messages only change its tail.
+            rows = sorted(self.db_manager.get_all_messages(username), key=lambda row: row[3])
+            bundle["messages"] = [list(row) for row in rows]
+        return bundle
+
+    def import_account(self, path, passphrase):
+        """
+        Restores an account written by `export_account` into local storage and
+        returns its username; log in as usual afterwards. Refuses to overwrite
+        an account that already exists on this device.
+        """
+        with open(path) as f:
+            sealed = json.load(f)
+        if sealed.get("format") != ACCOUNT_EXPORT_FORMAT:
+            raise ValueError(_("Not a nymCHAT account export"))
+        bundle = json.loads(self.crypto_utils.decrypt_with_passphrase(passphrase, sealed))
+        return self._restore_bundle(bundle)
+
+    def _restore_bundle(self, bundle):
+        """
+        Writes an account bundle to local storage; returns the username.
+        Bundles from before version 2 restore every contact as trusted and
+        unverified, with no key changes.
+        """
+        if (bundle.get("format") != ACCOUNT_EXPORT_FORMAT
+                or bundle.get("version") not in range(1, ACCOUNT_EXPORT_VERSION + 1)):
+            raise ValueError(_("Not a nymCHAT account, or an unsupported version: {version}",
+                               version=bundle.get('version')))
+
+        username = bundle["username"]
+        if not username or username in (".", "..") or os.path.basename(username) != username:
+            raise ValueError(_("Invalid username in account export: {username}", username=repr(username)))
+        if os.path.exists(os.path.join(self.crypto_utils.storage_dir, username)):
+            raise ValueError(_("An account named {username} already exists on this device", username=username))
+
+        private_key = serialization.load_pem_private_key(bundle["privateKey"].encode(), password=None)
+        public_key = private_key.public_key().public_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo,
+        ).decode()
+        try:
+            self.crypto_utils.save_keys(username, private_key, public_key)
+            db = PyDb.open(username, self.crypto_utils.storage_dir)
+            try:
+                db.register_user(username, public_key)
+                db.create_user_tables(username)
+                for contact in bundle.get("contacts", []):
+                    db.add_contact(username, contact["username"], contact["publicKey"])
+                    if contact.get("sharePresence"):
+                        db.set_share_presence(username, contact["username"], True)
+                    db.import_key_history(username, contact["username"],
+                                          [tuple(change) for change in contact.get("keyChanges", [])])
+                    db.set_trusted(username, contact["username"], contact.get("trusted", True))
+                    db.set_verified(username, contact["username"], contact.get("verified", False))
+                db.import_messages(username, [tuple(row) for row in bundle.get("messages", [])])
+                if bundle.get("delegation"):
+                    db.set_setting(username, "delegation", json.dumps(bundle["delegation"]))
+            finally:
+                db.close()
+        except Exception:
+            # Leave no half-imported account behind.
+            self.crypto_utils.wipe_user_data(username)
+            raise
+
+        server = bundle.get("settings", {}).get("server")
+        if server and server != mixnetMessages.SERVER_ADDRESS:
+            logger.warning(f"{username} is registered on {server}, not the configured server.")
+        logger.info(f"Imported account {username}")
+        return username
+
+    # --------------------------------------------------------------------------
+    # Delegated keys for bots
+    # --------------------------------------------------------------------------
+    def export_delegation(self, path, passphrase, expires_in=DEFAULT_DELEGATION_LIFETIME, scopes=(SCOPE_SEND,)):
+        """
+        Delegates to a new sub-key for a bot or daemon, and writes it to `path`
+        as an account export encrypted with `passphrase`: our contacts, the
+        sub-key in place of our private key, and the certificate our identity
+        key signed. `import_account` restores it on the bot's device and
+        `start_delegated` runs it. Returns the delegation's id, for
+        `revoke_delegation`.
+        """
+        if self.delegation:
+            raise RuntimeError(_("A delegated key cannot delegate further."))
+        username = self.current_user["username"]
+        bundle = self._account_bundle(include_history=False)
+        sub_key, sub_key_pem = self.crypto_utils.generate_key_pair(username)
+        delegation = Delegation.create(username, sub_key_pem, list(scopes), int(time.time()) + expires_in)
+        certificate = delegation.to_json()
+        signature = self.crypto_utils.sign_message(self.crypto_utils.load_private_key(username), certificate)
+        bundle["privateKey"] = sub_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        ).decode()
+        bundle["delegation"] = {"certificate": certificate, "signature": signature}
+        self._write_export(path, passphrase, bundle)
+
+        issued = self.delegations()
+        issued[delegation.id] = {"scopes": delegation.scopes, "expiresAt": delegation.expires_at}
+        self.db_manager.set_setting(username, "delegations", json.dumps(issued))
+        logger.info(f"Delegated to a sub-key until {delegation.expires_at}")
+        self.audit("delegation_issued", f"{delegation.id} ({', '.join(delegation.scopes)})")
+        return delegation.id
+
+    def delegations(self):
+        """ Delegations we issued and did not revoke: {id: {"scopes": [...], "expiresAt": Unix seconds}} """
+        username = self.current_user["username"]
+        value = self.db_manager.get_setting(username, "delegations") if self.db_manager else None
+        return json.loads(value) if value else {}
+
+    async def revoke_delegation(self, delegation_id):
+        """
+        Has every contact refuse the sub-key of a delegation from now on,
+        before it expires. Contacts who are offline get it from the server's
+        queue. Returns False if we issued no such delegation.
+        """
+        issued = self.delegations()
+        if issued.pop(delegation_id, None) is None:
+            return False
+        username = self.current_user["username"]
+        for contact, _ in self.db_manager.get_all_contacts(username):
+            await self._send_typed(contact, REVOCATION_MESSAGE, {"id": delegation_id})
+        self.db_manager.set_setting(username, "delegations", json.dumps(issued))
+        logger.info(f"Revoked delegation {delegation_id}")
+        self.audit("delegation_revoked", delegation_id)
+        return True
+
+    def start_delegated(self, username):
+        """
+        Runs as the bot an imported delegation was made for (see
+        `export_delegation`): opens the database of `username` without
+        logging in, which takes the identity key. Messages are then sent
+        sealed, signed with the sub-key and carrying the delegation; none are
+        received, as they are encrypted to the identity key. Returns False if
+        `username` holds no delegation, or it expired.
+        """
+        db = PyDb.open(username, self.crypto_utils.storage_dir)
+        value = db.get_setting(username, "delegation")
+        delegation = json.loads(value) if value else None
+        if not delegation or json.loads(delegation["certificate"])["expiresAt"] <= time.time():
+            logger.error(f"{username} holds no delegation that is still valid.")
+            db.close()
+            return False
+        self.db_manager = db
+        self.current_user["username"] = username
+        self.delegation = delegation
+        logger.info(f"Sending for {username} with a delegated key.")
+        return True
+
+    def _revoked_delegations(self, username):
+        value = self.db_manager.get_setting(self.current_user["username"], f"revoked_delegations:{username}")
+        return json.loads(value) if value else []
+
+    def _check_delegation(self, from_user, issuer_public_key_pem, delegation):
+        """
+        The delegation a message from `from_user` came with, if their identity
+        key signed it, it lets the sub-key send and it was not revoked; else None.
+        """
+        try:
+            checked = Delegation.verify(delegation["certificate"], delegation["signature"],
+                                        issuer_public_key_pem, int(time.time()))
+        except (KeyError, TypeError, ValueError) as e:
+            logger.error(f"Refusing a delegated key of {from_user}: {e}")
+            return None
+        if checked.issuer != from_user or not checked.allows(SCOPE_SEND):
+            logger.error(f"Refusing a delegated key of {from_user}: not theirs to send with.")
+            return None
+        if checked.id in self._revoked_delegations(from_user):
+            logger.warning(f"Refusing a revoked delegated key of {from_user}.")
+            return None
+        return checked
+
+    async def _handle_revocation(self, from_user, revocation):
+        """ Refuses a delegated key of `from_user` from now on. """
+        delegation_id = revocation.get("id") if isinstance(revocation, dict) else None
+        if not isinstance(delegation_id, str):
+            logger.warning(f"Ignoring a malformed revocation from {from_user}.")
+            return
+        revoked = self._revoked_delegations(from_user)
+        if delegation_id not in revoked:
+            revoked.append(delegation_id)
+            self.db_manager.set_setting(self.current_user["username"], f"revoked_delegations:{from_user}",
+                                        json.dumps(revoked))
+            logger.info(f"{from_user} revoked delegation {delegation_id}")
+
+    # --------------------------------------------------------------------------
+    # Encrypted remote backup
+    # --------------------------------------------------------------------------
+    def backup_enabled(self):
+        username = self.current_user["username"]
+        return bool(username) and self.crypto_utils.load_backup_state(username) is not None
+
+    async def enable_backup(self):
+        """
+        Turns on remote backups with a new recovery mnemonic, uploads the first
+        backup and returns the mnemonic. The mnemonic is the only way to
+        restore, so the user must write it down.
+        """
+        mnemonic = mnemonic_from_entropy(os.urandom(16))
+        state = {"seed": mnemonic_to_seed(mnemonic).hex(), "digests": []}
+        self.crypto_utils.save_backup_state(self.current_user["username"], state)
+        await self.run_backup()
+        return mnemonic
+
+    async def disable_backup(self):
+        """ Deletes the remote backup and forgets its mnemonic. """
+        username = self.current_user["username"]
+        state = self.crypto_utils.load_backup_state(username)
+        if state is None:
+            return
+        keys = self.crypto_utils.backup_keys(bytes.fromhex(state["seed"]))
+        await self.connection_client.send_message(MixnetMessage.backupDelete(keys["id"], keys["token"]))
+        self.crypto_utils.delete_backup_state(username)
+
+    async def run_backup(self):
+        """
+        Uploads the account bundle (history included), encrypted under the
+        mnemonic's key, in chunks. Chunks already stored are skipped, so the
+        periodic runs mostly send the few chunks holding new messages.
+        """
+        username = self.current_user["username"]
+        state = self.crypto_utils.load_backup_state(username) if username else None
+        if state is None or not self.db_manager:
+            return
+        keys = self.crypto_utils.backup_keys(bytes.fromhex(state["seed"]))
+        plaintext = json.dumps(self._account_bundle(include_history=True)).encode()
+        chunks = [plaintext[i:i + BACKUP_CHUNK_SIZE] for i in range(0, len(plaintext), BACKUP_CHUNK_SIZE)]
+        digests = [hashlib.sha256(chunk).hexdigest() for chunk in chunks]
+        old = state["digests"]
+
+        sent = 0
+        for index, chunk in enumerate(chunks):
+            # A changed chunk count must reach the server even if no chunk changed.
+            resize = index == len(chunks) - 1 and len(old) != len(chunks)
+            if index < len(old) and old[index] == digests[index] and not resize:
+                continue
+            data = self.crypto_utils.seal_chunk(keys["key"], keys["id"], index, chunk)
+            await self.connection_client.send_message(
+                MixnetMessage.backupPut(keys["id"], keys["token"], index, len(chunks), data)
+            )
+            sent += 1
+        state["digests"] = digests
+        self.crypto_utils.save_backup_state(username, state)
+        logger.info(f"Backup: sent {sent} of {len(chunks)} chunks")
+
+    async def handle_backup_response(self, content):
+        if not isinstance(content, dict) or content.get("status") == "success":
+            return
+        logger.error(f"Backup failed: {content.get('status')}")
+        # Forget the chunk's digest so the next run sends it again.
+        username = self.current_user["username"]
+        state = self.crypto_utils.load_backup_state(username) if username else None
+        index = content.get("index")
+        if state and isinstance(index, int) and index < len(state["digests"]):
+            state["digests"][index] = None
+            self.crypto_utils.save_backup_state(username, state)
+
+    async def restore_backup(self, mnemonic):
+        """
+        Fetches the remote backup belonging to `mnemonic`, restores the account
+        into local storage and returns its username; log in afterwards.
+        Backups stay on for the restored account.
+        """
+        seed = mnemonic_to_seed(mnemonic)
+        keys = self.crypto_utils.backup_keys(seed)
+        self.pending_restore = {
+            "keys": keys,
+            "chunks": {},
+            "future": asyncio.get_running_loop().create_future(),
+        }
+        try:
+            await self.connection_client.send_message(MixnetMessage.backupGet(keys["id"]))
+            chunks = await asyncio.wait_for(self.pending_restore["future"], RESPONSE_TIMEOUT)
+        except asyncio.TimeoutError:
+            raise ValueError(_("The backup did not arrive in time; try again."))
+        finally:
+            self.pending_restore = None
+        if not chunks:
+            raise ValueError(_("No backup found for this mnemonic."))
+
+        plain = [self.crypto_utils.open_chunk(keys["key"], keys["id"], i, data) for i, data in enumerate(chunks)]
+        username = self._restore_bundle(json.loads(b"".join(plain)))
+        state = {"seed": seed.hex(), "digests": [hashlib.sha256(chunk).hexdigest() for chunk in plain]}
+        self.crypto_utils.save_backup_state(username, state)
+        return username
+
+    async def handle_backup_chunk(self, content):
+        """
+        Collects a chunk of the backup being restored. The first chunk fixes
+        how many there are; chunks that disagree, or are out of range or
+        without data, are ignored.
+        """
+        restore = self.pending_restore
+        if not restore or not isinstance(content, dict) or content.get("backupId") != restore["keys"]["id"]:
+            return
+        total, index, data = content.get("total"), content.get("index"), content.get("data")
+        if restore["future"].done() or not isinstance(total, int) or not isinstance(index, int):
+            return
+        if not 0 <= total <= MAX_BACKUP_CHUNKS or restore.setdefault("total", total) != total:
+            logger.warning(f"Ignoring a backup chunk claiming {total} chunks.")
+            return
+        if total == 0:
+            restore["future"].set_result([])
+            return
+        if not 0 <= index < total or not isinstance(data, str):
+            logger.warning(f"Ignoring a malformed backup chunk {index} of {total}.")
+            return
+        restore["chunks"][index] = data
+        if len(restore["chunks"]) == total:
+            restore["future"].set_result([restore["chunks"][i] for i in range(total)])
 
     # --------------------------------------------------------------------------
     # Sending Direct Messages (All messages encrypted)
     # --------------------------------------------------------------------------
-    async def send_direct_message(self, recipient_username, message_content):
+    async def send_direct_message(self, recipient_username, message_content, forwarded_from=None):
+        """
+        Encrypts and sends a chat message, marked as forwarded if it was
+        written by `forwarded_from`. Returns its send id, whose delivery state
+        is tracked in `send_status`, or None if nothing was sent.
+        """
         if not recipient_username or not message_content.strip():
             return
+        if forwarded_from:
+            wrapped = {"type": 0, "message": message_content, "forwardedFrom": {"sender": forwarded_from}}
+            return await self._send_chat(recipient_username, wrapped, forwarded_text(forwarded_from, message_content),
+                                         kind="forwarded")
+        return await self._send_chat(recipient_username, {"type": 0, "message": message_content}, message_content)
+
+    async def send_location(self, recipient_username, location):
+        """
+        Sends a location ({"lat", "lon", "accuracy", "label"}) like a chat
+        message. Returns its send id, or None if the location is not valid or
+        nothing was sent.
+        """
+        location = parse_location(location)
+        if not recipient_username or location is None:
+            return
+        return await self._send_chat(recipient_username, {"type": LOCATION_MESSAGE, "message": location},
+                                     location_text(location), kind="location")
 
+    async def _send_chat(self, recipient_username, wrapped, stored_text, send_id=None, initial=None, kind="text"):
+        """
+        Encrypts and sends `wrapped`, and stores it in the chat history as
+        `stored_text` of the given `kind` (see `PyDb.save_message`), or queues
+        it in the outbox while the mixnet is down.
+        Messages from the outbox come with the `send_id` they were shown with
+        and whether they were the `initial` one, and are stored already.
+        """
         sender_private_key = self.crypto_utils.load_private_key(self.current_user["username"])
         if not sender_private_key:
             logger.error("No private key to send message.")
@@ -225,21 +1361,40 @@ class MessageHandler:
         if not contact:
             logger.error(f"No contact record found for {recipient_username}. Cannot send message.")
             return
+        if not self.is_trusted(recipient_username):
+            logger.error(f"The key of {recipient_username} changed and is not confirmed yet. Not sending.")
+            return
+        if self.downgrade(recipient_username):
+            logger.error(f"The weaker terms of {recipient_username} are not accepted yet. Not sending.")
+            return
 
-        existing_msgs = self.db_manager.get_messages_by_contact(self.current_user["username"], recipient_username)
-        initial_message = not existing_msgs
+        if initial is None:
+            existing_msgs = self.db_manager.load_messages(self.current_user["username"], recipient_username)
+            initial = not existing_msgs
+        message_id = wrapped.setdefault("id", send_id or secrets.token_hex(8))
+        if send_id is None and self._queueing():
+            send_id = self._queue_outbox(recipient_username, {"message": wrapped, "initial": initial}, message_id)
+            self.db_manager.save_sent_message(self.current_user["username"], recipient_username, stored_text,
+                                              message_id, "pending", kind=kind)
+            return send_id
 
         recipient_public_key_pem = contact[1]
 
-        # Maintain original message format
-        wrapped_message = json.dumps({"type": 0, "message": message_content})
+        address = await self._recipient_address(recipient_username)
+        if address is None:
+            return
+
+        observation = self._observation_for(recipient_username)
+        if observation:
+            wrapped["keyObservation"] = observation
+        wrapped_message = self._ratchet(recipient_username, json.dumps(wrapped))
 
         # Encrypt using ECDH + AES-GCM
-        encrypted_payload = self.crypto_utils.encrypt_message(recipient_public_key_pem, wrapped_message)
+        encrypted_payload = await self.crypto_utils.encrypt_message_async(recipient_public_key_pem, wrapped_message)
 
         # Sign only the encryptedPayload
         encrypted_payload_str = json.dumps(encrypted_payload)  # Convert to string for signing
-        payload_signature = self.crypto_utils.sign_message(sender_private_key, encrypted_payload_str)
+        payload_signature = await self.crypto_utils.sign_message_async(sender_private_key, encrypted_payload_str)
 
         # Construct the body field (ENCAPSULATING SIGNATURE INSIDE `body`)
         body = {
@@ -250,13 +1405,15 @@ class MessageHandler:
         # Construct final message payload
         payload = {
             "sender": self.current_user["username"],
-            "recipient": recipient_username,
+            **address,
             "body": body,  #  contains both encryptedPayload + signature
             "encrypted": True
         }
 
+        if self.delegation:
+            payload["delegation"] = self.delegation
         # If it's an initial message, include sender's public key
-        if initial_message:
+        elif initial:
             sender_public_key_obj = self.crypto_utils.load_public_key(self.current_user["username"])
             sender_public_key_pem = sender_public_key_obj.public_bytes(
                 encoding=serialization.Encoding.PEM,
@@ -266,131 +1423,2477 @@ class MessageHandler:
 
         # Sign the full message (for the server)
         payload_str = json.dumps(payload)
-        outer_signature = self.crypto_utils.sign_message(sender_private_key, payload_str)
+        outer_signature = await self.crypto_utils.sign_message_async(sender_private_key, payload_str)
 
         # Encapsulate as per existing protocol
-        if recipient_username in self.nym_addresses:
-            msg = MixnetMessage.directMessage(content=payload_str, signature=outer_signature)
-            msg["recipient"] = self.nym_addresses[recipient_username]
+        queued = send_id is not None
+        send_id = send_id or message_id
+        direct = MixnetMessage.directMessage(content=payload_str, signature=outer_signature)
+        if await self._send_direct(recipient_username, direct):
+            # Handed to the mixnet; from here on only the contact's receipts tell more.
+            self.send_status[send_id] = "sent"
+            if queued:
+                self.db_manager.set_message_status(self.current_user["username"], recipient_username, [send_id],
+                                                   "sent")
         else:
-            msg = MixnetMessage.send(content=payload_str, signature=outer_signature)
-
-        await self.connection_client.send_message(msg)
+            if self._seals_for(recipient_username):
+                msg = await self._sealed_message(recipient_public_key_pem, address, payload, send_id)
+            else:
+                msg = MixnetMessage.send(content=payload_str, signature=outer_signature, send_id=send_id)
+            self.send_status[send_id] = "sending"
+            self.pending_sends[send_id] = recipient_username
+            self.pending_contents[send_id] = {"message": wrapped, "initial": initial}
+            asyncio.get_running_loop().call_later(RESPONSE_TIMEOUT, self._expire_send, send_id)
+            await self.connection_client.send_message(msg)
         logger.info(f"Sent direct message to {recipient_username}")
+        if queued:
+            return send_id
 
-        self.db_manager.save_message(
+        status = self.send_status.get(send_id)
+        self.db_manager.save_sent_message(
             self.current_user["username"],
-            contact_username=recipient_username,
-            msg_type='to',
-            message=message_content
+            recipient_username,
+            stored_text,
+            send_id,
+            status if DELIVERY_ORDER.get(status, 0) > 0 else "pending",
+            kind=kind
         )
+        return send_id
 
 
-    async def send_handshake(self, recipient_username):
+    def downloads_dir(self):
+        """ The folder received files are kept in """
+        username = self.current_user["username"]
+        folder = self.db_manager.get_setting(username, DOWNLOADS_SETTING) if self.db_manager else None
+        return folder or os.path.join(self.crypto_utils.storage_dir, username, "downloads")
+
+    def set_downloads_dir(self, folder):
+        """ Keeps received files in `folder` from now on; an empty one restores the default. """
+        folder = folder.strip()
+        folder = os.path.abspath(os.path.expanduser(folder)) if folder else ""
+        self.db_manager.set_setting(self.current_user["username"], DOWNLOADS_SETTING, folder)
+
+    async def send_file(self, recipient_username, path):
         """
-        Sends a handshake (type 1 message) containing this client's nym address.
+        Sends the file at `path`: its manifest like a chat message, shown in
+        the chat, then its chunks. Returns the manifest's send id, or None if
+        nothing was sent: to groups, while offline, or a file that cannot be
+        read or is larger than MAX_FILE_SIZE.
         """
-        if self.nym_address is None:
-            logger.error("Nym address not set in MessageHandler.")
+        if (not recipient_username or recipient_username.startswith(GROUP_CHAT_PREFIX) or self.delegation
+                or self._queueing()):
             return
-
-        sender_private_key = self.crypto_utils.load_private_key(self.current_user["username"])
-        if not sender_private_key:
-            logger.error("No private key available for handshake.")
+        mime = mimetypes.guess_type(path)[0] or "application/octet-stream"
+        try:
+            upload = await asyncio.to_thread(FileUpload.open, path, mime)
+        except (OSError, ValueError) as e:
+            logger.warning(f"Cannot send {path}: {e}")
             return
-
-        if not self.db_manager:
-            logger.error("DB manager not initialized.")
+        manifest = json.loads(upload.manifest())
+        text = file_text(manifest["name"])
+        send_id = await self._send_chat(recipient_username, {"type": FILE_MESSAGE, "message": manifest}, text,
+                                        kind="file")
+        if not send_id:
             return
+        if self.chat_messages is not None:
+            self.chat_messages.setdefault(recipient_username, []).append(
+                (self.current_user["username"], text, timeUtils.utc_stamp(), send_id))
+        transfer_id = manifest["id"]
+        transfer = {"chat": recipient_username, "name": manifest["name"], "incoming": False, "done": 0,
+                    "total": upload.chunks}
+        self.transfers[transfer_id] = transfer
+        self._transfer_progress(recipient_username)
+        try:
+            for seq in range(upload.chunks):
+                # Logging out ends the transfer.
+                if self.transfers.get(transfer_id) is not transfer:
+                    return send_id
+                chunk = json.loads(await asyncio.to_thread(upload.chunk, seq))
+                if not await self._send_typed(recipient_username, FILE_CHUNK_MESSAGE, chunk):
+                    logger.warning(f"Stopped sending {manifest['name']} to {recipient_username}.")
+                    break
+                transfer["done"] = seq + 1
+                self._transfer_progress(recipient_username)
+        except (OSError, ValueError) as e:
+            logger.warning(f"Stopped sending {manifest['name']} to {recipient_username}: {e}")
+        finally:
+            if self.transfers.get(transfer_id) is transfer:
+                del self.transfers[transfer_id]
+                self._transfer_progress(recipient_username)
+        logger.info(f"Sent {transfer['done']} of {transfer['total']} chunks of a file to {recipient_username}.")
+        return send_id
 
-        contact = self.db_manager.get_contact(self.current_user["username"], recipient_username)
-        if not contact:
-            logger.error(f"No contact record found for {recipient_username}. Cannot send handshake.")
-            return
+    def transfers_in(self, chat_id):
+        """ The files being sent or received in a chat: [{"name", "incoming", "done", "total"}] """
+        return [{key: transfer[key] for key in ("name", "incoming", "done", "total")}
+                for transfer in self.transfers.values() if transfer["chat"] == chat_id]
 
-        recipient_public_key_pem = contact[1]
+    def _transfer_progress(self, chat_id):
+        if self.chat_messages is not None and chat_id == self._get_active_chat():
+            self._mark_dirty("chat")
 
-        # inner message format
-        handshake_payload = json.dumps({"type": 1, "message": self.nym_address})
+    def _expire_transfers(self):
+        """ Gives up files whose next chunk is overdue, and chunks whose manifest never came. """
+        now = time.time()
+        for transfer_id, transfer in list(self.transfers.items()):
+            if transfer["incoming"] and now - transfer["last"] > TRANSFER_TIMEOUT:
+                logger.warning(f"Gave up receiving {transfer['name']} from {transfer['chat']}.")
+                transfer["download"].abort()
+                del self.transfers[transfer_id]
+                self._transfer_progress(transfer["chat"])
+        for transfer_id, (first_seen, _chunks) in list(self.early_chunks.items()):
+            if now - first_seen > TRANSFER_TIMEOUT:
+                del self.early_chunks[transfer_id]
 
-        # Encrypt and sign
-        enc_result = self.crypto_utils.encrypt_message(recipient_public_key_pem, handshake_payload)
-        encrypted_payload_str = json.dumps(enc_result)
-        payload_signature = self.crypto_utils.sign_message(sender_private_key, encrypted_payload_str)
+    async def _handle_file_manifest(self, from_user, manifest):
+        """ Starts receiving a file from a contact into the downloads folder. """
+        self._expire_transfers()
+        username = self.current_user["username"]
+        if not self.db_manager or not self.db_manager.get_contact(username, from_user):
+            logger.warning(f"Refusing a file from {from_user}, who is not a contact.")
+            return
+        if not isinstance(manifest, dict) or manifest.get("id") in self.transfers:
+            return
+        if sum(transfer["incoming"] for transfer in self.transfers.values()) >= MAX_INCOMING_TRANSFERS:
+            logger.warning(f"Refusing a file from {from_user}: {MAX_INCOMING_TRANSFERS} are arriving already.")
+            return
+        try:
+            download = FileDownload.start(self.downloads_dir(), json.dumps(manifest))
+        except (OSError, ValueError) as e:
+            logger.warning(f"Refusing a file from {from_user}: {e}")
+            return
+        transfer_id = manifest["id"]
+        self.transfers[transfer_id] = {"chat": from_user, "name": download.name, "incoming": True, "done": 0,
+                                       "total": download.chunks, "download": download, "last": time.time()}
+        logger.info(f"Receiving {download.name} ({download.chunks} chunks) from {from_user}.")
+        _first_seen, early = self.early_chunks.pop(transfer_id, (None, []))
+        for sender, chunk in early:
+            if sender == from_user:
+                await self._handle_file_chunk(from_user, chunk)
+        if transfer_id in self.transfers and download.is_complete():
+            await self._finish_download(transfer_id)
+        self._transfer_progress(from_user)
 
-        payload = {
-            "sender": self.current_user["username"],
-            "recipient": recipient_username,
-            "body": {
-                "encryptedPayload": enc_result,
-                "payloadSignature": payload_signature
-            },
+    async def _handle_file_chunk(self, from_user, chunk):
+        """ Adds a chunk to the file it belongs to, and keeps the file once it is whole. """
+        transfer_id = chunk.get("id") if isinstance(chunk, dict) else None
+        if not isinstance(transfer_id, str):
+            logger.warning(f"Ignoring a malformed file chunk from {from_user}.")
+            return
+        transfer = self.transfers.get(transfer_id)
+        if transfer is None:
+            # The manifest may still be on its way.
+            if sum(len(chunks) for _first_seen, chunks in self.early_chunks.values()) < MAX_EARLY_CHUNKS:
+                self.early_chunks.setdefault(transfer_id, (time.time(), []))[1].append((from_user, chunk))
+            return
+        if not transfer["incoming"] or transfer["chat"] != from_user:
+            logger.warning(f"Ignoring a chunk of a file {from_user} is not sending.")
+            return
+        download = transfer["download"]
+        try:
+            added = download.add(json.dumps(chunk))
+        except ValueError as e:
+            logger.warning(f"Ignoring a chunk of {transfer['name']} from {from_user}: {e}")
+            return
+        if not added:
+            return
+        transfer["done"], transfer["last"] = download.received, time.time()
+        if download.is_complete():
+            await self._finish_download(transfer_id)
+        self._transfer_progress(from_user)
+
+    async def _finish_download(self, transfer_id):
+        """ Checks a whole file and shows it in its chat, or that it was damaged and discarded. """
+        transfer = self.transfers.pop(transfer_id)
+        from_user = transfer["chat"]
+        try:
+            path = await asyncio.to_thread(transfer["download"].finish)
+        except (OSError, ValueError) as e:
+            logger.warning(f"Discarded {transfer['name']} from {from_user}: {e}")
+            text = _("{name} from {username} arrived damaged and was discarded.", name=transfer["name"],
+                     username=from_user)
+            kind = "notice"
+        else:
+            logger.info(f"Received a file from {from_user}.")
+            text, kind = file_text(os.path.basename(path)), "file"
+        self._store_message(from_user, text, kind)
+        self._update_chat_ui(from_user, text)
+
+    async def send_handshake(self, recipient_username):
+        """
+        Starts a handshake (type 1 message) with our hello: this client's nym
+        address, unless the contact is in anonymous mode, and what it
+        supports. See `_handle_handshake` for the rest.
+        """
+        if self.nym_address is None:
+            logger.error("Nym address not set in MessageHandler.")
+            return
+
+        ratchet_secret, ratchet_key = RatchetSession.key_pair()
+        hello = HandshakeHello.create(HANDSHAKE_FEATURES, self._hello_address(recipient_username), ratchet_key).to_json()
+        self.handshakes[recipient_username] = {"initiator": hello, "responder": None, "ratchetSecret": ratchet_secret}
+        if await self._send_typed(recipient_username, HANDSHAKE_MESSAGE, {"stage": "hello", "hello": hello}):
+            logger.info(f"Sent handshake to {recipient_username}")
+
+    async def _handle_handshake(self, from_user, message):
+        """
+        Handles a handshake stage from `from_user`. Their hello is answered
+        with ours and the transcript hash of both; their answer to ours is
+        confirmed with the hash if it matches what we sent and got, and their
+        confirmation must match our answer. What the hellos agree on only takes
+        effect then. A hash that differs means a hello was changed on the way,
+        and the handshake is dropped.
+        """
+        if isinstance(message, str):
+            self._handle_legacy_handshake(from_user, message)
+            return
+        if not isinstance(message, dict):
+            logger.warning(f"Invalid handshake from {from_user}.")
+            return
+        stage = message.get("stage")
+        pending = self.handshakes.get(from_user)
+        try:
+            if stage == "hello":
+                theirs = message.get("hello")
+                # Both started at once: the hello of the smaller username goes on.
+                if pending and pending["responder"] is None and self.current_user["username"] < from_user:
+                    return
+                ratchet_secret, ratchet_key = RatchetSession.key_pair()
+                ours = HandshakeHello.create(HANDSHAKE_FEATURES, self._hello_address(from_user), ratchet_key).to_json()
+                HandshakeHello.parse(theirs).negotiate(HandshakeHello.parse(ours))
+                self.handshakes[from_user] = {"initiator": theirs, "responder": ours, "ratchetSecret": ratchet_secret}
+                transcript = HandshakeHello.transcript(theirs, ours)
+                await self._send_typed(from_user, HANDSHAKE_MESSAGE,
+                                       {"stage": "reply", "hello": ours, "transcript": transcript})
+            elif stage == "reply" and pending and pending["responder"] is None:
+                theirs = message.get("hello")
+                transcript = HandshakeHello.transcript(pending["initiator"], theirs)
+                del self.handshakes[from_user]
+                if message.get("transcript") != transcript:
+                    self._handshake_altered(from_user)
+                elif self._complete_handshake(from_user, pending["initiator"], theirs, theirs,
+                                              pending.get("ratchetSecret")):
+                    await self._send_typed(from_user, HANDSHAKE_MESSAGE, {"stage": "confirm", "transcript": transcript})
+            elif stage == "confirm" and pending and pending["responder"] is not None:
+                del self.handshakes[from_user]
+                if message.get("transcript") != HandshakeHello.transcript(pending["initiator"], pending["responder"]):
+                    self._handshake_altered(from_user)
+                else:
+                    initiator = pending["initiator"]
+                    self._complete_handshake(from_user, initiator, pending["responder"], initiator,
+                                             pending.get("ratchetSecret"))
+            else:
+                logger.warning(f"Unexpected handshake stage {stage!r} from {from_user}.")
+        except (ValueError, TypeError) as e:
+            logger.warning(f"Invalid handshake from {from_user}: {e}")
+
+    def _hello_address(self, username):
+        """ The nym address our hello to `username` gives: none in anonymous mode. """
+        return None if username in self.anonymous else self.nym_address
+
+    def _handle_legacy_handshake(self, from_user, nym_address):
+        """
+        Takes the nym address from an older client's handshake, unless the
+        contact's client negotiated before: then it is a downgrade.
+        """
+        agreed = self.contact_handshake(from_user)
+        if agreed:
+            logger.error(f"Refusing a version 1 handshake from {from_user}, who agreed on version {agreed[0]}.")
+            self.audit("handshake_downgrade", f"{from_user}: version {agreed[0]} -> 1")
+            return
+        logger.info(f"Storing handshake nym_address from {from_user}")
+        self.nym_addresses[from_user] = nym_address
+
+    def _handshake_altered(self, from_user):
+        logger.error(f"The handshake with {from_user} was altered on the way; dropping it.")
+        self.audit("handshake_altered", from_user)
+
+    def _complete_handshake(self, username, initiator, responder, their_hello, ratchet_secret=None):
+        """
+        Applies what the two hellos agree on. A lower version than the last
+        handshake with `username` agreed on is refused; fewer features are
+        taken, but noted in the audit log. If they agree on Double Ratchet
+        sessions, a new one starts from the ratchet keys of the hellos, with
+        `ratchet_secret` the secret of ours. Returns True if applied.
+        """
+        agreed = HandshakeHello.parse(initiator).negotiate(HandshakeHello.parse(responder))
+        previous = self.contact_handshake(username)
+        if previous and agreed.version < previous[0]:
+            logger.error(f"Refusing handshake version {agreed.version} from {username}, who agreed on {previous[0]}.")
+            self.audit("handshake_downgrade", f"{username}: version {previous[0]} -> {agreed.version}")
+            return False
+        lost = sorted(set(previous[1]) - set(agreed.features)) if previous else []
+        if lost:
+            logger.warning(f"The handshake with {username} no longer agrees on {', '.join(lost)}.")
+            self.audit("handshake_downgrade", f"{username}: {', '.join(lost)}")
+        if self.db_manager:
+            self.db_manager.set_contact_handshake(
+                self.current_user["username"], username, agreed.version, agreed.features)
+            # What is agreed on again needs no acceptance any more.
+            unaccepted = sorted((set(self.downgrade(username)) | set(lost)) - set(agreed.features))
+            self.db_manager.set_handshake_downgrade(self.current_user["username"], username, unaccepted)
+        if lost and self.downgrade_callback:
+            self.downgrade_callback(username, lost)
+        address = HandshakeHello.parse(their_hello).nym_address
+        if address:
+            self.nym_addresses[username] = address
+        if RATCHET_FEATURE in agreed.features:
+            self._start_ratchet(username, their_hello == initiator, ratchet_secret,
+                                HandshakeHello.parse(their_hello).ratchet_key)
+        elif self.db_manager:
+            self.db_manager.set_ratchet_session(self.current_user["username"], username, None)
+        logger.info(f"Handshake with {username}: version {agreed.version}, features {agreed.features}")
+        return True
+
+    def _start_ratchet(self, username, responding, ratchet_secret, their_ratchet_key):
+        """
+        Sets up the Double Ratchet session with `username` from the handshake
+        just completed, replacing any before it. The initiator's session can
+        send at once; ours as the responder once their first message under it
+        arrived, and until then messages go out as before.
+        """
+        contact = self.db_manager.get_contact(self.current_user["username"], username) if self.db_manager else None
+        private_key = self.crypto_utils.load_private_key(self.current_user["username"])
+        if not contact or not private_key or not ratchet_secret or not their_ratchet_key:
+            logger.warning(f"No Double Ratchet session with {username}: the handshake lacks a key.")
+            return
+        private_key_pem = private_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        ).decode()
+        start = RatchetSession.respond if responding else RatchetSession.initiate
+        try:
+            session = start(private_key_pem, ratchet_secret, contact[1], their_ratchet_key)
+        except ValueError as e:
+            logger.warning(f"Could not set up a Double Ratchet session with {username}: {e}")
+            return
+        self.db_manager.set_ratchet_session(self.current_user["username"], username, session.to_json())
+        logger.info(f"Started a Double Ratchet session with {username}.")
+
+    def _ratchet_session(self, username):
+        """ The Double Ratchet session with `username`, or None. """
+        raw = self.db_manager.get_ratchet_session(self.current_user["username"], username) if self.db_manager else None
+        try:
+            return RatchetSession.from_json(raw) if raw else None
+        except ValueError as e:
+            logger.error(f"Ignoring the damaged Double Ratchet session with {username}: {e}")
+            return None
+
+    def _ratchet(self, username, inner_payload):
+        """
+        `inner_payload`, the JSON of a direct message to `username`, as it is
+        encrypted to them: wrapped in a RATCHET_MESSAGE under their Double
+        Ratchet session if it can send, else (and from a delegated key)
+        unchanged. The session moves on.
+        """
+        if self.delegation:
+            # The session is the account's own; a bot would move it on behind its back.
+            return inner_payload
+        session = self._ratchet_session(username)
+        if session is None or not session.can_send():
+            return inner_payload
+        message = json.loads(session.encrypt(inner_payload))
+        self.db_manager.set_ratchet_session(self.current_user["username"], username, session.to_json())
+        return json.dumps({"type": RATCHET_MESSAGE, "message": message})
+
+    def _open_ratchet(self, username, message):
+        """
+        The direct message inside a RATCHET_MESSAGE from `username`, or None
+        if their session cannot decrypt it. The session moves on.
+        """
+        session = self._ratchet_session(username)
+        if session is None:
+            logger.error(f"A Double Ratchet message from {username}, but no session with them.")
+            return None
+        try:
+            inner = json.loads(session.decrypt(json.dumps(message)))
+        except (ValueError, TypeError) as e:
+            logger.error(f"Could not decrypt a Double Ratchet message from {username}: {e}")
+            return None
+        self.db_manager.set_ratchet_session(self.current_user["username"], username, session.to_json())
+        return inner if isinstance(inner, dict) else None
+
+    def contact_handshake(self, username):
+        """ `(version, features)` the last handshake with `username` agreed on, or None. """
+        if not self.db_manager:
+            return None
+        return self.db_manager.get_contact_handshake(self.current_user["username"], username)
+
+    def downgrade(self, username):
+        """
+        Features a handshake with `username` dropped that the user has not
+        accepted yet; chat messages to them wait until then.
+        """
+        if not self.db_manager:
+            return []
+        return self.db_manager.get_handshake_downgrade(self.current_user["username"], username)
+
+    def accept_downgrade(self, username):
+        """ Accepts sending to `username` without the features their handshake dropped. """
+        lost = self.downgrade(username)
+        self.db_manager.set_handshake_downgrade(self.current_user["username"], username, [])
+        logger.info(f"Accepted the weaker terms of {username}.")
+        self.audit("downgrade_accepted", f"{username}: {', '.join(lost)}")
+
+    def encryption_mode(self, chat_id):
+        """
+        How messages to `chat_id` are protected and carried, for the chat
+        header: {"encrypted": whether we hold what end-to-end encryption needs,
+        "algorithm" and "ratchet" (see DIRECT_ENCRYPTION and DOUBLE_RATCHET), "route": "p2p" to
+        the contact's nym address, "reply" through their sender tag or
+        "server", "sealed": whether server sends hide the sender, "version"
+        and "features" of the last handshake (None and [] before one),
+        "downgrade": features dropped and not yet accepted, and "anonymous":
+        whether the contact is kept from learning our nym address}.
+        """
+        if chat_id.startswith(GROUP_CHAT_PREFIX):
+            group = self._load_group(chat_id.removeprefix(GROUP_CHAT_PREFIX))
+            return {"encrypted": group is not None, **GROUP_ENCRYPTION, "route": "server", "sealed": False,
+                    "version": None, "features": [], "downgrade": [], "anonymous": False}
+        username = self.current_user["username"]
+        contact = self.db_manager.get_contact(username, chat_id) if self.db_manager else None
+        if chat_id in self.nym_addresses:
+            route = "p2p"
+        elif chat_id in self.sender_tags:
+            route = "reply"
+        else:
+            route = "server"
+        version, features = self.contact_handshake(chat_id) or (None, [])
+        encryption = dict(DIRECT_ENCRYPTION)
+        if self._ratchet_session(chat_id) is not None:
+            encryption["ratchet"] = DOUBLE_RATCHET
+        return {"encrypted": contact is not None, **encryption, "route": route,
+                "sealed": route == "server" and self._seals_for(chat_id), "version": version,
+                "features": list(features), "downgrade": self.downgrade(chat_id),
+                "anonymous": chat_id in self.anonymous}
+
+    def _seals_for(self, username):
+        """ Whether sends to `username` through the server are sealed: unless a handshake said they can't open them. """
+        if self.delegation:
+            # The server checks plain sends against the identity key.
+            return True
+        if not self.use_sealed_sender:
+            return False
+        agreed = self.contact_handshake(username)
+        return agreed is None or "sealedSender" in agreed[1]
+
+    async def _send_typed(self, recipient_username, message_type, message, surbs=None):
+        """
+        Encrypts and sends a non-chat message (handshake, account deletion)
+        that is neither stored nor tracked. Returns True if it was sent.
+        `surbs` reply SURBs go with it if it goes straight to the contact.
+        Read receipts and typing indicators the user does not send to the
+        recipient stop here.
+        """
+        setting = PRIVACY_SETTINGS.get(message_type)
+        if message_type == RECEIPT_MESSAGE and isinstance(message, dict) and message.get("status") == "delivered":
+            setting = None
+        if setting and not self.sends_allowed(setting, recipient_username):
+            logger.debug(f"Not sending {setting} to {recipient_username}.")
+            return
+        if self.delegation:
+            logger.debug(f"A delegated key only sends chat messages; not sending type {message_type}.")
+            return
+
+        sender_private_key = self.crypto_utils.load_private_key(self.current_user["username"])
+        if not sender_private_key:
+            logger.error("No private key available for handshake.")
+            return
+
+        if not self.db_manager:
+            logger.error("DB manager not initialized.")
+            return
+
+        contact = self.db_manager.get_contact(self.current_user["username"], recipient_username)
+        if not contact:
+            logger.error(f"No contact record found for {recipient_username}. Cannot send handshake.")
+            return
+        if not self.is_trusted(recipient_username):
+            logger.error(f"The key of {recipient_username} changed and is not confirmed yet. Not sending.")
+            return
+
+        recipient_public_key_pem = contact[1]
+
+        address = await self._recipient_address(recipient_username)
+        if address is None:
+            return
+
+        # inner message format
+        inner_payload = json.dumps({"type": message_type, "message": message})
+        if message_type != HANDSHAKE_MESSAGE:
+            inner_payload = self._ratchet(recipient_username, inner_payload)
+
+        # Encrypt and sign
+        enc_result = await self.crypto_utils.encrypt_message_async(recipient_public_key_pem, inner_payload)
+        encrypted_payload_str = json.dumps(enc_result)
+        payload_signature = await self.crypto_utils.sign_message_async(sender_private_key, encrypted_payload_str)
+
+        payload = {
+            "sender": self.current_user["username"],
+            **address,
+            "body": {
+                "encryptedPayload": enc_result,
+                "payloadSignature": payload_signature
+            },
             "encrypted": True
         }
 
         # Sign the entire payload (for server to verify)
         payload_str = json.dumps(payload)
-        signature = self.crypto_utils.sign_message(sender_private_key, payload_str)
+        signature = await self.crypto_utils.sign_message_async(sender_private_key, payload_str)
+
+        # Straight to the contact if we can reach them, else through the server
+        direct = MixnetMessage.directMessage(content=payload_str, signature=signature)
+        if await self._send_direct(recipient_username, direct, surbs):
+            return True
+        if self._seals_for(recipient_username):
+            msg = await self._sealed_message(recipient_public_key_pem, address, payload)
+        else:
+            msg = MixnetMessage.send(content=payload_str, signature=signature)
+
+        await self.connection_client.send_message(msg)
+        return True
+
+    async def _send_direct(self, recipient_username, msg, surbs=None):
+        """
+        Sends a directMessage to the contact without the server: to the nym
+        address from their handshake, or as a reply through the sender tag of
+        their direct messages. Returns False if neither is known or the reply
+        failed; the caller then goes through the server. To a contact in
+        anonymous mode it goes with `surbs` reply SURBs, SURBS_PER_MESSAGE by
+        default, for them to answer through.
+        """
+        if recipient_username in self.nym_addresses:
+            msg["recipient"] = self.nym_addresses[recipient_username]
+            envelope = self._direct_envelope(recipient_username, msg)
+            if surbs or recipient_username in self.anonymous:
+                await self.connection_client.send_with_surbs(msg["recipient"], envelope, surbs or SURBS_PER_MESSAGE)
+            else:
+                await self.connection_client.send_message({**msg, "message": envelope})
+            return True
+        sender_tag = self.sender_tags.get(recipient_username)
+        if sender_tag is None:
+            return False
+        envelope = self._direct_envelope(recipient_username, msg)
+        try:
+            await self.connection_client.reply_with_surb(sender_tag, envelope)
+        except Exception as e:
+            logger.warning(f"Could not reply to {recipient_username} through their sender tag: {e}")
+            self._forget_sender_tag(recipient_username)
+            return False
+        await self._spend_surbs(recipient_username, len(envelope.encode()))
+        return True
+
+    async def _spend_surbs(self, username, size):
+        """
+        Counts the SURBs a reply of `size` bytes to `username` took, and asks
+        them for more once few are left.
+        """
+        left = self.reply_surbs.get(username, 0) - max(1, -(-size // SURB_PAYLOAD_BYTES))
+        self.reply_surbs[username] = max(left, 0)
+        if left >= SURB_LOW_WATER or username in self.surbs_requested:
+            return
+        # Marked first: the request itself is a reply, and comes back here.
+        self.surbs_requested.add(username)
+        logger.info(f"Asking {username} for {SURB_TOP_UP} more reply SURBs.")
+        await self._send_typed(username, SURB_MESSAGE, {"surbs": SURB_TOP_UP})
+
+    async def _handle_surbs(self, from_user, message):
+        """
+        A contact replying through our SURBs asks for more, which go straight
+        to their nym address; or more of theirs arrived, on top of those every
+        message brings.
+        """
+        if not isinstance(message, dict):
+            logger.warning(f"Malformed SURB message from {from_user}.")
+            return
+        count = message.get("surbs", message.get("topUp"))
+        if not isinstance(count, int) or isinstance(count, bool) or count < 1:
+            logger.warning(f"Malformed SURB message from {from_user}.")
+            return
+        if "topUp" in message:
+            self.reply_surbs[from_user] = self.reply_surbs.get(from_user, 0) + max(count - SURBS_PER_MESSAGE, 0)
+            return
+        if from_user not in self.nym_addresses:
+            logger.warning(f"{from_user} asked for reply SURBs, but we cannot reach them directly.")
+            return
+        count = min(count, MAX_SURB_TOP_UP)
+        if await self._send_typed(from_user, SURB_MESSAGE, {"topUp": count}, surbs=count):
+            logger.info(f"Sent {from_user} {count} more reply SURBs")
+
+    def _direct_envelope(self, username, msg):
+        """
+        The envelope of the directMessage `msg` as sent to `username`: compact
+        if their handshake agreed on it and it has a compact form, else JSON.
+        """
+        agreed = self.contact_handshake(username)
+        if agreed and COMPACT_FEATURE in agreed[1]:
+            return encode_envelope(msg["message"]) or msg["message"]
+        return msg["message"]
+
+    def _remember_sender_tag(self, username, sender_tag):
+        """
+        Keeps the sender tag of a verified direct message from `username`, and
+        counts the reply SURBs that came with it.
+        """
+        if self.sender_tags.get(username) != sender_tag:
+            # The SURBs of another tag do not carry over.
+            self.reply_surbs[username] = 0
+        self.reply_surbs[username] = self.reply_surbs.get(username, 0) + SURBS_PER_MESSAGE
+        self.surbs_requested.discard(username)
+        if self.sender_tags.get(username) == sender_tag:
+            return
+        self.sender_tags[username] = sender_tag
+        if self.db_manager and self.nym_address:
+            self.db_manager.set_sender_tag(self.current_user["username"], username, sender_tag, self.nym_address)
+
+    def _forget_sender_tag(self, username):
+        self.reply_surbs.pop(username, None)
+        if self.sender_tags.pop(username, None) is not None and self.db_manager:
+            self.db_manager.delete_sender_tag(self.current_user["username"], username)
+
+    def load_sender_tags(self):
+        """
+        Restores the sender tags stored for the current user. Only tags that
+        reached our current nym address are usable: the SURBs behind them are
+        kept with that mixnet identity, so an ephemeral one never has them.
+        """
+        self.sender_tags.clear()
+        if not self.db_manager or not self.nym_address:
+            return
+        self.sender_tags.update(self.db_manager.get_sender_tags(self.current_user["username"], self.nym_address))
+
+    async def _sealed_message(self, recipient_public_key_pem, address, payload, send_id=None):
+        """
+        A sealedSend of `payload`: the recipient gets exactly what the server
+        would forward for a plain send, but encrypted to them, so only the
+        `address` fields are visible to the server.
+        """
+        forward = {k: payload[k] for k in ("sender", "body", "senderPublicKey", "delegation") if k in payload}
+        sealed = await self.crypto_utils.encrypt_message_async(recipient_public_key_pem, json.dumps(forward))
+        return MixnetMessage.sealedSend(json.dumps({**address, "sealed": sealed}), send_id)
+
+    async def _recipient_address(self, recipient_username):
+        """
+        The payload field naming the recipient: their routing token when
+        routing tokens are on and the message goes through the server, their
+        username otherwise. The first send to a contact this session queries
+        them for a token. Returns None if that query gets no answer.
+        """
+        if not self.use_routing_tokens or recipient_username in self.nym_addresses:
+            return {"recipient": recipient_username}
+        if recipient_username not in self.routing_tokens:
+            if not isinstance(await self.query_user(recipient_username), dict):
+                logger.error(f"Could not get a routing token for {recipient_username}.")
+                return None
+        token = self.routing_tokens.get(recipient_username)
+        if token is None:
+            logger.warning(f"The server issues no routing tokens; addressing {recipient_username} by name.")
+            return {"recipient": recipient_username}
+        return {"recipientToken": token}
+
+    async def handle_send_response(self, content):
+        """
+        Records the server's verdict on a sent message. Servers that predate
+        send ids answer with a bare status, which is taken to refer to the
+        oldest message still waiting.
+        """
+        if isinstance(content, dict):
+            send_id, status = content.get("id"), content.get("status")
+        else:
+            send_id, status = next(iter(self.pending_sends), None), content
+        if send_id not in self.pending_sends:
+            logger.warning(f"sendResponse for an unknown or expired message: {status}")
+            return
+        self._finish_send(send_id, "sent" if status == "success" else str(status))
+
+    def _expire_send(self, send_id):
+        if send_id in self.pending_sends:
+            self._finish_send(send_id, "error: no response from server")
+
+    def _finish_send(self, send_id, status):
+        recipient = self.pending_sends.pop(send_id)
+        self.pending_contents.pop(send_id, None)
+        if status == "sent" and not recipient.startswith(GROUP_CHAT_PREFIX) and self.db_manager:
+            self.db_manager.set_message_status(self.current_user["username"], recipient, [send_id], "sent")
+        # A receipt may have overtaken the server's answer.
+        if self.send_status.get(send_id) not in RECEIPT_STATUSES:
+            self.send_status[send_id] = status
+        if status == "error: invalid routing token":
+            # Issued under a server key that has since changed; query again next time.
+            self.routing_tokens.pop(recipient, None)
+        if status != "sent":
+            logger.warning(f"Message to {recipient} failed: {status}")
+            if self.send_failed_callback:
+                self.send_failed_callback(recipient, status)
+        if self.chat_messages is not None and recipient == self._get_active_chat():
+            self._mark_dirty("chat")
+
+    # --------------------------------------------------------------------------
+    # Outbox
+    # --------------------------------------------------------------------------
+    def set_connection_status(self, status, reason=None):
+        """
+        Follows the mixnet client's connection state ("connected",
+        "disconnected" or "reconnecting") and the `reason` it changed, such as
+        "resumed" or "network_changed"; reconnecting sends the outbox.
+        """
+        was_online, self.online = self.online, status == "connected"
+        self.connection_status, self.connection_reason = status, reason
+        if self.online and not was_online:
+            logger.info(f"Mixnet connection is back ({reason}).")
+            if self.outbox_pending:
+                asyncio.create_task(self.flush_outbox())
+        elif was_online and not self.online:
+            logger.warning(f"Mixnet connection dropped ({status}, {reason}); new messages wait in the outbox.")
+
+    def _queueing(self):
+        """ Whether new messages go to the outbox; while it holds any, they all do, to keep their order. """
+        return not self.online or self.outbox_pending
+
+    def _queue_outbox(self, chat_id, content, send_id=None):
+        """ Stores a message to send later and returns the send id it is shown with. """
+        outbox_id = self.db_manager.queue_outbox(self.current_user["username"], chat_id, json.dumps(content))
+        send_id = send_id or secrets.token_hex(8)
+        self.send_status[send_id] = "waiting"
+        self.outbox_sends[outbox_id] = send_id
+        self.outbox_pending = True
+        logger.info(f"Queued a message to {chat_id} until the mixnet is back.")
+        if self.online and not self.flushing_outbox:
+            asyncio.create_task(self.flush_outbox())
+        return send_id
+
+    def load_outbox(self):
+        """
+        Picks up messages the current user queued in an earlier session and,
+        if the mixnet is up, starts sending them.
+        """
+        self.outbox_sends.clear()
+        self.outbox_pending = bool(self.db_manager and self.db_manager.get_outbox(self.current_user["username"]))
+        if self.outbox_pending and self.online:
+            asyncio.create_task(self.flush_outbox())
+
+    def save_snapshot(self):
+        """
+        Writes to the database what otherwise only lives in memory: messages
+        the server has not acknowledged yet, group histories arriving in
+        parts, sender keys waiting for their group and handshakes in flight.
+        A crash or power loss then loses at most what changed since the last
+        call; see restore_snapshot. Nothing is written if nothing changed.
+        """
+        username = self.current_user.get("username")
+        if not self.db_manager or not username:
+            return
+        snapshot = json.dumps({
+            "sends": [[self.pending_sends[send_id], content] for send_id, content in self.pending_contents.items()],
+            "histories": [[admin, history_id, pending]
+                          for (admin, history_id), pending in self.pending_histories.items()],
+            "senderKeys": self.pending_sender_keys,
+            "handshakes": self.handshakes,
+        }, sort_keys=True)
+        if snapshot == self.last_snapshot:
+            return
+        try:
+            self.db_manager.set_setting(username, SNAPSHOT_SETTING, snapshot)
+        except Exception as e:
+            logger.error(f"save_snapshot: {e}")
+            return
+        self.last_snapshot = snapshot
+
+    def restore_snapshot(self):
+        """
+        Picks up what was in flight when the last session of the current user
+        ended, e.g. in a crash. Unacknowledged messages go back to the outbox,
+        after anything queued there already, so a message the server took just
+        before the crash may arrive twice. The rest waits as it did before.
+        Call it before load_outbox.
+        """
+        username = self.current_user.get("username")
+        value = self.db_manager.get_setting(username, SNAPSHOT_SETTING) if self.db_manager and username else None
+        if not value:
+            return
+        try:
+            snapshot = json.loads(value)
+            for chat_id, content in snapshot.get("sends", []):
+                self.db_manager.queue_outbox(username, chat_id, json.dumps(content))
+            for admin, history_id, pending in snapshot.get("histories", []):
+                # JSON made the part indices strings.
+                pending["parts"] = {int(index): messages for index, messages in pending["parts"].items()}
+                self.pending_histories.setdefault((admin, history_id), pending)
+            for group_id, keys in snapshot.get("senderKeys", {}).items():
+                for sender, sender_key in keys.items():
+                    self.pending_sender_keys.setdefault(group_id, {}).setdefault(sender, sender_key)
+            for contact, handshake in snapshot.get("handshakes", {}).items():
+                self.handshakes.setdefault(contact, handshake)
+            logger.info(f"Restored what was in flight: {len(snapshot.get('sends', []))} unacknowledged messages.")
+        except (ValueError, TypeError, KeyError, AttributeError) as e:
+            logger.error(f"Ignoring a damaged snapshot: {e}")
+        # The messages are in the outbox now; they must not be queued twice.
+        self.last_snapshot = None
+        self.save_snapshot()
+
+    async def flush_outbox(self):
+        """
+        Sends the queued messages, oldest first, while the mixnet stays up. A
+        message that fails to go out stays queued, with everything after it.
+        """
+        if self.flushing_outbox or not self.db_manager or not self.online:
+            return
+        self.flushing_outbox = True
+        username = self.current_user["username"]
+        try:
+            while self.online and self.db_manager:
+                rows = self.db_manager.get_outbox(username)
+                if not rows:
+                    self.outbox_pending = False
+                    return
+                outbox_id, chat_id, content = rows[0]
+                content = json.loads(content)
+                # Chat messages keep the id they were stored with, so receipts find them.
+                message = content.get("message")
+                message_id = message.get("id") if isinstance(message, dict) else None
+                send_id = self.outbox_sends.get(outbox_id) or message_id or secrets.token_hex(8)
+                try:
+                    sent = await self._send_queued(chat_id, content, send_id)
+                except Exception as e:
+                    logger.warning(f"Outbox message to {chat_id} not sent yet: {e}")
+                    self.pending_sends.pop(send_id, None)
+                    self.send_status[send_id] = "waiting"
+                    return
+                self.db_manager.delete_outbox(username, outbox_id)
+                self.outbox_sends.pop(outbox_id, None)
+                if not sent:
+                    # Nothing to retry: the contact or group is no longer one we can send to.
+                    self.send_status[send_id] = "error: could not be sent"
+                    if self.send_failed_callback:
+                        self.send_failed_callback(chat_id, self.send_status[send_id])
+                if self.chat_messages is not None and chat_id == self._get_active_chat():
+                    self._mark_dirty("chat")
+        finally:
+            self.flushing_outbox = False
+
+    async def _send_queued(self, chat_id, content, send_id):
+        if chat_id.startswith(GROUP_CHAT_PREFIX):
+            group_id = chat_id[len(GROUP_CHAT_PREFIX):]
+            return await self._send_group_plaintext(group_id, content["message"], send_id)
+        return await self._send_chat(chat_id, content["message"], None, send_id, content["initial"])
+
+    # --------------------------------------------------------------------------
+    # Query
+    # --------------------------------------------------------------------------
+    async def query_user(self, target_username):
+        future = asyncio.get_running_loop().create_future()
+        entry = (target_username, future)
+        self.pending_queries.append(entry)
+        try:
+            msg = MixnetMessage.query(target_username)
+            await self.connection_client.send_message(msg)
+            logger.info(f"Sent query for user: {target_username}")
+
+            return await asyncio.wait_for(future, RESPONSE_TIMEOUT)
+        except Exception as e:
+            logger.error(f"query_user: {e}")
+            return None
+        finally:
+            self.pending_queries.remove(entry)
+
+    async def handle_query_response(self, content, queried=None):
+        """
+        Answers the waiting query for the user `content` describes, or, for a
+        miss, for the user the server echoed as `queried`. A miss from a server
+        that echoes nothing is only taken when a single query is waiting; with
+        several, they time out rather than get each other's answers.
+        """
+        logger.info("queryResponse received")
+        username = content.get("username") if isinstance(content, dict) else queried
+        waiting = [(name, future) for name, future in self.pending_queries if not future.done()]
+        if username is None and len(waiting) > 1:
+            logger.warning("A queryResponse does not say which of the waiting queries it answers.")
+        for name, future in waiting:
+            if username == name or (username is None and len(waiting) == 1):
+                future.set_result(content)
+                break
+        if self.db_manager and isinstance(content, dict):
+            username = content.get("username")
+            public_key = content.get("publicKey")
+            if username and public_key:
+                self._store_contact_key(username, public_key)
+            if username and content.get("routingToken"):
+                self.routing_tokens[username] = content["routingToken"]
+
+    # --------------------------------------------------------------------------
+    # Contact keys
+    # --------------------------------------------------------------------------
+    def _store_contact_key(self, username, public_key):
+        """
+        Stores the directory's key for `username`. If it differs from the key
+        we had, the change is recorded, the contact is untrusted until the user
+        confirms the new key, and the UI is warned.
+        """
+        active_user = self.current_user["username"]
+        contact = self.db_manager.get_contact(active_user, username)
+        if not contact:
+            self.db_manager.add_contact(active_user, username, public_key)
+            return
+        try:
+            old_fingerprint = self.crypto_utils.fingerprint(contact[1])
+            new_fingerprint = self.crypto_utils.fingerprint(public_key)
+        except ValueError as e:
+            logger.error(f"Unreadable public key for {username}: {e}")
+            return
+        if hmac.compare_digest(old_fingerprint, new_fingerprint):
+            return
+        logger.warning(f"The public key of {username} changed.")
+        self.db_manager.record_key_change(active_user, username, public_key, old_fingerprint, new_fingerprint)
+        self.audit("key_changed", f"{username}: {old_fingerprint} -> {new_fingerprint}")
+        if self.key_change_callback:
+            self.key_change_callback(username, old_fingerprint, new_fingerprint)
+
+    def contact_invite(self, include_address=True):
+        """
+        A nymchat:// invite others can add us with, without the server
+        directory. With our nym address in it, their messages come straight to
+        us, which also tells them where we connect from.
+        """
+        username = self.current_user["username"]
+        public_key_pem = self.crypto_utils.load_public_key(username).public_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo
+        ).decode()
+        address = self.nym_address if include_address else None
+        return ContactInvite.create(username, public_key_pem, address).to_uri()
+
+    def fingerprint(self, username=None):
+        """
+        The hex key fingerprint of a contact or with no `username` ours; None
+        if there is no key to show.
+        """
+        return self._describe_key(self.crypto_utils.fingerprint, username)
+
+    def safety_number(self, username=None):
+        """
+        The numeric safety number of a contact or with no `username` ours, to
+        compare out of band; None if there is no key to show.
+        """
+        return self._describe_key(self.crypto_utils.safety_number, username)
+
+    def _describe_key(self, describe, username):
+        own = self.current_user["username"]
+        try:
+            if username is None or username == own:
+                return describe(self.crypto_utils.public_key_pem(self.crypto_utils.load_public_key(own)))
+            contact = self.db_manager.get_contact(own, username) if self.db_manager else None
+            return describe(contact[1]) if contact else None
+        except (OSError, ValueError) as e:
+            logger.error(f"No fingerprint for {username or own}: {e}")
+            return None
+
+    def add_contact_from_invite(self, uri):
+        """
+        Adds the contact a nymchat:// invite names, with its key, and keeps its
+        nym address for this session. A key differing from the one stored for
+        a known contact goes through the usual key change check. Returns the
+        invite, or None if it is not valid or is our own.
+        """
+        try:
+            invite = ContactInvite.parse(uri)
+        except ValueError as e:
+            logger.warning(f"Invalid contact invite: {e}")
+            return None
+        if invite.username == self.current_user["username"]:
+            return None
+        self._store_contact_key(invite.username, invite.public_key)
+        if invite.nym_address:
+            self.nym_addresses[invite.username] = invite.nym_address
+        self.audit("contact_invite", f"{invite.username}: {invite.fingerprint}")
+        return invite
+
+    def is_trusted(self, username):
+        """ False while a changed key of `username` waits for the user's confirmation. """
+        if not self.db_manager:
+            return True
+        return self.db_manager.is_trusted(self.current_user["username"], username)
+
+    def trust_contact(self, username):
+        """ Confirms the current key of `username`, allowing sends again. """
+        self.db_manager.set_trusted(self.current_user["username"], username, True)
+        logger.info(f"Confirmed the new key of {username}.")
+        self.audit("key_confirmed", username)
+
+    def is_verified(self, username):
+        """ Whether the user compared safety numbers with `username` since their key last changed. """
+        if not self.db_manager:
+            return False
+        return self.db_manager.is_verified(self.current_user["username"], username)
+
+    def set_verified(self, username, verified=True):
+        """ Marks the current key of `username` as verified out of band, or no longer. """
+        self.db_manager.set_verified(self.current_user["username"], username, verified)
+        self.audit("contact_verified" if verified else "contact_unverified",
+                   f"{username}: {self.fingerprint(username)}")
+
+    def reject_key_change(self, username):
+        """
+        Goes back to the key `username` had before it changed. The directory
+        still hands out the new key, so the next time it does the warning
+        comes back.
+        """
+        if self.db_manager.reject_key_change(self.current_user["username"], username):
+            logger.info(f"Rejected the new key of {username}.")
+            self.audit("key_rejected", username)
+            return True
+        return False
+
+    def key_changes(self, username):
+        """ (old fingerprint, new fingerprint, UTC time) of each key change of `username`, oldest first. """
+        if not self.db_manager:
+            return []
+        return self.db_manager.get_key_changes(self.current_user["username"], username)
+
+    # --------------------------------------------------------------------------
+    # Key transparency
+    # --------------------------------------------------------------------------
+    def _record_observation(self, envelope):
+        """
+        Keeps the signed queryResponse that gave us a contact's key. Only
+        answers signed by a pinned server key are kept: anything else proves
+        nothing about what the server said.
+        """
+        if not self.server_keys or not self.db_manager:
+            return
+        raw, signature = envelope.get("content"), envelope.get("signature")
+        found = self._parse_content(raw)
+        if not isinstance(found, dict) or not found.get("username") or not isinstance(signature, str):
+            return
+        self.db_manager.set_key_observation(self.current_user["username"], found["username"], raw, signature)
+
+    def _observation_for(self, username):
+        """ The observation to send `username` with our next message, if it is due. """
+        if username in self.gossiped or not self.db_manager:
+            return None
+        observation = self.db_manager.get_key_observation(self.current_user["username"], username)
+        if not observation:
+            return None
+        self.gossiped.add(username)
+        content, signature = observation
+        return {"content": content, "signature": signature}
+
+    def _check_observation(self, from_user, observation):
+        """
+        Compares the key the server gave `from_user` for us with our own key.
+        Warns the UI, once per key, if they differ: either the server hands out
+        a different key for us, or `from_user` holds an outdated one.
+        """
+        if not isinstance(observation, dict) or not self.server_keys:
+            return
+        signed = {"action": "queryResponse", **observation}
+        if not self._signed_by_server(signed):
+            logger.warning(f"{from_user} sent a key observation not signed by the server; ignoring it.")
+            return
+        found = self._parse_content(observation.get("content"))
+        username = self.current_user["username"]
+        if not isinstance(found, dict) or found.get("username") != username:
+            return
+        try:
+            own = self.crypto_utils.fingerprint(
+                self.crypto_utils.public_key_pem(self.crypto_utils.load_public_key(username)))
+            observed = self.crypto_utils.fingerprint(found.get("publicKey") or "")
+        except (OSError, ValueError) as e:
+            logger.error(f"Cannot check the key observation of {from_user}: {e}")
+            return
+        if hmac.compare_digest(own, observed) or (from_user, observed) in self.equivocations:
+            return
+        self.equivocations.add((from_user, observed))
+        logger.error(f"The server gave {from_user} a different key for {username}: {observed}")
+        self.audit("key_equivocation", f"{from_user} was given {observed}")
+        if self.equivocation_callback:
+            self.equivocation_callback(from_user, own, observed)
+
+    # --------------------------------------------------------------------------
+    # Groups
+    # --------------------------------------------------------------------------
+    def _load_group(self, group_id):
+        """
+        (name, GroupSession, MembershipLog) of a group we are in, or None.
+        Groups made before signed membership have an empty log, so nobody,
+        us included, is a member any more and they only keep their history.
+        """
+        row = self.db_manager.get_group(self.current_user["username"], group_id) if self.db_manager else None
+        if not row:
+            return None
+        name, session, membership = row
+        log = MembershipLog.from_json(membership) if membership else MembershipLog.create(group_id)
+        return name, GroupSession.from_json(session), log
+
+    def _save_group(self, group_id, name, session, log):
+        self.db_manager.save_group(self.current_user["username"], group_id, name, session.to_json(), log.to_json())
+
+    async def _public_key_of(self, username):
+        """
+        The public key (PEM) of `username`, asking the directory for it if
+        need be; None while a changed key of theirs is not confirmed.
+        """
+        active_user = self.current_user["username"]
+        if username == active_user:
+            return self.crypto_utils.public_key_pem(self.crypto_utils.load_public_key(active_user))
+        contact = self.db_manager.get_contact(active_user, username)
+        if not contact:
+            await self.query_user(username)
+            contact = self.db_manager.get_contact(active_user, username)
+        if contact and not self.is_trusted(username):
+            logger.warning(f"The new key of {username} is not confirmed; not using it.")
+            return None
+        return contact[1] if contact else None
+
+    def _commit_entry(self, group_id, log, entry):
+        """ Signs a membership entry we proposed, applies it to `log` and stores it. """
+        username = self.current_user["username"]
+        signature = self.crypto_utils.sign_message(self.crypto_utils.load_private_key(username), entry)
+        log.apply(entry, signature, self.crypto_utils.public_key_pem(self.crypto_utils.load_public_key(username)))
+        self.db_manager.append_group_log(username, group_id, len(log) - 1, entry, signature)
+
+    async def create_group(self, name, members):
+        """
+        Starts a group with `members`, us as its admin, and sends each of them
+        the signed creation entry and our sender key. Returns the group id.
+        """
+        username = self.current_user["username"]
+        group_id = secrets.token_hex(16)
+        log = MembershipLog.create(group_id)
+        self._commit_entry(group_id, log, log.propose_create(username, name, sorted(set(members) | {username})))
+        session = GroupSession.create(group_id, log.members())
+        self._save_group(group_id, name, session, log)
+        self._add_chat(group_chat_id(group_id), name)
+        await self._send_membership(group_id, log.members())
+        await self._distribute_sender_key(group_id, name, session)
+        return group_id
+
+    def group_members(self, group_id):
+        """ (members, admins) of a group, or None if we do not know it. """
+        group = self._load_group(group_id)
+        if group is None:
+            return None
+        _name, _session, log = group
+        return log.members(), log.admins()
+
+    async def invite_member(self, group_id, member):
+        return await self._change_membership(group_id, "invite", member)
+
+    async def kick_member(self, group_id, member):
+        return await self._change_membership(group_id, "kick", member)
+
+    async def promote_member(self, group_id, member):
+        return await self._change_membership(group_id, "promote", member)
+
+    async def set_history_limit(self, group_id, limit):
+        """ Sets how many recent messages new members are sent (0: none); admins only. """
+        return await self._change_membership(group_id, "history", min(limit, MAX_SHARED_HISTORY))
+
+    def history_limit(self, group_id):
+        """ How many recent messages the group sends new members """
+        group = self._load_group(group_id)
+        return group[2].history_limit if group else 0
+
+    def create_invite(self, group_id, expires_in=None, single_use=True):
+        """
+        A code that lets whoever holds it ask us to add them to the group,
+        valid for `expires_in` seconds (forever if None) and, if `single_use`,
+        for one join. Only admins hand out invites. None if we cannot.
+        """
+        username = self.current_user["username"]
+        group = self._load_group(group_id)
+        if group is None or username not in group[2].admins():
+            return None
+        name, _session, _log = group
+        secret = secrets.token_hex(16)
+        expires_at = int(time.time()) + expires_in if expires_in is not None else None
+        secret_hash = hashlib.sha256(secret.encode()).hexdigest()
+        self.db_manager.save_group_invite(username, secret_hash, group_id, expires_at, single_use)
+        return invite_code(group_id, secret, username, name, expires_at)
+
+    async def redeem_invite(self, code):
+        """
+        Asks the admin named in an invite code to add us to its group; they
+        send the membership log and every member then sends us their sender
+        key. Returns the invite's fields if the request went out, else None.
+        """
+        invite = parse_invite(code)
+        if invite is None or (invite["expires_at"] is not None and invite["expires_at"] <= time.time()):
+            logger.warning("Invalid or expired invite code.")
+            return None
+        admin = invite["admin"]
+        if not self.db_manager.get_contact(self.current_user["username"], admin):
+            await self.query_user(admin)
+        request = {"groupId": invite["group_id"], "secret": invite["secret"]}
+        if not await self._send_typed(admin, GROUP_JOIN_MESSAGE, request):
+            logger.error(f"Could not send the join request to {admin}.")
+            return None
+        logger.info(f"Asked {admin} to join {invite['name']}.")
+        return invite
+
+    async def _handle_join_request(self, from_user, request):
+        """ Adds `from_user` to a group we admin if they hold a valid invite to it. """
+        username = self.current_user["username"]
+        if not isinstance(request, dict) or not self.db_manager:
+            return
+        group_id, secret = request.get("groupId"), request.get("secret")
+        if not isinstance(group_id, str) or not isinstance(secret, str):
+            logger.warning(f"Ignoring a malformed join request from {from_user}.")
+            return
+        group = self._load_group(group_id)
+        if group is None or username not in group[2].admins():
+            logger.warning(f"Join request from {from_user} for a group we do not admin.")
+            return
+        secret_hash = hashlib.sha256(secret.encode()).hexdigest()
+        if not self.db_manager.redeem_group_invite(username, secret_hash, group_id, int(time.time())):
+            logger.warning(f"Join request from {from_user} with an invalid, used or expired invite.")
+            self.audit("invite_rejected", f"{from_user} for {group[0]}")
+            return
+        if from_user in group[2].members():
+            await self._send_membership(group_id, [from_user])
+            return
+        await self.invite_member(group_id, from_user)
+
+    async def _change_membership(self, group_id, op, member):
+        """
+        Signs an invite, kick, promotion or history policy (admins only) and
+        sends the log to everyone it concerns, the kicked member included. A
+        changed member list moves us to a new sender key, and an invited member
+        is sent the history the group allows. Returns True if the change was made.
+        """
+        username = self.current_user["username"]
+        group = self._load_group(group_id)
+        if group is None:
+            return False
+        name, session, log = group
+        before = set(log.members())
+        try:
+            self._commit_entry(group_id, log, getattr(log, f"propose_{op}")(username, member))
+        except ValueError as e:
+            logger.error(f"Cannot {op} {member} in {name}: {e}")
+            return False
+        rotated = session.set_members(log.members())
+        self._save_group(group_id, name, session, log)
+        self.audit(f"group_{op}", f"{member} in {name}")
+        await self._send_membership(group_id, sorted(before | set(log.members())))
+        if rotated:
+            await self._distribute_sender_key(group_id, name, session)
+        if op == "invite":
+            await self._share_history(group_id, member)
+        return True
+
+    async def _share_history(self, group_id, member):
+        """
+        Sends a new member the group's recent messages as we have them, in
+        parts, bounded by the group's history policy, MAX_SHARED_HISTORY and
+        MAX_SHARED_HISTORY_AGE. Only admins share history; the new member takes
+        their word for it, as the senders' signatures do not cover it.
+        """
+        username = self.current_user["username"]
+        _name, _session, log = self._load_group(group_id)
+        limit = min(log.history_limit, MAX_SHARED_HISTORY)
+        if not limit:
+            return
+        cutoff = timeUtils.utc_stamp(datetime.fromtimestamp(time.time() - MAX_SHARED_HISTORY_AGE, timezone.utc))
+        rows = [row for row in self.db_manager.load_group_messages(username, group_id) if row[2] >= cutoff]
+        parts, part, size = [], [], 0
+        for sender, text, stamp in rows[-limit:]:
+            item = {"sender": sender, "message": text, "timestamp": stamp}
+            item_size = len(json.dumps(item))
+            if part and size + item_size > HISTORY_CHUNK_SIZE:
+                parts.append(part)
+                part, size = [], 0
+            part.append(item)
+            size += item_size
+        if part:
+            parts.append(part)
+        history_id = secrets.token_hex(8)
+        for index, messages in enumerate(parts):
+            share = {"groupId": group_id, "historyId": history_id, "index": index, "total": len(parts),
+                     "messages": messages}
+            if not await self._send_typed(member, GROUP_HISTORY_MESSAGE, share):
+                logger.warning(f"Could not share the history of {group_id} with {member}.")
+                return
+        logger.info(f"Shared {sum(len(p) for p in parts)} message(s) of history with {member}.")
+
+    async def _handle_group_history(self, from_user, share):
+        """
+        Collects the parts of a history shared with us by an admin of one of
+        our groups and, once all are in, merges its messages into the group's.
+        A history that overtakes the membership log making us a member waits
+        for it.
+        """
+        if not isinstance(share, dict) or not self.db_manager:
+            return
+        group_id, history_id = share.get("groupId"), share.get("historyId")
+        index, total, messages = share.get("index"), share.get("total"), share.get("messages")
+        if (not isinstance(group_id, str) or not isinstance(history_id, str)
+                or not isinstance(total, int) or not 0 < total <= MAX_SHARED_HISTORY
+                or not isinstance(index, int) or not 0 <= index < total
+                or not isinstance(messages, list)
+                or not all(isinstance(m, dict) and isinstance(m.get("sender"), str)
+                           and isinstance(m.get("message"), str)
+                           and timeUtils.parse_stamp(m.get("timestamp")) is not None for m in messages)):
+            logger.warning(f"Ignoring malformed group history from {from_user}.")
+            return
+        key = (from_user, history_id)
+        if key not in self.pending_histories and len(self.pending_histories) >= MAX_PENDING_HISTORIES:
+            logger.warning(f"Too many group histories arriving; dropping one from {from_user}.")
+            return
+        pending = self.pending_histories.setdefault(key, {"groupId": group_id, "total": total, "parts": {}})
+        if (pending["groupId"], pending["total"]) != (group_id, total):
+            return
+        pending["parts"][index] = messages
+        self._merge_histories(group_id)
+
+    def _merge_histories(self, group_id):
+        """
+        Merges every complete history shared for `group_id` by one of its
+        admins, skipping messages we already have, once we are a member.
+        """
+        username = self.current_user["username"]
+        group = self._load_group(group_id)
+        if group is None or username not in group[2].members():
+            return
+        name, _session, log = group
+        for key, pending in list(self.pending_histories.items()):
+            admin = key[0]
+            if pending["groupId"] != group_id or len(pending["parts"]) < pending["total"]:
+                continue
+            if admin not in log.admins():
+                continue  # perhaps promoted in an entry still on its way
+            del self.pending_histories[key]
+            rows = [(m["sender"], m["message"], m["timestamp"])
+                    for i in range(pending["total"]) for m in pending["parts"][i]][-MAX_SHARED_HISTORY:]
+            imported = self.db_manager.import_group_messages(username, group_id, rows)
+            logger.info(f"{admin} shared {imported} earlier message(s) of {name}.")
+            if imported and self.chat_messages is not None:
+                self.chat_messages[group_chat_id(group_id)] = self.group_history(group_id)
+                if group_chat_id(group_id) == self._get_active_chat():
+                    self._mark_dirty("chat")
+
+    async def _send_membership(self, group_id, recipients):
+        """
+        Sends the group's whole signed membership log to `recipients`. New
+        members replay it from the start; the others skip what they have.
+        """
+        username = self.current_user["username"]
+        entries = self.db_manager.get_group_log(username, group_id)
+        control = {"groupId": group_id, "log": [{"entry": e, "signature": sig} for e, sig in entries]}
+        for member in recipients:
+            if member == username:
+                continue
+            if not self.db_manager.get_contact(username, member):
+                await self.query_user(member)
+            if not await self._send_typed(member, GROUP_CONTROL_MESSAGE, control):
+                logger.warning(f"Could not send the membership of {group_id} to {member}.")
+
+    async def _handle_group_control(self, from_user, control):
+        """
+        Applies the membership entries `from_user` relayed. Each is checked
+        against its own signer's key and the log so far, so neither the relay
+        nor the server can change who is in the group. A group we do not know
+        is joined if the log makes us a member.
+        """
+        username = self.current_user["username"]
+        if not isinstance(control, dict) or not self.db_manager:
+            return
+        group_id, entries = control.get("groupId"), control.get("log")
+        if (not isinstance(group_id, str) or not isinstance(entries, list)
+                or not all(isinstance(e, dict) and isinstance(e.get("entry"), str)
+                           and isinstance(e.get("signature"), str) for e in entries)):
+            logger.warning(f"Ignoring a malformed membership log from {from_user}.")
+            return
+        group = self._load_group(group_id)
+        log = group[2] if group else MembershipLog.create(group_id)
+        applied = []
+        for item in entries:
+            try:
+                entry = json.loads(item["entry"])
+                actor = entry["actor"]
+            except (ValueError, KeyError, TypeError):
+                logger.warning(f"Ignoring a malformed membership entry from {from_user}.")
+                break
+            public_key = await self._public_key_of(actor) if isinstance(actor, str) else None
+            if public_key is None:
+                logger.error(f"No public key for {actor}. Cannot check their membership entry.")
+                break
+            try:
+                if log.apply(item["entry"], item["signature"], public_key):
+                    applied.append(item)
+            except ValueError as e:
+                if str(e).startswith("fork"):
+                    logger.error(f"Conflicting membership of {group_id} from {from_user}: {e}")
+                    self.audit("group_fork", f"{group_id} from {from_user}")
+                else:
+                    logger.warning(f"Membership entry from {from_user} rejected: {e}")
+                break
+        if not applied:
+            return
+        is_member = username in log.members()
+        if group is None and not is_member:
+            return
+
+        if group is None:
+            name, session, rotated = log.name, GroupSession.create(group_id, log.members()), True
+        else:
+            name, session, _log = group
+            rotated = session.set_members(log.members())
+        first_seq = len(log) - len(applied)
+        for seq, item in enumerate(applied, first_seq):
+            self.db_manager.append_group_log(username, group_id, seq, item["entry"], item["signature"])
+        self._save_group(group_id, name, session, log)
+        if group is None:
+            logger.info(f"{from_user} added us to the group {name}.")
+            self._add_chat(group_chat_id(group_id), name)
+        if not is_member:
+            logger.info(f"We were removed from the group {name}.")
+            self.pending_sender_keys.pop(group_id, None)
+            return
+        for sender, sender_key in self.pending_sender_keys.pop(group_id, {}).items():
+            await self._handle_sender_key(sender, {"groupId": group_id, "senderKey": sender_key})
+        self._merge_histories(group_id)
+        if rotated:
+            await self._distribute_sender_key(group_id, name, session)
+
+    async def _distribute_sender_key(self, group_id, name, session):
+        """
+        Sends our current sender key to every other member, each over our
+        end-to-end channel with them.
+        """
+        username = self.current_user["username"]
+        announcement = {"groupId": group_id, "senderKey": json.loads(session.distribution_json())}
+        for member in session.members():
+            if member == username:
+                continue
+            if not self.db_manager.get_contact(username, member):
+                await self.query_user(member)
+            if not await self._send_typed(member, SENDER_KEY_MESSAGE, announcement):
+                logger.warning(f"Could not send our sender key for {name} to {member}.")
+
+    async def _handle_sender_key(self, from_user, announcement):
+        """
+        Stores the sender key `from_user` sent for a group, if the group's
+        membership log has them as a member. A key that arrives before the
+        entries admitting its sender waits for them.
+        """
+        username = self.current_user["username"]
+        if not isinstance(announcement, dict) or not self.db_manager:
+            return
+        group_id, sender_key = announcement.get("groupId"), announcement.get("senderKey")
+        if not isinstance(group_id, str) or not isinstance(sender_key, dict):
+            logger.warning(f"Ignoring a malformed sender key from {from_user}.")
+            return
+        group = self._load_group(group_id)
+        if group is None or from_user not in group[2].members():
+            waiting = sum(len(keys) for keys in self.pending_sender_keys.values())
+            if waiting < MAX_PENDING_SENDER_KEYS:
+                self.pending_sender_keys.setdefault(group_id, {})[from_user] = sender_key
+            return
+        name, session, log = group
+        if username not in log.members():
+            return
+        try:
+            session.process_distribution(from_user, json.dumps(sender_key))
+        except ValueError as e:
+            logger.error(f"Sender key from {from_user} rejected: {e}")
+            return
+        self._save_group(group_id, name, session, log)
+
+    async def send_group_message(self, group_id, message_content, forwarded_from=None):
+        """
+        Encrypts a message once, under our sender key, and has the server relay
+        it to every other member. Returns its send id, like `send_direct_message`.
+        """
+        if not message_content.strip():
+            return
+        username = self.current_user["username"]
+        group = self._load_group(group_id)
+        if group is None:
+            logger.error(f"Cannot send to group {group_id}.")
+            return
+        plaintext = {"type": 0, "message": message_content}
+        stored_text = message_content
+        # Mentions are tagged so the members named are alerted, not just shown the text; those in
+        # a forwarded message were meant for another chat.
+        mentions = sorted(mentioned_usernames(message_content) & set(group[2].members()) - {username})
+        if forwarded_from:
+            plaintext["forwardedFrom"] = {"sender": forwarded_from}
+            stored_text = forwarded_text(forwarded_from, message_content)
+        elif mentions:
+            plaintext["mentions"] = mentions
+        if self._queueing():
+            send_id = self._queue_outbox(group_chat_id(group_id), {"message": plaintext})
+        else:
+            send_id = await self._send_group_plaintext(group_id, plaintext)
+        if send_id:
+            self.db_manager.save_group_message(username, group_id, username, stored_text)
+        return send_id
+
+    async def _send_group_plaintext(self, group_id, plaintext, send_id=None):
+        """
+        Encrypts `plaintext` (a dict) for the group and sends it; returns the
+        send id, `send_id` if one is given.
+        """
+        username = self.current_user["username"]
+        private_key = self.crypto_utils.load_private_key(username)
+        group = self._load_group(group_id)
+        if not private_key or group is None or username not in group[2].members():
+            logger.error(f"Cannot send to group {group_id}.")
+            return
+        name, session, log = group
+
+        # Encrypted right here, not on the worker pool: the chain must not
+        # advance for two messages at once.
+        group_message = session.encrypt(json.dumps(plaintext))
+        # Saved before sending: a message key must never encrypt twice.
+        self._save_group(group_id, name, session, log)
+
+        payload = {"sender": username}
+        others = [m for m in session.members() if m != username]
+        tokens = [self.routing_tokens[m] for m in others if self.use_routing_tokens and m in self.routing_tokens]
+        names = [m for m in others if not (self.use_routing_tokens and m in self.routing_tokens)]
+        if names:
+            payload["recipients"] = names
+        if tokens:
+            payload["recipientTokens"] = tokens
+        payload["group"] = json.loads(group_message)
+        payload["groupSignature"] = await self.crypto_utils.sign_message_async(private_key, group_message)
+        payload_str = json.dumps(payload)
+        signature = await self.crypto_utils.sign_message_async(private_key, payload_str)
+
+        send_id = send_id or secrets.token_hex(8)
+        self.send_status[send_id] = "sending"
+        self.pending_sends[send_id] = group_chat_id(group_id)
+        self.pending_contents[send_id] = {"message": plaintext}
+        asyncio.get_running_loop().call_later(RESPONSE_TIMEOUT, self._expire_send, send_id)
+        await self.connection_client.send_message(MixnetMessage.sendGroup(payload_str, signature, send_id))
+        logger.info(f"Sent group message to {name}")
+        return send_id
+
+    async def create_poll(self, group_id, question, options, multiple=False):
+        """
+        Posts a poll to the group: a question and 2 to MAX_POLL_OPTIONS distinct
+        options, of which voters pick one, or any number if `multiple`.
+        Returns the poll id, or None if the poll is not valid or was not sent.
+        """
+        question = question.strip()
+        options = [option.strip() for option in options if option.strip()]
+        if not question or not 2 <= len(options) <= MAX_POLL_OPTIONS or len(set(options)) != len(options):
+            return None
+        poll = {"id": secrets.token_hex(8), "question": question, "options": options, "multiple": bool(multiple)}
+        if not await self._send_group_plaintext(group_id, {"type": POLL_MESSAGE, "poll": poll}):
+            return None
+        self._store_poll(group_id, self.current_user["username"], poll)
+        return poll["id"]
+
+    async def vote(self, group_id, poll_id, choices):
+        """
+        Votes in a poll, replacing our earlier vote; no choices withdraws it.
+        Returns whether the vote was sent.
+        """
+        poll = next((p for p in self.poll_results(group_id) if p["id"] == poll_id), None)
+        choices = sorted(set(choices))
+        if poll is None or not self._valid_choices(poll, choices):
+            return False
+        vote = {"pollId": poll_id, "choices": choices}
+        if not await self._send_group_plaintext(group_id, {"type": VOTE_MESSAGE, "vote": vote}):
+            return False
+        self.db_manager.save_poll_vote(self.current_user["username"], group_id, poll_id,
+                                       self.current_user["username"], json.dumps(choices))
+        if self.chat_messages is not None:
+            self._mark_dirty("chat")
+        return True
+
+    @staticmethod
+    def _valid_choices(poll, choices):
+        return (all(isinstance(c, int) and 0 <= c < len(poll["options"]) for c in choices)
+                and len(set(choices)) == len(choices) and (poll["multiple"] or len(choices) <= 1))
+
+    def _store_poll(self, group_id, creator, poll):
+        """ Stores a poll if it is well formed; returns whether it was. """
+        if (not isinstance(poll, dict) or not isinstance(poll.get("id"), str) or not poll["id"]
+                or not isinstance(poll.get("question"), str) or not poll["question"].strip()
+                or not isinstance(poll.get("options"), list)
+                or not 2 <= len(poll["options"]) <= MAX_POLL_OPTIONS
+                or not all(isinstance(o, str) and o for o in poll["options"])
+                or not isinstance(poll.get("multiple", False), bool)):
+            return False
+        self.db_manager.save_poll(self.current_user["username"], group_id, poll["id"], creator,
+                                  poll["question"], json.dumps(poll["options"]), poll.get("multiple", False))
+        return True
+
+    def poll_results(self, group_id):
+        """
+        The group's polls, oldest first, with their counts: dicts of "id",
+        "creator", "question", "options", "multiple", "timestamp", "counts"
+        (votes per option), "voters" and "mine" (our choices). Votes that do
+        not fit their poll, or come from non-members, are not counted.
+        """
+        if not self.db_manager:
+            return []
+        username = self.current_user["username"]
+        group = self._load_group(group_id)
+        members = set(group[2].members()) if group else set()
+        results = []
+        for poll_id, creator, question, options, multiple, stamp in self.db_manager.get_polls(username, group_id):
+            options = json.loads(options)
+            poll = {"id": poll_id, "creator": creator, "question": question, "options": options,
+                    "multiple": multiple, "timestamp": stamp, "counts": [0] * len(options), "voters": 0, "mine": []}
+            for voter, choices in self.db_manager.get_poll_votes(username, group_id, poll_id):
+                choices = json.loads(choices)
+                if (voter != username and voter not in members) or not isinstance(choices, list) \
+                        or not self._valid_choices(poll, choices) or not choices:
+                    continue
+                poll["voters"] += 1
+                for choice in choices:
+                    poll["counts"][choice] += 1
+                if voter == username:
+                    poll["mine"] = choices
+            results.append(poll)
+        return results
+
+    async def _handle_group_message(self, content):
+        """ Checks, decrypts and stores a message sent to one of our groups. """
+        from_user, message, signature = content.get("sender"), content.get("group"), content.get("groupSignature")
+        if not from_user or not isinstance(message, dict) or not isinstance(signature, str):
+            logger.error("Malformed group message.")
+            return
+        group_id = message.get("groupId")
+        group = self._load_group(group_id) if isinstance(group_id, str) else None
+        if group is None:
+            logger.warning(f"Group message from {from_user} for a group we are not in.")
+            return
+        name, session, log = group
+
+        username = self.current_user["username"]
+        contact = self.db_manager.get_contact(username, from_user)
+        if not contact:
+            await self.query_user(from_user)
+            contact = self.db_manager.get_contact(username, from_user)
+        if not contact:
+            logger.error(f"No public key for {from_user}. Cannot verify their group message.")
+            return
+        if not self.is_trusted(from_user):
+            logger.warning(f"Dropping a group message from {from_user}: their new key is not confirmed.")
+            return
+
+        signed = json.dumps(message)
+        sender_public_key = serialization.load_pem_public_key(contact[1].encode())
+        if not self.crypto_utils.verify_signature(sender_public_key, signed, signature):
+            logger.error(f"Signature verification failed for {from_user}. Dropping group message.")
+            self.audit("signature_failed", from_user)
+            return
+        try:
+            message_obj = json.loads(session.decrypt(from_user, contact[1], signed, signature))
+        except ValueError as e:
+            logger.error(f"Group message from {from_user} could not be decrypted: {e}")
+            return
+        self._save_group(group_id, name, session, log)
+
+        if not isinstance(message_obj, dict):
+            return
+        if message_obj.get("type") in (POLL_MESSAGE, VOTE_MESSAGE):
+            self._handle_poll_message(group_id, name, from_user, message_obj)
+            return
+        if message_obj.get("type") != 0:
+            return
+        actual_message = message_obj.get("message")
+        if not isinstance(actual_message, str):
+            return
+        # Only a tag the text bears out counts: nobody is alerted by a mention they cannot see.
+        mentions = message_obj.get("mentions")
+        mention = isinstance(mentions, list) and username in mentions and self.mentions_me(actual_message)
+        forwarded_sender = _forwarded_sender(message_obj)
+        if forwarded_sender:
+            actual_message = forwarded_text(forwarded_sender, actual_message)
+        self.db_manager.save_group_message(username, group_id, from_user, actual_message)
+        self._update_chat_ui(from_user, actual_message, group_chat_id(group_id), name, mention)
+
+    def _handle_poll_message(self, group_id, name, from_user, message_obj):
+        """ Stores a poll or a vote from a group member and refreshes the counts shown. """
+        username = self.current_user["username"]
+        if message_obj["type"] == POLL_MESSAGE:
+            poll = message_obj.get("poll")
+            if not self._store_poll(group_id, from_user, poll):
+                logger.warning(f"Ignoring a malformed poll from {from_user}.")
+                return
+            chat_id = group_chat_id(group_id)
+            self._add_chat(chat_id, name)
+            if (self.chat_messages is not None and chat_id != self._get_active_chat()
+                    and self.notification_level(chat_id) == "all" and self.new_message_callback):
+                self.new_message_callback(from_user, _("Poll: {question}", question=poll["question"]))
+        else:
+            vote = message_obj.get("vote")
+            if (not isinstance(vote, dict) or not isinstance(vote.get("pollId"), str)
+                    or not isinstance(vote.get("choices"), list)):
+                logger.warning(f"Ignoring a malformed vote from {from_user}.")
+                return
+            # Checked against the poll when counted; the poll may still be on its way.
+            self.db_manager.save_poll_vote(username, group_id, vote["pollId"], from_user, json.dumps(vote["choices"]))
+        if self.chat_messages is not None and group_chat_id(group_id) == self._get_active_chat():
+            self._mark_dirty("chat")
+
+    def mentions_me(self, text):
+        """ Whether `text` mentions the current user """
+        return self.current_user["username"] in mentioned_usernames(text)
+
+    def group_history(self, group_id):
+        """ The messages of a group as UI entries: [(sender, text, timestamp, None)] """
+        if not self.db_manager:
+            return []
+        rows = self.db_manager.load_group_messages(self.current_user["username"], group_id)
+        return [(sender, text, stamp, None) for sender, text, stamp in rows]
+
+    def search_messages(self, query):
+        """
+        Messages in every conversation matching all words of `query`, grouped
+        by chat: [(chat id, hits)], the chat with the best match first. Hits
+        are {"id", "sender", "snippet", "timestamp"}, oldest first; see
+        `PyDb.search_messages` for the snippet's match markers.
+        """
+        if not self.db_manager or not query.strip():
+            return []
+        chats = {}
+        for chat, message_id, sender, snippet, stamp in self.db_manager.search_messages(
+                self.current_user["username"], query, SEARCH_LIMIT):
+            chats.setdefault(chat, []).append(
+                {"id": message_id, "sender": sender, "snippet": snippet, "timestamp": stamp})
+        for hits in chats.values():
+            hits.sort(key=lambda hit: (hit["timestamp"], hit["id"]))
+        return list(chats.items())
+
+    async def forward_message(self, chat_id, sender, text):
+        """
+        Sends a message `sender` wrote in another chat on to `chat_id`, marked
+        as theirs; one that was forwarded already stays marked as its first
+        sender's. Returns the text as it now reads in `chat_id` and the send
+        id, None if nothing was sent.
+        """
+        sender, text = forwarded_from(text) or (sender, text)
+        if chat_id.startswith(GROUP_CHAT_PREFIX):
+            send_id = await self.send_group_message(chat_id.removeprefix(GROUP_CHAT_PREFIX), text, sender)
+        else:
+            send_id = await self.send_direct_message(chat_id, text, sender)
+        return forwarded_text(sender, text), send_id
+
+    def delete_local_messages(self, chat_id, entries):
+        """
+        Deletes this device's copy of messages of `chat_id`, given as the
+        `(sender, text, stamp, send_id)` entries shown for them; the other
+        side keeps theirs. Returns how many were deleted.
+        """
+        if not self.db_manager:
+            return 0
+        self.flush_writes()
+        username = self.current_user["username"]
+        deleted = 0
+        for sender, text, stamp, _send_id in entries:
+            if chat_id.startswith(GROUP_CHAT_PREFIX):
+                found = self.db_manager.delete_group_message(
+                    username, chat_id.removeprefix(GROUP_CHAT_PREFIX), sender, text, stamp)
+            else:
+                found = self.db_manager.delete_message(
+                    username, chat_id, "to" if sender == username else "from", text, stamp)
+            deleted += found
+        if self.chat_messages is not None and chat_id in self.chat_messages:
+            gone = {id(entry) for entry in entries}
+            self.chat_messages[chat_id] = [entry for entry in self.chat_messages[chat_id] if id(entry) not in gone]
+        logger.info(f"Deleted {deleted} local message(s) of {chat_id}.")
+        return deleted
+
+    # --------------------------------------------------------------------------
+    # Security audit log
+    # --------------------------------------------------------------------------
+    def audit(self, event, detail="", username=None):
+        """
+        Appends a security event to the audit log of `username` (default: the
+        current user). Events of an account that is not logged in, such as a
+        failed login, go to its database if there is one on this device.
+        Failing to log never fails the operation being logged.
+        """
+        current = self.current_user["username"]
+        username = username or current
+        if not username:
+            return
+        try:
+            if username == current and self.db_manager:
+                self.db_manager.append_audit_event(username, event, detail)
+            elif os.path.isdir(os.path.join(self.crypto_utils.storage_dir, username)):
+                db = PyDb.open(username, self.crypto_utils.storage_dir)
+                try:
+                    db.create_user_tables(username)
+                    db.append_audit_event(username, event, detail)
+                finally:
+                    db.close()
+        except Exception as e:
+            logger.error(f"Audit log: {e}")
+
+    def audit_log(self):
+        """
+        The current user's audit log as (id, event, detail, UTC time), oldest
+        first, and the id of the first tampered entry, or None if it is intact.
+        """
+        if not self.db_manager:
+            return [], None
+        username = self.current_user["username"]
+        return self.db_manager.get_audit_log(username), self.db_manager.verify_audit_log(username)
+
+    # --------------------------------------------------------------------------
+    # Health
+    # --------------------------------------------------------------------------
+    async def ping(self, server=None, timeout=30):
+        """
+        Sends a health probe and waits for the reply echoing its nonce.
+        Returns the server's health with the round trip as "rtt" (seconds),
+        or None if it did not answer within `timeout`.
+        """
+        nonce = secrets.token_hex(16)
+        future = asyncio.get_running_loop().create_future()
+        self.pending_health[nonce] = (future, time.monotonic())
+        try:
+            await self.connection_client.send_message(MixnetMessage.health(nonce, server))
+            health = await asyncio.wait_for(future, timeout)
+        except Exception as e:
+            logger.warning(f"ping: no health reply from {server or 'server'}: {e}")
+            return None
+        finally:
+            self.pending_health.pop(nonce, None)
+        if server is None or server == mixnetMessages.SERVER_ADDRESS:
+            self.health = health
+            self.latencies.append(health["rtt"])
+        return health
+
+    async def handle_health_response(self, content):
+        if not isinstance(content, dict):
+            logger.warning(f"healthResponse: {content}")
+            return
+        pending = self.pending_health.get(content.get("nonce"))
+        if not pending:
+            logger.warning("healthResponse for an unknown or expired probe")
+            return
+        future, sent = pending
+        if not future.done():
+            future.set_result({**content, "rtt": time.monotonic() - sent})
 
-        # If we have an ephemeral nym_address for a given user, format as directMessage & send to nym_address, else normal send to server
-        if recipient_username in self.nym_addresses:
-            msg = MixnetMessage.directMessage(content=payload_str, signature=signature)
-            msg["recipient"] = self.nym_addresses[recipient_username]
-        else:
-            msg = MixnetMessage.send(content=payload_str, signature=signature)
+    async def handle_surb_request(self, content):
+        """
+        The server is running out of reply SURBs to reach us with; send it the
+        number it asks for, up to MAX_SURB_TOP_UP.
+        """
+        count = content.get("surbs") if isinstance(content, dict) else None
+        if not isinstance(count, int) or isinstance(count, bool) or count < 1:
+            logger.warning(f"surbRequest: {content}")
+            return
+        count = min(count, MAX_SURB_TOP_UP)
+        msg = MixnetMessage.surbTopUp(count)
+        await self.connection_client.send_with_surbs(msg["recipient"], msg["message"], count)
+        logger.info(f"Sent the server {count} more reply SURBs")
 
-        await self.connection_client.send_message(msg)
-        logger.info(f"Sent handshake to {recipient_username}")
+    async def pick_server(self, servers):
+        """
+        Probes all `servers` at once and makes the fastest to answer the server
+        used for all further requests. Returns its address, or None.
+        """
+        results = await asyncio.gather(*(self.ping(server) for server in servers))
+        answered = [(health["rtt"], server, health) for server, health in zip(servers, results) if health]
+        if not answered:
+            return None
+        rtt, server, health = min(answered, key=lambda a: a[0])
+        mixnetMessages.SERVER_ADDRESS = server
+        self.health = health
+        self.latencies.clear()
+        self.latencies.append(rtt)
+        logger.info(f"Using server {server} ({rtt * 1000:.0f} ms)")
+        return server
 
-    async def handle_send_response(self, content):
-        pass
+    async def network_stats(self):
+        """
+        The connection's traffic counters (see MixnetConnectionClient.network_stats)
+        with the average health probe round trip to the server as "latency"
+        (seconds, None before the first answer).
+        """
+        stats = await self.connection_client.network_stats()
+        stats["latency"] = sum(self.latencies) / len(self.latencies) if self.latencies else None
+        return stats
 
     # --------------------------------------------------------------------------
-    # Query
+    # Presence
     # --------------------------------------------------------------------------
-    async def query_user(self, target_username):
+    def load_presence(self):
+        """ Loads sharing choices and last-seen times stored for the current user """
+        self.presence.clear()
+        self.presence_sharing.clear()
+        if not self.db_manager:
+            return
+        for username, share, last_seen in self.db_manager.get_presence(self.current_user["username"]):
+            if share:
+                self.presence_sharing.add(username)
+            if last_seen is not None:
+                self.presence[username] = {"online": False, "lastSeen": last_seen}
+
+    async def update_presence(self):
+        """
+        Announces our presence to the contacts we share it with and asks for
+        the presence of all contacts. Sent after login and then periodically;
+        without it the server reports us offline after a few minutes.
+        """
+        username = self.current_user["username"]
+        if not self.db_manager or not username:
+            return
+        watch = [contact for contact, _, _ in self.db_manager.get_presence(username)]
+        if not watch and not self.presence_sharing:
+            return
+        await self.connection_client.send_message(
+            MixnetMessage.presence(username, sorted(self.presence_sharing), watch)
+        )
+
+    def _schedule_heartbeat(self):
+        """ Schedules the next heartbeat, at a jittered delay, if they are sent """
+        if self.next_heartbeat:
+            self.next_heartbeat.cancel()
+            self.next_heartbeat = None
+        if not self.send_heartbeats or not self.current_user["username"]:
+            return
+        delay = HEARTBEAT_INTERVAL * random.uniform(1 - HEARTBEAT_JITTER, 1 + HEARTBEAT_JITTER)
+        self.next_heartbeat = asyncio.get_running_loop().call_later(
+            delay, lambda: asyncio.ensure_future(self.heartbeat())
+        )
+
+    async def heartbeat(self):
+        """ Tells the server we are still reachable, then schedules the next heartbeat """
+        username = self.current_user["username"]
+        if self.send_heartbeats and username and self.online:
+            try:
+                await self.connection_client.send_message(MixnetMessage.heartbeat(username))
+            except Exception as e:
+                logger.warning(f"Could not send a heartbeat: {e}")
+        self._schedule_heartbeat()
+
+    async def set_heartbeats(self, enabled):
+        """
+        Whether heartbeats are sent. Without them the server cannot tell when
+        we are offline, and keeps trying to deliver to us at once.
+        """
+        username = self.current_user["username"]
+        self.send_heartbeats = bool(enabled)
+        self.db_manager.set_setting(username, HEARTBEAT_SETTING, "1" if enabled else "0")
+        if enabled:
+            await self.heartbeat()
+            return
+        self._schedule_heartbeat()
+        # Otherwise the server would take the silence for us going offline.
+        await self.connection_client.send_message(MixnetMessage.heartbeat(username, enabled=False))
+
+    def load_anonymous(self):
+        """ Loads which contacts the current user keeps from learning their nym address """
+        self.anonymous.clear()
+        if self.db_manager:
+            self.anonymous.update(self.db_manager.get_anonymous_contacts(self.current_user["username"]))
+
+    def set_anonymous(self, contact, anonymous):
+        """
+        Turns anonymous mode for `contact` on or off. It only keeps our nym
+        address from them from now on: an address they already have stays with
+        them until we connect with a new one.
+        """
+        self.db_manager.set_anonymous(self.current_user["username"], contact, anonymous)
+        if anonymous:
+            self.anonymous.add(contact)
+        else:
+            self.anonymous.discard(contact)
+
+    async def set_presence_sharing(self, contact, share):
+        self.db_manager.set_share_presence(self.current_user["username"], contact, share)
+        if share:
+            self.presence_sharing.add(contact)
+        else:
+            self.presence_sharing.discard(contact)
+        await self.update_presence()
+
+    def load_privacy(self):
+        """
+        Loads whether the current user sends receipts and typing indicators,
+        overall and per contact, and heartbeats
+        """
+        self.privacy = dict(DEFAULT_PRIVACY)
+        self.contact_privacy.clear()
+        self.send_heartbeats = True
+        if not self.db_manager:
+            return
+        username = self.current_user["username"]
+        self.send_heartbeats = self.db_manager.get_setting(username, HEARTBEAT_SETTING) != "0"
+        if not self.send_heartbeats:
+            self._schedule_heartbeat()
+        for setting in self.privacy:
+            value = self.db_manager.get_setting(username, f"send_{setting}")
+            if value is not None:
+                self.privacy[setting] = value == "1"
+        for contact, receipts, typing in self.db_manager.get_contact_privacy(username):
+            self.contact_privacy[contact] = {"receipts": receipts, "typing": typing}
+
+    def set_privacy(self, setting, enabled, contact=None):
+        """
+        Sets whether `setting` ("receipts" or "typing") is sent to everyone,
+        or with a `contact`, to them; None for a contact follows the setting
+        for everyone.
+        """
+        username = self.current_user["username"]
+        if contact is None:
+            self.privacy[setting] = bool(enabled)
+            self.db_manager.set_setting(username, f"send_{setting}", "1" if enabled else "0")
+            return
+        own = self.contact_privacy.setdefault(contact, {"receipts": None, "typing": None})
+        own[setting] = enabled
+        self.db_manager.set_contact_privacy(username, contact, own["receipts"], own["typing"])
+
+    def load_away(self):
+        """ Loads the current user's away mode and the contacts opted out of it """
+        self.away = None
+        self.away_opt_out.clear()
+        self.away_replied.clear()
+        if not self.db_manager:
+            return
+        username = self.current_user["username"]
         try:
-            self.query_result_event.clear()
-            self.query_result = None
+            away = json.loads(self.db_manager.get_setting(username, AWAY_SETTING) or "null")
+            opt_out = json.loads(self.db_manager.get_setting(username, AWAY_OPT_OUT_SETTING) or "[]")
+        except json.JSONDecodeError:
+            logger.warning("Ignoring unreadable away mode settings.")
+            return
+        if isinstance(away, dict) and isinstance(away.get("message"), str):
+            interval = away.get("interval")
+            self.away = {"message": away["message"],
+                         "interval": interval if isinstance(interval, int) else DEFAULT_AWAY_INTERVAL}
+        if isinstance(opt_out, list):
+            self.away_opt_out.update(contact for contact in opt_out if isinstance(contact, str))
 
-            msg = MixnetMessage.query(target_username)
-            await self.connection_client.send_message(msg)
-            logger.info(f"Sent query for user: {target_username}")
+    def set_away(self, message, interval=DEFAULT_AWAY_INTERVAL):
+        """
+        Turns away mode on, answering with `message` at most once per contact
+        every `interval` seconds, or off with no `message`.
+        """
+        message = (message or "").strip()
+        self.away = {"message": message, "interval": max(0, int(interval))} if message else None
+        self.away_replied.clear()
+        self.db_manager.set_setting(self.current_user["username"], AWAY_SETTING, json.dumps(self.away))
+
+    def set_away_opt_out(self, contact, opt_out):
+        """ Whether `contact` is never answered with the away message """
+        if opt_out:
+            self.away_opt_out.add(contact)
+        else:
+            self.away_opt_out.discard(contact)
+        self.db_manager.set_setting(self.current_user["username"], AWAY_OPT_OUT_SETTING,
+                                    json.dumps(sorted(self.away_opt_out)))
+
+    def sends_allowed(self, setting, contact):
+        """ Whether `setting` ("receipts" or "typing") may be sent to `contact` """
+        own = self.contact_privacy.get(contact, {}).get(setting)
+        return self.privacy[setting] if own is None else own
 
-            await self.query_result_event.wait()
-            return self.query_result
+    def load_folders(self):
+        """ Loads the current user's sidebar folders and which chats are in them """
+        self.folders.clear()
+        self.chat_folders.clear()
+        if not self.db_manager:
+            return
+        username = self.current_user["username"]
+        self.folders.update(self.db_manager.get_folders(username))
+        self.chat_folders.update(self.db_manager.get_chat_folders(username))
+
+    def create_folder(self, name):
+        """ Adds an empty folder after the others; False if the name is empty or taken. """
+        name = name.strip()
+        if not name or not self.db_manager.create_folder(self.current_user["username"], name):
+            return False
+        self.folders[name] = False
+        self._sidebar_changed()
+        return True
+
+    def rename_folder(self, old, new):
+        """ Renames a folder in place; False if the new name is empty or taken. """
+        new = new.strip()
+        if not new or not self.db_manager.rename_folder(self.current_user["username"], old, new):
+            return False
+        self.folders = {new if name == old else name: collapsed for name, collapsed in self.folders.items()}
+        for chat, folder in self.chat_folders.items():
+            if folder == old:
+                self.chat_folders[chat] = new
+        self._sidebar_changed()
+        return True
+
+    def delete_folder(self, name):
+        """ Removes a folder; its chats stay, outside any folder. """
+        self.db_manager.delete_folder(self.current_user["username"], name)
+        self.folders.pop(name, None)
+        self.chat_folders = {chat: folder for chat, folder in self.chat_folders.items() if folder != name}
+        self._sidebar_changed()
+
+    def move_to_folder(self, chat_id, folder):
+        """ Files a chat in `folder`, or in none when None """
+        if folder is not None and folder not in self.folders:
+            return
+        self.db_manager.set_chat_folder(self.current_user["username"], chat_id, folder)
+        if folder is None:
+            self.chat_folders.pop(chat_id, None)
+        else:
+            self.chat_folders[chat_id] = folder
+        self._sidebar_changed()
+
+    def set_folder_collapsed(self, name, collapsed):
+        self.db_manager.set_folder_collapsed(self.current_user["username"], name, collapsed)
+        self.folders[name] = collapsed
+        self._sidebar_changed()
+
+    def folder_unread(self, name):
+        """ Unread messages across the chats in folder `name`, leaving out quiet chats """
+        return sum(count for chat, count in self.unread.items()
+                   if self.chat_folders.get(chat) == name and self.notification_level(chat) == "all")
+
+    def load_notifications(self):
+        """ Loads the current user's per-chat notification levels and mutes """
+        self.chat_notifications.clear()
+        if not self.db_manager:
+            return
+        for chat, level, muted_until in self.db_manager.get_chat_notifications(self.current_user["username"]):
+            self.chat_notifications[chat] = {"level": level, "mutedUntil": muted_until}
+
+    def muted_until(self, chat_id):
+        """ When the chat's mute ends (Unix seconds, or MUTE_FOREVER), or None if it is not muted """
+        until = self.chat_notifications.get(chat_id, {}).get("mutedUntil")
+        if until is None or until == MUTE_FOREVER or until > time.time():
+            return until
+        return None
+
+    def notification_level(self, chat_id):
+        """ How the chat notifies now: its own level, or "none" while it is muted """
+        if self.muted_until(chat_id) is not None:
+            return "none"
+        return self.chat_notifications.get(chat_id, {}).get("level", "all")
+
+    def set_notification_level(self, chat_id, level):
+        if level not in NOTIFY_LEVELS:
+            raise ValueError(f"unknown notification level {level!r}")
+        self._save_notifications(chat_id, level, self.chat_notifications.get(chat_id, {}).get("mutedUntil"))
+
+    def mute(self, chat_id, seconds=None):
+        """ Mutes a chat for `seconds`, or until it is unmuted when None """
+        until = MUTE_FOREVER if seconds is None else int(time.time() + seconds)
+        self._save_notifications(chat_id, self.chat_notifications.get(chat_id, {}).get("level", "all"), until)
+
+    def unmute(self, chat_id):
+        self._save_notifications(chat_id, self.chat_notifications.get(chat_id, {}).get("level", "all"), None)
+
+    def _save_notifications(self, chat_id, level, muted_until):
+        self.db_manager.set_chat_notifications(self.current_user["username"], chat_id, level, muted_until)
+        if level == "all" and muted_until is None:
+            self.chat_notifications.pop(chat_id, None)
+        else:
+            self.chat_notifications[chat_id] = {"level": level, "mutedUntil": muted_until}
+        self._sidebar_changed()
+
+    def load_drafts(self):
+        """ Loads the current user's unsent drafts, including what was being typed when the app last stopped """
+        self.drafts.clear()
+        self.unsaved_drafts.clear()
+        if not self.db_manager:
+            return
+        self.drafts.update(self.db_manager.get_drafts(self.current_user["username"]))
+
+    def set_draft(self, chat_id, text):
+        """
+        Remembers what is typed in a chat; `save_drafts` writes it out. Blank
+        text leaves no draft.
+        """
+        text = text if text.strip() else ""
+        if self.drafts.get(chat_id, "") == text:
+            return
+        had_draft = chat_id in self.drafts
+        if text:
+            self.drafts[chat_id] = text
+        else:
+            self.drafts.pop(chat_id, None)
+        self.unsaved_drafts.add(chat_id)
+        if had_draft != bool(text):
+            self._sidebar_changed()
+
+    def save_drafts(self):
+        """ Writes the drafts that changed since the last call """
+        if not self.db_manager or not self.unsaved_drafts:
+            return
+        username = self.current_user["username"]
+        for chat_id in sorted(self.unsaved_drafts):
+            try:
+                self.db_manager.save_draft(username, chat_id, self.drafts.get(chat_id, ""))
+            except Exception as e:
+                logger.error(f"save_drafts: {e}")
+                return
+            self.unsaved_drafts.discard(chat_id)
+
+    def mark_read(self, chat_id):
+        """
+        Clears the unread count of a chat that was opened, and sends read
+        receipts for the messages that arrived while it was closed.
+        """
+        if self.unread.pop(chat_id, None):
+            self._sidebar_changed()
+        ids = self.unreceipted.pop(chat_id, None)
+        if ids and self.sends_allowed("receipts", chat_id):
+            asyncio.get_running_loop().create_task(self._send_receipt(chat_id, ids, "read"))
+
+    def _sidebar_changed(self):
+        if self.chat_messages is not None:
+            self._mark_dirty("sidebar")
+
+    async def logout(self):
+        """
+        Tells the server we went offline; contacts then see our last-seen time.
+        A private key opened from a keystore is locked again.
+        """
+        username = self.current_user["username"]
+        if not username:
+            return
+        self.crypto_utils.lock(username)
+        try:
+            await self.connection_client.send_message(MixnetMessage.logout(username))
         except Exception as e:
-            logger.error(f"query_user: {e}")
-            return None
+            logger.warning(f"logout: {e}")
 
-    async def handle_query_response(self, content):
-        self.query_result = content
-        self.query_result_event.set()
-        logger.info("queryResponse received")
-        if self.db_manager and isinstance(content, dict):
-            username = content.get("username")
-            public_key = content.get("publicKey")
-            if username and public_key:
-                self.db_manager.add_contact(self.current_user["username"], username, public_key)
+    async def end_session(self):
+        """
+        Logs the current user out and closes their database, leaving the
+        handler ready for another account to log in.
+        """
+        username = self.current_user["username"]
+        self.flush_writes()
+        self.save_drafts()
+        self.save_snapshot()
+        await self.logout()
+        if self.db_manager:
+            self.db_manager.close()
+            self.db_manager = None
+        self._clear_session()
+        logger.info(f"Ended the session of {username}.")
+
+    def _clear_session(self):
+        """ Forgets everything learned while the current user was logged in """
+        if self.session_renewal:
+            self.session_renewal.cancel()
+            self.session_renewal = None
+        if self.next_heartbeat:
+            self.next_heartbeat.cancel()
+            self.next_heartbeat = None
+        self.send_heartbeats = True
+        self.session_expired = False
+        self.current_user["username"] = None
+        self.temporary_keys = {"private_key": None, "public_key": None}
+        self.pending_sends.clear()
+        self.pending_contents.clear()
+        if self.write_retry:
+            self.write_retry.cancel()
+            self.write_retry = None
+        self.pending_writes.clear()
+        self.send_status.clear()
+        self.unreceipted.clear()
+        self.typing.clear()
+        self.typing_sent.clear()
+        for transfer in self.transfers.values():
+            if transfer["incoming"]:
+                transfer["download"].abort()
+        self.transfers.clear()
+        self.early_chunks.clear()
+        self.outbox_sends.clear()
+        self.outbox_pending = False
+        self.pending_restore = None
+        self.last_snapshot = None
+        self.handshakes.clear()
+        self.presence.clear()
+        self.presence_sharing.clear()
+        self.privacy = dict(DEFAULT_PRIVACY)
+        self.contact_privacy.clear()
+        self.away = None
+        self.away_opt_out.clear()
+        self.away_replied.clear()
+        self.folders.clear()
+        self.chat_folders.clear()
+        self.unread.clear()
+        self.chat_notifications.clear()
+        self.drafts.clear()
+        self.unsaved_drafts.clear()
+        self.nym_addresses.clear()
+        self.sender_tags.clear()
+        self.reply_surbs.clear()
+        self.surbs_requested.clear()
+        self.anonymous.clear()
+        self.routing_tokens.clear()
+        self.gossiped.clear()
+        self.equivocations.clear()
+        self.pending_sender_keys.clear()
+        self.pending_histories.clear()
+        self.dirty.clear()
+
+    async def handle_presence_response(self, content):
+        if not isinstance(content, list):
+            if not self._session_error(content):
+                logger.warning(f"presenceResponse: {content}")
+            return
+        for status in content:
+            self._apply_presence(status)
+
+    async def handle_presence_update(self, content):
+        if isinstance(content, dict):
+            self._apply_presence(content)
+
+    def _apply_presence(self, status):
+        contact = status.get("username")
+        if not contact or not self.db_manager:
+            return
+        online = bool(status.get("online"))
+        last_seen = int(time.time()) if online else status.get("lastSeen")
+        self.presence[contact] = {"online": online, "lastSeen": None if online else last_seen}
+        if last_seen is not None:
+            self.db_manager.set_last_seen(self.current_user["username"], contact, last_seen)
+        if self.chat_messages is not None:
+            self._mark_dirty("sidebar")
+            if contact == self._get_active_chat():
+                self._mark_dirty("chat")
 
     # --------------------------------------------------------------------------
     # Handling Incoming Messages (SINGLE CALLBACK)
     # --------------------------------------------------------------------------
-    async def handle_incoming_message(self, message):
-        """ Main dispatcher for handling messages """
+    async def handle_incoming_message(self, message, sender_tag=None):
+        """
+        Main dispatcher for handling messages. `sender_tag` is set for messages
+        that came straight from another client with reply SURBs attached.
+        Compact envelopes from contacts are handled as the JSON they stand for.
+        """
+        try:
+            message = decode_envelope(message)
+        except ValueError as e:
+            logger.error(f"Could not decode a compact envelope: {e}")
+            self._quarantine("unparseable envelope", message)
+            return
         try:
             encapsulated_data = json.loads(message)
             action = encapsulated_data.get("action")
             context = encapsulated_data.get("context")
+
+            if action == "keyTransitionResponse":
+                await self.handle_key_transition(encapsulated_data)
+                return
+            if not self._signed_by_server(encapsulated_data):
+                logger.error(f"Dropping '{action}': not signed by a pinned server key.")
+                self.audit("signature_failed", f"server: {action}")
+                await self._request_key_transition()
+                return
             content = self._parse_content(encapsulated_data.get("content"))
 
             handler = self.get_handler(action, context)
-            if handler:
+            if handler == self.handle_incoming_message_content:
+                await handler(content, sender_tag)
+            elif handler == self.handle_query_response:
+                await handler(content, encapsulated_data.get("username"))
+            elif handler:
                 await handler(content)
             else:
                 logger.warning(f"Unknown or unhandled action '{action}', context='{context}'")
+            if action == "queryResponse":
+                self._record_observation(encapsulated_data)
+
+            # Messages relayed from the server's offline queue carry an id; ack them so
+            # they are not redelivered on the next login.
+            message_id = encapsulated_data.get("messageId")
+            if message_id and self.current_user.get("username"):
+                await self.connection_client.send_message(
+                    MixnetMessage.ack(self.current_user["username"], [message_id])
+                )
         except json.JSONDecodeError:
             logger.error("Could not decode the message content.")
+            self._quarantine("unparseable envelope", message)
+
+    # --------------------------------------------------------------------------
+    # Server identity
+    # --------------------------------------------------------------------------
+    def pin_server_keys(self, path):
+        """
+        Trusts only the server keys in the PEM file at `path` (one or more
+        keys, distributed out of band). Raises if the file holds none.
+        """
+        with open(path) as f:
+            keys = self.crypto_utils.load_public_keys(f.read())
+        if not keys:
+            raise ValueError(f"No public key in {path}")
+        self.server_keys = keys
+        self.server_keys_path = path
+        logger.info(f"Pinned {len(keys)} server key(s) from {path}.")
+
+    def _is_pinned(self, public_key):
+        return any(key.public_numbers() == public_key.public_numbers() for key in self.server_keys)
+
+    def _signed_by_server(self, envelope):
+        """ True if the envelope's content is signed by a pinned key, or nothing is pinned. """
+        if not self.server_keys or envelope.get("action") in NOT_SERVER_SIGNED:
+            return True
+        content, signature = envelope.get("content"), envelope.get("signature")
+        if not isinstance(content, str) or not isinstance(signature, str):
+            return False
+        return any(self.crypto_utils.verify_signature(key, content, signature) for key in self.server_keys)
+
+    async def _request_key_transition(self):
+        """ Asks once per key whether the server rotated it, in case that is why a signature failed. """
+        if self.transition_requested:
+            return
+        self.transition_requested = True
+        try:
+            await self.connection_client.send_message(MixnetMessage.keyTransition())
+        except Exception as e:
+            logger.warning(f"keyTransition: {e}")
+
+    async def handle_key_transition(self, envelope):
+        """
+        Follows a server key rotation. The transition must be signed by a
+        pinned key and by the new key, and the reply carrying it by the new
+        key; the new key then replaces the old one, also in the pin file.
+        """
+        if not self.server_keys:
+            return
+        transition = self._parse_content(envelope.get("content"))
+        if not isinstance(transition, dict):
+            logger.warning(f"keyTransitionResponse: {transition}")
+            return
+        try:
+            old_key, = self.crypto_utils.load_public_keys(transition["oldPublicKey"])
+            new_key, = self.crypto_utils.load_public_keys(transition["newPublicKey"])
+            signed = json.dumps({
+                "oldPublicKey": transition["oldPublicKey"],
+                "newPublicKey": transition["newPublicKey"],
+                "issued": transition["issued"],
+            })
+        except (KeyError, TypeError, ValueError) as e:
+            logger.error(f"Malformed key transition: {e}")
+            return
+
+        verify = self.crypto_utils.verify_signature
+        if not (self._is_pinned(old_key)
+                and verify(old_key, signed, transition.get("oldSignature") or "")
+                and verify(new_key, signed, transition.get("newSignature") or "")
+                and verify(new_key, envelope.get("content"), envelope.get("signature") or "")):
+            logger.error("Rejected a server key transition that is not signed by the pinned key.")
+            return
+        if self._is_pinned(new_key):
+            return
+
+        self.server_keys = [key for key in self.server_keys
+                            if key.public_numbers() != old_key.public_numbers()] + [new_key]
+        self.transition_requested = False
+        if self.server_keys_path:
+            tmp_path = f"{self.server_keys_path}.tmp"
+            with open(tmp_path, "w") as f:
+                f.write("".join(self.crypto_utils.public_key_pem(key) for key in self.server_keys))
+            os.replace(tmp_path, self.server_keys_path)
+        logger.warning("The server rotated its key; the new key is now pinned instead of the old one.")
 
     def _parse_content(self, content):
         """ Ensure content is a dictionary, converting if necessary """
@@ -409,47 +3912,151 @@ class MessageHandler:
             ("challenge", "login"): self.handle_login_challenge,
             ("challengeResponse", "registration"): self.handle_registration_response,
             ("challengeResponse", "login"): self.handle_login_response,
+            ("session", "login"): self.handle_session,
+            ("ackResponse", "chat"): self.handle_ack_response,
+            ("pendingResponse", "chat"): self.handle_pending_page,
+            ("challenge", "deleteAccount"): self.handle_deletion_challenge,
+            ("challengeResponse", "deleteAccount"): self.handle_deletion_response,
             ("incomingMessage", "chat"): self.handle_incoming_message_content,
             ("queryResponse", "query"): self.handle_query_response,
+            ("healthResponse", "health"): self.handle_health_response,
             ("sendResponse", "chat"): self.handle_send_response,
+            ("presenceResponse", "presence"): self.handle_presence_response,
+            ("presenceUpdate", "presence"): self.handle_presence_update,
+            ("backupResponse", "backup"): self.handle_backup_response,
+            ("backupChunk", "backup"): self.handle_backup_chunk,
+            ("surbRequest", "surbs"): self.handle_surb_request,
         }
         return handlers.get((action, context)) or handlers.get((action, None))
 
-    async def handle_incoming_message_content(self, content):
-        """Handles incoming messages, including decryption, verification, and storage."""
+    async def handle_incoming_message_content(self, content, sender_tag=None):
+        """
+        Handles incoming messages, including decryption, verification, and storage,
+        by running them through the `inbound` pipeline. A `sender_tag` is remembered
+        for replying to the sender once the message checks out.
+        """
         logger.info("Processing incoming message")
+        async with self.inbound_lock:
+            await self.inbound.run(Inbound(content, sender_tag, received=content))
+
+    def add_inbound_stage(self, name, stage, before="persist", after=None):
+        """
+        Adds `stage` to the receive path of direct messages, by default right
+        before they are stored; see pipeline.py.
+        """
+        self.inbound.insert(name, stage, before=None if after else before, after=after)
+
+    def _quarantine(self, reason, raw, sender=None):
+        """ Keeps a direct message that failed with `reason`, as the string `raw` it arrived as. """
+        username = self.current_user.get("username")
+        if not self.db_manager or not username:
+            return
+        self.db_manager.quarantine(username, sender, reason, raw)
+        count = self.db_manager.count_quarantine(username)
+        logger.warning(f"Quarantined a message{f' from {sender}' if sender else ''}: {reason} ({count} kept).")
+        if self.quarantine_callback:
+            self.quarantine_callback(reason, count)
+
+    def _reject(self, inbound, reason):
+        """ Quarantines the content of `inbound` as it arrived, for a stage dropping it. """
+        self._quarantine(reason, json.dumps(inbound.received), inbound.sender)
 
+    def quarantined(self):
+        """ The quarantined messages, newest first: (id, sender, reason, content, received at). """
+        username = self.current_user.get("username")
+        if not self.db_manager or not username:
+            return []
+        return self.db_manager.get_quarantine(username)
+
+    def delete_quarantined(self, quarantine_id):
+        self.db_manager.delete_quarantined(self.current_user["username"], quarantine_id)
+
+    def clear_quarantine(self):
+        self.db_manager.clear_quarantine(self.current_user["username"])
+
+    async def retry_quarantined(self, quarantine_id):
+        """
+        Runs a quarantined message through the receive path again, e.g. once
+        its sender's key is known. If it fails again it is quarantined anew.
+        Returns False if there is no such message.
+        """
+        row = next((row for row in self.quarantined() if row[0] == quarantine_id), None)
+        if row is None:
+            return False
+        self.delete_quarantined(quarantine_id)
+        try:
+            content = json.loads(row[3])
+        except json.JSONDecodeError:
+            await self.handle_incoming_message(row[3])
+            return True
+        await self.handle_incoming_message_content(content)
+        return True
+
+    async def _open_stage(self, inbound):
+        """ Opens sealed messages and checks the envelope; group messages take their own path. """
+        content = inbound.content
         if not isinstance(content, dict):
             logger.error("Parsed content is not a valid dictionary after JSON decoding.")
-            return
+            self._reject(inbound, "malformed message")
+            return None
+
+        if "group" in content:
+            await self._handle_group_message(content)
+            return None
 
-        from_user = content.get("sender")
+        if "sealed" in content:
+            content = await self._open_sealed(content["sealed"])
+            if content is None:
+                self._reject(inbound, "sealed message could not be opened")
+                return None
+            inbound.content, inbound.sealed = content, True
+
+        inbound.sender = content.get("sender")
         body = content.get("body")
-        sender_pub_from_msg = content.get("senderPublicKey")  # Extract sender's long-term public key
-        if not from_user or not body:
+        if not inbound.sender or not body:
             logger.error("Malformed incoming message. Missing sender or body.")
-            return
-
-        encrypted_payload = body.get("encryptedPayload")
-        payload_signature = body.get("payloadSignature")
+            self._reject(inbound, "malformed message")
+            return None
 
-        if not encrypted_payload or not payload_signature:
+        inbound.encrypted_payload = body.get("encryptedPayload")
+        inbound.signature = body.get("payloadSignature")
+        if not inbound.encrypted_payload or not inbound.signature:
             logger.error("Malformed body. Missing encryptedPayload or payloadSignature.")
-            return
+            self._reject(inbound, "malformed message")
+            return None
 
-        ephemeral_public_key_pem = encrypted_payload.get("ephemeralPublicKey")
-        if not ephemeral_public_key_pem:
+        if not inbound.encrypted_payload.get("ephemeralPublicKey"):
             logger.error("No ephemeral public key attached. Cannot derive shared secret.")
-            return
+            self._reject(inbound, "malformed message")
+            return None
 
-        logger.info(f"Received message from {from_user}")
+        logger.info(f"Received message from {inbound.sender}")
+        return inbound
+
+    async def _verify_stage(self, inbound):
+        """ Checks the payload signature against the sender's long-term key, learning the key if need be. """
+        from_user = inbound.sender
+        sender_pub_from_msg = inbound.content.get("senderPublicKey")  # Extract sender's long-term public key
 
         # Retrieve sender's stored long-term public key (for signature verification)
         contact = self.db_manager.get_contact(self.current_user["username"], from_user) if self.db_manager else None
         sender_public_key_pem = contact[1] if contact else None
 
-        # If this is the first contact, store the sender's public key
-        if sender_pub_from_msg:
+        if not sender_public_key_pem and inbound.sealed:
+            # The server never saw who sealed this, so nothing vouches for an
+            # attached key; take the sender's key from the directory instead.
+            logger.info(f"Looking up the public key of {from_user}")
+            await self.query_user(from_user)
+            contact = self.db_manager.get_contact(self.current_user["username"], from_user) if self.db_manager else None
+            sender_public_key_pem = contact[1] if contact else None
+        elif sender_pub_from_msg and sender_public_key_pem and sender_pub_from_msg.strip() != sender_public_key_pem.strip():
+            # The sender announces a new key; believe it only if the directory agrees.
+            logger.info(f"{from_user} sent a different public key; checking the directory")
+            await self.query_user(from_user)
+            contact = self.db_manager.get_contact(self.current_user["username"], from_user)
+            sender_public_key_pem = contact[1] if contact else None
+        elif sender_pub_from_msg:
+            # If this is the first contact, store the sender's public key
             if not sender_public_key_pem:  # First-time contact
                 logger.info(f"Storing new sender public key for {from_user}")
                 self.db_manager.add_contact(self.current_user["username"], from_user, sender_pub_from_msg)
@@ -458,159 +4065,376 @@ class MessageHandler:
         # If we still don't have a long-term public key, we cannot verify the signature
         if not sender_public_key_pem:
             logger.error(f"No sender public key available for {from_user}. Cannot verify message signature.")
+            self._reject(inbound, "no sender key")
             return None
 
-        sender_public_key = serialization.load_pem_public_key(sender_public_key_pem.encode())
-
-        # Step 1: Verify the `payloadSignature` using sender's long-term key
-        encrypted_payload_str = json.dumps(encrypted_payload)
+        # A bot of the sender's signs with a sub-key their identity key vouches for.
+        if "delegation" in inbound.content:
+            delegation = self._check_delegation(from_user, sender_public_key_pem, inbound.content["delegation"])
+            if delegation is None:
+                self._reject(inbound, "delegation refused")
+                return None
+            inbound.delegation = delegation.id
+            sender_public_key_pem = delegation.public_key
 
-        if not self.crypto_utils.verify_signature(sender_public_key, encrypted_payload_str, payload_signature):
+        sender_public_key = serialization.load_pem_public_key(sender_public_key_pem.encode())
+        encrypted_payload_str = json.dumps(inbound.encrypted_payload)
+        if not await self.crypto_utils.verify_signature_async(sender_public_key, encrypted_payload_str,
+                                                             inbound.signature):
             logger.error(f"Signature verification failed for {from_user}. Dropping message.")
+            self.audit("signature_failed", from_user)
+            self._reject(inbound, "invalid signature")
             return None
         logger.info("Payload signature verified successfully!")
+        inbound.trusted = self.is_trusted(from_user)
+        return inbound
 
-        # Step 2: Load recipient's private key
+    async def _decrypt_stage(self, inbound):
+        """ Decrypts the verified payload with our private key and the sender's ephemeral key. """
         recipient_private_key = self.crypto_utils.load_private_key(self.current_user["username"])
         if not recipient_private_key:
             logger.error(f"No private key available for recipient: {self.current_user['username']}.")
             return None
 
-        # Step 3: Decrypt using the ephemeral key
         try:
-            decrypted_message = self.crypto_utils.decrypt_message(
-                recipient_private_key, encrypted_payload
-            )
-
-            if decrypted_message:
-                logger.info(f" Decrypted message from {from_user}")
-            else:
-                logger.error(f"Failed to decrypt message from {from_user}.")
-
+            decrypted_message = await self.crypto_utils.decrypt_message_async(recipient_private_key,
+                                                                              inbound.encrypted_payload)
         except Exception as e:
             logger.error(f"Decryption failed: {e}")
+            decrypted_message = None
+        if not decrypted_message:
+            logger.error(f"Failed to decrypt message from {inbound.sender}.")
+            self._reject(inbound, "decryption failed")
             return None
+        logger.info(f" Decrypted message from {inbound.sender}")
 
-        # Step 4: Parse JSON 
         try:
             message_obj = json.loads(decrypted_message)
         except json.JSONDecodeError:
-            logger.error("Decrypted message not valid JSON")
+            message_obj = None
+        if not isinstance(message_obj, dict):
+            logger.error("Decrypted message is not a JSON object")
+            self._reject(inbound, "malformed inner message")
             return None
-        
-        # Step 5 Check type 
-        message_type = message_obj.get("type")
-        actual_message = message_obj.get("message")
+        inbound.message = message_obj
+        return inbound
 
-        if message_type == 1:
-            logger.info(f"Storing handshake nym_address from {from_user}")
-            self.nym_addresses[from_user] = actual_message
-            return
+    def _ratchet_stage(self, inbound):
+        """ Opens a message under the sender's Double Ratchet session, once it is known not to be a repeat. """
+        if inbound.message.get("type") != RATCHET_MESSAGE:
+            return inbound
+        message_obj = self._open_ratchet(inbound.sender, inbound.message.get("message"))
+        if message_obj is None:
+            self._reject(inbound, "ratchet decryption failed")
+            return None
+        inbound.message = message_obj
+        return inbound
 
-        # Step 6 Handle normal message storage
-        if from_user and actual_message and self.db_manager:
-            self._store_message(from_user, actual_message)
-            logger.info(f"Stored incoming message from {from_user} in DB.")
+    async def _control_stage(self, inbound):
+        """ Consumes handshakes and group control messages; chat messages go on. """
+        from_user, message_obj = inbound.sender, inbound.message
+        if inbound.delegation:
+            # Bots only chat, and cannot read replies sent back through them.
+            if message_obj.get("type") not in DELEGATED_MESSAGE_TYPES:
+                logger.warning(f"Dropping a message of type {message_obj.get('type')} from a bot of {from_user}.")
+                return None
+            return inbound
+        if not inbound.trusted:
+            if message_obj.get("type") not in UNTRUSTED_MESSAGE_TYPES:
+                logger.warning(f"Dropping a message of type {message_obj.get('type')} from {from_user}: "
+                               "their new key is not confirmed.")
+                return None
+            return inbound
+        self._check_observation(from_user, message_obj.get("keyObservation"))
+        if inbound.sender_tag:
+            self._remember_sender_tag(from_user, inbound.sender_tag)
 
-            # Update the chat UI
-            self._update_chat_ui(from_user, actual_message)
+        handlers = {
+            HANDSHAKE_MESSAGE: self._handle_handshake,
+            SENDER_KEY_MESSAGE: self._handle_sender_key,
+            GROUP_CONTROL_MESSAGE: self._handle_group_control,
+            GROUP_JOIN_MESSAGE: self._handle_join_request,
+            GROUP_HISTORY_MESSAGE: self._handle_group_history,
+            REVOCATION_MESSAGE: self._handle_revocation,
+            RECEIPT_MESSAGE: self._handle_receipt,
+            TYPING_MESSAGE: self._handle_typing,
+            FILE_MESSAGE: self._handle_file_manifest,
+            FILE_CHUNK_MESSAGE: self._handle_file_chunk,
+            SURB_MESSAGE: self._handle_surbs,
+        }
+        handler = handlers.get(message_obj.get("type"))
+        if handler:
+            await handler(from_user, message_obj.get("message"))
+            return None
+        return inbound
 
+    def _content_stage(self, inbound):
+        """ The text to store and show for a chat message, and its kind. """
+        from_user, message_obj = inbound.sender, inbound.message
+        message_type = message_obj.get("type")
+        actual_message = message_obj.get("message")
 
-    def _verify_and_decrypt_message(self, encrypted_payload, signature, from_user):
-        """ Calls CryptoUtils to verify the signature and then decrypt the message """
+        kind = "text"
+        forwarded_sender = _forwarded_sender(message_obj)
+        if message_type == 0 and isinstance(actual_message, str) and forwarded_sender:
+            actual_message = forwarded_text(forwarded_sender, actual_message)
+            kind = "forwarded"
 
-        # Retrieve sender's stored public key
-        contact = self.db_manager.get_contact(self.current_user["username"], from_user) if self.db_manager else None
-        sender_public_key_pem = contact[1] if contact else None
+        if message_type == LOCATION_MESSAGE:
+            location = parse_location(actual_message)
+            if location is None:
+                logger.warning(f"Ignoring a malformed location from {from_user}.")
+                return None
+            actual_message = location_text(location)
+            kind = "location"
 
-        if not sender_public_key_pem:
-            logger.error(f"No sender public key available for {from_user}. Cannot verify message.")
-            return None
+        if message_type == 2:
+            logger.info(f"{from_user} deleted their account")
+            self.nym_addresses.pop(from_user, None)
+            self._forget_sender_tag(from_user)
+            self.presence.pop(from_user, None)
+            actual_message = _("{username} deleted their account.", username=from_user)
+            kind = "notice"
 
-        sender_public_key = serialization.load_pem_public_key(sender_public_key_pem.encode())
+        inbound.text, inbound.kind = actual_message, kind
+        return inbound
 
-        # Step 1: Verify Signature Before Decryption
-        encrypted_payload_str = json.dumps(encrypted_payload)  # Convert dict to string for signature verification
-        if not self.crypto_utils.verify_signature(sender_public_key, encrypted_payload_str, signature):
-            logger.error(f"Signature verification failed for {from_user}. Dropping message.")
+    def _persist_stage(self, inbound):
+        """ Queues the message for the next batched write. """
+        if not inbound.text or not self.db_manager:
             return None
+        self._store_message(inbound.sender, inbound.text, inbound.kind)
+        logger.info(f"Queued incoming message from {inbound.sender} for the DB.")
+        return inbound
 
-        # Load recipient's private key
-        recipient_private_key = self.crypto_utils.load_private_key(self.current_user["username"])
+    def _emit_stage(self, inbound):
+        """ Shows the message in its chat and notifies about it. """
+        self.typing.pop(inbound.sender, None)
+        self._update_chat_ui(inbound.sender, inbound.text)
+        return inbound
 
-        if not recipient_private_key:
-            logger.error(f"No private key available for recipient {self.current_user['username']}.")
-            return None
+    async def _receipt_stage(self, inbound):
+        """
+        Tells the sender their chat message arrived, or that it was read if
+        its chat is open. Read receipts for other chats wait for mark_read.
+        """
+        from_user, message_obj = inbound.sender, inbound.message
+        message_id = message_obj.get("id")
+        if (inbound.delegation or message_obj.get("type") not in (0, LOCATION_MESSAGE)
+                or not isinstance(message_id, str) or not 0 < len(message_id) <= MAX_MESSAGE_ID):
+            return inbound
+        if self.chat_messages is not None and from_user == self._get_active_chat():
+            status = "read" if self.sends_allowed("receipts", from_user) else "delivered"
+        else:
+            status = "delivered"
+            self.unreceipted.setdefault(from_user, []).append(message_id)
+        await self._send_receipt(from_user, [message_id], status)
+        return inbound
 
-        try:
-            # Step 2: Decrypt Message
-            decrypted_message = self.crypto_utils.decrypt_message(
-                recipient_private_key, encrypted_payload
-            )
+    async def _send_receipt(self, contact, message_ids, status):
+        """ Sends `contact` a receipt with `status` ("delivered" or "read") for their messages. """
+        for start in range(0, len(message_ids), MAX_RECEIPT_IDS):
+            ids = message_ids[start:start + MAX_RECEIPT_IDS]
+            await self._send_typed(contact, RECEIPT_MESSAGE, {"ids": ids, "status": status})
 
-            if decrypted_message:
-                logger.info(f"Successfully decrypted message from {from_user}: {decrypted_message}")
-            else:
-                logger.error(f"Failed to decrypt message from {from_user}.")
-            
-            return decrypted_message
+    async def _handle_receipt(self, from_user, receipt):
+        """
+        Records that `from_user` received or read messages we sent them. Only
+        messages sent to them move on; receipts of older clients name none.
+        """
+        if not isinstance(receipt, dict):
+            logger.debug(f"{from_user} sent a receipt without message ids.")
+            return
+        ids, status = receipt.get("ids"), receipt.get("status")
+        if status not in RECEIPT_STATUSES or not isinstance(ids, list):
+            logger.warning(f"Ignoring a malformed receipt from {from_user}.")
+            return
+        changed = False
+        for message_id in ids[:MAX_RECEIPT_IDS]:
+            if not isinstance(message_id, str) or not self.db_manager:
+                continue
+            if self.db_manager.set_message_status(self.current_user["username"], from_user, [message_id], status):
+                self.send_status[message_id] = status
+                changed = True
+        if changed and self.chat_messages is not None and from_user == self._get_active_chat():
+            self._mark_dirty("chat")
 
-        except Exception as e:
-            logger.error(f"Decryption or verification failed: {e}")
-            return None
+    async def notify_typing(self, contact):
+        """
+        Tells `contact` the user is typing to them. Sent at most every
+        TYPING_INTERVAL seconds, only while online (an indicator is not worth
+        keeping in the outbox) and never to groups; the "typing" privacy
+        setting may stop it in _send_typed.
+        """
+        if not contact or contact.startswith(GROUP_CHAT_PREFIX) or self._queueing():
+            return
+        now = time.time()
+        if now - self.typing_sent.get(contact, -math.inf) < TYPING_INTERVAL:
+            return
+        self.typing_sent[contact] = now
+        await self._send_typed(contact, TYPING_MESSAGE, True)
+
+    async def _handle_typing(self, from_user, typing):
+        """ Shows `from_user` as typing for TYPING_TIMEOUT seconds. """
+        if typing is not True:
+            logger.warning(f"Ignoring a malformed typing indicator from {from_user}.")
+            return
+        self.typing[from_user] = time.time() + TYPING_TIMEOUT
+        if self.chat_messages is None:
+            return
+        asyncio.get_running_loop().call_later(TYPING_TIMEOUT, self._typing_expired, from_user)
+        if from_user == self._get_active_chat():
+            self._mark_dirty("chat")
+
+    def _typing_expired(self, contact):
+        """ Takes the indicator of `contact` down if no newer one arrived. """
+        if self.is_typing(contact) or self.typing.pop(contact, None) is None:
+            return
+        if self.chat_messages is not None and contact == self._get_active_chat():
+            self._mark_dirty("chat")
 
-    def _parse_message(self, decrypted_msg):
+    def is_typing(self, contact):
+        """ Whether `contact` told us they are typing within the last TYPING_TIMEOUT seconds. """
+        return self.typing.get(contact, 0) > time.time()
+
+    def load_delivery(self, contact):
         """
-        Ensures the message content is in the correct format.
-        - If it's JSON, return as a dict.
-        - If it's plaintext, wrap it in {"type": 0, "message": decrypted_msg}.
+        Loads how far the messages sent to `contact` got into `send_status`,
+        and returns their send ids by row id. Messages still "pending" from
+        an earlier session show no state; they may wait in the outbox.
         """
+        if not self.db_manager:
+            return {}
+        rows = [row for row in self.db_manager.get_message_statuses(self.current_user["username"], contact)
+                if row[2] != "pending"]
+        for _row_id, send_id, status in rows:
+            if DELIVERY_ORDER.get(self.send_status.get(send_id), -1) < DELIVERY_ORDER.get(status, 0):
+                self.send_status[send_id] = status
+        return {row_id: send_id for row_id, send_id, _status in rows}
+
+    async def _away_stage(self, inbound):
+        """ Answers a chat message with the away message while the user is away; see set_away. """
+        from_user, message_obj = inbound.sender, inbound.message
+        if (not self.away or inbound.delegation or from_user in self.away_opt_out
+                or message_obj.get("type") not in (0, LOCATION_MESSAGE) or message_obj.get("autoReply")):
+            return inbound
+        now = time.time()
+        if now - self.away_replied.get(from_user, -math.inf) < self.away["interval"]:
+            return inbound
+        self.away_replied[from_user] = now
+        # Stored first, so the answer follows the message in the history.
+        self.flush_writes()
+        text = self.away["message"]
+        send_id = await self._send_chat(from_user, {"type": 0, "message": text, "autoReply": True}, text)
+        if send_id and self.chat_messages is not None:
+            self.chat_messages.setdefault(from_user, []).append(
+                (self.current_user["username"], text, timeUtils.utc_stamp(), send_id))
+            if from_user == self._get_active_chat():
+                self._mark_dirty("chat")
+        logger.info(f"Answered {from_user} with the away message.")
+        return inbound
+
+    async def _open_sealed(self, sealed):
+        """ The payload inside a sealed message, or None if it is not for us. """
+        private_key = self.crypto_utils.load_private_key(self.current_user["username"])
+        if not private_key or not isinstance(sealed, dict):
+            logger.error("Cannot open sealed message.")
+            return None
         try:
-            parsed_message = json.loads(decrypted_msg)  # Try parsing JSON
-            if isinstance(parsed_message, dict):
-                return parsed_message
+            content = json.loads(await self.crypto_utils.decrypt_message_async(private_key, sealed) or "")
         except json.JSONDecodeError:
-            logger.error(" _parse_message: Decrypted message is not valid JSON")
+            content = None
+        if not isinstance(content, dict):
+            logger.error("Sealed message did not decrypt to a payload. Dropping it.")
             return None
+        return content
 
-    # def _handle_handshake(self, from_user, message_obj):
-    #     """ Handles handshake messages and updates contact list """
-    #     nym_addr = message_obj.get("message")
-    #     if nym_addr:
-    #         self.nym_addresses[from_user] = nym_addr
-    #         logger.info(f"Received handshake from {from_user}. Updated nym address: {nym_addr}")
-    #     else:
-    #         logger.warning(f"Handshake message from {from_user} missing nym address.")
+    def _store_message(self, from_user, actual_message, kind="text"):
+        """ Queues message for the next batched database write """
+        self.pending_writes.append((from_user, 'from', actual_message, kind))
+        if not self.flush_scheduled:
+            self.flush_scheduled = True
+            asyncio.get_running_loop().call_soon(self.flush_writes)
 
-    def _store_message(self, from_user, actual_message):
-        """ Stores message in the database """
-        self.db_manager.save_message(self.current_user["username"], from_user, 'from', actual_message)
-        logger.info(f"Stored incoming message from {from_user} in DB.")
+    def flush_writes(self):
+        """
+        Writes all queued incoming messages in a single transaction. If that
+        fails they stay queued, ahead of anything newer, and the write is
+        retried: the server may no longer hold them.
+        """
+        self.flush_scheduled = False
+        if self.write_retry:
+            self.write_retry.cancel()
+            self.write_retry = None
+        batch, self.pending_writes = self.pending_writes, []
+        if not batch or not self.db_manager:
+            return
+        try:
+            self.db_manager.save_messages(self.current_user["username"], batch)
+            logger.info(f"Stored {len(batch)} incoming message(s) in DB.")
+        except Exception as e:
+            logger.error(f"flush_writes: {e}; retrying in {WRITE_RETRY_DELAY}s")
+            self.pending_writes[:0] = batch
+            try:
+                self.write_retry = asyncio.get_running_loop().call_later(WRITE_RETRY_DELAY, self.flush_writes)
+            except RuntimeError:
+                logger.error(f"flush_writes: no event loop to retry on; {len(batch)} message(s) not stored")
 
-    def _update_chat_ui(self, from_user, actual_message):
-        """ Updates chat messages and UI elements """
+    def _update_chat_ui(self, from_user, actual_message, chat_id=None, chat_name=None, mention=False):
+        """
+        Updates chat messages and UI elements; `chat_id` is the group chat for
+        group messages, and `mention` whether the message mentions us.
+        """
         if self.chat_messages is None:
             logger.warning("chat_messages is None; UI might not be initialized.")
             return
 
-        if from_user not in self.chat_messages:
-            self.chat_messages[from_user] = []
+        chat_id = chat_id or from_user
+        if chat_id not in self.chat_messages:
+            self.chat_messages[chat_id] = []
 
-        stamp = datetime.now().strftime('%Y-%m-%d %H:%M:%S')
-        self.chat_messages[from_user].append((from_user, actual_message, stamp))
+        stamp = timeUtils.utc_stamp()
+        self.chat_messages[chat_id].append((from_user, actual_message, stamp, None))
 
-        if not any(chat["id"] == from_user for chat in self.chat_list):
-            self.chat_list.append({"id": from_user, "name": from_user})
-            if self.chat_list_sidebar_fn:
-                self.chat_list_sidebar_fn.refresh()
-            logger.info(f"Added {from_user} to chat list.")
+        self._add_chat(chat_id, chat_name or from_user)
 
-        currently_active_chat = self._get_active_chat()
-        if from_user == currently_active_chat and self.render_chat_fn:
-            try:
-                self.render_chat_fn.refresh(self.current_user["username"], currently_active_chat, self.chat_messages)
-                logger.info("Chat UI refreshed successfully.")
-            except Exception as e:
-                logger.error(f"Failed to refresh chat UI: {e}")
-        elif self.new_message_callback:
+        active = chat_id == self._get_active_chat()
+        if active:
+            self._mark_dirty("chat")
+        else:
+            self.unread[chat_id] = self.unread.get(chat_id, 0) + 1
+            self._mark_dirty("sidebar")
+        level = self.notification_level(chat_id)
+        if mention and level != "none" and self.mention_callback:
+            self.mention_callback(chat_id, from_user, actual_message)
+        elif not active and level == "all" and self.new_message_callback:
             self.new_message_callback(from_user, actual_message)
+
+    def _add_chat(self, chat_id, name):
+        """ Lists the chat in the sidebar if it is not there yet """
+        if self.chat_list is None or any(chat["id"] == chat_id for chat in self.chat_list):
+            return
+        self.chat_list.append({"id": chat_id, "name": name})
+        self._mark_dirty("sidebar")
+        logger.info(f"Added {name} to chat list.")
+
+    def _mark_dirty(self, part):
+        """ Schedules a render of `part` after the current burst of messages """
+        self.dirty.add(part)
+        if not self.render_scheduled:
+            self.render_scheduled = True
+            asyncio.get_running_loop().call_soon(self.render_dirty)
+
+    def render_dirty(self):
+        """ Refreshes only the parts of the UI that changed """
+        self.render_scheduled = False
+        dirty, self.dirty = self.dirty, set()
+        try:
+            if "sidebar" in dirty and self.chat_list_sidebar_fn:
+                self.chat_list_sidebar_fn.refresh()
+            if "chat" in dirty and self.render_chat_fn:
+                # The active chat may have changed since the message arrived.
+                self.render_chat_fn.refresh(self.current_user["username"], self._get_active_chat(), self.chat_messages)
+                logger.info("Chat UI refreshed successfully.")
+        except Exception as e:
+            logger.error(f"Failed to refresh chat UI: {e}")
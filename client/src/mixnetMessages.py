@@ -15,6 +15,20 @@ def load_env(filepath=".env"):
 load_env()
 
 SERVER_ADDRESS = os.getenv("SERVER_ADDRESS")
+# Optional comma-separated list of federated servers; the client picks the
+# fastest to answer a health probe and makes it the SERVER_ADDRESS.
+SERVER_ADDRESSES = [a.strip() for a in os.getenv("SERVER_ADDRESSES", "").split(",") if a.strip()]
+# Optional nymCHAT server that stores encrypted remote backups; defaults to SERVER_ADDRESS.
+BACKUP_ADDRESS = os.getenv("BACKUP_ADDRESS")
+# Optional PEM file with the public keys of the servers above, obtained out of
+# band; when set, server replies not signed by one of them are dropped.
+SERVER_PUBLIC_KEY_FILE = os.getenv("SERVER_PUBLIC_KEY_FILE")
+# When set, messages sent through the server name their recipient by the
+# opaque routing token from the recipient's last query instead of by username.
+ROUTING_TOKENS = os.getenv("ROUTING_TOKENS", "").lower() in ("1", "true", "yes")
+# When set, messages sent through the server carry their sender only inside
+# the end-to-end encryption, so the server cannot tell who sent them.
+SEALED_SENDER = os.getenv("SEALED_SENDER", "").lower() in ("1", "true", "yes")
 
 class MixnetMessage:
     @staticmethod
@@ -33,6 +47,37 @@ class MixnetMessage:
             "recipient": SERVER_ADDRESS,
         }
 
+    @staticmethod
+    def capabilities():
+        encapsulatedMessage = json.dumps({"action": "capabilities"})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def keyTransition():
+        """
+        Asks how the server key changed; answered with a transition signed by
+        the old and the new key, or an error if it never changed.
+        """
+        encapsulatedMessage = json.dumps({"action": "keyTransition"})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def health(nonce, server=None):
+        """
+        Liveness probe. The server echoes `nonce` in its signed healthResponse.
+        """
+        encapsulatedMessage = json.dumps({"action": "health", "nonce": nonce})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": server or SERVER_ADDRESS,
+        }
+
     @staticmethod
     def login(usernym):
         encapsulatedMessage = json.dumps({"action": "login", "usernym": usernym})
@@ -50,12 +95,31 @@ class MixnetMessage:
         }
 
     @staticmethod
-    def send(content, signature):
+    def send(content, signature, send_id=None):
         """
         Encapsulates a message for sending via the centralized server.
         This is used for handshake messages (to hide it from the server) and is not appropriate for p2p direct messaging.
+        The server echoes `send_id` in its sendResponse.
         """
-        encapsulatedMessage = json.dumps({"action": "send", "content": content, "signature": signature})
+        message = {"action": "send", "content": content, "signature": signature}
+        if send_id:
+            message["id"] = send_id
+        encapsulatedMessage = json.dumps(message)
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def sealedSend(content, send_id=None):
+        """
+        Like `send`, for a message whose sender is sealed inside `content`.
+        It is not signed: a signature would tell the server who sent it.
+        """
+        message = {"action": "sealedSend", "content": content}
+        if send_id:
+            message["id"] = send_id
+        encapsulatedMessage = json.dumps(message)
         return {
             "message": encapsulatedMessage,
             "recipient": SERVER_ADDRESS,
@@ -80,8 +144,15 @@ class MixnetMessage:
         }
 
     @staticmethod
-    def sendGroup(groupID, content, signature):
-        encapsulatedMessage = json.dumps({"action": "sendGroup", "target": groupID, "content": content, "signature": signature})
+    def sendGroup(content, signature, send_id=None):
+        """
+        Like `send`, for a group message that the server relays to each of the
+        recipients listed in `content`.
+        """
+        message = {"action": "sendGroup", "content": content, "signature": signature}
+        if send_id:
+            message["id"] = send_id
+        encapsulatedMessage = json.dumps(message)
         return {
             "message": encapsulatedMessage,
             "recipient": SERVER_ADDRESS,
@@ -104,26 +175,169 @@ class MixnetMessage:
         }
 
     @staticmethod
-    def registrationResponse(username, signature):
-        encapsulatedMessage = json.dumps({
+    def registrationResponse(username, signature, pow=None):
+        response = {
             "action": "registrationResponse",
             "username": username,
             "signature": signature,
-        })
+        }
+        if pow is not None:
+            response["pow"] = pow
+        encapsulatedMessage = json.dumps(response)
         return {
             "message": encapsulatedMessage,
             "recipient": SERVER_ADDRESS,
         }
 
     @staticmethod
-    def loginResponse(username, signature):
-        encapsulatedMessage = json.dumps({
+    def loginResponse(username, signature, page_size=None):
+        """
+        With `page_size`, the server sends the first `page_size` messages of
+        our offline queue and we ask for the rest with `fetchPending`, instead
+        of getting the whole queue at once.
+        """
+        response = {
             "action": "loginResponse",
             "username": username,
             "signature": signature,
+        }
+        if page_size is not None:
+            response["pendingPages"] = page_size
+        encapsulatedMessage = json.dumps(response)
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def presence(username, share, watch):
+        """
+        Shares presence with the contacts in `share` (none opts out) and asks for
+        the presence of those in `watch`. Repeated while online.
+        """
+        encapsulatedMessage = json.dumps({"action": "presence", "username": username, "share": share, "watch": watch})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def heartbeat(username, enabled=True):
+        """
+        Tells the server we are still online, so it delivers to us at once; with
+        `enabled` False, that we stop sending these.
+        """
+        message = {"action": "heartbeat", "username": username}
+        if not enabled:
+            message["enabled"] = False
+        encapsulatedMessage = json.dumps(message)
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def surbTopUp(count):
+        """
+        Sent with `count` extra reply SURBs when the server asks for them, so it
+        can keep delivering to us. Carries nothing else.
+        """
+        encapsulatedMessage = json.dumps({"action": "surbTopUp", "surbs": count})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def logout(username):
+        """
+        Tells the server we are going offline, so contacts see our last-seen time.
+        """
+        encapsulatedMessage = json.dumps({"action": "logout", "username": username})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def deleteAccount(username):
+        """
+        Asks the server to remove us from its directory. Must come from the
+        logged-in session; the server then sends a challenge to sign.
+        """
+        encapsulatedMessage = json.dumps({"action": "deleteAccount", "username": username})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def deleteAccountResponse(username, signature):
+        encapsulatedMessage = json.dumps({
+            "action": "deleteAccountResponse",
+            "username": username,
+            "signature": signature,
+        })
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def backupPut(backup_id, token, index, total, data):
+        """
+        Stores chunk `index` of `total` of an encrypted backup. The first write
+        of `backup_id` binds it to `token`; later writes must present it.
+        """
+        encapsulatedMessage = json.dumps({
+            "action": "backupPut",
+            "backupId": backup_id,
+            "token": token,
+            "index": index,
+            "total": total,
+            "data": data,
         })
+        return {
+            "message": encapsulatedMessage,
+            "recipient": BACKUP_ADDRESS or SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def backupGet(backup_id):
+        """ Asks for every chunk of a backup; each arrives as a backupChunk. """
+        encapsulatedMessage = json.dumps({"action": "backupGet", "backupId": backup_id})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": BACKUP_ADDRESS or SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def backupDelete(backup_id, token):
+        encapsulatedMessage = json.dumps({"action": "backupDelete", "backupId": backup_id, "token": token})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": BACKUP_ADDRESS or SERVER_ADDRESS,
+        }
+
+    @staticmethod
+    def fetchPending(username, after, limit):
+        """
+        Asks for up to `limit` messages of our offline queue with ids above the cursor `after`.
+        """
+        encapsulatedMessage = json.dumps({"action": "fetchPending", "username": username, "after": after,
+                                          "limit": limit})
         return {
             "message": encapsulatedMessage,
             "recipient": SERVER_ADDRESS,
         }
 
+    @staticmethod
+    def ack(username, message_ids):
+        """
+        Acknowledges messages delivered from the server's offline queue so it can drop them.
+        """
+        encapsulatedMessage = json.dumps({"action": "ack", "username": username, "ids": message_ids})
+        return {
+            "message": encapsulatedMessage,
+            "recipient": SERVER_ADDRESS,
+        }
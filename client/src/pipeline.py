@@ -0,0 +1,115 @@
+"""
+The receive path of direct messages, as a chain of stages:
+
+    open → verify → decrypt → dedupe → ratchet → control → content → persist →
+    emit → receipt → away
+
+Each stage is a callable, sync or async, that takes the `Inbound` message as
+processed so far and returns it, changed or not, to pass it on, or None to
+drop it; the stages after it then do not run. A stage can so be tested on its
+own with an `Inbound` built by hand, and features such as spam filtering or
+translation are added as stages of their own (see `Pipeline.insert`), usually
+right before `persist`, where the text to store and show is final.
+"""
+import hashlib
+import inspect
+import json
+from collections import OrderedDict
+from dataclasses import dataclass
+from typing import Optional
+from logUtils import logger
+
+# Signatures remembered by the dedupe stage
+DEDUPE_WINDOW = 1024
+
+
+@dataclass
+class Inbound:
+    """ A direct message on its way in; each stage fills in more of it. """
+    content: dict
+    sender_tag: Optional[str] = None
+    # The content as it arrived, before a seal was opened; what is quarantined if it fails
+    received: Optional[dict] = None
+    sealed: bool = False
+    sender: Optional[str] = None
+    encrypted_payload: Optional[dict] = None
+    signature: Optional[str] = None
+    # The id of the delegation a bot of the sender's signed under
+    delegation: Optional[str] = None
+    # False while the key it was verified with waits for the user's confirmation
+    trusted: bool = True
+    # The decrypted message object, with its "type" and "message"
+    message: Optional[dict] = None
+    # What is stored and shown, and how it is stored; see StoredMessage
+    text: Optional[str] = None
+    kind: str = "text"
+
+
+class Pipeline:
+    """ Named stages run in order over each inbound message. """
+
+    def __init__(self, stages=()):
+        self.stages = list(stages)
+
+    def names(self):
+        return [name for name, _stage in self.stages]
+
+    def _index(self, name):
+        for index, (stage_name, _stage) in enumerate(self.stages):
+            if stage_name == name:
+                return index
+        raise KeyError(f"No stage named '{name}'")
+
+    def insert(self, name, stage, before=None, after=None):
+        """ Adds `stage` before or after the stage named so, or at the end. """
+        if name in self.names():
+            raise ValueError(f"There is already a stage named '{name}'")
+        if before:
+            index = self._index(before)
+        elif after:
+            index = self._index(after) + 1
+        else:
+            index = len(self.stages)
+        self.stages.insert(index, (name, stage))
+
+    def remove(self, name):
+        del self.stages[self._index(name)]
+
+    async def run(self, inbound):
+        """ The message as the last stage left it, or None if a stage dropped it. """
+        for name, stage in self.stages:
+            result = stage(inbound)
+            if inspect.isawaitable(result):
+                result = await result
+            if result is None:
+                logger.debug(f"Inbound message dropped at '{name}'.")
+                return None
+            inbound = result
+        return inbound
+
+
+def dedupe(window=DEDUPE_WINDOW):
+    """
+    A stage dropping messages seen before, such as a queued message delivered
+    live and then again from the server's queue, or a replay. Messages are
+    told apart by a digest of their encrypted payload, as it was signed: a
+    fresh ephemeral key and IV make it differ between sends, and unlike the
+    signature (an ECDSA signature stays valid with s flipped to n - s) it
+    cannot be changed without failing verification.
+    """
+    seen = OrderedDict()
+
+    def stage(inbound):
+        if inbound.encrypted_payload is None:
+            return inbound
+        key = hashlib.sha256(json.dumps(inbound.encrypted_payload).encode()).hexdigest()
+        if key in seen:
+            seen.move_to_end(key)
+            logger.info(f"Dropping a message from {inbound.sender} that arrived before.")
+            return None
+        seen[key] = True
+        if len(seen) > window:
+            seen.popitem(last=False)
+        return inbound
+
+    return stage
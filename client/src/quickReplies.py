@@ -0,0 +1,75 @@
+"""
+Canned responses and text macros, from a JSON file the user edits:
+
+    {"macros": {";brb": "be right back", ";omw": "on my way"},
+     "replies": ["Can I call you later?", "Thanks, got it!"]}
+
+A macro is expanded in the message input once a space follows it, and when
+the message is sent. Replies are picked from a list (Alt+R) to answer fast.
+The file is NYMCHAT_QUICK_REPLIES, by default storage/quick_replies.json;
+without one there are neither.
+"""
+import json
+import os
+import re
+from logUtils import logger
+
+DEFAULT_PATH = os.path.join(os.getcwd(), "storage", "quick_replies.json")
+
+# Words of a message, which macros are matched against whole
+WORD = re.compile(r"\S+")
+
+path = DEFAULT_PATH
+macros = {}   # {trigger: text}
+replies = []  # canned responses, in the order of the file
+
+
+def configure(config_path=None):
+    """
+    Loads the macros and replies from `config_path`, by default from
+    NYMCHAT_QUICK_REPLIES; entries that are not text are skipped, and a file
+    that cannot be read leaves none.
+    """
+    global path, macros, replies
+    path = config_path or os.getenv("NYMCHAT_QUICK_REPLIES") or DEFAULT_PATH
+    macros, replies = {}, []
+    if not os.path.exists(path):
+        return
+    try:
+        with open(path, encoding="utf-8") as f:
+            config = json.load(f)
+    except (OSError, ValueError) as e:
+        logger.warning(f"Could not read quick replies from {path}: {e}")
+        return
+    if not isinstance(config, dict):
+        logger.warning(f"Quick replies in {path} are not a JSON object; ignoring them.")
+        return
+    found = config.get("macros")
+    for trigger, text in (found.items() if isinstance(found, dict) else ()):
+        # A trigger is one word, so it can be told apart from the text around it.
+        if isinstance(text, str) and WORD.fullmatch(trigger):
+            macros[trigger] = text
+        else:
+            logger.warning(f"Skipping the quick reply macro {trigger!r}: it must be one word, expanding to text.")
+    found = config.get("replies")
+    replies = [reply for reply in (found if isinstance(found, list) else ())
+               if isinstance(reply, str) and reply.strip()]
+
+
+def expand(text, complete=True):
+    """
+    `text` with every macro in it replaced by its text. Unless `complete`, a
+    macro at the very end is left as it is: the user may still be typing a
+    longer one.
+    """
+    def replace(match):
+        if not complete and match.end() == len(text):
+            return match.group()
+        return macros.get(match.group(), match.group())
+    return WORD.sub(replace, text)
+
+
+def matching(query):
+    """ The replies containing `query`, ignoring case. """
+    query = query.strip().lower()
+    return [reply for reply in replies if query in reply.lower()]
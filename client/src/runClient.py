@@ -1,16 +1,24 @@
 # runClient.py
 import threading
+import re
+import logging
 import os
 import asyncio
 from nicegui import ui, app
 from uuid import uuid4
-from datetime import datetime
 
-from dbUtils import SQLiteManager
 from cryptographyUtils import CryptoUtils
 from connectionUtils import MixnetConnectionClient
-from messageHandler import MessageHandler
-from logUtils import logger
+from messageHandler import (MessageHandler, DEFAULT_AWAY_INTERVAL, GROUP_CHAT_PREFIX, MAX_FILE_SIZE,
+                            MAX_LOCATION_LABEL, MAX_POLL_OPTIONS, MUTE_FOREVER, file_from_text, forwarded_from,
+                            group_chat_id, location_from_text, location_text, osm_link, parse_location)
+from mixnetMessages import SERVER_ADDRESSES, SERVER_PUBLIC_KEY_FILE
+from logUtils import logger, log_buffer, configure_log_buffer
+from i18n import _, set_locale
+import timeUtils
+import diagnostics
+import exportUtils
+import quickReplies
 
 ###############################################################################
 # GLOBAL / IN-MEMORY STATE
@@ -18,16 +26,61 @@ from logUtils import logger
 DB_DIR = os.path.join(os.getcwd(), "storage")
 usernames = []
 
-chat_list = []        # [{"id": <username>, "name": <username>}]
+chat_list = []        # [{"id": <username>, "name": <username>}]; groups: {"id": "group:<group id>", "name": <group name>}
 active_chat = None    # currently active chat user ID
 active_chat_user = None
-messages = {}         # {username: [(sender_id, msg_text, timestamp), ...]}
+messages = {}         # {username: [(sender_id, msg_text, timestamp, send_id), ...]}; send_id is None unless sent this session
+history_cursor = {}   # {username: id of the oldest loaded message, None once all are loaded}
+search_hit = None     # (chat id, sender, timestamp) of the message a search opened, until it has been shown
+selection = None      # {"chat": chat id, "ids": ids of the selected entries of messages[chat]} in selection mode
+
+# Messages loaded per conversation at login, and per "Load older messages" click
+HISTORY_PAGE = 100
 
 chat_messages_container = None  # assigned in chat_page()
 
+set_locale()
+timeUtils.configure()
+configure_log_buffer()
+quickReplies.configure()
+
 # Global variable for storing our nym address
 global_nym_address = None
 
+# Seconds between health probes while the chat page is open
+HEALTH_INTERVAL = 60
+
+# Seconds between refreshes of the network statistics page
+STATS_INTERVAL = 2
+
+# Seconds between refreshes of the log viewer
+LOG_VIEW_INTERVAL = 2
+
+# Seconds between presence announcements; the server drops us after three minutes of silence
+PRESENCE_INTERVAL = 60
+
+# Seconds between remote backup runs, when backups are on
+BACKUP_INTERVAL = 60 * 60
+
+# Seconds between writes of what is typed in the open chat; a crash loses at most this much of a draft
+DRAFT_AUTOSAVE_INTERVAL = 2
+
+# Seconds between snapshots of unacknowledged sends and partial transfers; a crash loses at most this much of them
+SNAPSHOT_INTERVAL = 5
+
+# Mutes offered in a chat's header, in seconds; None mutes until unmuted
+MUTE_DURATIONS = {"1h": 60 * 60, "8h": 8 * 60 * 60, "forever": None}
+
+# NYMCHAT_ACCESSIBLE: no animations or spinners, plain buttons instead of the
+# pop-out settings menu, and the chat as a plain list that screen readers announce.
+ACCESSIBLE_MODE = os.getenv("NYMCHAT_ACCESSIBLE", "").lower() in ("1", "true", "yes")
+# NYMCHAT_LINE_MODE: also print every notification and incoming message to stdout, one line each.
+LINE_MODE = os.getenv("NYMCHAT_LINE_MODE", "").lower() in ("1", "true", "yes")
+
+if ACCESSIBLE_MODE:
+    ui.add_head_html('<style>*, *::before, *::after { animation: none !important; transition: none !important; }</style>',
+                     shared=True)
+
 def set_active_chat(value):
     global active_chat
     active_chat = value
@@ -40,6 +93,365 @@ def set_active_chat_user(value):
     global active_chat_user
     active_chat_user = value
 
+###############################################################################
+# NOTIFICATIONS AND ACCESSIBILITY
+###############################################################################
+def announce(text):
+    """ Prints `text` as one line on stdout in line mode. """
+    if LINE_MODE:
+        print(f"[{timeUtils.clock_time()}] {text}", flush=True)
+
+def notify(message, **kwargs):
+    announce(message)
+    ui.notify(message, **kwargs)
+
+def busy_indicator():
+    """
+    Hidden until shown with `.props(remove='hidden')`: a spinner, or in
+    accessible mode a status line read out when it appears.
+    """
+    if ACCESSIBLE_MODE:
+        return ui.label(_("Working…")).props('hidden role=status aria-live=polite').classes("mb-4")
+    return ui.spinner(size='lg').props('hidden').classes("mb-4")
+
+def key_change_dialog(username):
+    """
+    The warning for a contact whose key changed. It stays up until the user
+    confirms the new key, goes back to the previous one or dismisses it; sends
+    stay blocked until one of the keys is trusted.
+    """
+    changes = message_handler.key_changes(username)
+    warning = _("The safety number of {username} changed. This happens when they reinstall or restore their "
+                "account, but also when someone intercepts your messages. Messages to them are paused until you "
+                "confirm the new key; compare it with them through another channel first.", username=username)
+    announce(warning)
+    with ui.dialog().props('persistent') as dialog, ui.card().props('role=alertdialog'):
+        ui.label(_('Safety number changed')).classes('text-xl font-bold text-red-6')
+        ui.label(warning)
+        if changes:
+            old, new, changed_at = changes[-1]
+            ui.label(_("Changed {time}", time=timeUtils.format_stamp(changed_at))).classes('text-gray-400')
+            ui.label(_("Previous: {fingerprint}", fingerprint=old)).classes('font-mono')
+            ui.label(_("New: {fingerprint}", fingerprint=new)).classes('font-mono')
+
+        def confirm():
+            message_handler.trust_contact(username)
+            dialog.close()
+            refresh_chat()
+            notify(_("New key of {username} confirmed.", username=username))
+
+        def reject():
+            dialog.close()
+            if message_handler.reject_key_change(username):
+                refresh_chat()
+                notify(_("Keeping the previous key of {username}.", username=username))
+            else:
+                notify(_("The previous key of {username} was not kept.", username=username), type='warning')
+
+        with ui.row():
+            ui.button(_('Not now'), color="green-6", on_click=dialog.close)
+            ui.button(_('Keep previous key'), color="green-6", icon="history", on_click=reject)
+            ui.button(_('Confirm new key'), color="red-6", icon="verified_user", on_click=confirm)
+    dialog.open()
+
+def downgrade_dialog(username):
+    """
+    The warning for a contact whose client agreed on weaker terms than before.
+    Chat messages to them wait until the user accepts sending without them.
+    """
+    lost = message_handler.downgrade(username)
+    features = {"sealedSender": _("sealed sender, which hides from the server who sends"),
+                "keyObservation": _("key observations, which detect a server handing out different keys")}
+    warning = _("The client of {username} no longer agrees on some protections it used before. An older client "
+                "does this, but so can someone tampering with your connection. Messages to them are paused until "
+                "you accept sending without these protections.", username=username)
+    announce(warning)
+    with ui.dialog().props('persistent') as dialog, ui.card().props('role=alertdialog'):
+        ui.label(_('Weaker encryption')).classes('text-xl font-bold text-red-6')
+        ui.label(warning)
+        for feature in lost:
+            ui.label(f"• {features.get(feature, feature)}")
+
+        def accept():
+            message_handler.accept_downgrade(username)
+            dialog.close()
+            notify(_("Sending to {username} without these protections.", username=username))
+
+        with ui.row():
+            ui.button(_('Not now'), color="green-6", on_click=dialog.close)
+            ui.button(_('Send anyway'), color="red-6", icon="gpp_maybe", on_click=accept)
+    dialog.open()
+
+def sends_blocked(username):
+    """ Shows why messages to `username` are paused, if they are; returns True then. """
+    if not message_handler.is_trusted(username):
+        key_change_dialog(username)
+        return True
+    if message_handler.downgrade(username):
+        downgrade_dialog(username)
+        return True
+    return False
+
+def equivocation_dialog(contact, own_fingerprint, observed_fingerprint):
+    """
+    Shown when a contact reports that the server gave them a key for us that
+    is not ours: the server may be intercepting their messages to us.
+    """
+    warning = _("The server gave {contact} a different key for your account than your own. Someone may be "
+                "reading what they send you, or they hold an outdated key. Compare safety numbers with them "
+                "through another channel.", contact=contact)
+    announce(warning)
+    with ui.dialog().props('persistent') as dialog, ui.card().props('role=alertdialog'):
+        ui.label(_('Key mismatch reported')).classes('text-xl font-bold text-red-6')
+        ui.label(warning)
+        ui.label(_("Yours: {fingerprint}", fingerprint=own_fingerprint)).classes('font-mono')
+        ui.label(_("Given to {contact}: {fingerprint}", contact=contact,
+                   fingerprint=observed_fingerprint)).classes('font-mono')
+        ui.button(_('Close'), color="green-6", on_click=dialog.close)
+    dialog.open()
+
+def session_expired_dialog():
+    """
+    Shown when the server ended our session and logging in again failed, e.g.
+    because the key file went missing; until then nothing reaches the server.
+    """
+    warning = _("Your session expired and could not be renewed. Messages cannot be sent or received until "
+                "you log in again.")
+    announce(warning)
+    with ui.dialog().props('persistent') as dialog, ui.card().props('role=alertdialog'):
+        ui.label(_('Session expired')).classes('text-xl font-bold text-red-6')
+        ui.label(warning)
+
+        async def retry():
+            dialog.close()
+            if await message_handler.renew_session():
+                notify(_("Logged in again."))
+            else:
+                session_expired_dialog()
+
+        async def log_out():
+            dialog.close()
+            await message_handler.end_session()
+            clear_session()
+            ui.navigate.to('/login')
+
+        with ui.row():
+            ui.button(_('Try again'), color="green-6", icon="refresh", on_click=retry)
+            ui.button(_('Log out'), color="red-6", icon="logout", on_click=log_out)
+    dialog.open()
+
+def group_members_dialog(group_id):
+    """
+    The members of a group. Admins can invite contacts, remove members, make
+    members admins and choose how much history new members are sent; each
+    change is signed and checked by every member.
+    """
+    members, admins = message_handler.group_members(group_id) or ([], [])
+    own = message_handler.current_user["username"]
+    is_admin = own in admins
+    with ui.dialog() as dialog, ui.card():
+        ui.label(_('Members')).classes('text-xl font-bold')
+
+        async def change(action, member, done):
+            dialog.close()
+            if await action(group_id, member):
+                notify(done)
+            else:
+                notify(_("The group could not be changed."), type='warning')
+
+        for member in members:
+            with ui.row().classes('items-center gap-2'):
+                ui.label(member).classes('font-bold')
+                if member in admins:
+                    ui.label(_('admin')).classes('text-gray-400 text-sm')
+                if is_admin and member != own:
+                    ui.button(icon='person_remove', color="red-6",
+                              on_click=lambda m=member: change(message_handler.kick_member, m,
+                                                               _("{name} removed.", name=m))) \
+                        .props(f'flat aria-label="{_("Remove {name}", name=member)}"')
+                    if member not in admins:
+                        ui.button(icon='admin_panel_settings', color="green-6",
+                                  on_click=lambda m=member: change(message_handler.promote_member, m,
+                                                                   _("{name} is now an admin.", name=m))) \
+                            .props(f'flat aria-label="{_("Make {name} an admin", name=member)}"')
+        if not is_admin and message_handler.history_limit(group_id):
+            ui.label(_("New members are sent up to the last {count} messages.",
+                       count=message_handler.history_limit(group_id))).classes('text-gray-400 text-sm')
+        if is_admin:
+            contacts = [username for username, _key in message_handler.db_manager.get_all_contacts(own)
+                        if username not in members]
+            invite_in = ui.select(contacts, label=_('Invite')).props('outlined')
+            ui.button(_('Invite'), color="green-6", icon="person_add",
+                      on_click=lambda: invite_in.value and change(message_handler.invite_member, invite_in.value,
+                                                                  _("{name} invited.", name=invite_in.value)))
+            history = {0: _('None'), 50: _('Last 50 messages'), 200: _('Last 200 messages')}
+            current = message_handler.history_limit(group_id)
+            history.setdefault(current, _('Last {count} messages', count=current))
+            ui.select(history, value=current, label=_('History shared with new members'),
+                      on_change=lambda e: change_history(e.value)).props('outlined')
+
+            async def change_history(limit):
+                if limit != message_handler.history_limit(group_id):
+                    await change(message_handler.set_history_limit, limit, _("History sharing updated."))
+
+            ui.separator()
+            expiries = {3600: _('1 hour'), 86400: _('1 day'), 7 * 86400: _('7 days'), 0: _('Never')}
+            expiry_in = ui.select(expiries, value=86400, label=_('Invite code expires after')).props('outlined')
+            single_use_in = ui.switch(_('Single use'), value=True)
+            code_out = ui.input(label=_('Invite code')).props('outlined readonly').classes('w-full font-mono')
+            code_out.visible = False
+
+            def make_code():
+                code_out.value = message_handler.create_invite(
+                    group_id, expires_in=expiry_in.value or None, single_use=single_use_in.value)
+                code_out.visible = True
+                ui.clipboard.write(code_out.value)
+                notify(_("Invite code copied. Anyone holding it can ask you to join the group."))
+
+            ui.button(_('Create invite code'), color="green-6", icon="link", on_click=make_code)
+        ui.button(_('Close'), color="green-6", on_click=dialog.close)
+    dialog.open()
+
+def join_group_dialog():
+    """ Redeems an invite code: its admin is asked to add us to the group. """
+    with ui.dialog() as dialog, ui.card():
+        ui.label(_('Join group')).classes('text-xl font-bold')
+        code_in = ui.input(label=_('Invite code')).props('outlined').classes('w-full font-mono')
+
+        async def do_join():
+            invite = await message_handler.redeem_invite(code_in.value or "")
+            if invite is None:
+                notify(_("This invite code is invalid or has expired."), type='warning')
+                return
+            dialog.close()
+            notify(_("Asked {admin} to add you to {name}.", admin=invite["admin"], name=invite["name"]))
+
+        with ui.row():
+            ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+            ui.button(_('Join'), color="green-6", icon="group", on_click=do_join)
+    dialog.open()
+
+def contact_invite_dialog():
+    """ Shares our contact invite, and adds a contact from theirs, without the server directory. """
+    with ui.dialog() as dialog, ui.card().classes('w-[36rem]'):
+        ui.label(_('Contact invite')).classes('text-xl font-bold')
+        ui.label(_('Anyone with your invite can add you and check your key without asking the server. '
+                   'Share it through a channel you trust.'))
+        address_in = ui.switch(_('Include my nym address, so messages come to me directly'), value=True)
+        invite_out = ui.input(label=_('Your invite')).props('outlined readonly').classes('w-full font-mono')
+
+        def show_invite():
+            invite_out.value = message_handler.contact_invite(include_address=address_in.value)
+
+        def copy_invite():
+            ui.clipboard.write(invite_out.value)
+            notify(_("Invite copied."))
+
+        address_in.on_value_change(show_invite)
+        show_invite()
+        ui.button(_('Copy'), color="green-6", icon="content_copy", on_click=copy_invite)
+
+        ui.separator()
+        invite_in = ui.input(label=_("Someone else's invite")).props('outlined').classes('w-full font-mono')
+
+        def add_contact():
+            invite = message_handler.add_contact_from_invite(invite_in.value or "")
+            if invite is None:
+                notify(_("This is not a valid contact invite."), type='warning')
+                return
+            chat = {"id": invite.username, "name": invite.username}
+            if chat not in chat_list:
+                chat_list.append(chat)
+            dialog.close()
+            notify(_("{name} added. Their key fingerprint is {fingerprint}.",
+                     name=invite.username, fingerprint=invite.fingerprint))
+
+        with ui.row():
+            ui.button(_('Close'), color="green-6", on_click=dialog.close)
+            ui.button(_('Add contact'), color="green-6", icon="person_add", on_click=add_contact)
+    dialog.open()
+
+###############################################################################
+# IDENTITY
+###############################################################################
+def short_address(address):
+    """ The start of a nym address's client and gateway parts, enough to tell addresses apart at a glance """
+    client, gateway = address.split("@", 1) if "@" in address else (address, "")
+    return f"{client[:8]}…@{gateway[:6]}…" if gateway else f"{client[:8]}…"
+
+def copy_address():
+    if not message_handler.nym_address:
+        notify(_("Not connected to the mixnet yet."), type='warning')
+        return
+    ui.clipboard.write(message_handler.nym_address)
+    notify(_("Nym address copied."))
+
+def copy_fingerprint():
+    safety_number = message_handler.safety_number()
+    if safety_number is None:
+        notify(_("Your key could not be read."), type='warning')
+        return
+    ui.clipboard.write(safety_number)
+    notify(_("Safety number copied."))
+
+def identity_dialog():
+    """
+    Our address and safety number in full, and the open chat's contact's, to
+    compare out of band and then mark the contact as verified.
+    """
+    with ui.dialog() as dialog, ui.card().classes('w-[36rem]'):
+        ui.label(_('Your identity')).classes('text-xl font-bold')
+        ui.label(_('Compare safety numbers with your contacts in person or through a channel you trust. If the '
+                   'numbers you each see match, nobody is reading your messages in between.'))
+        ui.input(label=_('Username'), value=message_handler.current_user["username"]).props('outlined readonly')
+        ui.textarea(label=_('Nym address'), value=message_handler.nym_address or _('Not connected')) \
+            .props('outlined readonly autogrow').classes('w-full font-mono')
+        ui.input(label=_('Your safety number'), value=message_handler.safety_number() or '') \
+            .props('outlined readonly').classes('w-full font-mono')
+        contact = active_chat if active_chat and not active_chat.startswith(GROUP_CHAT_PREFIX) else None
+        contact_number = message_handler.safety_number(contact) if contact else None
+        if contact:
+            ui.input(label=_("Safety number of {name}", name=contact),
+                     value=contact_number or _('No key yet')) \
+                .props('outlined readonly').classes('w-full font-mono')
+
+        def set_verified(verified):
+            message_handler.set_verified(contact, verified)
+            dialog.close()
+            refresh_chat()
+            notify(_("{name} verified.", name=contact) if verified
+                   else _("{name} is no longer verified.", name=contact))
+
+        with ui.row():
+            ui.button(_('Close'), color="green-6", on_click=dialog.close)
+            if contact_number and message_handler.is_verified(contact):
+                ui.button(_('Remove verification'), color="green-6", icon="remove_moderator",
+                          on_click=lambda: set_verified(False))
+            elif contact_number and message_handler.is_trusted(contact):
+                ui.button(_('Mark as verified'), color="green-6", icon="verified_user",
+                          on_click=lambda: set_verified(True))
+            ui.button(_('Copy address'), color="green-6", icon="content_copy", on_click=copy_address)
+            ui.button(_('Copy safety number'), color="green-6", icon="fingerprint", on_click=copy_fingerprint)
+    dialog.open()
+
+@ui.refreshable
+def status_bar():
+    """ Who we are signed in as and where we receive, with shortcuts to verify and share both. """
+    address = message_handler.nym_address
+    with ui.row().classes('w-full items-center gap-3 text-sm text-zinc-300'):
+        ui.icon('account_circle')
+        ui.label(message_handler.current_user["username"] or '').classes('font-bold')
+        if address:
+            ui.label(short_address(address)).classes('font-mono').tooltip(address)
+        else:
+            ui.label(_('Not connected')).classes('text-amber-5')
+        ui.button(icon='content_copy', color="green-6", on_click=copy_address).props('flat dense') \
+            .props(f'aria-label="{_("Copy address")}"').tooltip(f'{_("Copy address")} (Alt+C)')
+        ui.button(icon='fingerprint', color="green-6", on_click=copy_fingerprint).props('flat dense') \
+            .props(f'aria-label="{_("Copy safety number")}"').tooltip(f'{_("Copy safety number")} (Alt+F)')
+        ui.button(_('Verify'), icon='verified_user', color="green-6", on_click=identity_dialog).props('flat dense') \
+            .tooltip(f'{_("Your identity")} (Alt+V)')
+
 ###############################################################################
 # REFRESHABLE UI FOR CHAT
 ###############################################################################
@@ -48,29 +460,526 @@ def render_chat_messages(current_user, target_chat, msg_dict):
     """
     Refresh the chat area to display messages properly, inside a structured column.
     """
+    global search_hit
     # Ensure chat_messages_container is defined
     if chat_messages_container is not None:
         chat_messages_container.clear()  # Clear old messages before re-rendering
 
-    ui.label(f"Chat with {target_chat or ''}").classes('text-lg font-bold')
+    is_group = bool(target_chat) and target_chat.startswith(GROUP_CHAT_PREFIX)
+    selecting = bool(selection) and selection["chat"] == target_chat
+    with ui.row().classes('items-center gap-4'):
+        if is_group:
+            name = next((chat["name"] for chat in chat_list if chat["id"] == target_chat), target_chat)
+            ui.label(_("Group {name}", name=name)).classes('text-lg font-bold')
+            ui.button(_('Members'), color="green-6", icon="group",
+                      on_click=lambda: group_members_dialog(target_chat.removeprefix(GROUP_CHAT_PREFIX))) \
+                .props('flat')
+            ui.button(_('Poll'), color="green-6", icon="poll",
+                      on_click=lambda: new_poll_dialog(target_chat.removeprefix(GROUP_CHAT_PREFIX))).props('flat')
+            if any(sender_id != current_user and message_handler.mentions_me(text)
+                   for sender_id, text, _stamp, _send_id in msg_dict.get(target_chat, [])):
+                ui.button(_('Jump to mention'), color="amber-8", icon="alternate_email",
+                          on_click=jump_to_mention).props('flat')
+        else:
+            ui.label(_("Chat with {name}", name=target_chat or '')).classes('text-lg font-bold')
+        if target_chat:
+            encryption_indicator(target_chat)
+        if target_chat and not is_group and message_handler.is_verified(target_chat):
+            ui.icon('verified_user', color='green-6').tooltip(_("Safety number verified"))
+        if target_chat and not is_group:
+            presence = presence_label(target_chat)
+            if message_handler.is_typing(target_chat):
+                ui.label(_("{name} is typing…", name=target_chat)).classes('text-sm text-green-400 italic')
+            elif presence:
+                ui.label(presence).classes('text-sm text-gray-400')
+            ui.switch(_('Share my presence'), value=target_chat in message_handler.presence_sharing,
+                      on_change=lambda e: message_handler.set_presence_sharing(target_chat, e.value)) \
+                .tooltip(_("Let {name} see when you are online", name=target_chat))
+            ui.switch(_('Anonymous'), value=target_chat in message_handler.anonymous,
+                      on_change=lambda e: set_anonymous(target_chat, e.value)) \
+                .tooltip(_("Keep your nym address from {name}; they reply through reply blocks you send them",
+                           name=target_chat))
+            privacy_select(target_chat, "receipts", _("Read receipts"))
+            privacy_select(target_chat, "typing", _("Typing indicator"))
+            if message_handler.away:
+                ui.switch(_('Away message'), value=target_chat not in message_handler.away_opt_out,
+                          on_change=lambda e: message_handler.set_away_opt_out(target_chat, not e.value)) \
+                    .tooltip(_("Answer {name} with your away message", name=target_chat))
+        if target_chat and message_handler.folders:
+            folder_select(target_chat)
+        if target_chat:
+            notification_controls(target_chat, is_group)
+        if target_chat and msg_dict.get(target_chat) and not selecting:
+            ui.button(_('Select'), color="green-6", icon="checklist", on_click=lambda: start_selection()).props('flat')
+    if selecting:
+        selection_toolbar(target_chat)
+    if target_chat and not is_group:
+        verification_banner(target_chat)
+
+    if is_group:
+        render_polls(target_chat.removeprefix(GROUP_CHAT_PREFIX))
+    render_transfers(target_chat)
 
     if not target_chat or target_chat not in msg_dict or not msg_dict[target_chat]:
-        ui.label('No messages yet.').classes('mx-auto my-4')
+        ui.label(_('No messages yet.')).classes('mx-auto my-4')
     else:
+        if history_cursor.get(target_chat) is not None:
+            ui.button(_('Load older messages'), color="green-6", icon="history",
+                      on_click=lambda: load_older_messages(target_chat)).props('flat').classes('mx-auto')
+        if ACCESSIBLE_MODE:
+            # One line per message, oldest first, in a log region so new lines are read out.
+            with ui.column().classes('w-full gap-1').props('role=log aria-live=polite'):
+                for entry in msg_dict[target_chat]:
+                    sender_id, text, stamp, send_id = entry
+                    status = message_handler.send_status.get(send_id)
+                    if selecting:
+                        ui.checkbox(_('Select'), value=id(entry) in selection["ids"],
+                                    on_change=lambda e, m=entry: toggle_selected(m))
+                    line = ui.label(f"{sender_id}, {delivery_stamp(stamp, status)}: {shown_text(text)}") \
+                        .style('white-space: pre-wrap')
+                    with line:
+                        message_context_menu(target_chat, entry)
+                    location = location_from_text(message_body(text))
+                    if location:
+                        ui.link(_('Open in OpenStreetMap'), osm_link(location), new_tab=True)
+                    file_button(sender_id, text)
+                    if is_group and sender_id != current_user and message_handler.mentions_me(text):
+                        line.classes('nymchat-mention font-bold')
+                    if search_hit == (target_chat, sender_id, stamp):
+                        line.classes('nymchat-search-hit underline')
+            if search_hit and search_hit[0] == target_chat:
+                search_hit = None
+                scroll_to_search_hit()
+            return
         with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-2'):
-            for sender_id, text, stamp in msg_dict[target_chat]:
+            for entry in msg_dict[target_chat]:
+                sender_id, text, stamp, send_id = entry
                 is_sent = sender_id == current_user  # Check if the message is sent by the user
 
                 # Handle multi-line messages
-                text_content = text.split("\n") if "\n" in text else text
+                shown = shown_text(text)
+                text_content = shown.split("\n") if "\n" in shown else shown
 
-                ui.chat_message(
-                    text=text_content,
-                    stamp=stamp,
+                location = location_from_text(message_body(text))
+                message = ui.chat_message(
+                    text=text_content if not location else None,
+                    name=sender_id if is_group and not is_sent else None,
+                    stamp=delivery_stamp(stamp, message_handler.send_status.get(send_id)),
                     sent=is_sent
                 ).classes('p-3 rounded-lg')
+                if location:
+                    with message:
+                        ui.label(shown).style('white-space: pre-wrap')
+                        ui.link(_('Open in OpenStreetMap'), osm_link(location), new_tab=True)
+                if is_group and not is_sent and message_handler.mentions_me(text):
+                    message.classes('nymchat-mention bg-amber-900')
+                if search_hit == (target_chat, sender_id, stamp):
+                    message.classes('nymchat-search-hit bg-sky-900')
+                with message:
+                    file_button(sender_id, text)
+                    message_context_menu(target_chat, entry)
+                if selecting:
+                    # In selection mode a click selects the message, or unselects it.
+                    message.classes('cursor-pointer').on('click', lambda e, m=entry: toggle_selected(m))
+                    if id(entry) in selection["ids"]:
+                        message.classes('nymchat-selected bg-green-900')
+
+    if search_hit and search_hit[0] == target_chat:
+        search_hit = None
+        scroll_to_search_hit()
+    elif not selecting:
+        ui.run_javascript('window.scrollTo(0, document.body.scrollHeight)')  # Auto-scroll to latest message
+
+def render_transfers(chat_id):
+    """ How far the files being sent or received in the chat got """
+    for transfer in message_handler.transfers_in(chat_id):
+        with ui.row().classes('w-full max-w-6xl mx-auto items-center gap-2'):
+            ui.icon('download' if transfer["incoming"] else 'upload')
+            ui.label(_("Receiving {name}", name=transfer["name"]) if transfer["incoming"]
+                     else _("Sending {name}", name=transfer["name"]))
+            ui.linear_progress(value=transfer["done"] / transfer["total"] if transfer["total"] else 1,
+                               show_value=False).classes('flex-grow')
+            ui.label(f'{transfer["done"]}/{transfer["total"]}')
+
+def file_button(sender_id, text):
+    """ A button to save a file received in the chat, while it is in the downloads folder """
+    name = file_from_text(message_body(text))
+    if not name or sender_id == message_handler.current_user["username"]:
+        return
+    path = os.path.join(message_handler.downloads_dir(), name)
+    if os.path.isfile(path):
+        ui.button(_('Save file'), color="green-6", icon="download", on_click=lambda: ui.download(path)).props('flat')
+
+def message_body(text):
+    """ What a chat line says, without the marker of a forwarded message """
+    forwarded = forwarded_from(text)
+    return forwarded[1] if forwarded else text
+
+def shown_text(text):
+    """ A chat line as shown; a forwarded message starts by saying who wrote it """
+    forwarded = forwarded_from(text)
+    if forwarded is None:
+        return text
+    return _("Forwarded from {sender}:", sender=forwarded[0]) + "\n" + forwarded[1]
+
+def render_polls(group_id):
+    """ The group's polls with live counts; clicking an option votes for it, or takes the vote back. """
+    for poll in message_handler.poll_results(group_id):
+        with ui.card().classes('w-full max-w-6xl mx-auto'):
+            ui.label(poll["question"]).classes('font-bold')
+            ui.label(_("Poll by {creator}, {voters} voted, {kind}", creator=poll["creator"], voters=poll["voters"],
+                       kind=_("pick any") if poll["multiple"] else _("pick one"))).classes('text-gray-400 text-sm')
+            for index, option in enumerate(poll["options"]):
+                count, chosen = poll["counts"][index], index in poll["mine"]
+                with ui.row().classes('w-full items-center gap-2'):
+                    ui.button(option, icon='check_box' if chosen else 'check_box_outline_blank',
+                              color='green-6' if chosen else 'grey-8',
+                              on_click=lambda p=poll, i=index: vote_in_poll(group_id, p, i)).props('flat no-caps')
+                    ui.linear_progress(value=count / poll["voters"] if poll["voters"] else 0, show_value=False) \
+                        .classes('flex-grow')
+                    ui.label(str(count))
+
+async def vote_in_poll(group_id, poll, index):
+    if poll["multiple"]:
+        choices = set(poll["mine"]) ^ {index}
+    else:
+        choices = [] if poll["mine"] == [index] else [index]
+    if not await message_handler.vote(group_id, poll["id"], sorted(choices)):
+        notify(_("Your vote could not be sent."), type='warning')
 
-    ui.run_javascript('window.scrollTo(0, document.body.scrollHeight)')  # Auto-scroll to latest message
+def new_poll_dialog(group_id):
+    with ui.dialog() as dialog, ui.card():
+        ui.label(_('New poll')).classes('text-xl font-bold')
+        question_in = ui.input(label=_('Question')).props('outlined').classes('w-full')
+        options_in = ui.textarea(label=_('Options, one per line')).props('outlined').classes('w-full')
+        multiple_in = ui.switch(_('Allow several choices'))
+
+        async def do_create():
+            options = (options_in.value or "").splitlines()
+            if not await message_handler.create_poll(group_id, question_in.value or "", options, multiple_in.value):
+                notify(_("A poll needs a question and 2 to {max} different options.", max=MAX_POLL_OPTIONS),
+                       type='warning')
+                return
+            dialog.close()
+            render_chat_messages.refresh(message_handler.current_user["username"], active_chat, messages)
+
+        with ui.row():
+            ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+            ui.button(_('Create'), color="green-6", icon="poll", on_click=do_create)
+    dialog.open()
+
+def scroll_to_search_hit():
+    """ Scrolls the open chat to the message a search opened, once it is on the page """
+    ui.run_javascript("setTimeout(() => document.querySelector('.nymchat-search-hit')"
+                      "?.scrollIntoView({block: 'center'}), 100);")
+
+def open_search_hit(chat_id, name, hit):
+    """ Opens the chat of a search hit with the hit loaded, highlighted and scrolled to """
+    global search_hit
+    if not chat_id.startswith(GROUP_CHAT_PREFIX):
+        # Direct chats hold only recent pages; page back until the hit is loaded.
+        while history_cursor.get(chat_id) is not None and history_cursor[chat_id] > hit["id"]:
+            if not load_history_page(chat_id):
+                break
+    search_hit = (chat_id, hit["sender"], hit["timestamp"])
+    clear_selection()
+    set_active_chat(chat_id)
+    set_active_chat_user(name)
+    message_handler.mark_read(chat_id)
+    ui.navigate.to('/app')
+
+def jump_to_mention():
+    """ Scrolls the open chat to the latest message that mentions us """
+    ui.run_javascript("const m = document.querySelectorAll('.nymchat-mention');"
+                      "if (m.length) m[m.length - 1].scrollIntoView({block: 'center'});")
+
+###############################################################################
+# MESSAGE ACTIONS AND SELECTION
+###############################################################################
+def chat_title(chat_id):
+    name = next((chat["name"] for chat in chat_list if chat["id"] == chat_id), chat_id)
+    return _("Group {name}", name=name) if chat_id.startswith(GROUP_CHAT_PREFIX) else _("Chat with {name}", name=name)
+
+def refresh_chat():
+    render_chat_messages.refresh(message_handler.current_user["username"], active_chat, messages)
+
+def selected_messages(chat_id):
+    """ The selected messages of `chat_id`, oldest first. """
+    if not selection or selection["chat"] != chat_id:
+        return []
+    return [entry for entry in messages.get(chat_id, []) if id(entry) in selection["ids"]]
+
+def start_selection(entry=None):
+    global selection
+    selection = {"chat": active_chat, "ids": {id(entry)} if entry else set()}
+    refresh_chat()
+
+def toggle_selected(entry):
+    selection["ids"] ^= {id(entry)}
+    refresh_chat()
+
+def clear_selection():
+    global selection
+    selection = None
+
+def copy_messages(chat_id, entries):
+    ui.clipboard.write("\n".join(f"[{timeUtils.format_stamp(stamp)}] {sender}: {text}"
+                                 for sender, text, stamp, _send_id in entries))
+    notify(_("Copied {count} message(s).", count=len(entries)))
+
+def export_messages(chat_id, entries):
+    rows = [(sender, "text", text, [], stamp) for sender, text, stamp, _send_id in entries]
+    save_export(chat_id, "md", exportUtils.render(rows, chat_title(chat_id)))
+
+def delete_messages_dialog(chat_id, entries):
+    """ Deletes the messages from this device once confirmed; the other side keeps theirs. """
+    with ui.dialog() as dialog, ui.card():
+        ui.label(_('Delete messages')).classes('text-xl font-bold')
+        ui.label(_("Delete {count} message(s) from this device? Whoever else is in the chat keeps them.",
+                   count=len(entries)))
+
+        def delete():
+            dialog.close()
+            deleted = message_handler.delete_local_messages(chat_id, entries)
+            notify(_("Deleted {count} message(s).", count=deleted))
+            refresh_chat()
+
+        with ui.row():
+            ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+            ui.button(_('Delete'), color="red-6", icon="delete", on_click=delete)
+    dialog.open()
+
+def forward_messages_dialog(chat_id, entries):
+    """ Sends the messages on to another chat, marked as forwarded from whoever wrote them. """
+    targets = {info["id"]: info["name"] for info in chat_list if info["id"] != chat_id}
+    if not targets:
+        notify(_("There is no other chat to forward to."), type='warning')
+        return
+    with ui.dialog() as dialog, ui.card():
+        ui.label(_('Forward messages')).classes('text-xl font-bold')
+        target_in = ui.select(targets, label=_('Forward to'), value=next(iter(targets))).classes('w-64')
+
+        async def forward():
+            target = target_in.value
+            if not target.startswith(GROUP_CHAT_PREFIX) and sends_blocked(target):
+                return
+            dialog.close()
+            current_user = message_handler.current_user["username"]
+            for sender, text, _stamp, _send_id in entries:
+                shown, send_id = await message_handler.forward_message(target, sender, text)
+                messages.setdefault(target, []).append((current_user, shown, timeUtils.utc_stamp(), send_id))
+            notify(_("Forwarded {count} message(s) to {name}.", count=len(entries), name=targets[target]))
+
+        with ui.row():
+            ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+            ui.button(_('Forward'), color="green-6", icon="forward", on_click=forward)
+    dialog.open()
+
+# What can be done with one message, from its context menu, or with the selected ones:
+# (icon, label, action taking the chat id and its entries in messages)
+MESSAGE_ACTIONS = [
+    ('content_copy', _('Copy'), copy_messages),
+    ('download', _('Export'), export_messages),
+    ('forward', _('Forward'), forward_messages_dialog),
+    ('delete', _('Delete locally'), delete_messages_dialog),
+]
+
+def run_message_action(action, chat_id, entries):
+    """ Applies a message action, leaving selection mode. """
+    if selection:
+        clear_selection()
+        refresh_chat()
+    action(chat_id, entries)
+
+def message_context_menu(chat_id, entry):
+    with ui.context_menu():
+        ui.menu_item(_('Select'), on_click=lambda: start_selection(entry))
+        for _icon, label, action in MESSAGE_ACTIONS:
+            ui.menu_item(label, on_click=lambda a=action: run_message_action(a, chat_id, [entry]))
+
+def selection_toolbar(chat_id):
+    """ The count of selected messages and what can be done with them. """
+    entries = selected_messages(chat_id)
+    with ui.row().classes('w-full max-w-6xl mx-auto items-center gap-2 bg-zinc-800 p-2 rounded-lg'):
+        ui.label(_("{count} selected", count=len(entries))).classes('font-bold').props('role=status aria-live=polite')
+        for icon, label, action in MESSAGE_ACTIONS:
+            button = ui.button(label, color="green-6", icon=icon,
+                               on_click=lambda a=action: run_message_action(a, chat_id, selected_messages(chat_id))) \
+                .props('flat')
+            if not entries:
+                button.props('disable')
+        ui.button(_('Cancel'), color="green-6", icon="close",
+                  on_click=lambda: (clear_selection(), refresh_chat())).props('flat')
+
+def presence_label(contact):
+    """ "online", "last seen ..." or None if the contact does not share presence with us """
+    presence = message_handler.presence.get(contact)
+    if presence is None:
+        return None
+    if presence["online"]:
+        return _("online")
+    if presence["lastSeen"] is None:
+        return None
+    return _("last seen {time}", time=timeUtils.format_unix(presence['lastSeen']))
+
+def encryption_indicator(chat_id):
+    """ Whether the chat is end-to-end encrypted, how, and which way messages go; a warning while paused. """
+    mode = message_handler.encryption_mode(chat_id)
+    if mode["downgrade"]:
+        ui.button(_('Weaker encryption'), color="red-6", icon="gpp_maybe",
+                  on_click=lambda: downgrade_dialog(chat_id)).props('flat')
+        return
+    routes = {"p2p": _("directly to their nym address"), "reply": _("as a reply through their SURBs"),
+              "server": _("through the server, sender sealed") if mode["sealed"] else _("through the server")}
+    details = [f'{mode["algorithm"]}, {mode["ratchet"]}']
+    if mode["version"] is not None:
+        details.append(_("Handshake version {version}: {features}", version=mode["version"],
+                         features=", ".join(mode["features"]) or _("no features")))
+    elif not chat_id.startswith(GROUP_CHAT_PREFIX):
+        details.append(_("No handshake yet"))
+    with ui.row().classes('items-center gap-1 text-sm text-gray-400') as indicator:
+        ui.icon('lock' if mode["encrypted"] else 'lock_open')
+        ui.label(_("End-to-end encrypted") if mode["encrypted"] else _("Not encrypted"))
+        ui.label(f'· {routes[mode["route"]]}')
+        if mode["anonymous"]:
+            ui.label(_('· anonymous'))
+    indicator.tooltip(" · ".join(details))
+
+def verification_banner(contact):
+    """
+    A warning above the messages of a contact whose key changed and is not yet
+    confirmed, or whose safety number the user has not verified.
+    """
+    if message_handler.safety_number(contact) is None:
+        return
+    if not message_handler.is_trusted(contact):
+        with ui.row().classes('w-full items-center gap-2 p-2 rounded bg-red-10 text-red-2').props('role=alert'):
+            ui.icon('gpp_bad')
+            ui.label(_("The safety number of {name} changed. Messages to them are paused until you confirm "
+                       "the new key.", name=contact))
+            ui.button(_('Review'), color="red-6", on_click=lambda: key_change_dialog(contact)).props('flat')
+    elif not message_handler.is_verified(contact):
+        with ui.row().classes('w-full items-center gap-2 p-2 rounded bg-amber-10 text-amber-2'):
+            ui.icon('gpp_maybe')
+            ui.label(_("You have not verified {name}. Compare safety numbers with them to make sure nobody is "
+                       "in between.", name=contact))
+            ui.button(_('Verify'), color="amber-8", on_click=identity_dialog).props('flat')
+
+def set_anonymous(contact, anonymous):
+    message_handler.set_anonymous(contact, anonymous)
+    refresh_chat()
+    if anonymous and message_handler.contact_handshake(contact):
+        notify(_("{name} may still have your nym address from before. New handshakes leave it out.",
+                 name=contact))
+
+def privacy_select(contact, setting, label):
+    """ Whether `setting` is sent to `contact`: as for everyone, always, or never. """
+    own = message_handler.contact_privacy.get(contact, {}).get(setting)
+    everyone = _("On") if message_handler.privacy[setting] else _("Off")
+    options = {"default": _("Default ({state})", state=everyone), "on": _("On"), "off": _("Off")}
+    choices = {"default": None, "on": True, "off": False}
+    value = "default" if own is None else "on" if own else "off"
+    ui.select(options, label=label, value=value,
+              on_change=lambda e: message_handler.set_privacy(setting, choices[e.value], contact)).classes('w-36')
+
+def folder_select(chat_id):
+    """ Which sidebar folder the chat is filed in, if any. """
+    options = {"": _("No folder"), **{name: name for name in message_handler.folders}}
+    ui.select(options, label=_("Folder"), value=message_handler.chat_folders.get(chat_id, ""),
+              on_change=lambda e: message_handler.move_to_folder(chat_id, e.value or None)).classes('w-36')
+
+def notification_controls(chat_id, is_group):
+    """ Which messages of the chat notify, and muting it for a while. """
+    levels = {"all": _("All messages"), "mentions": _("Mentions only"), "none": _("Nothing")}
+    if not is_group:
+        del levels["mentions"]
+    own = message_handler.chat_notifications.get(chat_id, {}).get("level", "all")
+
+    def changed(action, *args):
+        action(chat_id, *args)
+        render_chat_messages.refresh(message_handler.current_user["username"], chat_id, messages)
+
+    ui.select(levels, label=_("Notify"), value=own if own in levels else "all",
+              on_change=lambda e: message_handler.set_notification_level(chat_id, e.value)).classes('w-36')
+    until = message_handler.muted_until(chat_id)
+    if until is None:
+        durations = {"1h": _("For 1 hour"), "8h": _("For 8 hours"), "forever": _("Until I unmute")}
+        with ui.button(_('Mute'), color="green-6", icon="notifications_off").props('flat'):
+            with ui.menu():
+                for key, label in durations.items():
+                    ui.menu_item(label, on_click=lambda k=key: changed(message_handler.mute, MUTE_DURATIONS[k]))
+    else:
+        muted = _("Muted") if until == MUTE_FOREVER else _("Muted until {time}", time=timeUtils.format_unix(until))
+        ui.label(muted).classes('text-sm text-gray-400')
+        ui.button(_('Unmute'), color="green-6", icon="notifications_active",
+                  on_click=lambda: changed(message_handler.unmute)).props('flat')
+
+def sidebar_sections():
+    """
+    The chat list as the sidebar shows it: `(folder, chats)` for each folder in
+    order, then `(None, chats)` for the chats in none.
+    """
+    filed = {name: [] for name in message_handler.folders}
+    unfiled = []
+    for info in chat_list:
+        filed.get(message_handler.chat_folders.get(info["id"]), unfiled).append(info)
+    return list(filed.items()) + [(None, unfiled)]
+
+def visible_chats():
+    """ The chats the sidebar shows, top to bottom; those in collapsed folders are hidden. """
+    return [info for folder, chats in sidebar_sections() if not message_handler.folders.get(folder) for info in chats]
+
+def delivery_stamp(stamp, status):
+    """ The display time of a stored timestamp, with the delivery state of a sent message. """
+    stamp = timeUtils.format_stamp(stamp)
+    if status is None:
+        return stamp
+    if status == "sending":
+        return f"{stamp} · {_('sending…')}"
+    if status == "waiting":
+        return f"{stamp} · {_('waiting for network')}"
+    if status == "sent":
+        return f"{stamp} · ✓"
+    if status == "delivered":
+        return f"{stamp} · ✓✓"
+    if status == "read":
+        return f"{stamp} · ✓✓ {_('read')}"
+    return f"{stamp} · {_('not delivered ({reason})', reason=status.removeprefix('error: '))}"
+
+@ui.refreshable
+def connection_indicator():
+    """
+    Round trip and version from the last health probe, or a warning if the
+    server stopped answering or the mixnet connection is down.
+    """
+    if not message_handler.online:
+        ui.icon('cloud_off', color='amber-5').tooltip(connection_text())
+        return
+    health = message_handler.health
+    if health is None:
+        ui.icon('wifi_off', color='red-5').tooltip(_('Server not responding'))
+        return
+    with ui.row().classes('items-center gap-1 text-sm text-zinc-300'):
+        ui.icon('wifi', color='green-5')
+        ui.label(f"{health['rtt'] * 1000:.0f} ms")
+        ui.tooltip(_("Server {server} v{version}, up {uptime}s", server=health.get('server') or '',
+                     version=health.get('version'), uptime=health.get('uptime')))
+
+def health_summary(health):
+    """ What the indicator and its tooltip show; they are only redrawn when this changes. """
+    if health is None:
+        return None
+    return f"{health['rtt'] * 1000:.0f}", health.get('server'), health.get('version'), health.get('uptime')
+
+async def refresh_health():
+    shown = health_summary(message_handler.health)
+    health = await message_handler.ping()
+    if health is None:
+        message_handler.health = None
+    if health_summary(message_handler.health) != shown:
+        connection_indicator.refresh()
+        if (shown is None) != (message_handler.health is None):
+            announce(_("Server not responding") if message_handler.health is None else _("Server is responding again"))
 
 ###############################################################################
 # CREATE CORE OBJECTS
@@ -78,6 +987,9 @@ def render_chat_messages(current_user, target_chat, msg_dict):
 crypto_utils = CryptoUtils()
 connection_client = MixnetConnectionClient()
 message_handler = MessageHandler(crypto_utils, connection_client)
+if SERVER_PUBLIC_KEY_FILE:
+    # Fails loudly rather than falling back to trusting any server.
+    message_handler.pin_server_keys(SERVER_PUBLIC_KEY_FILE)
 
 ###############################################################################
 # UTILITY: SCAN FOR USERS, LOAD CHATS FROM DB, CONNECT TO MIXNET
@@ -95,45 +1007,67 @@ def scan_for_users():
     ]
     usernames = dirs
 
+def load_history_page(contact_username):
+    """
+    Prepends the page of messages before the oldest loaded one.
+    Returns False when there is nothing older.
+    """
+    active_username = message_handler.current_user["username"]
+    rows = message_handler.db_manager.load_messages_before(
+        active_username, contact_username, history_cursor.get(contact_username), HISTORY_PAGE
+    )
+    send_ids = message_handler.load_delivery(contact_username)
+    page = []
+    for (row_id, msg_type, msg_content, stamp) in rows:
+        sender_id = active_username if msg_type == 'to' else contact_username
+        page.append((sender_id, msg_content, stamp, send_ids.get(row_id)))
+    messages[contact_username] = page + messages.get(contact_username, [])
+    history_cursor[contact_username] = rows[0][0] if len(rows) == HISTORY_PAGE else None
+    return bool(rows)
+
+def load_older_messages(contact_username):
+    if history_cursor.get(contact_username) is None:
+        return
+    if load_history_page(contact_username):
+        render_chat_messages.refresh(message_handler.current_user["username"], contact_username, messages)
+
 def load_chats_from_db():
-    """Load the chat_list and messages from DB for the current user."""
+    """Load the chat_list and the most recent page of each conversation for the current user."""
     global chat_list, messages
     chat_list.clear()
     messages.clear()
+    history_cursor.clear()
 
     active_username = message_handler.current_user["username"]
     if not message_handler.db_manager:
         logger.warning("DB manager not found; maybe not logged in yet.")
         return
 
-    rows = message_handler.db_manager.conn.execute(
-        f"SELECT DISTINCT username FROM messages_{active_username}"
-    ).fetchall()
+    rows = message_handler.db_manager.get_chat_usernames(active_username)
 
     # build chat_list
-    for (contact_username,) in rows:
+    for contact_username in rows:
         chat_list.append({"id": contact_username, "name": contact_username})
 
-    # load messages
+    # load the latest page of each conversation; older pages load on demand
     for info in chat_list:
-        contact_username = info["id"]
-        chat_msgs = message_handler.db_manager.get_messages_by_contact(
-            active_username, contact_username
-        )
+        load_history_page(info["id"])
 
-        msg_list = []
-        for (msg_type, msg_content, stamp) in chat_msgs:
-            sender_id = active_username if msg_type == 'to' else contact_username
-            msg_list.append((sender_id, msg_content, stamp))
-
-        messages[contact_username] = msg_list
+    for group_id, name in message_handler.db_manager.get_groups(active_username):
+        chat_list.append({"id": group_chat_id(group_id), "name": name})
+        messages[group_chat_id(group_id)] = message_handler.group_history(group_id)
 
     logger.info("Chat list and messages loaded from DB.")
 
-async def connect_mixnet():
+def profile_nym_dir(username):
+    """ Where the mixnet identity of `username` is kept once they switch to it. """
+    return os.path.join(DB_DIR, username, "nym")
+
+async def connect_mixnet(data_dir=None):
+    """ Connects to the mixnet, with the identity kept in `data_dir` or an ephemeral one. """
     global global_nym_address
     logger.info("Initializing Mixnet client...")
-    await connection_client.init()
+    await connection_client.init(data_dir)
     logger.info("Mixnet client initialized.")
     nym_address = await connection_client.get_nym_address()
     global_nym_address = nym_address
@@ -142,40 +1076,284 @@ async def connect_mixnet():
     logger.info(f"My Nym Address: {nym_address}")
     main_loop = asyncio.get_running_loop()
     def message_callback(msg):
-        logger.debug(f"Received raw message from server: {msg}")
-        asyncio.run_coroutine_threadsafe(message_handler.handle_incoming_message(msg), main_loop)
-    await connection_client.set_message_callback(message_callback)
+        logger.debug(f"Received raw message: {msg.message}")
+        handled = message_handler.handle_incoming_message(msg.message, msg.sender_tag)
+        asyncio.run_coroutine_threadsafe(handled, main_loop)
+    await connection_client.set_message_callback(message_callback, typed=True)
     logger.info("Message callback set.")
+    def status_callback(status, nym_address, reason):
+        main_loop.call_soon_threadsafe(connection_changed, status, reason, nym_address)
+    await connection_client.set_status_callback(status_callback)
     asyncio.create_task(connection_client.receive_messages())
     logger.info("Started message receiving loop.")
-    ui.navigate.to("/welcome")  # Redirect to welcome page
+    if SERVER_ADDRESSES:
+        if not await message_handler.pick_server(SERVER_ADDRESSES):
+            logger.warning("No federated server answered; keeping SERVER_ADDRESS.")
+
+def connection_reason(reason):
+    """ Why the mixnet connection was dropped or remade, as shown to the user. """
+    return {
+        "connection_closed": _("the gateway closed the connection"),
+        "resumed": _("the computer woke from sleep"),
+        "network_changed": _("the network changed"),
+        "network_lost": _("the network is unreachable"),
+        "network_restored": _("the network is back"),
+        "reconnect_failed": _("reconnecting failed; trying again when the network changes"),
+        "retry": _("trying again"),
+    }.get(reason)
+
+def connection_text():
+    """ The mixnet connection's state and why it is in it. """
+    reason = connection_reason(message_handler.connection_reason)
+    if message_handler.online:
+        text = _("Mixnet connection restored")
+    elif message_handler.connection_status == "reconnecting":
+        text = _("Reconnecting to the mixnet")
+    else:
+        text = _("No mixnet connection; messages wait in the outbox")
+    return f"{text} ({reason})" if reason else text
+
+def connection_changed(status, reason, nym_address=None):
+    """
+    Follows the mixnet connection, which decides whether messages go out or
+    wait in the outbox, and tells the user each time it is dropped or remade.
+    An ephemeral client comes back under a new `nym_address`, which is what
+    we advertise from then on.
+    """
+    global global_nym_address
+    shown = (message_handler.connection_status, message_handler.connection_reason)
+    message_handler.set_connection_status(status, reason)
+    if status == "connected" and nym_address and nym_address != global_nym_address:
+        logger.info(f"Reconnected under a new nym address: {nym_address}")
+        global_nym_address = nym_address
+        message_handler.update_nym_address(nym_address)
+        status_bar.refresh()
+    if (status, reason) != shown:
+        connection_indicator.refresh()
+        status_bar.refresh()
+        announce(connection_text())
+
+def open_session():
+    """ Points the message handler at the UI state and loads the logged-in user's chats. """
+    message_handler.set_ui_state(messages, chat_list, get_active_chat, render_chat_messages, chat_messages_container)
+    load_chats_from_db()
+    message_handler.load_presence()
+    message_handler.load_sender_tags()
+    message_handler.load_anonymous()
+    message_handler.restore_snapshot()
+    message_handler.load_outbox()
+    message_handler.load_privacy()
+    message_handler.load_away()
+    message_handler.load_folders()
+    message_handler.load_notifications()
+    message_handler.load_drafts()
+
+def clear_session():
+    chat_list.clear()
+    messages.clear()
+    history_cursor.clear()
+    clear_selection()
+    set_active_chat(None)
+    set_active_chat_user(None)
+
+async def switch_account(username, passphrase=None):
+    """
+    Logs the current account out and `username` in without restarting: the
+    mixnet client is replaced by one with the identity of `username`, so the
+    two accounts are never seen from the same nym address. `passphrase`
+    opens the key of `username` if it is kept in a keystore.
+    Returns True once `username` is logged in.
+    """
+    await message_handler.end_session()
+    clear_session()
+    await connection_client.shutdown()
+    await connect_mixnet(profile_nym_dir(username))
+    if not await message_handler.login_user(username, passphrase):
+        return False
+    open_session()
+    asyncio.create_task(message_handler.update_presence())
+    return True
 
 ###############################################################################
 # OUTGOING MESSAGES
 ###############################################################################
+def export_command(args):
+    """
+    `/export [md|txt] [since] [until]`: writes the open chat, or the days
+    `since` to `until` of it (YYYY-MM-DD), to the account's exports folder
+    and offers it for download.
+    """
+    fmt = args.pop(0) if args and args[0] in exportUtils.FORMATS else "md"
+    try:
+        if len(args) > 2:
+            raise ValueError(_("Usage: /export [md|txt] [since] [until]"))
+        since, until = (exportUtils.parse_day(day) for day in args + [None] * (2 - len(args)))
+        current_user = message_handler.current_user["username"]
+        document = exportUtils.export_conversation(message_handler.db_manager, current_user, active_chat, fmt,
+                                                   since, until)
+    except ValueError as e:
+        notify(str(e), type='warning')
+        return
+    save_export(active_chat, fmt, document)
+
+def save_export(chat_id, fmt, document):
+    """ Writes an export of `chat_id` to the account's exports folder and offers it for download. """
+    folder = os.path.join(message_handler.crypto_utils.storage_dir, message_handler.current_user["username"],
+                          "exports")
+    os.makedirs(folder, exist_ok=True)
+    path = os.path.join(folder, exportUtils.export_filename(chat_id, fmt))
+    with open(path, "w", encoding="utf-8") as f:
+        f.write(document)
+    message_handler.audit("conversation_exported", f"{chat_id} to {path}")
+    ui.download(path)
+    notify(_("Conversation exported to {path}", path=path), type='positive')
+
+async def save_diagnostics():
+    """
+    Writes a redacted diagnostic bundle (see diagnostics.py) to the account's
+    folder, or the storage folder before login, and offers it for download.
+    """
+    try:
+        stats = await message_handler.network_stats()
+    except Exception as e:
+        logger.warning(f"Network statistics unavailable for the diagnostic bundle: {e}")
+        stats = None
+    username = message_handler.current_user["username"]
+    db = message_handler.db_manager if username else None
+    bundle = diagnostics.collect(diagnostics.buffered_records(log_buffer.entries()), db, username, stats,
+                                 message_handler.health)
+    folder = message_handler.crypto_utils.storage_dir
+    if username:
+        folder = os.path.join(folder, username, "diagnostics")
+    os.makedirs(folder, exist_ok=True)
+    path = os.path.join(folder, diagnostics.bundle_filename())
+    diagnostics.write_bundle(bundle, path)
+    if username:
+        message_handler.audit("diagnostics_exported", path)
+    ui.download(path)
+    notify(_("Diagnostic bundle written to {path}", path=path), type='positive')
+
 async def send_message(text_input):
     if not active_chat or not text_input.value.strip():
         return
+    command = text_input.value.split()
+    if command[0] == "/export":
+        text_input.value = ''
+        export_command(command[1:])
+        return
+    if command[0] == "/diagnostics":
+        text_input.value = ''
+        await save_diagnostics()
+        return
+    if command[0] == "/send":
+        path = text_input.value.strip().removeprefix("/send").strip()
+        text_input.value = ''
+        await send_file_command(path)
+        return
+    is_group = active_chat.startswith(GROUP_CHAT_PREFIX)
+    if not is_group and sends_blocked(active_chat_user):
+        return
 
-    msg_text = text_input.value.strip()
+    msg_text = quickReplies.expand(text_input.value).strip()
     text_input.value = ''
     current_user = message_handler.current_user["username"]
 
-    # 1) Send direct message
-    await message_handler.send_direct_message(active_chat_user, msg_text)
+    # 1) Send direct or group message
+    if is_group:
+        send_id = await message_handler.send_group_message(active_chat.removeprefix(GROUP_CHAT_PREFIX), msg_text)
+    else:
+        send_id = await message_handler.send_direct_message(active_chat_user, msg_text)
 
     # 2) Store in local memory
-    stamp = datetime.now().strftime('%Y-%m-%d %H:%M:%S')
+    stamp = timeUtils.utc_stamp()
     if active_chat not in messages:
         messages[active_chat] = []
-    messages[active_chat].append((current_user, msg_text, stamp))
+    messages[active_chat].append((current_user, msg_text, stamp, send_id))
 
     # 3) Re-render chat UI
     render_chat_messages.refresh(current_user, active_chat, messages)
 
+async def send_file_command(path):
+    """ `/send <path>`: sends the file at `path` to the open direct chat, showing how far it got. """
+    if not path:
+        notify(_("Usage: /send <path>"), type='warning')
+        return
+    if active_chat.startswith(GROUP_CHAT_PREFIX):
+        notify(_("Files can only be sent in direct chats."), type='warning')
+        return
+    if sends_blocked(active_chat_user):
+        return
+    path = os.path.expanduser(path)
+    if not os.path.isfile(path):
+        notify(_("There is no file at {path}.", path=path), type='warning')
+        return
+    if not await message_handler.send_file(active_chat_user, path):
+        notify(_("{name} could not be sent. Files are sent only while connected, up to {size} MB.",
+                 name=os.path.basename(path), size=MAX_FILE_SIZE // 2**20), type='warning')
+
+def share_location_dialog():
+    """ Asks for a location, then shows it for confirmation before anything is sent """
+    if not active_chat or active_chat.startswith(GROUP_CHAT_PREFIX):
+        notify(_("Locations can only be shared in direct chats."), type='warning')
+        return
+    if sends_blocked(active_chat_user):
+        return
+    recipient = active_chat_user
+    with ui.dialog() as dialog, ui.card().classes('w-96'):
+        ui.label(_('Share location')).classes('text-xl font-bold')
+        with ui.column().classes('w-full') as form:
+            lat_in = ui.number(label=_('Latitude'), min=-90, max=90, format='%.6f').classes('w-full')
+            lon_in = ui.number(label=_('Longitude'), min=-180, max=180, format='%.6f').classes('w-full')
+            accuracy_in = ui.number(label=_('Accuracy in metres (optional)'), min=0).classes('w-full')
+            label_in = ui.input(label=_('Label (optional)')).props(f'maxlength={MAX_LOCATION_LABEL}').classes('w-full')
+        review = ui.column().classes('w-full')
+        chosen = {}
+
+        def show_review(shown):
+            form.set_visibility(not shown)
+            form_buttons.set_visibility(not shown)
+            review.set_visibility(shown)
+            review_buttons.set_visibility(shown)
+
+        def do_review():
+            location = parse_location({"lat": lat_in.value, "lon": lon_in.value, "accuracy": accuracy_in.value,
+                                       "label": label_in.value})
+            if location is None:
+                notify(_("Enter a latitude from -90 to 90 and a longitude from -180 to 180."), type='warning')
+                return
+            chosen["location"] = location
+            review.clear()
+            with review:
+                ui.label(_("Send this location to {name}?", name=recipient)).classes('font-bold')
+                ui.label(location_text(location))
+                ui.link(_('Open in OpenStreetMap'), osm_link(location), new_tab=True)
+            show_review(True)
+
+        async def do_send():
+            dialog.close()
+            await send_location(recipient, chosen["location"])
+
+        with ui.row() as form_buttons:
+            ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+            ui.button(_('Review'), color="green-6", icon="place", on_click=do_review)
+        with ui.row() as review_buttons:
+            ui.button(_('Back'), color="green-6", on_click=lambda: show_review(False))
+            ui.button(_('Send'), color="green-6", icon="send", on_click=do_send)
+        show_review(False)
+    dialog.open()
+
+async def send_location(recipient, location):
+    send_id = await message_handler.send_location(recipient, location)
+    if send_id is None:
+        notify(_("The location could not be sent."), type='warning')
+        return
+    current_user = message_handler.current_user["username"]
+    messages.setdefault(recipient, []).append((current_user, location_text(location), timeUtils.utc_stamp(), send_id))
+    render_chat_messages.refresh(current_user, active_chat, messages)
+
 async def send_handshake():
     """
-    Native function to send a handshake (type 1 message) to the active chat user.
+    Starts a handshake (type 1 message) with the active chat user.
     """
     if not active_chat or not active_chat_user or not global_nym_address:
         return
@@ -189,7 +1367,7 @@ def connect_page():
     with ui.column().classes('max-w-4xl mx-auto items-center flex flex-col justify-center h-screen'):
         ui.label("NymCHAT").classes("text-3xl font-bold mb-8")
         with ui.row().classes('justify-center w-full'):
-            spin = ui.spinner(size='lg').props('hidden').classes("mb-4")
+            spin = busy_indicator()
         
         async def do_connect():
             spin.props(remove='hidden')  # Show spinner
@@ -197,84 +1375,142 @@ def connect_page():
             spin.props('hidden')          # Hide spinner
             ui.navigate.to("/welcome")    # Navigate to welcome page
         
-        ui.button("Connect to Mixnet", color="green-6", on_click=do_connect, icon="wifi")
+        ui.button(_("Connect to Mixnet"), color="green-6", on_click=do_connect, icon="wifi")
 
 @ui.page('/welcome')
 def welcome_page():
     with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
-        ui.label("Welcome to NymCHAT").classes("text-3xl text-center font-bold mb-8")
-        ui.button("Login", color="green-6", on_click=lambda: ui.navigate.to("/login"), icon="login").classes("mb-2")
-        ui.button("Register", color="green-6", on_click=lambda: ui.navigate.to("/register"), icon="how_to_reg").classes("mb-2")
+        ui.label(_("Welcome to NymCHAT")).classes("text-3xl text-center font-bold mb-8")
+        ui.button(_("Login"), color="green-6", on_click=lambda: ui.navigate.to("/login"), icon="login").classes("mb-2")
+        ui.button(_("Register"), color="green-6", on_click=lambda: ui.navigate.to("/register"), icon="how_to_reg").classes("mb-2")
+        ui.button(_("Import Account"), color="green-6", on_click=lambda: ui.navigate.to("/import"), icon="upload_file").classes("mb-2")
+        ui.button(_("Restore Backup"), color="green-6", on_click=lambda: ui.navigate.to("/restore"), icon="cloud_download").classes("mb-2")
 
 @ui.page('/login')
 def login_page():
     with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
-        ui.label("Login").classes("text-2xl text-center font-bold mb-4")
+        ui.label(_("Login")).classes("text-2xl text-center font-bold mb-4")
         
         scan_for_users()  # Load list of usernames
 
         if usernames:
-            user_select = ui.select(usernames, label="Select a User").props("outlined").classes("mb-2")
+            user_select = ui.select(usernames, label=_("Select a User")).props("outlined").classes("mb-2")
+            # Only accounts whose key is in a keystore need it.
+            passphrase_in = ui.input(label=_("Passphrase"), password=True).props("outlined").classes("mb-2")
+            passphrase_in.bind_visibility_from(user_select, 'value', backward=crypto_utils.has_keystore)
             
             with ui.row().classes('justify-center w-full'):
-                spin = ui.spinner(size='lg').props('hidden').classes("mb-4")
+                spin = busy_indicator()
 
             async def do_login():
                 if not user_select.value:
-                    ui.notify("Please select a user.")
+                    notify(_("Please select a user."))
+                    return
+                if crypto_utils.has_keystore(user_select.value) and not passphrase_in.value:
+                    notify(_("Enter the passphrase of this account."))
                     return
                 spin.props(remove='hidden')  # Show spinner
 
                 # Begin login process
-                await message_handler.login_user(user_select.value)
-                await message_handler.login_complete.wait()
+                login_successful = await message_handler.login_user(user_select.value, passphrase_in.value or None)
+                passphrase_in.value = ""
 
                 # Set up UI state and load chat data
-                message_handler.set_ui_state(messages, chat_list, get_active_chat, render_chat_messages, chat_messages_container)
-                load_chats_from_db()
+                open_session()
 
                 spin.props('hidden')  # Hide spinner
 
-                if message_handler.login_successful:
-                    ui.notify("Login successful! Welcome.")
+                if login_successful:
+                    asyncio.create_task(message_handler.update_presence())
+                    notify(_("Login successful! Welcome."))
                     ui.navigate.to("/app")
+                elif crypto_utils.has_keystore(user_select.value):
+                    notify(_("Login Failed: wrong passphrase?"))
                 else:
-                    ui.notify("Login Failed: Did you delete your key file?")
+                    notify(_("Login Failed: Did you delete your key file?"))
 
-            ui.button("Login", color="green-6", on_click=do_login, icon="login").classes("mb-2")
+            ui.button(_("Login"), color="green-6", on_click=do_login, icon="login").classes("mb-2")
         else:
-            ui.label("No users found. Please register first.")
+            ui.label(_("No users found. Please register first."))
 
-        ui.button("Back", color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
+        ui.button(_("Back"), color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
 
 @ui.page('/register')
 def register_page():
     with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
-        ui.label("Register a New User").classes("text-2xl text-center font-bold mb-4")
-        user_in = ui.input(label="Username").props("outlined").classes("mb-2")
+        ui.label(_("Register a New User")).classes("text-2xl text-center font-bold mb-4")
+        user_in = ui.input(label=_("Username")).props("outlined").classes("mb-2")
+        passphrase_in = ui.input(label=_("Passphrase (optional)"), password=True).props("outlined").classes("mb-2")
+        confirm_in = ui.input(label=_("Repeat passphrase"), password=True).props("outlined").classes("mb-2")
         
         with ui.row().classes('justify-center w-full'):
-            spin = ui.spinner(size='lg').props('hidden').classes("mb-4")
+            spin = busy_indicator()
             
         async def do_register():
             username = user_in.value.strip()
             if not username:
-                ui.notify("Username is required!")
+                notify(_("Username is required!"))
+                return
+            if passphrase_in.value != confirm_in.value:
+                notify(_("The passphrases do not match."), type='warning')
                 return
 
             spin.props(remove='hidden')
-            await message_handler.register_user(username)
-            await message_handler.registration_complete.wait()
+            registration_successful = await message_handler.register_user(username,
+                                                                          passphrase=passphrase_in.value or None)
             spin.props('hidden')
 
-            if message_handler.registration_successful:
-                ui.notify("Registration completed! Please login.")
+            if registration_successful:
+                notify(_("Registration completed! Please login."))
                 ui.navigate.to("/login")
             else:
                 user_in.value = ""
 
-        ui.button("Register", color="green-6", on_click=do_register, icon="how_to_reg").classes("mb-2")
-        ui.button("Back", color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
+        ui.button(_("Register"), color="green-6", on_click=do_register, icon="how_to_reg").classes("mb-2")
+        ui.button(_("Back"), color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
+
+@ui.page('/import')
+def import_page():
+    with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
+        ui.label(_("Import an Account")).classes("text-2xl text-center font-bold mb-4")
+        path_in = ui.input(label=_("Export file")).props("outlined").classes("mb-2")
+        passphrase_in = ui.input(label=_("Passphrase"), password=True).props("outlined").classes("mb-2")
+
+        def do_import():
+            try:
+                username = message_handler.import_account(path_in.value.strip(), passphrase_in.value)
+            except Exception as e:
+                notify(_("Import failed: {error}", error=e), type='negative')
+                return
+            notify(_("Imported {username}. Please login.", username=username))
+            ui.navigate.to("/login")
+
+        ui.button(_("Import"), color="green-6", on_click=do_import, icon="upload_file").classes("mb-2")
+        ui.button(_("Back"), color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
+
+@ui.page('/restore')
+def restore_page():
+    with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
+        ui.label(_("Restore a Backup")).classes("text-2xl text-center font-bold mb-4")
+        mnemonic_in = ui.textarea(label=_("Recovery words")).props("outlined").classes("mb-2")
+
+        with ui.row().classes('justify-center w-full'):
+            spin = busy_indicator()
+
+        async def do_restore():
+            spin.props(remove='hidden')
+            try:
+                username = await message_handler.restore_backup(mnemonic_in.value or "")
+            except Exception as e:
+                notify(_("Restore failed: {error}", error=e), type='negative')
+                return
+            finally:
+                spin.props('hidden')
+            notify(_("Restored {username}. Please login.", username=username))
+            ui.navigate.to("/login")
+
+        ui.button(_("Restore"), color="green-6", on_click=do_restore, icon="cloud_download").classes("mb-2")
+        ui.button(_("Back"), color="green-6", on_click=lambda: ui.navigate.to("/welcome"), icon="arrow_back_ios_new").classes("mb-2")
 
 @ui.page('/app')
 def chat_page():
@@ -289,104 +1525,842 @@ def chat_page():
 
     def show_new_message_notification(sender, message):
         with chat_messages_container:
-            ui.notify(f"New message from {sender}: {message}")
+            notify(_("New message from {sender}: {message}", sender=sender, message=message))
 
     message_handler.new_message_callback = show_new_message_notification
 
+    def show_mention(chat_id, sender, message):
+        group = next((chat["name"] for chat in chat_list if chat["id"] == chat_id), chat_id)
+        text = _("{sender} mentioned you in {group}: {message}", sender=sender, group=group, message=message)
+        with chat_messages_container:
+            notify(text, type='warning', position='top', timeout=15000, close_button=_('Dismiss'))
+
+    message_handler.mention_callback = show_mention
+
+    def show_send_failure(recipient, status):
+        recipient = next((chat["name"] for chat in chat_list if chat["id"] == recipient), recipient)
+        with chat_messages_container:
+            notify(_("Message to {recipient} not delivered: {reason}",
+                        recipient=recipient, reason=status.removeprefix('error: ')), type='negative')
+
+    message_handler.send_failed_callback = show_send_failure
+
+    def show_key_change(username, old_fingerprint, new_fingerprint):
+        with chat_messages_container:
+            key_change_dialog(username)
+
+    message_handler.key_change_callback = show_key_change
+
+    def show_downgrade(username, lost):
+        with chat_messages_container:
+            downgrade_dialog(username)
+
+    message_handler.downgrade_callback = show_downgrade
+
+    def show_session_expired():
+        with chat_messages_container:
+            session_expired_dialog()
+
+    message_handler.session_expired_callback = show_session_expired
+
+    def show_equivocation(contact, own_fingerprint, observed_fingerprint):
+        with chat_messages_container:
+            equivocation_dialog(contact, own_fingerprint, observed_fingerprint)
+
+    message_handler.equivocation_callback = show_equivocation
+
+    def show_quarantined(reason, count):
+        with chat_messages_container:
+            notify(_("A message could not be read or verified and was quarantined ({count} in quarantine).",
+                     count=count), type='warning')
+
+    message_handler.quarantine_callback = show_quarantined
+
+    def export_account_dialog():
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Export account')).classes('text-xl font-bold')
+            ui.label(_('Writes your keys, contacts and settings to one encrypted file you can import on another device.'))
+            path_in = ui.input(label=_('File'), value=os.path.join(os.getcwd(), f"{user_id}.nymchat")).props('outlined')
+            passphrase_in = ui.input(label=_('Passphrase'), password=True).props('outlined')
+            confirm_in = ui.input(label=_('Repeat passphrase'), password=True).props('outlined')
+            history = ui.checkbox(_('Include message history'))
+
+            def do_export():
+                if not passphrase_in.value or passphrase_in.value != confirm_in.value:
+                    notify(_("The passphrases are empty or do not match."), type='warning')
+                    return
+                try:
+                    message_handler.export_account(path_in.value.strip(), passphrase_in.value, history.value)
+                except Exception as e:
+                    notify(_("Export failed: {error}", error=e), type='negative')
+                    return
+                dialog.close()
+                notify(_("Account exported to {path}", path=path_in.value.strip()))
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Export'), color="green-6", icon="download", on_click=do_export)
+        dialog.open()
+
+    def protect_key_dialog():
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Protect your key')).classes('text-xl font-bold')
+            if crypto_utils.has_keystore(user_id):
+                ui.label(_('Your private key is already encrypted with a passphrase.'))
+                ui.button(_('Close'), color="green-6", on_click=dialog.close)
+                dialog.open()
+                return
+            ui.label(_('Encrypts your private key on this device with a passphrase, asked for at every login. '
+                       'Without it the key cannot be recovered.'))
+            passphrase_in = ui.input(label=_('Passphrase'), password=True).props('outlined')
+            confirm_in = ui.input(label=_('Repeat passphrase'), password=True).props('outlined')
+
+            def do_protect():
+                if not passphrase_in.value or passphrase_in.value != confirm_in.value:
+                    notify(_("The passphrases are empty or do not match."), type='warning')
+                    return
+                try:
+                    message_handler.protect_key(passphrase_in.value)
+                except Exception as e:
+                    notify(_("Could not protect the key: {error}", error=e), type='negative')
+                    return
+                dialog.close()
+                notify(_("Your key is now encrypted with the passphrase."))
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Protect'), color="green-6", icon="key", on_click=do_protect)
+        dialog.open()
+
+    def backup_dialog():
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Remote backup')).classes('text-xl font-bold')
+            if message_handler.backup_enabled():
+                ui.label(_('Backups are on. Your account and messages are encrypted and uploaded every hour.'))
+
+                async def backup_now():
+                    await message_handler.run_backup()
+                    notify(_("Backup sent."))
+
+                async def turn_off():
+                    await message_handler.disable_backup()
+                    dialog.close()
+                    notify(_("Backups turned off and the remote copy deleted."))
+
+                with ui.row():
+                    ui.button(_('Back up now'), color="green-6", icon="cloud_upload", on_click=backup_now)
+                    ui.button(_('Turn off'), color="red-6", icon="cloud_off", on_click=turn_off)
+            else:
+                ui.label(_('Uploads an encrypted copy of your account and messages every hour. '
+                           'You get recovery words that restore it on any device.'))
+
+                async def turn_on():
+                    dialog.close()
+                    mnemonic = await message_handler.enable_backup()
+                    with ui.dialog() as words_dialog, ui.card():
+                        ui.label(_('Write down your recovery words')).classes('text-xl font-bold')
+                        ui.label(_('Anyone with these words can read your backup, and without them it cannot be '
+                                   'restored. They are shown only once.'))
+                        ui.label(mnemonic).classes('font-mono text-lg')
+                        ui.button(_('Done'), color="green-6", on_click=words_dialog.close)
+                    words_dialog.open()
+
+                ui.button(_('Turn on'), color="green-6", icon="cloud_upload", on_click=turn_on)
+            ui.button(_('Close'), color="green-6", on_click=dialog.close)
+        dialog.open()
+
+    def quick_reply_dialog():
+        """ Picks a canned response into the message input, to send as is or edit first """
+        if not active_chat:
+            return
+        if not quickReplies.replies:
+            notify(_("No quick replies yet. Add them to {path}.", path=quickReplies.path), type='warning')
+            return
+
+        def pick(reply):
+            dialog.close()
+            text_in.value = reply
+            text_in.run_method('focus')
+
+        with ui.dialog() as dialog, ui.card().classes('w-96'):
+            ui.label(_('Quick replies')).classes('text-xl font-bold')
+            query = ui.input(placeholder=_('Filter'), on_change=lambda e: reply_list.refresh(e.value)) \
+                .props('autofocus outlined dense').classes('w-full')
+
+            def pick_first():
+                found = quickReplies.matching(query.value)
+                if found:
+                    pick(found[0])
+            query.on('keydown.enter', pick_first)
+
+            @ui.refreshable
+            def reply_list(text=""):
+                for reply in quickReplies.matching(text):
+                    ui.item(reply, on_click=lambda r=reply: pick(r)).classes('w-full')
+
+            with ui.list().props('separator').classes('w-full'):
+                reply_list()
+            ui.button(_('Close'), color="green-6", on_click=dialog.close)
+        dialog.open()
+
+    def privacy_dialog():
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Privacy')).classes('text-xl font-bold')
+            ui.label(_('Whether contacts are told that you read their messages, and while you are typing. '
+                       'Each chat can change this for its contact.'))
+            ui.switch(_('Send read receipts'), value=message_handler.privacy["receipts"],
+                      on_change=lambda e: message_handler.set_privacy("receipts", e.value))
+            ui.switch(_('Send typing indicator'), value=message_handler.privacy["typing"],
+                      on_change=lambda e: message_handler.set_privacy("typing", e.value))
+            ui.label(_('Heartbeats tell the server while you are online, so it sends you messages at once '
+                       'and keeps them for later while you are not. Without them the server does not learn when '
+                       'you come and go.'))
+            ui.switch(_('Send heartbeats'), value=message_handler.send_heartbeats,
+                      on_change=lambda e: message_handler.set_heartbeats(e.value))
+            ui.button(_('Close'), color="green-6", on_click=dialog.close)
+        dialog.on('hide', lambda: render_chat_messages.refresh(user_id, active_chat, messages))
+        dialog.open()
+
+    def downloads_dialog():
+        with ui.dialog() as dialog, ui.card().classes('w-96'):
+            ui.label(_('Downloads')).classes('text-xl font-bold')
+            ui.label(_('Files your contacts send you are kept in this folder. Send one with /send and its path.'))
+            folder_in = ui.input(label=_('Folder'), value=message_handler.downloads_dir()) \
+                .props('outlined').classes('w-full')
+
+            def save(folder):
+                message_handler.set_downloads_dir(folder)
+                notify(_("Files are kept in {path}.", path=message_handler.downloads_dir()))
+                dialog.close()
+
+            with ui.row():
+                ui.button(_('Save'), color="green-6", icon="save", on_click=lambda: save(folder_in.value or ""))
+                ui.button(_('Default'), color="green-6", on_click=lambda: save("")).props('flat')
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close).props('flat')
+        dialog.open()
+
+    def away_dialog():
+        away = message_handler.away or {"message": "", "interval": DEFAULT_AWAY_INTERVAL}
+        with ui.dialog() as dialog, ui.card().classes('w-96'):
+            ui.label(_('Away')).classes('text-xl font-bold')
+            ui.label(_('While you are away, messages from contacts are answered with your away message, '
+                       'once per contact in each interval. Each chat can turn it off for its contact.'))
+            message_in = ui.textarea(label=_('Away message'), value=away["message"]) \
+                .props('outlined').classes('w-full')
+            minutes_in = ui.number(label=_('Minutes before answering a contact again'), min=0, precision=0,
+                                   value=away["interval"] // 60).classes('w-full')
+
+            def save(enabled):
+                if enabled and not message_in.value.strip():
+                    notify(_("Write an away message first."), type='warning')
+                    return
+                message_handler.set_away(message_in.value if enabled else None, int(minutes_in.value or 0) * 60)
+                notify(_("You are away.") if enabled else _("You are back."))
+                dialog.close()
+
+            with ui.row():
+                ui.button(_('Set away'), color="green-6", icon="do_not_disturb_on", on_click=lambda: save(True))
+                if message_handler.away:
+                    ui.button(_("I'm back"), color="green-6", icon="check", on_click=lambda: save(False))
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close).props('flat')
+        dialog.on('hide', lambda: render_chat_messages.refresh(user_id, active_chat, messages))
+        dialog.open()
+
+    def new_group_dialog():
+        contacts = [username for username, _key in message_handler.db_manager.get_all_contacts(user_id)]
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('New group')).classes('text-xl font-bold')
+            name_in = ui.input(label=_('Group name')).props('outlined')
+            members_in = ui.select(contacts, multiple=True, label=_('Members')).props('outlined use-chips')
+
+            async def do_create():
+                name = name_in.value.strip()
+                if not name or not members_in.value:
+                    notify(_("Give the group a name and at least one member."), type='warning')
+                    return
+                dialog.close()
+                group_id = await message_handler.create_group(name, members_in.value)
+                set_active_chat(group_chat_id(group_id))
+                set_active_chat_user(name)
+                render_chat_messages.refresh(user_id, active_chat, messages)
+                notify(_("Group {name} created.", name=name))
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Create'), color="green-6", icon="group_add", on_click=do_create)
+        dialog.open()
+
+    def confirm_delete_account():
+        # Typing the phrase, rather than clicking, guards against deleting by accident.
+        phrase = _("delete {username}", username=user_id)
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Delete account')).classes('text-xl font-bold')
+            ui.label(_('This removes {username} from the server, tells your contacts, and wipes your keys '
+                       'and messages from this device. It cannot be undone.', username=user_id))
+            ui.label(_('Type "{phrase}" to confirm.', phrase=phrase))
+            typed = ui.input(placeholder=phrase).props('outlined')
+
+            async def do_delete():
+                if typed.value != phrase:
+                    notify(_("The confirmation phrase does not match."), type='warning')
+                    return
+                dialog.close()
+                await delete_account()
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Delete'), color="red-6", icon="delete_forever", on_click=do_delete)
+        dialog.open()
+
+    def on_key(e):
+        if not e.modifiers.alt or not e.action.keydown:
+            return
+        if e.key == 'a' and not e.action.repeat:
+            ui.navigate.to('/switch')
+        elif e.key == 'u' and not e.action.repeat:
+            next_unread()
+        elif e.key == 'c' and not e.action.repeat:
+            copy_address()
+        elif e.key == 'f' and not e.action.repeat:
+            copy_fingerprint()
+        elif e.key == 'v' and not e.action.repeat:
+            identity_dialog()
+        elif e.key == 'r' and not e.action.repeat:
+            quick_reply_dialog()
+        elif e.key == 's' and not e.action.repeat:
+            ui.navigate.to('/search?scope=messages')
+        elif e.key.arrow_down:
+            step_chat(1)
+        elif e.key.arrow_up:
+            step_chat(-1)
+
+    ui.keyboard(on_key=on_key, ignore=[])
+
+    def new_folder_dialog():
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('New folder')).classes('text-xl font-bold')
+            name_in = ui.input(label=_('Folder name')).props('outlined')
+
+            def do_create():
+                if not message_handler.create_folder(name_in.value or ""):
+                    notify(_("Give the folder a name no other folder has."), type='warning')
+                    return
+                dialog.close()
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Create'), color="green-6", icon="create_new_folder", on_click=do_create)
+        dialog.open()
+
+    def rename_folder_dialog(folder):
+        with ui.dialog() as dialog, ui.card():
+            ui.label(_('Rename folder')).classes('text-xl font-bold')
+            name_in = ui.input(label=_('Folder name'), value=folder).props('outlined')
+
+            def do_rename():
+                if name_in.value.strip() != folder and not message_handler.rename_folder(folder, name_in.value):
+                    notify(_("Give the folder a name no other folder has."), type='warning')
+                    return
+                dialog.close()
+
+            with ui.row():
+                ui.button(_('Cancel'), color="green-6", on_click=dialog.close)
+                ui.button(_('Rename'), color="green-6", icon="edit", on_click=do_rename)
+        dialog.open()
+
+    def toggle_folder(folder):
+        message_handler.set_folder_collapsed(folder, not message_handler.folders[folder])
+
+    def unread_badge(count, quiet=False):
+        """ Quiet counts, of chats that do not notify of every message, are grey. """
+        if count:
+            ui.badge(str(count), color='grey-6' if quiet else 'green-6') \
+                .props(f'aria-label="{_("{count} unread", count=count)}"')
 
     @ui.refreshable
     def chat_list_sidebar():
-        with ui.column():
-            ui.label('Chats').classes('text-xl font-bold')
+        with ui.column().classes('w-full'):
+            with ui.row().classes('w-full items-center justify-between'):
+                ui.label(_('Chats')).classes('text-xl font-bold')
+                ui.button(icon='create_new_folder', color="green-6", on_click=new_folder_dialog).props('flat dense') \
+                    .props(f'aria-label="{_("New folder")}"').tooltip(_('New folder'))
             if not chat_list:
-                ui.label('No chats yet').classes('text-gray-400')
-            for info in chat_list:
-                with ui.row().classes('p-2 hover:bg-gray-800 cursor-pointer') \
-                        .on('click', lambda _, u=info: open_chat(u)):
-                    ui.label(info["name"]).classes('font-bold text-white')
-                    ui.label('Click to open chat').classes('text-gray-400 text-sm')
+                ui.label(_('No chats yet')).classes('text-gray-400')
+            for folder, chats in sidebar_sections():
+                indent = ''
+                if folder is not None:
+                    collapsed = message_handler.folders[folder]
+                    with ui.row().classes('w-full items-center gap-1 p-1 hover:bg-gray-800 cursor-pointer') \
+                            .on('click', lambda e, f=folder: toggle_folder(f)):
+                        ui.icon('chevron_right' if collapsed else 'expand_more')
+                        ui.icon('folder')
+                        ui.label(folder).classes('font-bold')
+                        unread_badge(message_handler.folder_unread(folder))
+                        with ui.context_menu():
+                            ui.menu_item(_('Rename'), on_click=lambda f=folder: rename_folder_dialog(f))
+                            ui.menu_item(_('Delete folder'), on_click=lambda f=folder: message_handler.delete_folder(f))
+                    if collapsed:
+                        continue
+                    indent = ' pl-6'
+                for info in chats:
+                    with ui.row().classes('p-2 hover:bg-gray-800 cursor-pointer items-center' + indent) \
+                            .on('click', lambda e, u=info: open_chat(u)):
+                        ui.label(info["name"]).classes('font-bold text-white')
+                        if info["id"] in message_handler.drafts:
+                            ui.icon('edit').props(f'aria-label="{_("Draft")}"').tooltip(_('Draft'))
+                        if message_handler.muted_until(info["id"]) is not None:
+                            ui.icon('notifications_off').props(f'aria-label="{_("Muted")}"')
+                        unread_badge(message_handler.unread.get(info["id"], 0),
+                                     message_handler.notification_level(info["id"]) != "all")
+                        ui.label(presence_label(info["id"]) or _('Click to open chat')).classes('text-gray-400 text-sm')
 
-    def open_chat(u):
+    def select_chat(u):
+        clear_selection()
         set_active_chat(u["id"])
         set_active_chat_user(u["name"])
-        chat_drawer.toggle()
+        text_in.value = message_handler.drafts.get(active_chat, "")
+        message_handler.mark_read(u["id"])
         if chat_messages_container:
             render_chat_messages.refresh(user_id, active_chat, messages)
 
+    def open_chat(u):
+        select_chat(u)
+        chat_drawer.toggle()
+
+    def step_chat(offset):
+        """ Opens the chat `offset` places below the open one in the sidebar, skipping collapsed folders. """
+        chats = visible_chats()
+        if not chats:
+            return
+        ids = [info["id"] for info in chats]
+        position = ids.index(active_chat) + offset if active_chat in ids else 0 if offset > 0 else -1
+        select_chat(chats[position % len(chats)])
+
+    def next_unread():
+        """ Opens the first chat with unread messages, expanding its folder. """
+        chat = next((info for folder, chats in sidebar_sections() for info in chats
+                     if message_handler.unread.get(info["id"])), None)
+        if chat is None:
+            notify(_("No unread messages."))
+            return
+        folder = message_handler.chat_folders.get(chat["id"])
+        if message_handler.folders.get(folder):
+            message_handler.set_folder_collapsed(folder, False)
+        select_chat(chat)
+
     with ui.left_drawer().classes('w-64 bg-zinc-700 text-white p-4') as chat_drawer:
         chat_list_sidebar()
 
     with ui.header().classes('w-full bg-zinc-800 text-white p-4 items-center justify-between'):
         with ui.row().classes('items-center gap-2'):
-            ui.button(icon='menu', color="", on_click=lambda: chat_drawer.toggle())
+            ui.button(icon='menu', color="", on_click=lambda: chat_drawer.toggle()).props(f'aria-label="{_("Chats")}"')
             ui.label('NymCHAT').classes('text-xl font-bold')
-            ui.button("Send Handshake", color="green-6", on_click=lambda: asyncio.create_task(send_handshake())).classes("ml-2")
-        ui.button('Search', color="green-6", on_click=lambda: ui.navigate.to('/search'), icon="search") \
+            ui.button(_("Send Handshake"), color="green-6", on_click=lambda: asyncio.create_task(send_handshake())).classes("ml-2")
+            connection_indicator()
+            ui.timer(HEALTH_INTERVAL, refresh_health)
+            ui.timer(PRESENCE_INTERVAL, message_handler.update_presence)
+            ui.timer(BACKUP_INTERVAL, message_handler.run_backup)
+            ui.timer(DRAFT_AUTOSAVE_INTERVAL, message_handler.save_drafts)
+            ui.timer(SNAPSHOT_INTERVAL, message_handler.save_snapshot)
+        ui.button(_('Search'), color="green-6", on_click=lambda: ui.navigate.to('/search'), icon="search") \
+            .tooltip(f'{_("Search messages")} (Alt+S)') \
             .classes('bg-blue-500 text-white p-2 rounded') \
             .style('margin-left: auto; margin-right: auto;')
-        with ui.element('q-fab').props('square icon=settings color=green-6 direction=left'):
-            ui.element('q-fab-action').props('icon=logout color=green-6 label=LOGOUT') \
-                .on('click', lambda: ui.navigate.to('/'))
-            ui.element('q-fab-action').props('icon=power_settings_new color=green-6 label=SHUTDOWN') \
-                .on('click', lambda: (app.shutdown(), ui.notify("Shutting down the app...")))
+        settings = [
+            ('logout', 'green-6', _("LOGOUT"), logout),
+            ('switch_account', 'green-6', f'{_("SWITCH ACCOUNT")} (Alt+A)', lambda: ui.navigate.to('/switch')),
+            ('group_add', 'green-6', _("NEW GROUP"), new_group_dialog),
+            ('link', 'green-6', _("JOIN GROUP"), join_group_dialog),
+            ('qr_code', 'green-6', _("CONTACT INVITE"), contact_invite_dialog),
+            ('download', 'green-6', _("EXPORT ACCOUNT"), export_account_dialog),
+            ('key', 'green-6', _("PROTECT KEY"), protect_key_dialog),
+            ('policy', 'green-6', _("AUDIT LOG"), lambda: ui.navigate.to('/audit')),
+            ('query_stats', 'green-6', _("NETWORK STATS"), lambda: ui.navigate.to('/stats')),
+            ('receipt_long', 'green-6', _("LOGS"), lambda: ui.navigate.to('/logs')),
+            ('report', 'green-6', _("QUARANTINE"), lambda: ui.navigate.to('/quarantine')),
+            ('cloud_upload', 'green-6', _("BACKUP"), backup_dialog),
+            ('privacy_tip', 'green-6', _("PRIVACY"), privacy_dialog),
+            ('do_not_disturb_on', 'green-6', _("AWAY"), away_dialog),
+            ('folder', 'green-6', _("DOWNLOADS"), downloads_dialog),
+            ('delete_forever', 'red-6', _("DELETE ACCOUNT"), confirm_delete_account),
+            ('power_settings_new', 'green-6', _("SHUTDOWN"),
+             lambda: (app.shutdown(), notify(_("Shutting down the app...")))),
+        ]
+        if ACCESSIBLE_MODE:
+            # Always-visible buttons are easier to reach than the pop-out menu.
+            with ui.row().classes('gap-1'):
+                for icon, color, label, action in settings:
+                    ui.button(label, color=color, icon=icon, on_click=action)
+        else:
+            with ui.element('q-fab').props('square icon=settings color=green-6 direction=left'):
+                for icon, color, label, action in settings:
+                    ui.element('q-fab-action').props(f'icon={icon} color={color} label="{label}"').on('click', action)
 
     message_handler.set_ui_state(messages, chat_list, get_active_chat, render_chat_messages, chat_messages_container, chat_list_sidebar)
     render_chat_messages(user_id, active_chat, messages)
 
     with ui.footer().classes('w-full bg-zinc-800 text-white p-4'):
         with ui.row().classes('w-full items-center'):
-            text_in = ui.input(placeholder='Type a message...') \
+            def typed(e):
+                # Macros are expanded as soon as a space ends them.
+                expanded = quickReplies.expand(e.value, complete=False)
+                if expanded != e.value:
+                    text_in.value = expanded
+                elif active_chat:
+                    message_handler.set_draft(active_chat, e.value)
+                    if e.value:
+                        asyncio.create_task(message_handler.notify_typing(active_chat))
+
+            text_in = ui.input(placeholder=_('Type a message...'), value=message_handler.drafts.get(active_chat, ""),
+                               on_change=typed) \
                 .props('rounded outlined input-class=mx-3') \
                 .classes('flex-grow bg-zinc-700 text-white p-2 rounded-lg') \
                 .on('keydown.enter', lambda: asyncio.create_task(send_message(text_in)))
-            ui.button('Send', color="green-6", icon="send", on_click=lambda: asyncio.create_task(send_message(text_in))) \
+            ui.button(_('Send'), color="green-6", icon="send", on_click=lambda: asyncio.create_task(send_message(text_in))) \
+                .classes('text-white p-2 rounded')
+            ui.button(icon="place", color="green-6", on_click=share_location_dialog) \
+                .props(f'aria-label="{_("Share location")}"').tooltip(_('Share location')) \
                 .classes('text-white p-2 rounded')
+            ui.button(icon="quickreply", color="green-6", on_click=quick_reply_dialog) \
+                .props(f'aria-label="{_("Quick replies")}"').tooltip(f'{_("Quick replies")} (Alt+R)') \
+                .classes('text-white p-2 rounded')
+        status_bar()
+
+@ui.page('/switch')
+def switch_page():
+    current = message_handler.current_user["username"]
+    with ui.column().classes('max-w-2xl mx-auto items-stretch flex-grow gap-1 flex justify-center items-center h-screen w-full'):
+        ui.label(_("Switch Account")).classes("text-2xl text-center font-bold mb-4")
+        if current:
+            ui.label(_("Logged in as {username}", username=current)).classes("text-center text-gray-400 mb-2")
+
+        scan_for_users()
+        others = [u for u in usernames if u != current]
+
+        with ui.row().classes('justify-center w-full'):
+            spin = busy_indicator()
+
+        passphrase_in = ui.input(label=_("Passphrase, for accounts that have one"), password=True) \
+            .props("outlined").classes("mb-2")
+
+        async def do_switch(username):
+            spin.props(remove='hidden')
+            switched = await switch_account(username, passphrase_in.value or None)
+            spin.props('hidden')
+            if switched:
+                notify(_("Switched to {username}.", username=username))
+                ui.navigate.to("/app")
+            else:
+                notify(_("Could not log in as {username}.", username=username), type='negative')
+                ui.navigate.to("/login")
+
+        for username in others:
+            ui.button(username, color="green-6", icon="account_circle",
+                      on_click=lambda _, u=username: do_switch(u)).classes("mb-2")
+        if not others:
+            ui.label(_("No other accounts on this device."))
+
+        ui.button(_("Back"), color="green-6", on_click=lambda: ui.navigate.to("/app" if current else "/welcome"),
+                  icon="arrow_back_ios_new").classes("mb-2")
+
+@ui.page('/audit')
+def audit_page():
+    """ The security audit log of the current user, newest first, and whether its chain is intact. """
+    events = {
+        "key_generated": _("Key generated"),
+        "key_changed": _("Contact key changed"),
+        "key_confirmed": _("Contact key confirmed"),
+        "key_rejected": _("Contact key change rejected"),
+        "contact_verified": _("Contact verified"),
+        "contact_unverified": _("Contact verification removed"),
+        "signature_failed": _("Signature check failed"),
+        "unlock_failed": _("Login failed"),
+        "account_exported": _("Account exported"),
+        "key_protected": _("Key protected with a passphrase"),
+        "key_equivocation": _("Key mismatch reported"),
+        "group_fork": _("Conflicting group membership"),
+        "group_invite": _("Group member invited"),
+        "group_kick": _("Group member removed"),
+        "group_promote": _("Group member made admin"),
+        "invite_rejected": _("Group invite rejected"),
+        "contact_invite": _("Contact added from invite"),
+        "handshake_altered": _("Handshake altered in transit"),
+        "handshake_downgrade": _("Weaker handshake from contact"),
+        "downgrade_accepted": _("Weaker handshake accepted"),
+        "group_history": _("Group history sharing changed"),
+        "conversation_exported": _("Conversation exported"),
+        "diagnostics_exported": _("Diagnostic bundle exported"),
+    }
+    with ui.header().classes('w-full bg-zinc-950 text-white p-4 justify-between'):
+        ui.button(_('Back'), color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
+
+    with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-2 p-4'):
+        ui.label(_("Audit Log")).classes("text-2xl font-bold")
+        entries, tampered = message_handler.audit_log()
+        if tampered is None:
+            ui.label(_("The log is intact.")).classes('text-green-6')
+        else:
+            warning = _("Entry {id} and the ones after it were altered or follow a removed entry.", id=tampered)
+            announce(warning)
+            ui.label(warning).classes('text-red-6 font-bold')
+        if not entries:
+            ui.label(_("No security events recorded yet."))
+            return
+        columns = [
+            {"name": "time", "label": _("Time"), "field": "time", "align": "left"},
+            {"name": "event", "label": _("Event"), "field": "event", "align": "left"},
+            {"name": "detail", "label": _("Details"), "field": "detail", "align": "left"},
+        ]
+        rows = [
+            {"id": entry_id, "time": timeUtils.format_stamp(stamp), "event": events.get(event, event),
+             "detail": detail}
+            for entry_id, event, detail, stamp in reversed(entries)
+        ]
+        ui.table(columns=columns, rows=rows, row_key="id").classes('w-full')
+
+@ui.refreshable
+def quarantine_list():
+    """ The quarantined messages, newest first, each with what can be done about it. """
+    reasons = {
+        "unparseable envelope": _("Could not be parsed"),
+        "malformed message": _("Malformed message"),
+        "sealed message could not be opened": _("Sealed message could not be opened"),
+        "no sender key": _("Sender key unknown"),
+        "delegation refused": _("Delegated key refused"),
+        "invalid signature": _("Invalid signature"),
+        "decryption failed": _("Decryption failed"),
+        "malformed inner message": _("Malformed message content"),
+    }
+    entries = message_handler.quarantined()
+    if not entries:
+        ui.label(_("No messages in quarantine."))
+        return
+
+    async def retry(quarantine_id):
+        await message_handler.retry_quarantined(quarantine_id)
+        quarantine_list.refresh()
+
+    def delete(quarantine_id):
+        message_handler.delete_quarantined(quarantine_id)
+        quarantine_list.refresh()
+
+    for quarantine_id, sender, reason, content, received_at in entries:
+        with ui.card().classes('w-full bg-zinc-800'):
+            with ui.row().classes('w-full items-center gap-2'):
+                ui.label(reasons.get(reason, reason)).classes('font-bold text-amber-400')
+                ui.label(sender or _("Unknown sender"))
+                ui.label(timeUtils.format_stamp(received_at)).classes('text-sm text-gray-400')
+                ui.button(_('Retry'), color="green-6", icon="replay",
+                          on_click=lambda q=quarantine_id: asyncio.create_task(retry(q))).props('flat dense')
+                ui.button(_('Delete'), color="red-6", icon="delete",
+                          on_click=lambda q=quarantine_id: delete(q)).props('flat dense')
+            with ui.expansion(_("Content")).classes('w-full'):
+                ui.label(content).classes('font-mono text-xs').style('white-space: pre-wrap; word-break: break-all')
+
+@ui.page('/quarantine')
+def quarantine_page():
+    """ Messages that failed to parse, decrypt or verify, to inspect, delete or retry. """
+    with ui.header().classes('w-full bg-zinc-950 text-white p-4 justify-between'):
+        ui.button(_('Back'), color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
+
+    with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-2 p-4'):
+        ui.label(_("Quarantine")).classes("text-2xl font-bold")
+        ui.label(_("Messages that could not be read or verified. Retry one once the cause is fixed, e.g. "
+                   "when the sender's key is known.")) \
+            .classes('text-sm text-gray-400')
+        ui.button(_('Clear all'), color="green-6", icon="delete_sweep",
+                  on_click=lambda: (message_handler.clear_quarantine(), quarantine_list.refresh()))
+        quarantine_list()
+
+@ui.refreshable
+def network_stats_table(stats):
+    """ The connection's traffic figures, as gathered by MessageHandler.network_stats. """
+    if stats is None:
+        ui.label(_("Loading…"))
+        return
+
+    def count(key):
+        return f"{stats[key]:,}"
+
+    uptime = stats["uptime"]
+    latency = stats["latency"]
+    rows = [
+        (_("Gateway"), stats["gateway"] or _("Disconnected")),
+        (_("Connected for"), _("Disconnected") if uptime is None else timeUtils.format_duration(uptime)),
+        (_("Average latency"), _("No answer yet") if latency is None else f"{latency * 1000:.0f} ms"),
+        (_("Messages sent / received"), f"{count('messages_sent')} / {count('messages_received')}"),
+        (_("Packets sent / received (estimated)"), f"{count('packets_sent')} / {count('packets_received')}"),
+        (_("Retried sends"), count("send_retries")),
+        (_("Failed sends"), count("send_failures")),
+        (_("Reconnects"), count("reconnects")),
+        (_("Reply SURBs sent"), count("surbs_sent")),
+        (_("Reply SURBs left (estimated)"), count("surbs_remaining")),
+    ]
+    columns = [
+        {"name": "name", "label": _("Statistic"), "field": "name", "align": "left"},
+        {"name": "value", "label": _("Value"), "field": "value", "align": "left"},
+    ]
+    ui.table(columns=columns, rows=[{"name": name, "value": value} for name, value in rows],
+             row_key="name").classes('w-full').props('hide-bottom')
+
+@ui.page('/stats')
+def stats_page():
+    """ Live traffic statistics of the mixnet connection. """
+    with ui.header().classes('w-full bg-zinc-950 text-white p-4 justify-between'):
+        ui.button(_('Back'), color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
+
+    with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-2 p-4'):
+        ui.label(_("Network Statistics")).classes("text-2xl font-bold")
+        network_stats_table(None)
+        ui.label(_("Cover traffic and retransmissions happen inside the Nym client and are not counted here.")) \
+            .classes('text-sm text-gray-400')
+
+    async def refresh():
+        try:
+            stats = await message_handler.network_stats()
+        except Exception as e:
+            logger.warning(f"Network statistics unavailable: {e}")
+            return
+        network_stats_table.refresh(stats)
+
+    ui.timer(0, refresh, once=True)
+    ui.timer(STATS_INTERVAL, refresh)
+
+LOG_LEVELS = {
+    logging.DEBUG: "DEBUG",
+    logging.INFO: "INFO",
+    logging.WARNING: "WARNING",
+    logging.ERROR: "ERROR",
+}
+
+@ui.refreshable
+def log_table(level):
+    """ The records kept in memory at `level` or above, newest first. """
+    entries = log_buffer.entries(level)
+    if not entries:
+        ui.label(_("No log records at this level."))
+        return
+    columns = [
+        {"name": "time", "label": _("Time"), "field": "time", "align": "left"},
+        {"name": "level", "label": _("Level"), "field": "level", "align": "left"},
+        {"name": "target", "label": _("Source"), "field": "target", "align": "left"},
+        {"name": "message", "label": _("Message"), "field": "message", "align": "left",
+         "style": "white-space: pre-wrap"},
+    ]
+    rows = [
+        {"id": index, "time": timeUtils.format_unix(entry.timestamp), "level": entry.level_name,
+         "target": entry.target, "message": entry.message}
+        for index, entry in reversed(list(enumerate(entries)))
+    ]
+    ui.table(columns=columns, rows=rows, row_key="id").classes('w-full').props('dense')
+
+@ui.page('/logs')
+def logs_page():
+    """ The client's recent log records, filtered by level. """
+    with ui.header().classes('w-full bg-zinc-950 text-white p-4 justify-between'):
+        ui.button(_('Back'), color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
+
+    with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-2 p-4'):
+        ui.label(_("Logs")).classes("text-2xl font-bold")
+        ui.label(_("The last {count} records; the log file in storage keeps the rest.", count=log_buffer.capacity)) \
+            .classes('text-sm text-gray-400')
+        with ui.row().classes('items-center gap-2'):
+            level = ui.toggle(LOG_LEVELS, value=logging.INFO, on_change=lambda e: log_table.refresh(e.value))
+            ui.button(_('Clear'), color="green-6", icon="delete_sweep",
+                      on_click=lambda: (log_buffer.clear(), log_table.refresh(level.value)))
+            ui.button(_('Diagnostic bundle'), color="green-6", icon="bug_report", on_click=save_diagnostics) \
+                .tooltip(_("Logs, connection statistics and database checks for a bug report, with secrets removed"))
+        log_table(level.value)
+
+    ui.timer(LOG_VIEW_INTERVAL, lambda: log_table.refresh(level.value))
+
+def render_snippet(snippet):
+    """ A search snippet as inline text, with the matched words in bold """
+    with ui.element('div'):
+        for index, part in enumerate(re.split('[\x02\x03]', snippet)):
+            if part:
+                # Parts alternate: text between matches, then a match.
+                ui.label(part).style('display: inline; white-space: pre-wrap') \
+                    .classes('font-bold text-amber-400' if index % 2 else '')
 
 @ui.page('/search')
-def search_page():
+def search_page(scope: str = "users"):
+    """ Looks up users, or with `scope` "messages" (Alt+S in the chat) searches all local messages. """
     with ui.header().classes('w-full bg-zinc-950 text-white p-4 justify-between'):
-        ui.button('Back', color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
+        ui.button(_('Back'), color="green-6", icon="arrow_back_ios_new", on_click=lambda: ui.navigate.to('/app')).classes('text-white p-2 rounded')
     
+    # Users looks a username up in the server's directory; Messages searches every local conversation.
+    placeholders = {"users": _('Enter a username: *CASE SENSITIVE*'), "messages": _('Words in your messages')}
+    scope = scope if scope in placeholders else "users"
+
+    def set_mode(e):
+        search_in.props(f'placeholder="{placeholders[e.value]}"')
+        profile_container.clear()
+
     with ui.column().classes('w-full max-w-6xl mx-auto items-stretch flex-grow gap-1 w-full items-start p-4'):
+        mode = ui.toggle({"users": _("Users"), "messages": _("Messages")}, value=scope, on_change=set_mode)
         with ui.row().classes('gap-2 bg-zinc-800 p-4 rounded-lg shadow-lg w-full items-center justify-center'):
-            search_in = ui.input(placeholder='Enter a username: *CASE SENSITIVE*') \
-                .props('rounded outlined input-class=mx-3') \
+            search_in = ui.input(placeholder=placeholders[scope]) \
+                .props('rounded outlined autofocus input-class=mx-3') \
                 .classes('flex-grow bg-zinc-700 text-white p-2 rounded-lg') \
                 .on('keydown.enter', lambda: asyncio.create_task(do_search()))
-            ui.button('Search', color="green-6", icon="search", on_click=lambda: asyncio.create_task(do_search())).classes('text-white p-2 rounded')
+            ui.button(_('Search'), color="green-6", icon="search", on_click=lambda: asyncio.create_task(do_search())).classes('text-white p-2 rounded')
         
         global profile_container
-        profile_container = ui.column().classes('mt-4')
-        
+        profile_container = ui.column().classes('mt-4 w-full')
+
+        def show_message_hits(query):
+            profile_container.clear()
+            results = message_handler.search_messages(query)
+            names = {chat["id"]: chat["name"] for chat in chat_list}
+            with profile_container:
+                if not results:
+                    ui.label(_("No messages match {query}.", query=query.strip()))
+                    return
+                for chat_id, hits in results:
+                    name = names.get(chat_id, chat_id)
+                    icon = 'group' if chat_id.startswith(GROUP_CHAT_PREFIX) else 'person'
+                    with ui.expansion(_("{name} ({count})", name=name, count=len(hits)), icon=icon, value=True) \
+                            .classes('w-full bg-zinc-800 rounded-lg'):
+                        for hit in hits:
+                            with ui.row().classes('w-full p-2 gap-2 items-baseline hover:bg-gray-800 cursor-pointer') \
+                                    .on('click', lambda e, c=chat_id, n=name, h=hit: open_search_hit(c, n, h)):
+                                ui.label(timeUtils.format_stamp(hit["timestamp"])).classes('text-gray-400 text-sm')
+                                ui.label(hit["sender"]).classes('font-bold')
+                                render_snippet(hit["snippet"])
+
         async def do_search():
+            if mode.value == "messages":
+                show_message_hits(search_in.value or "")
+                return
             username = search_in.value.strip()
             with profile_container:
                 profile_container.clear()
                 if not username:
-                    ui.notify("Enter a username to search.")
+                    notify(_("Enter a username to search."))
                     return
-                ui.notify(f"Searching for '{username}'...")
+                notify(_("Searching for '{username}'...", username=username))
             result = await message_handler.query_user(username)
             with profile_container:
                 if result is None:
-                    ui.notify("Error or no response from server.")
+                    notify(_("Error or no response from server."))
                     return
                 if isinstance(result, str):
-                    ui.notify(result)
+                    notify(result)
                 elif isinstance(result, dict):
                     user_data = result
                     with ui.card().classes('p-4 bg-zinc-700 text-white rounded-lg shadow-lg w-80'):
-                        ui.label(f"Username: {user_data.get('username') or 'N/A'}").classes('text-xl font-bold')
+                        ui.label(_("Username: {username}", username=user_data.get('username') or 'N/A')).classes('text-xl font-bold')
                         partial_key = (user_data.get('publicKey') or '')[:50]
-                        ui.label(f"Public Key (partial): {partial_key}...")
+                        ui.label(_("Public Key (partial): {key}...", key=partial_key))
                         def start_chat():
                             new_chat = {"id": user_data["username"], "name": user_data["username"]}
                             if new_chat not in chat_list:
                                 chat_list.append(new_chat)
                             ui.navigate.to('/app')
-                        ui.button('Start Chat', color='green-6', icon="chat", on_click=start_chat).classes('text-white p-2 mt-2 rounded')
+                        ui.button(_('Start Chat'), color='green-6', icon="chat", on_click=start_chat).classes('text-white p-2 mt-2 rounded')
                 else:
-                    ui.notify("Unexpected response format from server.")
+                    notify(_("Unexpected response format from server."))
 
 ###############################################################################
 # APP STARTUP
@@ -402,12 +2376,30 @@ async def startup_sequence():
         chat_messages_container # container (if needed)
     )
 
+async def logout():
+    await message_handler.logout()
+    ui.navigate.to('/')
+
+async def delete_account():
+    if not await message_handler.delete_account():
+        notify(_("Account deletion failed; local data was kept."), type='negative')
+        return
+    clear_session()
+    notify(_("Account deleted."))
+    ui.navigate.to('/welcome')
+
+async def logout_and_shutdown():
+    await message_handler.logout()
+    await connection_client.shutdown()
+
 def shutdown_client():
     # Create a new event loop in this thread and run the shutdown coroutine
-    asyncio.run(connection_client.shutdown())
+    asyncio.run(logout_and_shutdown())
 
 @app.on_shutdown
 def on_shutdown():
+    message_handler.flush_writes()
+    message_handler.save_drafts()
     if connection_client.client is not None:
         logger.info("Shutting down Mixnet client...")
         t = threading.Thread(target=shutdown_client)
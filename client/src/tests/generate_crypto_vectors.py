@@ -0,0 +1,87 @@
+"""
+Writes the crypto test vectors shared by the Python client and the Rust
+`nymchat_protocol` crate (client/nymchat_protocol/tests/vectors/crypto.json).
+
+Keys are derived from fixed scalars and signatures use RFC 6979 deterministic
+ECDSA, as the Rust side does, so both implementations must reproduce them
+byte for byte. Payloads are encrypted with `CryptoUtils.encrypt_message`,
+so their ephemeral keys, salts and IVs change on every run; only regenerate
+when the format changes on purpose. Needs cryptography >= 44.
+
+Run from client/src:
+    python -m tests.generate_crypto_vectors
+"""
+import json
+import os
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptographyUtils import CryptoUtils
+
+VECTORS_PATH = os.path.normpath(os.path.join(
+    os.path.dirname(os.path.abspath(__file__)), "..", "..", "nymchat_protocol", "tests", "vectors", "crypto.json"
+))
+
+RECIPIENT_SCALAR = int.from_bytes(b"nymCHAT test vector recipient", "big")
+SENDER_SCALAR = int.from_bytes(b"nymCHAT test vector sender", "big")
+
+PLAINTEXTS = [
+    "",
+    "hello",
+    json.dumps({"type": 0, "message": "héllo wörld 😀"}),
+    json.dumps({"type": 0, "message": "x" * 1000}),
+]
+
+SIGNED_MESSAGES = ["nonce", "b7e1c3a9f04d2e68", "héllo 😀"]
+
+
+def key_entry(private_key):
+    return {
+        "privateKey": private_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        ).decode(),
+        "publicKey": private_key.public_key().public_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PublicFormat.SubjectPublicKeyInfo,
+        ).decode(),
+    }
+
+
+def deterministic_sign(private_key, message):
+    return private_key.sign(message.encode(), ec.ECDSA(hashes.SHA256(), deterministic_signing=True)).hex()
+
+
+def main():
+    crypto = CryptoUtils.__new__(CryptoUtils)
+    recipient = ec.derive_private_key(RECIPIENT_SCALAR, ec.SECP256R1())
+    sender = ec.derive_private_key(SENDER_SCALAR, ec.SECP256R1())
+    recipient_keys = key_entry(recipient)
+
+    encryptions = []
+    for plaintext in PLAINTEXTS:
+        payload = crypto.encrypt_message(recipient_keys["publicKey"], plaintext)
+        signed = json.dumps(payload)
+        encryptions.append({
+            "plaintext": plaintext,
+            "payload": payload,
+            "signedPayload": signed,
+            "payloadSignature": deterministic_sign(sender, signed),
+        })
+
+    vectors = {
+        "recipient": recipient_keys,
+        "sender": key_entry(sender),
+        "signatures": [
+            {"message": message, "signature": deterministic_sign(sender, message)} for message in SIGNED_MESSAGES
+        ],
+        "encryptions": encryptions,
+    }
+    with open(VECTORS_PATH, "w") as f:
+        json.dump(vectors, f, indent=2)
+        f.write("\n")
+    print(f"Wrote {VECTORS_PATH}")
+
+
+if __name__ == "__main__":
+    main()
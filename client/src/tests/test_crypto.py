@@ -1,6 +1,8 @@
+import asyncio
 import unittest
 import os
 import json
+import threading
 from cryptography.hazmat.primitives import serialization
 from cryptographyUtils import CryptoUtils
 
@@ -56,6 +58,56 @@ class TestCryptoUtils(unittest.TestCase):
         public_key = self.crypto.load_public_key(self.username)  # Load test_user's public key
         self.assertFalse(self.crypto.verify_signature(public_key, message, invalid_signature))
 
+    def test_passphrase_encryption(self):
+        sealed = self.crypto.encrypt_with_passphrase("correct horse", "account bundle")
+        self.assertEqual(self.crypto.decrypt_with_passphrase("correct horse", sealed), "account bundle")
+        with self.assertRaises(ValueError):
+            self.crypto.decrypt_with_passphrase("wrong horse", sealed)
+
+    def test_keystore(self):
+        def der(key):
+            return key.private_bytes(serialization.Encoding.DER, serialization.PrivateFormat.PKCS8,
+                                     serialization.NoEncryption())
+
+        # An existing plain key moves into the keystore, and stays usable until locked.
+        self.crypto.protect_keys(self.username, "correct horse")
+        self.assertFalse(os.path.exists(f"test_storage/{self.username}/{self.username}_private_key.pem"))
+        self.assertTrue(self.crypto.has_keystore(self.username))
+        self.assertEqual(der(self.crypto.load_private_key(self.username)), der(self.private_key))
+        self.crypto.lock(self.username)
+        self.assertIsNone(self.crypto.load_private_key(self.username))
+
+        # Only the right passphrase opens it.
+        self.assertFalse(self.crypto.unlock(self.username, "wrong horse"))
+        self.assertTrue(CryptoUtils(storage_dir="test_storage").unlock(self.username, "correct horse"))
+        self.assertTrue(self.crypto.unlock(self.username, "correct horse"))
+        self.assertEqual(der(self.crypto.load_private_key(self.username)), der(self.private_key))
+
+        # A new account can start with one.
+        private_key, public_key_pem = self.crypto.generate_key_pair("new_user")
+        self.crypto.save_keys("new_user", private_key, public_key_pem, "battery staple")
+        self.assertFalse(os.path.exists("test_storage/new_user/new_user_private_key.pem"))
+        self.crypto.lock()
+        self.assertTrue(self.crypto.unlock("new_user", "battery staple"))
+
+    def test_backup_chunks_are_bound_to_their_index(self):
+        keys = self.crypto.backup_keys(b"\x01" * 64)
+        self.assertEqual(keys, self.crypto.backup_keys(b"\x01" * 64))
+        self.assertNotEqual(keys["id"], keys["token"])
+        sealed = self.crypto.seal_chunk(keys["key"], keys["id"], 3, b"chunk")
+        self.assertEqual(self.crypto.open_chunk(keys["key"], keys["id"], 3, sealed), b"chunk")
+        with self.assertRaises(ValueError):
+            self.crypto.open_chunk(keys["key"], keys["id"], 4, sealed)
+
+    def test_wipe_user_data(self):
+        user_dir = os.path.join("test_storage", self.username)
+        os.makedirs(os.path.join(user_dir, "attachments"))
+        with open(os.path.join(user_dir, "attachments", "blob"), "wb") as f:
+            f.write(b"secret")
+        self.crypto.wipe_user_data(self.username)
+        self.assertFalse(os.path.exists(user_dir))
+        self.assertTrue(os.path.exists(os.path.join("test_storage", self.recipient)))
+
     def test_encrypt_and_decrypt_message(self):
         recipient_public_key_pem = self.crypto.load_public_key(self.recipient).public_bytes(
             encoding=serialization.Encoding.PEM,
@@ -84,5 +136,80 @@ class TestCryptoUtils(unittest.TestCase):
         decrypted_message = self.crypto.decrypt_message(self.recipient_private_key, encrypted_message) 
         self.assertEqual(decrypted_message, "Secret Message")
 
+    def test_async_facade_runs_on_the_worker_pool(self):
+        async def roundtrip():
+            loop_thread = threading.current_thread()
+            self.assertIsNot(await self.crypto.run(threading.current_thread), loop_thread)
+            encrypted = await self.crypto.encrypt_message_async(self.recipient_public_key_pem, "Secret Message")
+            signature = await self.crypto.sign_message_async(self.private_key, json.dumps(encrypted))
+            public_key = self.private_key.public_key()
+            self.assertTrue(await self.crypto.verify_signature_async(public_key, json.dumps(encrypted), signature))
+            return await self.crypto.decrypt_message_async(self.recipient_private_key, encrypted)
+
+        self.assertEqual(asyncio.run(roundtrip()), "Secret Message")
+
+
+VECTORS_PATH = os.path.join(
+    os.path.dirname(os.path.abspath(__file__)), "..", "..", "nymchat_protocol", "tests", "vectors", "crypto.json"
+)
+
+
+class TestCryptoVectors(unittest.TestCase):
+    """Vectors shared with the Rust `nymchat_protocol` crate, which checks them as well."""
+
+    def setUp(self):
+        self.crypto = CryptoUtils.__new__(CryptoUtils)
+        with open(VECTORS_PATH) as f:
+            self.vectors = json.load(f)
+        self.recipient = serialization.load_pem_private_key(
+            self.vectors["recipient"]["privateKey"].encode(), password=None
+        )
+        self.sender_public_key = serialization.load_pem_public_key(self.vectors["sender"]["publicKey"].encode())
+
+    def test_keys_reencode_identically(self):
+        for party in ("recipient", "sender"):
+            keys = self.vectors[party]
+            private_key = serialization.load_pem_private_key(keys["privateKey"].encode(), password=None)
+            private_pem = private_key.private_bytes(
+                encoding=serialization.Encoding.PEM,
+                format=serialization.PrivateFormat.PKCS8,
+                encryption_algorithm=serialization.NoEncryption(),
+            ).decode()
+            public_pem = private_key.public_key().public_bytes(
+                encoding=serialization.Encoding.PEM,
+                format=serialization.PublicFormat.SubjectPublicKeyInfo,
+            ).decode()
+            self.assertEqual(private_pem, keys["privateKey"])
+            self.assertEqual(public_pem, keys["publicKey"])
+
+    def test_signatures_verify(self):
+        for vector in self.vectors["signatures"]:
+            self.assertTrue(
+                self.crypto.verify_signature(self.sender_public_key, vector["message"], vector["signature"])
+            )
+
+    def test_payloads_decrypt_and_verify(self):
+        for vector in self.vectors["encryptions"]:
+            signed = json.dumps(vector["payload"])
+            self.assertEqual(signed, vector["signedPayload"])
+            self.assertTrue(self.crypto.verify_signature(self.sender_public_key, signed, vector["payloadSignature"]))
+            self.assertEqual(self.crypto.decrypt_message(self.recipient, vector["payload"]), vector["plaintext"])
+
+    def test_encrypted_payloads_match_the_vector_layout(self):
+        recipient_public_pem = self.vectors["recipient"]["publicKey"]
+        for vector in self.vectors["encryptions"]:
+            expected = vector["payload"]
+            payload = self.crypto.encrypt_message(recipient_public_pem, vector["plaintext"])
+            self.assertEqual(len(payload["salt"]), len(expected["salt"]))
+            for field in ("iv", "ciphertext", "tag"):
+                self.assertEqual(len(payload["encryptedBody"][field]), len(expected["encryptedBody"][field]))
+            self.assertEqual(len(payload["ephemeralPublicKey"]), len(expected["ephemeralPublicKey"]))
+
+    def test_safety_numbers_match_the_rust_crate(self):
+        sender = self.crypto.safety_number(self.vectors["sender"]["publicKey"])
+        self.assertEqual(sender, "18363 33796 21594 85783 68308 49598")
+        self.assertNotEqual(self.crypto.safety_number(self.vectors["recipient"]["publicKey"]), sender)
+
+
 if __name__ == "__main__":
     unittest.main()
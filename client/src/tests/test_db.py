@@ -1,12 +1,18 @@
 import unittest
 import os
-from dbUtils import SQLiteManager  # Assuming the class is in a file named sqlite_manager.py
+import json
+import sqlite3
+from datetime import date
+from async_ffi import PyDb
+import diagnostics
+import exportUtils
+import timeUtils
 
-class TestSQLiteManager(unittest.TestCase):
+class TestPyDb(unittest.TestCase):
     def setUp(self):
         self.username = "testuser"
         self.storage_dir = "test_storage"
-        self.db_manager = SQLiteManager(self.username, self.storage_dir)
+        self.db_manager = PyDb.open(self.username, self.storage_dir)
         
         # Populate the database with test data
         self.db_manager.register_user(self.username, "public_key_testuser")
@@ -18,9 +24,10 @@ class TestSQLiteManager(unittest.TestCase):
 
     def tearDown(self):
         self.db_manager.close()
-        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
-        if os.path.exists(db_path):
-            os.remove(db_path)
+        for name in (f"{self.username}_client.db", f"{self.username}_audit.key"):
+            path = os.path.join(self.storage_dir, self.username, name)
+            if os.path.exists(path):
+                os.remove(path)
         os.rmdir(os.path.join(self.storage_dir, self.username))
         os.rmdir(self.storage_dir)
 
@@ -51,7 +58,7 @@ class TestSQLiteManager(unittest.TestCase):
 
     def test_save_message(self):
         self.db_manager.save_message(self.username, "dave", "to", "Hey Dave!")
-        messages = self.db_manager.get_messages_by_contact(self.username, "dave")
+        messages = self.db_manager.load_messages(self.username, "dave")
         self.assertEqual(len(messages), 1)
         self.assertEqual(messages[0][1], "Hey Dave!")
 
@@ -59,6 +66,10 @@ class TestSQLiteManager(unittest.TestCase):
         messages = self.db_manager.get_all_messages(self.username)
         self.assertGreater(len(messages), 1)
 
+    def test_import_messages_keeps_timestamps(self):
+        self.db_manager.import_messages(self.username, [("carol", "from", "Hi!", "2024-01-02 03:04:05")])
+        self.assertEqual(self.db_manager.load_messages(self.username, "carol"), [("from", "Hi!", "2024-01-02 03:04:05")])
+
     def test_delete_contact(self):
         self.db_manager.delete_contact(self.username, "alice")
         contact = self.db_manager.get_contact(self.username, "alice")
@@ -69,9 +80,253 @@ class TestSQLiteManager(unittest.TestCase):
         messages = self.db_manager.get_all_messages(self.username)
         self.assertEqual(len(messages), 0)
 
+    def test_presence(self):
+        self.db_manager.set_share_presence(self.username, "alice", True)
+        self.db_manager.set_last_seen(self.username, "bob", 1700000100)
+        # Re-adding a contact keeps its presence settings.
+        self.db_manager.add_contact(self.username, "alice", "public_key_alice2")
+        presence = sorted(self.db_manager.get_presence(self.username))
+        self.assertEqual(presence, [("alice", True, None), ("bob", False, 1700000100)])
+
+    def test_sender_tags(self):
+        self.db_manager.set_sender_tag(self.username, "alice", "tag1", "addr1")
+        self.db_manager.set_sender_tag(self.username, "bob", "tag2", "addr1")
+        self.db_manager.set_sender_tag(self.username, "alice", "tag3", "addr2")
+        self.db_manager.delete_sender_tag(self.username, "bob")
+        self.assertEqual(self.db_manager.get_sender_tags(self.username, "addr1"), [])
+        self.assertEqual(self.db_manager.get_sender_tags(self.username, "addr2"), [("alice", "tag3")])
+
+    def test_outbox(self):
+        first = self.db_manager.queue_outbox(self.username, "alice", "one")
+        second = self.db_manager.queue_outbox(self.username, "group:g1", "two")
+        self.db_manager.queue_outbox(self.username, "alice", "three")
+        self.db_manager.delete_outbox(self.username, first)
+        self.assertEqual([row[1:] for row in self.db_manager.get_outbox(self.username)],
+                         [("group:g1", "two"), ("alice", "three")])
+        self.assertEqual(self.db_manager.get_outbox(self.username)[0][0], second)
+
+    def test_privacy_settings(self):
+        self.assertIsNone(self.db_manager.get_setting(self.username, "send_receipts"))
+        self.db_manager.set_setting(self.username, "send_receipts", "1")
+        self.db_manager.set_setting(self.username, "send_receipts", "0")
+        self.assertEqual(self.db_manager.get_setting(self.username, "send_receipts"), "0")
+
+        self.assertEqual(self.db_manager.get_contact_privacy(self.username), [])
+        self.db_manager.set_contact_privacy(self.username, "alice", False, None)
+        self.assertEqual(self.db_manager.get_contact_privacy(self.username), [("alice", False, None)])
+        self.db_manager.set_contact_privacy(self.username, "alice", None, None)
+        self.assertEqual(self.db_manager.get_contact_privacy(self.username), [])
+
+    def test_stored_message_format(self):
+        self.db_manager.save_group_message(self.username, "g1", "alice", "Hi group")
+
+        # Rows written before the format existed are plain text, whatever they look like.
+        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
+        conn = sqlite3.connect(db_path)
+        for prefix in ("messages", "group_messages"):
+            conn.execute(f'UPDATE "{prefix}_{self.username}" SET message = json_extract(message, \'$.body\')')
+        legacy = '{"v": 1, "kind": "text", "body": "not what was typed"}'
+        conn.execute(f'INSERT INTO "messages_{self.username}" (username, type, message) VALUES (?, ?, ?)',
+                     ("bob", "to", legacy))
+        conn.execute(f'DELETE FROM "settings_{self.username}" WHERE key = \'message_format\'')
+        conn.commit()
+
+        self.assertEqual(self.db_manager.migrate_messages(self.username), 4)
+        self.assertEqual(self.db_manager.migrate_messages(self.username), 0)
+        bob = self.db_manager.load_messages(self.username, "bob")
+        self.assertEqual([row[1] for row in bob], ["Hello Bob!", legacy])
+        self.assertEqual(self.db_manager.load_group_messages(self.username, "g1")[0][1], "Hi group")
+
+        self.db_manager.save_message(self.username, "carol", "from", "📍 52.52, 13.405", kind="location")
+        [(stored,)] = conn.execute(f'SELECT message FROM "messages_{self.username}" WHERE username = \'carol\'')
+        conn.close()
+        self.assertEqual(json.loads(stored), {"v": 1, "kind": "location", "body": "📍 52.52, 13.405"})
+
+    def test_export_conversation(self):
+        timeUtils.configure("Europe/Berlin", "24h")
+        self.addCleanup(timeUtils.configure, "UTC", "24h")
+        self.db_manager.save_message(self.username, "alice", "from", "Line one\nline two")
+        self.db_manager.save_message(self.username, "alice", "to", "Out of range")
+        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
+        conn = sqlite3.connect(db_path)
+        table = f'"messages_{self.username}"'
+        for (row_id,), stamp in zip(conn.execute(f"SELECT id FROM {table} WHERE username = 'alice' ORDER BY id"),
+                                    ("2024-06-30 21:59:59", "2024-07-01 08:00:00", "2024-07-02 22:00:00")):
+            conn.execute(f"UPDATE {table} SET timestamp = ? WHERE id = ?", (stamp, row_id))
+        photo = {"v": 1, "kind": "text", "body": "See this",
+                 "attachments": [{"name": "photo.jpg", "mime": "image/jpeg", "size": 1024}]}
+        conn.execute(f"INSERT INTO {table} (username, type, message, timestamp) VALUES (?, ?, ?, ?)",
+                     ("alice", "to", json.dumps(photo), "2024-07-01 09:30:00"))
+        conn.commit()
+        conn.close()
+
+        # July 1st in Berlin is 22:00 UTC on June 30th to 21:59:59 on July 1st.
+        rows = self.db_manager.export_messages(self.username, "alice", "2024-06-30 22:00:00", "2024-07-01 21:59:59")
+        self.assertEqual([row[2] for row in rows], ["Line one\nline two", "See this"])
+        self.assertEqual(exportUtils.stamp_range(date(2024, 7, 1), date(2024, 7, 1)),
+                         ("2024-06-30 22:00:00", "2024-07-01 21:59:59"))
+
+        markdown = exportUtils.export_conversation(self.db_manager, self.username, "alice", "md",
+                                                   date(2024, 7, 1), date(2024, 7, 1))
+        self.assertEqual(markdown, "# Chat with alice\nFrom 2024-07-01 to 2024-07-01\n\n"
+                                   "**alice** · 2024-07-01 10:00  \nLine one  \nline two  \n\n"
+                                   "**testuser** · 2024-07-01 11:30  \nSee this  \n📎 Attachment: `photo.jpg`\n")
+        text = exportUtils.export_conversation(self.db_manager, self.username, "alice", "txt", since=date(2024, 7, 2))
+        self.assertEqual(text, "Chat with alice\nFrom 2024-07-02 to …\n\n[2024-07-03 00:00] testuser: Out of range\n")
+
+        self.db_manager.save_group_message(self.username, "g1", "bob", "Hi group")
+        text = exportUtils.export_conversation(self.db_manager, self.username, "group:g1", "txt", title="G")
+        self.assertRegex(text, r"^G\n\n\[\d{4}-\d\d-\d\d \d\d:\d\d\] bob: Hi group\n$")
+        with self.assertRaises(ValueError):
+            exportUtils.stamp_range(date(2024, 7, 2), date(2024, 7, 1))
+
+    def test_delete_local_messages(self):
+        for _ in range(2):
+            self.db_manager.save_message(self.username, "alice", "from", "Same text")
+        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
+        conn = sqlite3.connect(db_path)
+        table = f'"messages_{self.username}"'
+        for (row_id,), stamp in zip(conn.execute(f"SELECT id FROM {table} WHERE message LIKE '%Same text%' "
+                                                 "ORDER BY id"), ("2024-07-01 08:00:00", "2024-07-01 09:00:00")):
+            conn.execute(f"UPDATE {table} SET timestamp = ? WHERE id = ?", (stamp, row_id))
+        conn.commit()
+        conn.close()
+
+        # The shown time can be a moment off the stored one; the closest copy goes.
+        self.assertFalse(self.db_manager.delete_message(self.username, "alice", "to", "Same text",
+                                                        "2024-07-01 09:00:01"))
+        self.assertTrue(self.db_manager.delete_message(self.username, "alice", "from", "Same text",
+                                                       "2024-07-01 09:00:01"))
+        rows = self.db_manager.load_messages_before(self.username, "alice", None, 10)
+        self.assertEqual([row[3] for row in rows if row[2] == "Same text"], ["2024-07-01 08:00:00"])
+        self.assertEqual(self.db_manager.search_messages(self.username, "same", 10)[0][4], "2024-07-01 08:00:00")
+
+        self.db_manager.save_group_message(self.username, "g1", "bob", "Hi group")
+        stamp = self.db_manager.export_group_messages(self.username, "g1", None, None)[0][4]
+        self.assertFalse(self.db_manager.delete_group_message(self.username, "g1", "carol", "Hi group", stamp))
+        self.assertTrue(self.db_manager.delete_group_message(self.username, "g1", "bob", "Hi group", stamp))
+        self.assertEqual(self.db_manager.export_group_messages(self.username, "g1", None, None), [])
+
+    def test_search_messages(self):
+        self.db_manager.save_message(self.username, "alice", "from", "The café opens at nine")
+        self.db_manager.save_group_message(self.username, "g1", "bob", "Cafe meeting, bring notes")
+
+        # Whole words or their start, ignoring case and accents, in direct and group chats alike.
+        hits = self.db_manager.search_messages(self.username, "CAF")
+        self.assertEqual(sorted((chat, sender) for chat, _id, sender, _snippet, _stamp in hits),
+                         [("alice", "alice"), ("group:g1", "bob")])
+        self.assertIn("\x02café\x03", next(hit[3] for hit in hits if hit[0] == "alice"))
+        self.assertEqual([hit[0] for hit in self.db_manager.search_messages(self.username, "hello alice")], ["alice"])
+        self.assertEqual(self.db_manager.search_messages(self.username, "hello carol"), [])
+
+        # Search syntax is taken as text, and the stored format is not searched.
+        self.assertEqual(self.db_manager.search_messages(self.username, 'notes" OR "x'), [])
+        self.assertEqual(self.db_manager.search_messages(self.username, "kind"), [])
+        self.assertEqual(self.db_manager.search_messages(self.username, "   "), [])
+
+        # The index follows deletions, and is built for messages stored before it existed.
+        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
+        conn = sqlite3.connect(db_path)
+        conn.execute(f'DROP TABLE "message_index_{self.username}"')
+        conn.commit()
+        conn.close()
+        self.db_manager.create_user_tables(self.username)
+        self.assertEqual(len(self.db_manager.search_messages(self.username, "hello")), 2)
+        self.db_manager.delete_all_messages(self.username)
+        self.assertEqual([hit[0] for hit in self.db_manager.search_messages(self.username, "caf")], ["group:g1"])
+
+    def test_key_changes(self):
+        self.assertTrue(self.db_manager.is_trusted(self.username, "alice"))
+        self.db_manager.record_key_change(self.username, "alice", "public_key_alice2", "aaaa", "bbbb")
+        self.assertFalse(self.db_manager.is_trusted(self.username, "alice"))
+        self.assertEqual(self.db_manager.get_contact(self.username, "alice"), ("alice", "public_key_alice2"))
+        [(old, new, _)] = self.db_manager.get_key_changes(self.username, "alice")
+        self.assertEqual((old, new), ("aaaa", "bbbb"))
+
+        self.db_manager.set_trusted(self.username, "alice", True)
+        self.assertTrue(self.db_manager.is_trusted(self.username, "alice"))
+        self.assertEqual(self.db_manager.get_key_changes(self.username, "bob"), [])
+
+    def test_rejected_key_change_restores_the_old_key(self):
+        self.assertFalse(self.db_manager.reject_key_change(self.username, "alice"))
+        self.db_manager.record_key_change(self.username, "alice", "public_key_alice2", "aaaa", "bbbb")
+        self.assertTrue(self.db_manager.reject_key_change(self.username, "alice"))
+        self.assertEqual(self.db_manager.get_contact(self.username, "alice"), ("alice", "public_key_alice"))
+        self.assertTrue(self.db_manager.is_trusted(self.username, "alice"))
+        # The change stays in the history, and is no longer pending.
+        self.assertEqual(len(self.db_manager.get_key_changes(self.username, "alice")), 1)
+        self.assertFalse(self.db_manager.reject_key_change(self.username, "alice"))
+
+    def test_verified_contacts(self):
+        self.assertFalse(self.db_manager.is_verified(self.username, "alice"))
+        self.db_manager.set_verified(self.username, "alice", True)
+        self.assertTrue(self.db_manager.is_verified(self.username, "alice"))
+        # The same key again keeps the verification; another key drops it.
+        self.db_manager.add_contact(self.username, "alice", "public_key_alice")
+        self.assertTrue(self.db_manager.is_verified(self.username, "alice"))
+        self.db_manager.add_contact(self.username, "alice", "public_key_alice2")
+        self.assertFalse(self.db_manager.is_verified(self.username, "alice"))
+
+        self.db_manager.set_verified(self.username, "alice", True)
+        self.db_manager.record_key_change(self.username, "alice", "public_key_alice3", "aaaa", "bbbb")
+        self.assertFalse(self.db_manager.is_verified(self.username, "alice"))
+        self.assertFalse(self.db_manager.is_verified(self.username, "nobody"))
+
+    def test_audit_log(self):
+        self.db_manager.append_audit_event(self.username, "key_generated", "aaaa")
+        self.db_manager.append_audit_event(self.username, "key_changed", "alice: aaaa -> bbbb")
+        self.db_manager.append_audit_event(self.username, "account_exported", "backup.nymchat")
+        log = self.db_manager.get_audit_log(self.username)
+        self.assertEqual([(event, detail) for _, event, detail, _ in log], [
+            ("key_generated", "aaaa"), ("key_changed", "alice: aaaa -> bbbb"), ("account_exported", "backup.nymchat"),
+        ])
+        self.assertIsNone(self.db_manager.verify_audit_log(self.username))
+
+        # Editing the database file directly breaks the chain at the edited entry.
+        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
+        conn = sqlite3.connect(db_path)
+        with self.assertRaises(sqlite3.DatabaseError):
+            conn.execute(f'UPDATE "audit_{self.username}" SET detail = \'alice: aaaa -> cccc\' WHERE id = 2')
+        conn.execute(f'DROP TRIGGER "audit_no_update_{self.username}"')
+        conn.execute(f'UPDATE "audit_{self.username}" SET detail = \'alice: aaaa -> cccc\' WHERE id = 2')
+        conn.commit()
+        conn.close()
+        self.assertEqual(self.db_manager.verify_audit_log(self.username), 2)
+
     def test_get_all_users(self):
         users = self.db_manager.get_all_users()
         self.assertIn((self.username, "public_key_testuser"), users)
 
+    def test_diagnostic_bundle(self):
+        key = "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE\n-----END PUBLIC KEY-----"
+        address = "8dHpQx9dZRHi5oGhaZRbP7bk4ASqBb5BLRwp8Gf7vXmP.8zGMYaTKGKrQsF8hqaKdPPZm4nYa1CMYwpaZzXTbGrbD" \
+                  "@62F81C9GrHDRja9WCqozemRFSzFPMecY85MbGwn6efve"
+        records = [{"time": "2024-07-01 12:00:00,000", "level": "INFO", "source": "AppLogger",
+                    "message": f"alice sent {key} from {address}, signed 3045022100{'ab' * 16}; bob-2 waits"}]
+        bundle = diagnostics.collect(records, self.db_manager, self.username, {"reconnects": 1},
+                                     {"rtt": 0.2, "version": "1.2.0", "server": address})
+        self.assertEqual(bundle["logs"][0]["message"],
+                         "[contact 1] sent [key] from [nym address], signed [hex]; bob-2 waits")
+        self.assertEqual(bundle["database"], {"integrity": [], "auditLogTamperedAt": None, "contacts": 2,
+                                              "groups": 0, "quarantined": 0})
+        self.assertEqual(bundle["versions"]["server"], "1.2.0")
+        self.assertEqual(bundle["server"], {"rtt": 0.2, "server": "[nym address]"})
+        self.assertEqual(bundle["connection"], {"reconnects": 1})
+        self.assertNotIn(self.username, json.dumps(bundle))
+
+    def test_diagnostic_log_file_records(self):
+        path = os.path.join(self.storage_dir, "app.log")
+        with open(path, "w") as f:
+            f.write("2024-07-01 12:00:00,000 - INFO - AppLogger - first\n"
+                    "2024-07-01 12:00:01,000 - ERROR - AppLogger - failed\nTraceback\n"
+                    "2024-07-01 12:00:02,000 - DEBUG - other - last\n")
+        try:
+            records = diagnostics.file_records(path, limit=2)
+        finally:
+            os.remove(path)
+        self.assertEqual([(r["level"], r["message"]) for r in records], [("ERROR", "failed\nTraceback"),
+                                                                           ("DEBUG", "last")])
+        self.assertEqual(diagnostics.file_records(path), [])
+
 if __name__ == "__main__":
     unittest.main()
@@ -0,0 +1,58 @@
+import ast
+import os
+import string
+import unittest
+import i18n
+from i18n import _
+
+SRC_DIR = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+
+
+def message_ids():
+    """ Every literal passed to `_()` in the client sources. """
+    ids = set()
+    for name in os.listdir(SRC_DIR):
+        if not name.endswith(".py"):
+            continue
+        with open(os.path.join(SRC_DIR, name), encoding="utf-8") as f:
+            tree = ast.parse(f.read())
+        for node in ast.walk(tree):
+            if (isinstance(node, ast.Call) and isinstance(node.func, ast.Name) and node.func.id == "_"
+                    and node.args and isinstance(node.args[0], ast.Constant)):
+                ids.add(node.args[0].value)
+    return ids
+
+
+def placeholders(text):
+    return {field for _, field, _, _ in string.Formatter().parse(text) if field}
+
+
+class TestI18n(unittest.TestCase):
+    def tearDown(self):
+        i18n.set_locale("en")
+
+    def test_catalogs_cover_every_message(self):
+        ids = message_ids()
+        self.assertIn("Welcome to NymCHAT", ids)
+        for locale in i18n.available_locales():
+            if locale == "en":
+                continue
+            catalog = i18n.load_catalog(locale)
+            self.assertEqual(ids - catalog.keys(), set(), f"untranslated in {locale}")
+            self.assertEqual(catalog.keys() - ids, set(), f"unused in {locale}")
+            for message, translation in catalog.items():
+                self.assertEqual(placeholders(message), placeholders(translation), f"{locale}: {message}")
+
+    def test_locale_selection(self):
+        i18n.set_locale("es_ES.UTF-8")
+        self.assertEqual(i18n.current_locale, "es_ES.UTF-8")
+        self.assertEqual(_("Switched to {username}.", username="alice"), "Ahora estás usando alice.")
+
+        # Unknown locales and messages fall back to English.
+        i18n.set_locale("xx_YY")
+        self.assertEqual(i18n.current_locale, "en")
+        self.assertEqual(_("Switched to {username}.", username="alice"), "Switched to alice.")
+
+
+if __name__ == "__main__":
+    unittest.main()
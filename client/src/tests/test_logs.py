@@ -0,0 +1,43 @@
+import logging
+import unittest
+from logUtils import LogBuffer
+
+
+class TestLogBuffer(unittest.TestCase):
+    def setUp(self):
+        self.buffer = LogBuffer(capacity=3)
+        self.logger = logging.getLogger("TestLogBuffer")
+        self.logger.propagate = False
+        self.logger.setLevel(logging.DEBUG)
+        self.logger.addHandler(self.buffer)
+
+    def tearDown(self):
+        self.logger.removeHandler(self.buffer)
+
+    def test_only_the_newest_records_are_kept(self):
+        for n in range(5):
+            self.logger.info("message %d", n)
+        self.assertEqual([entry.message for entry in self.buffer.entries()], ["message 2", "message 3", "message 4"])
+        entry = self.buffer.entries()[0]
+        self.assertEqual((entry.level_name, entry.target), ("INFO", "TestLogBuffer"))
+
+        self.buffer.set_capacity(2)
+        self.assertEqual([entry.message for entry in self.buffer.entries()], ["message 3", "message 4"])
+        self.buffer.clear()
+        self.assertEqual(self.buffer.entries(), [])
+
+    def test_filtering_by_level(self):
+        self.logger.debug("noise")
+        self.logger.warning("careful")
+        try:
+            raise ValueError("broken")
+        except ValueError:
+            self.logger.exception("failed")
+        self.assertEqual([entry.level_name for entry in self.buffer.entries()], ["DEBUG", "WARNING", "ERROR"])
+        errors = self.buffer.entries(logging.WARNING)
+        self.assertEqual([entry.message.splitlines()[0] for entry in errors], ["careful", "failed"])
+        self.assertIn("ValueError: broken", errors[1].message)
+
+
+if __name__ == '__main__':
+    unittest.main()
@@ -2,13 +2,24 @@ import unittest
 import json
 import os
 import secrets
+import hashlib
+import time
 import asyncio
+import tempfile
 from cryptography.hazmat.primitives import serialization
-from messageHandler import MessageHandler
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature, encode_dss_signature
+from messageHandler import (MessageHandler, DIRECT_ENCRYPTION, DOUBLE_RATCHET, HANDSHAKE_MESSAGE, HEARTBEAT_INTERVAL,
+                            HEARTBEAT_JITTER, MUTE_FOREVER, RATCHET_MESSAGE, RECEIPT_MESSAGE, REVOCATION_MESSAGE,
+                            TYPING_MESSAGE, FILE_CHUNK_MESSAGE, FILE_MESSAGE, SURB_LOW_WATER, SURB_MESSAGE,
+                            SENDER_KEY_MESSAGE, SURBS_PER_MESSAGE,
+                            forwarded_from, forwarded_text, group_chat_id, invite_code, location_from_text, location_text,
+                            parse_invite, parse_location)
 from cryptographyUtils import CryptoUtils
-from dbUtils import SQLiteManager
+from async_ffi import (COMPACT_FEATURE, RATCHET_FEATURE, ContactInvite, Delegation, FileUpload, GroupSession,
+                       HandshakeHello, MembershipLog, PyDb, RatchetSession, decode_envelope, encode_envelope)
 from connectionUtils import MixnetConnectionClient
 from mixnetMessages import MixnetMessage
+from pipeline import Inbound, dedupe
 
 class MockServer:
     def __init__(self):
@@ -140,6 +151,10 @@ class MockServer:
         return json.dumps({"action": "sendResponse", "content": "success", "context": "chat"})
 
 
+# Order of the P-256 group
+P256_ORDER = 0xFFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551
+
+
 class TestMessageHandler(unittest.TestCase):
     def setUp(self):
         """Setup real dependencies and mock the server."""
@@ -152,7 +167,7 @@ class TestMessageHandler(unittest.TestCase):
         self.storage_dir = "test_storage"
         self.crypto_utils = CryptoUtils()
         self.connection_client = MixnetConnectionClient()
-        self.db_manager = SQLiteManager(self.username, self.storage_dir)
+        self.db_manager = PyDb.open(self.username, self.storage_dir)
 
         # ✅ Generate keys for testuser
         self.private_key, self.public_key_pem = self.crypto_utils.generate_key_pair(self.username)
@@ -194,9 +209,10 @@ class TestMessageHandler(unittest.TestCase):
     def tearDown(self):
         # self.logger.setLevel(logging.NOTSET)
         self.db_manager.close()
-        db_path = os.path.join(self.storage_dir, self.username, f"{self.username}_client.db")
-        if os.path.exists(db_path):
-            os.remove(db_path)
+        for name in (f"{self.username}_client.db", f"{self.username}_audit.key"):
+            path = os.path.join(self.storage_dir, self.username, name)
+            if os.path.exists(path):
+                os.remove(path)
         os.rmdir(os.path.join(self.storage_dir, self.username))
         os.rmdir(self.storage_dir)
 
@@ -304,9 +320,1945 @@ class TestMessageHandler(unittest.TestCase):
         await asyncio.sleep(0.5)
 
         # ✅ Verify that message was stored
-        chat_messages = self.db_manager.get_messages_by_contact(recipient, sender)
+        chat_messages = self.db_manager.load_messages(recipient, sender)
         self.assertGreater(len(chat_messages), 0)
 
+    def test_pinned_server_keys(self):
+        asyncio.run(self.async_test_pinned_server_keys())
+
+    async def async_test_pinned_server_keys(self):
+        server_key, server_pem = self.crypto_utils.generate_key_pair("server")
+        new_key, new_pem = self.crypto_utils.generate_key_pair("server")
+        impostor, impostor_pem = self.crypto_utils.generate_key_pair("impostor")
+        pin_path = os.path.join(self.storage_dir, "server_keys.pem")
+        with open(pin_path, "w") as f:
+            f.write(server_pem)
+        self.message_handler.pin_server_keys(pin_path)
+
+        def reply(key, username, public_key):
+            content = json.dumps({"username": username, "publicKey": public_key})
+            return json.dumps({
+                "action": "queryResponse", "context": "query", "content": content,
+                "signature": self.crypto_utils.sign_message(key, content),
+            })
+
+        # Replies signed by another key are dropped before they are trusted.
+        await self.message_handler.handle_incoming_message(reply(impostor, "carol", impostor_pem))
+        self.assertIsNone(self.db_manager.get_contact(self.username, "carol"))
+        await self.message_handler.handle_incoming_message(reply(server_key, "carol", "carol_key"))
+        self.assertIsNotNone(self.db_manager.get_contact(self.username, "carol"))
+
+        # A rotation signed by the pinned and the new key moves the pin.
+        signed = json.dumps({"oldPublicKey": server_pem, "newPublicKey": new_pem, "issued": 1700000000})
+        transition = json.dumps({
+            "oldPublicKey": server_pem, "newPublicKey": new_pem, "issued": 1700000000,
+            "oldSignature": self.crypto_utils.sign_message(server_key, signed),
+            "newSignature": self.crypto_utils.sign_message(new_key, signed),
+        })
+        await self.message_handler.handle_incoming_message(json.dumps({
+            "action": "keyTransitionResponse", "context": "keyTransition", "content": transition,
+            "signature": self.crypto_utils.sign_message(new_key, transition),
+        }))
+        await self.message_handler.handle_incoming_message(reply(server_key, "dave", "dave_key"))
+        self.assertIsNone(self.db_manager.get_contact(self.username, "dave"))
+        await self.message_handler.handle_incoming_message(reply(new_key, "dave", "dave_key"))
+        self.assertIsNotNone(self.db_manager.get_contact(self.username, "dave"))
+        with open(pin_path) as f:
+            self.assertEqual(f.read(), new_pem)
+        os.remove(pin_path)
+
+    def test_routing_tokens(self):
+        asyncio.run(self.async_test_routing_tokens())
+
+    async def async_test_routing_tokens(self):
+        handler = self.message_handler
+        handler.use_routing_tokens = True
+        sent = []
+
+        async def send_message(msg):
+            sent.append(msg)
+            request = json.loads(msg["message"])
+            if request["action"] == "query":
+                content = json.dumps({
+                    "username": request["username"], "publicKey": self.friend_public_key_pem, "routingToken": "ab12",
+                })
+                await handler.handle_incoming_message(json.dumps({
+                    "action": "queryResponse", "context": "query", "content": content,
+                }))
+        self.connection_client.send_message = send_message
+
+        # The first send queries for a token; later ones reuse it.
+        await handler.send_direct_message(self.friend_username, "hi")
+        await handler.send_direct_message(self.friend_username, "again")
+        actions = [json.loads(msg["message"])["action"] for msg in sent]
+        self.assertEqual(actions, ["query", "send", "send"])
+        payload = json.loads(json.loads(sent[1]["message"])["content"])
+        self.assertEqual(payload["recipientToken"], "ab12")
+        self.assertNotIn("recipient", payload)
+
+        # A token the server no longer accepts is dropped so the next send asks again.
+        send_id = next(iter(handler.pending_sends))
+        await handler.handle_send_response({"id": send_id, "status": "error: invalid routing token"})
+        self.assertNotIn(self.friend_username, handler.routing_tokens)
+
+    def test_sealed_sender(self):
+        asyncio.run(self.async_test_sealed_sender())
+
+    async def async_test_sealed_sender(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = True
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"]))
+        self.connection_client.send_message = send_message
+
+        # Outgoing: the server sees the recipient and a sealed blob, not the sender.
+        await handler.send_direct_message(self.friend_username, "hi")
+        self.assertEqual(sent[0]["action"], "sealedSend")
+        self.assertNotIn("signature", sent[0])
+        content = json.loads(sent[0]["content"])
+        self.assertEqual(set(content), {"recipient", "sealed"})
+        opened = json.loads(self.crypto_utils.decrypt_message(self.friend_private_key, content["sealed"]))
+        self.assertEqual(opened["sender"], self.username)
+
+        # Incoming: the sender is only known once the seal is opened.
+        friend_key = self.crypto_utils.load_private_key(self.friend_username)
+        encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps({"type": 0, "message": "sealed hi"}))
+        forward = {
+            "sender": self.friend_username,
+            "body": {
+                "encryptedPayload": encrypted,
+                "payloadSignature": self.crypto_utils.sign_message(friend_key, json.dumps(encrypted)),
+            },
+        }
+        sealed = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(forward))
+        await handler.handle_incoming_message(json.dumps({
+            "action": "incomingMessage", "context": "chat", "content": {"sealed": sealed},
+        }))
+        handler.flush_writes()
+        messages = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertIn("sealed hi", [m[1] for m in messages])
+
+    def test_key_change_blocks_sends(self):
+        asyncio.run(self.async_test_key_change_blocks_sends())
+
+    async def async_test_key_change_blocks_sends(self):
+        handler = self.message_handler
+        warnings = []
+        handler.key_change_callback = lambda *args: warnings.append(args)
+        sent = []
+
+        async def send_message(msg):
+            sent.append(msg)
+        self.connection_client.send_message = send_message
+
+        def query_response(public_key_pem):
+            content = json.dumps({"username": self.friend_username, "publicKey": public_key_pem})
+            return json.dumps({"action": "queryResponse", "context": "query", "content": content})
+
+        # The same key again is no change.
+        await handler.handle_incoming_message(query_response(self.friend_public_key_pem))
+        self.assertEqual(warnings, [])
+
+        _, new_pem = self.crypto_utils.generate_key_pair("friend2")
+        await handler.handle_incoming_message(query_response(new_pem))
+        self.assertEqual(warnings, [(
+            self.friend_username,
+            self.crypto_utils.fingerprint(self.friend_public_key_pem),
+            self.crypto_utils.fingerprint(new_pem),
+        )])
+        self.assertEqual(len(handler.key_changes(self.friend_username)), 1)
+        self.assertIsNone(await handler.send_direct_message(self.friend_username, "hi"))
+        self.assertEqual(sent, [])
+
+        handler.trust_contact(self.friend_username)
+        self.assertIsNotNone(await handler.send_direct_message(self.friend_username, "hi"))
+        self.assertEqual(len(sent), 1)
+
+        entries, tampered = handler.audit_log()
+        self.assertEqual([event for _, event, _, _ in entries], ["key_changed", "key_confirmed"])
+        self.assertIsNone(tampered)
+
+    def test_nothing_is_applied_under_an_unconfirmed_key(self):
+        asyncio.run(self.async_test_nothing_is_applied_under_an_unconfirmed_key())
+
+    async def async_test_nothing_is_applied_under_an_unconfirmed_key(self):
+        handler = self.message_handler
+        applied = []
+
+        async def apply(from_user, message):
+            applied.append(message)
+        handler._handle_handshake = apply
+        handler._handle_sender_key = apply
+
+        async def send_message(msg):
+            pass
+        self.connection_client.send_message = send_message
+
+        new_key, new_pem = self.crypto_utils.generate_key_pair("friend2")
+        content = json.dumps({"username": self.friend_username, "publicKey": new_pem})
+        await handler.handle_incoming_message(json.dumps({"action": "queryResponse", "context": "query",
+                                                          "content": content}))
+        self.assertFalse(handler.is_trusted(self.friend_username))
+
+        async def receive(message_obj):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "senderPublicKey": new_pem,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(new_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope))
+
+        # The text is shown; the handshake and sender key are not applied.
+        await receive({"type": HANDSHAKE_MESSAGE, "message": "hello"})
+        await receive({"type": SENDER_KEY_MESSAGE, "message": "key"})
+        await receive({"type": 0, "message": "new phone"})
+        handler.flush_writes()
+        self.assertEqual(applied, [])
+        messages = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertEqual(messages[-1][1], "new phone")
+
+        handler.trust_contact(self.friend_username)
+        await receive({"type": HANDSHAKE_MESSAGE, "message": "hello again"})
+        self.assertEqual(applied, ["hello again"])
+
+    def test_session_renewal(self):
+        asyncio.run(self.async_test_session_renewal())
+
+    async def async_test_session_renewal(self):
+        handler = self.message_handler
+        expired = []
+        handler.session_expired_callback = lambda: expired.append(True)
+
+        # A started session schedules the next login before it runs out.
+        session = json.dumps({"action": "session", "context": "login", "content": {"ttl": 3600}})
+        await handler.handle_incoming_message(session)
+        delay = handler.session_renewal.when() - asyncio.get_running_loop().time()
+        self.assertAlmostEqual(delay, 3600 * 0.8, delta=5)
+
+        # The server ended the session and the key is gone: the UI is told, once.
+        def missing_key(username):
+            raise FileNotFoundError(username)
+        handler.crypto_utils.load_private_key = missing_key
+        refused = json.dumps({"action": "ackResponse", "context": "chat", "content": "error: session expired"})
+        for _ in range(2):
+            await handler.handle_incoming_message(refused)
+            await asyncio.sleep(0)
+        self.assertEqual(expired, [True])
+        self.assertTrue(handler.session_expired)
+
+        # Logging in again with the key clears it.
+        del handler.crypto_utils.load_private_key
+
+        async def authenticate(msg, renewal=False):
+            self.assertEqual(json.loads(msg["message"])["action"], "login")
+            self.assertTrue(renewal)
+            handler.session_expired = False
+            return True
+        handler._authenticate = authenticate
+        self.assertTrue(await handler.renew_session())
+        self.assertFalse(handler.session_expired)
+        del handler._authenticate
+
+        # A renewal's login keeps the session's database; a new login opens its own.
+        handler.auth_renewal = True
+        await handler.handle_login_response("success")
+        self.assertIs(handler.db_manager, self.db_manager)
+        handler.auth_renewal = False
+
+        await handler.end_session()
+        self.assertIsNone(handler.session_renewal)
+
+    def test_surb_top_up(self):
+        asyncio.run(self.async_test_surb_top_up())
+
+    async def async_test_surb_top_up(self):
+        sent = []
+
+        async def send_with_surbs(recipient, message, surb_count):
+            sent.append((json.loads(message), surb_count))
+        self.connection_client.send_with_surbs = send_with_surbs
+
+        # The server asks for more SURBs; we send what it asks for, within reason.
+        for count in (20, 500, 0, "5"):
+            request = {"action": "surbRequest", "context": "surbs", "content": json.dumps({"surbs": count})}
+            await self.message_handler.handle_incoming_message(json.dumps(request))
+        self.assertEqual(sent, [({"action": "surbTopUp", "surbs": 20}, 20),
+                                ({"action": "surbTopUp", "surbs": 100}, 100)])
+
+    def test_network_stats(self):
+        asyncio.run(self.async_test_network_stats())
+
+    async def async_test_network_stats(self):
+        handler = self.message_handler
+
+        async def send_message(msg):
+            nonce = json.loads(msg["message"])["nonce"]
+            asyncio.get_running_loop().call_soon(
+                asyncio.ensure_future, handler.handle_health_response({"nonce": nonce, "version": "1"}))
+        self.connection_client.send_message = send_message
+
+        async def network_stats():
+            return {"gateway": "gw", "uptime": 5.0, "messages_sent": 3}
+        self.connection_client.network_stats = network_stats
+
+        self.assertIsNone((await handler.network_stats())["latency"])
+        for _ in range(3):
+            self.assertIsNotNone(await handler.ping())
+        stats = await handler.network_stats()
+        self.assertEqual((stats["gateway"], stats["messages_sent"]), ("gw", 3))
+        self.assertAlmostEqual(stats["latency"], sum(handler.latencies) / 3)
+
+    def test_key_transparency_gossip(self):
+        asyncio.run(self.async_test_key_transparency_gossip())
+
+    async def async_test_key_transparency_gossip(self):
+        handler = self.message_handler
+        server_key, server_pem = self.crypto_utils.generate_key_pair("server")
+        pin_path = os.path.join(self.storage_dir, "server_keys.pem")
+        with open(pin_path, "w") as f:
+            f.write(server_pem)
+        handler.pin_server_keys(pin_path)
+        os.remove(pin_path)
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        def signed_binding(username, public_key_pem):
+            content = json.dumps({"username": username, "publicKey": public_key_pem})
+            return {"content": content, "signature": self.crypto_utils.sign_message(server_key, content)}
+
+        # The directory's signed answer for friend goes to friend with the first message only.
+        observation = signed_binding(self.friend_username, self.friend_public_key_pem)
+        await handler.handle_incoming_message(json.dumps({"action": "queryResponse", "context": "query", **observation}))
+        await handler.send_direct_message(self.friend_username, "hi")
+        await handler.send_direct_message(self.friend_username, "again")
+        plaintexts = [json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, payload["body"]["encryptedPayload"])) for payload in sent]
+        self.assertEqual(plaintexts[0]["keyObservation"], observation)
+        self.assertNotIn("keyObservation", plaintexts[1])
+
+        # A contact given a key for us that is not ours is reported, once.
+        warnings = []
+        handler.equivocation_callback = lambda *args: warnings.append(args)
+        handler._check_observation(self.friend_username, signed_binding(self.username, self.public_key_pem))
+        self.assertEqual(warnings, [])
+        _, rogue_pem = self.crypto_utils.generate_key_pair("rogue")
+        for _ in range(2):
+            handler._check_observation(self.friend_username, signed_binding(self.username, rogue_pem))
+        self.assertEqual(warnings, [(
+            self.friend_username,
+            self.crypto_utils.fingerprint(self.public_key_pem),
+            self.crypto_utils.fingerprint(rogue_pem),
+        )])
+
+        # Observations the server did not sign prove nothing.
+        _, other_pem = self.crypto_utils.generate_key_pair("other")
+        forged = signed_binding(self.username, other_pem)
+        forged["signature"] = self.crypto_utils.sign_message(self.friend_private_key, forged["content"])
+        handler._check_observation(self.friend_username, forged)
+        self.assertEqual(len(warnings), 1)
+
+    def test_group_messages(self):
+        asyncio.run(self.async_test_group_messages())
+
+    async def async_test_group_messages(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        handler.use_routing_tokens = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        # Creating a group sends the friend its signed membership log, then our
+        # sender key, pairwise.
+        group_id = await handler.create_group("team", [self.friend_username])
+        self.assertEqual(len(sent), 2)
+        control, announcement = [json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, payload["body"]["encryptedPayload"])) for payload in sent]
+        self.assertEqual((control["type"], announcement["type"]), (4, 3))
+        friend_log = MembershipLog.create(group_id)
+        for item in control["message"]["log"]:
+            friend_log.apply(item["entry"], item["signature"], self.public_key_pem)
+        self.assertEqual(friend_log.members(), sorted([self.username, self.friend_username]))
+        self.assertEqual(friend_log.admins(), [self.username])
+        announcement = announcement["message"]
+        friend_session = GroupSession.create(group_id, friend_log.members())
+        friend_session.process_distribution(self.username, json.dumps(announcement["senderKey"]))
+
+        # The friend's key in return changes no membership, so nothing is re-sent.
+        await handler._handle_sender_key(self.friend_username, {
+            "groupId": group_id, "senderKey": json.loads(friend_session.distribution_json())})
+        self.assertEqual(len(sent), 2)
+
+        # A message from the friend, encrypted once for the group.
+        group_message = friend_session.encrypt(json.dumps({"type": 0, "message": "hello team"}))
+        content = {
+            "sender": self.friend_username,
+            "group": json.loads(group_message),
+            "groupSignature": self.crypto_utils.sign_message(self.friend_private_key, group_message),
+        }
+        await handler.handle_incoming_message_content(content)
+        await handler.handle_incoming_message_content(content)  # replayed: its key is used up
+        chat = handler.chat_messages[group_chat_id(group_id)]
+        self.assertEqual([(sender, text) for sender, text, _, _ in chat], [(self.friend_username, "hello team")])
+        self.assertEqual(handler.group_history(group_id)[0][:2], (self.friend_username, "hello team"))
+
+        # Other members cannot sign as the friend.
+        forged = friend_session.encrypt(json.dumps({"type": 0, "message": "forged"}))
+        await handler.handle_incoming_message_content({
+            "sender": self.friend_username,
+            "group": json.loads(forged),
+            "groupSignature": self.crypto_utils.sign_message(self.private_key, forged),
+        })
+        self.assertEqual(len(chat), 1)
+
+        # Our message goes out once, naming the other members, and the friend can read it.
+        await handler.send_group_message(group_id, "hi all")
+        payload = sent[-1]
+        self.assertEqual(payload["recipients"], [self.friend_username])
+        plaintext = friend_session.decrypt(
+            self.username, self.public_key_pem, json.dumps(payload["group"]), payload["groupSignature"])
+        self.assertEqual(json.loads(plaintext)["message"], "hi all")
+
+        # Mentions of members are tagged; others stay plain text.
+        await handler.send_group_message(group_id, f"@{self.friend_username} @alice look")
+        payload = sent[-1]
+        plaintext = friend_session.decrypt(
+            self.username, self.public_key_pem, json.dumps(payload["group"]), payload["groupSignature"])
+        self.assertEqual(json.loads(plaintext)["mentions"], [self.friend_username])
+
+        # A tagged mention of us alerts us; a tag the text does not show does not.
+        mentions = []
+        handler.mention_callback = lambda *args: mentions.append(args)
+        for text in (f"ping @{self.username}!", "nothing to see"):
+            group_message = friend_session.encrypt(json.dumps({"type": 0, "message": text,
+                                                               "mentions": [self.username]}))
+            await handler.handle_incoming_message_content({
+                "sender": self.friend_username,
+                "group": json.loads(group_message),
+                "groupSignature": self.crypto_utils.sign_message(self.friend_private_key, group_message),
+            })
+        self.assertEqual(mentions, [(group_chat_id(group_id), self.friend_username, f"ping @{self.username}!")])
+        self.assertTrue(handler.mentions_me(chat[-2][1]))
+        self.assertFalse(handler.mentions_me(f"mail{self.username}@example.org @{self.username}x"))
+
+    def test_group_membership(self):
+        asyncio.run(self.async_test_group_membership())
+
+    async def async_test_group_membership(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        handler.use_routing_tokens = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        def signed(log, entry, key):
+            signature = self.crypto_utils.sign_message(key, entry)
+            log.apply(entry, signature, self.crypto_utils.public_key_pem(key.public_key()))
+            return {"entry": entry, "signature": signature}
+
+        # The friend starts a group with us. Their sender key overtakes the
+        # membership log and waits for it.
+        friend_log = MembershipLog.create("g1")
+        genesis = signed(friend_log, friend_log.propose_create(
+            self.friend_username, "team", [self.friend_username, self.username]), self.friend_private_key)
+        friend_session = GroupSession.create("g1", friend_log.members())
+        sender_key = json.loads(friend_session.distribution_json())
+        await handler._handle_sender_key(self.friend_username, {"groupId": "g1", "senderKey": sender_key})
+        self.assertIsNone(handler.group_members("g1"))
+        await handler._handle_group_control(self.friend_username, {"groupId": "g1", "log": [genesis]})
+        self.assertEqual(handler.group_members("g1"), (friend_log.members(), [self.friend_username]))
+        self.assertEqual(len(sent), 1)  # our own sender key, for the friend
+        self.assertEqual(handler.pending_sender_keys, {})
+
+        # We are no admin: the change is refused before anything is signed.
+        self.assertFalse(await handler.kick_member("g1", self.friend_username))
+        # An entry signed by someone other than its actor is dropped.
+        forged = friend_log.propose_invite(self.friend_username, "alice")
+        await handler._handle_group_control(self.friend_username, {"groupId": "g1", "log": [
+            genesis, {"entry": forged, "signature": self.crypto_utils.sign_message(self.private_key, forged)}]})
+        self.assertNotIn("alice", handler.group_members("g1")[0])
+
+        # The friend promotes us; the log they show us later conflicts with it.
+        fork_base = MembershipLog.from_json(friend_log.to_json())
+        promote = signed(friend_log, friend_log.propose_promote(self.friend_username, self.username),
+                         self.friend_private_key)
+        await handler._handle_group_control(self.friend_username, {"groupId": "g1", "log": [genesis, promote]})
+        self.assertEqual(handler.group_members("g1")[1], sorted([self.friend_username, self.username]))
+        other = signed(fork_base, fork_base.propose_invite(self.friend_username, "bob"), self.friend_private_key)
+        await handler._handle_group_control(self.friend_username, {"groupId": "g1", "log": [genesis, other]})
+        self.assertNotIn("bob", handler.group_members("g1")[0])
+        self.assertIn("group_fork", [event for _, event, _, _ in handler.audit_log()[0]])
+
+        # As an admin we remove the friend: they get the log, and our new
+        # sender key goes to nobody.
+        sent.clear()
+        self.assertTrue(await handler.kick_member("g1", self.friend_username))
+        self.assertEqual(handler.group_members("g1"), ([self.username], [self.username]))
+        self.assertEqual(len(sent), 1)
+        control = json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, sent[0]["body"]["encryptedPayload"]))["message"]
+        kick = control["log"][-1]
+        self.assertEqual(len(control["log"]), 3)
+        friend_log.apply(kick["entry"], kick["signature"], self.public_key_pem)
+        self.assertEqual(friend_log.members(), [self.username])
+
+    def test_group_invites(self):
+        asyncio.run(self.async_test_group_invites())
+
+    async def async_test_group_invites(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        handler.use_routing_tokens = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        def opened(payload):
+            return json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"]))
+
+        # Redeeming a code asks the admin it names, pairwise.
+        code = invite_code("g1", "ab" * 16, self.friend_username, "their team")
+        self.assertEqual(parse_invite(code)["name"], "their team")
+        self.assertIsNone(parse_invite(code[:-4]))
+        self.assertIsNotNone(await handler.redeem_invite(code))
+        self.assertEqual(opened(sent[-1]), {"type": 5, "message": {"groupId": "g1", "secret": "ab" * 16}})
+        expired = invite_code("g1", "ab" * 16, self.friend_username, "their team", expires_at=1)
+        self.assertIsNone(await handler.redeem_invite(expired))
+
+        # As the admin we add whoever brings a valid code, once.
+        group_id = await handler.create_group("team", [])
+        invite = parse_invite(handler.create_invite(group_id, expires_in=3600))
+        self.assertEqual((invite["group_id"], invite["admin"]), (group_id, self.username))
+        sent.clear()
+        await handler._handle_join_request(self.friend_username, {"groupId": group_id, "secret": "00" * 16})
+        self.assertEqual(handler.group_members(group_id)[0], [self.username])
+        await handler._handle_join_request(self.friend_username, {"groupId": group_id, "secret": invite["secret"]})
+        self.assertEqual(handler.group_members(group_id)[0], sorted([self.username, self.friend_username]))
+        self.assertEqual([opened(payload)["type"] for payload in sent], [4, 3])
+        self.assertFalse(handler.db_manager.redeem_group_invite(
+            self.username, hashlib.sha256(invite["secret"].encode()).hexdigest(), group_id, int(time.time())))
+
+        # Expired codes are refused by the admin too.
+        expired = parse_invite(handler.create_invite(group_id, expires_in=0, single_use=False))
+        await handler.kick_member(group_id, self.friend_username)
+        await handler._handle_join_request(self.friend_username, {"groupId": group_id, "secret": expired["secret"]})
+        self.assertEqual(handler.group_members(group_id)[0], [self.username])
+
+    def test_group_history_sharing(self):
+        asyncio.run(self.async_test_group_history_sharing())
+
+    async def async_test_group_history_sharing(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        handler.use_routing_tokens = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        # As an admin we send a new member the recent history the group allows.
+        group_id = await handler.create_group("team", [])
+        self.assertTrue(await handler.set_history_limit(group_id, 2))
+        for text in ("one", "two", "three"):
+            self.db_manager.save_group_message(self.username, group_id, self.username, text)
+        sent.clear()
+        await handler.invite_member(group_id, self.friend_username)
+        shared = [json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, payload["body"]["encryptedPayload"])) for payload in sent]
+        shared = [m["message"] for m in shared if m["type"] == 6]
+        self.assertEqual([m["message"] for part in shared for m in part["messages"]], ["two", "three"])
+
+        # As the new member we keep parts that overtake the log making us one,
+        # and merge them in order once it arrives.
+        friend_log = MembershipLog.create("g2")
+        entry = friend_log.propose_create(self.friend_username, "theirs", [self.friend_username, self.username])
+        signature = self.crypto_utils.sign_message(self.friend_private_key, entry)
+        parts = [[{"sender": "alice", "message": "later", "timestamp": "2024-01-01 10:00:00"}],
+                 [{"sender": self.friend_username, "message": "earlier", "timestamp": "2023-12-31 09:00:00"}]]
+        for index in (1, 0):
+            await handler._handle_group_history(self.friend_username, {
+                "groupId": "g2", "historyId": "h1", "index": index, "total": 2, "messages": parts[index]})
+        self.assertEqual(handler.group_history("g2"), [])
+        await handler._handle_group_control(self.friend_username, {
+            "groupId": "g2", "log": [{"entry": entry, "signature": signature}]})
+        self.db_manager.save_group_message(self.username, "g2", self.friend_username, "live")
+        self.assertEqual([text for _, text, _, _ in handler.group_history("g2")], ["earlier", "later", "live"])
+        self.assertEqual(handler.pending_histories, {})
+
+        # Only admins share history, and nothing is merged twice.
+        await handler._handle_group_history("alice", {
+            "groupId": "g2", "historyId": "h2", "index": 0, "total": 1, "messages": parts[0]})
+        await handler._handle_group_history(self.friend_username, {
+            "groupId": "g2", "historyId": "h3", "index": 0, "total": 1, "messages": parts[0]})
+        self.assertEqual(len(handler.group_history("g2")), 3)
+
+    def test_group_polls(self):
+        asyncio.run(self.async_test_group_polls())
+
+    async def async_test_group_polls(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        handler.use_routing_tokens = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        group_id = await handler.create_group("team", [self.friend_username])
+        announcement = json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, sent[1]["body"]["encryptedPayload"]))["message"]
+        friend_session = GroupSession.create(group_id, sorted([self.username, self.friend_username]))
+        friend_session.process_distribution(self.username, json.dumps(announcement["senderKey"]))
+        await handler._handle_sender_key(self.friend_username, {
+            "groupId": group_id, "senderKey": json.loads(friend_session.distribution_json())})
+
+        # Polls need 2 or more distinct options; the friend receives ours.
+        self.assertIsNone(await handler.create_poll(group_id, "Lunch?", ["pizza", "pizza"]))
+        poll_id = await handler.create_poll(group_id, "Lunch?", ["pizza", "sushi", " "])
+        payload = sent[-1]
+        plaintext = json.loads(friend_session.decrypt(
+            self.username, self.public_key_pem, json.dumps(payload["group"]), payload["groupSignature"]))
+        self.assertEqual((plaintext["type"], plaintext["poll"]["options"]), (7, ["pizza", "sushi"]))
+
+        async def friend_votes(choices):
+            message = friend_session.encrypt(json.dumps({"type": 8, "vote": {"pollId": poll_id, "choices": choices}}))
+            await handler.handle_incoming_message_content({
+                "sender": self.friend_username,
+                "group": json.loads(message),
+                "groupSignature": self.crypto_utils.sign_message(self.friend_private_key, message),
+            })
+
+        # A later vote replaces the earlier one; votes that do not fit the poll are not counted.
+        await friend_votes([0])
+        await friend_votes([1])
+        self.assertEqual(handler.poll_results(group_id)[0]["counts"], [0, 1])
+        await friend_votes([0, 1])
+        self.assertEqual(handler.poll_results(group_id)[0]["counts"], [0, 0])
+        await friend_votes([5])
+        self.assertEqual(handler.poll_results(group_id)[0]["voters"], 0)
+
+        # Our own vote goes out to the group and counts too.
+        await friend_votes([1])
+        self.assertFalse(await handler.vote(group_id, poll_id, [2]))
+        self.assertTrue(await handler.vote(group_id, poll_id, [1]))
+        payload = sent[-1]
+        plaintext = json.loads(friend_session.decrypt(
+            self.username, self.public_key_pem, json.dumps(payload["group"]), payload["groupSignature"]))
+        self.assertEqual(plaintext["vote"], {"pollId": poll_id, "choices": [1]})
+        poll = handler.poll_results(group_id)[0]
+        self.assertEqual((poll["counts"], poll["voters"], poll["mine"]), ([0, 2], 2, [1]))
+
+    def test_sender_tags_survive_restarts(self):
+        asyncio.run(self.async_test_sender_tags_survive_restarts())
+
+    async def async_test_sender_tags_survive_restarts(self):
+        handler = self.message_handler
+        handler.update_nym_address("me@gateway")
+        replies, sent = [], []
+
+        async def reply_with_surb(sender_tag, message):
+            replies.append((sender_tag, json.loads(json.loads(message)["content"])["sender"]))
+        self.connection_client.reply_with_surb = reply_with_surb
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"])["action"])
+        self.connection_client.send_message = send_message
+
+        async def receive(text, sender_tag):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps({"type": 0, "message": text}))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope), sender_tag)
+
+        # Messages relayed by the server carry no tag; direct ones do.
+        await receive("via the server", None)
+        self.assertEqual(handler.sender_tags, {})
+        await receive("direct", "tag1")
+        self.assertEqual(handler.sender_tags, {self.friend_username: "tag1"})
+
+        # After a restart with the same mixnet identity, replies still go straight back.
+        handler.sender_tags.clear()
+        handler.load_sender_tags()
+        await handler.send_direct_message(self.friend_username, "hi")
+        self.assertEqual((replies, sent), ([("tag1", self.username)], []))
+
+        # Another identity has none of the SURBs, so the tag is not restored.
+        handler.update_nym_address("other@gateway")
+        handler.load_sender_tags()
+        self.assertEqual(handler.sender_tags, {})
+
+        # A failed reply falls back to the server and forgets the tag.
+        handler.update_nym_address("me@gateway")
+        handler.load_sender_tags()
+
+        async def failing_reply(sender_tag, message):
+            raise RuntimeError("no SURBs left")
+        self.connection_client.reply_with_surb = failing_reply
+        handler.use_sealed_sender = False
+        await handler.send_direct_message(self.friend_username, "hi again")
+        self.assertEqual(sent, ["send"])
+        self.assertEqual(self.db_manager.get_sender_tags(self.username, "me@gateway"), [])
+
+    def test_anonymous_mode(self):
+        asyncio.run(self.async_test_anonymous_mode())
+
+    async def async_test_anonymous_mode(self):
+        handler = self.message_handler
+        handler.update_nym_address("me@gateway")
+        handler.use_sealed_sender = False
+        handler.set_anonymous(self.friend_username, True)
+        handler.anonymous.clear()
+        handler.load_anonymous()
+        self.assertEqual(handler.anonymous, {self.friend_username})
+        with_surbs, replies = [], []
+
+        def opened(message):
+            payload = json.loads(json.loads(message)["content"])
+            return json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"]))
+
+        async def send_with_surbs(recipient, message, count):
+            with_surbs.append((recipient, count, opened(message)))
+        self.connection_client.send_with_surbs = send_with_surbs
+
+        async def reply_with_surb(sender_tag, message):
+            replies.append(opened(message))
+        self.connection_client.reply_with_surb = reply_with_surb
+
+        # Our hello leaves the address out, and goes with SURBs to answer through.
+        handler.nym_addresses[self.friend_username] = "friend@gateway"
+        await handler.send_handshake(self.friend_username)
+        [(recipient, count, hello)] = with_surbs
+        self.assertEqual((recipient, count, hello["type"]), ("friend@gateway", SURBS_PER_MESSAGE, HANDSHAKE_MESSAGE))
+        self.assertIsNone(HandshakeHello.parse(hello["message"]["hello"]).nym_address)
+
+        async def receive(message_obj, sender_tag):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope), sender_tag)
+
+        # A contact asking for SURBs gets them, straight to their address.
+        await receive({"type": SURB_MESSAGE, "message": {"surbs": 30}}, None)
+        self.assertEqual(with_surbs[-1][1:], (30, {"type": SURB_MESSAGE, "message": {"topUp": 30}}))
+
+        # Replying through a contact's SURBs, we ask for more once few are left, and only once.
+        del handler.nym_addresses[self.friend_username]
+        await receive({"type": 0, "message": "hello from nowhere"}, "tag1")
+        for _ in range(SURBS_PER_MESSAGE - SURB_LOW_WATER + 2):
+            await handler._send_typed(self.friend_username, TYPING_MESSAGE, True)
+        requests = [reply for reply in replies if reply["type"] == SURB_MESSAGE]
+        self.assertEqual([request["message"] for request in requests], [{"surbs": 20}])
+        left = handler.reply_surbs[self.friend_username]
+        self.assertLess(left, SURB_LOW_WATER)
+        await receive({"type": SURB_MESSAGE, "message": {"topUp": 20}}, "tag1")
+        self.assertEqual(handler.reply_surbs[self.friend_username], left + 20)
+        self.assertNotIn(self.friend_username, handler.surbs_requested)
+
+    def test_outbox_while_offline(self):
+        asyncio.run(self.async_test_outbox_while_offline())
+
+    async def async_test_outbox_while_offline(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+        broken = [False]
+
+        async def send_message(msg):
+            if broken[0]:
+                raise RuntimeError("not connected")
+            payload = json.loads(json.loads(msg["message"])["content"])
+            wrapped = json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"]))
+            sent.append((wrapped["message"], "senderPublicKey" in payload))
+        self.connection_client.send_message = send_message
+
+        # While the mixnet is down, messages are stored and shown as waiting.
+        handler.set_connection_status("disconnected")
+        first = await handler.send_direct_message(self.friend_username, "one")
+        second = await handler.send_direct_message(self.friend_username, "two")
+        self.assertEqual(sent, [])
+        self.assertEqual((handler.send_status[first], handler.send_status[second]), ("waiting", "waiting"))
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)],
+                         ["one", "two"])
+        self.assertEqual(len(self.db_manager.get_outbox(self.username)), 2)
+
+        # Reconnecting sends them in order; only the first introduces our key.
+        handler.set_connection_status("connected")
+        while handler.outbox_pending:
+            await asyncio.sleep(0)
+        self.assertEqual(sent, [("one", True), ("two", False)])
+        self.assertEqual(handler.send_status[first], "sending")
+        self.assertEqual(self.db_manager.get_outbox(self.username), [])
+
+        # A send that fails keeps the message, and those after it, queued.
+        handler.set_connection_status("reconnecting", "resumed")
+        self.assertEqual((handler.online, handler.connection_reason), (False, "resumed"))
+        third = await handler.send_direct_message(self.friend_username, "three")
+        broken[0] = True
+        handler.set_connection_status("connected")
+        await handler.flush_outbox()
+        self.assertEqual((handler.send_status[third], len(self.db_manager.get_outbox(self.username))), ("waiting", 1))
+        fourth = await handler.send_direct_message(self.friend_username, "four")
+        self.assertEqual(handler.send_status[fourth], "waiting")
+        broken[0] = False
+        await handler.flush_outbox()
+        self.assertEqual(sent[2:], [("three", False), ("four", False)])
+
+    def test_receipt_and_typing_privacy(self):
+        asyncio.run(self.async_test_receipt_and_typing_privacy())
+
+    async def async_test_receipt_and_typing_privacy(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"]))["type"])
+        self.connection_client.send_message = send_message
+
+        async def send_both():
+            sent.clear()
+            await handler._send_typed(self.friend_username, RECEIPT_MESSAGE, "read")
+            await handler._send_typed(self.friend_username, TYPING_MESSAGE, "typing")
+            return sent
+
+        self.assertEqual(await send_both(), [RECEIPT_MESSAGE, TYPING_MESSAGE])
+
+        # Turned off for everyone, neither leaves the client; a contact's own setting wins.
+        handler.set_privacy("receipts", False)
+        handler.set_privacy("typing", False)
+        handler.set_privacy("typing", True, self.friend_username)
+        self.assertEqual(await send_both(), [TYPING_MESSAGE])
+
+        # The settings are kept across sessions, and None follows the global one again.
+        handler.load_privacy()
+        self.assertEqual(handler.privacy, {"receipts": False, "typing": False})
+        self.assertEqual(handler.contact_privacy, {self.friend_username: {"receipts": None, "typing": True}})
+        handler.set_privacy("typing", None, self.friend_username)
+        self.assertEqual(await send_both(), [])
+        self.assertFalse(handler.sends_allowed("receipts", "alice"))
+
+    def test_delivery_and_read_receipts(self):
+        asyncio.run(self.async_test_delivery_and_read_receipts())
+
+    async def async_test_delivery_and_read_receipts(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"])))
+        self.connection_client.send_message = send_message
+
+        async def receive(message_obj):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope))
+
+        def statuses():
+            return [row[1:] for row in self.db_manager.get_message_statuses(self.username, self.friend_username)]
+
+        # A chat message goes out under its send id, and is stored with it.
+        send_id = await handler.send_direct_message(self.friend_username, "hi")
+        self.assertEqual(sent.pop()["id"], send_id)
+        self.assertEqual(statuses(), [(send_id, "pending")])
+        await handler.handle_send_response({"id": send_id, "status": "success"})
+        self.assertEqual(statuses(), [(send_id, "sent")])
+
+        # The contact's receipts move it on, never back; receipts naming nothing we sent them change nothing.
+        await receive({"type": 10, "message": {"ids": [send_id], "status": "read"}})
+        await receive({"type": 10, "message": {"ids": [send_id, "unknown"], "status": "delivered"}})
+        await receive({"type": 10, "message": "read"})
+        self.assertEqual((statuses(), handler.send_status[send_id]), ([(send_id, "read")], "read"))
+        self.assertNotIn("unknown", handler.send_status)
+        self.assertEqual(sent, [])
+        handler.send_status.clear()
+        self.assertEqual(handler.load_delivery(self.friend_username),
+                         {self.db_manager.get_message_statuses(self.username, self.friend_username)[0][0]: send_id})
+        self.assertEqual(handler.send_status, {send_id: "read"})
+
+        # A message in a closed chat is acknowledged at once, and read once its chat is opened.
+        await receive({"type": 0, "message": "hello", "id": "m1"})
+        await receive({"type": 0, "message": "no id"})
+        self.assertEqual([(m["type"], m["message"]) for m in sent],
+                         [(RECEIPT_MESSAGE, {"ids": ["m1"], "status": "delivered"})])
+        sent.clear()
+        handler.mark_read(self.friend_username)
+        await asyncio.sleep(0)
+        self.assertEqual(sent.pop()["message"], {"ids": ["m1"], "status": "read"})
+
+        # In the open chat it is read at once; without read receipts only its delivery is told.
+        handler._get_active_chat = lambda: self.friend_username
+        await receive({"type": 0, "message": "again", "id": "m2"})
+        self.assertEqual(sent.pop()["message"], {"ids": ["m2"], "status": "read"})
+        handler.set_privacy("receipts", False)
+        handler._get_active_chat = lambda: None
+        await receive({"type": 0, "message": "once more", "id": "m3"})
+        handler.mark_read(self.friend_username)
+        await asyncio.sleep(0)
+        self.assertEqual([m["message"] for m in sent], [{"ids": ["m3"], "status": "delivered"}])
+
+    def test_typing_indicators(self):
+        asyncio.run(self.async_test_typing_indicators())
+
+    async def async_test_typing_indicators(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"])))
+        self.connection_client.send_message = send_message
+
+        # Typing is told at most every TYPING_INTERVAL, never to groups nor while offline.
+        await handler.notify_typing(self.friend_username)
+        await handler.notify_typing(self.friend_username)
+        await handler.notify_typing("group:g1")
+        self.assertEqual([(m["type"], m["message"]) for m in sent], [(TYPING_MESSAGE, True)])
+        handler.typing_sent.clear()
+        handler.online = False
+        await handler.notify_typing(self.friend_username)
+        handler.online = True
+        handler.set_privacy("typing", False)
+        await handler.notify_typing(self.friend_username)
+        self.assertEqual(len(sent), 1)
+
+        async def receive(message_obj):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope))
+
+        # The contact shows as typing until their message arrives; nothing is stored.
+        await receive({"type": TYPING_MESSAGE, "message": True})
+        self.assertTrue(handler.is_typing(self.friend_username))
+        handler.flush_writes()
+        self.assertEqual(self.db_manager.load_messages(self.username, self.friend_username), [])
+        await receive({"type": 0, "message": "done typing"})
+        self.assertFalse(handler.is_typing(self.friend_username))
+        await receive({"type": TYPING_MESSAGE, "message": {"typing": "yes"}})
+        self.assertFalse(handler.is_typing(self.friend_username))
+
+    def test_file_transfer(self):
+        asyncio.run(self.async_test_file_transfer())
+
+    async def async_test_file_transfer(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"])))
+        self.connection_client.send_message = send_message
+
+        async def receive(message_obj):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            envelope = {"action": "incomingMessage", "context": "chat", "content": json.dumps({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })}
+            await handler.handle_incoming_message(json.dumps(envelope))
+
+        with tempfile.TemporaryDirectory() as folder:
+            handler.set_downloads_dir(os.path.join(folder, "downloads"))
+            source = os.path.join(folder, "report.pdf")
+            content = os.urandom(40000)
+            with open(source, "wb") as f:
+                f.write(content)
+
+            # Sent: the manifest like a chat message, then every chunk.
+            send_id = await handler.send_file(self.friend_username, source)
+            manifest, *chunks = sent
+            self.assertEqual((manifest["type"], manifest["id"]), (FILE_MESSAGE, send_id))
+            self.assertEqual((manifest["message"]["name"], manifest["message"]["mime"]), ("report.pdf", "application/pdf"))
+            self.assertEqual([chunk["type"] for chunk in chunks], [FILE_CHUNK_MESSAGE] * manifest["message"]["chunks"])
+            self.assertEqual(handler.chat_messages[self.friend_username][-1][1], "📎 report.pdf")
+            self.assertEqual(handler.transfers, {})
+            self.assertIsNone(await handler.send_file("group:g1", source))
+
+            # Received in any order, a chunk even before its manifest, and kept once whole.
+            await receive(chunks[-1])
+            await receive(manifest)
+            self.assertEqual(handler.transfers_in(self.friend_username)[0]["done"], 1)
+            for chunk in chunks[:-1]:
+                await receive(chunk)
+            self.assertEqual(handler.transfers, {})
+            with open(os.path.join(folder, "downloads", "report.pdf"), "rb") as f:
+                self.assertEqual(f.read(), content)
+            handler.flush_writes()
+            self.assertEqual(self.db_manager.load_messages(self.username, self.friend_username)[-1][:2],
+                             ("from", "📎 report.pdf"))
+
+            # A file that does not match its manifest is discarded.
+            forged = dict(manifest, message=dict(manifest["message"], sha256="00" * 32))
+            await receive(forged)
+            for chunk in chunks:
+                await receive(chunk)
+            handler.flush_writes()
+            self.assertIn("damaged", self.db_manager.load_messages(self.username, self.friend_username)[-1][1])
+            self.assertEqual(sorted(os.listdir(os.path.join(folder, "downloads"))), ["report.pdf"])
+
+    def test_contact_folders(self):
+        asyncio.run(self.async_test_contact_folders())
+
+    async def async_test_contact_folders(self):
+        handler = self.message_handler
+        self.assertTrue(handler.create_folder("Work"))
+        self.assertTrue(handler.create_folder(" Friends "))
+        self.assertFalse(handler.create_folder("Work"))
+        self.assertFalse(handler.create_folder("  "))
+        handler.move_to_folder("alice", "Work")
+        handler.move_to_folder("group:g1", "Work")
+        handler.move_to_folder("bob", "Friends")
+        handler.move_to_folder("bob", "Bots")  # no such folder
+        handler.set_folder_collapsed("Work", True)
+
+        # Messages in chats that are not open count as unread, rolled up per folder.
+        handler._update_chat_ui("alice", "one")
+        handler._update_chat_ui("alice", "two")
+        handler._update_chat_ui("carol", "three", "group:g1", "Team")
+        handler._update_chat_ui("bob", "four")
+        self.assertEqual(handler.unread, {"alice": 2, "group:g1": 1, "bob": 1})
+        self.assertEqual((handler.folder_unread("Work"), handler.folder_unread("Friends")), (3, 1))
+        handler.mark_read("alice")
+        self.assertEqual(handler.folder_unread("Work"), 1)
+
+        # Folders, their order, collapsed state and contents are kept across sessions.
+        self.assertFalse(handler.rename_folder("Work", "Friends"))
+        self.assertTrue(handler.rename_folder("Work", "Office"))
+        handler.load_folders()
+        self.assertEqual(list(handler.folders.items()), [("Office", True), ("Friends", False)])
+        self.assertEqual(handler.chat_folders, {"alice": "Office", "group:g1": "Office", "bob": "Friends"})
+        handler.move_to_folder("alice", None)
+        handler.delete_folder("Office")
+        handler.load_folders()
+        self.assertEqual((handler.folders, handler.chat_folders), ({"Friends": False}, {"bob": "Friends"}))
+
+    def test_notification_settings(self):
+        asyncio.run(self.async_test_notification_settings())
+
+    async def async_test_notification_settings(self):
+        handler = self.message_handler
+        shown = []
+        handler.new_message_callback = lambda sender, message: shown.append(message)
+        handler.mention_callback = lambda chat, sender, message: shown.append(f"@{message}")
+        handler.create_folder("Work")
+        for chat in ("alice", "bob", "group:g1"):
+            handler.move_to_folder(chat, "Work")
+
+        handler.mute("alice", 60 * 60)
+        handler.mute("bob")
+        handler.set_notification_level("group:g1", "mentions")
+        handler._update_chat_ui("alice", "muted")
+        handler._update_chat_ui("bob", "muted for good")
+        handler._update_chat_ui("carol", "plain", "group:g1", "Team")
+        handler._update_chat_ui("carol", "hi @testuser", "group:g1", "Team", mention=True)
+        handler._update_chat_ui("friend", "shown")
+        self.assertEqual(shown, ["@hi @testuser", "shown"])
+
+        # Quiet chats still count what they missed, but not towards their folder.
+        self.assertEqual(handler.unread, {"alice": 1, "bob": 1, "group:g1": 2, "friend": 1})
+        self.assertEqual(handler.folder_unread("Work"), 0)
+
+        # Settings are kept across sessions; a mute that ran out is over.
+        handler.load_notifications()
+        self.assertEqual(handler.muted_until("bob"), MUTE_FOREVER)
+        self.assertEqual(handler.notification_level("group:g1"), "mentions")
+        handler.chat_notifications["alice"]["mutedUntil"] = int(time.time()) - 1
+        self.assertEqual((handler.muted_until("alice"), handler.notification_level("alice")), (None, "all"))
+        handler.unmute("bob")
+        handler.set_notification_level("group:g1", "all")
+        handler.load_notifications()
+        self.assertEqual(list(handler.chat_notifications), ["alice"])
+        with self.assertRaises(ValueError):
+            handler.set_notification_level("bob", "loud")
+
+    def test_drafts_survive_restarts(self):
+        asyncio.run(self.async_test_drafts_survive_restarts())
+
+    async def async_test_drafts_survive_restarts(self):
+        handler = self.message_handler
+        handler.set_draft("alice", "half a thought")
+        handler.set_draft("group:g1", "typed, then")
+        handler.set_draft("group:g1", "   ")
+        self.assertEqual(handler.drafts, {"alice": "half a thought"})
+
+        # Nothing reaches the database until an autosave; then what was typed
+        # is back after a crash, and a cleared draft is gone.
+        self.assertEqual(self.db_manager.get_drafts(self.username), [])
+        handler.save_drafts()
+        handler.set_draft("alice", "half a thought, finished")
+        handler.save_drafts()
+        handler.load_drafts()
+        self.assertEqual(handler.drafts, {"alice": "half a thought, finished"})
+        handler.set_draft("alice", "")
+        handler.save_drafts()
+        handler.load_drafts()
+        self.assertEqual(handler.drafts, {})
+
+    def test_search_groups_hits_by_chat(self):
+        for text in ("lunch at noon?", "no lunch today", "ok"):
+            self.db_manager.save_message(self.username, "alice", "from", text)
+        self.db_manager.save_message(self.username, "bob", "to", "lunch lunch lunch")
+        results = self.message_handler.search_messages("lunch")
+        self.assertEqual(sorted(chat for chat, _hits in results), ["alice", "bob"])
+        alice = dict(results)["alice"]
+        self.assertEqual([hit["id"] for hit in alice], sorted(hit["id"] for hit in alice))
+        self.assertEqual({hit["sender"] for hit in dict(results)["bob"]}, {self.username})
+        self.assertEqual(self.message_handler.search_messages(" "), [])
+
+    def test_pending_pages(self):
+        asyncio.run(self.async_test_pending_pages())
+
+    async def async_test_pending_pages(self):
+        handler = self.message_handler
+        handled, sent = [], []
+
+        async def handle(content, sender_tag=None):
+            handled.append(content["n"])
+        handler.handle_incoming_message_content = handle
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"]))
+        self.connection_client.send_message = send_message
+
+        def page(ids, more):
+            messages = [{"messageId": str(i), "content": json.dumps({"n": i})} for i in ids]
+            return {"messages": messages, "cursor": ids[-1], "more": more}
+
+        # Each page is acked, and the next one asked for after its cursor.
+        await handler.handle_pending_page(page([3, 4], True))
+        self.assertEqual(handled, [3, 4])
+        self.assertEqual([(m["action"], m.get("ids"), m.get("after")) for m in sent],
+                         [("ack", ["3", "4"], None), ("fetchPending", None, 4)])
+
+        # Messages redelivered because an ack got lost are acked again, not handled twice.
+        sent.clear()
+        await handler.handle_pending_page(page([4, 7], False))
+        self.assertEqual(handled, [3, 4, 7])
+        self.assertEqual([(m["action"], m.get("ids")) for m in sent], [("ack", ["4", "7"])])
+
+    def test_handshakes(self):
+        asyncio.run(self.async_test_handshakes())
+
+    async def async_test_handshakes(self):
+        handler = self.message_handler
+        friend = self.friend_username
+        address = "8HVwZXW3oYbjzvyoNpnk.4B3K7xGsDNBHjFP2@Fo4f4SQLdoyoGkFae5Tp"
+        handler.update_nym_address(address)
+        handler.use_sealed_sender = True
+        sent = []
+
+        async def send_typed(recipient, message_type, message):
+            self.assertEqual((recipient, message_type), (friend, HANDSHAKE_MESSAGE))
+            sent.append(message)
+            return True
+        handler._send_typed = send_typed
+
+        def audited(event):
+            return [detail for _, name, detail, _ in handler.audit_log()[0] if name == event]
+
+        # We start: their reply must hash the hello we sent, and is confirmed with that hash.
+        theirs = HandshakeHello.create(["keyObservation"], address.replace("8", "9")).to_json()
+        await handler.send_handshake(friend)
+        ours = sent[-1]["hello"]
+        stripped = ours.replace(',"sealedSender"', "")
+        await handler._handle_handshake(friend, {"stage": "reply", "hello": theirs,
+                                                  "transcript": HandshakeHello.transcript(stripped, theirs)})
+        self.assertEqual((len(sent), audited("handshake_altered")), (1, [friend]))
+        self.assertIsNone(handler.contact_handshake(friend))
+
+        await handler.send_handshake(friend)
+        transcript = HandshakeHello.transcript(sent[-1]["hello"], theirs)
+        await handler._handle_handshake(friend, {"stage": "reply", "hello": theirs, "transcript": transcript})
+        self.assertEqual(sent[-1], {"stage": "confirm", "transcript": transcript})
+        self.assertEqual(handler.contact_handshake(friend), (2, ["keyObservation"]))
+        self.assertEqual(handler.nym_addresses[friend], address.replace("8", "9"))
+        self.assertFalse(handler._seals_for(friend))
+
+        # They start: our reply takes effect once their confirmation matches it.
+        theirs = HandshakeHello.create(["keyObservation", "sealedSender"]).to_json()
+        await handler._handle_handshake(friend, {"stage": "hello", "hello": theirs})
+        reply = sent[-1]
+        self.assertEqual(reply["transcript"], HandshakeHello.transcript(theirs, reply["hello"]))
+        await handler._handle_handshake(friend, {"stage": "confirm", "transcript": reply["transcript"]})
+        self.assertEqual(handler.contact_handshake(friend), (2, ["keyObservation", "sealedSender"]))
+        self.assertTrue(handler._seals_for(friend))
+
+        # After that, an address-only handshake is a downgrade, and weaker terms are noted.
+        handler._handle_legacy_handshake(friend, "other.address@gateway")
+        self.assertEqual(handler.nym_addresses[friend], address.replace("8", "9"))
+        handler._complete_handshake(friend, theirs, HandshakeHello.create([]).to_json(), theirs)
+        self.assertEqual(sorted(audited("handshake_downgrade")),
+                         [f"{friend}: keyObservation, sealedSender", f"{friend}: version 2 -> 1"])
+
+        # Chat messages wait until the user accepts the weaker terms; what is agreed on again needs no acceptance.
+        self.assertEqual(handler.downgrade(friend), ["keyObservation", "sealedSender"])
+        self.assertIsNone(await handler.send_direct_message(friend, "still there?"))
+        both = HandshakeHello.create(["keyObservation"]).to_json()
+        handler._complete_handshake(friend, both, both, both)
+        self.assertEqual(handler.encryption_mode(friend)["downgrade"], ["sealedSender"])
+        handler.accept_downgrade(friend)
+        self.assertEqual(audited("downgrade_accepted"), [f"{friend}: sealedSender"])
+        self.assertEqual(handler.encryption_mode(friend),
+                         {"encrypted": True, **DIRECT_ENCRYPTION, "route": "p2p", "sealed": False, "version": 2,
+                          "features": ["keyObservation"], "downgrade": []})
+        del handler.nym_addresses[friend]
+        self.assertEqual(handler.encryption_mode(friend)["route"], "server")
+
+    def test_contact_invites(self):
+        handler = self.message_handler
+        address = "8HVwZXW3oYbjzvyoNpnk.4B3K7xGsDNBHjFP2@Fo4f4SQLdoyoGkFae5Tp"
+        invite = ContactInvite.create("carol", self.friend_public_key_pem, address)
+
+        added = handler.add_contact_from_invite(invite.to_uri())
+        self.assertEqual(added.fingerprint, self.crypto_utils.fingerprint(self.friend_public_key_pem))
+        self.assertEqual(self.db_manager.get_contact(self.username, "carol")[1], self.friend_public_key_pem)
+        self.assertEqual(handler.nym_addresses["carol"], address)
+
+        # Damaged links and our own invite add nobody.
+        self.assertIsNone(handler.add_contact_from_invite(invite.to_uri()[:-30]))
+        self.assertIsNone(handler.add_contact_from_invite("carol"))
+        handler.update_nym_address(address)
+        own = handler.contact_invite()
+        self.assertTrue(own.endswith(address))
+        self.assertNotIn(address, handler.contact_invite(include_address=False))
+        self.assertIsNone(handler.add_contact_from_invite(own))
+
+        # The safety numbers the identity bar shows: ours, a contact's, and none for a stranger.
+        self.assertEqual(handler.fingerprint(), self.crypto_utils.fingerprint(self.public_key_pem))
+        self.assertEqual(handler.fingerprint("carol"), added.fingerprint)
+        self.assertIsNone(handler.fingerprint("nobody"))
+
+    def test_location_messages(self):
+        asyncio.run(self.async_test_location_messages())
+
+    async def async_test_location_messages(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        # Out of range, missing or non-numeric coordinates are not locations.
+        for bad in ({"lat": 91, "lon": 0}, {"lat": 0}, {"lat": "1", "lon": 2}, {"lat": True, "lon": 2},
+                    {"lat": 0, "lon": 0, "accuracy": -1}, {"lat": 0, "lon": 0, "label": "x" * 101}):
+            self.assertIsNone(parse_location(bad))
+            self.assertIsNone(await handler.send_location(self.friend_username, bad))
+        self.assertEqual(sent, [])
+
+        # A location reads back from its chat line, label and all.
+        location = parse_location({"lat": 52.5200066, "lon": -13.404954, "accuracy": 12.4, "label": " Cafe  (north) "})
+        self.assertEqual(location_text(location), "📍 Cafe (north) (52.520007, -13.404954) ±12 m")
+        self.assertEqual(location_from_text(location_text(location)), location)
+        self.assertIsNone(location_from_text("(52.52, 13.40)"))
+
+        # The friend receives it encrypted like a chat message, and it is kept as text.
+        self.assertIsNotNone(await handler.send_location(self.friend_username, location))
+        wrapped = json.loads(self.crypto_utils.decrypt_message(
+            self.friend_private_key, sent[-1]["body"]["encryptedPayload"]))
+        self.assertEqual((wrapped["type"], wrapped["message"]), (9, location))
+        stored = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertEqual(stored[-1][1], location_text(location))
+
+        # Incoming locations are checked before they are stored.
+        for message in ({"lat": 1.5, "lon": 2.5}, {"lat": 100, "lon": 2.5}):
+            plaintext = json.dumps({"type": 9, "message": message})
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, plaintext)
+            await handler.handle_incoming_message_content({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })
+        await asyncio.sleep(0)
+        stored = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertEqual([m[1] for m in stored[-2:]], [location_text(location), "📍 (1.500000, 2.500000)"])
+
+    def test_forwarded_messages(self):
+        asyncio.run(self.async_test_forwarded_messages())
+
+    async def async_test_forwarded_messages(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(json.loads(msg["message"])["content"]))
+        self.connection_client.send_message = send_message
+
+        # The friend is told who wrote it; a forwarded message keeps its first sender.
+        senders = []
+        for text in ("Meet at noon\nby the gate", forwarded_text("alice", "Meet at noon\nby the gate")):
+            shown, send_id = await handler.forward_message(self.friend_username, "carol", text)
+            self.assertIsNotNone(send_id)
+            wrapped = json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, sent[-1]["body"]["encryptedPayload"]))
+            self.assertEqual(wrapped["message"], "Meet at noon\nby the gate")
+            self.assertEqual(forwarded_from(shown), (wrapped["forwardedFrom"]["sender"], wrapped["message"]))
+            senders.append(wrapped["forwardedFrom"]["sender"])
+        self.assertEqual(senders, ["carol", "alice"])
+        self.assertEqual(self.db_manager.load_messages(self.username, self.friend_username)[-1][1],
+                         "↪ alice\nMeet at noon\nby the gate")
+
+        # Incoming forwards are marked, unless the sender named is not a username.
+        for forwarded in ({"sender": "dave"}, {"sender": "da ve\n"}, "dave"):
+            plaintext = json.dumps({"type": 0, "message": "hi", "forwardedFrom": forwarded})
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, plaintext)
+            await handler.handle_incoming_message_content({
+                "sender": self.friend_username,
+                "body": {
+                    "encryptedPayload": encrypted,
+                    "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted)),
+                },
+            })
+        await asyncio.sleep(0)
+        stored = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertEqual([m[1] for m in stored[-3:]], ["↪ dave\nhi", "hi", "hi"])
+
+    def test_end_session(self):
+        asyncio.run(self.async_test_end_session())
+
+    async def async_test_end_session(self):
+        self.message_handler.presence["alice"] = {"online": True, "lastSeen": None}
+        self.message_handler.nym_addresses["alice"] = "alice.address@gateway"
+        self.message_handler.send_status["1"] = "sent"
+
+        await self.message_handler.end_session()
+
+        # Nothing of the old account survives for the next one to log in to.
+        self.assertIsNone(self.message_handler.current_user["username"])
+        self.assertIsNone(self.message_handler.db_manager)
+        self.assertEqual(self.message_handler.presence, {})
+        self.assertEqual(self.message_handler.nym_addresses, {})
+        self.assertEqual(self.message_handler.send_status, {})
+
+
+    def test_inbound_pipeline(self):
+        asyncio.run(self.async_test_inbound_pipeline())
+
+    async def async_test_inbound_pipeline(self):
+        handler = self.message_handler
+        self.assertEqual(handler.inbound.names(),
+                         ["open", "verify", "decrypt", "dedupe", "ratchet", "control", "content", "persist", "emit",
+                          "receipt", "away"])
+
+        def incoming(text):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps({"type": 0, "message": text}))
+            signature = self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted))
+            body = {"encryptedPayload": encrypted, "payloadSignature": signature}
+            return {"sender": self.friend_username, "body": body}
+
+        # A plugin sees the final text before it is stored, and can change or drop it.
+        seen = []
+
+        def spam_filter(inbound):
+            seen.append((inbound.sender, inbound.kind))
+            if "lottery" in inbound.text:
+                return None
+            inbound.text = inbound.text.upper()
+            return inbound
+        handler.add_inbound_stage("spam", spam_filter)
+        self.assertEqual(handler.inbound.names()[-5:], ["spam", "persist", "emit", "receipt", "away"])
+        with self.assertRaises(ValueError):
+            handler.add_inbound_stage("spam", spam_filter)
+
+        hello = incoming("hello")
+        # The same signature with s flipped to n - s verifies as well.
+        r, s = decode_dss_signature(bytes.fromhex(hello["body"]["payloadSignature"]))
+        flipped = {**hello, "body": {**hello["body"],
+                                     "payloadSignature": encode_dss_signature(r, P256_ORDER - s).hex()}}
+        for content in (hello, incoming("You won the lottery"), hello, flipped):
+            await handler.handle_incoming_message_content(content)
+        await asyncio.sleep(0)
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)], ["HELLO"])
+        self.assertEqual(handler.chat_messages[self.friend_username][-1][1], "HELLO")
+        # The repeated deliveries never got past dedupe.
+        self.assertEqual(seen, [(self.friend_username, "text")] * 2)
+
+        # Stages work on their own, on a message built by hand.
+        stage = dedupe(window=2)
+        for payload, kept in (("a", True), ("b", True), ("a", False), ("c", True), ("b", True)):
+            self.assertEqual(stage(Inbound({}, encrypted_payload={"ciphertext": payload})) is not None, kept, payload)
+        dropped = await handler._open_stage(Inbound({"sender": "x", "body": {"encryptedPayload": {}}}))
+        self.assertIsNone(dropped)
+
+
+    def test_delegated_keys(self):
+        asyncio.run(self.async_test_delegated_keys())
+
+    async def async_test_delegated_keys(self):
+        handler = self.message_handler
+        bot_key, bot_pem = self.crypto_utils.generate_key_pair("bot")
+
+        def delegation(signing_key, expires_at=None):
+            certificate = Delegation.create(self.friend_username, bot_pem, ["send"],
+                                            expires_at or int(time.time()) + 3600).to_json()
+            return {"certificate": certificate, "signature": self.crypto_utils.sign_message(signing_key, certificate)}
+        valid = delegation(self.friend_private_key)
+
+        def incoming(key, message_type, message, delegated=None):
+            plaintext = json.dumps({"type": message_type, "message": message})
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, plaintext)
+            signature = self.crypto_utils.sign_message(key, json.dumps(encrypted))
+            body = {"encryptedPayload": encrypted, "payloadSignature": signature}
+            content = {"sender": self.friend_username, "body": body}
+            if delegated:
+                content["delegation"] = delegated
+            return content
+
+        def received():
+            handler.flush_writes()
+            return [m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)]
+
+        # The friend's bot chats under a delegation their identity key signed...
+        await handler.handle_incoming_message_content(incoming(bot_key, 0, "build passed", valid))
+        self.assertEqual(received(), ["build passed"])
+        # ...but cannot speak for the account, nor sign for itself or past its expiry.
+        await handler.handle_incoming_message_content(incoming(bot_key, 2, "", valid))
+        await handler.handle_incoming_message_content(incoming(bot_key, 0, "forged", delegation(bot_key)))
+        expired = delegation(self.friend_private_key, int(time.time()) - 1)
+        await handler.handle_incoming_message_content(incoming(bot_key, 0, "stale", expired))
+        await handler.handle_incoming_message_content(incoming(bot_key, REVOCATION_MESSAGE, {"id": "x"}, valid))
+        self.assertEqual(received(), ["build passed"])
+
+        # Once the friend revokes it, the bot is refused.
+        delegation_id = json.loads(valid["certificate"])["id"]
+        await handler.handle_incoming_message_content(
+            incoming(self.friend_private_key, REVOCATION_MESSAGE, {"id": delegation_id}))
+        await handler.handle_incoming_message_content(incoming(bot_key, 0, "after revocation", valid))
+        self.assertEqual(received(), ["build passed"])
+
+        # On the bot's side: sends are sealed and carry the delegation; the
+        # account itself is out of reach.
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"]))
+        self.connection_client.send_message = send_message
+        handler.delegation = valid
+        await handler.send_direct_message(self.friend_username, "hi")
+        self.assertEqual(sent[0]["action"], "sealedSend")
+        opened = json.loads(self.crypto_utils.decrypt_message(self.friend_private_key,
+                                                              json.loads(sent[0]["content"])["sealed"]))
+        self.assertEqual(opened["delegation"], valid)
+        self.assertNotIn("senderPublicKey", opened)
+        self.assertFalse(await handler.delete_account())
+        with self.assertRaises(RuntimeError):
+            handler.export_delegation("unused", "passphrase")
+        self.assertEqual(len(sent), 1)
+
+
+    def test_backup_chunks_are_checked(self):
+        asyncio.run(self.async_test_backup_chunks_are_checked())
+
+    async def async_test_backup_chunks_are_checked(self):
+        handler = self.message_handler
+        future = asyncio.get_running_loop().create_future()
+        handler.pending_restore = {"keys": {"id": "b1"}, "chunks": {}, "future": future}
+
+        async def chunk(index, total, data="x"):
+            await handler.handle_backup_chunk({"backupId": "b1", "index": index, "total": total, "data": data})
+
+        await chunk(0, 10 ** 9)
+        await chunk(1, 2)
+        await chunk(0, 3)  # The first chunk said there are 2.
+        await chunk(5, 2)
+        await chunk(-1, 2)
+        await chunk(0, 2, data=None)
+        self.assertFalse(future.done())
+        await chunk(0, 2, data="a")
+        self.assertEqual(await future, ["a", "x"])
+
+    def test_account_bundles_keep_key_trust(self):
+        handler = self.message_handler
+        self.db_manager.set_verified(self.username, self.friend_username, True)
+        self.db_manager.record_key_change(self.username, "alice", "public_key_alice2", "aa", "bb")
+
+        bundle = handler._account_bundle(include_history=False)
+        bundle["username"] = "restored"
+        self.assertEqual(handler._restore_bundle(bundle), "restored")
+        restored = PyDb.open("restored", self.crypto_utils.storage_dir)
+        try:
+            self.assertTrue(restored.is_verified("restored", self.friend_username))
+            self.assertFalse(restored.is_trusted("restored", "alice"))
+            self.assertTrue(restored.is_trusted("restored", "bob"))
+            self.assertEqual([change[:2] for change in restored.get_key_changes("restored", "alice")], [("aa", "bb")])
+            self.assertTrue(restored.reject_key_change("restored", "alice"))
+            self.assertEqual(restored.get_contact("restored", "alice")[1], "public_key_alice")
+        finally:
+            restored.close()
+            self.crypto_utils.wipe_user_data("restored")
+
+    def test_failed_writes_are_kept_and_retried(self):
+        asyncio.run(self.async_test_failed_writes_are_kept_and_retried())
+
+    async def async_test_failed_writes_are_kept_and_retried(self):
+        handler = self.message_handler
+
+        class FailingDb:
+            def __init__(self, db):
+                self.db = db
+
+            def save_messages(self, active_user, batch):
+                raise OSError("disk full")
+
+            def __getattr__(self, name):
+                return getattr(self.db, name)
+
+        handler.db_manager = FailingDb(self.db_manager)
+        handler._store_message(self.friend_username, "first")
+        handler.flush_writes()
+        handler._store_message(self.friend_username, "second")
+        self.assertIsNotNone(handler.write_retry)
+        self.assertEqual([write[2] for write in handler.pending_writes], ["first", "second"])
+
+        handler.db_manager = self.db_manager
+        handler.flush_writes()
+        self.assertIsNone(handler.write_retry)
+        self.assertEqual(handler.pending_writes, [])
+        messages = self.db_manager.load_messages(self.username, self.friend_username)
+        self.assertEqual([m[1] for m in messages][-2:], ["first", "second"])
+
+    def test_query_misses_answer_the_query_they_name(self):
+        asyncio.run(self.async_test_query_misses_answer_the_query_they_name())
+
+    async def async_test_query_misses_answer_the_query_they_name(self):
+        handler = self.message_handler
+
+        async def send_message(msg):
+            pass
+        self.connection_client.send_message = send_message
+
+        carol = asyncio.create_task(handler.query_user("carol"))
+        dave = asyncio.create_task(handler.query_user("dave"))
+        await asyncio.sleep(0)
+
+        # Without the echo, a miss can't tell the two queries apart.
+        await handler.handle_query_response("No user found")
+        await handler.handle_query_response("No user found", "dave")
+        self.assertEqual(await dave, "No user found")
+        self.assertFalse(carol.done())
+
+        # With one query left, an unnamed miss can only be its answer.
+        await handler.handle_query_response("No user found")
+        self.assertEqual(await carol, "No user found")
+
+    def test_contacts_hear_of_a_deletion_only_once_the_server_takes_it(self):
+        asyncio.run(self.async_test_contacts_hear_of_a_deletion_only_once_the_server_takes_it())
+
+    async def async_test_contacts_hear_of_a_deletion_only_once_the_server_takes_it(self):
+        handler = self.message_handler
+        events = []
+
+        async def send_typed(recipient, message_type, message):
+            events.append((recipient, message_type))
+            return True
+        handler._send_typed = send_typed
+
+        async def send_message(msg):
+            events.append(json.loads(msg["message"])["action"])
+        self.connection_client.send_message = send_message
+
+        # Refused: nobody is told.
+        deletion = asyncio.create_task(handler.delete_account())
+        await asyncio.sleep(0)
+        await handler.handle_deletion_response("error: refused")
+        self.assertFalse(await deletion)
+        self.assertEqual(events, ["deleteAccount"])
+
+        # Taken: every contact is told before the challenge is answered.
+        events.clear()
+        deletion = asyncio.create_task(handler.delete_account())
+        await asyncio.sleep(0)
+        await handler.handle_deletion_challenge({"nonce": "abc"})
+        await handler.handle_deletion_response("error: late failure")
+        self.assertFalse(await deletion)
+        self.assertEqual(events[0], "deleteAccount")
+        self.assertEqual(sorted(events[1:-1]), [("alice", 2), ("bob", 2), (self.friend_username, 2)])
+        self.assertEqual(events[-1], "deleteAccountResponse")
+
+    def test_quarantine(self):
+        asyncio.run(self.async_test_quarantine())
+
+    async def async_test_quarantine(self):
+        handler = self.message_handler
+        carol_key, carol_pem = self.crypto_utils.generate_key_pair("carol")
+        notices = []
+        handler.quarantine_callback = lambda reason, count: notices.append((reason, count))
+
+        def incoming(sender, key, text):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps({"type": 0, "message": text}))
+            signature = self.crypto_utils.sign_message(key, json.dumps(encrypted))
+            return {"sender": sender, "body": {"encryptedPayload": encrypted, "payloadSignature": signature}}
+
+        # What cannot be read or verified is kept with why, not dropped.
+        await handler.handle_incoming_message_content(incoming("carol", carol_key, "hi, it's carol"))
+        await handler.handle_incoming_message_content(incoming(self.friend_username, carol_key, "forged"))
+        await handler.handle_incoming_message("not json")
+        self.assertEqual(notices, [("no sender key", 1), ("invalid signature", 2), ("unparseable envelope", 3)])
+        kept = handler.quarantined()
+        self.assertEqual([(sender, reason) for _id, sender, reason, _content, _at in kept],
+                         [(None, "unparseable envelope"), (self.friend_username, "invalid signature"),
+                          ("carol", "no sender key")])
+
+        # Once carol's key is known, her message goes through on a retry.
+        self.db_manager.add_contact(self.username, "carol", carol_pem)
+        self.assertTrue(await handler.retry_quarantined(kept[2][0]))
+        self.assertFalse(await handler.retry_quarantined(kept[2][0]))
+        handler.flush_writes()
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, "carol")], ["hi, it's carol"])
+
+        # The forgery fails again and is quarantined anew; the rest can be deleted.
+        await handler.retry_quarantined(kept[1][0])
+        self.assertEqual([row[2] for row in handler.quarantined()], ["invalid signature", "unparseable envelope"])
+        handler.delete_quarantined(kept[0][0])
+        self.assertEqual(len(handler.quarantined()), 1)
+        handler.clear_quarantine()
+        self.assertEqual(handler.quarantined(), [])
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)], [])
+
+    def test_snapshot_survives_a_crash(self):
+        asyncio.run(self.async_test_snapshot_survives_a_crash())
+
+    async def async_test_snapshot_survives_a_crash(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"]))["message"])
+        self.connection_client.send_message = send_message
+
+        # One message is acknowledged, one is not; a group history is half in.
+        acked = await handler.send_direct_message(self.friend_username, "acked")
+        await handler.send_direct_message(self.friend_username, "in flight")
+        await handler.handle_send_response({"id": acked, "status": "success"})
+        part = {"groupId": "g1", "historyId": "h1", "index": 0, "total": 2,
+                "messages": [{"sender": "alice", "message": "old", "timestamp": "2024-07-01 10:00:00"}]}
+        await handler._handle_group_history("alice", part)
+        handler.handshakes["bob"] = {"initiator": "hello", "responder": None}
+        handler.save_snapshot()
+        written = self.db_manager.get_setting(self.username, "snapshot")
+        handler.save_snapshot()
+        self.assertEqual(self.db_manager.get_setting(self.username, "snapshot"), written)
+
+        # The process dies; the next one sends the unacknowledged message again.
+        restarted = MessageHandler(crypto_utils=self.crypto_utils, connection_client=self.connection_client)
+        restarted.current_user["username"] = self.username
+        restarted.db_manager = self.db_manager
+        restarted.use_sealed_sender = False
+        restarted.restore_snapshot()
+        self.assertEqual(restarted.pending_histories[("alice", "h1")]["parts"], {0: part["messages"]})
+        self.assertEqual(restarted.handshakes, {"bob": {"initiator": "hello", "responder": None}})
+        restarted.load_outbox()
+        while restarted.outbox_pending:
+            await asyncio.sleep(0)
+        self.assertEqual(sent, ["acked", "in flight", "in flight"])
+        # Restoring again queues nothing twice.
+        restarted.restore_snapshot()
+        self.assertEqual(self.db_manager.get_outbox(self.username), [])
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)],
+                         ["acked", "in flight"])
+
+    def test_away_mode(self):
+        asyncio.run(self.async_test_away_mode())
+
+    async def async_test_away_mode(self):
+        handler = self.message_handler
+        handler.use_sealed_sender = False
+        sent = []
+
+        async def send_message(msg):
+            payload = json.loads(json.loads(msg["message"])["content"])
+            sent.append(json.loads(self.crypto_utils.decrypt_message(
+                self.friend_private_key, payload["body"]["encryptedPayload"])))
+        self.connection_client.send_message = send_message
+
+        async def receive(text, **extra):
+            plaintext = json.dumps({"type": 0, "message": text, **extra})
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, plaintext)
+            signature = self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted))
+            body = {"encryptedPayload": encrypted, "payloadSignature": signature}
+            await handler.handle_incoming_message_content({"sender": self.friend_username, "body": body})
+
+        await receive("are you there?")
+        self.assertEqual(sent, [])
+
+        # Away, the first message is answered; more within the interval are not.
+        handler.set_away("  Back on Monday  ", interval=3600)
+        await receive("hello?")
+        await receive("anyone?")
+        self.assertEqual(sent, [{"type": 0, "message": "Back on Monday", "autoReply": True}])
+        handler.flush_writes()
+        self.assertEqual([m[1] for m in self.db_manager.load_messages(self.username, self.friend_username)],
+                         ["are you there?", "hello?", "Back on Monday", "anyone?"])
+        self.assertEqual(handler.chat_messages[self.friend_username][-2][1], "Back on Monday")
+
+        # Another away message is never answered, nor is a contact opted out.
+        handler.away_replied.clear()
+        await receive("I am away too", autoReply=True)
+        handler.set_away_opt_out(self.friend_username, True)
+        await receive("hello again?")
+        self.assertEqual(len(sent), 1)
+
+        # Both survive a restart; turning it off stops the answers.
+        restarted = MessageHandler(crypto_utils=self.crypto_utils, connection_client=self.connection_client)
+        restarted.current_user["username"] = self.username
+        restarted.db_manager = self.db_manager
+        restarted.load_away()
+        self.assertEqual(restarted.away, {"message": "Back on Monday", "interval": 3600})
+        self.assertEqual(restarted.away_opt_out, {self.friend_username})
+        handler.set_away_opt_out(self.friend_username, False)
+        handler.set_away("")
+        await receive("back yet?")
+        self.assertEqual(len(sent), 1)
+        restarted.load_away()
+        self.assertIsNone(restarted.away)
+
+
+    def test_heartbeats(self):
+        asyncio.run(self.async_test_heartbeats())
+
+    async def async_test_heartbeats(self):
+        handler = self.message_handler
+        sent = []
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"]))
+        self.connection_client.send_message = send_message
+
+        # Logging in starts them, each at a jittered time.
+        await handler.handle_session({"ttl": 3600})
+        delay = handler.next_heartbeat.when() - asyncio.get_running_loop().time()
+        self.assertTrue(HEARTBEAT_INTERVAL * (1 - HEARTBEAT_JITTER) - 1 <= delay
+                        <= HEARTBEAT_INTERVAL * (1 + HEARTBEAT_JITTER))
+        await handler.heartbeat()
+        self.assertEqual(sent, [{"action": "heartbeat", "username": self.username}])
+        handler.online = False
+        await handler.heartbeat()
+        self.assertEqual(len(sent), 1)
+        handler.online = True
+
+        # Turned off, the server is told so, and nothing more is sent, also after a restart.
+        await handler.set_heartbeats(False)
+        self.assertEqual(sent[-1], {"action": "heartbeat", "username": self.username, "enabled": False})
+        self.assertIsNone(handler.next_heartbeat)
+        await handler.heartbeat()
+        self.assertEqual(len(sent), 2)
+        handler.send_heartbeats = True
+        handler.load_privacy()
+        self.assertFalse(handler.send_heartbeats)
+        await handler.set_heartbeats(True)
+        self.assertEqual(sent[-1], {"action": "heartbeat", "username": self.username})
+        self.assertIsNotNone(handler.next_heartbeat)
+        handler._clear_session()
+        self.assertIsNone(handler.next_heartbeat)
+
+
+    def test_compact_envelopes(self):
+        asyncio.run(self.async_test_compact_envelopes())
+
+    async def async_test_compact_envelopes(self):
+        handler = self.message_handler
+        friend = self.friend_username
+        handler.use_sealed_sender = False
+        handler.nym_addresses[friend] = "friend.address@gateway"
+        sent = []
+
+        async def send_message(msg):
+            sent.append(msg["message"])
+        self.connection_client.send_message = send_message
+
+        # JSON until a handshake agrees on the compact envelope.
+        await handler.send_direct_message(friend, "first")
+        hello = HandshakeHello.create(["keyObservation", COMPACT_FEATURE]).to_json()
+        handler._complete_handshake(friend, hello, hello, hello)
+        await handler.send_direct_message(friend, "second")
+        self.assertEqual((sent[0][0], sent[1][0]), ("{", "~"))
+        decoded = decode_envelope(sent[1])
+        self.assertLess(len(sent[1]), len(decoded) - 300)
+        self.assertEqual(json.loads(json.loads(decoded)["content"])["recipient"], friend)
+
+        # What they send compact reads like JSON; a damaged one is quarantined.
+        encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps({"type": 0, "message": "hi"}))
+        payload = json.dumps({"sender": friend, "recipient": self.username, "body": {
+            "encryptedPayload": encrypted,
+            "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted))},
+            "encrypted": True})
+        incoming = MixnetMessage.directMessage(payload, self.crypto_utils.sign_message(self.friend_private_key,
+                                                                                         payload))["message"]
+        compact = encode_envelope(incoming)
+        await handler.handle_incoming_message(compact)
+        handler.flush_writes()
+        self.assertEqual(self.db_manager.load_messages(self.username, friend)[-1][1], "hi")
+        await handler.handle_incoming_message(compact[:20])
+        self.assertEqual([row[2] for row in handler.quarantined()], ["unparseable envelope"])
+
+    def test_double_ratchet(self):
+        asyncio.run(self.async_test_double_ratchet())
+
+    async def async_test_double_ratchet(self):
+        handler = self.message_handler
+        friend = self.friend_username
+        handler.use_sealed_sender = False
+        handler.nym_addresses[friend] = "friend.address@gateway"
+        handshakes, sent = [], []
+
+        async def send_typed(recipient, message_type, message):
+            handshakes.append(message)
+            return True
+        handler._send_typed = send_typed
+
+        async def send_message(msg):
+            sent.append(json.loads(msg["message"]))
+        self.connection_client.send_message = send_message
+
+        # A handshake agreeing on it sets up a session from the ratchet keys of both hellos.
+        await handler.send_handshake(friend)
+        ours = handshakes[-1]["hello"]
+        secret, ratchet_key = RatchetSession.key_pair()
+        theirs = HandshakeHello.create([RATCHET_FEATURE], None, ratchet_key).to_json()
+        await handler._handle_handshake(friend, {"stage": "reply", "hello": theirs,
+                                                  "transcript": HandshakeHello.transcript(ours, theirs)})
+        self.assertEqual(handler.encryption_mode(friend)["ratchet"], DOUBLE_RATCHET)
+        friend_pem = self.friend_private_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        ).decode()
+        session = RatchetSession.respond(friend_pem, secret, self.public_key_pem,
+                                         HandshakeHello.parse(ours).ratchet_key)
+
+        # What we send is a ratchet message inside the usual encryption.
+        await handler.send_direct_message(friend, "forward secret")
+        encrypted = json.loads(sent[-1]["content"])["body"]["encryptedPayload"]
+        inner = json.loads(self.crypto_utils.decrypt_message(self.friend_private_key, encrypted))
+        self.assertEqual(inner["type"], RATCHET_MESSAGE)
+        self.assertEqual(json.loads(session.decrypt(json.dumps(inner["message"])))["message"], "forward secret")
+
+        # Their answer opens with our session; its message key only once, however it is wrapped.
+        answer = {"type": RATCHET_MESSAGE,
+                  "message": json.loads(session.encrypt(json.dumps({"type": 0, "message": "got it"})))}
+
+        def incoming():
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(answer))
+            return {"sender": friend, "recipient": self.username, "body": {
+                "encryptedPayload": encrypted,
+                "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted))},
+                "encrypted": True}
+        await handler.handle_incoming_message_content(incoming())
+        await handler.handle_incoming_message_content(incoming())
+        handler.flush_writes()
+        self.assertEqual(self.db_manager.load_messages(self.username, friend)[-1][1], "got it")
+        self.assertEqual([row[2] for row in handler.quarantined()], ["ratchet decryption failed"])
+
+        # A handshake without it drops the session.
+        plain = HandshakeHello.create([]).to_json()
+        handler._complete_handshake(friend, plain, plain, plain)
+        self.assertEqual(handler.encryption_mode(friend)["ratchet"], DIRECT_ENCRYPTION["ratchet"])
+
+    def test_inbound_messages_are_handled_in_order(self):
+        asyncio.run(self.async_test_inbound_messages_are_handled_in_order())
+
+    async def async_test_inbound_messages_are_handled_in_order(self):
+        handler = self.message_handler
+        friend = self.friend_username
+        replies = []
+
+        async def send_typed(recipient, message_type, message):
+            replies.append(message)
+            return True
+        handler._send_typed = send_typed
+        friend_pem = self.friend_private_key.private_bytes(
+            encoding=serialization.Encoding.PEM,
+            format=serialization.PrivateFormat.PKCS8,
+            encryption_algorithm=serialization.NoEncryption(),
+        ).decode()
+
+        # The friend starts a handshake, and we answer it.
+        secret, ratchet_key = RatchetSession.key_pair()
+        theirs = HandshakeHello.create([RATCHET_FEATURE], None, ratchet_key).to_json()
+        await handler._handle_handshake(friend, {"stage": "hello", "hello": theirs})
+        ours = replies[-1]["hello"]
+        session = RatchetSession.initiate(friend_pem, secret, self.public_key_pem,
+                                          HandshakeHello.parse(ours).ratchet_key)
+
+        def incoming(message_obj):
+            encrypted = self.crypto_utils.encrypt_message(self.public_key_pem, json.dumps(message_obj))
+            return {"sender": friend, "recipient": self.username, "body": {
+                "encryptedPayload": encrypted,
+                "payloadSignature": self.crypto_utils.sign_message(self.friend_private_key, json.dumps(encrypted))},
+                "encrypted": True}
+        confirm = incoming({"type": HANDSHAKE_MESSAGE, "message": {
+            "stage": "confirm", "transcript": HandshakeHello.transcript(theirs, ours)}})
+        first = incoming({"type": RATCHET_MESSAGE,
+                          "message": json.loads(session.encrypt(json.dumps({"type": 0, "message": "right after"})))})
+
+        # Their confirmation and first ratchet message arrive back to back; the
+        # session the confirmation sets up is there for the message.
+        await asyncio.gather(handler.handle_incoming_message_content(confirm),
+                             handler.handle_incoming_message_content(first))
+        handler.flush_writes()
+        self.assertEqual(handler.quarantined(), [])
+        self.assertEqual(self.db_manager.load_messages(self.username, friend)[-1][1], "right after")
         
 if __name__ == "__main__":
     unittest.main()
@@ -0,0 +1,43 @@
+import json
+import os
+import tempfile
+import unittest
+import quickReplies
+
+
+class TestQuickReplies(unittest.TestCase):
+    def setUp(self):
+        fd, self.path = tempfile.mkstemp(suffix=".json")
+        with os.fdopen(fd, "w") as f:
+            json.dump({"macros": {";brb": "be right back", ";b": "bye", "two words": "no", ";n": 1},
+                       "replies": ["On my way!", "Can I call you later?", "", 3]}, f)
+        quickReplies.configure(self.path)
+
+    def tearDown(self):
+        os.remove(self.path)
+        quickReplies.configure(os.devnull + ".missing")
+
+    def test_macros_expand_as_whole_words(self):
+        self.assertEqual(quickReplies.macros, {";brb": "be right back", ";b": "bye"})
+        self.assertEqual(quickReplies.expand("ok ;brb"), "ok be right back")
+        self.assertEqual(quickReplies.expand("x;brb ;brbs"), "x;brb ;brbs")
+        # While typing, a macro at the end may be the start of a longer one.
+        self.assertEqual(quickReplies.expand("ok ;b", complete=False), "ok ;b")
+        self.assertEqual(quickReplies.expand("ok ;b ", complete=False), "ok bye ")
+        self.assertEqual(quickReplies.expand(";brb\nthen", complete=False), "be right back\nthen")
+
+    def test_replies_are_filtered(self):
+        self.assertEqual(quickReplies.replies, ["On my way!", "Can I call you later?"])
+        self.assertEqual(quickReplies.matching("CALL"), ["Can I call you later?"])
+        self.assertEqual(quickReplies.matching(""), quickReplies.replies)
+
+    def test_a_broken_file_leaves_none(self):
+        with open(self.path, "w") as f:
+            f.write("{not json")
+        quickReplies.configure(self.path)
+        self.assertEqual((quickReplies.macros, quickReplies.replies), ({}, []))
+        self.assertEqual(quickReplies.expand(";brb"), ";brb")
+
+
+if __name__ == "__main__":
+    unittest.main()
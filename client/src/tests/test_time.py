@@ -0,0 +1,43 @@
+import unittest
+from datetime import datetime, timezone
+import timeUtils
+
+
+class TestTimeUtils(unittest.TestCase):
+    def tearDown(self):
+        timeUtils.configure("UTC", "24h")
+
+    def test_stored_times_are_shown_in_the_display_zone(self):
+        timeUtils.configure("America/New_York", "24h")
+        self.assertEqual(timeUtils.format_stamp("2024-07-01 18:30:00"), "2024-07-01 14:30")
+        timeUtils.configure("Asia/Kolkata", "12h")
+        self.assertEqual(timeUtils.format_stamp("2024-07-01 18:30:00"), "2024-07-02 12:00 AM")
+        self.assertEqual(timeUtils.format_unix(0), "1970-01-01 5:30 AM")
+
+    def test_relative_times(self):
+        timeUtils.configure("UTC", "relative")
+        now = datetime(2024, 7, 1, 12, 0, tzinfo=timezone.utc)
+        self.assertEqual(timeUtils.format_stamp("2024-07-01 11:59:30", now), "just now")
+        self.assertEqual(timeUtils.format_stamp("2024-07-01 11:55:00", now), "5m ago")
+        self.assertEqual(timeUtils.format_stamp("2024-07-01 09:00:00", now), "3h ago")
+        self.assertEqual(timeUtils.format_stamp("2024-06-29 12:00:00", now), "2d ago")
+        self.assertEqual(timeUtils.format_stamp("2024-06-01 12:00:00", now), "2024-06-01")
+
+    def test_new_stamps_are_utc(self):
+        moment = datetime(2024, 7, 1, 20, 0, tzinfo=timezone.utc)
+        self.assertEqual(timeUtils.utc_stamp(moment.astimezone()), "2024-07-01 20:00:00")
+        self.assertEqual(timeUtils.parse_stamp("2024-07-01 20:00:00"), moment)
+
+    def test_durations(self):
+        self.assertEqual(timeUtils.format_duration(42.7), "0:00:42")
+        self.assertEqual(timeUtils.format_duration(97385), "27:03:05")
+
+    def test_bad_settings_and_stamps_fall_back(self):
+        timeUtils.configure("Not/AZone", "36h")
+        self.assertIsNone(timeUtils.display_zone)
+        self.assertEqual(timeUtils.time_format, "24h")
+        self.assertEqual(timeUtils.format_stamp("yesterday"), "yesterday")
+
+
+if __name__ == "__main__":
+    unittest.main()
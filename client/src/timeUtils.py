@@ -0,0 +1,110 @@
+"""
+Display of message times.
+
+Timestamps are stored in UTC as "YYYY-MM-DD HH:MM:SS", the format SQLite's
+CURRENT_TIMESTAMP uses, and converted only when shown:
+- NYMCHAT_TIMEZONE: an IANA zone such as "Europe/Berlin"; the system's
+  local zone by default.
+- NYMCHAT_TIME_FORMAT: "24h" (default), "12h", or "relative" for "5m ago"
+  style times that turn into dates after a week.
+"""
+import os
+from datetime import datetime, timezone
+from zoneinfo import ZoneInfo, ZoneInfoNotFoundError
+from i18n import _
+from logUtils import logger
+
+STORED_FORMAT = "%Y-%m-%d %H:%M:%S"
+TIME_FORMATS = ("24h", "12h", "relative")
+
+display_zone = None  # None: the system's local zone
+time_format = "24h"
+
+
+def configure(zone_name=None, fmt=None):
+    """
+    Sets the display zone and format, by default from NYMCHAT_TIMEZONE and
+    NYMCHAT_TIME_FORMAT; unknown values fall back to the defaults.
+    """
+    global display_zone, time_format
+    zone_name = zone_name or os.getenv("NYMCHAT_TIMEZONE")
+    fmt = fmt or os.getenv("NYMCHAT_TIME_FORMAT")
+    display_zone = None
+    if zone_name:
+        try:
+            display_zone = ZoneInfo(zone_name)
+        except (ZoneInfoNotFoundError, ValueError):
+            logger.warning(f"Unknown time zone {zone_name!r}; using the system's.")
+    if fmt and fmt not in TIME_FORMATS:
+        logger.warning(f"Unknown time format {fmt!r}; use one of {', '.join(TIME_FORMATS)}.")
+    time_format = fmt if fmt in TIME_FORMATS else "24h"
+
+
+def utc_stamp(moment=None):
+    """ `moment` (default: now) in the stored format. """
+    moment = moment or datetime.now(timezone.utc)
+    return moment.astimezone(timezone.utc).strftime(STORED_FORMAT)
+
+
+def parse_stamp(stamp):
+    """ A stored timestamp as an aware datetime, or None if it is not one. """
+    try:
+        return datetime.strptime(stamp, STORED_FORMAT).replace(tzinfo=timezone.utc)
+    except (TypeError, ValueError):
+        return None
+
+
+def local(moment):
+    """ `moment` in the display zone. """
+    return moment.astimezone(display_zone)
+
+
+def _absolute(moment, with_date=True):
+    moment = local(moment)
+    clock = moment.strftime("%I:%M %p").lstrip("0") if time_format == "12h" else moment.strftime("%H:%M")
+    return f"{moment.strftime('%Y-%m-%d')} {clock}" if with_date else clock
+
+
+def _relative(moment, now):
+    seconds = (now - moment).total_seconds()
+    if seconds < 60:
+        return _("just now")
+    if seconds < 60 * 60:
+        return _("{minutes}m ago", minutes=int(seconds // 60))
+    if seconds < 24 * 60 * 60:
+        return _("{hours}h ago", hours=int(seconds // 3600))
+    if seconds < 7 * 24 * 60 * 60:
+        return _("{days}d ago", days=int(seconds // 86400))
+    return local(moment).strftime("%Y-%m-%d")
+
+
+def format_moment(moment, now=None):
+    """ An aware datetime as shown in the UI, under the configured zone and format. """
+    if time_format == "relative":
+        return _relative(moment, now or datetime.now(timezone.utc))
+    return _absolute(moment)
+
+
+def format_stamp(stamp, now=None):
+    """ A stored timestamp as shown in the UI; anything unparseable is shown as is. """
+    moment = parse_stamp(stamp)
+    return stamp if moment is None else format_moment(moment, now)
+
+
+def format_unix(seconds, now=None):
+    """ Unix seconds as shown in the UI. """
+    return format_moment(datetime.fromtimestamp(seconds, timezone.utc), now)
+
+
+def format_duration(seconds):
+    """ A span of time as hours:minutes:seconds, e.g. 27:03:05. """
+    minutes, seconds = divmod(int(seconds), 60)
+    hours, minutes = divmod(minutes, 60)
+    return f"{hours}:{minutes:02}:{seconds:02}"
+
+
+def clock_time(moment=None):
+    """ Time of day in the display zone, with seconds, for log-style output. """
+    moment = local(moment or datetime.now(timezone.utc))
+    return moment.strftime("%I:%M:%S %p") if time_format == "12h" else moment.strftime("%H:%M:%S")
+
@@ -0,0 +1,86 @@
+//! Times the SQLite storage paths a busy server hits most: directory lookups
+//! and the offline queue (storing, loading and acking messages), under both
+//! durability settings.
+//!
+//!     cargo bench -p nymchat-server --bench storage
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use nymchat_server::{DatabaseOptions, Db, Durability, Storage};
+use std::path::PathBuf;
+
+const USERS: usize = 200;
+/// Messages loaded and acked per iteration, like a login after some time offline.
+const BACKLOG: usize = 20;
+
+/// A file-backed database with `USERS` registered users; in-memory databases
+/// would hide the cost of syncing to disk.
+struct Fixture {
+    dir: PathBuf,
+    db: Db,
+    users: Vec<String>,
+}
+
+impl Fixture {
+    fn new(durability: Durability) -> Self {
+        let dir = std::env::temp_dir().join(format!("nymchat-bench-{}-{:?}", std::process::id(), durability));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = DatabaseOptions { durability, ..DatabaseOptions::default() };
+        let db = Db::open_with(dir.join("bench.db"), &options).unwrap();
+        let users: Vec<String> = (0..USERS).map(|i| format!("user{}", i)).collect();
+        for user in &users {
+            db.add_user(user, "public-key", "sender-tag").unwrap();
+        }
+        Fixture { dir, db, users }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn storage(c: &mut Criterion) {
+    for &durability in [Durability::Full, Durability::Normal].iter() {
+        let fixture = Fixture::new(durability);
+        let db = &fixture.db;
+        let mut group = c.benchmark_group(format!("storage/{:?}", durability));
+
+        let mut i = 0;
+        group.bench_function("lookup", |b| {
+            b.iter(|| {
+                i = (i + 1) % USERS;
+                db.get_user(black_box(&fixture.users[i])).unwrap().unwrap()
+            })
+        });
+        group.bench_function("enqueue", |b| {
+            b.iter(|| db.enqueue_message(&fixture.users[0], black_box(&[0u8; 512]), 0).unwrap())
+        });
+        let queued = db.queued_messages(&fixture.users[0], 0, usize::MAX).unwrap();
+        db.delete_queued(&fixture.users[0], &queued.into_iter().map(|(id, _)| id).collect::<Vec<_>>())
+            .unwrap();
+        group.bench_function("load_and_ack", |b| {
+            b.iter_batched(
+                || {
+                    for _ in 0..BACKLOG {
+                        db.enqueue_message(&fixture.users[1], &[0u8; 512], 0).unwrap();
+                    }
+                },
+                |()| {
+                    let ids: Vec<i64> = db
+                        .queued_messages(&fixture.users[1], 0, BACKLOG)
+                        .unwrap()
+                        .into_iter()
+                        .map(|(id, _)| id)
+                        .collect();
+                    db.delete_queued(&fixture.users[1], &ids).unwrap()
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, storage);
+criterion_main!(benches);
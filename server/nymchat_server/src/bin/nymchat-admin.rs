@@ -0,0 +1,109 @@
+//! Administration tool for the Rust server. Operates on the server's database
+//! and key files directly, using the same `.env` settings as the server.
+
+use anyhow::{bail, Context};
+use nymchat_server::{config, db, identity, Config, Storage};
+use std::env;
+use std::path::Path;
+
+const USAGE: &str = "Usage: nymchat-admin <command>
+
+Commands:
+  users              List registered users and their queued message counts
+  ban <username>     Ban a user from logging in, sending and re-registering
+  unban <username>   Lift a ban
+  banned             List banned users
+  queues             Show offline queue sizes per user
+  backup <path>      Write a consistent copy of the live database to <path>
+  public-key         Print the server public key, for clients to pin
+  rotate-key         Replace the server key pair and write a signed transition announcement
+  metrics            Print metrics in the Prometheus text format";
+
+fn open_db(config: &Config) -> anyhow::Result<Box<dyn Storage>> {
+    if config.database_url.is_none() && !config.database_path.exists() {
+        bail!("No database at {}", config.database_path.display());
+    }
+    db::open(config.database_url.as_deref(), &config.database_path, &config.database).context("Failed to open database")
+}
+
+fn username_arg(args: &[String]) -> anyhow::Result<&str> {
+    match args.get(1) {
+        Some(username) => Ok(username),
+        None => bail!("Missing username\n\n{}", USAGE),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    config::load_env(".env");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    let config = Config::from_env()?;
+
+    match command {
+        "users" => {
+            let db = open_db(&config)?;
+            for user in db.users()? {
+                let banned = if db.is_banned(&user.username)? { " (banned)" } else { "" };
+                println!("{}\t{} queued{}", user.username, db.queue_len(&user.username)?, banned);
+            }
+        }
+        "ban" => {
+            let username = username_arg(&args)?;
+            if open_db(&config)?.ban_user(username)? {
+                println!("Banned {}.", username);
+            } else {
+                println!("{} is already banned.", username);
+            }
+        }
+        "unban" => {
+            let username = username_arg(&args)?;
+            if open_db(&config)?.unban_user(username)? {
+                println!("Unbanned {}.", username);
+            } else {
+                println!("{} is not banned.", username);
+            }
+        }
+        "banned" => {
+            for username in open_db(&config)?.banned_users()? {
+                println!("{}", username);
+            }
+        }
+        "queues" => {
+            for (username, queued) in open_db(&config)?.queue_sizes()? {
+                println!("{}\t{}", username, queued);
+            }
+        }
+        "backup" => {
+            let dest = match args.get(1) {
+                Some(dest) => Path::new(dest),
+                None => bail!("Missing backup path\n\n{}", USAGE),
+            };
+            open_db(&config)?.backup(dest)?;
+            println!("Backup written to {}.", dest.display());
+        }
+        "public-key" => {
+            print!("{}", identity::public_key_pem(&config.keys_dir, &config.nym_client_id)?);
+        }
+        "rotate-key" => {
+            let password = config.password()?;
+            let transition = identity::rotate(&config.keys_dir, &config.nym_client_id, &password)?;
+            println!("New server public key:\n{}", transition.new_public_key);
+            println!("Restart the server to start signing with the new key.");
+        }
+        "metrics" => {
+            let stats = open_db(&config)?.stats()?;
+            println!("# TYPE nymchat_users gauge\nnymchat_users {}", stats.users);
+            println!("# TYPE nymchat_remote_users gauge\nnymchat_remote_users {}", stats.remote_users);
+            println!("# TYPE nymchat_banned_users gauge\nnymchat_banned_users {}", stats.banned);
+            println!("# TYPE nymchat_queued_messages gauge\nnymchat_queued_messages {}", stats.queued_messages);
+        }
+        other => bail!("Unknown command: {}\n\n{}", other, USAGE),
+    }
+    Ok(())
+}
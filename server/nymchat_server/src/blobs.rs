@@ -0,0 +1,44 @@
+//! Encrypted blob store for client backups.
+//!
+//! Clients keep an encrypted, chunked copy of their account here. A blob is
+//! addressed by an id the client derives from its recovery mnemonic, and
+//! anyone who knows the id may read it, so restoring on a new device needs
+//! nothing but the mnemonic. Writes need the blob's write token, also derived
+//! from the mnemonic: the first write of an id sets it and the server keeps
+//! only its SHA-256. Chunk contents are opaque to the server.
+
+use sha2::{Digest, Sha256};
+
+/// Size limits for stored blobs.
+#[derive(Clone, Debug)]
+pub struct BlobLimits {
+    /// Blobs the server stores in total; 0 turns the blob store off.
+    pub max_blobs: usize,
+    /// Chunks per blob.
+    pub max_chunks: u32,
+    /// Longest chunk, as sent by the client (hex ciphertext).
+    pub max_chunk_size: usize,
+}
+
+impl Default for BlobLimits {
+    fn default() -> Self {
+        BlobLimits {
+            max_blobs: 10_000,
+            max_chunks: 512,
+            max_chunk_size: 24 * 1024,
+        }
+    }
+}
+
+/// Longest write token accepted.
+pub const MAX_TOKEN_LEN: usize = 128;
+
+/// Blob ids are 32 bytes, hex encoded.
+pub fn is_valid_blob_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// What the server stores in place of a write token.
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
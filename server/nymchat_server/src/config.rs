@@ -0,0 +1,158 @@
+use crate::blobs::BlobLimits;
+use crate::db::DatabaseOptions;
+use crate::directory::DirectoryLimits;
+use crate::handler::SESSION_TTL;
+use crate::heartbeat::HEARTBEAT_TTL;
+use crate::queue::QueueLimits;
+use crate::surbs::SurbLimits;
+use anyhow::Context;
+use nymchat_protocol::NetworkConfig;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Server settings, read from the same environment variables as the Python server.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Name of the server identity key files (`NYM_CLIENT_ID`).
+    pub nym_client_id: String,
+    /// SQLite directory database (`DATABASE_PATH`).
+    pub database_path: PathBuf,
+    /// Postgres connection string; overrides `database_path` when set (`DATABASE_URL`).
+    pub database_url: Option<String>,
+    /// Commit durability and pool size (`DATABASE_SYNCHRONOUS`, `DATABASE_POOL_SIZE`).
+    pub database: DatabaseOptions,
+    /// Directory holding the encrypted server key pair (`KEYS_DIR`).
+    pub keys_dir: PathBuf,
+    /// File containing the key encryption password (`SECRET_PATH`).
+    pub secret_path: PathBuf,
+    /// Nym network to connect to (`NYM_NETWORK`, `NYM_NETWORK_ENV_FILE`, `NYM_API_URL`, `NYXD_URL`).
+    pub network: NetworkConfig,
+    /// Persistent nym client storage, keeps the server address stable (`NYM_DATA_DIR`).
+    pub nym_data_dir: PathBuf,
+    /// Where the server's nym address is written for clients (`ADDRESS_FILE`).
+    pub address_file: PathBuf,
+    /// Offline queue limits (`QUEUE_TTL_SECS`, `QUEUE_MAX_MESSAGES`, `QUEUE_MAX_MESSAGE_BYTES`,
+    /// `QUEUE_PAGE_SIZE`, `QUEUE_PAGE_BYTES`).
+    pub queue: QueueLimits,
+    /// Directory protections (`DIRECTORY_BURST`, `DIRECTORY_PER_MINUTE`,
+    /// `DIRECTORY_BLOOM_FILTER`, `RESPONSE_DELAY_MS`).
+    pub directory: DirectoryLimits,
+    /// Backup blob store limits, 0 blobs turns it off (`BLOB_MAX_BLOBS`,
+    /// `BLOB_MAX_CHUNKS`, `BLOB_MAX_CHUNK_BYTES`).
+    pub blobs: BlobLimits,
+    /// Proof of work bits required to register, 0 to disable (`REGISTRATION_POW_BITS`).
+    pub registration_pow_bits: u32,
+    /// How long a login lasts before the client must log in again (`SESSION_TTL_SECS`).
+    pub session_ttl: Duration,
+    /// How long after its last heartbeat a client counts as offline (`HEARTBEAT_TTL_SECS`).
+    pub heartbeat_ttl: Duration,
+    /// When clients are asked for more reply SURBs, how many, and how long SURBs
+    /// stay usable (`SURB_LOW_WATER`, `SURB_TOP_UP`, `SURB_EPOCH_SECS`).
+    pub surbs: SurbLimits,
+    /// Name this server signs federation messages as (`FEDERATION_NAME`, defaults to `NYM_CLIENT_ID`).
+    pub federation_name: String,
+    /// Trust list of peer servers; federation is off when unset (`FEDERATION_PEERS`).
+    pub federation_peers: Option<PathBuf>,
+}
+
+fn var_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn parsed_or<T: FromStr>(name: &str, default: T) -> anyhow::Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{} is not a valid number: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = QueueLimits::default();
+        let directory = DirectoryLimits::default();
+        let database = DatabaseOptions::default();
+        let blobs = BlobLimits::default();
+        let surbs = SurbLimits::default();
+        let nym_client_id = var_or("NYM_CLIENT_ID", "nym_server");
+        Ok(Config {
+            federation_name: var_or("FEDERATION_NAME", &nym_client_id),
+            federation_peers: env::var("FEDERATION_PEERS").ok().map(PathBuf::from),
+            nym_client_id,
+            database_path: var_or("DATABASE_PATH", "storage/nym_server.db").into(),
+            database_url: env::var("DATABASE_URL").ok(),
+            database: DatabaseOptions {
+                durability: match env::var("DATABASE_SYNCHRONOUS") {
+                    Ok(value) => value.parse().map_err(|e| anyhow::anyhow!("DATABASE_SYNCHRONOUS: {}", e))?,
+                    Err(_) => database.durability,
+                },
+                pool_size: parsed_or("DATABASE_POOL_SIZE", database.pool_size)?,
+            },
+            keys_dir: var_or("KEYS_DIR", "storage/keys").into(),
+            secret_path: env::var("SECRET_PATH").context("SECRET_PATH is not set")?.into(),
+            network: NetworkConfig::from_env()?,
+            nym_data_dir: var_or("NYM_DATA_DIR", "storage/nym").into(),
+            address_file: var_or("ADDRESS_FILE", "storage/nym_address.txt").into(),
+            queue: QueueLimits {
+                ttl: Duration::from_secs(parsed_or("QUEUE_TTL_SECS", defaults.ttl.as_secs())?),
+                max_messages: parsed_or("QUEUE_MAX_MESSAGES", defaults.max_messages)?,
+                max_message_size: parsed_or("QUEUE_MAX_MESSAGE_BYTES", defaults.max_message_size)?,
+                page_size: parsed_or("QUEUE_PAGE_SIZE", defaults.page_size)?,
+                page_bytes: parsed_or("QUEUE_PAGE_BYTES", defaults.page_bytes)?,
+            },
+            directory: DirectoryLimits {
+                burst: parsed_or("DIRECTORY_BURST", directory.burst)?,
+                per_minute: parsed_or("DIRECTORY_PER_MINUTE", directory.per_minute)?,
+                bloom_filter: matches!(var_or("DIRECTORY_BLOOM_FILTER", "false").as_str(), "1" | "true"),
+                response_delay: Duration::from_millis(parsed_or(
+                    "RESPONSE_DELAY_MS",
+                    directory.response_delay.as_millis() as u64,
+                )?),
+                ..directory
+            },
+            blobs: BlobLimits {
+                max_blobs: parsed_or("BLOB_MAX_BLOBS", blobs.max_blobs)?,
+                max_chunks: parsed_or("BLOB_MAX_CHUNKS", blobs.max_chunks)?,
+                max_chunk_size: parsed_or("BLOB_MAX_CHUNK_BYTES", blobs.max_chunk_size)?,
+            },
+            registration_pow_bits: parsed_or("REGISTRATION_POW_BITS", 0)?,
+            session_ttl: Duration::from_secs(parsed_or("SESSION_TTL_SECS", SESSION_TTL.as_secs())?),
+            heartbeat_ttl: Duration::from_secs(parsed_or("HEARTBEAT_TTL_SECS", HEARTBEAT_TTL as u64)?),
+            surbs: SurbLimits {
+                low_water: parsed_or("SURB_LOW_WATER", surbs.low_water)?,
+                top_up: parsed_or("SURB_TOP_UP", surbs.top_up)?,
+                epoch_length: Duration::from_secs(parsed_or("SURB_EPOCH_SECS", surbs.epoch_length.as_secs())?),
+                ..surbs
+            },
+        })
+    }
+
+    /// Reads the key encryption password from `secret_path`.
+    pub fn password(&self) -> anyhow::Result<String> {
+        let password = fs::read_to_string(&self.secret_path)
+            .with_context(|| format!("Encryption password not found at {}", self.secret_path.display()))?;
+        Ok(password.trim().to_string())
+    }
+}
+
+/// Loads `KEY=value` lines from a `.env` file into the process environment.
+/// Mirrors the Python `envLoader`: blank lines and `#` comments are skipped.
+pub fn load_env(path: &str) {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            env::set_var(key, value);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Server persistence.
+//!
+//! Everything the server stores goes through the [`Storage`] trait. [`Db`]
+//! keeps it in a single SQLite file and is all a small deployment needs;
+//! [`PostgresDb`] (behind the `postgres` feature) pools connections to a
+//! Postgres server for deployments that want concurrent writers and online
+//! backups of a live database.
+
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresDb;
+pub use self::sqlite::Db;
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A directory entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub username: String,
+    pub public_key: String,
+    /// Sender tag of the SURBs the user last reached us with.
+    pub sender_tag: String,
+}
+
+/// A directory entry gossiped by a federated server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteUser {
+    pub username: String,
+    pub public_key: String,
+    /// Name of the peer server the user is homed on.
+    pub server: String,
+}
+
+/// A client backup in the blob store, see [`crate::blobs`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Blob {
+    /// Chunk count of the latest version.
+    pub total: u32,
+    /// `(index, data)` pairs stored so far, by index.
+    pub chunks: Vec<(u32, String)>,
+}
+
+/// Row counts reported by `nymchat-admin metrics`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub users: usize,
+    pub remote_users: usize,
+    pub banned: usize,
+    pub queued_messages: usize,
+}
+
+/// A storage backend failure, with the backend's own message.
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// How hard the backend works to make every commit survive a power loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Every commit is flushed to disk before it returns.
+    Full,
+    /// SQLite runs in WAL mode with `synchronous=NORMAL`, Postgres with
+    /// `synchronous_commit=off`. The last commits before a power loss may be
+    /// lost, but the database is never corrupted. Much faster for the small
+    /// writes of queueing and acking messages.
+    Normal,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(Durability::Full),
+            "normal" => Ok(Durability::Normal),
+            other => Err(format!("unknown durability {:?}, expected full or normal", other)),
+        }
+    }
+}
+
+/// Backend tuning, shared by both backends where it applies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseOptions {
+    pub durability: Durability,
+    /// Postgres connections kept by the pool.
+    pub pool_size: u32,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions { durability: Durability::Full, pool_size: 8 }
+    }
+}
+
+/// Server persistence: the username directory, bans, federated entries, the
+/// offline queue and the backup blob store.
+pub trait Storage: Send {
+    /// Inserts a new user. Returns `false` if the username is already taken.
+    fn add_user(&self, username: &str, public_key: &str, sender_tag: &str) -> StorageResult<bool>;
+    fn get_user(&self, username: &str) -> StorageResult<Option<User>>;
+    /// All local users, ordered by username.
+    fn users(&self) -> StorageResult<Vec<User>>;
+    fn usernames(&self) -> StorageResult<Vec<String>>;
+    fn update_sender_tag(&self, username: &str, sender_tag: &str) -> StorageResult<()>;
+    /// Removes a local user and everything queued for them. Returns `false` if
+    /// there was no such user.
+    fn delete_user(&self, username: &str) -> StorageResult<bool>;
+
+    /// Bans `username`. Returns `false` if it was already banned.
+    fn ban_user(&self, username: &str) -> StorageResult<bool>;
+    /// Lifts a ban. Returns `false` if `username` was not banned.
+    fn unban_user(&self, username: &str) -> StorageResult<bool>;
+    fn is_banned(&self, username: &str) -> StorageResult<bool>;
+    fn banned_users(&self) -> StorageResult<Vec<String>>;
+
+    /// Stores a peer's directory entry. Entries for a username homed on a
+    /// different server are left alone; the first server to announce a name keeps it.
+    fn upsert_remote_user(&self, username: &str, public_key: &str, server: &str) -> StorageResult<bool>;
+    fn get_remote_user(&self, username: &str) -> StorageResult<Option<RemoteUser>>;
+    /// Public keys of all local users, for announcing them to peers.
+    fn local_entries(&self) -> StorageResult<Vec<(String, String)>>;
+
+    fn enqueue_message(&self, username: &str, payload: &[u8], created: i64) -> StorageResult<i64>;
+    /// Drops the oldest queued messages for `username` beyond `keep`.
+    fn trim_queue(&self, username: &str, keep: usize) -> StorageResult<()>;
+    fn purge_queue_before(&self, created: i64) -> StorageResult<usize>;
+    /// Returns up to `limit` `(id, sealed payload)` pairs for `username` with
+    /// ids above `after`, oldest first.
+    fn queued_messages(&self, username: &str, after: i64, limit: usize) -> StorageResult<Vec<(i64, Vec<u8>)>>;
+    fn queue_len(&self, username: &str) -> StorageResult<usize>;
+    /// Number of queued messages per user, for users with anything queued.
+    fn queue_sizes(&self) -> StorageResult<Vec<(String, usize)>>;
+    fn delete_queued(&self, username: &str, ids: &[i64]) -> StorageResult<usize>;
+
+    /// Hash of the write token of blob `id`, if the blob exists.
+    fn blob_token(&self, id: &str) -> StorageResult<Option<String>>;
+    fn blob_count(&self) -> StorageResult<usize>;
+    /// Stores chunk `index` of blob `id`, creating the blob with `token_hash`
+    /// if needed, and drops its chunks at or beyond `total`.
+    fn put_blob_chunk(&self, id: &str, token_hash: &str, index: u32, total: u32, data: &str) -> StorageResult<()>;
+    fn get_blob(&self, id: &str) -> StorageResult<Option<Blob>>;
+    /// Returns `false` if there was no such blob.
+    fn delete_blob(&self, id: &str) -> StorageResult<bool>;
+
+    /// Table sizes, for metrics.
+    fn stats(&self) -> StorageResult<Stats>;
+    /// Writes a consistent copy of the live database to `dest`.
+    fn backup(&self, dest: &Path) -> StorageResult<()>;
+}
+
+/// Opens the backend selected by `DATABASE_URL`, falling back to the SQLite
+/// file at `DATABASE_PATH`.
+pub fn open(
+    database_url: Option<&str>,
+    database_path: &Path,
+    options: &DatabaseOptions,
+) -> anyhow::Result<Box<dyn Storage>> {
+    match database_url {
+        #[cfg(feature = "postgres")]
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            Ok(Box::new(PostgresDb::connect(url, options)?))
+        }
+        Some(url) => anyhow::bail!(
+            "Unsupported DATABASE_URL {}; Postgres needs the `postgres` feature",
+            url.split("://").next().unwrap_or_default()
+        ),
+        None => {
+            if let Some(parent) = database_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Ok(Box::new(Db::open_with(database_path, options)?))
+        }
+    }
+}
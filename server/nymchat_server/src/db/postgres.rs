@@ -0,0 +1,347 @@
+use super::{Blob, DatabaseOptions, Durability, RemoteUser, Stats, Storage, StorageError, StorageResult, User};
+use postgres::NoTls;
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use std::path::Path;
+use std::process::Command;
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+impl From<postgres::Error> for StorageError {
+    fn from(e: postgres::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+impl From<r2d2::Error> for StorageError {
+    fn from(e: r2d2::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+/// Postgres storage with a connection pool, so the server, `nymchat-admin`
+/// and backups can work on the database at the same time. Column names
+/// match the SQLite schema.
+pub struct PostgresDb {
+    pool: Pool<Manager>,
+    url: String,
+}
+
+impl PostgresDb {
+    pub fn connect(url: &str, options: &DatabaseOptions) -> StorageResult<Self> {
+        let mut config: postgres::Config = url.parse()?;
+        if options.durability == Durability::Normal {
+            config.options("-c synchronous_commit=off");
+        }
+        let manager = PostgresConnectionManager::new(config, NoTls);
+        let db = PostgresDb {
+            pool: Pool::builder()
+                .max_size(options.pool_size)
+                .min_idle(Some(1))
+                .build(manager)?,
+            url: url.to_string(),
+        };
+        db.initialize_tables()?;
+        Ok(db)
+    }
+
+    fn conn(&self) -> StorageResult<PooledConnection<Manager>> {
+        Ok(self.pool.get()?)
+    }
+
+    fn initialize_tables(&self) -> StorageResult<()> {
+        self.conn()?.batch_execute(
+            r#"CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                "publicKey" TEXT NOT NULL,
+                "senderTag" TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS groups (
+                "groupID" TEXT PRIMARY KEY,
+                "userList" TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS offline_queue (
+                id BIGSERIAL PRIMARY KEY,
+                username TEXT NOT NULL,
+                payload BYTEA NOT NULL,
+                created BIGINT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS offline_queue_username ON offline_queue (username);
+            CREATE TABLE IF NOT EXISTS banned (
+                username TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS remote_users (
+                username TEXT PRIMARY KEY,
+                "publicKey" TEXT NOT NULL,
+                server TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS blobs (
+                id TEXT PRIMARY KEY,
+                token TEXT NOT NULL,
+                total INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS blob_chunks (
+                id TEXT NOT NULL,
+                idx INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (id, idx)
+            );"#,
+        )?;
+        Ok(())
+    }
+
+    fn count(&self, table: &str) -> StorageResult<usize> {
+        let row = self
+            .conn()?
+            .query_one(format!("SELECT COUNT(*) FROM {}", table).as_str(), &[])?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+}
+
+impl Storage for PostgresDb {
+    fn add_user(&self, username: &str, public_key: &str, sender_tag: &str) -> StorageResult<bool> {
+        let inserted = self.conn()?.execute(
+            r#"INSERT INTO users (username, "publicKey", "senderTag") VALUES ($1, $2, $3)
+               ON CONFLICT DO NOTHING"#,
+            &[&username, &public_key, &sender_tag],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn get_user(&self, username: &str) -> StorageResult<Option<User>> {
+        let row = self.conn()?.query_opt(
+            r#"SELECT username, "publicKey", "senderTag" FROM users WHERE username = $1"#,
+            &[&username],
+        )?;
+        Ok(row.map(|row| User {
+            username: row.get(0),
+            public_key: row.get(1),
+            sender_tag: row.get(2),
+        }))
+    }
+
+    fn users(&self) -> StorageResult<Vec<User>> {
+        let rows = self.conn()?.query(
+            r#"SELECT username, "publicKey", "senderTag" FROM users ORDER BY username"#,
+            &[],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| User {
+                username: row.get(0),
+                public_key: row.get(1),
+                sender_tag: row.get(2),
+            })
+            .collect())
+    }
+
+    fn usernames(&self) -> StorageResult<Vec<String>> {
+        let rows = self.conn()?.query("SELECT username FROM users", &[])?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn update_sender_tag(&self, username: &str, sender_tag: &str) -> StorageResult<()> {
+        self.conn()?.execute(
+            r#"UPDATE users SET "senderTag" = $1 WHERE username = $2"#,
+            &[&sender_tag, &username],
+        )?;
+        Ok(())
+    }
+
+    fn delete_user(&self, username: &str) -> StorageResult<bool> {
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM users WHERE username = $1", &[&username])?;
+        tx.execute("DELETE FROM offline_queue WHERE username = $1", &[&username])?;
+        tx.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn ban_user(&self, username: &str) -> StorageResult<bool> {
+        let inserted = self.conn()?.execute(
+            "INSERT INTO banned (username) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&username],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn unban_user(&self, username: &str) -> StorageResult<bool> {
+        let deleted = self
+            .conn()?
+            .execute("DELETE FROM banned WHERE username = $1", &[&username])?;
+        Ok(deleted == 1)
+    }
+
+    fn is_banned(&self, username: &str) -> StorageResult<bool> {
+        let row = self
+            .conn()?
+            .query_opt("SELECT 1 FROM banned WHERE username = $1", &[&username])?;
+        Ok(row.is_some())
+    }
+
+    fn banned_users(&self) -> StorageResult<Vec<String>> {
+        let rows = self.conn()?.query("SELECT username FROM banned ORDER BY username", &[])?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn upsert_remote_user(&self, username: &str, public_key: &str, server: &str) -> StorageResult<bool> {
+        let changed = self.conn()?.execute(
+            r#"INSERT INTO remote_users (username, "publicKey", server) VALUES ($1, $2, $3)
+               ON CONFLICT (username) DO UPDATE SET "publicKey" = excluded."publicKey"
+               WHERE remote_users.server = excluded.server"#,
+            &[&username, &public_key, &server],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn get_remote_user(&self, username: &str) -> StorageResult<Option<RemoteUser>> {
+        let row = self.conn()?.query_opt(
+            r#"SELECT username, "publicKey", server FROM remote_users WHERE username = $1"#,
+            &[&username],
+        )?;
+        Ok(row.map(|row| RemoteUser {
+            username: row.get(0),
+            public_key: row.get(1),
+            server: row.get(2),
+        }))
+    }
+
+    fn local_entries(&self) -> StorageResult<Vec<(String, String)>> {
+        let rows = self.conn()?.query(r#"SELECT username, "publicKey" FROM users"#, &[])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn enqueue_message(&self, username: &str, payload: &[u8], created: i64) -> StorageResult<i64> {
+        let row = self.conn()?.query_one(
+            "INSERT INTO offline_queue (username, payload, created) VALUES ($1, $2, $3) RETURNING id",
+            &[&username, &payload, &created],
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn trim_queue(&self, username: &str, keep: usize) -> StorageResult<()> {
+        self.conn()?.execute(
+            "DELETE FROM offline_queue WHERE username = $1 AND id NOT IN (
+                SELECT id FROM offline_queue WHERE username = $1 ORDER BY id DESC LIMIT $2
+            )",
+            &[&username, &(keep as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn purge_queue_before(&self, created: i64) -> StorageResult<usize> {
+        let deleted = self
+            .conn()?
+            .execute("DELETE FROM offline_queue WHERE created < $1", &[&created])?;
+        Ok(deleted as usize)
+    }
+
+    fn queued_messages(&self, username: &str, after: i64, limit: usize) -> StorageResult<Vec<(i64, Vec<u8>)>> {
+        let rows = self.conn()?.query(
+            "SELECT id, payload FROM offline_queue WHERE username = $1 AND id > $2 ORDER BY id LIMIT $3",
+            &[&username, &after, &(limit.min(i64::MAX as usize) as i64)],
+        )?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn queue_len(&self, username: &str) -> StorageResult<usize> {
+        let row = self
+            .conn()?
+            .query_one("SELECT COUNT(*) FROM offline_queue WHERE username = $1", &[&username])?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    fn queue_sizes(&self) -> StorageResult<Vec<(String, usize)>> {
+        let rows = self.conn()?.query(
+            "SELECT username, COUNT(*) FROM offline_queue GROUP BY username ORDER BY COUNT(*) DESC, username",
+            &[],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1) as usize))
+            .collect())
+    }
+
+    fn delete_queued(&self, username: &str, ids: &[i64]) -> StorageResult<usize> {
+        let deleted = self.conn()?.execute(
+            "DELETE FROM offline_queue WHERE username = $1 AND id = ANY($2)",
+            &[&username, &ids],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn blob_token(&self, id: &str) -> StorageResult<Option<String>> {
+        let row = self.conn()?.query_opt("SELECT token FROM blobs WHERE id = $1", &[&id])?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    fn blob_count(&self) -> StorageResult<usize> {
+        self.count("blobs")
+    }
+
+    fn put_blob_chunk(&self, id: &str, token_hash: &str, index: u32, total: u32, data: &str) -> StorageResult<()> {
+        let (index, total) = (index as i32, total as i32);
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO blobs (id, token, total) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE SET total = excluded.total",
+            &[&id, &token_hash, &total],
+        )?;
+        tx.execute(
+            "INSERT INTO blob_chunks (id, idx, data) VALUES ($1, $2, $3)
+            ON CONFLICT (id, idx) DO UPDATE SET data = excluded.data",
+            &[&id, &index, &data],
+        )?;
+        tx.execute("DELETE FROM blob_chunks WHERE id = $1 AND idx >= $2", &[&id, &total])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_blob(&self, id: &str) -> StorageResult<Option<Blob>> {
+        let mut conn = self.conn()?;
+        let total: i32 = match conn.query_opt("SELECT total FROM blobs WHERE id = $1", &[&id])? {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+        let rows = conn.query("SELECT idx, data FROM blob_chunks WHERE id = $1 ORDER BY idx", &[&id])?;
+        Ok(Some(Blob {
+            total: total as u32,
+            chunks: rows.iter().map(|row| (row.get::<_, i32>(0) as u32, row.get(1))).collect(),
+        }))
+    }
+
+    fn delete_blob(&self, id: &str) -> StorageResult<bool> {
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM blobs WHERE id = $1", &[&id])?;
+        tx.execute("DELETE FROM blob_chunks WHERE id = $1", &[&id])?;
+        tx.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn stats(&self) -> StorageResult<Stats> {
+        Ok(Stats {
+            users: self.count("users")?,
+            remote_users: self.count("remote_users")?,
+            banned: self.count("banned")?,
+            queued_messages: self.count("offline_queue")?,
+        })
+    }
+
+    /// Runs `pg_dump`, which reads a consistent snapshot without blocking writers.
+    fn backup(&self, dest: &Path) -> StorageResult<()> {
+        let output = Command::new("pg_dump")
+            .arg("--format=custom")
+            .arg("--file")
+            .arg(dest)
+            .arg(&self.url)
+            .output()
+            .map_err(|e| StorageError(format!("Failed to run pg_dump: {}", e)))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(StorageError(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use super::{Blob, DatabaseOptions, Durability, RemoteUser, Stats, Storage, StorageResult, User};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Statements kept prepared per connection; enough for every query below.
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+/// SQLite storage. The schema matches the Python server's `nym_server.db`,
+/// so an existing database can be reused as is. Queries are static SQL with
+/// bound parameters and go through the connection's statement cache, so each
+/// is compiled once.
+pub struct Db {
+    conn: Connection,
+}
+
+impl Db {
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        Self::open_with(path, &DatabaseOptions::default())
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, options: &DatabaseOptions) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        if options.durability == Durability::Normal {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+        }
+        let db = Db { conn };
+        db.conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        db.initialize_tables()?;
+        Ok(db)
+    }
+
+    /// Opens a throwaway database, used by tests.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        let db = Db { conn: Connection::open_in_memory()? };
+        db.initialize_tables()?;
+        Ok(db)
+    }
+
+    fn initialize_tables(&self) -> rusqlite::Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                publicKey TEXT NOT NULL,
+                senderTag TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS groups (
+                groupID TEXT PRIMARY KEY,
+                userList TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS offline_queue (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                payload BLOB NOT NULL,
+                created INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS offline_queue_username ON offline_queue (username);
+            CREATE TABLE IF NOT EXISTS banned (
+                username TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS remote_users (
+                username TEXT PRIMARY KEY,
+                publicKey TEXT NOT NULL,
+                server TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS blobs (
+                id TEXT PRIMARY KEY,
+                token TEXT NOT NULL,
+                total INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS blob_chunks (
+                id TEXT NOT NULL,
+                idx INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (id, idx)
+            );",
+        )
+    }
+
+    fn read_user(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
+        Ok(User {
+            username: row.get(0)?,
+            public_key: row.get(1)?,
+            sender_tag: row.get(2)?,
+        })
+    }
+
+    fn count(&self, table: &str) -> rusqlite::Result<usize> {
+        self.conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get::<_, i64>(0))
+            .map(|n| n as usize)
+    }
+}
+
+impl Storage for Db {
+    fn add_user(&self, username: &str, public_key: &str, sender_tag: &str) -> StorageResult<bool> {
+        let inserted = self
+            .conn
+            .prepare_cached("INSERT OR IGNORE INTO users (username, publicKey, senderTag) VALUES (?1, ?2, ?3)")?
+            .execute(params![username, public_key, sender_tag])?;
+        Ok(inserted == 1)
+    }
+
+    fn get_user(&self, username: &str) -> StorageResult<Option<User>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT username, publicKey, senderTag FROM users WHERE username = ?1")?
+            .query_row(params![username], Self::read_user)
+            .optional()?)
+    }
+
+    fn users(&self) -> StorageResult<Vec<User>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT username, publicKey, senderTag FROM users ORDER BY username")?;
+        let rows = stmt.query_map([], Self::read_user)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn usernames(&self) -> StorageResult<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT username FROM users")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn update_sender_tag(&self, username: &str, sender_tag: &str) -> StorageResult<()> {
+        self.conn
+            .prepare_cached("UPDATE users SET senderTag = ?1 WHERE username = ?2")?
+            .execute(params![sender_tag, username])?;
+        Ok(())
+    }
+
+    fn delete_user(&self, username: &str) -> StorageResult<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM users WHERE username = ?1")?
+            .execute(params![username])?;
+        tx.prepare_cached("DELETE FROM offline_queue WHERE username = ?1")?
+            .execute(params![username])?;
+        tx.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn ban_user(&self, username: &str) -> StorageResult<bool> {
+        let inserted = self
+            .conn
+            .prepare_cached("INSERT OR IGNORE INTO banned (username) VALUES (?1)")?
+            .execute(params![username])?;
+        Ok(inserted == 1)
+    }
+
+    fn unban_user(&self, username: &str) -> StorageResult<bool> {
+        let deleted = self
+            .conn
+            .prepare_cached("DELETE FROM banned WHERE username = ?1")?
+            .execute(params![username])?;
+        Ok(deleted == 1)
+    }
+
+    fn is_banned(&self, username: &str) -> StorageResult<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT 1 FROM banned WHERE username = ?1")?
+            .query_row(params![username], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    fn banned_users(&self) -> StorageResult<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT username FROM banned ORDER BY username")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn upsert_remote_user(&self, username: &str, public_key: &str, server: &str) -> StorageResult<bool> {
+        let changed = self
+            .conn
+            .prepare_cached(
+                "INSERT INTO remote_users (username, publicKey, server) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(username) DO UPDATE SET publicKey = excluded.publicKey
+                 WHERE remote_users.server = excluded.server",
+            )?
+            .execute(params![username, public_key, server])?;
+        Ok(changed == 1)
+    }
+
+    fn get_remote_user(&self, username: &str) -> StorageResult<Option<RemoteUser>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT username, publicKey, server FROM remote_users WHERE username = ?1")?
+            .query_row(params![username], |row| {
+                Ok(RemoteUser {
+                    username: row.get(0)?,
+                    public_key: row.get(1)?,
+                    server: row.get(2)?,
+                })
+            })
+            .optional()?)
+    }
+
+    fn local_entries(&self) -> StorageResult<Vec<(String, String)>> {
+        let mut stmt = self.conn.prepare_cached("SELECT username, publicKey FROM users")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn enqueue_message(&self, username: &str, payload: &[u8], created: i64) -> StorageResult<i64> {
+        self.conn
+            .prepare_cached("INSERT INTO offline_queue (username, payload, created) VALUES (?1, ?2, ?3)")?
+            .execute(params![username, payload, created])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn trim_queue(&self, username: &str, keep: usize) -> StorageResult<()> {
+        self.conn
+            .prepare_cached(
+                "DELETE FROM offline_queue WHERE username = ?1 AND id NOT IN (
+                    SELECT id FROM offline_queue WHERE username = ?1 ORDER BY id DESC LIMIT ?2
+                )",
+            )?
+            .execute(params![username, keep as i64])?;
+        Ok(())
+    }
+
+    fn purge_queue_before(&self, created: i64) -> StorageResult<usize> {
+        Ok(self
+            .conn
+            .prepare_cached("DELETE FROM offline_queue WHERE created < ?1")?
+            .execute(params![created])?)
+    }
+
+    fn queued_messages(&self, username: &str, after: i64, limit: usize) -> StorageResult<Vec<(i64, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, payload FROM offline_queue WHERE username = ?1 AND id > ?2 ORDER BY id LIMIT ?3",
+        )?;
+        let limit = limit.min(i64::MAX as usize) as i64;
+        let rows = stmt.query_map(params![username, after, limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn queue_len(&self, username: &str) -> StorageResult<usize> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT COUNT(*) FROM offline_queue WHERE username = ?1")?
+            .query_row(params![username], |row| row.get::<_, i64>(0).map(|n| n as usize))?)
+    }
+
+    fn queue_sizes(&self) -> StorageResult<Vec<(String, usize)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT username, COUNT(*) FROM offline_queue GROUP BY username ORDER BY COUNT(*) DESC, username",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn delete_queued(&self, username: &str, ids: &[i64]) -> StorageResult<usize> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("DELETE FROM offline_queue WHERE username = ?1 AND id = ?2")?;
+        let mut deleted = 0;
+        for id in ids {
+            deleted += stmt.execute(params![username, id])?;
+        }
+        Ok(deleted)
+    }
+
+    fn blob_token(&self, id: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT token FROM blobs WHERE id = ?1")?
+            .query_row(params![id], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn blob_count(&self) -> StorageResult<usize> {
+        Ok(self.count("blobs")?)
+    }
+
+    fn put_blob_chunk(&self, id: &str, token_hash: &str, index: u32, total: u32, data: &str) -> StorageResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO blobs (id, token, total) VALUES (?1, ?2, ?3)
+            ON CONFLICT (id) DO UPDATE SET total = excluded.total",
+        )?
+        .execute(params![id, token_hash, total])?;
+        tx.prepare_cached("INSERT OR REPLACE INTO blob_chunks (id, idx, data) VALUES (?1, ?2, ?3)")?
+            .execute(params![id, index, data])?;
+        tx.prepare_cached("DELETE FROM blob_chunks WHERE id = ?1 AND idx >= ?2")?
+            .execute(params![id, total])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_blob(&self, id: &str) -> StorageResult<Option<Blob>> {
+        let total: Option<u32> = self
+            .conn
+            .prepare_cached("SELECT total FROM blobs WHERE id = ?1")?
+            .query_row(params![id], |row| row.get(0))
+            .optional()?;
+        let total = match total {
+            Some(total) => total,
+            None => return Ok(None),
+        };
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT idx, data FROM blob_chunks WHERE id = ?1 ORDER BY idx")?;
+        let chunks = stmt
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Some(Blob { total, chunks }))
+    }
+
+    fn delete_blob(&self, id: &str) -> StorageResult<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let deleted = tx.prepare_cached("DELETE FROM blobs WHERE id = ?1")?.execute(params![id])?;
+        tx.prepare_cached("DELETE FROM blob_chunks WHERE id = ?1")?
+            .execute(params![id])?;
+        tx.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn stats(&self) -> StorageResult<Stats> {
+        Ok(Stats {
+            users: self.count("users")?,
+            remote_users: self.count("remote_users")?,
+            banned: self.count("banned")?,
+            queued_messages: self.count("offline_queue")?,
+        })
+    }
+
+    /// Uses SQLite's online backup API, so the server can keep writing meanwhile.
+    fn backup(&self, dest: &Path) -> StorageResult<()> {
+        self.conn.backup(rusqlite::DatabaseName::Main, dest, None)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Protections for the username directory against enumeration.
+//!
+//! Directory lookups (`query`, `directoryFilter`) are rate limited per sender
+//! tag with a token bucket. Clients that do not want to reveal which name they
+//! are looking for can instead fetch a bloom filter of all registered
+//! usernames and test membership locally.
+
+use crate::db::{Storage, StorageResult};
+use nymchat_protocol::BloomFilter;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Directory protection settings.
+#[derive(Clone, Debug)]
+pub struct DirectoryLimits {
+    /// Lookups a single sender tag may burst before being throttled.
+    pub burst: u32,
+    /// Sustained lookups allowed per sender tag per minute.
+    pub per_minute: u32,
+    /// Serve `directoryFilter` requests.
+    pub bloom_filter: bool,
+    /// Target false positive rate of the served bloom filter.
+    pub bloom_false_positive_rate: f64,
+    /// Every reply is held until this long after its request arrived, so
+    /// processing time does not reveal which path a request took.
+    pub response_delay: Duration,
+}
+
+impl Default for DirectoryLimits {
+    fn default() -> Self {
+        DirectoryLimits {
+            burst: 10,
+            per_minute: 30,
+            bloom_filter: false,
+            bloom_false_positive_rate: 0.01,
+            response_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by sender tag.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        RateLimiter {
+            burst: f64::from(burst),
+            per_second: f64::from(per_minute) / 60.0,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for `key` at time `now`; returns `false` when the bucket is empty.
+    pub fn allow(&mut self, key: &str, now: Instant) -> bool {
+        if self.buckets.len() > 10_000 {
+            self.prune(now);
+        }
+        let burst = self.burst;
+        let per_second = self.per_second;
+        let bucket = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: burst, updated: now });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets buckets that have refilled completely.
+    fn prune(&mut self, now: Instant) {
+        let (burst, per_second) = (self.burst, self.per_second);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * per_second < burst
+        });
+    }
+}
+
+/// Bloom filter of registered usernames, rebuilt lazily after registrations.
+pub struct UsernameFilter {
+    false_positive_rate: f64,
+    cached: Option<BloomFilter>,
+}
+
+impl UsernameFilter {
+    pub fn new(false_positive_rate: f64) -> Self {
+        UsernameFilter { false_positive_rate, cached: None }
+    }
+
+    pub fn invalidate(&mut self) {
+        self.cached = None;
+    }
+
+    pub fn get(&mut self, db: &dyn Storage) -> StorageResult<&BloomFilter> {
+        if self.cached.is_none() {
+            let usernames = db.usernames()?;
+            let mut filter = BloomFilter::with_capacity(usernames.len(), self.false_positive_rate);
+            for username in &usernames {
+                filter.insert(username);
+            }
+            self.cached = Some(filter);
+        }
+        Ok(self.cached.as_ref().expect("filter was just built"))
+    }
+}
@@ -0,0 +1,21 @@
+//! Events the server publishes for whoever runs or embeds it.
+//!
+//! [`Handler::subscribe`](crate::handler::Handler::subscribe) hands out
+//! receivers; `main.rs` logs what they get. Events published while nobody is
+//! subscribed are dropped, and a subscriber more than [`EVENT_CAPACITY`]
+//! events behind misses the oldest.
+
+/// Events kept for a subscriber that has not read them yet.
+pub const EVENT_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    /// Few SURBs are left for `sender_tag`, and its client was asked for more.
+    SurbsLow { sender_tag: String, remaining: u32 },
+    /// No SURBs are left for `sender_tag`: its client cannot be reached until
+    /// it sends something again.
+    SurbsExhausted { sender_tag: String },
+    /// The SURBs held for `sender_tag` were made before the current topology
+    /// epoch, and its client was asked for fresh ones.
+    SurbsStale { sender_tag: String },
+}
@@ -0,0 +1,120 @@
+//! Federation between discovery servers.
+//!
+//! Servers listed in each other's trust list gossip signed directory entries
+//! for the users they home, and forward chat messages for users homed
+//! elsewhere. Every federation message is signed with the origin server's
+//! identity key and only accepted from servers on the trust list, so a peer
+//! cannot speak for users it does not home.
+
+use anyhow::Context;
+use nymchat_protocol::{to_python_json, Crypto, Envelope};
+use p256::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// A trusted peer server, as listed in the trust list file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Peer {
+    /// Name the peer signs its messages as.
+    pub name: String,
+    /// Nym address federation messages are sent to.
+    pub address: String,
+    /// PEM encoded server identity key.
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+}
+
+/// A message for another server, sent to its nym address rather than through SURBs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerMessage {
+    pub recipient: String,
+    pub message: String,
+}
+
+/// Directory entry gossiped between servers (`federatedEntry`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    pub username: String,
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+    /// Name of the server the user is homed on.
+    pub server: String,
+}
+
+/// Chat message forwarded to the recipient's home server (`federatedSend`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ForwardedMessage {
+    pub recipient: String,
+    /// Name of the server that accepted the message from the sender.
+    pub server: String,
+    /// The chat payload exactly as the recipient should receive it.
+    pub payload: String,
+}
+
+/// This server's federation identity and trust list.
+#[derive(Clone, Debug)]
+pub struct Federation {
+    pub name: String,
+    peers: Vec<(Peer, PublicKey)>,
+}
+
+impl Federation {
+    pub fn new(name: impl Into<String>, peers: Vec<Peer>) -> anyhow::Result<Self> {
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                let key = Crypto::public_key_from_pem(&peer.public_key)
+                    .map_err(|e| anyhow::anyhow!("Invalid public key for peer {}: {}", peer.name, e))?;
+                Ok((peer, key))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Federation { name: name.into(), peers })
+    }
+
+    /// Loads the trust list, a JSON array of `{"name", "address", "publicKey"}` objects.
+    pub fn load(name: impl Into<String>, trust_list: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(trust_list)
+            .with_context(|| format!("Failed to read trust list {}", trust_list.display()))?;
+        let peers: Vec<Peer> = serde_json::from_str(&contents).context("Invalid trust list")?;
+        Self::new(name, peers)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.iter().map(|(peer, _)| peer)
+    }
+
+    pub fn peer(&self, name: &str) -> Option<&Peer> {
+        self.peers().find(|peer| peer.name == name)
+    }
+
+    /// Signs `content` as this server and addresses it to `peer`.
+    pub fn message<T: Serialize>(&self, identity: &SecretKey, peer: &Peer, action: &str, content: &T) -> PeerMessage {
+        let content = to_python_json(content).expect("federation message serializes");
+        let envelope = Envelope::new(action)
+            .with_context("federation")
+            .with_signature(Crypto::sign(identity, &content))
+            .with_content(content);
+        PeerMessage {
+            recipient: peer.address.clone(),
+            message: envelope.to_json_string().expect("envelope serializes"),
+        }
+    }
+
+    /// Parses a federation message and checks it was signed by the trusted
+    /// server named in its `server` field.
+    pub fn verify<T>(&self, envelope: &Envelope, server: impl Fn(&T) -> &str) -> Option<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let content = envelope.content.as_deref()?;
+        let signature = envelope.signature.as_deref()?;
+        let parsed: T = serde_json::from_str(content).ok()?;
+        let (_, key) = self.peers.iter().find(|(peer, _)| peer.name == server(&parsed))?;
+        if Crypto::verify(key, content, signature) {
+            Some(parsed)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,1393 @@
+use crate::blobs::{self, BlobLimits};
+use crate::db::{Storage, User};
+use crate::directory::{DirectoryLimits, RateLimiter, UsernameFilter};
+use crate::events::{ServerEvent, EVENT_CAPACITY};
+use crate::federation::{DirectoryEntry, Federation, ForwardedMessage, PeerMessage};
+use crate::heartbeat::{Heartbeats, Liveness, HEARTBEAT_TTL};
+use crate::identity::KeyTransition;
+use crate::nonce::{NonceError, NonceStore, CHALLENGE_TTL};
+use crate::presence::Presence;
+use crate::queue::{now_secs, OfflineQueue, QueueError, QueueLimits};
+use crate::routing::RoutingTokens;
+use crate::surbs::{SurbLedger, SurbLimits, SurbWarning};
+use nymchat_protocol::{
+    ct_eq, is_valid_username, pow, to_python_json, ChatPayload, Crypto, Envelope, GroupPayload, SealedPayload,
+    UserRecord,
+};
+use p256::{PublicKey, SecretKey};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug_span, error, info, warn};
+
+/// Replies are padded to a multiple of this many bytes, so an observer of the
+/// reply size cannot tell a found user from a miss or an error.
+pub const REPLY_BUCKET: usize = 1024;
+
+/// A message to send back through the mixnet using the SURBs of `sender_tag`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub sender_tag: String,
+    pub message: String,
+}
+
+/// Processes client envelopes and produces the SURB replies to send back.
+pub struct Handler {
+    db: Box<dyn Storage>,
+    identity: SecretKey,
+    queue: OfflineQueue,
+    routing: RoutingTokens,
+    directory: DirectoryLimits,
+    rate_limiter: RateLimiter,
+    /// Sealed sends carry no identity to throttle, so they are limited per sender tag.
+    sealed_limiter: RateLimiter,
+    username_filter: UsernameFilter,
+    /// Leading zero bits a registration proof of work must have; 0 disables it.
+    pow_difficulty: u32,
+    /// Registration challenges carry the public key being registered.
+    registrations: NonceStore<String>,
+    /// Login challenges carry the stored public key, `None` for decoy challenges
+    /// issued to unknown usernames.
+    logins: NonceStore<Option<String>>,
+    /// Account deletion challenges carry the stored public key.
+    deletions: NonceStore<String>,
+    federation: Option<Federation>,
+    /// Served to clients that pinned the previous server key.
+    key_transition: Option<KeyTransition>,
+    /// Federation messages waiting to be sent, see [`Handler::take_peer_messages`].
+    outbox: Vec<PeerMessage>,
+    /// Reported as uptime in health replies.
+    started: Instant,
+    presence: Presence,
+    heartbeats: Heartbeats,
+    blobs: BlobLimits,
+    /// How long a login lasts; clients log in again before it runs out.
+    session_ttl: Duration,
+    /// When each logged-in user's session ends, in Unix seconds. Kept in
+    /// memory only: a restart logs everyone out.
+    sessions: HashMap<String, i64>,
+    /// Estimated reply SURBs left per sender tag.
+    surbs: SurbLedger,
+    events: broadcast::Sender<ServerEvent>,
+}
+
+/// Default for [`Handler::with_session_ttl`].
+pub const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest client nonce echoed back in a health reply.
+const MAX_HEALTH_NONCE: usize = 64;
+/// Longest `id` a client may attach to a `send` to match its `sendResponse`.
+const MAX_SEND_ID: usize = 64;
+/// Sealed sends a single sender tag may burst before being throttled.
+const SEALED_SEND_BURST: u32 = 20;
+/// Sustained sealed sends allowed per sender tag per minute.
+const SEALED_SENDS_PER_MINUTE: u32 = 60;
+/// Most recipients a single group send may address.
+const MAX_GROUP_RECIPIENTS: usize = 256;
+/// Most contacts a presence request may share with or watch.
+const MAX_PRESENCE_CONTACTS: usize = 1000;
+
+fn challenge_content(nonce: &str, difficulty: u32) -> String {
+    let content = if difficulty > 0 {
+        json!({ "nonce": nonce, "difficulty": difficulty })
+    } else {
+        json!({ "nonce": nonce })
+    };
+    to_python_json(&content).expect("nonce serializes")
+}
+
+/// A top-level array of strings, capped at [`MAX_PRESENCE_CONTACTS`]; other values are skipped.
+fn string_list(envelope: &Envelope, name: &str) -> Vec<String> {
+    match envelope.fields.get(name) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .take(MAX_PRESENCE_CONTACTS)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn challenge_error(e: NonceError, missing: &'static str) -> &'static str {
+    match e {
+        NonceError::Missing => missing,
+        NonceError::Expired => "error: challenge expired",
+        NonceError::UsernameMismatch => "error: username does not match challenge",
+    }
+}
+
+impl Handler {
+    pub fn new<S: Storage + 'static>(db: S, identity: SecretKey) -> Self {
+        Self::with_storage(Box::new(db), identity)
+    }
+
+    /// Like [`Handler::new`], for a backend chosen at runtime.
+    pub fn with_storage(db: Box<dyn Storage>, identity: SecretKey) -> Self {
+        let directory = DirectoryLimits::default();
+        Handler {
+            queue: OfflineQueue::new(&identity, QueueLimits::default()),
+            routing: RoutingTokens::new(&identity),
+            rate_limiter: RateLimiter::new(directory.burst, directory.per_minute),
+            sealed_limiter: RateLimiter::new(SEALED_SEND_BURST, SEALED_SENDS_PER_MINUTE),
+            username_filter: UsernameFilter::new(directory.bloom_false_positive_rate),
+            directory,
+            pow_difficulty: 0,
+            db,
+            identity,
+            registrations: NonceStore::new(CHALLENGE_TTL),
+            logins: NonceStore::new(CHALLENGE_TTL),
+            deletions: NonceStore::new(CHALLENGE_TTL),
+            federation: None,
+            key_transition: None,
+            outbox: Vec::new(),
+            started: Instant::now(),
+            presence: Presence::new(),
+            heartbeats: Heartbeats::new(HEARTBEAT_TTL),
+            blobs: BlobLimits::default(),
+            session_ttl: SESSION_TTL,
+            sessions: HashMap::new(),
+            surbs: SurbLedger::new(SurbLimits::default()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    pub fn with_queue_limits(mut self, limits: QueueLimits) -> Self {
+        self.queue = OfflineQueue::new(&self.identity, limits);
+        self
+    }
+
+    pub fn with_directory_limits(mut self, limits: DirectoryLimits) -> Self {
+        self.rate_limiter = RateLimiter::new(limits.burst, limits.per_minute);
+        self.username_filter = UsernameFilter::new(limits.bloom_false_positive_rate);
+        self.directory = limits;
+        self
+    }
+
+    pub fn with_blob_limits(mut self, limits: BlobLimits) -> Self {
+        self.blobs = limits;
+        self
+    }
+
+    /// Requires registrations to solve a proof of work of `difficulty` bits.
+    pub fn with_registration_pow(mut self, difficulty: u32) -> Self {
+        self.pow_difficulty = difficulty;
+        self
+    }
+
+    /// Ends logins `ttl` after they succeed.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = ttl;
+        self
+    }
+
+    /// Counts users offline `ttl` after their last heartbeat.
+    pub fn with_heartbeat_ttl(mut self, ttl: Duration) -> Self {
+        self.heartbeats = Heartbeats::new(ttl.as_secs() as i64);
+        self
+    }
+
+    /// Counts reply SURBs, and asks clients for more, as `limits` say.
+    pub fn with_surb_limits(mut self, limits: SurbLimits) -> Self {
+        self.surbs = SurbLedger::new(limits);
+        self
+    }
+
+    /// Gossips directory entries with, and forwards messages to, the trusted peers.
+    pub fn with_federation(mut self, federation: Federation) -> Self {
+        self.federation = Some(federation);
+        self
+    }
+
+    /// Serves `transition` to clients asking how the server key changed.
+    pub fn with_key_transition(mut self, transition: KeyTransition) -> Self {
+        self.key_transition = Some(transition);
+        self
+    }
+
+    /// Drains the federation messages produced so far; `main.rs` sends them to
+    /// the peers' nym addresses.
+    pub fn take_peer_messages(&mut self) -> Vec<PeerMessage> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Receives the [`ServerEvent`]s published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Announces every local user to every peer, e.g. after startup.
+    pub fn announce_all(&mut self) {
+        if self.federation.is_none() {
+            return;
+        }
+        match self.db.local_entries() {
+            Ok(entries) => {
+                for (username, public_key) in entries {
+                    self.announce(&username, &public_key);
+                }
+            }
+            Err(e) => error!("announceAll - database error: {}", e),
+        }
+    }
+
+    fn announce(&mut self, username: &str, public_key: &str) {
+        let federation = match &self.federation {
+            Some(federation) => federation,
+            None => return,
+        };
+        let entry = DirectoryEntry {
+            username: username.to_string(),
+            public_key: public_key.to_string(),
+            server: federation.name.clone(),
+        };
+        for peer in federation.peers() {
+            self.outbox
+                .push(federation.message(&self.identity, peer, "federatedEntry", &entry));
+        }
+    }
+
+    /// How long after a request arrived its replies should be sent.
+    pub fn response_delay(&self) -> Duration {
+        self.directory.response_delay
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.identity.public_key()
+    }
+
+    /// Handles one message received with `sender_tag` and returns the replies to send.
+    pub fn handle(&mut self, raw: &str, sender_tag: &str) -> Vec<Reply> {
+        let envelope = match Envelope::parse(raw) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Decoding JSON failed: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let surbs = match envelope.action.as_str() {
+            "surbTopUp" => match envelope.fields.get("surbs").and_then(Value::as_u64) {
+                Some(count) => count.min(self.surbs.limits().max_top_up as u64) as u32,
+                None => self.surbs.limits().per_message,
+            },
+            _ => self.surbs.limits().per_message,
+        };
+        self.surbs.credit(sender_tag, surbs, Instant::now());
+
+        let _span = debug_span!("handle", action = envelope.action.as_str()).entered();
+
+        let replies = match envelope.action.as_str() {
+            // Carries SURBs and nothing else; answering would spend one.
+            "surbTopUp" => Vec::new(),
+            "query" => self.handle_query(&envelope, sender_tag),
+            "capabilities" => self.handle_capabilities(sender_tag),
+            "health" => self.handle_health(&envelope, sender_tag),
+            "directoryFilter" => self.handle_directory_filter(sender_tag),
+            "keyTransition" => self.handle_key_transition(sender_tag),
+            "register" => self.handle_register(&envelope, sender_tag),
+            "registrationResponse" => self.handle_registration_response(&envelope, sender_tag),
+            "login" => self.handle_login(&envelope, sender_tag),
+            "loginResponse" => self.handle_login_response(&envelope, sender_tag),
+            "send" => self.handle_send(&envelope, sender_tag),
+            "sealedSend" => self.handle_sealed_send(&envelope, sender_tag),
+            "sendGroup" => self.handle_send_group(&envelope, sender_tag),
+            "ack" => self.handle_ack(&envelope, sender_tag),
+            "fetchPending" => self.handle_fetch_pending(&envelope, sender_tag),
+            "presence" => self.handle_presence(&envelope, sender_tag),
+            "heartbeat" => self.handle_heartbeat(&envelope, sender_tag),
+            "logout" => self.handle_logout(&envelope, sender_tag),
+            "deleteAccount" => self.handle_delete_account(&envelope, sender_tag),
+            "deleteAccountResponse" => self.handle_delete_account_response(&envelope, sender_tag),
+            "backupPut" => self.handle_backup_put(&envelope, sender_tag),
+            "backupGet" => self.handle_backup_get(&envelope, sender_tag),
+            "backupDelete" => self.handle_backup_delete(&envelope, sender_tag),
+            other => {
+                error!("Unknown encapsulated action: {}", other);
+                Vec::new()
+            }
+        };
+        self.spend_surbs(replies)
+    }
+
+    /// Handles a message from a peer server. These arrive without SURBs and are
+    /// authenticated by the signature of a server on the trust list.
+    /// Returns the SURB replies to send, like [`Handler::handle`].
+    pub fn handle_peer(&mut self, raw: &str) -> Vec<Reply> {
+        let envelope = match Envelope::parse(raw) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Decoding JSON failed: {}", e);
+                return Vec::new();
+            }
+        };
+        if self.federation.is_none() {
+            warn!("Dropping message without SURBs; cannot reply.");
+            return Vec::new();
+        }
+
+        match envelope.action.as_str() {
+            "federatedEntry" => {
+                self.handle_federated_entry(&envelope);
+                Vec::new()
+            }
+            "federatedSend" => {
+                let replies = self.handle_federated_send(&envelope);
+                self.spend_surbs(replies)
+            }
+            other => {
+                error!("Unknown federation action: {}", other);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Counts the SURBs `replies` take. A tag that runs low is sent a
+    /// `surbRequest` for more after its replies; one that runs out cannot be,
+    /// and only an event is published.
+    fn spend_surbs(&mut self, replies: Vec<Reply>) -> Vec<Reply> {
+        let now = Instant::now();
+        let mut requests = Vec::new();
+        for reply in &replies {
+            let warning = match self.surbs.spend(&reply.sender_tag, reply.message.len(), now) {
+                Some(warning) => warning,
+                None => continue,
+            };
+            let sender_tag = reply.sender_tag.clone();
+            let event = match warning {
+                SurbWarning::Low { remaining } => {
+                    requests.push(sender_tag.clone());
+                    ServerEvent::SurbsLow { sender_tag, remaining }
+                }
+                SurbWarning::Exhausted => ServerEvent::SurbsExhausted { sender_tag },
+                SurbWarning::Stale => {
+                    requests.push(sender_tag.clone());
+                    ServerEvent::SurbsStale { sender_tag }
+                }
+            };
+            // Nobody may be listening.
+            let _ = self.events.send(event);
+        }
+
+        let mut replies = replies;
+        for sender_tag in requests {
+            let content = to_python_json(&json!({ "surbs": self.surbs.limits().top_up })).expect("count serializes");
+            let request = self.reply(&sender_tag, "surbRequest", "surbs", content);
+            // The request takes a SURB too; if that was the last, the tag is out.
+            if let Some(SurbWarning::Exhausted) = self.surbs.spend(&sender_tag, request.message.len(), now) {
+                let _ = self.events.send(ServerEvent::SurbsExhausted { sender_tag: sender_tag.clone() });
+            }
+            replies.push(request);
+        }
+        replies
+    }
+
+    /// Builds an envelope around `content`, signed with the server key.
+    fn signed(&self, action: &str, context: &str, content: String) -> Envelope {
+        let signature = Crypto::sign(&self.identity, &content);
+        Envelope::new(action)
+            .with_context(context)
+            .with_content(content)
+            .with_signature(signature)
+    }
+
+    fn padded(sender_tag: &str, envelope: Envelope) -> Reply {
+        let message = envelope
+            .to_padded_json_string(REPLY_BUCKET)
+            .expect("envelope serializes");
+        Reply { sender_tag: sender_tag.to_string(), message }
+    }
+
+    fn reply(&self, sender_tag: &str, action: &str, context: &str, content: impl Into<String>) -> Reply {
+        Self::padded(sender_tag, self.signed(action, context, content.into()))
+    }
+
+    /// Delivers a queued chat message; the recipient acks it by `messageId`.
+    fn deliver(&self, sender_tag: &str, id: i64, payload: String) -> Reply {
+        let envelope = self
+            .signed("incomingMessage", "chat", payload)
+            .with_field("messageId", id.to_string());
+        Self::padded(sender_tag, envelope)
+    }
+
+    /// Sends the queued messages for `username` after `after` as one
+    /// `pendingResponse`: `{"messages": [{"messageId", "content"}], "cursor",
+    /// "more"}`. The client acks them and asks for the page after `cursor`.
+    fn pending_page(&self, sender_tag: &str, username: &str, after: i64, limit: usize) -> Reply {
+        let page = match self.queue.page(self.db.as_ref(), username, after, limit) {
+            Ok(page) => page,
+            Err(e) => {
+                error!("fetchPending - reading offline queue failed: {}", e);
+                return self.reply(sender_tag, "pendingResponse", "chat", "error: database failure");
+            }
+        };
+        let messages: Vec<Value> = page
+            .messages
+            .into_iter()
+            .map(|(id, payload)| json!({ "messageId": id.to_string(), "content": payload }))
+            .collect();
+        let content = json!({ "messages": messages, "cursor": page.cursor, "more": page.more });
+        self.reply(sender_tag, "pendingResponse", "chat", to_python_json(&content).expect("page serializes"))
+    }
+
+    fn handle_query(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        if !self.rate_limiter.allow(sender_tag, Instant::now()) {
+            warn!("handleQuery - rate limited");
+            return vec![self.reply(sender_tag, "queryResponse", "query", "error: rate limited")];
+        }
+
+        let username = match envelope.field("username") {
+            Some(username) => username,
+            None => {
+                warn!("handleQuery - missing username field");
+                return vec![self.reply(sender_tag, "queryResponse", "query", "error: missing 'username' field")];
+            }
+        };
+
+        let content = match self.db.get_user(username) {
+            Ok(Some(user)) => to_python_json(&UserRecord {
+                routing_token: Some(self.routing.issue(&user.username)),
+                username: user.username,
+                public_key: user.public_key,
+            })
+            .expect("user record serializes"),
+            Ok(None) => match self.db.get_remote_user(username) {
+                Ok(Some(user)) => to_python_json(&UserRecord {
+                    routing_token: Some(self.routing.issue(&user.username)),
+                    username: user.username,
+                    public_key: user.public_key,
+                })
+                .expect("user record serializes"),
+                _ => return vec![self.query_miss(sender_tag, username)],
+            },
+            Err(e) => {
+                error!("handleQuery - database error: {}", e);
+                return vec![self.query_miss(sender_tag, username)];
+            }
+        };
+        vec![self.reply(sender_tag, "queryResponse", "query", content)]
+    }
+
+    /// A miss names the user looked up, so a client with several queries in
+    /// flight knows which one it answers.
+    fn query_miss(&self, sender_tag: &str, username: &str) -> Reply {
+        let envelope = self
+            .signed("queryResponse", "query", "No user found".to_string())
+            .with_field("username", username);
+        Self::padded(sender_tag, envelope)
+    }
+
+    /// Advertises the optional features this server has enabled.
+    fn handle_capabilities(&self, sender_tag: &str) -> Vec<Reply> {
+        let content = to_python_json(&json!({
+            "directoryFilter": self.directory.bloom_filter,
+            "offlineQueue": true,
+            "pendingPages": self.queue.limits().page_size,
+            "registrationPow": self.pow_difficulty,
+            "backups": self.blobs.max_blobs > 0,
+            "routingTokens": true,
+            "sealedSender": true,
+            "groups": true,
+            "heartbeatTtl": self.heartbeats.ttl(),
+        }))
+        .expect("capabilities serialize");
+        vec![self.reply(sender_tag, "capabilitiesResponse", "capabilities", content)]
+    }
+
+    /// Answers a liveness probe. The client's nonce is echoed inside the signed
+    /// content, so a reply cannot be replayed to answer a later probe.
+    fn handle_health(&self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let nonce = match envelope.field("nonce") {
+            Some(nonce) if nonce.len() <= MAX_HEALTH_NONCE => nonce,
+            _ => {
+                warn!("handleHealth - missing or oversized nonce");
+                return vec![self.reply(sender_tag, "healthResponse", "health", "error: invalid nonce")];
+            }
+        };
+        let content = to_python_json(&json!({
+            "nonce": nonce,
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": self.started.elapsed().as_secs(),
+            "server": self.federation.as_ref().map(|federation| federation.name.as_str()),
+        }))
+        .expect("health serializes");
+        vec![self.reply(sender_tag, "healthResponse", "health", content)]
+    }
+
+    fn handle_key_transition(&self, sender_tag: &str) -> Vec<Reply> {
+        let content = match &self.key_transition {
+            Some(transition) => to_python_json(transition).expect("key transition serializes"),
+            None => "error: no key transition".to_string(),
+        };
+        vec![self.reply(sender_tag, "keyTransitionResponse", "keyTransition", content)]
+    }
+
+    /// Serves the bloom filter of registered usernames, if enabled.
+    fn handle_directory_filter(&mut self, sender_tag: &str) -> Vec<Reply> {
+        let reply = |handler: &Self, content: String| {
+            vec![handler.reply(sender_tag, "directoryFilterResponse", "query", content)]
+        };
+        if !self.directory.bloom_filter {
+            return reply(self, "error: directory filter disabled".to_string());
+        }
+        if !self.rate_limiter.allow(sender_tag, Instant::now()) {
+            warn!("handleDirectoryFilter - rate limited");
+            return reply(self, "error: rate limited".to_string());
+        }
+
+        match self.username_filter.get(self.db.as_ref()) {
+            Ok(filter) => {
+                let content = serde_json::to_string(filter).expect("bloom filter serializes");
+                reply(self, content)
+            }
+            Err(e) => {
+                error!("handleDirectoryFilter - database error: {}", e);
+                reply(self, "error: database failure".to_string())
+            }
+        }
+    }
+
+    fn handle_register(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let error = |handler: &Self, msg: &str| vec![handler.reply(sender_tag, "challengeResponse", "registration", msg)];
+
+        let (username, public_key) = match (envelope.field("usernym"), envelope.field("publicKey")) {
+            (Some(username), Some(public_key)) => (username, public_key),
+            _ => return error(self, "error: missing username or public key"),
+        };
+        if !is_valid_username(username) {
+            return error(self, "error: invalid username format");
+        }
+        if Crypto::public_key_from_pem(public_key).is_err() {
+            return error(self, "error: invalid public key");
+        }
+        if self.is_banned(username) {
+            return error(self, "error: username already in use");
+        }
+        match (self.db.get_user(username), self.db.get_remote_user(username)) {
+            (Ok(None), Ok(None)) => {}
+            (Ok(Some(_)), _) | (_, Ok(Some(_))) => return error(self, "error: username already in use"),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("handleRegister - database error: {}", e);
+                return error(self, "error: database failure");
+            }
+        }
+
+        let nonce = self
+            .registrations
+            .issue(sender_tag, username, public_key.to_string(), Instant::now());
+        let content = challenge_content(&nonce, self.pow_difficulty);
+        info!("handleRegister - sending challenge");
+        vec![self.reply(sender_tag, "challenge", "registration", content)]
+    }
+
+    fn handle_registration_response(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let pending = match self
+            .registrations
+            .take(sender_tag, envelope.field("username"), Instant::now())
+        {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("handleRegistrationResponse - rejected challenge: {:?}", e);
+                let content = challenge_error(e, "error: no pending registration for sender");
+                return vec![self.reply(sender_tag, "challengeResponse", "registration", content)];
+            }
+        };
+
+        let solution = match envelope.fields.get("pow") {
+            Some(Value::Number(n)) => n.as_u64(),
+            Some(Value::String(s)) => s.parse().ok(),
+            _ => None,
+        };
+        let solved = self.pow_difficulty == 0
+            || solution.is_some_and(|counter| pow::verify(&pending.nonce, self.pow_difficulty, counter));
+        if !solved {
+            warn!("handleRegistrationResponse - proof of work missing or invalid");
+            return vec![self.reply(sender_tag, "challengeResponse", "registration", "error: invalid proof of work")];
+        }
+
+        let verified = match (Crypto::public_key_from_pem(&pending.data), envelope.signature.as_deref()) {
+            (Ok(key), Some(signature)) => Crypto::verify(&key, &pending.nonce, signature),
+            _ => false,
+        };
+        if !verified {
+            warn!("handleRegistrationResponse - registration failed");
+            return vec![self.reply(
+                sender_tag,
+                "challengeResponse",
+                "registration",
+                "error: signature verification failed",
+            )];
+        }
+
+        let content = match self.db.add_user(&pending.username, &pending.data, sender_tag) {
+            Ok(true) => {
+                self.username_filter.invalidate();
+                self.announce(&pending.username, &pending.data);
+                info!("handleRegistrationResponse - registration successful");
+                // Registering logs the new user in.
+                let session = self.start_session(&pending.username, sender_tag);
+                return vec![self.reply(sender_tag, "challengeResponse", "registration", "success"), session];
+            }
+            Ok(false) => "error: username already in use",
+            Err(e) => {
+                error!("handleRegistrationResponse - database error: {}", e);
+                "error: database failure"
+            }
+        };
+        vec![self.reply(sender_tag, "challengeResponse", "registration", content)]
+    }
+
+    /// Starts a session for `username` and builds the reply telling the client how long it lasts.
+    fn start_session(&mut self, username: &str, sender_tag: &str) -> Reply {
+        let ttl = self.session_ttl.as_secs();
+        self.sessions.insert(username.to_string(), now_secs() + ttl as i64);
+        self.heartbeats.forget(username);
+        let content = to_python_json(&json!({ "ttl": ttl })).expect("session serializes");
+        self.reply(sender_tag, "session", "login", content)
+    }
+
+    /// Unknown usernames receive a decoy challenge that can never be answered,
+    /// so a login attempt does not reveal whether an account exists.
+    fn handle_login(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match envelope.field("usernym") {
+            Some(username) => username,
+            None => {
+                warn!("handleLogin - missing username");
+                return vec![self.reply(sender_tag, "challengeResponse", "login", "error: missing username")];
+            }
+        };
+
+        // Banned users get a decoy challenge, like unknown ones.
+        let public_key = match self.db.get_user(username) {
+            Ok(_) if self.is_banned(username) => None,
+            Ok(user) => user.map(|user| user.public_key),
+            Err(e) => {
+                error!("handleLogin - database error: {}", e);
+                None
+            }
+        };
+
+        let nonce = self.logins.issue(sender_tag, username, public_key, Instant::now());
+        let content = challenge_content(&nonce, 0);
+        info!("handleLogin - sending challenge");
+        vec![self.reply(sender_tag, "challenge", "login", content)]
+    }
+
+    fn handle_login_response(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let pending = match self.logins.take(sender_tag, envelope.field("username"), Instant::now()) {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("handleLoginResponse - rejected challenge: {:?}", e);
+                let content = challenge_error(e, "error: no pending login for sender");
+                return vec![self.reply(sender_tag, "challengeResponse", "login", content)];
+            }
+        };
+
+        // Decoy logins still pay for a verification so both paths take the same time.
+        let key = pending
+            .data
+            .as_deref()
+            .and_then(|pem| Crypto::public_key_from_pem(pem).ok());
+        let signature = envelope.signature.as_deref().unwrap_or_default();
+        let verified = match key {
+            Some(key) => Crypto::verify(&key, &pending.nonce, signature),
+            None => {
+                Crypto::verify(&self.public_key(), &pending.nonce, signature);
+                false
+            }
+        };
+
+        if !verified {
+            warn!("handleLoginResponse - invalid signature");
+            return vec![self.reply(sender_tag, "challengeResponse", "login", "error: invalid signature")];
+        }
+
+        self.refresh_sender_tag(&pending.username, sender_tag);
+        info!("handleLoginResponse - success!");
+        let mut replies = vec![
+            self.reply(sender_tag, "challengeResponse", "login", "success"),
+            self.start_session(&pending.username, sender_tag),
+        ];
+
+        // Clients that page through the queue get the first page now instead of all of it.
+        if let Some(limit) = envelope.fields.get("pendingPages").and_then(Value::as_u64) {
+            replies.push(self.pending_page(sender_tag, &pending.username, 0, limit as usize));
+            return replies;
+        }
+        match self.queue.pending(self.db.as_ref(), &pending.username) {
+            Ok(queued) => {
+                if !queued.is_empty() {
+                    info!("handleLoginResponse - delivering {} queued messages", queued.len());
+                }
+                replies.extend(queued.into_iter().map(|(id, payload)| self.deliver(sender_tag, id, payload)));
+            }
+            Err(e) => error!("handleLoginResponse - reading offline queue failed: {}", e),
+        }
+        replies
+    }
+
+    /// Starts deleting the sender's account. Only accepted from the sender tag
+    /// the user logged in with, and confirmed by signing a challenge.
+    fn handle_delete_account(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let respond =
+            |handler: &Self, msg: &str| vec![handler.reply(sender_tag, "challengeResponse", "deleteAccount", msg)];
+        let user = match self.session_user(envelope, sender_tag).map(|username| self.db.get_user(username)) {
+            Ok(Ok(Some(user))) => user,
+            Ok(_) => return respond(self, "error: not logged in"),
+            Err(e) => {
+                warn!("handleDeleteAccount - {}", e);
+                return respond(self, e);
+            }
+        };
+
+        let nonce = self.deletions.issue(sender_tag, &user.username, user.public_key, Instant::now());
+        info!("handleDeleteAccount - sending challenge");
+        vec![self.reply(sender_tag, "challenge", "deleteAccount", challenge_content(&nonce, 0))]
+    }
+
+    fn handle_delete_account_response(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let respond =
+            |handler: &Self, msg: &str| vec![handler.reply(sender_tag, "challengeResponse", "deleteAccount", msg)];
+        let pending = match self.deletions.take(sender_tag, envelope.field("username"), Instant::now()) {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("handleDeleteAccountResponse - rejected challenge: {:?}", e);
+                return respond(self, challenge_error(e, "error: no pending deletion for sender"));
+            }
+        };
+        let verified = Crypto::public_key_from_pem(&pending.data)
+            .map(|key| Crypto::verify(&key, &pending.nonce, envelope.signature.as_deref().unwrap_or_default()))
+            .unwrap_or(false);
+        if !verified {
+            warn!("handleDeleteAccountResponse - invalid signature");
+            return respond(self, "error: invalid signature");
+        }
+
+        match self.db.delete_user(&pending.username) {
+            Ok(_) => {
+                self.presence.forget(&pending.username);
+                self.heartbeats.forget(&pending.username);
+                self.sessions.remove(&pending.username);
+                info!("handleDeleteAccountResponse - deleted {}", pending.username);
+                respond(self, "success")
+            }
+            Err(e) => {
+                error!("handleDeleteAccountResponse - database error: {}", e);
+                respond(self, "error: database failure")
+            }
+        }
+    }
+
+    fn backup_response(&self, sender_tag: &str, index: Option<u64>, status: &str) -> Vec<Reply> {
+        let content = match index {
+            Some(index) => json!({ "index": index, "status": status }),
+            None => json!({ "status": status }),
+        };
+        let content = to_python_json(&content).expect("backup response serializes");
+        vec![self.reply(sender_tag, "backupResponse", "backup", content)]
+    }
+
+    /// Checks the blob id and write token of a backup write. Returns the token
+    /// hash to store, and whether the blob already exists.
+    fn authorize_blob_write(&self, envelope: &Envelope) -> Result<(String, bool), &'static str> {
+        if self.blobs.max_blobs == 0 {
+            return Err("error: backups disabled");
+        }
+        let id = envelope.field("backupId").filter(|id| blobs::is_valid_blob_id(id));
+        let token = envelope
+            .field("token")
+            .filter(|token| !token.is_empty() && token.len() <= blobs::MAX_TOKEN_LEN);
+        let (id, token) = match (id, token) {
+            (Some(id), Some(token)) => (id, token),
+            _ => return Err("error: invalid backup id or token"),
+        };
+        let hash = blobs::token_hash(token);
+        match self.db.blob_token(id) {
+            Ok(Some(stored)) if ct_eq(stored.as_bytes(), hash.as_bytes()) => Ok((hash, true)),
+            Ok(Some(_)) => Err("error: invalid token"),
+            Ok(None) => Ok((hash, false)),
+            Err(e) => {
+                error!("backup - database error: {}", e);
+                Err("error: database failure")
+            }
+        }
+    }
+
+    /// Stores one chunk of a client backup. Chunks beyond `total` are dropped,
+    /// so a backup that shrank does not keep stale data.
+    fn handle_backup_put(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let index = envelope.fields.get("index").and_then(Value::as_u64);
+        let (hash, exists) = match self.authorize_blob_write(envelope) {
+            Ok(authorized) => authorized,
+            Err(status) => {
+                warn!("handleBackupPut - {}", status);
+                return self.backup_response(sender_tag, index, status);
+            }
+        };
+        let total = envelope.fields.get("total").and_then(Value::as_u64);
+        let data = envelope.field("data").unwrap_or_default();
+        let (index, total) = match (index, total) {
+            (Some(index), Some(total)) if index < total && total <= u64::from(self.blobs.max_chunks) => {
+                (index as u32, total as u32)
+            }
+            _ => return self.backup_response(sender_tag, index, "error: invalid chunk index"),
+        };
+        if data.len() > self.blobs.max_chunk_size {
+            return self.backup_response(sender_tag, Some(index.into()), "error: chunk too large");
+        }
+        if !exists && self.db.blob_count().map_or(true, |count| count >= self.blobs.max_blobs) {
+            warn!("handleBackupPut - blob store full");
+            return self.backup_response(sender_tag, Some(index.into()), "error: backup store full");
+        }
+
+        let id = envelope.field("backupId").unwrap_or_default();
+        match self.db.put_blob_chunk(id, &hash, index, total, data) {
+            Ok(()) => self.backup_response(sender_tag, Some(index.into()), "success"),
+            Err(e) => {
+                error!("handleBackupPut - database error: {}", e);
+                self.backup_response(sender_tag, Some(index.into()), "error: database failure")
+            }
+        }
+    }
+
+    /// Sends every stored chunk of a backup, one `backupChunk` reply each. A
+    /// backup that does not exist is answered with `total` 0.
+    fn handle_backup_get(&self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let id = match envelope.field("backupId").filter(|id| blobs::is_valid_blob_id(id)) {
+            Some(id) => id,
+            None => return self.backup_response(sender_tag, None, "error: invalid backup id"),
+        };
+        let chunk = |index: u32, total: u32, data: &str| {
+            let content = json!({ "backupId": id, "index": index, "total": total, "data": data });
+            let content = to_python_json(&content).expect("backup chunk serializes");
+            self.reply(sender_tag, "backupChunk", "backup", content)
+        };
+        match self.db.get_blob(id) {
+            Ok(Some(blob)) => {
+                info!("handleBackupGet - sending {} chunks", blob.chunks.len());
+                blob.chunks
+                    .iter()
+                    .map(|(index, data)| chunk(*index, blob.total, data))
+                    .collect()
+            }
+            Ok(None) => vec![chunk(0, 0, "")],
+            Err(e) => {
+                error!("handleBackupGet - database error: {}", e);
+                self.backup_response(sender_tag, None, "error: database failure")
+            }
+        }
+    }
+
+    fn handle_backup_delete(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let status = match self.authorize_blob_write(envelope) {
+            Ok((_, false)) => "error: no such backup",
+            Ok((_, true)) => match self.db.delete_blob(envelope.field("backupId").unwrap_or_default()) {
+                Ok(_) => "success",
+                Err(e) => {
+                    error!("handleBackupDelete - database error: {}", e);
+                    "error: database failure"
+                }
+            },
+            Err(status) => status,
+        };
+        self.backup_response(sender_tag, None, status)
+    }
+
+    /// Answers a `send` with `status`. A request carrying an `id` gets
+    /// `{"id": ..., "status": ...}` instead of the bare status, so a client with
+    /// several messages in flight knows which one it refers to.
+    fn send_response(&self, request: &Envelope, sender_tag: &str, status: &str) -> Reply {
+        let content = match request.field("id") {
+            Some(id) if id.len() <= MAX_SEND_ID => {
+                to_python_json(&json!({ "id": id, "status": status })).expect("send response serializes")
+            }
+            _ => status.to_string(),
+        };
+        self.reply(sender_tag, "sendResponse", "chat", content)
+    }
+
+    fn handle_send(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let error = |handler: &Self, msg: &str| vec![handler.send_response(envelope, sender_tag, msg)];
+
+        let (content, signature) = match (envelope.content.as_deref(), envelope.signature.as_deref()) {
+            (Some(content), Some(signature)) => (content, signature),
+            _ => {
+                warn!("handleSend - missing content or signature");
+                return error(self, "error: missing 'content' or 'signature'");
+            }
+        };
+
+        let payload: ChatPayload = match serde_json::from_str(content) {
+            Ok(payload) => payload,
+            Err(_) => {
+                warn!("handleSend - invalid JSON");
+                return error(self, "error: invalid JSON in content");
+            }
+        };
+        let recipient = match self.recipient(payload.recipient.as_deref(), payload.recipient_token.as_deref()) {
+            Ok(recipient) => recipient,
+            Err(e) => return error(self, e),
+        };
+
+        if let Err(e) = self.check_sender(&payload.sender, content, signature, sender_tag) {
+            return error(self, e);
+        }
+
+        // Forward exactly what the Python server forwards: sender, body and the optional key.
+        let forward = ChatPayload {
+            sender: payload.sender.clone(),
+            recipient: None,
+            recipient_token: None,
+            body: payload.body.clone(),
+            encrypted: None,
+            sender_public_key: payload.sender_public_key.clone(),
+        };
+        let forward = to_python_json(&forward).expect("chat payload serializes");
+
+        let target = match self.db.get_user(&recipient) {
+            Ok(Some(target)) => target,
+            _ => {
+                if self.forward_to_home_server(&recipient, forward) {
+                    return vec![self.send_response(envelope, sender_tag, "success")];
+                }
+                warn!("handleSend - could not find recipient in DB");
+                return error(self, "error: recipient not found");
+            }
+        };
+
+        let delivery = match self.queue_and_deliver(&target, forward) {
+            Ok(delivery) => delivery,
+            Err(e) => return error(self, e),
+        };
+        delivery.into_iter().chain([self.send_response(envelope, sender_tag, "success")]).collect()
+    }
+
+    /// Checks that `content` was signed by `username`, a registered user who
+    /// is not banned, and remembers the sender tag it came from.
+    fn check_sender(
+        &self,
+        username: &str,
+        content: &str,
+        signature: &str,
+        sender_tag: &str,
+    ) -> Result<(), &'static str> {
+        let sender = match self.db.get_user(username) {
+            Ok(Some(sender)) if !self.is_banned(&sender.username) => sender,
+            _ => {
+                warn!("handleSend - could not find sender in DB");
+                return Err("error: unrecognized sender username");
+            }
+        };
+        let verified = Crypto::public_key_from_pem(&sender.public_key)
+            .map(|key| Crypto::verify(&key, content, signature))
+            .unwrap_or(false);
+        if !verified {
+            warn!("handleSend - invalid signature");
+            return Err("error: invalid signature");
+        }
+        if sender.sender_tag != sender_tag {
+            self.refresh_sender_tag(&sender.username, sender_tag);
+        }
+        Ok(())
+    }
+
+    /// The username a send is addressed to. Clients that address by routing
+    /// token leave the username out.
+    fn recipient(&self, recipient: Option<&str>, token: Option<&str>) -> Result<String, &'static str> {
+        match (recipient, token) {
+            (Some(recipient), _) => Ok(recipient.to_string()),
+            (None, Some(token)) => self.routing.open(token).ok_or_else(|| {
+                warn!("handleSend - invalid routing token");
+                "error: invalid routing token"
+            }),
+            (None, None) => {
+                warn!("handleSend - missing sender/recipient");
+                Err("error: missing 'sender' or 'recipient' field in message content")
+            }
+        }
+    }
+
+    /// Relays a message whose sender is sealed inside the end-to-end
+    /// encryption. Nothing here identifies the sender: the request is not
+    /// signed and its sender tag is not recorded, so the server only learns
+    /// that someone wrote to the recipient. Sealed senders cannot be checked
+    /// against bans, so the sender tag is rate limited instead.
+    fn handle_sealed_send(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let error = |handler: &Self, msg: &str| vec![handler.send_response(envelope, sender_tag, msg)];
+
+        if !self.sealed_limiter.allow(sender_tag, Instant::now()) {
+            warn!("handleSealedSend - rate limited");
+            return error(self, "error: rate limited");
+        }
+        let payload: SealedPayload = match envelope.content.as_deref().map(serde_json::from_str) {
+            Some(Ok(payload)) => payload,
+            _ => {
+                warn!("handleSealedSend - invalid JSON");
+                return error(self, "error: invalid JSON in content");
+            }
+        };
+        let recipient = match self.recipient(payload.recipient.as_deref(), payload.recipient_token.as_deref()) {
+            Ok(recipient) => recipient,
+            Err(e) => return error(self, e),
+        };
+        // Peers only accept messages whose sender they can check, so sealed
+        // messages stay on this server.
+        let target = match self.db.get_user(&recipient) {
+            Ok(Some(target)) => target,
+            _ => {
+                warn!("handleSealedSend - could not find recipient in DB");
+                return error(self, "error: recipient not found");
+            }
+        };
+
+        let forward = SealedPayload {
+            recipient: None,
+            recipient_token: None,
+            sealed: payload.sealed,
+        };
+        let forward = to_python_json(&forward).expect("sealed payload serializes");
+        let delivery = match self.queue_and_deliver(&target, forward) {
+            Ok(delivery) => delivery,
+            Err(e) => return error(self, e),
+        };
+        delivery.into_iter().chain([self.send_response(envelope, sender_tag, "success")]).collect()
+    }
+
+    /// Relays a group message, encrypted once under the sender's sender key, to
+    /// every listed member. The sender signs the request as for `send`. All
+    /// recipients must be local users: nothing is delivered if one is missing,
+    /// and group messages are not federated.
+    fn handle_send_group(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let error = |handler: &Self, msg: &str| vec![handler.send_response(envelope, sender_tag, msg)];
+
+        let (content, signature) = match (envelope.content.as_deref(), envelope.signature.as_deref()) {
+            (Some(content), Some(signature)) => (content, signature),
+            _ => {
+                warn!("handleSendGroup - missing content or signature");
+                return error(self, "error: missing 'content' or 'signature'");
+            }
+        };
+        let payload: GroupPayload = match serde_json::from_str(content) {
+            Ok(payload) => payload,
+            Err(_) => {
+                warn!("handleSendGroup - invalid JSON");
+                return error(self, "error: invalid JSON in content");
+            }
+        };
+        let count = payload.recipients.len() + payload.recipient_tokens.len();
+        if count == 0 {
+            return error(self, "error: missing 'recipients' field in message content");
+        }
+        if count > MAX_GROUP_RECIPIENTS {
+            warn!("handleSendGroup - too many recipients");
+            return error(self, "error: too many recipients");
+        }
+        if let Err(e) = self.check_sender(&payload.sender, content, signature, sender_tag) {
+            return error(self, e);
+        }
+
+        let by_name = payload.recipients.iter().map(|name| self.recipient(Some(name), None));
+        let by_token = payload.recipient_tokens.iter().map(|token| self.recipient(None, Some(token)));
+        let mut names = HashSet::new();
+        let mut targets = Vec::with_capacity(count);
+        for recipient in by_name.chain(by_token) {
+            let recipient = match recipient {
+                Ok(recipient) => recipient,
+                Err(e) => return error(self, e),
+            };
+            if recipient == payload.sender || !names.insert(recipient.clone()) {
+                continue;
+            }
+            match self.db.get_user(&recipient) {
+                Ok(Some(target)) => targets.push(target),
+                _ => {
+                    warn!("handleSendGroup - could not find recipient in DB");
+                    return error(self, "error: recipient not found");
+                }
+            }
+        }
+
+        let forward = GroupPayload {
+            sender: payload.sender,
+            recipients: Vec::new(),
+            recipient_tokens: Vec::new(),
+            group: payload.group,
+            group_signature: payload.group_signature,
+        };
+        let forward = to_python_json(&forward).expect("group payload serializes");
+        let mut replies = Vec::with_capacity(targets.len() + 1);
+        for target in &targets {
+            match self.queue_and_deliver(target, forward.clone()) {
+                Ok(delivery) => replies.extend(delivery),
+                Err(e) => return error(self, e),
+            }
+        }
+        replies.push(self.send_response(envelope, sender_tag, "success"));
+        replies
+    }
+
+    /// Queues `forward` for a local user and builds its delivery. The message is
+    /// queued first so it survives until the recipient acks it, even if the
+    /// SURBs behind their stored tag are stale. Recipients whose heartbeats
+    /// stopped get it with the queue once they are back, so `None` then.
+    fn queue_and_deliver(&self, target: &User, forward: String) -> Result<Option<Reply>, &'static str> {
+        match self.queue.push(self.db.as_ref(), &target.username, &forward) {
+            Ok(_) if self.heartbeats.liveness(&target.username, now_secs()) == Liveness::Offline => {
+                info!("handleSend - recipient offline, queued only");
+                Ok(None)
+            }
+            Ok(id) => Ok(Some(self.deliver(&target.sender_tag, id, forward))),
+            Err(QueueError::TooLarge) => {
+                warn!("handleSend - message too large to queue");
+                Err("error: message too large")
+            }
+            Err(QueueError::Storage(e)) => {
+                error!("handleSend - queueing failed, delivering directly: {}", e);
+                Ok(Some(self.reply(&target.sender_tag, "incomingMessage", "chat", forward)))
+            }
+        }
+    }
+
+    /// Hands a message for a user homed on a peer server to that server.
+    /// Returns `false` if the recipient is not known to any peer.
+    fn forward_to_home_server(&mut self, recipient: &str, payload: String) -> bool {
+        let federation = match &self.federation {
+            Some(federation) => federation,
+            None => return false,
+        };
+        let home = match self.db.get_remote_user(recipient) {
+            Ok(Some(user)) => user.server,
+            _ => return false,
+        };
+        let peer = match federation.peer(&home) {
+            Some(peer) => peer,
+            None => {
+                warn!("handleSend - recipient homed on untrusted server {}", home);
+                return false;
+            }
+        };
+        let forwarded = ForwardedMessage {
+            recipient: recipient.to_string(),
+            server: federation.name.clone(),
+            payload,
+        };
+        info!("handleSend - forwarding to home server {}", home);
+        self.outbox
+            .push(federation.message(&self.identity, peer, "federatedSend", &forwarded));
+        true
+    }
+
+    fn handle_federated_entry(&mut self, envelope: &Envelope) {
+        let federation = self.federation.as_ref().expect("checked by handle_peer");
+        let entry = match federation.verify(envelope, |entry: &DirectoryEntry| &entry.server) {
+            Some(entry) => entry,
+            None => {
+                warn!("handleFederatedEntry - unverified entry");
+                return;
+            }
+        };
+        if !is_valid_username(&entry.username) || Crypto::public_key_from_pem(&entry.public_key).is_err() {
+            warn!("handleFederatedEntry - malformed entry from {}", entry.server);
+            return;
+        }
+        // Local registrations always win over gossip.
+        if let Ok(Some(_)) = self.db.get_user(&entry.username) {
+            warn!("handleFederatedEntry - {} claims a local username", entry.server);
+            return;
+        }
+        match self
+            .db
+            .upsert_remote_user(&entry.username, &entry.public_key, &entry.server)
+        {
+            Ok(true) => info!("handleFederatedEntry - stored entry from {}", entry.server),
+            Ok(false) => warn!("handleFederatedEntry - username is homed on another server"),
+            Err(e) => error!("handleFederatedEntry - database error: {}", e),
+        }
+    }
+
+    fn handle_federated_send(&mut self, envelope: &Envelope) -> Vec<Reply> {
+        let federation = self.federation.as_ref().expect("checked by handle_peer");
+        let forwarded = match federation.verify(envelope, |message: &ForwardedMessage| &message.server) {
+            Some(forwarded) => forwarded,
+            None => {
+                warn!("handleFederatedSend - unverified message");
+                return Vec::new();
+            }
+        };
+
+        // A peer may only relay messages from users it homes.
+        let sender_home = serde_json::from_str::<ChatPayload>(&forwarded.payload)
+            .ok()
+            .and_then(|payload| self.db.get_remote_user(&payload.sender).ok().flatten())
+            .map(|user| user.server);
+        if sender_home.as_deref() != Some(forwarded.server.as_str()) {
+            warn!("handleFederatedSend - sender is not homed on {}", forwarded.server);
+            return Vec::new();
+        }
+
+        let target = match self.db.get_user(&forwarded.recipient) {
+            Ok(Some(target)) => target,
+            _ => {
+                warn!("handleFederatedSend - recipient is not homed here");
+                return Vec::new();
+            }
+        };
+        match self.queue_and_deliver(&target, forwarded.payload) {
+            Ok(delivery) => delivery.into_iter().collect(),
+            Err(e) => {
+                warn!("handleFederatedSend - {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The `username` of a request, if it came from the sender tag that user
+    /// last logged in or sent with and their login has not expired.
+    fn session_user<'a>(&self, envelope: &'a Envelope, sender_tag: &str) -> Result<&'a str, &'static str> {
+        let username = envelope.field("username").ok_or("error: not logged in")?;
+        match self.db.get_user(username) {
+            Ok(Some(user)) if ct_eq(user.sender_tag.as_bytes(), sender_tag.as_bytes()) => {}
+            _ => return Err("error: not logged in"),
+        }
+        match self.sessions.get(username) {
+            Some(&expires) if now_secs() < expires => Ok(username),
+            _ => Err("error: session expired"),
+        }
+    }
+
+    /// Removes delivered messages from the offline queue. Only accepted from the
+    /// sender tag the user last logged in or sent with.
+    fn handle_ack(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match self.session_user(envelope, sender_tag) {
+            Ok(username) => username,
+            Err(e) => {
+                warn!("handleAck - {}", e);
+                return vec![self.reply(sender_tag, "ackResponse", "chat", e)];
+            }
+        };
+
+        let ids: Vec<i64> = match envelope.fields.get("ids") {
+            Some(Value::Array(ids)) => ids
+                .iter()
+                .filter_map(|id| match id {
+                    Value::Number(n) => n.as_i64(),
+                    Value::String(s) => s.parse().ok(),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Err(e) = self.queue.ack(self.db.as_ref(), username, &ids) {
+            error!("handleAck - database error: {}", e);
+        }
+        Vec::new()
+    }
+
+    /// Sends the next page of the sender's offline queue:
+    /// `{"username", "after": <cursor>, "limit"}`. Paging does not remove
+    /// anything; messages stay queued until they are acked.
+    fn handle_fetch_pending(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match self.session_user(envelope, sender_tag) {
+            Ok(username) => username,
+            Err(e) => {
+                warn!("handleFetchPending - {}", e);
+                return vec![self.reply(sender_tag, "pendingResponse", "chat", e)];
+            }
+        };
+        let after = envelope.fields.get("after").and_then(Value::as_i64).unwrap_or(0);
+        let limit = envelope.fields.get("limit").and_then(Value::as_u64).unwrap_or(u64::MAX);
+        vec![self.pending_page(sender_tag, username, after, limit as usize)]
+    }
+
+    /// Opts in to (or out of) presence and sets the contacts to watch:
+    /// `{"username", "share": [...], "watch": [...]}`. Clients repeat it while
+    /// online. The reply lists the watched contacts that share with the sender.
+    fn handle_presence(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match self.session_user(envelope, sender_tag) {
+            Ok(username) => username.to_string(),
+            Err(e) => {
+                warn!("handlePresence - {}", e);
+                return vec![self.reply(sender_tag, "presenceResponse", "presence", e)];
+            }
+        };
+        let share: HashSet<String> = string_list(envelope, "share").into_iter().collect();
+        let watch = string_list(envelope, "watch");
+        let now = now_secs();
+
+        let watchers = self.presence.announce(&username, share, now);
+        let mut replies = self.presence_updates(&username, watchers, now);
+        let statuses = self.presence.watch(&username, &watch, now);
+        let content = to_python_json(&statuses).expect("presence statuses serialize");
+        replies.push(self.reply(sender_tag, "presenceResponse", "presence", content));
+        replies
+    }
+
+    /// Records that the sender is online: `{"username"}`, or with `"enabled":
+    /// false that they stopped sending heartbeats. Heartbeats get no reply,
+    /// unless the sender was offline: then the first page of what was queued
+    /// meanwhile, as `fetchPending` would send it.
+    fn handle_heartbeat(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match self.session_user(envelope, sender_tag) {
+            Ok(username) => username,
+            Err(e) => {
+                warn!("handleHeartbeat - {}", e);
+                return vec![self.reply(sender_tag, "pendingResponse", "chat", e)];
+            }
+        };
+        if envelope.fields.get("enabled") == Some(&Value::Bool(false)) {
+            self.heartbeats.forget(username);
+            return Vec::new();
+        }
+        if !self.heartbeats.beat(username, now_secs()) {
+            return Vec::new();
+        }
+        info!("handleHeartbeat - back online");
+        vec![self.pending_page(sender_tag, username, 0, self.queue.limits().page_size)]
+    }
+
+    /// Marks the sender offline for the contacts watching them.
+    fn handle_logout(&mut self, envelope: &Envelope, sender_tag: &str) -> Vec<Reply> {
+        let username = match self.session_user(envelope, sender_tag) {
+            Ok(username) => username.to_string(),
+            Err(e) => {
+                warn!("handleLogout - {}", e);
+                return Vec::new();
+            }
+        };
+        self.sessions.remove(&username);
+        self.heartbeats.forget(&username);
+        let now = now_secs();
+        let watchers = self.presence.logout(&username, now);
+        self.presence_updates(&username, watchers, now)
+    }
+
+    /// Tells each of `watchers` the current presence of `username`.
+    fn presence_updates(&self, username: &str, watchers: Vec<String>, now: i64) -> Vec<Reply> {
+        watchers
+            .iter()
+            .filter_map(|watcher| {
+                let status = self.presence.status(username, watcher, now)?;
+                let tag = match self.db.get_user(watcher) {
+                    Ok(Some(user)) => user.sender_tag,
+                    _ => return None,
+                };
+                let content = to_python_json(&status).expect("presence status serializes");
+                Some(self.reply(&tag, "presenceUpdate", "presence", content))
+            })
+            .collect()
+    }
+
+    fn is_banned(&self, username: &str) -> bool {
+        self.db.is_banned(username).unwrap_or_else(|e| {
+            error!("Checking ban for {} failed: {}", username, e);
+            false
+        })
+    }
+
+    fn refresh_sender_tag(&self, username: &str, sender_tag: &str) {
+        if let Err(e) = self.db.update_sender_tag(username, sender_tag) {
+            error!("Updating sender tag for {} failed: {}", username, e);
+        }
+    }
+}
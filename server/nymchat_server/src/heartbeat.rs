@@ -0,0 +1,60 @@
+//! Heartbeats: whether a logged-in user is reachable right now.
+//!
+//! Nothing is written to disk. Clients that opt in send a `heartbeat` at
+//! jittered intervals while they run; a user whose heartbeats stopped for the
+//! TTL (by default [`HEARTBEAT_TTL`]) counts as offline. Messages for an
+//! offline user are only queued, not also delivered through SURBs that reach
+//! nobody, and their next heartbeat brings the queue. Users that never sent
+//! one since logging in, such as older clients or ones with heartbeats turned
+//! off, are [`Liveness::Unknown`] and delivered to as before.
+
+use std::collections::HashMap;
+
+/// Seconds without a heartbeat before a user counts as offline.
+pub const HEARTBEAT_TTL: i64 = 3 * 60;
+
+/// What the heartbeats of one user tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    Online,
+    Offline,
+    /// No heartbeats since the last login.
+    Unknown,
+}
+
+pub struct Heartbeats {
+    /// Last heartbeat of each user, in Unix seconds.
+    last: HashMap<String, i64>,
+    ttl: i64,
+}
+
+impl Heartbeats {
+    pub fn new(ttl: i64) -> Self {
+        Heartbeats { last: HashMap::new(), ttl }
+    }
+
+    pub fn ttl(&self) -> i64 {
+        self.ttl
+    }
+
+    /// Records a heartbeat of `username`. Returns whether they were offline
+    /// until now, so what was queued meanwhile is due.
+    pub fn beat(&mut self, username: &str, now: i64) -> bool {
+        let was = self.liveness(username, now);
+        self.last.insert(username.to_string(), now);
+        was == Liveness::Offline
+    }
+
+    pub fn liveness(&self, username: &str, now: i64) -> Liveness {
+        match self.last.get(username) {
+            Some(&last) if now - last < self.ttl => Liveness::Online,
+            Some(_) => Liveness::Offline,
+            None => Liveness::Unknown,
+        }
+    }
+
+    /// Forgets the heartbeats of `username`, on login or logout.
+    pub fn forget(&mut self, username: &str) {
+        self.last.remove(username);
+    }
+}
@@ -0,0 +1,198 @@
+//! Server signing key storage.
+//!
+//! Keys are stored exactly like the Python server's `CryptoUtils`:
+//! `<keys_dir>/<id>_private_key.enc` holds
+//! `base64(salt[16] || iv[12] || tag[16] || ciphertext)` of the PKCS#8 PEM,
+//! encrypted with AES-256-GCM under a PBKDF2-HMAC-SHA256 (100 000 rounds) key
+//! derived from the server password. `<id>_public_key.pem` holds the SPKI PEM.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use nymchat_protocol::{to_python_json, Crypto};
+use p256::SecretKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+const PBKDF2_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+/// Encrypts a private key PEM into the Python server's `.enc` format.
+pub fn encrypt_private_key(pem: &str, password: &str) -> anyhow::Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut iv = [0u8; IV_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+
+    let cipher = Aes256Gcm::new_from_slice(&derive_key(password, &salt)).expect("32 byte key");
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&iv), pem.as_bytes())
+        .map_err(|_| anyhow!("Failed to encrypt private key"))?;
+    // aes-gcm appends the tag, Python stores it in front of the ciphertext.
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+
+    let mut blob = Vec::with_capacity(SALT_LEN + IV_LEN + sealed.len());
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&iv);
+    blob.extend_from_slice(tag);
+    blob.extend_from_slice(ciphertext);
+    Ok(STANDARD.encode(blob))
+}
+
+/// Decrypts a `.enc` blob back into the private key PEM.
+pub fn decrypt_private_key(encoded: &str, password: &str) -> anyhow::Result<String> {
+    let blob = STANDARD
+        .decode(encoded.trim())
+        .context("Private key file is not valid base64")?;
+    if blob.len() < SALT_LEN + IV_LEN + TAG_LEN {
+        return Err(anyhow!("Private key file is truncated"));
+    }
+    let (salt, rest) = blob.split_at(SALT_LEN);
+    let (iv, rest) = rest.split_at(IV_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+    let mut sealed = ciphertext.to_vec();
+    sealed.extend_from_slice(tag);
+
+    let cipher = Aes256Gcm::new_from_slice(&derive_key(password, salt)).expect("32 byte key");
+    let pem = cipher
+        .decrypt(Nonce::from_slice(iv), sealed.as_slice())
+        .map_err(|_| anyhow!("Failed to decrypt private key (wrong password?)"))?;
+    String::from_utf8(pem).context("Private key is not valid UTF-8")
+}
+
+fn key_paths(keys_dir: &Path, id: &str) -> (PathBuf, PathBuf) {
+    (
+        keys_dir.join(format!("{}_private_key.enc", id)),
+        keys_dir.join(format!("{}_public_key.pem", id)),
+    )
+}
+
+/// Reads the server public key PEM, which clients pin to recognise the server.
+pub fn public_key_pem(keys_dir: &Path, id: &str) -> anyhow::Result<String> {
+    let (_, public_path) = key_paths(keys_dir, id);
+    fs::read_to_string(&public_path).with_context(|| format!("Failed to read {}", public_path.display()))
+}
+
+/// Loads the server signing key, generating and storing a new one on first run.
+pub fn load_or_generate(keys_dir: &Path, id: &str, password: &str) -> anyhow::Result<SecretKey> {
+    let (private_path, public_path) = key_paths(keys_dir, id);
+
+    if private_path.exists() {
+        let encoded = fs::read_to_string(&private_path)
+            .with_context(|| format!("Failed to read {}", private_path.display()))?;
+        let pem = decrypt_private_key(&encoded, password)?;
+        return Ok(Crypto::private_key_from_pem(&pem)?);
+    }
+
+    fs::create_dir_all(keys_dir)?;
+    let (secret, public_pem) = Crypto::generate_key_pair()?;
+    let private_pem = Crypto::private_key_pem(&secret)?;
+    fs::write(&private_path, encrypt_private_key(&private_pem, password)?)?;
+    fs::write(&public_path, public_pem)?;
+    info!("Server key pair generated.");
+    Ok(secret)
+}
+
+/// Announcement that the server moved to a new key pair. It is signed by both
+/// keys, so a client that pinned the old key can follow the move and nobody
+/// holding only one of the keys can forge it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyTransition {
+    #[serde(rename = "oldPublicKey")]
+    pub old_public_key: String,
+    #[serde(rename = "newPublicKey")]
+    pub new_public_key: String,
+    /// Unix time of the rotation.
+    pub issued: u64,
+    #[serde(rename = "oldSignature")]
+    pub old_signature: String,
+    #[serde(rename = "newSignature")]
+    pub new_signature: String,
+}
+
+impl KeyTransition {
+    /// The string both signatures cover.
+    pub fn signed_content(old_public_key: &str, new_public_key: &str, issued: u64) -> String {
+        to_python_json(&serde_json::json!({
+            "oldPublicKey": old_public_key,
+            "newPublicKey": new_public_key,
+            "issued": issued,
+        }))
+        .expect("transition serializes")
+    }
+
+    pub fn verify(&self) -> bool {
+        let content = Self::signed_content(&self.old_public_key, &self.new_public_key, self.issued);
+        let verifies = |pem: &str, signature: &str| {
+            Crypto::public_key_from_pem(pem)
+                .map(|key| Crypto::verify(&key, &content, signature))
+                .unwrap_or(false)
+        };
+        verifies(&self.old_public_key, &self.old_signature) && verifies(&self.new_public_key, &self.new_signature)
+    }
+}
+
+fn transition_path(keys_dir: &Path, id: &str) -> PathBuf {
+    keys_dir.join(format!("{}_key_transition.json", id))
+}
+
+/// Replaces the server key pair with a fresh one. The old key files are kept
+/// with an `.<issued>.old` suffix and the signed transition is written next to
+/// the new keys, where the server picks it up on its next start.
+pub fn rotate(keys_dir: &Path, id: &str, password: &str) -> anyhow::Result<KeyTransition> {
+    let (private_path, public_path) = key_paths(keys_dir, id);
+    if !private_path.exists() {
+        return Err(anyhow!("No server key at {}", private_path.display()));
+    }
+    let old = load_or_generate(keys_dir, id, password)?;
+    let (new, new_public_key) = Crypto::generate_key_pair()?;
+    let old_public_key = Crypto::public_key_pem(&old.public_key())?;
+
+    let issued = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let content = KeyTransition::signed_content(&old_public_key, &new_public_key, issued);
+    let transition = KeyTransition {
+        old_signature: Crypto::sign(&old, &content),
+        new_signature: Crypto::sign(&new, &content),
+        old_public_key,
+        new_public_key,
+        issued,
+    };
+
+    for path in [&private_path, &public_path].iter() {
+        let mut backup = path.as_os_str().to_os_string();
+        backup.push(format!(".{}.old", issued));
+        fs::rename(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+    }
+    let private_pem = Crypto::private_key_pem(&new)?;
+    fs::write(&private_path, encrypt_private_key(&private_pem, password)?)?;
+    fs::write(&public_path, &transition.new_public_key)?;
+    fs::write(transition_path(keys_dir, id), serde_json::to_string(&transition)?)?;
+    info!("Server key pair rotated.");
+    Ok(transition)
+}
+
+/// Loads the transition written by the last [`rotate`], if any.
+pub fn load_transition(keys_dir: &Path, id: &str) -> anyhow::Result<Option<KeyTransition>> {
+    let path = transition_path(keys_dir, id);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(Some(serde_json::from_str(&contents).context("Invalid key transition file")?))
+}
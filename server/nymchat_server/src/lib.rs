@@ -0,0 +1,40 @@
+//! Rust implementation of the nymCHAT discovery / relay server.
+//!
+//! The server keeps a `username -> (public key, sender tag)` directory and
+//! answers clients exclusively through SURB replies, so it never learns a
+//! client's nym address. Message handling is transport independent:
+//! [`Handler::handle`] takes one received message and returns the replies to
+//! send, [`serve`] runs it over a pair of channels, and `main.rs` connects
+//! those to the mixnet. [`loopback`] connects them to in-process clients
+//! instead, for end-to-end tests.
+
+pub mod blobs;
+pub mod config;
+pub mod db;
+pub mod directory;
+pub mod events;
+pub mod federation;
+pub mod heartbeat;
+pub mod handler;
+pub mod identity;
+pub mod loopback;
+pub mod nonce;
+pub mod presence;
+pub mod queue;
+pub mod routing;
+pub mod sealing;
+pub mod server;
+pub mod surbs;
+
+pub use blobs::BlobLimits;
+pub use config::Config;
+#[cfg(feature = "postgres")]
+pub use db::PostgresDb;
+pub use db::{Blob, DatabaseOptions, Db, Durability, RemoteUser, Stats, Storage, StorageError, User};
+pub use directory::DirectoryLimits;
+pub use events::ServerEvent;
+pub use federation::{Federation, Peer, PeerMessage};
+pub use handler::{Handler, Reply};
+pub use queue::QueueLimits;
+pub use server::{serve, Incoming, Outgoing};
+pub use surbs::SurbLimits;
@@ -0,0 +1,154 @@
+//! In-process stand-in for the mixnet.
+//!
+//! [`LoopbackMixnet`] runs a [`Handler`] through the same [`serve`] loop as the
+//! real server and hands out [`LoopbackClient`]s. Each client gets its own
+//! sender tag, the way a client session's SURBs do, and receives exactly the
+//! replies the server addresses to that tag. Servers can be linked so their
+//! federation messages reach each other. Nothing leaves the process, which
+//! makes end-to-end tests deterministic and runnable in CI.
+
+use crate::handler::Handler;
+use crate::server::{serve, Incoming, Outgoing};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long [`LoopbackClient::recv`] waits before concluding nothing is coming.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Routes {
+    /// Client inboxes by sender tag.
+    clients: HashMap<String, mpsc::UnboundedSender<String>>,
+    /// Linked servers by nym address.
+    peers: HashMap<String, mpsc::Sender<Incoming>>,
+}
+
+/// A server reachable through in-process channels.
+pub struct LoopbackMixnet {
+    name: String,
+    incoming: mpsc::Sender<Incoming>,
+    routes: Arc<Mutex<Routes>>,
+    next_client: AtomicUsize,
+    server: JoinHandle<()>,
+    router: JoinHandle<()>,
+}
+
+impl LoopbackMixnet {
+    /// Starts `handler` under `name`, which also serves as its nym address for linked peers.
+    pub fn start(name: &str, handler: Handler) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::channel(256);
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        let server = tokio::spawn(serve(handler, incoming_rx, outgoing_tx));
+        let router_routes = Arc::clone(&routes);
+        let router = tokio::spawn(async move {
+            while let Some(outgoing) = outgoing_rx.recv().await {
+                match outgoing {
+                    Outgoing::Reply(reply) => {
+                        let inbox = router_routes.lock().unwrap().clients.get(&reply.sender_tag).cloned();
+                        // Replies to tags nobody holds are lost, like replies over stale SURBs.
+                        if let Some(inbox) = inbox {
+                            let _ = inbox.send(reply.message);
+                        }
+                    }
+                    Outgoing::Peer(message) => {
+                        let peer = router_routes.lock().unwrap().peers.get(&message.recipient).cloned();
+                        if let Some(peer) = peer {
+                            let _ = peer
+                                .send(Incoming { message: message.message, sender_tag: None })
+                                .await;
+                        }
+                    }
+                }
+            }
+        });
+
+        LoopbackMixnet {
+            name: name.to_string(),
+            incoming: incoming_tx,
+            routes,
+            next_client: AtomicUsize::new(0),
+            server,
+            router,
+        }
+    }
+
+    /// The address peers reach this server at.
+    pub fn address(&self) -> &str {
+        &self.name
+    }
+
+    /// Opens a new client session with a fresh sender tag.
+    pub fn client(&self) -> LoopbackClient {
+        let id = self.next_client.fetch_add(1, Ordering::SeqCst);
+        let sender_tag = format!("{}-tag-{}", self.name, id);
+        let (inbox_tx, inbox) = mpsc::unbounded_channel();
+        self.routes
+            .lock()
+            .unwrap()
+            .clients
+            .insert(sender_tag.clone(), inbox_tx);
+        LoopbackClient {
+            sender_tag,
+            server: self.incoming.clone(),
+            inbox,
+        }
+    }
+
+    /// Delivers this server's federation messages for `peer`'s address to `peer`.
+    pub fn link(&self, peer: &LoopbackMixnet) {
+        let incoming = peer.incoming.clone();
+        self.routes
+            .lock()
+            .unwrap()
+            .peers
+            .insert(peer.address().to_string(), incoming);
+    }
+}
+
+impl Drop for LoopbackMixnet {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.router.abort();
+    }
+}
+
+/// One client session. Messages sent through it carry its sender tag.
+pub struct LoopbackClient {
+    pub sender_tag: String,
+    server: mpsc::Sender<Incoming>,
+    inbox: mpsc::UnboundedReceiver<String>,
+}
+
+impl LoopbackClient {
+    pub async fn send(&self, message: &str) {
+        self.server
+            .send(Incoming {
+                message: message.to_string(),
+                sender_tag: Some(self.sender_tag.clone()),
+            })
+            .await
+            .expect("server is running");
+    }
+
+    /// Waits for the next reply, or `None` after [`RECV_TIMEOUT`].
+    pub async fn recv(&mut self) -> Option<String> {
+        self.recv_within(RECV_TIMEOUT).await
+    }
+
+    /// Waits up to `timeout` for the next reply.
+    pub async fn recv_within(&mut self, timeout: Duration) -> Option<String> {
+        tokio::time::timeout(timeout, self.inbox.recv()).await.ok().flatten()
+    }
+
+    /// Sends `message` and waits for the first reply.
+    pub async fn request(&mut self, message: &str) -> Option<String> {
+        self.send(message).await;
+        self.recv().await
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Context;
+use futures::StreamExt;
+use nym_sdk::mixnet::{
+    AnonymousSenderTag, IncludedSurbs, MixnetClientBuilder, MixnetClientSender, MixnetMessageSender, Recipient,
+    StoragePaths,
+};
+use nym_sdk::NymNetworkDetails;
+use nymchat_server::{config, db, identity, serve, Config, Federation, Handler, Incoming, Outgoing, ServerEvent};
+use std::fs;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Logs to stdout; with the `tokio-console` feature, also serves task data
+/// to `tokio-console`.
+fn init_tracing() {
+    #[cfg(feature = "tokio-console")]
+    {
+        use tracing_subscriber::filter::LevelFilter;
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+            .init();
+    }
+    #[cfg(not(feature = "tokio-console"))]
+    tracing_subscriber::fmt::init();
+}
+
+async fn send_outgoing(sender: &MixnetClientSender, outgoing: Outgoing) {
+    match outgoing {
+        Outgoing::Reply(reply) => {
+            let tag = match AnonymousSenderTag::try_from_base58_string(&reply.sender_tag) {
+                Ok(tag) => tag,
+                Err(e) => {
+                    error!("Invalid sender tag {}: {}", reply.sender_tag, e);
+                    return;
+                }
+            };
+            if let Err(e) = sender.send_reply(tag, reply.message.into_bytes()).await {
+                error!("Error sending reply: {}", e);
+            }
+        }
+        // Federation messages go straight to the peer's address; peer servers
+        // are public, so there is nothing to hide and no SURBs are attached.
+        Outgoing::Peer(message) => {
+            let recipient = match message.recipient.parse::<Recipient>() {
+                Ok(recipient) => recipient,
+                Err(e) => {
+                    error!("Invalid peer address {}: {}", message.recipient, e);
+                    return;
+                }
+            };
+            if let Err(e) = sender
+                .send_message(recipient, message.message.into_bytes(), IncludedSurbs::ExposeSelfAddress)
+                .await
+            {
+                error!("Error sending to peer: {}", e);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    config::load_env(".env");
+    init_tracing();
+
+    let config = Config::from_env()?;
+    let password = config.password()?;
+    let identity = identity::load_or_generate(&config.keys_dir, &config.nym_client_id, &password)?;
+
+    let db = db::open(config.database_url.as_deref(), &config.database_path, &config.database).context("Failed to open database")?;
+    let mut handler = Handler::with_storage(db, identity)
+        .with_queue_limits(config.queue.clone())
+        .with_directory_limits(config.directory.clone())
+        .with_blob_limits(config.blobs.clone())
+        .with_registration_pow(config.registration_pow_bits)
+        .with_session_ttl(config.session_ttl)
+        .with_heartbeat_ttl(config.heartbeat_ttl)
+        .with_surb_limits(config.surbs.clone());
+    if let Some(transition) = identity::load_transition(&config.keys_dir, &config.nym_client_id)? {
+        handler = handler.with_key_transition(transition);
+    }
+    if let Some(trust_list) = &config.federation_peers {
+        let federation = Federation::load(config.federation_name.clone(), trust_list)?;
+        info!("Federating as {} with {} peers", federation.name, federation.peers().count());
+        handler = handler.with_federation(federation);
+    }
+
+    fs::create_dir_all(&config.nym_data_dir)?;
+    let storage = StoragePaths::new_from_dir(&config.nym_data_dir)?;
+    let network = if config.network.is_mainnet() {
+        NymNetworkDetails::new_mainnet()
+    } else {
+        config.network.export_to_env()?;
+        info!("Using the {} network", config.network.network);
+        NymNetworkDetails::new_from_env()
+    };
+    let mut client = MixnetClientBuilder::new_with_default_storage(storage)
+        .await?
+        .network_details(network)
+        .build()?
+        .connect_to_mixnet()
+        .await
+        .context("Failed to connect to mixnet")?;
+
+    let address = client.nym_address().to_string();
+    info!("Connected to the mixnet. Nym address: {}", address);
+    if let Some(parent) = config.address_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::write(&config.address_file, &address) {
+        Ok(()) => info!("Nym address saved to {}", config.address_file.display()),
+        Err(e) => error!("Failed to write address to file: {}", e),
+    }
+
+    let mut events = handler.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(ServerEvent::SurbsLow { sender_tag, remaining }) => {
+                    warn!("Only {} reply SURBs left for {}; asked for more.", remaining, sender_tag)
+                }
+                Ok(ServerEvent::SurbsExhausted { sender_tag }) => {
+                    warn!("No reply SURBs left for {}; unreachable until it sends again.", sender_tag)
+                }
+                Ok(ServerEvent::SurbsStale { sender_tag }) => {
+                    info!("Reply SURBs for {} predate this topology epoch; asked for fresh ones.", sender_tag)
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Missed {} server events.", missed),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }.instrument(info_span!("server_events")));
+
+    let (incoming_tx, incoming_rx) = mpsc::channel(256);
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel();
+    let server = tokio::spawn(serve(handler, incoming_rx, outgoing_tx));
+    let sender = client.split_sender();
+    let sending = tokio::spawn(async move {
+        while let Some(outgoing) = outgoing_rx.recv().await {
+            send_outgoing(&sender, outgoing).await;
+        }
+    }.instrument(info_span!("sender")));
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutdown requested.");
+                break;
+            }
+            received = client.next() => {
+                let received = match received {
+                    Some(received) => received,
+                    None => {
+                        warn!("Mixnet connection closed.");
+                        break;
+                    }
+                };
+                let incoming = Incoming {
+                    message: String::from_utf8_lossy(&received.message).into_owned(),
+                    sender_tag: received.sender_tag.map(|tag| tag.to_base58_string()),
+                };
+                if incoming_tx.send(incoming).await.is_err() {
+                    error!("Server loop stopped.");
+                    break;
+                }
+            }
+        }
+    }
+
+    // Let replies still waiting out their delay go out before disconnecting.
+    drop(incoming_tx);
+    let _ = server.await;
+    let _ = sending.await;
+    client.disconnect().await;
+    info!("Client disconnected.");
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Bookkeeping for the registration and login challenges.
+//!
+//! Each challenge nonce is random, bound to the username it was issued for and
+//! to the sender tag (SURB set) that requested it, expires after a timeout and
+//! can be answered at most once: a replayed response finds nothing pending.
+
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a client has to answer a challenge.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(120);
+
+/// An issued challenge and whatever the caller needs to finish the flow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge<T> {
+    pub username: String,
+    pub nonce: String,
+    pub data: T,
+    issued: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NonceError {
+    /// No challenge is outstanding for this sender tag (never issued or already answered).
+    Missing,
+    /// The challenge timed out.
+    Expired,
+    /// The response names a different user than the challenge was issued for.
+    UsernameMismatch,
+}
+
+/// Outstanding challenges keyed by sender tag.
+pub struct NonceStore<T> {
+    ttl: Duration,
+    pending: HashMap<String, Challenge<T>>,
+}
+
+pub fn new_nonce() -> String {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+impl<T> NonceStore<T> {
+    pub fn new(ttl: Duration) -> Self {
+        NonceStore {
+            ttl,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Issues a fresh nonce for `username`, replacing any challenge pending for `sender_tag`.
+    pub fn issue(&mut self, sender_tag: &str, username: &str, data: T, now: Instant) -> String {
+        self.prune(now);
+        let nonce = new_nonce();
+        self.pending.insert(
+            sender_tag.to_string(),
+            Challenge {
+                username: username.to_string(),
+                nonce: nonce.clone(),
+                data,
+                issued: now,
+            },
+        );
+        nonce
+    }
+
+    /// Consumes the challenge pending for `sender_tag`. The challenge is removed
+    /// whatever the outcome, so every nonce gets exactly one answer.
+    /// `username` is the name the response claims, if it carries one.
+    pub fn take(&mut self, sender_tag: &str, username: Option<&str>, now: Instant) -> Result<Challenge<T>, NonceError> {
+        let challenge = self.pending.remove(sender_tag).ok_or(NonceError::Missing)?;
+        if now.saturating_duration_since(challenge.issued) > self.ttl {
+            return Err(NonceError::Expired);
+        }
+        if username.is_some_and(|username| username != challenge.username) {
+            return Err(NonceError::UsernameMismatch);
+        }
+        Ok(challenge)
+    }
+
+    /// Number of outstanding challenges.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Drops expired challenges.
+    pub fn prune(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.pending
+            .retain(|_, challenge| now.saturating_duration_since(challenge.issued) <= ttl);
+    }
+}
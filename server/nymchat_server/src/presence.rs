@@ -0,0 +1,145 @@
+//! Opt-in presence, visible only to contacts a user approved.
+//!
+//! Nothing is written to disk. A client that shares presence announces itself
+//! after login with the contacts allowed to see it, and re-announces while it
+//! stays online; without announcements it lapses to offline after
+//! [`ONLINE_TTL`]. Viewers learn an online flag and a last-seen time rounded
+//! down to [`LAST_SEEN_GRANULARITY`], and only about users that approved them:
+//! everyone else looks like a user who never opted in.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Seconds without an announcement before a user counts as offline.
+pub const ONLINE_TTL: i64 = 3 * 60;
+/// Last-seen times are rounded down to this many seconds.
+pub const LAST_SEEN_GRANULARITY: i64 = 15 * 60;
+
+/// What a viewer is told about one contact.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceStatus {
+    pub username: String,
+    pub online: bool,
+    /// Unix seconds, rounded down; `None` while online.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+}
+
+struct Sharing {
+    allowed: HashSet<String>,
+    /// Last announcement, or the logout time once logged out.
+    last_active: i64,
+    logged_out: bool,
+}
+
+impl Sharing {
+    fn online(&self, now: i64) -> bool {
+        !self.logged_out && now - self.last_active <= ONLINE_TTL
+    }
+}
+
+#[derive(Default)]
+pub struct Presence {
+    sharing: HashMap<String, Sharing>,
+    /// Viewers watching each user, as last requested by the viewer.
+    watchers: HashMap<String, HashSet<String>>,
+}
+
+fn coarse(time: i64) -> i64 {
+    time - time.rem_euclid(LAST_SEEN_GRANULARITY)
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `username` is online and visible to `allowed`; an empty set
+    /// stops sharing. Returns the watchers to notify if this brought the user
+    /// online.
+    pub fn announce(&mut self, username: &str, allowed: HashSet<String>, now: i64) -> Vec<String> {
+        if allowed.is_empty() {
+            self.sharing.remove(username);
+            return Vec::new();
+        }
+        let was_online = self.sharing.get(username).is_some_and(|s| s.online(now));
+        self.sharing.insert(
+            username.to_string(),
+            Sharing { allowed, last_active: now, logged_out: false },
+        );
+        if was_online {
+            Vec::new()
+        } else {
+            self.visible_watchers(username)
+        }
+    }
+
+    /// Marks `username` offline and stops their watching. Returns the watchers to notify.
+    pub fn logout(&mut self, username: &str, now: i64) -> Vec<String> {
+        for watchers in self.watchers.values_mut() {
+            watchers.remove(username);
+        }
+        match self.sharing.get_mut(username) {
+            Some(sharing) if sharing.online(now) => {
+                sharing.last_active = now;
+                sharing.logged_out = true;
+            }
+            _ => return Vec::new(),
+        }
+        self.visible_watchers(username)
+    }
+
+    /// Drops everything known about `username`, e.g. when the account is deleted.
+    pub fn forget(&mut self, username: &str) {
+        self.sharing.remove(username);
+        self.watchers.remove(username);
+        for watchers in self.watchers.values_mut() {
+            watchers.remove(username);
+        }
+    }
+
+    /// Makes `viewer` watch exactly `contacts` and returns the status of those
+    /// that share with them.
+    pub fn watch(&mut self, viewer: &str, contacts: &[String], now: i64) -> Vec<PresenceStatus> {
+        for watchers in self.watchers.values_mut() {
+            watchers.remove(viewer);
+        }
+        self.watchers.retain(|_, watchers| !watchers.is_empty());
+        for contact in contacts {
+            self.watchers
+                .entry(contact.clone())
+                .or_default()
+                .insert(viewer.to_string());
+        }
+        contacts
+            .iter()
+            .filter_map(|contact| self.status(contact, viewer, now))
+            .collect()
+    }
+
+    /// The presence of `username` as `viewer` may see it.
+    pub fn status(&self, username: &str, viewer: &str, now: i64) -> Option<PresenceStatus> {
+        let sharing = self.sharing.get(username).filter(|s| s.allowed.contains(viewer))?;
+        let online = sharing.online(now);
+        Some(PresenceStatus {
+            username: username.to_string(),
+            online,
+            last_seen: if online { None } else { Some(coarse(sharing.last_active)) },
+        })
+    }
+
+    fn visible_watchers(&self, username: &str) -> Vec<String> {
+        let allowed = match self.sharing.get(username) {
+            Some(sharing) => &sharing.allowed,
+            None => return Vec::new(),
+        };
+        let mut watchers: Vec<String> = self
+            .watchers
+            .get(username)
+            .map(|watchers| watchers.intersection(allowed).cloned().collect())
+            .unwrap_or_default();
+        watchers.sort();
+        watchers
+    }
+}
@@ -0,0 +1,141 @@
+//! Store-and-forward queue for recipients that are not reachable right now.
+//!
+//! Every forwarded chat message is kept until the recipient acknowledges it,
+//! and redelivered after their next login, all at once or, for clients that
+//! ask, one [`PendingPage`] at a time. Payloads are sealed with
+//! AES-256-GCM under keys derived from the server identity (see
+//! [`SealingKey`]), so a copy of the database alone does not reveal who wrote
+//! to whom.
+
+use crate::db::{Storage, StorageError, StorageResult};
+use crate::sealing::SealingKey;
+use p256::SecretKey;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Size and age limits for queued messages.
+#[derive(Clone, Debug)]
+pub struct QueueLimits {
+    /// Messages older than this are dropped undelivered.
+    pub ttl: Duration,
+    /// Per-recipient cap; the oldest messages are dropped first.
+    pub max_messages: usize,
+    /// Larger forwards are rejected instead of queued.
+    pub max_message_size: usize,
+    /// Most messages in one page of the queue.
+    pub page_size: usize,
+    /// Sealed bytes after which a page ends early; a page holds at least one
+    /// message however large.
+    pub page_bytes: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            max_messages: 100,
+            max_message_size: 64 * 1024,
+            page_size: 25,
+            page_bytes: 256 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    TooLarge,
+    Storage(String),
+}
+
+/// Part of a recipient's queue, oldest first.
+#[derive(Debug, PartialEq)]
+pub struct PendingPage {
+    /// `(id, payload)` of each message.
+    pub messages: Vec<(i64, String)>,
+    /// Id of the last message looked at; the next page starts after it.
+    pub cursor: i64,
+    /// Whether more messages wait after `cursor`.
+    pub more: bool,
+}
+
+pub struct OfflineQueue {
+    key: SealingKey,
+    limits: QueueLimits,
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl OfflineQueue {
+    pub fn new(identity: &SecretKey, limits: QueueLimits) -> Self {
+        OfflineQueue {
+            key: SealingKey::new(identity, b"nymchat offline queue"),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &QueueLimits {
+        &self.limits
+    }
+
+    fn seal(&self, plaintext: &str) -> Vec<u8> {
+        self.key.seal(plaintext.as_bytes())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<String> {
+        String::from_utf8(self.key.open(sealed)?).ok()
+    }
+
+    /// Queues `payload` for `username` and returns its message id.
+    pub fn push(&self, db: &dyn Storage, username: &str, payload: &str) -> Result<i64, QueueError> {
+        if payload.len() > self.limits.max_message_size {
+            return Err(QueueError::TooLarge);
+        }
+        let storage = |e: StorageError| QueueError::Storage(e.0);
+        let id = db
+            .enqueue_message(username, &self.seal(payload), now_secs())
+            .map_err(storage)?;
+        db.trim_queue(username, self.limits.max_messages).map_err(storage)?;
+        Ok(id)
+    }
+
+    /// Returns `(id, payload)` for every unexpired message waiting for `username`.
+    pub fn pending(&self, db: &dyn Storage, username: &str) -> StorageResult<Vec<(i64, String)>> {
+        db.purge_queue_before(now_secs() - self.limits.ttl.as_secs() as i64)?;
+        Ok(db
+            .queued_messages(username, 0, usize::MAX)?
+            .into_iter()
+            .filter_map(|(id, sealed)| self.open(&sealed).map(|payload| (id, payload)))
+            .collect())
+    }
+
+    /// Returns the unexpired messages waiting for `username` with ids above
+    /// `after`: at most `limit` of them, capped by the page limits.
+    pub fn page(&self, db: &dyn Storage, username: &str, after: i64, limit: usize) -> StorageResult<PendingPage> {
+        db.purge_queue_before(now_secs() - self.limits.ttl.as_secs() as i64)?;
+        let limit = limit.clamp(1, self.limits.page_size.max(1));
+        let mut page = PendingPage { messages: Vec::new(), cursor: after, more: false };
+        let (mut taken, mut bytes) = (0, 0);
+        for (id, sealed) in db.queued_messages(username, after, limit + 1)? {
+            if taken == limit || (taken > 0 && bytes + sealed.len() > self.limits.page_bytes) {
+                page.more = true;
+                break;
+            }
+            taken += 1;
+            bytes += sealed.len();
+            page.cursor = id;
+            if let Some(payload) = self.open(&sealed) {
+                page.messages.push((id, payload));
+            }
+        }
+        Ok(page)
+    }
+
+    /// Removes acknowledged messages. Ids belonging to other users are ignored.
+    pub fn ack(&self, db: &dyn Storage, username: &str, ids: &[i64]) -> StorageResult<usize> {
+        db.delete_queued(username, ids)
+    }
+}
@@ -0,0 +1,41 @@
+//! Routing tokens that stand in for recipient usernames in `send` envelopes.
+//!
+//! A `queryResponse` carries a fresh token for the user looked up: the
+//! username sealed with AES-256-GCM under a key derived from the server
+//! identity (see [`SealingKey`]) and a random nonce. Clients that address messages by token keep
+//! usernames out of everything they send after the query, and since every
+//! query yields a different token, two senders writing to the same contact
+//! send nothing the server could match up without opening them. The server
+//! still opens the token to route the message. Tokens stay valid until the
+//! server identity changes.
+
+use crate::sealing::SealingKey;
+use p256::SecretKey;
+
+/// Longest token accepted; a valid one for the longest username is well below this.
+pub const MAX_TOKEN_LEN: usize = 512;
+
+pub struct RoutingTokens {
+    key: SealingKey,
+}
+
+impl RoutingTokens {
+    pub fn new(identity: &SecretKey) -> Self {
+        RoutingTokens {
+            key: SealingKey::new(identity, b"nymchat routing token"),
+        }
+    }
+
+    /// A new token for `username`, hex encoded.
+    pub fn issue(&self, username: &str) -> String {
+        hex::encode(self.key.seal(username.as_bytes()))
+    }
+
+    /// The username inside `token`, or `None` if this server did not issue it.
+    pub fn open(&self, token: &str) -> Option<String> {
+        if token.len() > MAX_TOKEN_LEN {
+            return None;
+        }
+        String::from_utf8(self.key.open(&hex::decode(token).ok()?)?).ok()
+    }
+}
@@ -0,0 +1,109 @@
+//! AES-256-GCM under long-lived server keys, with IVs that never repeat.
+//!
+//! The offline queue and routing tokens encrypt under keys derived from the
+//! server identity, for as long as the identity lasts. Their IVs are random,
+//! and random 96-bit IVs are only safe for a bounded number of messages per
+//! key (2^32, NIST SP 800-38D). So a [`SealingKey`] does not encrypt under the
+//! identity-derived key itself: it derives a subkey from it and a random key
+//! id, prefixes sealed data with that id, and moves to a new subkey after
+//! [`MAX_MESSAGES_PER_KEY`] messages. Every start begins a new subkey, so the
+//! count needs no persistence. Data sealed before subkeys existed (nonce and
+//! ciphertext only) still opens.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use p256::SecretKey;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::sync::Mutex;
+
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Messages sealed under one subkey before moving to the next.
+pub const MAX_MESSAGES_PER_KEY: u64 = 1 << 32;
+
+struct Subkey {
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+    used: u64,
+}
+
+pub struct SealingKey {
+    identity: [u8; 32],
+    info: &'static [u8],
+    max_messages: u64,
+    current: Mutex<Subkey>,
+}
+
+fn derive(identity: &[u8], salt: Option<&[u8]>, info: &[u8]) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, identity)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    Aes256Gcm::new_from_slice(&key).expect("32 byte key")
+}
+
+fn open_with(cipher: &Aes256Gcm, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+impl SealingKey {
+    /// Keys for one purpose, named by `info`, derived from the server identity.
+    pub fn new(identity: &SecretKey, info: &'static [u8]) -> Self {
+        let identity: [u8; 32] = identity.to_bytes().into();
+        let current = Mutex::new(Self::subkey(&identity, info));
+        SealingKey { identity, info, max_messages: MAX_MESSAGES_PER_KEY, current }
+    }
+
+    /// Moves to a new subkey after `max_messages` messages instead of [`MAX_MESSAGES_PER_KEY`].
+    pub fn with_max_messages(mut self, max_messages: u64) -> Self {
+        self.max_messages = max_messages.max(1);
+        self
+    }
+
+    fn subkey(identity: &[u8], info: &[u8]) -> Subkey {
+        let mut id = [0u8; KEY_ID_LEN];
+        OsRng.fill_bytes(&mut id);
+        Subkey { id, cipher: derive(identity, Some(&id), info), used: 0 }
+    }
+
+    /// `plaintext` sealed as key id, random nonce, ciphertext and tag.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if current.used >= self.max_messages {
+            *current = Self::subkey(&self.identity, self.info);
+        }
+        current.used += 1;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = current.id.to_vec();
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(
+            current
+                .cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .expect("AES-GCM encryption does not fail"),
+        );
+        sealed
+    }
+
+    /// The plaintext of data sealed under any subkey of this key, or `None`
+    /// if it was not sealed by this key or was altered.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() >= KEY_ID_LEN + NONCE_LEN + TAG_LEN {
+            let (id, rest) = sealed.split_at(KEY_ID_LEN);
+            if let Some(plaintext) = open_with(&derive(&self.identity, Some(id), self.info), rest) {
+                return Some(plaintext);
+            }
+        }
+        open_with(&derive(&self.identity, None, self.info), sealed)
+    }
+}
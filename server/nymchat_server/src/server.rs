@@ -0,0 +1,74 @@
+//! The server loop, independent of the transport that feeds it.
+//!
+//! `main.rs` bridges [`serve`] to the mixnet; [`crate::loopback`] bridges it to
+//! in-process test clients.
+
+use crate::federation::PeerMessage;
+use crate::handler::{Handler, Reply};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug_span, info_span, Instrument};
+
+/// A message received from the transport.
+#[derive(Clone, Debug)]
+pub struct Incoming {
+    pub message: String,
+    /// `None` for messages that came without SURBs, which only peer servers send.
+    pub sender_tag: Option<String>,
+}
+
+/// A message for the transport to send.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outgoing {
+    /// Sent back through the SURBs of `reply.sender_tag`.
+    Reply(Reply),
+    /// Sent to a peer server's nym address.
+    Peer(PeerMessage),
+}
+
+/// Handles `incoming` messages until the channel closes, then flushes the
+/// replies still waiting out their response delay.
+///
+/// Replies are held until [`Handler::response_delay`] after their request
+/// arrived, whichever path produced them, and leave in the order the requests
+/// came in.
+#[tracing::instrument(name = "serve", skip_all)]
+pub async fn serve(mut handler: Handler, mut incoming: mpsc::Receiver<Incoming>, outgoing: mpsc::UnboundedSender<Outgoing>) {
+    handler.announce_all();
+    for message in handler.take_peer_messages() {
+        let _ = outgoing.send(Outgoing::Peer(message));
+    }
+
+    let (paced_tx, mut paced_rx) = mpsc::unbounded_channel::<(Instant, Vec<Outgoing>)>();
+    let pacer = tokio::spawn(async move {
+        while let Some((deadline, batch)) = paced_rx.recv().await {
+            sleep_until(deadline).await;
+            for message in batch {
+                if outgoing.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    }.instrument(info_span!("pacer")));
+
+    while let Some(received) = incoming.recv().await {
+        if received.message.is_empty() {
+            continue;
+        }
+        let deadline = Instant::now() + handler.response_delay();
+        let _span = debug_span!("request", sender_tag = received.sender_tag.as_deref()).entered();
+        let replies = match &received.sender_tag {
+            Some(tag) => handler.handle(&received.message, tag),
+            None => handler.handle_peer(&received.message),
+        };
+
+        let mut batch: Vec<Outgoing> = replies.into_iter().map(Outgoing::Reply).collect();
+        batch.extend(handler.take_peer_messages().into_iter().map(Outgoing::Peer));
+        if !batch.is_empty() {
+            let _ = paced_tx.send((deadline, batch));
+        }
+    }
+
+    drop(paced_tx);
+    let _ = pacer.await;
+}
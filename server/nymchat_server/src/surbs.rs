@@ -0,0 +1,181 @@
+//! Reply SURB accounting per sender tag.
+//!
+//! The server reaches a client only through the reply SURBs it attached to
+//! its messages, and the mixnet client does not say how many are left. The
+//! ledger keeps an estimate instead: every message from a tag is credited with
+//! the SURBs clients attach by default, a `surbTopUp` with the count it
+//! names, and every reply is debited with the SURBs its size takes. When a
+//! tag runs low the handler asks the client for more before it becomes
+//! unreachable; see [`crate::handler::Handler::subscribe`] for the warnings.
+//!
+//! A SURB is built against the mixnet topology of the epoch it was made in,
+//! and may no longer route once the topology changes. The ledger follows
+//! epochs by the clock ([`SurbLimits::epoch_length`], from the Unix epoch, as
+//! the Nym mainnet schedules them): SURBs from an earlier epoch are not
+//! counted, and the first reply to a tag holding only those asks its client to
+//! issue fresh ones.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// About this many bytes of a reply fit in the packet one SURB carries.
+pub const SURB_PAYLOAD_BYTES: usize = 2000;
+/// Tags unused for this long are forgotten; their SURBs have expired with the
+/// mixnet keys they were made for.
+pub const SURB_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// Tags tracked before idle ones are pruned.
+const MAX_TRACKED_TAGS: usize = 65_536;
+
+/// How the ledger counts and when it asks for more.
+#[derive(Clone, Debug)]
+pub struct SurbLimits {
+    /// SURBs a client attaches to each message it sends.
+    pub per_message: u32,
+    /// Below this many the client is asked for more.
+    pub low_water: u32,
+    /// SURBs asked for at a time.
+    pub top_up: u32,
+    /// Most SURBs a single `surbTopUp` is credited with.
+    pub max_top_up: u32,
+    /// How long a topology epoch lasts; zero ignores epochs.
+    pub epoch_length: Duration,
+}
+
+impl Default for SurbLimits {
+    fn default() -> Self {
+        SurbLimits {
+            per_message: 10,
+            low_water: 4,
+            top_up: 20,
+            max_top_up: 100,
+            epoch_length: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Why a tag's client may soon be out of reach.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SurbWarning {
+    /// Fewer than [`SurbLimits::low_water`] are left.
+    Low { remaining: u32 },
+    /// None are left, or fewer than the reply needed.
+    Exhausted,
+    /// All of them were made in an earlier epoch.
+    Stale,
+}
+
+struct Balance {
+    remaining: u32,
+    /// Epoch the SURBs were made in.
+    epoch: u64,
+    /// Whether it was reported low, out, and stale since the client last sent SURBs.
+    warned_low: bool,
+    warned_exhausted: bool,
+    warned_stale: bool,
+    last_used: Instant,
+}
+
+pub struct SurbLedger {
+    limits: SurbLimits,
+    balances: HashMap<String, Balance>,
+    /// The wall clock time of an instant, to place instants in epochs.
+    clock: (Instant, Duration),
+}
+
+/// SURBs a reply of `bytes` bytes takes.
+pub fn surbs_for(bytes: usize) -> u32 {
+    bytes.div_ceil(SURB_PAYLOAD_BYTES).max(1) as u32
+}
+
+impl SurbLedger {
+    pub fn new(limits: SurbLimits) -> Self {
+        let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        SurbLedger { limits, balances: HashMap::new(), clock: (Instant::now(), since_unix) }
+    }
+
+    fn since_unix(&self, now: Instant) -> Duration {
+        let (origin, since_unix) = self.clock;
+        since_unix + now.saturating_duration_since(origin)
+    }
+
+    /// The topology epoch `now` falls in.
+    pub fn epoch(&self, now: Instant) -> u64 {
+        match self.limits.epoch_length.as_secs() {
+            0 => 0,
+            length => self.since_unix(now).as_secs() / length,
+        }
+    }
+
+    /// When the epoch after the one `now` falls in begins.
+    pub fn next_epoch(&self, now: Instant) -> Option<Instant> {
+        let length = self.limits.epoch_length.as_secs();
+        if length == 0 {
+            return None;
+        }
+        let next = Duration::from_secs((self.epoch(now) + 1) * length);
+        Some(now + (next - self.since_unix(now)))
+    }
+
+    pub fn limits(&self) -> &SurbLimits {
+        &self.limits
+    }
+
+    /// Records `surbs` more SURBs received from `sender_tag`. Those it held
+    /// from an earlier epoch are dropped.
+    pub fn credit(&mut self, sender_tag: &str, surbs: u32, now: Instant) {
+        if self.balances.len() >= MAX_TRACKED_TAGS && !self.balances.contains_key(sender_tag) {
+            self.balances.retain(|_, balance| now.duration_since(balance.last_used) < SURB_LIFETIME);
+        }
+        let epoch = self.epoch(now);
+        let held = match self.balances.get(sender_tag) {
+            Some(balance) if balance.epoch == epoch => balance.remaining,
+            _ => 0,
+        };
+        let balance = Balance {
+            remaining: held.saturating_add(surbs),
+            epoch,
+            warned_low: false,
+            warned_exhausted: false,
+            warned_stale: false,
+            last_used: now,
+        };
+        self.balances.insert(sender_tag.to_string(), balance);
+    }
+
+    /// Records a reply of `bytes` bytes sent to `sender_tag`, and returns a
+    /// warning the first time the tag runs low, runs out, or holds only SURBs
+    /// from an earlier epoch, since it last sent SURBs. Tags the ledger has not
+    /// been told about, such as those users last reached a restarted server
+    /// with, are not counted.
+    pub fn spend(&mut self, sender_tag: &str, bytes: usize, now: Instant) -> Option<SurbWarning> {
+        let needed = surbs_for(bytes);
+        let low_water = self.limits.low_water;
+        let epoch = self.epoch(now);
+        let balance = self.balances.get_mut(sender_tag)?;
+        balance.last_used = now;
+        if balance.epoch != epoch {
+            // Whatever is left may not route; the SDK tries it anyway.
+            let first = !balance.warned_stale;
+            balance.warned_stale = true;
+            return first.then_some(SurbWarning::Stale);
+        }
+        if balance.remaining < needed {
+            balance.remaining = 0;
+            let first = !balance.warned_exhausted;
+            balance.warned_exhausted = true;
+            return first.then_some(SurbWarning::Exhausted);
+        }
+        balance.remaining -= needed;
+        if balance.remaining < low_water && !balance.warned_low {
+            balance.warned_low = true;
+            return Some(SurbWarning::Low { remaining: balance.remaining });
+        }
+        None
+    }
+
+    /// Estimated SURBs left for `sender_tag`, if it is tracked, including
+    /// stale ones.
+    pub fn remaining(&self, sender_tag: &str) -> Option<u32> {
+        self.balances.get(sender_tag).map(|balance| balance.remaining)
+    }
+}
@@ -0,0 +1,122 @@
+use nymchat_protocol::{to_python_json, Crypto, Envelope};
+use nymchat_server::{Db, Federation, Handler, Peer, Reply};
+use p256::SecretKey;
+use serde_json::{json, Value};
+
+struct Server {
+    handler: Handler,
+    address: &'static str,
+}
+
+/// Two servers, "east" and "west", that trust each other.
+fn federated_pair() -> (Server, Server) {
+    let (east_key, east_pem) = Crypto::generate_key_pair().unwrap();
+    let (west_key, west_pem) = Crypto::generate_key_pair().unwrap();
+    let peer = |name: &str, pem: &str| Peer {
+        name: name.into(),
+        address: format!("{}-address", name),
+        public_key: pem.into(),
+    };
+
+    let east = Handler::new(Db::open_in_memory().unwrap(), east_key)
+        .with_federation(Federation::new("east", vec![peer("west", &west_pem)]).unwrap());
+    let west = Handler::new(Db::open_in_memory().unwrap(), west_key)
+        .with_federation(Federation::new("west", vec![peer("east", &east_pem)]).unwrap());
+    (
+        Server { handler: east, address: "east-address" },
+        Server { handler: west, address: "west-address" },
+    )
+}
+
+/// Delivers everything `from` queued for its peers to `to`.
+fn gossip(from: &mut Server, to: &mut Server) -> Vec<Reply> {
+    let mut replies = Vec::new();
+    for message in from.handler.take_peer_messages() {
+        assert_eq!(message.recipient, to.address);
+        replies.extend(to.handler.handle_peer(&message.message));
+    }
+    replies
+}
+
+fn content(reply: &Reply) -> String {
+    Envelope::parse(&reply.message).unwrap().content.unwrap()
+}
+
+fn register(server: &mut Server, username: &str, tag: &str) -> SecretKey {
+    let (secret, public_pem) = Crypto::generate_key_pair().unwrap();
+    let request = json!({ "action": "register", "usernym": username, "publicKey": public_pem });
+    let replies = server.handler.handle(&request.to_string(), tag);
+    let challenge: Value = serde_json::from_str(&content(&replies[0])).unwrap();
+
+    let signature = Crypto::sign(&secret, challenge["nonce"].as_str().unwrap());
+    let response = json!({ "action": "registrationResponse", "username": username, "signature": signature });
+    let replies = server.handler.handle(&response.to_string(), tag);
+    assert_eq!(content(&replies[0]), "success");
+    secret
+}
+
+#[test]
+fn entries_are_gossiped_to_peers() {
+    let (mut east, mut west) = federated_pair();
+    register(&mut east, "alice", "tag-a");
+    gossip(&mut east, &mut west);
+
+    let replies = west.handler.handle(r#"{"action": "query", "username": "alice"}"#, "tag-q");
+    assert!(content(&replies[0]).starts_with(r#"{"username": "alice", "publicKey": "#));
+
+    // The name is now taken network wide.
+    let (_, public_pem) = Crypto::generate_key_pair().unwrap();
+    let request = json!({ "action": "register", "usernym": "alice", "publicKey": public_pem });
+    let replies = west.handler.handle(&request.to_string(), "tag-b");
+    assert_eq!(content(&replies[0]), "error: username already in use");
+}
+
+#[test]
+fn messages_are_forwarded_to_the_home_server() {
+    let (mut east, mut west) = federated_pair();
+    let alice = register(&mut east, "alice", "tag-alice");
+    register(&mut west, "bob", "tag-bob");
+    gossip(&mut east, &mut west);
+    gossip(&mut west, &mut east);
+
+    let (_, bob_pem) = Crypto::generate_key_pair().unwrap();
+    let encrypted = Crypto::encrypt(&Crypto::public_key_from_pem(&bob_pem).unwrap(), "hi").unwrap();
+    let payload = to_python_json(&json!({
+        "sender": "alice",
+        "recipient": "bob",
+        "body": {
+            "encryptedPayload": encrypted,
+            "payloadSignature": Crypto::sign(&alice, &to_python_json(&encrypted).unwrap()),
+        },
+    }))
+    .unwrap();
+    let request = Envelope::new("send")
+        .with_content(payload.clone())
+        .with_signature(Crypto::sign(&alice, &payload));
+    let replies = east.handler.handle(&request.to_json_string().unwrap(), "tag-alice");
+    assert_eq!(replies.len(), 1);
+    assert_eq!(content(&replies[0]), "success");
+
+    let deliveries = gossip(&mut east, &mut west);
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].sender_tag, "tag-bob");
+    let delivered: Value = serde_json::from_str(&content(&deliveries[0])).unwrap();
+    assert_eq!(delivered["sender"], "alice");
+}
+
+#[test]
+fn entries_from_untrusted_servers_are_ignored() {
+    let (_, mut west) = federated_pair();
+    let (rogue, _) = Crypto::generate_key_pair().unwrap();
+    let (_, mallory_pem) = Crypto::generate_key_pair().unwrap();
+
+    let entry = to_python_json(&json!({ "username": "mallory", "publicKey": mallory_pem, "server": "east" })).unwrap();
+    let forged = Envelope::new("federatedEntry")
+        .with_context("federation")
+        .with_signature(Crypto::sign(&rogue, &entry))
+        .with_content(entry);
+    west.handler.handle_peer(&forged.to_json_string().unwrap());
+
+    let replies = west.handler.handle(r#"{"action": "query", "username": "mallory"}"#, "tag-q");
+    assert_eq!(content(&replies[0]), "No user found");
+}
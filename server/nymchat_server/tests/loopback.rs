@@ -0,0 +1,204 @@
+//! End-to-end flows between test clients and the server over the loopback mixnet.
+
+use nymchat_protocol::{to_python_json, ChatPayload, Crypto, Envelope, MessageBody, UserRecord};
+use nymchat_server::loopback::{LoopbackClient, LoopbackMixnet};
+use nymchat_server::{Db, DirectoryLimits, Federation, Handler, Peer};
+use p256::SecretKey;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// A server without response delay or lookup limits, so tests run fast.
+fn server() -> (Handler, String) {
+    let (identity, public_pem) = Crypto::generate_key_pair().unwrap();
+    let handler = Handler::new(Db::open_in_memory().unwrap(), identity).with_directory_limits(DirectoryLimits {
+        burst: 10_000,
+        per_minute: 10_000,
+        response_delay: Duration::ZERO,
+        ..DirectoryLimits::default()
+    });
+    (handler, public_pem)
+}
+
+/// A client that speaks the protocol the way the real clients do.
+struct TestUser {
+    name: String,
+    key: SecretKey,
+    public_pem: String,
+    session: LoopbackClient,
+}
+
+impl TestUser {
+    fn new(name: &str, mixnet: &LoopbackMixnet) -> Self {
+        let (key, public_pem) = Crypto::generate_key_pair().unwrap();
+        TestUser { name: name.into(), key, public_pem, session: mixnet.client() }
+    }
+
+    async fn recv(&mut self) -> Envelope {
+        let raw = self.session.recv().await.expect("reply within timeout");
+        Envelope::parse(&raw).unwrap()
+    }
+
+    async fn request(&mut self, request: Value) -> Envelope {
+        self.session.send(&request.to_string()).await;
+        self.recv().await
+    }
+
+    async fn challenge_flow(&mut self, request: Value, response_action: &str) -> String {
+        let challenge = self.request(request).await;
+        assert_eq!(challenge.action, "challenge");
+        let content: Value = serde_json::from_str(challenge.content.as_deref().unwrap()).unwrap();
+        let signature = Crypto::sign(&self.key, content["nonce"].as_str().unwrap());
+        let response = json!({ "action": response_action, "username": self.name, "signature": signature });
+        let outcome = self.request(response).await.content.unwrap();
+        if outcome == "success" {
+            // A successful login is followed by the session lifetime.
+            assert_eq!(self.recv().await.action, "session");
+        }
+        outcome
+    }
+
+    async fn register(&mut self) {
+        let request = json!({ "action": "register", "usernym": self.name, "publicKey": self.public_pem });
+        assert_eq!(self.challenge_flow(request, "registrationResponse").await, "success");
+    }
+
+    /// Logs in from a new session, as a restarted client would.
+    async fn login(&mut self, mixnet: &LoopbackMixnet) -> String {
+        self.session = mixnet.client();
+        let request = json!({ "action": "login", "usernym": self.name });
+        self.challenge_flow(request, "loginResponse").await
+    }
+
+    async fn query(&mut self, username: &str) -> Option<UserRecord> {
+        let reply = self.request(json!({ "action": "query", "username": username })).await;
+        serde_json::from_str(reply.content.as_deref().unwrap()).ok()
+    }
+
+    async fn send(&mut self, to: &UserRecord, text: &str) -> String {
+        let recipient_key = Crypto::public_key_from_pem(&to.public_key).unwrap();
+        let encrypted = Crypto::encrypt(&recipient_key, &json!({ "type": 0, "message": text }).to_string()).unwrap();
+        let payload = ChatPayload {
+            sender: self.name.clone(),
+            recipient: Some(to.username.clone()),
+            recipient_token: None,
+            body: MessageBody {
+                payload_signature: Crypto::sign(&self.key, &to_python_json(&encrypted).unwrap()),
+                encrypted_payload: encrypted,
+            },
+            encrypted: Some(true),
+            sender_public_key: None,
+        };
+        let content = to_python_json(&payload).unwrap();
+        let request = Envelope::new("send")
+            .with_signature(Crypto::sign(&self.key, &content))
+            .with_content(content);
+        self.session.send(&request.to_json_string().unwrap()).await;
+        self.recv().await.content.unwrap()
+    }
+
+    /// Receives one chat message, checks who signed it and acks it.
+    async fn receive(&mut self, sender: &UserRecord) -> String {
+        let delivery = self.recv().await;
+        assert_eq!(delivery.action, "incomingMessage");
+        let payload: ChatPayload = serde_json::from_str(delivery.content.as_deref().unwrap()).unwrap();
+        assert_eq!(payload.sender, sender.username);
+
+        let sender_key = Crypto::public_key_from_pem(&sender.public_key).unwrap();
+        let plaintext = Crypto::decrypt(&self.key, &payload.body.encrypted_payload)
+            .unwrap()
+            .verify(&sender_key, &payload.body.payload_signature)
+            .unwrap();
+
+        let id = delivery.field("messageId").unwrap().to_string();
+        let ack = json!({ "action": "ack", "username": self.name, "ids": [id] });
+        self.session.send(&ack.to_string()).await;
+
+        let message: Value = serde_json::from_str(&plaintext).unwrap();
+        message["message"].as_str().unwrap().to_string()
+    }
+}
+
+#[tokio::test]
+async fn register_login_query_send_receive() {
+    let (handler, _) = server();
+    let mixnet = LoopbackMixnet::start("hub", handler);
+    let mut alice = TestUser::new("alice", &mixnet);
+    let mut bob = TestUser::new("bob", &mixnet);
+
+    alice.register().await;
+    bob.register().await;
+    assert_eq!(alice.login(&mixnet).await, "success");
+    assert_eq!(bob.login(&mixnet).await, "success");
+
+    let bob_record = alice.query("bob").await.expect("bob is registered");
+    assert_eq!(bob_record.public_key, bob.public_pem);
+    assert!(alice.query("carol").await.is_none());
+    let alice_record = bob.query("alice").await.expect("alice is registered");
+
+    assert_eq!(alice.send(&bob_record, "hello bob").await, "success");
+    assert_eq!(bob.receive(&alice_record).await, "hello bob");
+    assert_eq!(bob.send(&alice_record, "hi alice").await, "success");
+    assert_eq!(alice.receive(&bob_record).await, "hi alice");
+}
+
+#[tokio::test]
+async fn messages_reach_the_recipient_and_survive_a_relogin_until_acked() {
+    let (handler, _) = server();
+    let mixnet = LoopbackMixnet::start("hub", handler);
+    let mut alice = TestUser::new("alice", &mixnet);
+    let mut bob = TestUser::new("bob", &mixnet);
+    alice.register().await;
+    bob.register().await;
+    let bob_record = alice.query("bob").await.unwrap();
+    let alice_record = bob.query("alice").await.unwrap();
+
+    // Bob goes offline: the delivery over his old tag is lost, and the queued
+    // copy arrives with his next login.
+    bob.session = mixnet.client();
+    assert_eq!(alice.send(&bob_record, "are you there?").await, "success");
+    assert_eq!(bob.login(&mixnet).await, "success");
+    assert_eq!(bob.receive(&alice_record).await, "are you there?");
+
+    // Acked messages are not redelivered.
+    assert_eq!(bob.login(&mixnet).await, "success");
+    assert!(bob.session.recv_within(Duration::from_millis(200)).await.is_none());
+}
+
+#[tokio::test]
+async fn federated_servers_relay_between_their_users() {
+    let (east_handler, east_pem) = server();
+    let (west_handler, west_pem) = server();
+    let peer = |name: &str, pem: &str| Peer { name: name.into(), address: name.into(), public_key: pem.into() };
+    let east = LoopbackMixnet::start(
+        "east",
+        east_handler.with_federation(Federation::new("east", vec![peer("west", &west_pem)]).unwrap()),
+    );
+    let west = LoopbackMixnet::start(
+        "west",
+        west_handler.with_federation(Federation::new("west", vec![peer("east", &east_pem)]).unwrap()),
+    );
+    east.link(&west);
+    west.link(&east);
+
+    let mut alice = TestUser::new("alice", &east);
+    let mut bob = TestUser::new("bob", &west);
+    alice.register().await;
+    bob.register().await;
+
+    // Gossip is asynchronous; wait for the entries to arrive.
+    let bob_record = loop {
+        if let Some(record) = alice.query("bob").await {
+            break record;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let alice_record = loop {
+        if let Some(record) = bob.query("alice").await {
+            break record;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    assert_eq!(alice.send(&bob_record, "hello from the east").await, "success");
+    assert_eq!(bob.receive(&alice_record).await, "hello from the east");
+}
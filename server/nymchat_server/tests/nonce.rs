@@ -0,0 +1,53 @@
+use nymchat_server::nonce::{NonceError, NonceStore};
+use std::time::{Duration, Instant};
+
+#[test]
+fn nonce_is_single_use() {
+    let mut store = NonceStore::new(Duration::from_secs(60));
+    let now = Instant::now();
+    let nonce = store.issue("tag", "alice", (), now);
+    assert_eq!(nonce.len(), 32);
+
+    let challenge = store.take("tag", Some("alice"), now).unwrap();
+    assert_eq!(challenge.nonce, nonce);
+    assert_eq!(store.take("tag", Some("alice"), now), Err(NonceError::Missing));
+}
+
+#[test]
+fn nonce_is_bound_to_sender_tag_and_username() {
+    let mut store = NonceStore::new(Duration::from_secs(60));
+    let now = Instant::now();
+    store.issue("tag", "alice", (), now);
+
+    assert_eq!(store.take("other-tag", Some("alice"), now), Err(NonceError::Missing));
+    assert_eq!(store.take("tag", Some("bob"), now), Err(NonceError::UsernameMismatch));
+    // A failed answer still burns the challenge.
+    assert_eq!(store.take("tag", Some("alice"), now), Err(NonceError::Missing));
+}
+
+#[test]
+fn nonce_expires() {
+    let mut store = NonceStore::new(Duration::from_secs(60));
+    let now = Instant::now();
+    store.issue("tag", "alice", (), now);
+    assert_eq!(
+        store.take("tag", None, now + Duration::from_secs(61)),
+        Err(NonceError::Expired)
+    );
+
+    store.issue("old", "alice", (), now);
+    store.issue("new", "bob", (), now + Duration::from_secs(61));
+    assert_eq!(store.len(), 1);
+}
+
+#[test]
+fn reissue_replaces_pending_challenge() {
+    let mut store = NonceStore::new(Duration::from_secs(60));
+    let now = Instant::now();
+    let first = store.issue("tag", "alice", 1, now);
+    let second = store.issue("tag", "alice", 2, now);
+    assert_ne!(first, second);
+
+    let challenge = store.take("tag", None, now).unwrap();
+    assert_eq!((challenge.nonce, challenge.data), (second, 2));
+}
@@ -0,0 +1,63 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use nymchat_protocol::Crypto;
+use nymchat_server::sealing::SealingKey;
+use sha2::Sha256;
+use std::collections::HashSet;
+
+#[test]
+fn sealed_data_opens_only_with_the_same_key() {
+    let (identity, _) = Crypto::generate_key_pair().unwrap();
+    let (other, _) = Crypto::generate_key_pair().unwrap();
+    let key = SealingKey::new(&identity, b"test");
+
+    let sealed = key.seal(b"hello");
+    assert_eq!(key.open(&sealed).unwrap(), b"hello");
+    // Same identity, later start: a new subkey, but old data still opens.
+    assert_eq!(SealingKey::new(&identity, b"test").open(&sealed).unwrap(), b"hello");
+    assert!(SealingKey::new(&identity, b"other purpose").open(&sealed).is_none());
+    assert!(SealingKey::new(&other, b"test").open(&sealed).is_none());
+
+    let mut altered = sealed.clone();
+    *altered.last_mut().unwrap() ^= 1;
+    assert!(key.open(&altered).is_none());
+    assert!(key.open(&sealed[..10]).is_none());
+}
+
+#[test]
+fn subkeys_rotate_after_the_message_cap() {
+    let (identity, _) = Crypto::generate_key_pair().unwrap();
+    let key = SealingKey::new(&identity, b"test").with_max_messages(2);
+
+    let sealed: Vec<Vec<u8>> = (0..5).map(|i| key.seal(format!("message {}", i).as_bytes())).collect();
+    let key_ids: Vec<&[u8]> = sealed.iter().map(|s| &s[..8]).collect();
+    assert_eq!(key_ids[0], key_ids[1]);
+    assert_ne!(key_ids[1], key_ids[2]);
+    assert_eq!(key_ids[2], key_ids[3]);
+    assert_eq!(key_ids.iter().collect::<HashSet<_>>().len(), 3);
+
+    for (i, s) in sealed.iter().enumerate() {
+        assert_eq!(key.open(s).unwrap(), format!("message {}", i).as_bytes());
+    }
+}
+
+#[test]
+fn data_sealed_without_a_key_id_still_opens() {
+    let (identity, _) = Crypto::generate_key_pair().unwrap();
+    let mut legacy_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, identity.to_bytes().as_slice())
+        .expand(b"nymchat offline queue", &mut legacy_key)
+        .unwrap();
+    let nonce = [3u8; 12];
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        Aes256Gcm::new_from_slice(&legacy_key)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&nonce), b"queued before the upgrade".as_ref())
+            .unwrap(),
+    );
+
+    let key = SealingKey::new(&identity, b"nymchat offline queue");
+    assert_eq!(key.open(&sealed).unwrap(), b"queued before the upgrade");
+}
@@ -0,0 +1,48 @@
+use nymchat_server::{DatabaseOptions, Db, Durability, Stats, Storage};
+
+#[test]
+fn sqlite_backup_copies_the_live_database() {
+    let dir = std::env::temp_dir().join(format!("nymchat-backup-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db = Db::open(dir.join("live.db")).unwrap();
+    db.add_user("alice", "alice-key", "tag-a").unwrap();
+    db.enqueue_message("alice", b"sealed", 0).unwrap();
+    db.ban_user("mallory").unwrap();
+
+    db.backup(&dir.join("backup.db")).unwrap();
+    let copy = Db::open(dir.join("backup.db")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(copy.get_user("alice").unwrap().unwrap().public_key, "alice-key");
+    assert_eq!(copy.queue_sizes().unwrap(), vec![("alice".to_string(), 1)]);
+    assert_eq!(
+        copy.stats().unwrap(),
+        Stats { users: 1, remote_users: 0, banned: 1, queued_messages: 1 }
+    );
+}
+
+#[test]
+fn backends_are_interchangeable_behind_the_trait() {
+    let db: Box<dyn Storage> = Box::new(Db::open_in_memory().unwrap());
+    assert!(db.add_user("alice", "key", "tag").unwrap());
+    assert!(!db.add_user("alice", "other", "tag").unwrap());
+    assert!(db.upsert_remote_user("bob", "key", "east").unwrap());
+    assert!(!db.upsert_remote_user("bob", "key", "west").unwrap());
+    assert_eq!(db.get_remote_user("bob").unwrap().unwrap().server, "east");
+}
+
+#[test]
+fn normal_durability_switches_sqlite_to_wal() {
+    assert_eq!("NORMAL".parse::<Durability>(), Ok(Durability::Normal));
+    assert!("fast".parse::<Durability>().is_err());
+
+    let dir = std::env::temp_dir().join(format!("nymchat-wal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = DatabaseOptions { durability: Durability::Normal, ..DatabaseOptions::default() };
+    let db = Db::open_with(dir.join("wal.db"), &options).unwrap();
+    db.add_user("alice", "key", "tag").unwrap();
+    let has_wal = dir.join("wal.db-wal").exists();
+    drop(db);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(has_wal);
+}
@@ -0,0 +1,36 @@
+use nymchat_server::surbs::{SurbLedger, SurbWarning};
+use nymchat_server::SurbLimits;
+use std::time::{Duration, Instant};
+
+#[test]
+fn surbs_from_an_earlier_epoch_are_reissued() {
+    let limits = SurbLimits { epoch_length: Duration::from_secs(3600), ..SurbLimits::default() };
+    let mut ledger = SurbLedger::new(limits);
+    let now = Instant::now();
+    let next = ledger.next_epoch(now).unwrap();
+    assert_eq!(ledger.epoch(next), ledger.epoch(now) + 1);
+    assert_eq!(ledger.epoch(next - Duration::from_millis(1)), ledger.epoch(now));
+
+    ledger.credit("tag", 10, now);
+    assert_eq!(ledger.spend("tag", 100, now), None);
+
+    // Once the epoch turns, the client is asked once to issue fresh SURBs.
+    assert_eq!(ledger.spend("tag", 100, next), Some(SurbWarning::Stale));
+    assert_eq!(ledger.spend("tag", 100, next), None);
+
+    // Fresh SURBs replace the stale ones instead of adding to them.
+    ledger.credit("tag", 20, next);
+    assert_eq!(ledger.remaining("tag"), Some(20));
+    assert_eq!(ledger.spend("tag", 100, next), None);
+    assert_eq!(ledger.remaining("tag"), Some(19));
+}
+
+#[test]
+fn epochs_can_be_ignored() {
+    let mut ledger = SurbLedger::new(SurbLimits { epoch_length: Duration::ZERO, ..SurbLimits::default() });
+    let now = Instant::now();
+    assert_eq!(ledger.next_epoch(now), None);
+    ledger.credit("tag", 10, now);
+    assert_eq!(ledger.spend("tag", 100, now + Duration::from_secs(86_400)), None);
+    assert_eq!(ledger.remaining("tag"), Some(9));
+}